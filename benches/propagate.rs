@@ -0,0 +1,75 @@
+use belief_propagation::generators::{
+    awgn_llrs, ldpc_ensemble, random_grid, random_regular_bipartite, random_tree,
+};
+use belief_propagation::{BPGraph, BPResult, DenseMsg, LdpcAlgorithm, LdpcDecoder};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+
+const STEPS: usize = 10;
+const THREADS: u32 = 4;
+
+//Every iteration propagates a freshly initialized graph, building it is not measured
+fn bench_graph(
+    c: &mut Criterion,
+    name: &str,
+    build: impl Fn() -> BPResult<BPGraph<usize, DenseMsg>>,
+) {
+    let setup = || {
+        let mut g = build().expect("Graph can be built");
+        g.initialize().expect("Graph can be initialized");
+        g
+    };
+    let mut group = c.benchmark_group(name);
+    group.bench_function("propagate", |b| {
+        b.iter_batched(
+            setup,
+            |mut g| g.propagate(STEPS).unwrap(),
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("propagate_gpu", |b| {
+        b.iter_batched(
+            setup,
+            |mut g| g.propagate_gpu(STEPS).unwrap(),
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("propagate_threaded", |b| {
+        b.iter_batched(
+            setup,
+            |mut g| g.propagate_threaded(STEPS, THREADS).unwrap(),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+fn bipartite(c: &mut Criterion) {
+    bench_graph(c, "random_regular_bipartite", || {
+        random_regular_bipartite(3000, 3, 3, 4, 1)
+    });
+}
+
+fn tree(c: &mut Criterion) {
+    bench_graph(c, "random_tree", || random_tree(7, 3, 4, 1));
+}
+
+fn grid(c: &mut Criterion) {
+    bench_graph(c, "random_grid", || {
+        random_grid(50, 50, 8, 0.5, 1).map(|(g, _)| g)
+    });
+}
+
+fn ldpc(c: &mut Criterion) {
+    let checks = ldpc_ensemble(1200, 3, 6, 1).expect("Code exists");
+    let llrs = awgn_llrs(1200, 0.8, 2);
+    c.bench_function("ldpc_decode", |b| {
+        b.iter_batched(
+            || LdpcDecoder::new(1200, checks.clone(), LdpcAlgorithm::SumProduct).unwrap(),
+            |mut decoder| decoder.decode(&llrs, 20).unwrap(),
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+criterion_group!(benches, bipartite, tree, grid, ldpc);
+criterion_main!(benches);
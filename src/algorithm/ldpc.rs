@@ -0,0 +1,177 @@
+use crate::{
+    BPError, BPGraph, BPResult, DenseMsg, MinSum, Msg, ParityFactor, Probability, SumProduct,
+    VariableNode, VariableNodeCtrl, VariableNodeCtrlAnswer,
+};
+use std::sync::Arc;
+
+/*
+Decoder for binary linear codes (e.g., LDPC codes) given by a sparse parity-check matrix.
+
+Every column is a binary variable node (values 0 and 1), every row a ParityFactor connected to the columns
+with a one in that row. The channel output is given as log-likelihood ratios L = ln(P(x = 0) / P(x = 1)),
+which become the priors of the variables:
+    sum-product: (P(x = 0), P(x = 1)) = (1 / (1 + e^-L), 1 / (1 + e^L))
+    min-sum:     costs (0, L)
+One iteration consists of two steps (variables -> checks, checks -> variables).
+*/
+
+pub type LdpcGraph =
+    BPGraph<usize, DenseMsg, VariableNodeCtrl<DenseMsg>, VariableNodeCtrlAnswer<DenseMsg>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LdpcAlgorithm {
+    SumProduct,
+    MinSum,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LdpcResult {
+    //Hard decisions (true is 1), a bit is 1 if its posterior LLR is negative
+    pub codeword: Vec<bool>,
+    //Posterior log-likelihood ratios
+    pub llrs: Vec<f64>,
+    //Rows of the parity-check matrix not satisfied by codeword
+    pub unsatisfied_checks: Vec<usize>,
+    pub iterations: usize,
+}
+
+impl LdpcResult {
+    pub fn is_codeword(&self) -> bool {
+        self.unsatisfied_checks.is_empty()
+    }
+}
+
+pub struct LdpcDecoder {
+    graph: LdpcGraph,
+    num_bits: usize,
+    checks: Vec<Vec<usize>>,
+    algorithm: LdpcAlgorithm,
+}
+
+impl LdpcDecoder {
+    //checks[r] lists the columns with a one in row r of the parity-check matrix.
+    //The graph is initialized.
+    pub fn new(
+        num_bits: usize,
+        checks: Vec<Vec<usize>>,
+        algorithm: LdpcAlgorithm,
+    ) -> BPResult<Self> {
+        let mut graph = LdpcGraph::new();
+        graph.reserve(num_bits + checks.len());
+        graph.set_semiring(match algorithm {
+            LdpcAlgorithm::SumProduct => Arc::new(SumProduct),
+            LdpcAlgorithm::MinSum => Arc::new(MinSum),
+        });
+        for i in 0..num_bits {
+            graph.add_node(format!("x{}", i), Box::new(VariableNode::new()))?;
+        }
+        for (r, row) in checks.iter().enumerate() {
+            for (k, col) in row.iter().enumerate() {
+                if *col >= num_bits || row[..k].contains(col) {
+                    return Err(BPError::new(
+                        "LdpcDecoder::new".to_owned(),
+                        format!("Invalid or repeated column {} in row {}", col, r),
+                    ));
+                }
+            }
+            let check = graph.add_node(format!("check{}", r), Box::new(ParityFactor::new()))?;
+            for col in row {
+                graph.add_edge(*col, check)?;
+            }
+        }
+        graph.initialize()?;
+        Ok(LdpcDecoder {
+            graph,
+            num_bits,
+            checks,
+            algorithm,
+        })
+    }
+
+    pub fn graph(&self) -> &LdpcGraph {
+        &self.graph
+    }
+
+    pub fn graph_mut(&mut self) -> &mut LdpcGraph {
+        &mut self.graph
+    }
+
+    pub fn num_bits(&self) -> usize {
+        self.num_bits
+    }
+
+    pub fn algorithm(&self) -> LdpcAlgorithm {
+        self.algorithm
+    }
+
+    //Rows of the parity-check matrix violated by codeword
+    pub fn syndrome(&self, codeword: &[bool]) -> Vec<usize> {
+        self.checks
+            .iter()
+            .enumerate()
+            .filter(|(_, row)| row.iter().filter(|col| codeword[**col]).count() % 2 == 1)
+            .map(|(r, _)| r)
+            .collect()
+    }
+
+    //Runs at most max_iterations iterations, stopping early once the hard decisions form a codeword.
+    //The state of previous decodings is discarded.
+    pub fn decode(&mut self, llrs: &[f64], max_iterations: usize) -> BPResult<LdpcResult> {
+        if llrs.len() != self.num_bits {
+            return Err(BPError::new(
+                "LdpcDecoder::decode".to_owned(),
+                format!(
+                    "Wrong number of LLRs ({}, needed: {})",
+                    llrs.len(),
+                    self.num_bits
+                ),
+            ));
+        }
+        for (i, llr) in llrs.iter().enumerate() {
+            let prior = match self.algorithm {
+                LdpcAlgorithm::SumProduct => {
+                    DenseMsg::from_vec(vec![1.0 / (1.0 + (-llr).exp()), 1.0 / (1.0 + llr.exp())])
+                }
+                LdpcAlgorithm::MinSum => DenseMsg::from_vec(vec![0.0, *llr]),
+            };
+            self.graph
+                .send_control_message(i, VariableNodeCtrl::SetPrior(Some(prior)))?;
+        }
+        self.graph.reset_schedule_state()?;
+        let mut result = self.hard_decision(0)?;
+        while result.iterations < max_iterations && !result.is_codeword() {
+            self.graph.propagate(2).map_err(|e| {
+                e.attach_info_str(
+                    "LdpcDecoder::decode",
+                    format!("Iteration {} failed", result.iterations + 1),
+                )
+            })?;
+            result = self.hard_decision(result.iterations + 1)?;
+        }
+        Ok(result)
+    }
+
+    fn hard_decision(&self, iterations: usize) -> BPResult<LdpcResult> {
+        let mut llrs = Vec::with_capacity(self.num_bits);
+        for i in 0..self.num_bits {
+            let res = self.graph.get_result(i)?.ok_or_else(|| {
+                BPError::new(
+                    "LdpcDecoder::hard_decision".to_owned(),
+                    format!("Bit {} has no result", i),
+                )
+            })?;
+            let (p0, p1): (Probability, Probability) = (res[&0], res[&1]);
+            llrs.push(match self.algorithm {
+                LdpcAlgorithm::SumProduct => (p0 / p1).ln(),
+                LdpcAlgorithm::MinSum => p1 - p0,
+            });
+        }
+        let codeword: Vec<bool> = llrs.iter().map(|llr| *llr < 0.0).collect();
+        Ok(LdpcResult {
+            unsatisfied_checks: self.syndrome(&codeword),
+            codeword,
+            llrs,
+            iterations,
+        })
+    }
+}
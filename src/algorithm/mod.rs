@@ -0,0 +1,2 @@
+//Decoders and other ready-made algorithms built on BPGraph
+pub mod ldpc;
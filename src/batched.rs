@@ -0,0 +1,367 @@
+use crate::junction_tree::{variable_priors, Table};
+use crate::{BPError, BPGraph, BPResult, Msg, NodeIndex, Probability};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+
+/*
+Loopy BP over B independent observation sets (e.g., one per trace) that share the structure and the factors
+of a graph. Only the priors of the variables differ between the batch elements.
+
+Every message holds the entries of all batch elements, laid out as [value * B + b], so that the per-edge
+operations run over contiguous slices of length B (which the compiler can vectorize) and the schedule is
+only walked once for the whole batch. Updates are synchronous (flooding):
+    var -> factor:  n_vf(x) = prior_v(x) * prod_{g != f} m_gv(x)
+    factor -> var:  m_fv(x) = sum_{x_f, x_v = x} psi_f(x_f) * prod_{u != v} n_uf(x_u)
+
+As for the junction tree, factor potentials are taken from NodeFunction::potential and the priors of the
+variable nodes of the graph define the domains and the initial priors of all batch elements.
+*/
+
+pub struct BatchedBPGraph<T> {
+    //Graph node index of each variable
+    variables: Vec<NodeIndex>,
+    domains: Vec<Vec<T>>,
+    batch_size: usize,
+    //Per variable, [value * batch_size + b]
+    priors: Vec<Vec<Probability>>,
+    factors: Vec<Table>,
+    //(factor, position in the scope of the factor) for every variable
+    neighbours: Vec<Vec<(usize, usize)>>,
+    //Factor to variable messages, in the order of the scope of the factor, [value * batch_size + b]
+    msgs: Vec<Vec<Vec<Probability>>>,
+}
+
+//Normalizes every batch element of values ([value * batch_size + b]) to sum to one.
+//Batch elements summing to zero are left as they are.
+fn normalize_batch(values: &mut [Probability], batch_size: usize, sums: &mut Vec<Probability>) {
+    sums.clear();
+    sums.resize(batch_size, 0.0);
+    for chunk in values.chunks_exact(batch_size) {
+        for (s, p) in sums.iter_mut().zip(chunk) {
+            *s += p;
+        }
+    }
+    sums.iter_mut()
+        .for_each(|s| *s = if *s > 0.0 { 1.0 / *s } else { 1.0 });
+    for chunk in values.chunks_exact_mut(batch_size) {
+        for (p, s) in chunk.iter_mut().zip(sums.iter()) {
+            *p *= s;
+        }
+    }
+}
+
+impl<T> BatchedBPGraph<T>
+where
+    T: Copy + Eq + Hash + Debug,
+{
+    //Fails if batch_size is 0 or the table of a factor would have more than max_table_size entries.
+    pub fn new<MsgT: Msg<T> + Clone, CtrlMsgT, CtrlMsgAT: Default>(
+        graph: &BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>,
+        batch_size: usize,
+        max_table_size: usize,
+    ) -> BPResult<Self> {
+        if batch_size == 0 {
+            return Err(BPError::new(
+                "BatchedBPGraph::new".to_owned(),
+                "Batch size has to be positive".to_owned(),
+            ));
+        }
+        let (variables, priors) = variable_priors(graph, "BatchedBPGraph::new")?;
+        let var_id: HashMap<NodeIndex, usize> = variables
+            .iter()
+            .enumerate()
+            .map(|(i, idx)| (*idx, i))
+            .collect();
+        let domains: Vec<Vec<T>> = priors
+            .iter()
+            .map(|prior| prior.iter().map(|(v, _)| *v).collect())
+            .collect();
+        let domain_sizes: Vec<usize> = domains.iter().map(|d| d.len()).collect();
+        let priors: Vec<Vec<Probability>> = priors
+            .into_iter()
+            .map(|prior| {
+                prior
+                    .into_iter()
+                    .flat_map(|(_, p)| std::iter::repeat_n(p, batch_size))
+                    .collect()
+            })
+            .collect();
+
+        let mut factors = Vec::new();
+        let mut neighbours = vec![Vec::new(); variables.len()];
+        for idx in 0..graph.len() {
+            let node = graph.get_node(idx)?;
+            if !node.is_factor() {
+                continue;
+            }
+            let scope = node
+                .get_connections()
+                .iter()
+                .map(|con| {
+                    var_id.get(con).copied().ok_or_else(|| {
+                        BPError::new(
+                            "BatchedBPGraph::new".to_owned(),
+                            format!("Factor {} is connected to unknown variable {}", idx, con),
+                        )
+                    })
+                })
+                .collect::<BPResult<Vec<usize>>>()?;
+            scope
+                .iter()
+                .try_fold(1usize, |acc, v| acc.checked_mul(domain_sizes[*v]))
+                .filter(|n| *n <= max_table_size)
+                .ok_or_else(|| {
+                    BPError::new(
+                        "BatchedBPGraph::new".to_owned(),
+                        format!(
+                            "Table of factor {} is too large (maximum: {})",
+                            idx, max_table_size
+                        ),
+                    )
+                })?;
+            for (k, v) in scope.iter().enumerate() {
+                neighbours[*v].push((factors.len(), k));
+            }
+            let mut potential = Table::ones(scope, &domain_sizes);
+            let mut values = Vec::with_capacity(potential.vars.len());
+            for i in 0..potential.values.len() {
+                let assignment = potential.assignment(i);
+                values.clear();
+                values.extend(
+                    potential
+                        .vars
+                        .iter()
+                        .zip(assignment)
+                        .map(|(v, a)| domains[*v][a]),
+                );
+                potential.values[i] = node.potential(&values).ok_or_else(|| {
+                    BPError::new(
+                        "BatchedBPGraph::new".to_owned(),
+                        format!(
+                            "Factor {} ({}) does not implement potential",
+                            idx,
+                            node.get_name()
+                        ),
+                    )
+                })?;
+            }
+            factors.push(potential);
+        }
+        let msgs = factors
+            .iter()
+            .map(|f| {
+                f.dims
+                    .iter()
+                    .map(|d| vec![1.0 / *d as Probability; *d * batch_size])
+                    .collect()
+            })
+            .collect();
+        Ok(BatchedBPGraph {
+            variables,
+            domains,
+            batch_size,
+            priors,
+            factors,
+            neighbours,
+            msgs,
+        })
+    }
+
+    pub fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    fn variable(&self, node_index: NodeIndex, fn_name: &str) -> BPResult<usize> {
+        self.variables
+            .iter()
+            .position(|idx| *idx == node_index)
+            .ok_or_else(|| {
+                BPError::new(
+                    fn_name.to_owned(),
+                    format!("Node {} is not a variable node", node_index),
+                )
+            })
+    }
+
+    //Replaces the prior of node_index in batch element b. Values of the domain missing in prior get probability 0.
+    //Fails for values outside of the domain (given by the prior of the node in the graph).
+    pub fn set_prior(
+        &mut self,
+        node_index: NodeIndex,
+        b: usize,
+        prior: &HashMap<T, Probability>,
+    ) -> BPResult<()> {
+        let v = self.variable(node_index, "BatchedBPGraph::set_prior")?;
+        if b >= self.batch_size {
+            return Err(BPError::new(
+                "BatchedBPGraph::set_prior".to_owned(),
+                format!(
+                    "Batch element {} out of range (batch size: {})",
+                    b, self.batch_size
+                ),
+            ));
+        }
+        if let Some(value) = prior.keys().find(|x| !self.domains[v].contains(x)) {
+            return Err(BPError::new(
+                "BatchedBPGraph::set_prior".to_owned(),
+                format!(
+                    "Value {:?} is not in the domain of node {}",
+                    value, node_index
+                ),
+            ));
+        }
+        for (i, x) in self.domains[v].iter().enumerate() {
+            self.priors[v][i * self.batch_size + b] = prior.get(x).copied().unwrap_or(0.0);
+        }
+        Ok(())
+    }
+
+    //Sets the priors of all batch elements of node_index at once, priors[b] being the prior of batch element b
+    pub fn set_priors(
+        &mut self,
+        node_index: NodeIndex,
+        priors: &[HashMap<T, Probability>],
+    ) -> BPResult<()> {
+        if priors.len() != self.batch_size {
+            return Err(BPError::new(
+                "BatchedBPGraph::set_priors".to_owned(),
+                format!(
+                    "Number of priors ({}) does not match the batch size ({})",
+                    priors.len(),
+                    self.batch_size
+                ),
+            ));
+        }
+        for (b, prior) in priors.iter().enumerate() {
+            self.set_prior(node_index, b, prior)?;
+        }
+        Ok(())
+    }
+
+    //Resets all messages to uniform, e.g., before running the next batch
+    pub fn reset_messages(&mut self) {
+        for msgs in self.msgs.iter_mut() {
+            for m in msgs.iter_mut() {
+                let d = m.len() / self.batch_size;
+                m.iter_mut().for_each(|p| *p = 1.0 / d as Probability);
+            }
+        }
+    }
+
+    //prior_v * prod_{g != excluded} m_gv (unnormalized)
+    fn product(&self, v: usize, excluded: Option<usize>) -> Vec<Probability> {
+        let mut product = self.priors[v].clone();
+        for (f, k) in &self.neighbours[v] {
+            if Some(*f) == excluded {
+                continue;
+            }
+            for (p, m) in product.iter_mut().zip(self.msgs[*f][*k].iter()) {
+                *p *= m;
+            }
+        }
+        product
+    }
+
+    //One synchronous update of all messages, returns the maximal absolute change of a message entry
+    pub fn step(&mut self) -> f64 {
+        let bs = self.batch_size;
+        let mut sums = Vec::with_capacity(bs);
+        let incoming: Vec<Vec<Vec<Probability>>> = self
+            .factors
+            .iter()
+            .enumerate()
+            .map(|(f, factor)| {
+                factor
+                    .vars
+                    .iter()
+                    .map(|v| {
+                        let mut n = self.product(*v, Some(f));
+                        normalize_batch(&mut n, bs, &mut sums);
+                        n
+                    })
+                    .collect()
+            })
+            .collect();
+        let mut max_change: f64 = 0.0;
+        let mut acc = vec![0.0; bs];
+        for (f, factor) in self.factors.iter().enumerate() {
+            let mut new_msgs: Vec<Vec<Probability>> =
+                factor.dims.iter().map(|d| vec![0.0; *d * bs]).collect();
+            for (i, psi) in factor.values.iter().enumerate() {
+                if *psi == 0.0 {
+                    continue;
+                }
+                let assignment = factor.assignment(i);
+                for k in 0..assignment.len() {
+                    acc.iter_mut().for_each(|p| *p = *psi);
+                    for (j, (a, n)) in assignment.iter().zip(incoming[f].iter()).enumerate() {
+                        if j != k {
+                            for (p, n) in acc.iter_mut().zip(&n[a * bs..(a + 1) * bs]) {
+                                *p *= n;
+                            }
+                        }
+                    }
+                    let a = assignment[k];
+                    for (m, p) in new_msgs[k][a * bs..(a + 1) * bs].iter_mut().zip(acc.iter()) {
+                        *m += p;
+                    }
+                }
+            }
+            for (old, mut new) in self.msgs[f].iter_mut().zip(new_msgs) {
+                normalize_batch(&mut new, bs, &mut sums);
+                for (o, n) in old.iter().zip(new.iter()) {
+                    max_change = max_change.max((o - n).abs());
+                }
+                *old = new;
+            }
+        }
+        max_change
+    }
+
+    //Runs at most max_steps steps until the messages of all batch elements change by at most tolerance,
+    //returns the number of steps
+    pub fn propagate(&mut self, max_steps: usize, tolerance: f64) -> usize {
+        for steps in 0..max_steps {
+            if self.step() <= tolerance {
+                return steps + 1;
+            }
+        }
+        max_steps
+    }
+
+    //Belief (summing to one) of a variable node in batch element b
+    pub fn marginal(&self, node_index: NodeIndex, b: usize) -> Option<HashMap<T, Probability>> {
+        if b >= self.batch_size {
+            return None;
+        }
+        let v = self.variables.iter().position(|idx| *idx == node_index)?;
+        let belief = self.product(v, None);
+        let values: Vec<Probability> = belief
+            .iter()
+            .skip(b)
+            .step_by(self.batch_size)
+            .copied()
+            .collect();
+        let sum: Probability = values.iter().sum();
+        Some(
+            self.domains[v]
+                .iter()
+                .copied()
+                .zip(
+                    values
+                        .into_iter()
+                        .map(|p| if sum > 0.0 { p / sum } else { p }),
+                )
+                .collect(),
+        )
+    }
+
+    //Beliefs of all variable nodes in batch element b
+    pub fn marginals(&self, b: usize) -> HashMap<NodeIndex, HashMap<T, Probability>> {
+        self.variables
+            .iter()
+            .filter_map(|idx| self.marginal(*idx, b).map(|m| (*idx, m)))
+            .collect()
+    }
+}
@@ -0,0 +1,243 @@
+use crate::{
+    BPError, BPGraph, BPResult, DenseMsg, NodeIndex, Probability, TableFactor, VariableNodeCtrl,
+    VariableNodeCtrlAnswer,
+};
+use std::collections::HashMap;
+
+/*
+Bayesian networks over discrete variables, compiled to factor graphs.
+
+Every BN node becomes a variable with the values 0..cardinality and a uniform prior, and its CPD
+P(x | parents) becomes a TableFactor connected to the node and its parents. This factor is the clique of the
+moral graph (the node married to its parents), so no separate moralization step is needed. Evidence is set
+as the prior of the variable. Nodes have to be added after their parents, which keeps the network acyclic.
+BP is exact on polytrees (networks without undirected cycles) and approximate otherwise.
+*/
+
+pub type BayesNetGraph =
+    BPGraph<usize, DenseMsg, VariableNodeCtrl<DenseMsg>, VariableNodeCtrlAnswer<DenseMsg>>;
+
+#[derive(Debug, Clone)]
+struct BnNode {
+    name: String,
+    cardinality: usize,
+    parents: Vec<usize>,
+    cpd: Vec<Probability>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BayesNet {
+    nodes: Vec<BnNode>,
+    index: HashMap<String, usize>,
+}
+
+impl BayesNet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    //Adds a node with the values 0..cardinality. cpd lists P(x | parents) for every assignment of the parents
+    //(the value of the first parent changes fastest), the distribution over x for one assignment being
+    //contiguous, i.e., the entry of (x, p0, ..., p_n-1) is cpd[x + c * (p0 + c0 * (p1 + ...))] for the
+    //cardinalities c, c0, ..., c_n-1 (the order of TableFactor). Every distribution has to sum to 1.
+    //The parents have to be added before. Returns the index of the node.
+    pub fn add_node(
+        &mut self,
+        name: &str,
+        cardinality: usize,
+        parents: &[&str],
+        cpd: Vec<Probability>,
+    ) -> BPResult<usize> {
+        let error = |msg: String| BPError::new("BayesNet::add_node".to_owned(), msg);
+        if self.index.contains_key(name) {
+            return Err(error(format!("Node {} already exists", name)));
+        }
+        if cardinality == 0 {
+            return Err(error(format!("Node {} has cardinality 0", name)));
+        }
+        let mut parent_indices = Vec::with_capacity(parents.len());
+        for parent in parents {
+            let p = *self
+                .index
+                .get(*parent)
+                .ok_or_else(|| error(format!("Parent {} of {} does not exist", parent, name)))?;
+            if parent_indices.contains(&p) {
+                return Err(error(format!("Parent {} of {} is repeated", parent, name)));
+            }
+            parent_indices.push(p);
+        }
+        let size: usize = cardinality
+            * parent_indices
+                .iter()
+                .map(|p| self.nodes[*p].cardinality)
+                .product::<usize>();
+        if cpd.len() != size {
+            return Err(error(format!(
+                "CPD of {} has {} entries, needed: {}",
+                name,
+                cpd.len(),
+                size
+            )));
+        }
+        for (i, distribution) in cpd.chunks(cardinality).enumerate() {
+            let sum: Probability = distribution.iter().sum();
+            if distribution.iter().any(|p| *p < 0.0) || (sum - 1.0).abs() > 1e-6 {
+                return Err(error(format!(
+                    "Distribution {} of the CPD of {} is not a distribution (sum: {})",
+                    i, name, sum
+                )));
+            }
+        }
+        let index = self.nodes.len();
+        self.nodes.push(BnNode {
+            name: name.to_owned(),
+            cardinality,
+            parents: parent_indices,
+            cpd,
+        });
+        self.index.insert(name.to_owned(), index);
+        Ok(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        self.index.get(name).copied()
+    }
+
+    pub fn parents(&self, name: &str) -> Option<Vec<&str>> {
+        let node = &self.nodes[self.index_of(name)?];
+        Some(
+            node.parents
+                .iter()
+                .map(|p| self.nodes[*p].name.as_str())
+                .collect(),
+        )
+    }
+
+    //Builds the factor graph, the graph is initialized. The BN node with index i is variable i of the graph,
+    //the factor of its CPD is node len() + i.
+    pub fn compile(&self) -> BPResult<CompiledBayesNet> {
+        let mut graph = BayesNetGraph::new();
+        graph.reserve(2 * self.nodes.len());
+        for node in &self.nodes {
+            graph.add_variable(node.name.clone(), DenseMsg::uniform(node.cardinality))?;
+        }
+        for (i, node) in self.nodes.iter().enumerate() {
+            let cardinalities = std::iter::once(node.cardinality)
+                .chain(node.parents.iter().map(|p| self.nodes[*p].cardinality))
+                .collect();
+            let factor = graph.add_factor(
+                format!("P({})", node.name),
+                TableFactor::new(cardinalities, node.cpd.clone())?,
+            )?;
+            for variable in std::iter::once(i).chain(node.parents.iter().copied()) {
+                graph.add_edge(variable, factor)?;
+            }
+        }
+        graph.initialize()?;
+        Ok(CompiledBayesNet {
+            graph,
+            names: self.nodes.iter().map(|n| n.name.clone()).collect(),
+            cardinalities: self.nodes.iter().map(|n| n.cardinality).collect(),
+            index: self.index.clone(),
+        })
+    }
+}
+
+//Factor graph of a BayesNet, queried by the names of the BN nodes
+pub struct CompiledBayesNet {
+    graph: BayesNetGraph,
+    names: Vec<String>,
+    cardinalities: Vec<usize>,
+    index: HashMap<String, usize>,
+}
+
+impl CompiledBayesNet {
+    pub fn graph(&self) -> &BayesNetGraph {
+        &self.graph
+    }
+
+    pub fn graph_mut(&mut self) -> &mut BayesNetGraph {
+        &mut self.graph
+    }
+
+    //Variable of the graph belonging to a BN node
+    pub fn variable(&self, name: &str) -> BPResult<NodeIndex> {
+        self.index.get(name).copied().ok_or_else(|| {
+            BPError::new(
+                "CompiledBayesNet::variable".to_owned(),
+                format!("Unknown node {}", name),
+            )
+        })
+    }
+
+    //Fixes the node to value until the evidence is cleared. Takes effect in the next propagate.
+    pub fn observe(&mut self, name: &str, value: usize) -> BPResult<()> {
+        let i = self.variable(name)?;
+        if value >= self.cardinalities[i] {
+            return Err(BPError::new(
+                "CompiledBayesNet::observe".to_owned(),
+                format!(
+                    "Value {} out of range for {} (cardinality {})",
+                    value, name, self.cardinalities[i]
+                ),
+            ));
+        }
+        let mut prior = vec![0.0; self.cardinalities[i]];
+        prior[value] = 1.0;
+        self.graph.send_control_message(
+            i,
+            VariableNodeCtrl::SetPrior(Some(DenseMsg::from_vec(prior))),
+        )?;
+        Ok(())
+    }
+
+    pub fn clear_evidence(&mut self, name: &str) -> BPResult<()> {
+        let i = self.variable(name)?;
+        self.graph.send_control_message(
+            i,
+            VariableNodeCtrl::SetPrior(Some(DenseMsg::uniform(self.cardinalities[i]))),
+        )?;
+        Ok(())
+    }
+
+    //Starts the propagation over (see BPGraph::reset_schedule_state) and runs steps steps. On a polytree,
+    //twice the diameter of the factor graph suffices for exact marginals.
+    pub fn propagate(&mut self, steps: usize) -> BPResult<()> {
+        self.graph.reset_schedule_state()?;
+        self.graph.propagate(steps).map_err(|e| {
+            e.attach_info_str(
+                "CompiledBayesNet::propagate",
+                "Propagating the factor graph failed".to_owned(),
+            )
+        })
+    }
+
+    //Posterior of a node given the evidence as probabilities of the values 0..cardinality
+    pub fn marginal(&self, name: &str) -> BPResult<Vec<Probability>> {
+        let i = self.variable(name)?;
+        let res = self.graph.get_result(i)?.ok_or_else(|| {
+            BPError::new(
+                "CompiledBayesNet::marginal".to_owned(),
+                format!("Node {} has no result", name),
+            )
+        })?;
+        Ok((0..self.cardinalities[i])
+            .map(|v| res.get(&v).copied().unwrap_or(0.0))
+            .collect())
+    }
+
+    pub fn marginals(&self) -> BPResult<HashMap<String, Vec<Probability>>> {
+        self.names
+            .iter()
+            .map(|name| Ok((name.clone(), self.marginal(name)?)))
+            .collect()
+    }
+}
@@ -0,0 +1,231 @@
+use crate::metrics::entropy;
+use crate::{
+    BPError, BPGraph, BPResult, Msg, NodeIndex, Probability, ResultOptions, VariableNodeCtrl,
+};
+use std::fmt::Debug;
+use std::hash::Hash;
+
+impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default> BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>
+where
+    T: Copy + Eq + Hash + Debug,
+    MsgT: Clone,
+    CtrlMsgT: From<VariableNodeCtrl<MsgT>>,
+{
+    //Bethe approximation of ln Z (minus the Bethe free energy) at the current beliefs:
+    //sum_f (E_bf[ln psi_f] + H(b_f)) + sum_v (E_bv[ln prior_v] + (1 - d_v) * H(b_v)), d_v being the degree of v.
+    //Exact on trees once the propagation has converged. Priors count as unary factors and are not normalized.
+    //The factor beliefs are computed from the cavity distributions of the variables, so every variable needs
+    //a message from each of its factors in its inbox (with the flooding schedule after an even number of steps).
+    //Fails for factors without potential (see NodeFunction::potential) or with more than max_assignments
+    //joint assignments. Only available for probabilities.
+    pub fn bethe_log_partition(&self, max_assignments: usize) -> BPResult<f64> {
+        if !self.get_normalization().is_probability() {
+            return Err(BPError::new(
+                "BPGraph::bethe_log_partition".to_owned(),
+                "Messages are not probabilities".to_owned(),
+            ));
+        }
+        let mut log_z = 0.0;
+        for (node, _, is_factor) in self.nodes() {
+            if is_factor {
+                for (_, b, psi) in self.cavity_factor_belief(node, max_assignments)? {
+                    if b > 0.0 {
+                        log_z += b * (psi.ln() - b.ln());
+                    }
+                }
+                continue;
+            }
+            if !self.get_node(node)?.has_post_from_all_connections() {
+                return Err(BPError::new(
+                    "BPGraph::bethe_log_partition".to_owned(),
+                    format!(
+                        "Variable {} has not received messages from all connections, propagate one more step",
+                        node
+                    ),
+                ));
+            }
+            let belief = self.get_result(node)?.ok_or_else(|| {
+                BPError::new(
+                    "BPGraph::bethe_log_partition".to_owned(),
+                    format!("Variable {} has no belief", node),
+                )
+            })?;
+            let sum: Probability = belief.values().sum();
+            if let Some(prior) = self.get_node(node)?.get_prior() {
+                //Values missing in the prior are not restricted by it
+                log_z += belief
+                    .iter()
+                    .filter(|(_, b)| **b > 0.0)
+                    .map(|(v, b)| b / sum * prior.get(*v).unwrap_or(1.0).ln())
+                    .sum::<f64>();
+            }
+            let degree = self.get_connections(node)?.len() as f64;
+            log_z += (1.0 - degree) * entropy(&belief);
+        }
+        Ok(log_z)
+    }
+
+    //Approximate log-likelihood ln p(evidence) of the observations (variable, value) as the difference of
+    //bethe_log_partition with and without the variables clamped to the observed values. Both are computed
+    //after steps further steps of propagation from the current state (one more if the variables do not hold
+    //messages from all factors afterwards), clamping keeps the prior of the observed value.
+    //The graph (messages and priors) is restored afterwards, see BPGraph::snapshot.
+    //E.g., to score a model on held-out observations.
+    pub fn log_likelihood_of_evidence(
+        &mut self,
+        evidence: &[(NodeIndex, T)],
+        steps: usize,
+        max_assignments: usize,
+    ) -> BPResult<f64> {
+        let state = self.snapshot();
+        let res = self.evidence_log_partitions(evidence, steps, max_assignments);
+        self.restore(state)?;
+        let (log_z, log_z_evidence) = res.map_err(|e| {
+            e.attach_info_str(
+                "BPGraph::log_likelihood_of_evidence",
+                "Could not compute the log partition functions".to_owned(),
+            )
+        })?;
+        Ok(log_z_evidence - log_z)
+    }
+
+    fn evidence_log_partitions(
+        &mut self,
+        evidence: &[(NodeIndex, T)],
+        steps: usize,
+        max_assignments: usize,
+    ) -> BPResult<(f64, f64)> {
+        self.propagate_until_variables_have_post(steps)?;
+        let log_z = self.bethe_log_partition(max_assignments)?;
+        for (node, value) in evidence {
+            if self.is_factor(*node)? {
+                return Err(BPError::new(
+                    "BPGraph::log_likelihood_of_evidence".to_owned(),
+                    format!("Cannot clamp factor {}", node),
+                ));
+            }
+            let old_prior = self.get_node(*node)?.get_prior();
+            //Every other value of the domain gets an explicit 0, missing values would not be restricted
+            let domain: Vec<T> = match &old_prior {
+                Some(prior) => prior.clone().into_iter().map(|(v, _)| v).collect(),
+                None => self
+                    .get_result(*node)?
+                    .map(|belief| belief.into_keys().collect())
+                    .unwrap_or_default(),
+            };
+            if !domain.contains(value) {
+                return Err(BPError::new(
+                    "BPGraph::log_likelihood_of_evidence".to_owned(),
+                    format!("Value {:?} is not in the domain of node {}", value, node),
+                ));
+            }
+            let mut prior = MsgT::new();
+            for v in domain {
+                prior.insert(v, 0.0);
+            }
+            let p = old_prior
+                .as_ref()
+                .and_then(|p| p.get(*value))
+                .unwrap_or(1.0);
+            prior.insert(*value, p);
+            self.send_control_message(*node, VariableNodeCtrl::SetPrior(Some(prior)).into())?;
+        }
+        self.propagate_until_variables_have_post(steps)?;
+        let log_z_evidence = self.bethe_log_partition(max_assignments)?;
+        Ok((log_z, log_z_evidence))
+    }
+}
+
+impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default> BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>
+where
+    T: Copy + Eq + Hash + Debug,
+    MsgT: Clone,
+{
+    //Propagates steps steps and one more if a variable has not received messages from all factors afterwards
+    pub(crate) fn propagate_until_variables_have_post(&mut self, steps: usize) -> BPResult<()> {
+        self.propagate(steps)?;
+        let complete = self
+            .nodes()
+            .filter(|(_, _, is_factor)| !is_factor)
+            .all(|(i, _, _)| {
+                self.get_node(i)
+                    .is_ok_and(|n| n.has_post_from_all_connections())
+            });
+        if !complete {
+            self.propagate(1)?;
+        }
+        Ok(())
+    }
+
+    //Belief of a factor (normalized) from the potential and the cavity distributions of its variables,
+    //i.e., their beliefs without the message of the factor, as (values, belief, potential) for every assignment.
+    //The values are in the order of the connections.
+    pub(crate) fn cavity_factor_belief(
+        &self,
+        factor: NodeIndex,
+        max_assignments: usize,
+    ) -> BPResult<Vec<(Vec<T>, Probability, Probability)>> {
+        let node = self.get_node(factor)?;
+        let mut cavities: Vec<Vec<(T, Probability)>> = Vec::new();
+        for var in node.get_connections() {
+            let options = ResultOptions {
+                include_prior: true,
+                exclude_neighbors: &[factor],
+            };
+            let cavity = match self.get_result_with_options(*var, &options)? {
+                Some(cavity) => cavity.into_iter().collect(),
+                //The factor is the only source of information, the cavity is uniform
+                None => self
+                    .get_result(*var)?
+                    .map(|belief| belief.into_keys().map(|v| (v, 1.0)).collect())
+                    .unwrap_or_default(),
+            };
+            cavities.push(cavity);
+        }
+        let count = cavities
+            .iter()
+            .try_fold(1usize, |acc, c| acc.checked_mul(c.len()))
+            .filter(|count| *count <= max_assignments)
+            .ok_or_else(|| {
+                BPError::new(
+                    "BPGraph::cavity_factor_belief".to_owned(),
+                    format!(
+                        "Factor {} has more than {} assignments",
+                        factor, max_assignments
+                    ),
+                )
+            })?;
+        let mut belief = Vec::with_capacity(count);
+        let mut idx = vec![0usize; cavities.len()];
+        for _ in 0..count {
+            let values: Vec<T> = idx.iter().zip(&cavities).map(|(i, c)| c[*i].0).collect();
+            let psi = node.potential(&values).ok_or_else(|| {
+                BPError::new(
+                    "BPGraph::cavity_factor_belief".to_owned(),
+                    format!("Factor {} does not implement potential", factor),
+                )
+            })?;
+            let p = idx.iter().zip(&cavities).fold(psi, |p, (i, c)| p * c[*i].1);
+            belief.push((values, p, psi));
+            for (i, c) in idx.iter_mut().zip(&cavities) {
+                *i += 1;
+                if *i < c.len() {
+                    break;
+                }
+                *i = 0;
+            }
+        }
+        let sum: Probability = belief.iter().map(|(_, p, _)| p).sum();
+        if !(sum > 0.0 && sum.is_finite()) {
+            return Err(BPError::new(
+                "BPGraph::cavity_factor_belief".to_owned(),
+                format!(
+                    "Belief of factor {} cannot be normalized (sum: {})",
+                    factor, sum
+                ),
+            ));
+        }
+        belief.iter_mut().for_each(|(_, p, _)| *p /= sum);
+        Ok(belief)
+    }
+}
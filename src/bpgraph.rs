@@ -1,26 +1,328 @@
 #[cfg(feature = "progress_output")]
 use std::io::{self, Write};
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::default::Default;
 use std::fmt::Debug;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::{BPError, BPResult, Msg, Node, NodeFunction, Probability};
+use crossbeam::deque::{Steal, Stealer, Worker};
+
+use crate::metrics::MetricsEmitter;
+use crate::node::{msg_to_hashmap, NodeState};
+use crate::thread_pool::{run_on_threads, BatchStrategy};
+use crate::variable_node::{FromVariableNodeCtrlAnswer, IntoVariableNodeCtrl};
+use crate::{gpu, semiring};
+use crate::{
+    BPError, BPResult, DenseMsg, EqualityFactor, GraphInfo, HistoryFormat, InboxPolicy, InputNeed,
+    MarginalChange, Msg, MsgHistory, MsgPool, MsgTransform, Node, NodeFunction, NormalizationMode,
+    Probability, ResultOptions, ResultOrder, Scheduler, Semiring, SplitMix64, TableFactor,
+    ThreadPool, ThreadingConfig, TimeWindow, ValidationIssue, VariableNode, ZeroMessagePolicy,
+};
 
 pub type NodeIndex = usize;
 
+fn count_messages<MsgT>(msgs: &[(NodeIndex, Vec<(NodeIndex, MsgT)>)]) -> usize {
+    msgs.iter().map(|(_, msgs)| msgs.len()).sum()
+}
+
+type Batch<'a, T, MsgT, CtrlMsgT, CtrlMsgAT> =
+    Vec<(NodeIndex, &'a mut Node<T, MsgT, CtrlMsgT, CtrlMsgAT>)>;
+
+//BatchStrategy::WorkStealing for BPGraph::create_messages_threaded. Every thread works on the batches in its own
+//deque and steals from the other threads once it runs empty.
+#[allow(clippy::type_complexity)]
+fn create_messages_work_stealing<'a, T, MsgT, CtrlMsgT, CtrlMsgAT: Default>(
+    nodes: Batch<'a, T, MsgT, CtrlMsgT, CtrlMsgAT>,
+    pool: Option<&ThreadPool>,
+    config: ThreadingConfig,
+    thread_count: u32,
+    cancel: Option<&(dyn Fn() -> bool + Sync)>,
+    step: usize,
+) -> BPResult<(Vec<(NodeIndex, Vec<(NodeIndex, MsgT)>)>, bool)>
+where
+    T: Send + Sync + Debug,
+    MsgT: Msg<T> + Send + Sync,
+{
+    let thread_count = thread_count.max(1);
+    let batch_size = config.batch_size(nodes.len(), thread_count);
+    let mut batches: Vec<Batch<'a, T, MsgT, CtrlMsgT, CtrlMsgAT>> = Vec::new();
+    let mut nodes = nodes.into_iter().peekable();
+    while nodes.peek().is_some() {
+        batches.push(nodes.by_ref().take(batch_size).collect());
+    }
+    thread_print!("{} batches of at most {} nodes", batches.len(), batch_size);
+    //Contiguous shares, neighbouring nodes tend to be similarly expensive
+    let share = batches.len() / thread_count as usize + 1;
+    let workers: Vec<Worker<Batch<'a, T, MsgT, CtrlMsgT, CtrlMsgAT>>> =
+        (0..thread_count).map(|_| Worker::new_fifo()).collect();
+    for (i, batch) in batches.into_iter().enumerate() {
+        workers[i / share].push(batch);
+    }
+    let stealers: Vec<Stealer<Batch<'a, T, MsgT, CtrlMsgT, CtrlMsgAT>>> =
+        workers.iter().map(|w| w.stealer()).collect();
+    //Worker is not Sync, every thread takes out its own
+    let workers: Vec<Mutex<Option<Worker<Batch<'a, T, MsgT, CtrlMsgT, CtrlMsgAT>>>>> =
+        workers.into_iter().map(|w| Mutex::new(Some(w))).collect();
+    let steal = |i: usize, local: &Worker<Batch<'a, T, MsgT, CtrlMsgT, CtrlMsgAT>>| loop {
+        let mut retry = false;
+        for j in 1..stealers.len() {
+            match stealers[(i + j) % stealers.len()].steal_batch_and_pop(local) {
+                Steal::Success(batch) => return Some(batch),
+                Steal::Retry => retry = true,
+                Steal::Empty => {}
+            }
+        }
+        if !retry {
+            return None;
+        }
+    };
+    let thread_results = run_on_threads(pool, thread_count, |i| {
+        let local = workers[i as usize]
+            .lock()
+            .expect("Locking mutex failed.")
+            .take()
+            .expect("Deque already taken");
+        let mut thread_msgs = Vec::new();
+        while let Some(batch) = local.pop().or_else(|| steal(i as usize, &local)) {
+            thread_print!("Thread {} working on {} nodes..", i, batch.len());
+            for (idx, node) in batch {
+                thread_msgs.push((
+                    idx,
+                    node.create_messages().map_err(|e| {
+                        e.attach_debug_object("idx (node index)", idx)
+                            .attach_debug_object("node.get_name() (node name)", node.get_name())
+                            .attach_debug_object("step", step)
+                    })?,
+                ));
+            }
+            if cancel.is_some_and(|c| c()) {
+                thread_print!("Thread {} cancelled.", i);
+                break;
+            }
+        }
+        thread_print!("Thread {} finished.", i);
+        Ok(thread_msgs)
+    });
+    let mut result = Vec::new();
+    for res in thread_results {
+        result.extend(res?);
+    }
+    let complete = stealers.iter().all(|s| s.is_empty());
+    Ok((result, complete))
+}
+
+//An all-zero message recorded with ZeroMessagePolicy::MarkContradiction: the evidence reaching from
+//contradicts itself, so from could not send a message to to in step
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Contradiction {
+    pub from: NodeIndex,
+    pub to: NodeIndex,
+    pub step: usize,
+}
+
+//Summary of a propagation step, returned by BPGraph::propagate_step, BPGraph::propagate_step_threaded and
+//BPGraph::propagate_step_with_scheduler. A step in which no node fired means that the schedule stalled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StepReport {
+    pub step: usize,
+    //Nodes that created messages
+    pub nodes_fired: usize,
+    //Messages created in the step (including messages dropped as all-zero, see ZeroMessagePolicy)
+    pub messages_sent: usize,
+    //Nodes considered in the step (all nodes or the scheduled ones) that were not ready
+    pub skipped_not_ready: usize,
+    pub duration: Duration,
+}
+
+impl StepReport {
+    pub fn is_stalled(&self) -> bool {
+        self.nodes_fired == 0
+    }
+}
+
+//Readiness of a node in a step, see BPGraph::ready_report
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeReadiness {
+    pub node: NodeIndex,
+    pub name: String,
+    pub ready: bool,
+    //Messages in the inbox
+    pub received: usize,
+    pub connections: usize,
+    //Messages needed to be ready, None if unknown (see NodeFunction::inputs_needed).
+    //Factors that do not report it are assumed to need all inputs.
+    pub needed: Option<usize>,
+    pub input_need: Option<InputNeed>,
+}
+
+//Raises msg to the power 1 / temperature, which multiplies log-probabilities and costs by 1 / temperature
+fn temper<T, MsgT: Msg<T>>(msg: &mut MsgT, temperature: f64, kind: semiring::SemiringKind) {
+    if temperature == 1.0 {
+        return;
+    }
+    let beta = 1.0 / temperature;
+    match kind {
+        semiring::SemiringKind::MinSum | semiring::SemiringKind::MaxSum => {
+            msg.for_each(|p| p * beta)
+        }
+        _ => msg.for_each(|p| p.powf(beta)),
+    }
+}
+
+//Normalizes a message before it is delivered and applies policy to all-zero messages.
+//Returns false if the message must not be delivered.
+fn normalize_outgoing<T, MsgT: Msg<T>>(
+    msg: &mut MsgT,
+    normalization: NormalizationMode,
+    policy: ZeroMessagePolicy,
+    from: NodeIndex,
+    to: NodeIndex,
+    step: usize,
+) -> BPResult<bool> {
+    if normalization == NormalizationMode::None {
+        return Ok(true);
+    }
+    let err = match msg.normalize_with(normalization) {
+        Ok(()) => return Ok(true),
+        Err(err) => err,
+    };
+    let is_zero = policy != ZeroMessagePolicy::Error && normalization.is_probability() && {
+        let (mut count, mut zero) = (0usize, true);
+        msg.for_each(|p| {
+            count += 1;
+            zero &= p == 0.0;
+            p
+        });
+        count > 0 && zero
+    };
+    if !is_zero {
+        return Err(err
+            .attach_info_str(
+                "BPGraph::send",
+                format!("Trying to normalize message {} -> {}.", from, to),
+            )
+            .attach_debug_object("msg (the message that could not be normalized)", &msg)
+            .attach_debug_object("step", step));
+    }
+    match policy {
+        ZeroMessagePolicy::ReplaceWithUniform => {
+            msg.for_each(|_| 1.0);
+            msg.normalize_with(normalization)?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeFilter {
+    All,
+    Factors,
+    Variables,
+}
+
+impl NodeFilter {
+    pub fn matches(&self, is_factor: bool) -> bool {
+        match self {
+            NodeFilter::All => true,
+            NodeFilter::Factors => is_factor,
+            NodeFilter::Variables => !is_factor,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PropagationState {
+    Finished,
+    //If partial_step is set, only some nodes have sent their messages in the last step.
+    //Nodes that were not processed keep their inbox.
+    Cancelled {
+        completed_steps: usize,
+        partial_step: bool,
+    },
+}
+
+//State of the propagation of a graph, see BPGraph::snapshot
+pub struct GraphState<MsgT> {
+    step: usize,
+    nodes: Vec<NodeState<MsgT>>,
+    dirty: BTreeSet<NodeIndex>,
+}
+
+impl<MsgT> GraphState<MsgT> {
+    pub fn step(&self) -> usize {
+        self.step
+    }
+}
+
 pub struct BPGraph<T, MsgT: Msg<T>, CtrlMsgT = (), CtrlMsgAT: Default = ()>
 where
     T: Debug,
 {
     nodes: Vec<Node<T, MsgT, CtrlMsgT, CtrlMsgAT>>,
     step: usize,
-    normalize: bool,
+    normalization: NormalizationMode,
+    zero_message_policy: ZeroMessagePolicy,
+    //Recorded with ZeroMessagePolicy::MarkContradiction
+    contradictions: Vec<Contradiction>,
     check_validity: bool,
+    //Set by set_strict, inboxes are checked before the nodes create their messages
+    strict: bool,
+    deterministic: bool,
+    msg_pool: MsgPool<MsgT>,
+    //Set by set_track_marginals, computes the marginal of a node after every step
+    marginal_fn: Option<MarginalFn<T, MsgT, CtrlMsgT, CtrlMsgAT>>,
+    previous_marginals: Vec<Option<HashMap<T, Probability>>>,
+    current_marginals: Vec<Option<HashMap<T, Probability>>>,
+    //Set by set_incremental, nodes then keep the last received messages
+    clone_msg: Option<fn(&MsgT) -> MsgT>,
+    //Nodes changed after the propagation has started, see propagate_incremental
+    dirty: BTreeSet<NodeIndex>,
+    //Appearance probabilities of the factors for tree-reweighted BP, 1 if not set
+    edge_weights: HashMap<NodeIndex, f64>,
+    //Set by set_edge_transform, keyed by (from, to). Shared by clones of the graph.
+    edge_transforms: HashMap<(NodeIndex, NodeIndex), Arc<dyn MsgTransform<T, MsgT>>>,
+    //Overrides of normalization for the messages sent by factors / variables (see set_sender_normalization)
+    //and for single edges keyed by (from, to) (see set_edge_normalization)
+    factor_normalization: Option<NormalizationMode>,
+    variable_normalization: Option<NormalizationMode>,
+    edge_normalization: HashMap<(NodeIndex, NodeIndex), NormalizationMode>,
+    //Set by set_history_recording
+    history: Option<MsgHistory<MsgT>>,
+    //Set by set_metrics_output
+    metrics: Option<MetricsEmitter<T>>,
+    //Workers used by the threaded propagation instead of spawning threads in every step
+    thread_pool: Option<Arc<ThreadPool>>,
+    //Set by set_threading_config
+    threading_config: ThreadingConfig,
+    //Set by set_semiring, passed to all nodes
+    semiring: Option<Arc<dyn Semiring>>,
+    //Messages are raised to the power 1 / temperature when sent, see set_temperature
+    temperature: f64,
+    //Decay of the temperature towards 1 after every step, see set_annealing
+    annealing: Option<f64>,
+    //(parent, child), set by set_edge_directed
+    directed: HashSet<(NodeIndex, NodeIndex)>,
+    //Nodes changed since the structure was last validated (see set_check_validity),
+    //None if the whole graph has to be validated
+    unvalidated: Option<BTreeSet<NodeIndex>>,
+    //Set by set_window
+    window: Option<TimeWindow>,
+    //Control messages by the step before which they are delivered, see send_control_message_at
+    scheduled_ctrl: BTreeMap<usize, Vec<(NodeIndex, CtrlMsgT)>>,
+    //(step, node, answer) of the delivered scheduled control messages
+    scheduled_answers: Vec<(usize, NodeIndex, CtrlMsgAT)>,
+    //Set by initialize, adding edges fails until unseal is called
+    sealed: bool,
 }
 
+type MarginalFn<T, MsgT, CtrlMsgT, CtrlMsgAT> =
+    fn(&Node<T, MsgT, CtrlMsgT, CtrlMsgAT>) -> BPResult<Option<HashMap<T, Probability>>>;
+
 impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default> BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>
 where
     T: Debug,
@@ -31,348 +333,1982 @@ where
         node_index: NodeIndex,
         msg: MsgT,
     ) -> BPResult<()> {
-        let n = self.get_node_mut(node_index)?;
+        let len = self.len();
+        let n = self.nodes.get_mut(node_index).ok_or_else(|| {
+            BPError::new(
+                "BPGraph::initialize_node_constant_msg".to_owned(),
+                format!("Index {} out of bounds ({})", node_index, len),
+            )
+        })?;
         for m_i in n.get_connections().clone() {
-            n.send_post(m_i, msg.clone());
+            let mut m = self.msg_pool.take();
+            m.clone_from(&msg);
+            n.send_post(m_i, m);
         }
         n.initialize()?;
         Ok(())
     }
-}
-
-impl<T, MsgT: Msg<T> + Clone, CtrlMsgT, CtrlMsgAT: Default> BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>
-where
-    T: Copy + Eq + Debug + std::hash::Hash,
-    MsgT: Clone,
-{
-    pub fn get_result(
-        &self,
-        node_index: NodeIndex,
-    ) -> BPResult<Option<std::collections::HashMap<T, Probability>>> {
-        let n = self.get_node(node_index)?;
-        n.get_result().map_err(|e| {
-            e.attach_info_str(
-                "BPGraph::get_result",
-                format!("Failed to retrieve result from node {}", node_index),
-            )
-        })
-    }
-}
 
-impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default> BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>
-where
-    T: Send + Sync + Debug,
-    MsgT: Send + Sync,
-{
-    //msgs: [(from, [(to, msg)])]
-    fn send_threaded(
-        &mut self,
-        msgs: Vec<(NodeIndex, Vec<(NodeIndex, MsgT)>)>,
-        thread_count: u32,
-    ) -> BPResult<()> {
-        let normalize = self.normalize;
-        let check_validity = self.check_validity;
-        let step = self.step;
-        let mut nodes: Vec<Arc<Mutex<&mut Node<T, MsgT, CtrlMsgT, CtrlMsgAT>>>> = self
-            .nodes
-            .iter_mut()
-            .map(|n| Arc::new(Mutex::new(n)))
-            .collect();
-        let ln_msgs = msgs.len();
-        let mut msgs = Arc::new(Mutex::new(msgs));
-        let min_batch_size = 5;
-        #[cfg(feature = "progress_output")]
-        let (whitespace_padding, step) = {
-            let max_diff_in_number = f64::log10(ln_msgs as f64) as usize + 1;
-            (
-                &(std::iter::repeat(" ")
-                    .take(max_diff_in_number)
-                    .collect::<String>()),
-                self.step.clone(),
+    //Stops the message flow across the edge between a variable and a factor without removing it, e.g., to
+    //ignore one leakage model in an ablation study. Both nodes keep a message with all entries set to one
+    //(in the semiring of the graph) over the domain of the variable (given by its prior) in their inbox in
+    //place of the messages of the other node. Thus, both still see all their connections (and checks of the
+    //number of inputs pass), the factor sums over the variable and the variable ignores the factor.
+    pub fn disable_edge(&mut self, node0: NodeIndex, node1: NodeIndex) -> BPResult<()> {
+        let (variable, factor) = self.edge_endpoints(node0, node1, "BPGraph::disable_edge")?;
+        let one = semiring::one(self.semiring.as_deref());
+        let mut neutral = self.get_node(variable)?.get_prior().ok_or_else(|| {
+            BPError::new(
+                "BPGraph::disable_edge".to_owned(),
+                format!(
+                    "Variable node {} has no prior to derive the neutral message from",
+                    variable
+                ),
             )
-        };
-        crossbeam::scope(|scope| {
-            let mut handles = Vec::with_capacity(thread_count as usize);
-            for i in 0..thread_count {
-                handles.push(scope.spawn(|_| {
-                    loop {
-                        let chunck: Vec<(NodeIndex, Vec<(NodeIndex, MsgT)>)> = {
-                            thread_print!("Thread {} waiting for lock..", i);
-                            let mut msgs = msgs.lock().expect("Locking mutex failed.");
-                            thread_print!("Thread {} has lock..", i);
-                            let len = msgs.len();
-                            if len == 0 {
-                                break;
-                            }
-
-                            #[cfg(feature = "progress_output")]
-                            {
-                                print!(
-                                    "Step {}: {} nodes left{}\r",
-                                    step,
-                                    nodes.len(),
-                                    &whitespace_padding
-                                );
-                                std::io::stdout().flush();
-                            }
-                            let mut batch_size = std::cmp::max(
-                                min_batch_size,
-                                msgs.len() / (2 * thread_count) as usize,
-                            ); //TODO
-                            let chunck = msgs
-                                .drain(0..std::cmp::min(batch_size as usize, len))
-                                .collect();
-                            chunck
-                        };
-
-                        for (from, mut msgmap) in chunck.into_iter() {
-                            for (to, mut msg) in msgmap.into_iter() {
-                                debug_print!("Sending from {} to {}", from, to);
-                                {
-                                    if check_validity && !msg.is_valid() {
-                                        return Err(BPError::new(
-                                            "BPGraph::send".to_owned(),
-                                            format!("Trying to send an invalid message ({} -> {})", from, to),
-                                        )
-                                        .attach_debug_object("msg (the invalid message)", &msg)
-                                        .attach_debug_object("step", step));
-                                    }
-                                    if normalize {
-                                        msg.normalize().map_err(|e| {
-                                            e.attach_info_str(
-                                                "BPGraph::send",
-                                                format!("Trying to normalize message {} -> {}.", from, to),
-                                            )
-                                            .attach_debug_object("msg (the message that could not be normalized)", &msg)
-                                            .attach_debug_object("step", step)
-                                        })?;
-                                    }
-                                }
-                                let mut nto = nodes[to].lock().expect("Locking node failed");
-                                if !nto.get_connections().contains(&from) {
-                                    return Err(BPError::new(
-                                        "BPGraph::send".to_owned(),
-                                        format!(
-                                            "Trying to send a message along a non-existent edge ({} -> {}).",
-                                            from, to
-                                        ),
-                                    )
-                                    .attach_debug_object("step", step)
-                                    .attach_debug_object("edges", nto.get_connections())
-                                    .attach_debug_object("name of node to sending to", nto.get_name()));
-                                }
-                                nto.send_post(from, msg);
-                            }
-                        }
-                    }
-                    Ok(())
-                }));
-            }
-            for handle in handles {
-                handle.join().expect("Joining threads failed")?;
-            }
-            #[cfg(feature = "progress_output")]
-            {
-                let whitespace_padding2 = std::iter::repeat(" ").take(30).collect::<String>(); //Not very elegant...
-                print!("{}{}\r", whitespace_padding2, &whitespace_padding);
-                std::io::stdout().flush();
-            }
-            Ok(())
-        }).expect("Scoped threading failed")
+        })?;
+        neutral.for_each(|_| one);
+        self.get_node_mut(factor)?
+            .disable_connection(variable, neutral.clone())?;
+        self.get_node_mut(variable)?
+            .disable_connection(factor, neutral)?;
+        self.mark_changed(variable);
+        self.mark_changed(factor);
+        Ok(())
     }
 
-    fn create_messages_threaded(
-        &mut self,
-        thread_count: u32,
-    ) -> BPResult<Vec<(NodeIndex, Vec<(NodeIndex, MsgT)>)>> {
-        info_print!("Creating messages with {} threads..", thread_count);
-        let step = self.step;
-        let mut nodes_ = Vec::new();
-        for (i, n) in self.nodes.iter_mut().enumerate() {
-            if n.is_ready(step)? {
-                nodes_.push((i, n));
-            }
-            else {
-                n.read_post();
-            }
+    //Restores the message flow across an edge disabled by disable_edge. The nodes receive messages across
+    //the edge from the next step on, until then they use the neutral messages.
+    pub fn enable_edge(&mut self, node0: NodeIndex, node1: NodeIndex) -> BPResult<()> {
+        let (variable, factor) = self.edge_endpoints(node0, node1, "BPGraph::enable_edge")?;
+        if !self.get_node_mut(factor)?.enable_connection(variable) {
+            return Err(BPError::new(
+                "BPGraph::enable_edge".to_owned(),
+                format!("Edge ({}, {}) is not disabled", node0, node1),
+            ));
         }
-        let mut min_batch_size = 5;
-        #[cfg(feature = "progress_output")]
-        let (whitespace_padding, step) = {
-            let max_diff_in_number = f64::log10(nodes_.len() as f64) as usize + 1;
-            (
-                &(std::iter::repeat(" ")
-                    .take(max_diff_in_number)
-                    .collect::<String>()),
-                self.step.clone(),
-            )
-        };
-        thread_print!("Minimal batch size is {}", min_batch_size);
-        let mut nodes = Arc::new(Mutex::new(nodes_));
+        self.get_node_mut(variable)?.enable_connection(factor);
+        self.mark_changed(variable);
+        self.mark_changed(factor);
+        Ok(())
+    }
 
-        crossbeam::scope(|scope| {
-            let mut handles = Vec::with_capacity(thread_count as usize);
-            let mut result = Vec::new();
-            for i in 0..thread_count {
-                //Force capture by ref
-                let nodes = &nodes;
-                handles.push(scope.spawn(move |_| {
-                    let mut thread_msgs = Vec::new();
-                    loop {
-                        //nodes is locked in this block
-                        let chunck: Vec<(NodeIndex, &mut Node<T, MsgT, CtrlMsgT, CtrlMsgAT>)> = {
-                            thread_print!("Thread {} waiting for lock..", i);
-                            let mut nodes = nodes.lock().expect("Locking mutex failed.");
-                            thread_print!("Thread {} has lock..", i);
-                            let len = nodes.len();
-                            if len == 0 {
-                                break;
-                            }
-
-                            #[cfg(feature = "progress_output")]
-                            {
-                                print!(
-                                    "Step {}: {} nodes left{}\r",
-                                    step,
-                                    nodes.len(),
-                                    &whitespace_padding
-                                );
-                                std::io::stdout().flush();
-                            }
-                            let mut batch_size = std::cmp::max(
-                                min_batch_size,
-                                nodes.len() / (2 * thread_count) as usize,
-                            ); //TODO
-                            let chunck = nodes
-                                .drain(0..std::cmp::min(batch_size as usize, len))
-                                .collect();
-                            chunck
-                        };
-                        thread_print!("Thread {} working on {} nodes..", i, chunck.len());
-                        if chunck.is_empty() {
-                            break;
-                        }
-                        for (idx, node) in chunck {
-                            thread_msgs.push((
-                                idx,
-                                node.create_messages().map_err(|e| {
-                                    e.attach_debug_object("idx (node index)", idx)
-                                        .attach_debug_object(
-                                            "node.get_name() (node name)",
-                                            node.get_name(),
-                                        )
-                                        .attach_debug_object("step", step)
-                                })?,
-                            ));
-                        }
-                    }
-                    thread_print!("Thread {} finished.", i);
-                    Ok(thread_msgs)
-                }));
-            }
-            for handle in handles {
-                result.extend(handle.join().expect("Joining threads failed")?);
-            }
-            #[cfg(feature = "progress_output")]
-            {
-                let whitespace_padding2 = std::iter::repeat(" ").take(30).collect::<String>(); //Not very elegant...
-                print!("{}{}\r", whitespace_padding2, &whitespace_padding);
-                std::io::stdout().flush();
-            }
-            Ok(result)
-        })
-        .expect("Scoped threading failed.")
+    pub fn is_edge_disabled(&self, node0: NodeIndex, node1: NodeIndex) -> BPResult<bool> {
+        let (variable, factor) = self.edge_endpoints(node0, node1, "BPGraph::is_edge_disabled")?;
+        Ok(self.get_node(factor)?.is_disabled(variable))
     }
 
-    pub fn propagate_step_threaded(&mut self, thread_count: u32) -> BPResult<()> {
-        if self.check_validity && !self.is_valid() {
+    //(variable, factor) of an existing edge
+    fn edge_endpoints(
+        &self,
+        node0: NodeIndex,
+        node1: NodeIndex,
+        fn_name: &str,
+    ) -> BPResult<(NodeIndex, NodeIndex)> {
+        let n0 = self.get_node(node0)?;
+        if !n0.is_connected(node1) {
             return Err(BPError::new(
-                "propagate_step_threaded".to_owned(),
-                "Graph is invalid".to_owned(),
+                fn_name.to_owned(),
+                format!("There is no edge ({}, {})", node0, node1),
             ));
         }
-        info_print!("Propagating step {}..", self.step);
-        debug_print!("Creating messages..");
-        let outgoing_msgs = self.create_messages_threaded(thread_count)?;
-        info_print!("Sending messages (threaded)");
-        self.send_threaded(outgoing_msgs, thread_count)?;
-        info_print!("Done propagating step {}\n", self.step);
-        self.step += 1;
-        Ok(())
+        Ok(if n0.is_factor() {
+            (node1, node0)
+        } else {
+            (node0, node1)
+        })
     }
 
-    pub fn propagate_threaded(&mut self, steps: usize, thread_count: u32) -> BPResult<()> {
-        if !self.is_initialized() {
+    //Records the messages sent in every every-th step, keeping only the last capacity messages.
+    //Replaces the current history.
+    pub fn set_history_recording(&mut self, every: usize, capacity: usize) {
+        self.history = Some(MsgHistory::new(every, capacity, MsgT::clone));
+    }
+
+    pub fn disable_history_recording(&mut self) {
+        self.history = None;
+    }
+
+    pub fn get_history(&self) -> Option<&MsgHistory<MsgT>> {
+        self.history.as_ref()
+    }
+
+    pub fn dump_history<W: std::io::Write>(
+        &self,
+        writer: &mut W,
+        format: HistoryFormat,
+    ) -> BPResult<()> {
+        self.history
+            .as_ref()
+            .ok_or_else(|| {
+                BPError::new(
+                    "BPGraph::dump_history".to_owned(),
+                    "History is not recorded (see set_history_recording)".to_owned(),
+                )
+            })?
+            .dump(writer, format)
+    }
+
+    //Copies the inboxes, the step counter and the state of the node functions (see NodeFunction::save_state),
+    //e.g., to try clamping a variable and roll back with restore if the propagation fails.
+    //Structure and settings of the graph are not part of the snapshot.
+    pub fn snapshot(&self) -> GraphState<MsgT> {
+        GraphState {
+            step: self.step,
+            nodes: self.nodes.iter().map(|n| n.save_state()).collect(),
+            dirty: self.dirty.clone(),
+        }
+    }
+
+    //Returns to a state taken by snapshot. Fails if nodes were added or removed since.
+    //Tracked marginals (see set_track_marginals) are cleared.
+    pub fn restore(&mut self, state: GraphState<MsgT>) -> BPResult<()> {
+        if state.nodes.len() != self.nodes.len() {
             return Err(BPError::new(
-                "propagate_threaded".to_owned(),
-                "Graph is not initialized".to_owned(),
+                "BPGraph::restore".to_owned(),
+                format!(
+                    "Snapshot has {} nodes, graph has {}",
+                    state.nodes.len(),
+                    self.nodes.len()
+                ),
             ));
         }
-        for _ in 0..steps {
-            self.propagate_step_threaded(thread_count)?;
+        let pool = &mut self.msg_pool;
+        for (i, (node, node_state)) in self.nodes.iter_mut().zip(state.nodes).enumerate() {
+            node.load_state(node_state, pool).map_err(|e| {
+                e.attach_info_str("BPGraph::restore", format!("Could not restore node {}", i))
+            })?;
         }
+        self.step = state.step;
+        self.dirty = state.dirty;
+        self.previous_marginals.clear();
+        self.current_marginals.clear();
         Ok(())
     }
-    pub fn factor_nodes_count(&self) -> usize {
-        self.nodes.iter().filter(|&n| n.is_factor()).count()
-    }
-    pub fn variable_nodes_count(&self) -> usize {
-        self.nodes.iter().filter(|&n| !n.is_factor()).count()
-    }
-    pub fn nodes_count(&self) -> usize {
-        self.nodes.len()
+
+    //Independent copy of the graph including messages, priors and settings, e.g., to run the same graph from
+    //several random initializations in parallel. Fails if a node function cannot be cloned (see
+    //NodeFunction::clone_box). Edge transforms and the thread pool are shared with the copy, the message pool
+    //starts empty, metrics are not written for the copy and scheduled control messages are not copied.
+    pub fn try_clone(&self) -> BPResult<Self>
+    where
+        T: Clone + 'static,
+        MsgT: 'static,
+        CtrlMsgT: 'static,
+        CtrlMsgAT: 'static,
+    {
+        let nodes = self
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(i, n)| {
+                n.try_clone().map_err(|e| {
+                    e.attach_info_str("BPGraph::try_clone", format!("Could not clone node {}", i))
+                })
+            })
+            .collect::<BPResult<Vec<_>>>()?;
+        Ok(BPGraph {
+            nodes,
+            step: self.step,
+            normalization: self.normalization,
+            zero_message_policy: self.zero_message_policy,
+            contradictions: self.contradictions.clone(),
+            strict: self.strict,
+            check_validity: self.check_validity,
+            deterministic: self.deterministic,
+            msg_pool: MsgPool::new(self.msg_pool.max_size()),
+            marginal_fn: self.marginal_fn,
+            previous_marginals: self.previous_marginals.clone(),
+            current_marginals: self.current_marginals.clone(),
+            clone_msg: self.clone_msg,
+            dirty: self.dirty.clone(),
+            edge_weights: self.edge_weights.clone(),
+            edge_transforms: self.edge_transforms.clone(),
+            factor_normalization: self.factor_normalization,
+            variable_normalization: self.variable_normalization,
+            edge_normalization: self.edge_normalization.clone(),
+            history: self.history.clone(),
+            metrics: None,
+            thread_pool: self.thread_pool.clone(),
+            threading_config: self.threading_config,
+            semiring: self.semiring.clone(),
+            temperature: self.temperature,
+            annealing: self.annealing,
+            directed: self.directed.clone(),
+            unvalidated: self.unvalidated.clone(),
+            window: self.window.clone(),
+            scheduled_ctrl: BTreeMap::new(),
+            scheduled_answers: Vec::new(),
+            sealed: self.sealed,
+        })
     }
 }
 
 impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default> BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>
 where
-    T: Clone + Debug,
+    T: Copy + Ord + Debug,
     MsgT: Clone,
 {
-    pub fn get_inbox(&self, node_index: NodeIndex) -> BPResult<Vec<(NodeIndex, MsgT)>> {
-        let node = self.get_node(node_index)?;
-        Ok(node.clone_inbox())
+    //Sends a random message along every edge in both directions and initializes the graph.
+    //Every probability of the message is drawn uniformly from [1 - noise, 1], the values are those of the prior
+    //of the variable node. This breaks the symmetry of symmetric models, where uniform messages get stuck.
+    //The messages only depend on seed and the structure of the graph.
+    pub fn initialize_random(&mut self, seed: u64, noise: Probability) -> BPResult<()> {
+        if !(0.0..=1.0).contains(&noise) {
+            return Err(BPError::new(
+                "BPGraph::initialize_random".to_owned(),
+                format!("Noise has to be in [0, 1] (got {})", noise),
+            ));
+        }
+        let mut rng = SplitMix64::new(seed);
+        for var in 0..self.len() {
+            if self.nodes[var].is_factor() {
+                continue;
+            }
+            let prior = self.nodes[var].get_prior().ok_or_else(|| {
+                BPError::new(
+                    "BPGraph::initialize_random".to_owned(),
+                    format!("Variable node {} has no prior to take the values from", var),
+                )
+            })?;
+            //Sorted, as the iteration order of a message may not be deterministic
+            let mut values: Vec<T> = prior.clone().into_iter().map(|(v, _)| v).collect();
+            values.sort_unstable();
+            for factor in self.nodes[var].get_connections().clone() {
+                for (from, to) in [(var, factor), (factor, var)] {
+                    let mut msg = self.msg_pool.take();
+                    msg.clone_from(&prior);
+                    for v in &values {
+                        if let Some(p) = msg.get_mut(*v) {
+                            *p = 1.0 - noise * rng.next_f64();
+                        }
+                    }
+                    self.get_node_mut(to)?.send_post(from, msg);
+                }
+            }
+        }
+        self.initialize()
     }
 }
 
-impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default> BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>
+impl<T, MsgT: Msg<T> + Clone, CtrlMsgT, CtrlMsgAT: Default> BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>
 where
-    T: Debug,
+    T: Copy + Eq + Debug + std::hash::Hash,
+    MsgT: Clone,
 {
-    pub fn new() -> Self {
-        BPGraph {
-            nodes: Vec::new(),
-            step: 0,
-            normalize: true,
-            check_validity: false,
-        }
-    }
-
-    pub fn set_normalize(&mut self, normalize: bool) {
-        self.normalize = normalize;
+    pub fn get_result(
+        &self,
+        node_index: NodeIndex,
+    ) -> BPResult<Option<std::collections::HashMap<T, Probability>>> {
+        self.get_result_with_options(node_index, &ResultOptions::default())
     }
 
-    pub fn send_control_message(
-        &mut self,
+    //Like get_result, but the normalized result is cached on the node until it receives new post
+    pub fn get_result_cached(
+        &self,
         node_index: NodeIndex,
-        ctrl_msg: CtrlMsgT,
-    ) -> BPResult<CtrlMsgAT> {
-        self.get_node_mut(node_index)?
-            .send_control_message(ctrl_msg)
+    ) -> BPResult<Option<&HashMap<T, Probability>>> {
+        self.get_node(node_index)?
+            .get_result_cached(self.normalization)
+            .map_err(|e| {
+                e.attach_info_str(
+                    "BPGraph::get_result_cached",
+                    format!("Failed to retrieve result from node {}", node_index),
+                )
+            })
     }
 
-    pub fn set_check_validity(&mut self, value: bool) {
-        self.check_validity = value;
+    //E.g., the cavity distribution of node_index with respect to neighbor (needed for EM and decimation):
+    //get_result_with_options(node_index, &ResultOptions { include_prior: true, exclude_neighbors: &[neighbor] })
+    pub fn get_result_with_options(
+        &self,
+        node_index: NodeIndex,
+        options: &ResultOptions,
+    ) -> BPResult<Option<std::collections::HashMap<T, Probability>>> {
+        let n = self.get_node(node_index)?;
+        if let Some(other) = options
+            .exclude_neighbors
+            .iter()
+            .find(|other| !n.is_connected(**other))
+        {
+            return Err(BPError::new(
+                "BPGraph::get_result_with_options".to_owned(),
+                format!("Node {} is not a neighbor of node {}", other, node_index),
+            ));
+        }
+        let mut res = n.get_result_with_options(options).map_err(|e| {
+            e.attach_info_str(
+                "BPGraph::get_result",
+                format!("Failed to retrieve result from node {}", node_index),
+            )
+        })?;
+        if let Some(res) = res.as_mut() {
+            res.normalize_with(self.normalization).map_err(|e| {
+                e.attach_info_str(
+                    "BPGraph::get_result",
+                    format!("Failed to normalize result of node {}", node_index),
+                )
+            })?;
+        }
+        Ok(res)
     }
 
-    pub fn is_initialized(&self) -> bool {
-        self.nodes.iter().all(|n| n.is_initialized())
+    //Joint belief of a factor over the values of its connections, see Node::get_factor_belief
+    pub fn get_factor_belief(
+        &self,
+        node_index: NodeIndex,
+        max_assignments: usize,
+    ) -> BPResult<HashMap<Vec<T>, Probability>> {
+        self.get_node(node_index)?
+            .get_factor_belief(max_assignments)
+            .map_err(|e| {
+                e.attach_info_str(
+                    "BPGraph::get_factor_belief",
+                    format!("Failed to compute belief of factor {}", node_index),
+                )
+            })
     }
 
-    pub fn reset(&mut self) -> BPResult<()> {
-        self.nodes.iter_mut().try_for_each(|n| n.reset())
+    //The belief of a factor marginalized onto each of its connections (in the order of the connections)
+    pub fn get_factor_belief_marginals(
+        &self,
+        node_index: NodeIndex,
+        max_assignments: usize,
+    ) -> BPResult<Vec<(NodeIndex, HashMap<T, Probability>)>> {
+        let belief = self.get_factor_belief(node_index, max_assignments)?;
+        let connections = self.get_connections(node_index)?;
+        let mut marginals: Vec<(NodeIndex, HashMap<T, Probability>)> = connections
+            .iter()
+            .map(|con| (*con, HashMap::new()))
+            .collect();
+        for (values, p) in belief {
+            for (v, (_, marginal)) in values.into_iter().zip(marginals.iter_mut()) {
+                *marginal.entry(v).or_insert(0.0) += p;
+            }
+        }
+        Ok(marginals)
+    }
+
+    //If set, the marginals of all variable nodes are stored after every step (costs a get_result per node and step).
+    //Note that BPGraph::get_result fails for nodes whose messages cannot be normalized, which then also aborts the propagation.
+    //This is needed for marginal_change_metrics.
+    pub fn set_track_marginals(&mut self, track: bool) {
+        self.marginal_fn = if track { Some(Node::get_result) } else { None };
+        self.previous_marginals = Vec::new();
+        self.current_marginals = Vec::new();
+    }
+
+    //Compares the marginal of every variable node after the last step it received messages in
+    //with the marginal after the step before that.
+    //Requires set_track_marginals(true); only nodes with two tracked marginals are included.
+    pub fn marginal_change_metrics(&self) -> BPResult<Vec<MarginalChange>> {
+        if self.marginal_fn.is_none() {
+            return Err(BPError::new(
+                "BPGraph::marginal_change_metrics".to_owned(),
+                "Marginals are not tracked (see set_track_marginals)".to_owned(),
+            ));
+        }
+        Ok(self
+            .current_marginals
+            .iter()
+            .zip(self.previous_marginals.iter())
+            .enumerate()
+            .filter_map(|(i, (cur, prev))| match (cur, prev) {
+                (Some(cur), Some(prev)) => Some(MarginalChange::new(i, cur, prev)),
+                _ => None,
+            })
+            .collect())
+    }
+
+    //Appends one JSON line per completed propagation step to writer (flushed after every line), e.g.,
+    //{"step":3,"residual":0.01,"mean_entropy":0.52,"wall_time":0.004,"messages":24}, to monitor long runs.
+    //residual is the largest max_abs_diff of marginal_change_metrics, mean_entropy the mean entropy of the
+    //tracked marginals (null while there are none), wall_time the seconds since this call and messages the
+    //number of messages sent in the step. Enables set_track_marginals. Replaces the current writer.
+    pub fn set_metrics_output<W: std::io::Write + Send + 'static>(&mut self, writer: W) {
+        if self.marginal_fn.is_none() {
+            self.set_track_marginals(true);
+        }
+        self.metrics = Some(MetricsEmitter::new(writer));
+    }
+
+    pub fn disable_metrics_output(&mut self) {
+        self.metrics = None;
+    }
+
+    //If set, every node keeps a copy of the last message it received from each connection.
+    //This has to be set before propagating to use propagate_incremental later on.
+    pub fn set_incremental(&mut self, incremental: bool) {
+        self.clone_msg = if incremental { Some(MsgT::clone) } else { None };
+        for n in self.nodes.iter_mut() {
+            n.set_keep_last_received(self.clone_msg);
+        }
+        self.dirty.clear();
+    }
+
+    //Propagates the changes since the last propagation (e.g., a new factor) instead of starting from scratch.
+    //Starting at the dirty nodes, nodes recompute their messages from the last received ones and only messages
+    //that differ from the previous message on that edge by more than tolerance (maximal absolute difference
+    //after normalizing) are sent, whose receivers are processed in the next step.
+    //Stops once no message changes or after max_steps steps and returns the number of steps; nodes that
+    //are still to be processed stay dirty. Afterwards, the inbox of every processed variable node holds
+    //the last messages of all its connections (so that get_result works), the inboxes of factors are empty.
+    pub fn propagate_incremental(&mut self, max_steps: usize, tolerance: f64) -> BPResult<usize> {
+        if self.clone_msg.is_none() {
+            return Err(BPError::new(
+                "BPGraph::propagate_incremental".to_owned(),
+                "Incremental propagation is not enabled (see set_incremental)".to_owned(),
+            ));
+        }
+        if !self.is_initialized() {
+            return Err(BPError::new(
+                "BPGraph::propagate_incremental".to_owned(),
+                "Graph is not initialized".to_owned(),
+            ));
+        }
+        let mut frontier = BTreeSet::new();
+        let mut processed = BTreeSet::new();
+        let mut steps = 0;
+        while steps < max_steps {
+            //Nodes changed by the scheduled control messages are processed in this step
+            self.deliver_scheduled_control_messages()?;
+            frontier.append(&mut self.dirty);
+            if frontier.is_empty() {
+                break;
+            }
+            let mut msgs = Vec::new();
+            for i in &frontier {
+                let step = self.step;
+                let node = self.get_node_mut(*i)?;
+                node.restore_post()?;
+                if node.is_ready(step)? {
+                    let out = node.create_messages().map_err(|e| {
+                        e.attach_info_str(
+                            "BPGraph::propagate_incremental",
+                            format!("Failed to create messages at node {}", i),
+                        )
+                    })?;
+                    msgs.push((*i, out));
+                } else {
+                    node.read_post();
+                }
+            }
+            processed.append(&mut frontier);
+            for (from, out) in msgs.iter_mut() {
+                let mut changed = Vec::with_capacity(out.len());
+                for (to, msg) in out.drain(..) {
+                    if self.msg_changed(*from, to, &msg, tolerance)? {
+                        frontier.insert(to);
+                        changed.push((to, msg));
+                    }
+                }
+                *out = changed;
+            }
+            self.send(msgs)?;
+            self.end_step()?;
+            self.step += 1;
+            steps += 1;
+        }
+        frontier.append(&mut self.dirty);
+        processed.extend(frontier.iter().copied());
+        self.dirty = frontier;
+        for i in processed {
+            let node = self.get_node_mut(i)?;
+            if node.is_factor() {
+                node.read_post();
+            } else {
+                node.restore_post()?;
+            }
+        }
+        Ok(steps)
+    }
+
+    fn msg_changed(
+        &self,
+        from: NodeIndex,
+        to: NodeIndex,
+        msg: &MsgT,
+        tolerance: f64,
+    ) -> BPResult<bool> {
+        let previous = self
+            .get_node(to)?
+            .get_last_received()
+            .iter()
+            .find(|(idx, _)| *idx == from);
+        let previous = match previous {
+            None => return Ok(true),
+            Some((_, previous)) => previous,
+        };
+        //Messages that cannot be normalized count as changed
+        let (scale, scale_previous) = match (
+            msg.normalization(NormalizationMode::SumToOne),
+            previous.normalization(NormalizationMode::SumToOne),
+        ) {
+            (Ok((_, scale)), Ok((_, scale_previous))) => (scale, scale_previous),
+            _ => return Ok(true),
+        };
+        //Values missing in one of the messages have probability 0
+        let exceeds = |diff: f64| diff.is_nan() || diff > tolerance;
+        for (v, p) in msg.clone() {
+            let q = previous.get(v).unwrap_or(0.0);
+            if exceeds((p * scale - q * scale_previous).abs()) {
+                return Ok(true);
+            }
+        }
+        for (v, q) in previous.clone() {
+            if msg.get(v).is_none() && exceeds(q * scale_previous) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+impl<T, MsgT: Msg<T> + Clone, CtrlMsgT, CtrlMsgAT: Default> BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>
+where
+    T: Copy + Ord + Debug + std::hash::Hash,
+{
+    //Like get_result, but as a list in a deterministic order
+    pub fn get_result_sorted(
+        &self,
+        node_index: NodeIndex,
+        order: ResultOrder,
+    ) -> BPResult<Option<Vec<(T, Probability)>>> {
+        Ok(self.get_result(node_index)?.map(|res| order.sort(res)))
+    }
+
+    //Writes the results of all variable nodes as CSV (node,name,value,probability), ordered by node index
+    //and value, so that the output of two runs can be diffed. Nodes without a result are skipped.
+    pub fn export_marginals_csv<W: std::io::Write>(&self, writer: &mut W) -> BPResult<()> {
+        let io_error = |e: std::io::Error| {
+            BPError::new(
+                "BPGraph::export_marginals_csv".to_owned(),
+                format!("Writing failed: {}", e),
+            )
+        };
+        let quote = |s: &str| format!("\"{}\"", s.replace('"', "\"\""));
+        writeln!(writer, "node,name,value,probability").map_err(io_error)?;
+        for (node, name, is_factor) in self.nodes() {
+            if is_factor {
+                continue;
+            }
+            if let Some(res) = self.get_result_sorted(node, ResultOrder::ByValue)? {
+                for (v, p) in res {
+                    writeln!(
+                        writer,
+                        "{},{},{},{}",
+                        node,
+                        quote(name),
+                        quote(&format!("{:?}", v)),
+                        p
+                    )
+                    .map_err(io_error)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T, MsgT: Msg<T> + Clone, CtrlMsgT, CtrlMsgAT: Default> BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>
+where
+    T: Copy + Eq + Debug + std::hash::Hash + Send + Sync,
+    MsgT: Send + Sync,
+{
+    //Results (as in get_result) of all variable nodes, computed on thread_count threads (on the thread pool
+    //if one is set). Every thread handles a contiguous range of nodes. Nodes without a result are skipped.
+    pub fn get_marginals_threaded(
+        &self,
+        thread_count: u32,
+    ) -> BPResult<HashMap<NodeIndex, HashMap<T, Probability>>> {
+        let len = self.len();
+        let thread_count = thread_count.clamp(1, len.max(1) as u32);
+        //Only the nodes are shared with the threads, control messages need not be Sync
+        let nodes = &self.nodes;
+        let normalization = self.normalization;
+        let results = run_on_threads(self.thread_pool.as_deref(), thread_count, |t| {
+            let t = t as usize;
+            let n = thread_count as usize;
+            let mut results = Vec::new();
+            for (i, node) in nodes
+                .iter()
+                .enumerate()
+                .take((t + 1) * len / n)
+                .skip(t * len / n)
+            {
+                if node.is_factor() {
+                    continue;
+                }
+                let res = node
+                    .get_result_with_options(&ResultOptions::default())
+                    .map_err(|e| {
+                        e.attach_info_str(
+                            "BPGraph::get_result",
+                            format!("Failed to retrieve result from node {}", i),
+                        )
+                    })?;
+                if let Some(mut res) = res {
+                    res.normalize_with(normalization).map_err(|e| {
+                        e.attach_info_str(
+                            "BPGraph::get_result",
+                            format!("Failed to normalize result of node {}", i),
+                        )
+                    })?;
+                    results.push((i, res));
+                }
+            }
+            Ok(results)
+        });
+        let mut marginals = HashMap::with_capacity(self.variable_nodes_count());
+        for res in results {
+            marginals.extend(res.map_err(|e: BPError| {
+                e.attach_info_str(
+                    "BPGraph::get_marginals_threaded",
+                    "Failed to retrieve results".to_owned(),
+                )
+            })?);
+        }
+        Ok(marginals)
+    }
+}
+
+impl<T, MsgT: Msg<T> + Clone, CtrlMsgT, CtrlMsgAT: Default> BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>
+where
+    T: Debug,
+{
+    //Marks the edge between parent and child as directed for propagate_forward
+    pub fn set_edge_directed(&mut self, parent: NodeIndex, child: NodeIndex) -> BPResult<()> {
+        if !self.get_node(child)?.is_connected(parent) {
+            return Err(BPError::new(
+                "BPGraph::set_edge_directed".to_owned(),
+                format!("There is no edge between {} and {}", parent, child),
+            ));
+        }
+        if self.directed.contains(&(child, parent)) {
+            return Err(BPError::new(
+                "BPGraph::set_edge_directed".to_owned(),
+                format!("Edge is already directed from {} to {}", child, parent),
+            ));
+        }
+        self.directed.insert((parent, child));
+        Ok(())
+    }
+
+    pub fn is_edge_directed(&self, parent: NodeIndex, child: NodeIndex) -> bool {
+        self.directed.contains(&(parent, child))
+    }
+
+    //Single forward pass (ancestral propagation) along the directed edges: all inboxes are emptied, then every node
+    //with children creates its messages in topological order and only the messages to its children are delivered.
+    //Messages from connections that are not parents are replaced by neutral messages (all probabilities one) based on
+    //the prior of the receiving variable or of the connected variable, so variables whose parent is a factor with
+    //several children need a prior (e.g., uniform). Afterwards, every inbox holds the messages of the parents of the node,
+    //so the results of the variables are their forward marginals.
+    //Fails if the directed edges contain a cycle.
+    pub fn propagate_forward(&mut self) -> BPResult<()> {
+        if !self.is_initialized() {
+            return Err(BPError::new(
+                "BPGraph::propagate_forward".to_owned(),
+                "Graph is not initialized".to_owned(),
+            ));
+        }
+        let order = self.topological_order()?;
+        self.deliver_scheduled_control_messages()?;
+        let parents: HashSet<NodeIndex> = self.directed.iter().map(|(parent, _)| *parent).collect();
+        for node in self.nodes.iter_mut() {
+            let post = node.read_post();
+            node.recycle_post(post, &mut self.msg_pool);
+        }
+        for node_index in order {
+            if !parents.contains(&node_index) {
+                continue;
+            }
+            let received = self.fill_non_parents(node_index)?;
+            let node = self.nodes.get_mut(node_index).expect("Index is valid");
+            let msgs = node
+                .create_messages_recycling(&mut self.msg_pool)
+                .map_err(|e| {
+                    e.attach_info_str(
+                        "BPGraph::propagate_forward",
+                        format!("Node {} failed to create messages", node_index),
+                    )
+                })?;
+            let (to_children, rest): (Vec<_>, Vec<_>) = msgs
+                .into_iter()
+                .partition(|(to, _)| self.directed.contains(&(node_index, *to)));
+            for (_, msg) in rest {
+                self.msg_pool.recycle(msg);
+            }
+            //Keep the messages of the parents, e.g., for get_result
+            let node = self.nodes.get_mut(node_index).expect("Index is valid");
+            for (from, msg) in received {
+                node.send_post(from, msg);
+            }
+            self.send(vec![(node_index, to_children)])?;
+        }
+        self.end_step()?;
+        self.step += 1;
+        Ok(())
+    }
+
+    //Nodes with directed edges in topological order (Kahn)
+    fn topological_order(&self) -> BPResult<Vec<NodeIndex>> {
+        let mut in_degree: HashMap<NodeIndex, usize> = HashMap::new();
+        let mut children: HashMap<NodeIndex, Vec<NodeIndex>> = HashMap::new();
+        for (parent, child) in &self.directed {
+            in_degree.entry(*parent).or_insert(0);
+            *in_degree.entry(*child).or_insert(0) += 1;
+            children.entry(*parent).or_default().push(*child);
+        }
+        let mut ready: BTreeSet<NodeIndex> = in_degree
+            .iter()
+            .filter(|(_, d)| **d == 0)
+            .map(|(n, _)| *n)
+            .collect();
+        let mut order = Vec::with_capacity(in_degree.len());
+        while let Some(n) = ready.pop_first() {
+            order.push(n);
+            for child in children.get(&n).into_iter().flatten() {
+                let d = in_degree.get_mut(child).expect("Child has an in-degree");
+                *d -= 1;
+                if *d == 0 {
+                    ready.insert(*child);
+                }
+            }
+        }
+        if order.len() != in_degree.len() {
+            return Err(BPError::new(
+                "BPGraph::propagate_forward".to_owned(),
+                "The directed edges contain a cycle".to_owned(),
+            ));
+        }
+        Ok(order)
+    }
+
+    //Rebuilds the inbox of a node in the order of its connections, adding neutral messages for non-parents.
+    //Returns copies of the received messages.
+    fn fill_non_parents(&mut self, node_index: NodeIndex) -> BPResult<Vec<(NodeIndex, MsgT)>> {
+        let node = self.get_node(node_index)?;
+        let connections = node.get_connections().clone();
+        let is_factor = node.is_factor();
+        let own_prior = if is_factor { None } else { node.get_prior() };
+        let mut post = self.nodes[node_index].read_post();
+        let received = post.clone();
+        let mut inbox = Vec::with_capacity(connections.len());
+        for con in connections {
+            if let Some(pos) = post.iter().position(|(from, _)| *from == con) {
+                inbox.push(post.swap_remove(pos));
+                continue;
+            }
+            let template = if is_factor {
+                self.get_node(con)?.get_prior()
+            } else {
+                own_prior.clone().or_else(|| {
+                    inbox
+                        .first()
+                        .map(|(_, msg): &(NodeIndex, MsgT)| msg.clone())
+                })
+            };
+            let mut neutral = template.ok_or_else(|| {
+                BPError::new(
+                    "BPGraph::propagate_forward".to_owned(),
+                    format!(
+                        "Cannot create a neutral message from {} to {} (the variable needs a prior)",
+                        con, node_index
+                    ),
+                )
+            })?;
+            neutral.for_each(|_| 1.0);
+            inbox.push((con, neutral));
+        }
+        self.nodes[node_index].recycle_post(post, &mut self.msg_pool);
+        let node = &mut self.nodes[node_index];
+        for (from, msg) in inbox {
+            node.send_post(from, msg);
+        }
+        Ok(received)
+    }
+}
+
+impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default> BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>
+where
+    T: Send + Sync + Debug,
+    MsgT: Send + Sync,
+{
+    //msgs: [(from, [(to, msg)])]
+    fn send_threaded(
+        &mut self,
+        msgs: Vec<(NodeIndex, Vec<(NodeIndex, MsgT)>)>,
+        thread_count: u32,
+    ) -> BPResult<()> {
+        if let Some(history) = self.history.as_mut() {
+            history.record(self.step, &msgs);
+        }
+        let zero_message_policy = self.zero_message_policy;
+        let check_validity = self.check_validity;
+        let step = self.step;
+        let edge_transforms = &self.edge_transforms;
+        let temperature = self.temperature;
+        let kind = semiring::kind(self.semiring.as_deref());
+        //Node i belongs to shard i % shards, the messages are split by destination up front, so that every
+        //worker owns its destinations exclusively and no locks are needed while sending.
+        let len = self.nodes.len();
+        let shards = std::cmp::max(1, std::cmp::min(thread_count as usize, len));
+        let mut shard_msgs: Vec<Vec<(NodeIndex, NodeIndex, MsgT, NormalizationMode)>> =
+            (0..shards).map(|_| Vec::new()).collect();
+        for (from, msgmap) in msgs.into_iter() {
+            for (to, msg) in msgmap.into_iter() {
+                if to >= len {
+                    return Err(BPError::new(
+                        "BPGraph::send".to_owned(),
+                        format!("Index {} out of bounds ({})", to, len),
+                    ));
+                }
+                let normalization = self.get_edge_normalization(from, to)?;
+                shard_msgs[to % shards].push((from, to, msg, normalization));
+            }
+        }
+        let mut shard_nodes: Vec<Vec<&mut Node<T, MsgT, CtrlMsgT, CtrlMsgAT>>> = (0..shards)
+            .map(|_| Vec::with_capacity(len / shards + 1))
+            .collect();
+        for (i, n) in self.nodes.iter_mut().enumerate() {
+            shard_nodes[i % shards].push(n);
+        }
+        #[cfg(feature = "progress_output")]
+        let (whitespace_padding, step) = {
+            let ln_msgs: usize = shard_msgs.iter().map(|m| m.len()).sum();
+            let max_diff_in_number = f64::log10(ln_msgs as f64) as usize + 1;
+            (
+                &(std::iter::repeat(" ")
+                    .take(max_diff_in_number)
+                    .collect::<String>()),
+                self.step.clone(),
+            )
+        };
+        //Every worker takes its shard once
+        let work: Vec<Mutex<Option<_>>> = shard_nodes
+            .into_iter()
+            .zip(shard_msgs)
+            .map(|shard| Mutex::new(Some(shard)))
+            .collect();
+        let pool = self.thread_pool.as_deref();
+        let results = run_on_threads(pool, shards as u32, |i| {
+            let (mut nodes, msgs) = work[i as usize]
+                .lock()
+                .expect("Locking mutex failed.")
+                .take()
+                .expect("Shard has not been taken");
+            #[cfg(feature = "progress_output")]
+            {
+                print!(
+                    "Step {}: {} messages in shard {}{}\r",
+                    step,
+                    msgs.len(),
+                    i,
+                    &whitespace_padding
+                );
+                std::io::stdout().flush();
+            }
+            let mut contradictions = Vec::new();
+            for (from, to, mut msg, normalization) in msgs.into_iter() {
+                debug_print!("Sending from {} to {}", from, to);
+                if let Some(transform) = edge_transforms.get(&(from, to)) {
+                    msg = transform.transform(msg).map_err(|e| {
+                        e.attach_info_str(
+                            "BPGraph::send",
+                            format!("Failed to transform message {} -> {}.", from, to),
+                        )
+                        .attach_debug_object("step", step)
+                    })?;
+                }
+                temper(&mut msg, temperature, kind);
+                //Messages in the log domain or costs are not probabilities
+                if check_validity && normalization.is_probability() {
+                    msg.validate().map_err(|e| {
+                        BPError::new(
+                            "BPGraph::send".to_owned(),
+                            format!(
+                                "Trying to send an invalid message ({} -> {}): {}",
+                                from, to, e
+                            ),
+                        )
+                        .attach_debug_object("step", step)
+                    })?;
+                }
+                if !normalize_outgoing(
+                    &mut msg,
+                    normalization,
+                    zero_message_policy,
+                    from,
+                    to,
+                    step,
+                )? {
+                    if zero_message_policy == ZeroMessagePolicy::MarkContradiction {
+                        contradictions.push(Contradiction { from, to, step });
+                    }
+                    continue;
+                }
+                let nto = &mut nodes[to / shards];
+                if !nto.is_connected(from) {
+                    return Err(BPError::new(
+                        "BPGraph::send".to_owned(),
+                        format!(
+                            "Trying to send a message along a non-existent edge ({} -> {}).",
+                            from, to
+                        ),
+                    )
+                    .attach_debug_object("step", step)
+                    .attach_debug_object("edges", nto.get_connections())
+                    .attach_debug_object("name of node to sending to", nto.get_name()));
+                }
+                nto.send_post(from, msg);
+            }
+            Ok(contradictions)
+        });
+        for res in results {
+            self.contradictions.extend(res?);
+        }
+        #[cfg(feature = "progress_output")]
+        {
+            let whitespace_padding2 = std::iter::repeat(" ").take(30).collect::<String>(); //Not very elegant...
+            print!("{}{}\r", whitespace_padding2, &whitespace_padding);
+            std::io::stdout().flush();
+        }
+        Ok(())
+    }
+
+    //Returns the created messages and whether all ready nodes have been processed (i.e., not cancelled)
+    #[allow(clippy::type_complexity)]
+    fn create_messages_threaded(
+        &mut self,
+        thread_count: u32,
+        cancel: Option<&(dyn Fn() -> bool + Sync)>,
+    ) -> BPResult<(Vec<(NodeIndex, Vec<(NodeIndex, MsgT)>)>, bool)> {
+        info_print!("Creating messages with {} threads..", thread_count);
+        let step = self.step;
+        let strict = self.strict;
+        let mut nodes_ = Vec::new();
+        for (i, n) in self.nodes.iter_mut().enumerate() {
+            if n.is_ready(step)? {
+                if strict {
+                    n.check_inbox()
+                        .map_err(|e| e.attach_debug_object("step", step))?;
+                }
+                nodes_.push((i, n));
+            }
+            else {
+                let post = n.read_post();
+                n.recycle_post(post, &mut self.msg_pool);
+            }
+        }
+        let config = self.threading_config;
+        let pool = self.thread_pool.as_deref();
+        if config.strategy == BatchStrategy::WorkStealing {
+            return create_messages_work_stealing(nodes_, pool, config, thread_count, cancel, step);
+        }
+        #[cfg(feature = "progress_output")]
+        let (whitespace_padding, step) = {
+            let max_diff_in_number = f64::log10(nodes_.len() as f64) as usize + 1;
+            (
+                &(std::iter::repeat(" ")
+                    .take(max_diff_in_number)
+                    .collect::<String>()),
+                self.step.clone(),
+            )
+        };
+        thread_print!(
+            "Batch size is between {} and {}",
+            config.min_batch,
+            config.max_batch
+        );
+        let mut nodes = Arc::new(Mutex::new(nodes_));
+
+        let thread_results = run_on_threads(pool, thread_count, |i| {
+            let mut thread_msgs = Vec::new();
+            loop {
+                //nodes is locked in this block
+                let chunck: Vec<(NodeIndex, &mut Node<T, MsgT, CtrlMsgT, CtrlMsgAT>)> = {
+                    thread_print!("Thread {} waiting for lock..", i);
+                    let mut nodes = nodes.lock().expect("Locking mutex failed.");
+                    thread_print!("Thread {} has lock..", i);
+                    let len = nodes.len();
+                    if len == 0 {
+                        break;
+                    }
+                    if cancel.is_some_and(|c| c()) {
+                        thread_print!("Thread {} cancelled.", i);
+                        break;
+                    }
+
+                    #[cfg(feature = "progress_output")]
+                    {
+                        print!(
+                            "Step {}: {} nodes left{}\r",
+                            step,
+                            nodes.len(),
+                            &whitespace_padding
+                        );
+                        std::io::stdout().flush();
+                    }
+                    let batch_size = config.batch_size(len, thread_count);
+                    let chunck = nodes.drain(0..std::cmp::min(batch_size, len)).collect();
+                    chunck
+                };
+                thread_print!("Thread {} working on {} nodes..", i, chunck.len());
+                if chunck.is_empty() {
+                    break;
+                }
+                for (idx, node) in chunck {
+                    thread_msgs.push((
+                        idx,
+                        node.create_messages().map_err(|e| {
+                            e.attach_debug_object("idx (node index)", idx)
+                                .attach_debug_object("node.get_name() (node name)", node.get_name())
+                                .attach_debug_object("step", step)
+                        })?,
+                    ));
+                }
+            }
+            thread_print!("Thread {} finished.", i);
+            Ok(thread_msgs)
+        });
+        let mut result = Vec::new();
+        for res in thread_results {
+            result.extend(res?);
+        }
+        #[cfg(feature = "progress_output")]
+        {
+            let whitespace_padding2 = std::iter::repeat(" ").take(30).collect::<String>(); //Not very elegant...
+            print!("{}{}\r", whitespace_padding2, &whitespace_padding);
+            std::io::stdout().flush();
+        }
+        let complete = nodes.lock().expect("Locking mutex failed.").is_empty();
+        Ok((result, complete))
+    }
+
+    pub fn propagate_step_threaded(&mut self, thread_count: u32) -> BPResult<StepReport> {
+        Ok(self
+            .propagate_step_threaded_impl(thread_count, None)?
+            .expect("Step without cancel is complete"))
+    }
+
+    //Returns None if the step has been cancelled before all nodes were processed
+    fn propagate_step_threaded_impl(
+        &mut self,
+        thread_count: u32,
+        cancel: Option<&(dyn Fn() -> bool + Sync)>,
+    ) -> BPResult<Option<StepReport>> {
+        let start = Instant::now();
+        if self.check_validity {
+            self.check_structure("propagate_step_threaded", "Graph is invalid")?;
+        }
+        self.deliver_scheduled_control_messages()?;
+        info_print!("Propagating step {}..", self.step);
+        debug_print!("Creating messages..");
+        let (mut outgoing_msgs, complete) = self.create_messages_threaded(thread_count, cancel)?;
+        let messages = count_messages(&outgoing_msgs);
+        let nodes_fired = outgoing_msgs.len();
+        //Messages that were already created are delivered even if cancelled, otherwise they would be lost
+        if self.deterministic {
+            info_print!("Sending messages (deterministic)");
+            outgoing_msgs.sort_by_key(|(from, _)| *from);
+            for (_, msgs) in outgoing_msgs.iter_mut() {
+                msgs.sort_by_key(|(to, _)| *to);
+            }
+            self.send(outgoing_msgs)?;
+        } else {
+            info_print!("Sending messages (threaded)");
+            self.send_threaded(outgoing_msgs, thread_count)?;
+        }
+        if !complete {
+            info_print!("Cancelled step {}\n", self.step);
+            return Ok(None);
+        }
+        info_print!("Done propagating step {}\n", self.step);
+        self.end_step()?;
+        self.step += 1;
+        self.anneal();
+        self.snapshot_marginals()?;
+        self.emit_metrics(messages)?;
+        Ok(Some(StepReport {
+            step: self.step - 1,
+            nodes_fired,
+            messages_sent: messages,
+            skipped_not_ready: self.nodes.len() - nodes_fired,
+            duration: start.elapsed(),
+        }))
+    }
+
+    pub fn propagate_threaded(&mut self, steps: usize, thread_count: u32) -> BPResult<()> {
+        if !self.is_initialized() {
+            return Err(BPError::new(
+                "propagate_threaded".to_owned(),
+                "Graph is not initialized".to_owned(),
+            ));
+        }
+        for _ in 0..steps {
+            self.propagate_step_threaded(thread_count)?;
+        }
+        Ok(())
+    }
+
+    //Like propagate_threaded, but stops as soon as possible once cancel is set.
+    //cancel is checked between batches, already created messages are still delivered.
+    //After cancellation, get_result can be used to retrieve the results computed so far.
+    pub fn propagate_threaded_cancellable(
+        &mut self,
+        steps: usize,
+        thread_count: u32,
+        cancel: &AtomicBool,
+    ) -> BPResult<PropagationState> {
+        if !self.is_initialized() {
+            return Err(BPError::new(
+                "propagate_threaded_cancellable".to_owned(),
+                "Graph is not initialized".to_owned(),
+            ));
+        }
+        for completed_steps in 0..steps {
+            if cancel.load(Ordering::Relaxed) {
+                return Ok(PropagationState::Cancelled {
+                    completed_steps,
+                    partial_step: false,
+                });
+            }
+            if self
+                .propagate_step_threaded_impl(
+                    thread_count,
+                    Some(&|| cancel.load(Ordering::Relaxed)),
+                )?
+                .is_none()
+            {
+                return Ok(PropagationState::Cancelled {
+                    completed_steps,
+                    partial_step: true,
+                });
+            }
+        }
+        Ok(PropagationState::Finished)
+    }
+
+    //Like propagate_for, the time is also checked between the batches of a step.
+    //Messages created in a step that ran out of time are still delivered, but the step is not counted.
+    pub fn propagate_threaded_for(
+        &mut self,
+        duration: Duration,
+        thread_count: u32,
+    ) -> BPResult<usize> {
+        if !self.is_initialized() {
+            return Err(BPError::new(
+                "propagate_threaded_for".to_owned(),
+                "Graph is not initialized".to_owned(),
+            ));
+        }
+        let deadline = Instant::now() + duration;
+        let timeout = || Instant::now() >= deadline;
+        let mut completed_steps = 0;
+        while !timeout()
+            && self
+                .propagate_step_threaded_impl(thread_count, Some(&timeout))?
+                .is_some()
+        {
+            completed_steps += 1;
+        }
+        Ok(completed_steps)
+    }
+    //Like initialize, but the nodes are initialized on thread_count threads (on the thread pool if one is set),
+    //e.g., for factors precomputing tables. The threads take the nodes one by one, as the cost of initializing
+    //differs a lot between node functions. If a node fails, the nodes initialized so far stay initialized (and are
+    //told about the graph by the next successful call).
+    #[allow(clippy::type_complexity)]
+    pub fn initialize_threaded(&mut self, thread_count: u32) -> BPResult<()> {
+        let pending: Vec<(NodeIndex, &mut Node<T, MsgT, CtrlMsgT, CtrlMsgAT>)> = self
+            .nodes
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, node)| !node.is_initialized())
+            .collect();
+        let thread_count = thread_count.clamp(1, pending.len().max(1) as u32);
+        let queue = Mutex::new(pending.into_iter());
+        let results = run_on_threads(self.thread_pool.as_deref(), thread_count, |t| {
+            loop {
+                let next = queue.lock().expect("Locking mutex failed.").next();
+                let (i, node) = match next {
+                    Some(next) => next,
+                    None => break,
+                };
+                thread_print!("Thread {} initializes node {}", t, i);
+                node.initialize().map_err(|e| {
+                    e.attach_info_str(
+                        "BPGraph::initialize_threaded",
+                        format!("Failed to initialize node {}", i),
+                    )
+                })?;
+            }
+            Ok(())
+        });
+        for res in results {
+            res?;
+        }
+        self.finish_initialize()
+    }
+
+    pub fn factor_nodes_count(&self) -> usize {
+        self.nodes.iter().filter(|&n| n.is_factor()).count()
+    }
+    pub fn variable_nodes_count(&self) -> usize {
+        self.nodes.iter().filter(|&n| !n.is_factor()).count()
+    }
+    pub fn nodes_count(&self) -> usize {
+        self.nodes.len()
+    }
+}
+
+impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default> BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>
+where
+    T: Debug + PartialEq + Send + Sync + 'static,
+    MsgT: Clone + Send + Sync + 'static,
+{
+    //Adds an EqualityFactor connected to all given variables, i.e., the variables are treated as the same quantity.
+    pub fn link_variables(&mut self, name: String, variables: &[NodeIndex]) -> BPResult<NodeIndex> {
+        self.check_unsealed("BPGraph::link_variables")?;
+        let factor = self.add_node(name, Box::new(EqualityFactor::new()))?;
+        for var in variables {
+            self.add_edge(factor, *var).map_err(|e| {
+                e.attach_info_str(
+                    "BPGraph::link_variables",
+                    format!(
+                        "Failed to link variable {} to equality factor {}",
+                        var, factor
+                    ),
+                )
+            })?;
+        }
+        Ok(factor)
+    }
+}
+
+impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT> BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>
+where
+    T: Debug + Send + Sync + 'static,
+    MsgT: Clone + Send + Sync + 'static,
+    CtrlMsgT: IntoVariableNodeCtrl<MsgT> + 'static,
+    CtrlMsgAT: FromVariableNodeCtrlAnswer<MsgT> + 'static,
+{
+    //Adds a VariableNode with the given prior and default settings. The prior is checked and normalized
+    //as by VariableNode::set_prior when the graph is initialized.
+    pub fn add_variable(&mut self, name: String, prior: MsgT) -> BPResult<NodeIndex> {
+        self.add_node(
+            name,
+            Box::new(VariableNode::<T, MsgT>::builder().prior(prior).build()),
+        )
+    }
+}
+
+impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default> BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>
+where
+    T: Clone + Debug,
+    MsgT: Clone,
+{
+    pub fn get_inbox(&self, node_index: NodeIndex) -> BPResult<Vec<(NodeIndex, MsgT)>> {
+        let node = self.get_node(node_index)?;
+        Ok(node.clone_inbox())
+    }
+
+    //Like get_result, but the result is computed and returned as MsgT, without the conversion to a HashMap
+    //(e.g., for DenseMsg). See Node::get_result_msg.
+    pub fn get_result_msg(&self, node_index: NodeIndex) -> BPResult<Option<MsgT>> {
+        let mut res = self
+            .get_node(node_index)?
+            .get_result_msg(&ResultOptions::default())
+            .map_err(|e| {
+                e.attach_info_str(
+                    "BPGraph::get_result_msg",
+                    format!("Failed to retrieve result from node {}", node_index),
+                )
+            })?;
+        if let Some(res) = res.as_mut() {
+            res.normalize_with(self.normalization).map_err(|e| {
+                e.attach_info_str(
+                    "BPGraph::get_result_msg",
+                    format!("Failed to normalize result of node {}", node_index),
+                )
+            })?;
+        }
+        Ok(res)
+    }
+}
+
+impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default> BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>
+where
+    T: Debug,
+{
+    pub fn get_step(&self) -> usize {
+        self.step
+    }
+
+    //Same as get_step
+    pub fn current_step(&self) -> usize {
+        self.get_step()
+    }
+
+    //Sets the step passed to the nodes (e.g., to NodeFunction::is_ready) when driving a schedule externally
+    pub fn set_step(&mut self, step: usize) {
+        self.step = step;
+    }
+
+    //Sets the appearance probabilities (in (0, 1]) of factors for tree-reweighted BP (see TreeReweighted).
+    //For pairwise models, the factors are the edges of the model. Factors not given keep their weight.
+    pub fn set_edge_weights(&mut self, weights: HashMap<NodeIndex, f64>) -> BPResult<()> {
+        for (idx, w) in &weights {
+            if !self.is_factor(*idx)? {
+                return Err(BPError::new(
+                    "BPGraph::set_edge_weights".to_owned(),
+                    format!("Node {} is not a factor", idx),
+                ));
+            }
+            if !(*w > 0.0 && *w <= 1.0) {
+                return Err(BPError::new(
+                    "BPGraph::set_edge_weights".to_owned(),
+                    format!("Weight of factor {} has to be in (0, 1] (got {})", idx, w),
+                ));
+            }
+        }
+        self.edge_weights.extend(weights);
+        Ok(())
+    }
+
+    pub fn get_edge_weight(&self, factor: NodeIndex) -> f64 {
+        self.edge_weights.get(&factor).copied().unwrap_or(1.0)
+    }
+
+    //Applies transform to every message sent from from to to (before normalization), replacing a
+    //previously set transform of this edge.
+    pub fn set_edge_transform(
+        &mut self,
+        from: NodeIndex,
+        to: NodeIndex,
+        transform: Box<dyn MsgTransform<T, MsgT>>,
+    ) -> BPResult<()> {
+        if !self.get_node(to)?.is_connected(from) {
+            return Err(BPError::new(
+                "BPGraph::set_edge_transform".to_owned(),
+                format!("There is no edge between {} and {}", from, to),
+            ));
+        }
+        self.edge_transforms
+            .insert((from, to), Arc::from(transform));
+        self.mark_changed(from);
+        Ok(())
     }
 
+    pub fn remove_edge_transform(
+        &mut self,
+        from: NodeIndex,
+        to: NodeIndex,
+    ) -> Option<Arc<dyn MsgTransform<T, MsgT>>> {
+        self.edge_transforms.remove(&(from, to))
+    }
+
+    //Whether an edge of the node has a transform or a normalization override or is directed
+    pub(crate) fn has_edge_settings(&self, node_index: NodeIndex) -> bool {
+        self.edge_transforms
+            .keys()
+            .chain(self.edge_normalization.keys())
+            .chain(self.directed.iter())
+            .any(|(a, b)| *a == node_index || *b == node_index)
+    }
+
+    pub fn is_factor(&self, node_index: NodeIndex) -> BPResult<bool> {
+        Ok(self.get_node(node_index)?.is_factor())
+    }
+
+    pub fn is_ready(&self, node_index: NodeIndex) -> BPResult<bool> {
+        self.get_node(node_index)?.is_ready(self.step)
+    }
+
+    //Readiness of all nodes in step, e.g., to find out why a propagation stalls
+    pub fn ready_report(&self, step: usize) -> BPResult<Vec<NodeReadiness>> {
+        if !self.is_initialized() {
+            return Err(BPError::new(
+                "BPGraph::ready_report".to_owned(),
+                "Graph is not initialized".to_owned(),
+            ));
+        }
+        self.nodes
+            .iter()
+            .enumerate()
+            .map(|(i, n)| {
+                let connections = n.get_connections().len();
+                Ok(NodeReadiness {
+                    node: i,
+                    name: n.get_name().clone(),
+                    ready: n.is_ready(step).map_err(|e| {
+                        e.attach_info_str("BPGraph::ready_report", format!("Node {} failed", i))
+                    })?,
+                    received: n.post_len(),
+                    connections,
+                    needed: n.inputs_needed(step).or_else(|| {
+                        if n.is_factor() {
+                            Some(connections)
+                        } else {
+                            None
+                        }
+                    }),
+                    input_need: n.input_need(),
+                })
+            })
+            .collect()
+    }
+
+    //Typed access to the node function of a node, e.g., to read parameters after propagation.
+    //Fails if the node function is not an F.
+    pub fn get_node_function<F: 'static>(&self, node_index: NodeIndex) -> BPResult<&F>
+    where
+        T: 'static,
+        MsgT: 'static,
+        CtrlMsgT: 'static,
+        CtrlMsgAT: 'static,
+    {
+        self.get_node(node_index)?
+            .node_function_as_any()
+            .downcast_ref::<F>()
+            .ok_or_else(|| {
+                BPError::new(
+                    "BPGraph::get_node_function".to_owned(),
+                    format!(
+                        "Node function of node {} is not a {}",
+                        node_index,
+                        std::any::type_name::<F>()
+                    ),
+                )
+            })
+    }
+
+    pub fn get_node_function_mut<F: 'static>(&mut self, node_index: NodeIndex) -> BPResult<&mut F>
+    where
+        T: 'static,
+        MsgT: 'static,
+        CtrlMsgT: 'static,
+        CtrlMsgAT: 'static,
+    {
+        self.get_node(node_index)?;
+        self.mark_changed(node_index);
+        self.nodes[node_index]
+            .node_function_as_any_mut()
+            .downcast_mut::<F>()
+            .ok_or_else(|| {
+                BPError::new(
+                    "BPGraph::get_node_function_mut".to_owned(),
+                    format!(
+                        "Node function of node {} is not a {}",
+                        node_index,
+                        std::any::type_name::<F>()
+                    ),
+                )
+            })
+    }
+
+    pub fn get_connections(&self, node_index: NodeIndex) -> BPResult<&Vec<NodeIndex>> {
+        Ok(self.get_node(node_index)?.get_connections())
+    }
+
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        BPGraph {
+            nodes: Vec::new(),
+            step: 0,
+            normalization: NormalizationMode::SumToOne,
+            zero_message_policy: ZeroMessagePolicy::Error,
+            contradictions: Vec::new(),
+            strict: false,
+            check_validity: false,
+            deterministic: false,
+            msg_pool: MsgPool::default(),
+            marginal_fn: None,
+            previous_marginals: Vec::new(),
+            current_marginals: Vec::new(),
+            clone_msg: None,
+            dirty: BTreeSet::new(),
+            edge_weights: HashMap::new(),
+            edge_transforms: HashMap::new(),
+            factor_normalization: None,
+            variable_normalization: None,
+            edge_normalization: HashMap::new(),
+            history: None,
+            metrics: None,
+            thread_pool: None,
+            threading_config: ThreadingConfig::default(),
+            semiring: None,
+            temperature: 1.0,
+            annealing: None,
+            directed: HashSet::new(),
+            unvalidated: None,
+            window: None,
+            scheduled_ctrl: BTreeMap::new(),
+            scheduled_answers: Vec::new(),
+            sealed: false,
+        }
+    }
+
+    //If set, every inbox has to contain exactly one message from each connection (or none at all) when
+    //the node creates its messages, see Node::check_inbox. Useful to find errors in schedules and node functions.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    //Selects the algebra of the propagation (e.g., MaxProduct or MinSum) for all nodes, including nodes added later,
+    //and sets the normalization suggested by the semiring. See Semiring for the supported node functions.
+    pub fn set_semiring(&mut self, semiring: Arc<dyn Semiring>) {
+        self.normalization = semiring.normalization();
+        for node in self.nodes.iter_mut() {
+            node.set_semiring(semiring.clone());
+        }
+        self.semiring = Some(semiring);
+    }
+
+    pub fn get_semiring(&self) -> Option<&dyn Semiring> {
+        self.semiring.as_deref()
+    }
+
+    //Every sent message is raised to the power 1 / temperature before it is normalized and combined by its
+    //receiver (for MinSum and MaxSum, the costs or log-probabilities are multiplied by 1 / temperature).
+    //Temperatures above 1 flatten the messages, 1 (the default) is standard BP.
+    pub fn set_temperature(&mut self, temperature: f64) -> BPResult<()> {
+        if !(temperature > 0.0 && temperature.is_finite()) {
+            return Err(BPError::new(
+                "BPGraph::set_temperature".to_owned(),
+                format!(
+                    "Temperature has to be positive and finite (got {})",
+                    temperature
+                ),
+            ));
+        }
+        self.temperature = temperature;
+        Ok(())
+    }
+
+    pub fn get_temperature(&self) -> f64 {
+        self.temperature
+    }
+
+    //Sets the temperature to initial and moves it towards 1 after every propagation step,
+    //T = 1 + (T - 1) * decay, so annealed BP starts on a smoothed model and ends with standard BP.
+    //decay has to be in [0, 1].
+    pub fn set_annealing(&mut self, initial: f64, decay: f64) -> BPResult<()> {
+        if !(0.0..=1.0).contains(&decay) {
+            return Err(BPError::new(
+                "BPGraph::set_annealing".to_owned(),
+                format!("Decay has to be in [0, 1] (got {})", decay),
+            ));
+        }
+        self.set_temperature(initial).map_err(|e| {
+            e.attach_info_str(
+                "BPGraph::set_annealing",
+                "Invalid initial temperature".to_owned(),
+            )
+        })?;
+        self.annealing = Some(decay);
+        Ok(())
+    }
+
+    //Keeps the current temperature
+    pub fn disable_annealing(&mut self) {
+        self.annealing = None;
+    }
+
+    fn anneal(&mut self) {
+        if let Some(decay) = self.annealing {
+            self.temperature = 1.0 + (self.temperature - 1.0) * decay;
+        }
+    }
+
+    //true is NormalizationMode::SumToOne, false is NormalizationMode::None
+    pub fn set_normalize(&mut self, normalize: bool) {
+        self.normalization = if normalize {
+            NormalizationMode::SumToOne
+        } else {
+            NormalizationMode::None
+        };
+    }
+
+    //Used for all sent messages and for the results returned by get_result
+    pub fn set_normalization(&mut self, mode: NormalizationMode) {
+        self.normalization = mode;
+        //Cached results are normalized with the previous mode
+        self.nodes.iter_mut().for_each(|n| n.invalidate_result());
+    }
+
+    pub fn get_normalization(&self) -> NormalizationMode {
+        self.normalization
+    }
+
+    //Overrides the normalization of the messages sent by the nodes matching filter, e.g., normalized
+    //variable -> factor messages and raw factor -> variable messages keeping the likelihood. None falls back to
+    //the normalization of the graph. Overrides of single edges (see set_edge_normalization) take precedence.
+    //The results returned by get_result are normalized with the normalization of the graph.
+    pub fn set_sender_normalization(
+        &mut self,
+        filter: NodeFilter,
+        mode: Option<NormalizationMode>,
+    ) {
+        if filter.matches(true) {
+            self.factor_normalization = mode;
+        }
+        if filter.matches(false) {
+            self.variable_normalization = mode;
+        }
+    }
+
+    //Overrides the normalization of the messages sent from from to to
+    pub fn set_edge_normalization(
+        &mut self,
+        from: NodeIndex,
+        to: NodeIndex,
+        mode: NormalizationMode,
+    ) -> BPResult<()> {
+        if !self.get_node(to)?.is_connected(from) {
+            return Err(BPError::new(
+                "BPGraph::set_edge_normalization".to_owned(),
+                format!("There is no edge between {} and {}", from, to),
+            ));
+        }
+        self.edge_normalization.insert((from, to), mode);
+        Ok(())
+    }
+
+    pub fn remove_edge_normalization(
+        &mut self,
+        from: NodeIndex,
+        to: NodeIndex,
+    ) -> Option<NormalizationMode> {
+        self.edge_normalization.remove(&(from, to))
+    }
+
+    //Normalization used for the messages sent from from to to
+    pub fn get_edge_normalization(
+        &self,
+        from: NodeIndex,
+        to: NodeIndex,
+    ) -> BPResult<NormalizationMode> {
+        let sender = if self.get_node(from)?.is_factor() {
+            self.factor_normalization
+        } else {
+            self.variable_normalization
+        };
+        Ok(self
+            .edge_normalization
+            .get(&(from, to))
+            .copied()
+            .or(sender)
+            .unwrap_or(self.normalization))
+    }
+
+    pub fn set_zero_message_policy(&mut self, policy: ZeroMessagePolicy) {
+        self.zero_message_policy = policy;
+    }
+
+    pub fn get_zero_message_policy(&self) -> ZeroMessagePolicy {
+        self.zero_message_policy
+    }
+
+    //All-zero messages recorded with ZeroMessagePolicy::MarkContradiction in the order they were found
+    pub fn get_contradictions(&self) -> &[Contradiction] {
+        &self.contradictions
+    }
+
+    //The nodes that sent an all-zero message, i.e., where the contradictory evidence meets
+    pub fn contradicting_nodes(&self) -> BTreeSet<NodeIndex> {
+        self.contradictions.iter().map(|c| c.from).collect()
+    }
+
+    pub fn clear_contradictions(&mut self) {
+        self.contradictions.clear();
+    }
+
+    //Maximal number of discarded messages kept for reuse (0 disables recycling)
+    pub fn set_msg_pool_size(&mut self, max_size: usize) {
+        self.msg_pool.set_max_size(max_size);
+    }
+
+    //Returns an empty message, reusing the allocation of a discarded message if possible
+    pub fn take_msg(&mut self) -> MsgT {
+        self.msg_pool.take()
+    }
+
+    pub fn get_msg_pool(&self) -> &MsgPool<MsgT> {
+        &self.msg_pool
+    }
+
+    //Fails if the node rejects the message (see NodeFunction::accepts_control_message), e.g.,
+    //a VariableNodeCtrl sent to a factor
+    pub fn send_control_message(
+        &mut self,
+        node_index: NodeIndex,
+        ctrl_msg: CtrlMsgT,
+    ) -> BPResult<CtrlMsgAT> {
+        match self.deliver_control_message(node_index, ctrl_msg, "BPGraph::send_control_message")? {
+            Some(answer) => Ok(answer),
+            None => {
+                let node = self.get_node(node_index)?;
+                Err(BPError::new(
+                    "BPGraph::send_control_message".to_owned(),
+                    format!(
+                        "{} node {} ({}) does not accept the control message",
+                        if node.is_factor() {
+                            "Factor"
+                        } else {
+                            "Variable"
+                        },
+                        node_index,
+                        node.get_name()
+                    ),
+                ))
+            }
+        }
+    }
+
+    //Returns None if the node rejects ctrl_msg. Messages the node does not accept are delivered nevertheless,
+    //so that node functions only overriding send_control_message keep working; for them a failure counts as
+    //a rejection.
+    fn deliver_control_message(
+        &mut self,
+        node_index: NodeIndex,
+        ctrl_msg: CtrlMsgT,
+        fn_name: &'static str,
+    ) -> BPResult<Option<CtrlMsgAT>> {
+        let node = self.get_node_mut(node_index)?;
+        let accepts = node.accepts_control_message(&ctrl_msg);
+        let changes_node = node.control_message_changes_node(&ctrl_msg);
+        let answer = match node.send_control_message(ctrl_msg) {
+            Ok(answer) => answer,
+            Err(_) if !accepts => return Ok(None),
+            Err(e) => {
+                return Err(e.attach_info_str(
+                    fn_name,
+                    format!("Node {} failed to handle the control message", node_index),
+                ))
+            }
+        };
+        //The control message may have changed the prior
+        if changes_node {
+            self.mark_changed(node_index);
+        }
+        Ok(Some(answer))
+    }
+
+    //Schedules ctrl_msg to be delivered to the node right before the messages of step are created, e.g., to
+    //change a parameter of a node function at step 10. Messages for the same step are delivered in the order
+    //in which they were scheduled, the answers can be retrieved with take_control_answers. Delivered by
+    //propagate_step, propagate_step_threaded (and the functions based on them), propagate_incremental and
+    //propagate_forward; a step that fails delivering (e.g., as the node rejects the message, see
+    //send_control_message) aborts the propagation. Fails if step has already been propagated or the node does not exist.
+    pub fn send_control_message_at(
+        &mut self,
+        node_index: NodeIndex,
+        ctrl_msg: CtrlMsgT,
+        step: usize,
+    ) -> BPResult<()> {
+        self.get_node(node_index)?;
+        if step < self.step {
+            return Err(BPError::new(
+                "BPGraph::send_control_message_at".to_owned(),
+                format!(
+                    "Step {} has already been propagated (current step: {})",
+                    step, self.step
+                ),
+            ));
+        }
+        self.scheduled_ctrl
+            .entry(step)
+            .or_default()
+            .push((node_index, ctrl_msg));
+        Ok(())
+    }
+
+    //Number of scheduled control messages that have not been delivered yet
+    pub fn scheduled_control_messages(&self) -> usize {
+        self.scheduled_ctrl.values().map(|msgs| msgs.len()).sum()
+    }
+
+    pub fn clear_scheduled_control_messages(&mut self) {
+        self.scheduled_ctrl.clear();
+    }
+
+    //Answers to the delivered scheduled control messages as (step, node, answer) in the order of delivery
+    pub fn take_control_answers(&mut self) -> Vec<(usize, NodeIndex, CtrlMsgAT)> {
+        std::mem::take(&mut self.scheduled_answers)
+    }
+
+    fn deliver_scheduled_control_messages(&mut self) -> BPResult<()> {
+        let step = self.step;
+        for (node_index, ctrl_msg) in self.scheduled_ctrl.remove(&step).unwrap_or_default() {
+            let answer = self
+                .send_control_message(node_index, ctrl_msg)
+                .map_err(|e| {
+                    e.attach_info_str(
+                        "BPGraph::deliver_scheduled_control_messages",
+                        format!(
+                            "Failed to deliver control message to node {} before step {}",
+                            node_index, step
+                        ),
+                    )
+                })?;
+            self.scheduled_answers.push((step, node_index, answer));
+        }
+        Ok(())
+    }
+
+    //Sends a copy of ctrl_msg to every node, returns the answers of the nodes not rejecting it (see
+    //send_control_message) in the order of the nodes
+    pub fn broadcast_control_message(
+        &mut self,
+        ctrl_msg: CtrlMsgT,
+    ) -> BPResult<Vec<(NodeIndex, CtrlMsgAT)>>
+    where
+        CtrlMsgT: Clone,
+    {
+        self.broadcast_control_message_filtered(ctrl_msg, NodeFilter::All)
+    }
+
+    pub fn broadcast_control_message_filtered(
+        &mut self,
+        ctrl_msg: CtrlMsgT,
+        filter: NodeFilter,
+    ) -> BPResult<Vec<(NodeIndex, CtrlMsgAT)>>
+    where
+        CtrlMsgT: Clone,
+    {
+        let mut answers = Vec::new();
+        for i in 0..self.nodes.len() {
+            if !filter.matches(self.nodes[i].is_factor()) {
+                continue;
+            }
+            let answer = self.deliver_control_message(
+                i,
+                ctrl_msg.clone(),
+                "BPGraph::broadcast_control_message",
+            )?;
+            if let Some(answer) = answer {
+                answers.push((i, answer));
+            }
+        }
+        Ok(answers)
+    }
+
+    //Creates a pool of thread_count workers that is used by all threaded propagation methods
+    //(which then run their threads as jobs on the pool), None drops the pool.
+    pub fn set_thread_pool(&mut self, thread_count: Option<u32>) {
+        self.thread_pool = thread_count.map(|n| Arc::new(ThreadPool::new(n)));
+    }
+
+    //Sets how the threaded propagation methods split the ready nodes of a step into batches
+    pub fn set_threading_config(&mut self, config: ThreadingConfig) -> BPResult<()> {
+        if config.min_batch == 0 || config.min_batch > config.max_batch {
+            return Err(BPError::new(
+                "BPGraph::set_threading_config".to_owned(),
+                format!(
+                    "Invalid batch sizes (min_batch: {}, max_batch: {})",
+                    config.min_batch, config.max_batch
+                ),
+            ));
+        }
+        self.threading_config = config;
+        Ok(())
+    }
+
+    pub fn get_threading_config(&self) -> ThreadingConfig {
+        self.threading_config
+    }
+
+    //Uses an existing pool, e.g., one shared by several graphs
+    pub fn use_thread_pool(&mut self, pool: Arc<ThreadPool>) {
+        self.thread_pool = Some(pool);
+    }
+
+    pub fn get_thread_pool(&self) -> Option<&Arc<ThreadPool>> {
+        self.thread_pool.as_ref()
+    }
+
+    //If set, the structure of the graph is checked before every step. The whole graph is validated once,
+    //afterwards only the nodes changed since (added nodes, added edges, ...) are validated again.
+    pub fn set_check_validity(&mut self, value: bool) {
+        self.check_validity = value;
+    }
+
+    //Forces the next check of validity to validate the whole graph again, e.g., after node functions changed
+    //their number of inputs through state shared outside of the graph
+    pub fn invalidate_validation(&mut self) {
+        self.unvalidated = None;
+    }
+
+    //None if the graph is not in windowed mode (see BPGraph::set_window)
+    pub fn get_window(&self) -> Option<&TimeWindow> {
+        self.window.as_ref()
+    }
+    pub(crate) fn get_window_mut(&mut self) -> &mut Option<TimeWindow> {
+        &mut self.window
+    }
+
+    fn check_structure(&mut self, fn_name: &str, msg: &str) -> BPResult<()> {
+        //Nodes may have been removed since they were marked
+        let len = self.nodes.len();
+        if let Some(nodes) = &mut self.unvalidated {
+            nodes.retain(|node| *node < len);
+        }
+        let issues = match &self.unvalidated {
+            None => self.validate().err().unwrap_or_default(),
+            Some(nodes) => self.validate_nodes(nodes.iter().copied()),
+        };
+        if !issues.is_empty() {
+            return Err(BPError::new(fn_name.to_owned(), msg.to_owned())
+                .attach_debug_object("issues", issues));
+        }
+        self.unvalidated = Some(BTreeSet::new());
+        Ok(())
+    }
+
+    //If set, the threaded propagation still creates messages in parallel but delivers them
+    //sorted by (from, to). Inboxes are then filled in a fixed order and results do not depend on the thread scheduling.
+    pub fn set_deterministic(&mut self, deterministic: bool) {
+        self.deterministic = deterministic;
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        self.nodes.iter().all(|n| n.is_initialized())
+    }
+
+    pub fn reset(&mut self) -> BPResult<()> {
+        self.nodes.iter_mut().try_for_each(|n| n.reset())
+    }
+
+    //Sets the step to 0 and drops all messages in the inboxes, so that the propagation starts over.
+    //Unlike reset, priors, structure and settings are kept and the nodes stay initialized.
+    pub fn reset_schedule_state(&mut self) -> BPResult<()> {
+        self.step = 0;
+        self.dirty.clear();
+        self.previous_marginals.clear();
+        self.current_marginals.clear();
+        let pool = &mut self.msg_pool;
+        self.nodes
+            .iter_mut()
+            .try_for_each(|n| n.reset_schedule_state(pool))
+    }
+
+    //Like reset_schedule_state, but only for nodes (the step is kept)
+    pub(crate) fn reset_schedule_state_of(&mut self, nodes: &[NodeIndex]) -> BPResult<()> {
+        for node in nodes {
+            let pool = &mut self.msg_pool;
+            let node = self.nodes.get_mut(*node).ok_or_else(|| {
+                BPError::new(
+                    "BPGraph::reset_schedule_state_of".to_owned(),
+                    format!("Node {} does not exist", node),
+                )
+            })?;
+            node.reset_schedule_state(pool)?;
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::unnecessary_unwrap)]
     pub fn initialize_node(
         &mut self,
         node_index: NodeIndex,
@@ -381,48 +2317,315 @@ where
         if msgs.is_some() {
             self.send(vec![(node_index, msgs.unwrap())]);
         }
-        let node = self.get_node_mut(node_index)?;
-        node.initialize()?;
+        let node = self.get_node_mut(node_index)?;
+        node.initialize()?;
+        Ok(())
+    }
+
+    //See InboxPolicy, nodes use InboxPolicy::KeepLatest by default
+    pub fn set_inbox_policy(&mut self, node_index: NodeIndex, policy: InboxPolicy) -> BPResult<()> {
+        self.get_node_mut(node_index)?.set_inbox_policy(policy);
+        Ok(())
+    }
+
+    //Sets the inbox policy of all nodes added so far
+    pub fn set_inbox_policy_all(&mut self, policy: InboxPolicy) {
+        self.nodes
+            .iter_mut()
+            .for_each(|node| node.set_inbox_policy(policy));
+    }
+
+    //Initializes all nodes that are not initialized and seals the graph (see unseal), unless it is in
+    //windowed mode. Afterwards, the newly initialized nodes are told about their neighbours (see
+    //NodeFunction::on_graph_initialized). Nodes that were not told because an earlier call failed are told
+    //by the next successful one.
+    pub fn initialize(&mut self) -> BPResult<()> {
+        for node in self.nodes.iter_mut() {
+            if !node.is_initialized() {
+                node.initialize()?;
+            }
+        }
+        self.finish_initialize()
+    }
+
+    //Checks the graph, calls NodeFunction::on_graph_initialized in the order of the indices for the
+    //initialized nodes that were not told yet and seals the graph
+    fn finish_initialize(&mut self) -> BPResult<()> {
+        if self.check_validity {
+            self.unvalidated = None;
+            self.check_structure("BPGraph::initialize", "Invalid graph")?;
+        }
+        for i in 0..self.nodes.len() {
+            if !self.nodes[i].needs_graph_info() {
+                continue;
+            }
+            let graph_info = GraphInfo {
+                node_index: i,
+                name: self.nodes[i].get_name().clone(),
+                neighbor_names: self.nodes[i]
+                    .get_connections()
+                    .iter()
+                    .map(|j| self.nodes[*j].get_name().clone())
+                    .collect(),
+                number_nodes: self.nodes.len(),
+            };
+            self.nodes[i]
+                .on_graph_initialized(&graph_info)
+                .map_err(|e| {
+                    e.attach_info_str(
+                        "BPGraph::initialize",
+                        format!(
+                            "Node {} failed to handle the initialization of the graph",
+                            i
+                        ),
+                    )
+                })?;
+        }
+        //Slices are added to windowed graphs over time
+        self.sealed = self.window.is_none();
+        Ok(())
+    }
+
+    //Allows to add nodes and edges after initialize. unseal itself does not change any node: add_edge marks
+    //the nodes getting new edges as not initialized (and as dirty for propagate_incremental), so they have
+    //to be initialized again, which seals the graph again.
+    pub fn unseal(&mut self) {
+        self.sealed = false;
+    }
+
+    pub fn is_sealed(&self) -> bool {
+        self.sealed
+    }
+
+    pub(crate) fn check_unsealed(&self, fn_name: &str) -> BPResult<()> {
+        if self.sealed {
+            return Err(BPError::new(
+                fn_name.to_owned(),
+                "Graph is sealed, as it has been initialized (see BPGraph::unseal)".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn propagate(&mut self, steps: usize) -> BPResult<()> {
+        if !self.is_initialized() {
+            return Err(BPError::new(
+                "BPGraph::propagate".to_owned(),
+                "Graph is not initialized".to_owned(),
+            ));
+        }
+        for _ in 0..steps {
+            self.propagate_step()?;
+        }
         Ok(())
     }
 
-    pub fn initialize(&mut self) -> BPResult<()> {
-        self.nodes.iter_mut().try_for_each(|node| {
-            if !node.is_initialized() {
-                node.initialize()
-            } else {
-                Ok(())
-            }
-        })
+    //Propagates until duration has passed (checked between steps), returns the number of completed steps.
+    //The results computed so far can then be retrieved by get_result.
+    pub fn propagate_for(&mut self, duration: Duration) -> BPResult<usize> {
+        if !self.is_initialized() {
+            return Err(BPError::new(
+                "BPGraph::propagate_for".to_owned(),
+                "Graph is not initialized".to_owned(),
+            ));
+        }
+        let deadline = Instant::now() + duration;
+        let mut completed_steps = 0;
+        while Instant::now() < deadline {
+            self.propagate_step()?;
+            completed_steps += 1;
+        }
+        Ok(completed_steps)
     }
 
-    pub fn propagate(&mut self, steps: usize) -> BPResult<()> {
+    pub fn propagate_step(&mut self) -> BPResult<StepReport> {
+        self.propagate_step_impl(None, false)
+    }
+
+    //Propagates on the subgraph induced by nodes: only these nodes create messages and messages to other nodes
+    //are dropped, so the rest of the graph is left untouched, e.g., to repair a region after a small change of
+    //the evidence. The messages from neighbours outside the subset are kept fixed: if the nodes keep the last
+    //received messages (see set_incremental), a node of the subset that receives post also gets the last
+    //messages of its outside neighbours again, otherwise it only receives messages from inside the subset.
+    //The steps count as regular propagation steps.
+    pub fn propagate_subset(&mut self, nodes: &[NodeIndex], steps: usize) -> BPResult<()> {
         if !self.is_initialized() {
             return Err(BPError::new(
-                "BPGraph::propagate".to_owned(),
+                "BPGraph::propagate_subset".to_owned(),
                 "Graph is not initialized".to_owned(),
             ));
         }
+        if let Some(idx) = nodes.iter().find(|idx| **idx >= self.nodes.len()) {
+            return Err(BPError::new(
+                "BPGraph::propagate_subset".to_owned(),
+                format!("Node {} out of bounds ({})", idx, self.nodes.len()),
+            ));
+        }
+        let mut subset = nodes.to_vec();
+        subset.sort_unstable();
+        subset.dedup();
         for _ in 0..steps {
-            self.propagate_step()?;
+            self.propagate_step_impl(Some(&subset), true)?;
         }
         Ok(())
     }
 
-    pub fn propagate_step(&mut self) -> BPResult<()> {
-        if self.check_validity && !self.is_valid() {
+    pub fn propagate_with_scheduler<S>(&mut self, steps: usize, scheduler: &mut S) -> BPResult<()>
+    where
+        S: Scheduler<T, MsgT, CtrlMsgT, CtrlMsgAT> + ?Sized,
+    {
+        if !self.is_initialized() {
             return Err(BPError::new(
-                "BPGraph::propagate_step".to_owned(),
-                "Invalid graph".to_owned(),
+                "BPGraph::propagate_with_scheduler".to_owned(),
+                "Graph is not initialized".to_owned(),
             ));
         }
-        info_print!("Propagating step {}", self.step);
+        for _ in 0..steps {
+            self.propagate_step_with_scheduler(scheduler)?;
+        }
+        Ok(())
+    }
+
+    //Only the nodes returned by the scheduler (and ready) create messages in this step
+    pub fn propagate_step_with_scheduler<S>(&mut self, scheduler: &mut S) -> BPResult<StepReport>
+    where
+        S: Scheduler<T, MsgT, CtrlMsgT, CtrlMsgAT> + ?Sized,
+    {
+        let mut batch = scheduler.next_batch(self);
+        batch.sort_unstable();
+        batch.dedup();
+        self.propagate_step_impl(Some(&batch), false)
+    }
+
+    //If restrict is set, only messages to nodes of the (sorted) batch are delivered
+    fn propagate_step_impl(
+        &mut self,
+        batch: Option<&[NodeIndex]>,
+        restrict: bool,
+    ) -> BPResult<StepReport> {
+        let start = Instant::now();
+        self.begin_step()?;
         info_print!("Creating messages");
-        let outgoing_msgs = self.create_messages()?;
+        let outgoing_msgs = self.create_messages(batch)?;
+        self.finish_step(outgoing_msgs, batch, restrict, start)
+    }
+
+    fn begin_step(&mut self) -> BPResult<()> {
+        if self.check_validity {
+            self.check_structure("BPGraph::propagate_step", "Invalid graph")?;
+        }
+        self.deliver_scheduled_control_messages()?;
+        info_print!("Propagating step {}", self.step);
+        Ok(())
+    }
+
+    //Sends the messages created in the step begun at start and ends the step
+    fn finish_step(
+        &mut self,
+        mut outgoing_msgs: Vec<(NodeIndex, Vec<(NodeIndex, MsgT)>)>,
+        batch: Option<&[NodeIndex]>,
+        restrict: bool,
+        start: Instant,
+    ) -> BPResult<StepReport> {
+        let subset = batch.filter(|_| restrict);
+        if let Some(subset) = subset {
+            for (_, msgs) in outgoing_msgs.iter_mut() {
+                msgs.retain(|(to, _)| subset.binary_search(to).is_ok());
+            }
+        }
+        let messages = count_messages(&outgoing_msgs);
+        let nodes_fired = outgoing_msgs.len();
         info_print!("Sending messages");
         self.send(outgoing_msgs)?;
+        if let (Some(subset), Some(clone_msg)) = (subset, self.clone_msg) {
+            self.resend_boundary_post(subset, clone_msg);
+        }
         info_print!("Done propagating step {}\n", self.step);
+        self.end_step()?;
         self.step += 1;
+        self.anneal();
+        self.snapshot_marginals()?;
+        self.emit_metrics(messages)?;
+        Ok(StepReport {
+            step: self.step - 1,
+            nodes_fired,
+            messages_sent: messages,
+            skipped_not_ready: batch.map_or(self.nodes.len(), |b| b.len()) - nodes_fired,
+            duration: start.elapsed(),
+        })
+    }
+
+    //Calls NodeFunction::on_step_end of every node for the current step
+    fn end_step(&mut self) -> BPResult<()> {
+        let step = self.step;
+        for (i, node) in self.nodes.iter_mut().enumerate() {
+            node.on_step_end(step).map_err(|e| {
+                e.attach_info_str(
+                    "BPGraph::end_step",
+                    format!("Node {} failed at the end of step {}", i, step),
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    //Nodes of the subset that received post get the last messages of their neighbours outside the subset again
+    fn resend_boundary_post(&mut self, subset: &[NodeIndex], clone_msg: fn(&MsgT) -> MsgT) {
+        for i in subset {
+            let node = &mut self.nodes[*i];
+            if !node.has_post() {
+                continue;
+            }
+            let boundary: Vec<(NodeIndex, MsgT)> = node
+                .get_last_received()
+                .iter()
+                .filter(|(from, _)| {
+                    subset.binary_search(from).is_err() && !node.has_post_from(*from)
+                })
+                .map(|(from, msg)| (*from, clone_msg(msg)))
+                .collect();
+            for (from, msg) in boundary {
+                node.send_post(from, msg);
+            }
+        }
+    }
+
+    fn emit_metrics(&mut self, messages: usize) -> BPResult<()> {
+        match self.metrics.as_mut() {
+            Some(metrics) => metrics.emit(
+                self.step - 1,
+                messages,
+                &self.current_marginals,
+                &self.previous_marginals,
+            ),
+            None => Ok(()),
+        }
+    }
+
+    //Only nodes that have received messages are updated, as the inboxes of the other nodes
+    //are empty (variable and factor nodes usually send in alternating steps).
+    fn snapshot_marginals(&mut self) -> BPResult<()> {
+        if let Some(marginal_fn) = self.marginal_fn {
+            let len = self.nodes.len();
+            self.previous_marginals.resize_with(len, || None);
+            self.current_marginals.resize_with(len, || None);
+            for (i, n) in self.nodes.iter().enumerate() {
+                if n.is_factor() || !n.has_post() {
+                    continue;
+                }
+                let marginal = marginal_fn(n).map_err(|e| {
+                    e.attach_info_str(
+                        "BPGraph::snapshot_marginals",
+                        format!(
+                            "Failed to compute marginal of node {} after step {}",
+                            i, self.step
+                        ),
+                    )
+                })?;
+                self.previous_marginals[i] =
+                    std::mem::replace(&mut self.current_marginals[i], marginal);
+            }
+        }
         Ok(())
     }
 
@@ -433,23 +2636,71 @@ where
     pub fn is_empty(&self) -> bool {
         self.nodes.is_empty()
     }
+
+    //(index, name, is_factor) of every node, ordered by index
+    pub fn nodes(&self) -> impl Iterator<Item = (NodeIndex, &str, bool)> + '_ {
+        self.nodes
+            .iter()
+            .enumerate()
+            .map(|(i, n)| (i, n.get_name().as_str(), n.is_factor()))
+    }
+
+    //Every edge once as (smaller index, larger index), ordered by the first and then by the connection order
+    pub fn edges(&self) -> impl Iterator<Item = (NodeIndex, NodeIndex)> + '_ {
+        self.nodes.iter().enumerate().flat_map(|(i, n)| {
+            n.get_connections()
+                .iter()
+                .filter(move |j| **j > i)
+                .map(move |j| (i, *j))
+        })
+    }
     //Returns Node (from) -> (Node(to) -> Msg)
-    fn create_messages(&mut self) -> BPResult<Vec<(NodeIndex, Vec<(NodeIndex, MsgT)>)>> {
+    //batch: Nodes allowed to send, all nodes if None.
+    //Nodes not in the batch keep their inbox, discard mode only applies to nodes in the batch.
+    #[allow(clippy::type_complexity)]
+    fn create_messages(
+        &mut self,
+        batch: Option<&[NodeIndex]>,
+    ) -> BPResult<Vec<(NodeIndex, Vec<(NodeIndex, MsgT)>)>> {
         let mut res = Vec::new();
+        if let Some(&idx) = batch.and_then(|b| b.iter().find(|idx| **idx >= self.nodes.len())) {
+            return Err(BPError::new(
+                "BPGraph::create_messages".to_owned(),
+                format!(
+                    "Scheduled node {} out of bounds ({})",
+                    idx,
+                    self.nodes.len()
+                ),
+            ));
+        }
+        let mut scheduled = batch.map(|b| b.iter().peekable());
+        let (strict, step) = (self.strict, self.step);
         for (i, node) in self.nodes.iter_mut().enumerate() {
+            if let Some(scheduled) = scheduled.as_mut() {
+                //batch is sorted
+                if scheduled.next_if_eq(&&i).is_none() {
+                    continue;
+                }
+            }
             if node.is_ready(self.step)? {
+                if strict {
+                    node.check_inbox()
+                        .map_err(|e| e.attach_debug_object("step", step))?;
+                }
                 debug_print!("Creating messages at node <{}>", node.get_name());
                 res.push((
                     i,
-                    node.create_messages().map_err(|e| {
-                        e.attach_debug_object("i", i)
-                            .attach_debug_object("node.get_name()", node.get_name())
-                    })?,
+                    node.create_messages_recycling(&mut self.msg_pool)
+                        .map_err(|e| {
+                            e.attach_debug_object("i", i)
+                                .attach_debug_object("node.get_name()", node.get_name())
+                        })?,
                 ));
             }
             else {
                 if node.discard_mode() {
-                    node.read_post();
+                    let post = node.read_post();
+                    node.recycle_post(post, &mut self.msg_pool);
                 }
             }
         }
@@ -458,14 +2709,20 @@ where
 
     //msgs: [(from, [(to, msg)])]
     fn send(&mut self, msgs: Vec<(NodeIndex, Vec<(NodeIndex, MsgT)>)>) -> BPResult<()> {
-        let normalize = self.normalize;
-        let check_validity = self.check_validity;
+        if let Some(history) = self.history.as_mut() {
+            history.record(self.step, &msgs);
+        }
+        let zero_message_policy = self.zero_message_policy;
         let step = self.step;
+        let kind = semiring::kind(self.semiring.as_deref());
         for (from, mut msgmap) in msgs.into_iter() {
-            for (to, mut msg) in msgmap.into_iter() {
+            for (to, mut msg) in msgmap.drain(..) {
+                let normalization = self.get_edge_normalization(from, to)?;
+                //Messages in the log domain or costs are not probabilities
+                let check_validity = self.check_validity && normalization.is_probability();
                 debug_print!("Sending from {} to {}", from, to);
                 let nto = self.get_node_mut(to)?;
-                if !nto.get_connections().contains(&from) {
+                if !nto.is_connected(from) {
                     return Err(BPError::new(
                         "BPGraph::send".to_owned(),
                         format!(
@@ -477,26 +2734,47 @@ where
                     .attach_debug_object("edges", nto.get_connections())
                     .attach_debug_object("name of node to sending to", nto.get_name()));
                 }
-                if normalize {
-                    msg.normalize().map_err(|e| {
+                if let Some(transform) = self.edge_transforms.get(&(from, to)) {
+                    msg = transform.transform(msg).map_err(|e| {
                         e.attach_info_str(
                             "BPGraph::send",
-                            format!("Trying to normalize message {} -> {}.", from, to),
+                            format!("Failed to transform message {} -> {}.", from, to),
                         )
-                        .attach_debug_object("msg (the message that could not be normalized)", &msg)
                         .attach_debug_object("step", step)
                     })?;
                 }
-                if check_validity && !msg.is_valid() {
-                    return Err(BPError::new(
-                        "BPGraph::send".to_owned(),
-                        format!("Trying to send an invalid message ({} -> {})", from, to),
-                    )
-                    .attach_debug_object("msg (the invalid message)", &msg)
-                    .attach_debug_object("step", step));
+                temper(&mut msg, self.temperature, kind);
+                if !normalize_outgoing(
+                    &mut msg,
+                    normalization,
+                    zero_message_policy,
+                    from,
+                    to,
+                    step,
+                )? {
+                    if zero_message_policy == ZeroMessagePolicy::MarkContradiction {
+                        self.contradictions.push(Contradiction { from, to, step });
+                    }
+                    self.msg_pool.recycle(msg);
+                    continue;
                 }
-                nto.send_post(from, msg);
+                //to was checked above
+                let nto = &mut self.nodes[to];
+                if check_validity {
+                    msg.validate().map_err(|e| {
+                        BPError::new(
+                            "BPGraph::send".to_owned(),
+                            format!(
+                                "Trying to send an invalid message ({} -> {}): {}",
+                                from, to, e
+                            ),
+                        )
+                        .attach_debug_object("step", step)
+                    })?;
+                }
+                nto.send_post_recycling(from, msg, &mut self.msg_pool);
             }
+            self.msg_pool.recycle_buffer(msgmap);
         }
         Ok(())
     }
@@ -509,21 +2787,227 @@ where
         &mut self,
         name: String,
         node_function: Box<dyn NodeFunction<T, MsgT, CtrlMsgT, CtrlMsgAT> + Send + Sync>,
-    ) -> NodeIndex {
-        self.nodes.push(Node::<T, MsgT, CtrlMsgT, CtrlMsgAT>::new(
+    ) -> BPResult<NodeIndex> {
+        self.add_node_directly(Node::<T, MsgT, CtrlMsgT, CtrlMsgAT>::new(
             name,
             node_function,
-        ));
-        self.nodes.len() - 1
+        ))
     }
 
-    pub fn add_node_directly(&mut self, node: Node<T, MsgT, CtrlMsgT, CtrlMsgAT>) -> NodeIndex {
+    pub fn add_factor<F>(&mut self, name: String, node_function: F) -> BPResult<NodeIndex>
+    where
+        F: NodeFunction<T, MsgT, CtrlMsgT, CtrlMsgAT> + Send + Sync + 'static,
+    {
+        self.add_node(name, Box::new(node_function))
+    }
+
+    pub fn add_node_directly(
+        &mut self,
+        mut node: Node<T, MsgT, CtrlMsgT, CtrlMsgAT>,
+    ) -> BPResult<NodeIndex> {
+        self.check_unsealed("BPGraph::add_node")?;
+        node.set_keep_last_received(self.clone_msg);
+        if let Some(semiring) = &self.semiring {
+            node.set_semiring(semiring.clone());
+        }
         self.nodes.push(node);
-        self.nodes.len() - 1
+        let idx = self.nodes.len() - 1;
+        self.mark_changed(idx);
+        Ok(idx)
+    }
+
+    //Marks a node whose messages have to be recomputed by propagate_incremental.
+    //Adding nodes and edges and sending control messages that change a node (see
+    //NodeFunction::control_message_changes_node) after the propagation has started does this automatically.
+    pub fn mark_dirty(&mut self, node_index: NodeIndex) -> BPResult<()> {
+        self.get_node(node_index)?;
+        self.dirty.insert(node_index);
+        Ok(())
+    }
+
+    pub fn get_dirty(&self) -> &BTreeSet<NodeIndex> {
+        &self.dirty
+    }
+
+    fn mark_changed(&mut self, node_index: NodeIndex) {
+        if let Some(unvalidated) = &mut self.unvalidated {
+            unvalidated.insert(node_index);
+        }
+        if self.clone_msg.is_some() && self.step > 0 {
+            self.dirty.insert(node_index);
+        }
+    }
+
+    //Moves the nodes into the subgraph induced by nodes (in the given order) and returns it together
+    //with the mapping from old to new indices. Edges to other nodes and their messages are dropped, as is the window.
+    //The settings of the graph are kept; the nodes have to be initialized again. The graph is left without
+    //nodes (and without the settings of single nodes or edges), unless this fails. See clone_subgraph to keep
+    //the graph.
+    pub fn subgraph(
+        &mut self,
+        nodes: &[NodeIndex],
+    ) -> BPResult<(Self, HashMap<NodeIndex, NodeIndex>)> {
+        let mapping = self.subgraph_mapping(nodes)?;
+        let mut sub = self.empty_subgraph(&mapping, nodes.len());
+        let rest = self.empty_subgraph(&HashMap::new(), 0);
+        let old = std::mem::replace(self, rest);
+        sub.msg_pool = old.msg_pool;
+        let mut old_nodes: Vec<Option<Node<T, MsgT, CtrlMsgT, CtrlMsgAT>>> =
+            old.nodes.into_iter().map(Some).collect();
+        for old in nodes {
+            let mut node = old_nodes[*old].take().expect("Node taken twice");
+            node.remap_indices(|idx| mapping.get(&idx).copied());
+            sub.nodes.push(node);
+        }
+        Ok((sub, mapping))
+    }
+
+    //Like subgraph, but the graph is kept and the nodes of the subgraph are copies (see Node::try_clone).
+    //Fails if the node function of one of the nodes cannot be cloned.
+    pub fn clone_subgraph(
+        &self,
+        nodes: &[NodeIndex],
+    ) -> BPResult<(Self, HashMap<NodeIndex, NodeIndex>)>
+    where
+        T: Clone + 'static,
+        MsgT: Clone + 'static,
+        CtrlMsgT: 'static,
+        CtrlMsgAT: 'static,
+    {
+        let mapping = self.subgraph_mapping(nodes)?;
+        let mut sub = self.empty_subgraph(&mapping, nodes.len());
+        for old in nodes {
+            let mut node = self.nodes[*old].try_clone().map_err(|e| {
+                e.attach_info_str(
+                    "BPGraph::clone_subgraph",
+                    format!("Could not clone node {}", old),
+                )
+            })?;
+            node.remap_indices(|idx| mapping.get(&idx).copied());
+            sub.nodes.push(node);
+        }
+        Ok((sub, mapping))
+    }
+
+    //Mapping from the indices of nodes to their positions
+    fn subgraph_mapping(&self, nodes: &[NodeIndex]) -> BPResult<HashMap<NodeIndex, NodeIndex>> {
+        let len = self.len();
+        let mut mapping = HashMap::with_capacity(nodes.len());
+        for (new, old) in nodes.iter().enumerate() {
+            if *old >= len {
+                return Err(BPError::new(
+                    "BPGraph::subgraph".to_owned(),
+                    format!("Index {} out of bounds ({})", old, len),
+                ));
+            }
+            if mapping.insert(*old, new).is_some() {
+                return Err(BPError::new(
+                    "BPGraph::subgraph".to_owned(),
+                    format!("Node {} given more than once", old),
+                ));
+            }
+        }
+        Ok(mapping)
+    }
+
+    //Graph without nodes with the settings of this graph restricted to the nodes in mapping
+    fn empty_subgraph(&self, mapping: &HashMap<NodeIndex, NodeIndex>, capacity: usize) -> Self {
+        BPGraph {
+            nodes: Vec::with_capacity(capacity),
+            step: self.step,
+            normalization: self.normalization,
+            zero_message_policy: self.zero_message_policy,
+            contradictions: Vec::new(),
+            strict: self.strict,
+            check_validity: self.check_validity,
+            deterministic: self.deterministic,
+            msg_pool: MsgPool::new(self.msg_pool.max_size()),
+            marginal_fn: self.marginal_fn,
+            previous_marginals: Vec::new(),
+            current_marginals: Vec::new(),
+            clone_msg: self.clone_msg,
+            dirty: BTreeSet::new(),
+            edge_weights: self
+                .edge_weights
+                .iter()
+                .filter_map(|(idx, w)| mapping.get(idx).map(|new| (*new, *w)))
+                .collect(),
+            edge_transforms: self
+                .edge_transforms
+                .iter()
+                .filter_map(|((from, to), t)| {
+                    Some(((*mapping.get(from)?, *mapping.get(to)?), t.clone()))
+                })
+                .collect(),
+            factor_normalization: self.factor_normalization,
+            variable_normalization: self.variable_normalization,
+            edge_normalization: self
+                .edge_normalization
+                .iter()
+                .filter_map(|((from, to), mode)| {
+                    Some(((*mapping.get(from)?, *mapping.get(to)?), *mode))
+                })
+                .collect(),
+            history: None,
+            metrics: None,
+            thread_pool: self.thread_pool.clone(),
+            threading_config: self.threading_config,
+            temperature: self.temperature,
+            annealing: self.annealing,
+            semiring: self.semiring.clone(),
+            directed: self
+                .directed
+                .iter()
+                .filter_map(|(p, c)| Some((*mapping.get(p)?, *mapping.get(c)?)))
+                .collect(),
+            unvalidated: None,
+            window: None,
+            scheduled_ctrl: BTreeMap::new(),
+            scheduled_answers: Vec::new(),
+            sealed: false,
+        }
+    }
+
+    //Appends all nodes of other (keeping their edges and inboxes), returns the offset
+    //that has to be added to indices of other. The appended nodes have to be initialized again.
+    //Fails if the graph is sealed, see unseal
+    pub fn merge(&mut self, other: Self) -> BPResult<NodeIndex> {
+        self.check_unsealed("BPGraph::merge")?;
+        let offset = self.len();
+        self.nodes.reserve(other.len());
+        for mut node in other.nodes {
+            node.remap_indices(|idx| Some(idx + offset));
+            self.add_node_directly(node)?;
+        }
+        self.edge_weights
+            .extend(other.edge_weights.iter().map(|(idx, w)| (idx + offset, *w)));
+        self.edge_transforms.extend(
+            other
+                .edge_transforms
+                .into_iter()
+                .map(|((from, to), t)| ((from + offset, to + offset), t)),
+        );
+        self.edge_normalization.extend(
+            other
+                .edge_normalization
+                .iter()
+                .map(|((from, to), mode)| ((from + offset, to + offset), *mode)),
+        );
+        self.directed
+            .extend(other.directed.iter().map(|(p, c)| (p + offset, c + offset)));
+        for (step, msgs) in other.scheduled_ctrl {
+            self.scheduled_ctrl.entry(step).or_default().extend(
+                msgs.into_iter()
+                    .map(|(idx, ctrl_msg)| (idx + offset, ctrl_msg)),
+            );
+        }
+        Ok(offset)
     }
 
+    //Fails if the graph is sealed, see unseal
     pub fn add_edge(&mut self, node0: NodeIndex, node1: NodeIndex) -> BPResult<()> {
         debug_print!("Connecting nodes {} and {}", node0, node1);
+        self.check_unsealed("BPGraph::add_edge")?;
         if self.get_node(node0)?.is_factor() == self.get_node(node1)?.is_factor() {
             debug_print!("Cannot link nodes: {} and {}", node0, node1);
             return Err(BPError::new(
@@ -540,17 +3024,153 @@ where
         }
         let n1 = self.get_node_mut(node1)?;
         n1.add_edge(node0)?;
+        self.mark_changed(node0);
+        self.mark_changed(node1);
+        Ok(())
+    }
+
+    //Like add_edge, but the connection is also bound to port of the factor, see NodeFunction::ports
+    pub fn add_edge_port(
+        &mut self,
+        factor: NodeIndex,
+        port: &str,
+        node: NodeIndex,
+    ) -> BPResult<()> {
+        self.check_unsealed("BPGraph::add_edge_port")?;
+        let f = self.get_node(factor)?;
+        if !f.is_factor() {
+            return Err(BPError::new(
+                "BPGraph::add_edge_port".to_owned(),
+                format!("Node {} is not a factor", factor),
+            ));
+        }
+        if f.get_port(port).is_some() {
+            return Err(BPError::new(
+                "BPGraph::add_edge_port".to_owned(),
+                format!("Port {} of node {} is already bound", port, factor),
+            ));
+        }
+        self.add_edge(factor, node)?;
+        self.get_node_mut(factor)?.add_port(port.to_owned(), node)
+    }
+
+    //Adds many edges at once. Duplicates (in edges or already in the graph) are skipped using hash sets
+    //instead of scanning the connections for every edge, and the work is split between the threads of the
+    //thread pool (or as many threads as available). Bounds, types and the number of inputs of the nodes
+    //are checked before the graph is changed. The connections are added in the order of edges.
+    pub fn add_edges_unchecked_parallel(
+        &mut self,
+        edges: &[(NodeIndex, NodeIndex)],
+    ) -> BPResult<()> {
+        self.check_unsealed("BPGraph::add_edges_unchecked_parallel")?;
+        let len = self.len();
+        let is_factor: Vec<bool> = self.nodes.iter().map(|n| n.is_factor()).collect();
+        let existing: Vec<&[NodeIndex]> = self
+            .nodes
+            .iter()
+            .map(|n| n.get_connections().as_slice())
+            .collect();
+        let thread_count = self
+            .thread_pool
+            .as_ref()
+            .map(|pool| pool.thread_count())
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()))
+            .clamp(1, len.max(1)) as u32;
+        let pool = self.thread_pool.as_deref();
+        let chunk = |t: u32, total: usize| {
+            let t = t as usize;
+            let n = thread_count as usize;
+            (t * total / n)..((t + 1) * total / n)
+        };
+
+        let checks = run_on_threads(pool, thread_count, |t| {
+            edges[chunk(t, edges.len())]
+                .iter()
+                .try_for_each(|(n0, n1)| {
+                    if *n0 >= len || *n1 >= len {
+                        return Err(BPError::new(
+                            "BPGraph::add_edges_unchecked_parallel".to_owned(),
+                            format!("Edge ({}, {}) out of bounds ({})", n0, n1, len),
+                        ));
+                    }
+                    if is_factor[*n0] == is_factor[*n1] {
+                        return Err(BPError::new(
+                            "BPGraph::add_edges_unchecked_parallel".to_owned(),
+                            format!(
+                                "Cannot link two nodes of same type (variable/factor) ({}, {})",
+                                n0, n1
+                            ),
+                        ));
+                    }
+                    Ok(())
+                })
+        });
+        checks.into_iter().collect::<BPResult<()>>()?;
+
+        //Both directions of every edge, grouped by the thread whose range of nodes contains the first node
+        //(node i is in range t iff t * len <= i * thread_count < (t + 1) * len, see chunk)
+        let mut shards: Vec<Vec<(NodeIndex, NodeIndex)>> = vec![Vec::new(); thread_count as usize];
+        for (n0, n1) in edges {
+            for (from, to) in [(*n0, *n1), (*n1, *n0)] {
+                shards[((from + 1) * thread_count as usize - 1) / len].push((from, to));
+            }
+        }
+
+        //Every thread collects the new connections of a range of nodes
+        let added: Vec<Vec<(NodeIndex, Vec<NodeIndex>)>> =
+            run_on_threads(pool, thread_count, |t| {
+                let range = chunk(t, len);
+                let mut new_connections: Vec<Vec<NodeIndex>> = vec![Vec::new(); range.len()];
+                let mut seen: HashSet<(NodeIndex, NodeIndex)> = HashSet::new();
+                let mut touched = vec![false; range.len()];
+                for (from, to) in &shards[t as usize] {
+                    let local = from - range.start;
+                    if !touched[local] {
+                        touched[local] = true;
+                        seen.extend(existing[*from].iter().map(|con| (*from, *con)));
+                    }
+                    if seen.insert((*from, *to)) {
+                        new_connections[local].push(*to);
+                    }
+                }
+                range
+                    .zip(new_connections)
+                    .filter(|(_, new)| !new.is_empty())
+                    .collect()
+            });
+
+        for (node, new) in added.iter().flatten() {
+            if let Some(n) = self.nodes[*node].number_inputs() {
+                let count = self.nodes[*node].get_connections().len() + new.len();
+                if count > n {
+                    return Err(BPError::new(
+                        "BPGraph::add_edges_unchecked_parallel".to_owned(),
+                        format!(
+                            "Wrong number ({}) of connections at node {} (needed: {})",
+                            count, node, n
+                        ),
+                    ));
+                }
+            }
+        }
+        for (node, new) in added.into_iter().flatten() {
+            self.nodes[node].extend_connections(new);
+            self.mark_changed(node);
+        }
         Ok(())
     }
 
-    fn get_node(&self, node: NodeIndex) -> BPResult<&Node<T, MsgT, CtrlMsgT, CtrlMsgAT>> {
+    pub(crate) fn get_node(
+        &self,
+        node: NodeIndex,
+    ) -> BPResult<&Node<T, MsgT, CtrlMsgT, CtrlMsgAT>> {
         let len = self.len();
         self.nodes.get(node).ok_or(BPError::new(
             "BPGraph::get_node".to_owned(),
             format!("Index {} out of bounds ({})", node, len),
         ))
     }
-    fn get_node_mut(
+    pub(crate) fn get_node_mut(
         &mut self,
         node: NodeIndex,
     ) -> BPResult<&mut Node<T, MsgT, CtrlMsgT, CtrlMsgAT>> {
@@ -568,47 +3188,319 @@ where
             .all(|(i, _)| self.is_valid_node(i))
     }
     pub fn is_valid_node(&self, node: NodeIndex) -> bool {
-        let nres = self.get_node(node);
-        if nres.is_err() {
-            info_print!("Could not find node {}", node);
-            return false;
+        let issues = self.validate_node(node);
+        for issue in &issues {
+            info_print!("{}", issue);
+        }
+        issues.is_empty()
+    }
+    //Collects all issues of the graph instead of stopping at the first one
+    pub fn validate(&self) -> Result<(), Vec<ValidationIssue>> {
+        let issues = self.validate_nodes(0..self.nodes.len());
+        if issues.is_empty() {
+            Ok(())
+        } else {
+            Err(issues)
+        }
+    }
+    //Issues of the given nodes in the order they are found, without duplicates
+    fn validate_nodes(&self, nodes: impl Iterator<Item = NodeIndex>) -> Vec<ValidationIssue> {
+        let mut seen = HashSet::new();
+        let mut issues = Vec::new();
+        for node in nodes {
+            for issue in self.validate_node(node) {
+                //Same type edges are found from both sides
+                if seen.insert(issue.clone()) {
+                    issues.push(issue);
+                }
+            }
+        }
+        issues
+    }
+    pub fn validate_node(&self, node: NodeIndex) -> Vec<ValidationIssue> {
+        let mut issues = Vec::new();
+        let n = match self.get_node(node) {
+            Ok(n) => n,
+            Err(_) => {
+                issues.push(ValidationIssue::NodeNotFound { node });
+                return issues;
+            }
+        };
+        //Frozen nodes are cut off from the window and never propagated again
+        if self.window.as_ref().is_some_and(|w| w.is_frozen(node)) {
+            return issues;
         }
-        let n = nres.ok().unwrap();
         let cons = n.get_connections();
         if cons.is_empty() {
-            info_print!("Node {} has no edges", node);
-            return false;
-        }
-        let number_inputs = n.number_inputs();
-        if number_inputs.is_some() && number_inputs.unwrap() != cons.len() {
-            info_print!(
-                "Node {} has a wrong number ({}) of inputs (should be: {})",
+            issues.push(ValidationIssue::IsolatedNode {
                 node,
-                cons.len(),
-                number_inputs.unwrap()
-            );
-            return false;
-        }
-        for con in n.get_connections() {
-            let nconres = self.get_node(*con);
-            if nconres.is_err() {
-                info_print!("Could not find node {} in connections of {}", con, node);
-                return false;
-            }
-            let ncon = nconres.ok().unwrap();
-            if !ncon.get_connections().contains(&node) {
-                info_print!(
-                    "{} does not have {} as connection but {} has {} as connection",
-                    ncon,
+                name: n.get_name().clone(),
+            });
+        }
+        if let Some(number_inputs) = n.number_inputs() {
+            if number_inputs != cons.len() {
+                issues.push(ValidationIssue::WrongArity {
+                    node,
+                    name: n.get_name().clone(),
+                    expected: number_inputs,
+                    got: cons.len(),
+                });
+            }
+        }
+        let mut seen = HashSet::with_capacity(cons.len());
+        for con in cons.iter() {
+            if !seen.insert(*con) {
+                issues.push(ValidationIssue::DuplicateEdge {
                     node,
+                    name: n.get_name().clone(),
+                    to: *con,
+                });
+                continue;
+            }
+            let ncon = match self.get_node(*con) {
+                Ok(ncon) => ncon,
+                Err(_) => {
+                    issues.push(ValidationIssue::DanglingEdge {
+                        node,
+                        name: n.get_name().clone(),
+                        to: *con,
+                    });
+                    continue;
+                }
+            };
+            if !ncon.is_connected(node) {
+                issues.push(ValidationIssue::AsymmetricEdge {
                     node,
-                    ncon
-                );
-                return false;
+                    name: n.get_name().clone(),
+                    to: *con,
+                    to_name: ncon.get_name().clone(),
+                });
+            }
+            if ncon.is_factor() == n.is_factor() {
+                let (node0, name0, node1, name1) = if node < *con {
+                    (node, n.get_name(), *con, ncon.get_name())
+                } else {
+                    (*con, ncon.get_name(), node, n.get_name())
+                };
+                issues.push(ValidationIssue::SameTypeEdge {
+                    node0,
+                    name0: name0.clone(),
+                    node1,
+                    name1: name1.clone(),
+                });
+            }
+        }
+        for port in n.declared_ports() {
+            if !n.get_port(&port).is_some_and(|to| n.is_connected(to)) {
+                issues.push(ValidationIssue::UnboundPort {
+                    node,
+                    name: n.get_name().clone(),
+                    port,
+                });
+            }
+        }
+        issues
+    }
+}
+
+impl<CtrlMsgT, CtrlMsgAT: Default> BPGraph<usize, DenseMsg, CtrlMsgT, CtrlMsgAT>
+where
+    CtrlMsgT: 'static,
+    CtrlMsgAT: 'static,
+{
+    //Like propagate_step, but the messages of VariableNodes and TableFactors are computed by a compute shader
+    //(see gpu) if the feature gpu is enabled, a GPU is available and the semiring is sum-product.
+    //Variables need messages from all of their (at least two) connections of the length of their prior.
+    //All other nodes create their messages on the CPU.
+    pub fn propagate_step_gpu(&mut self) -> BPResult<StepReport> {
+        #[cfg(feature = "gpu")]
+        if let Some(backend) = gpu::backend() {
+            if semiring::kind(self.semiring.as_deref()) == semiring::SemiringKind::SumProduct {
+                let start = Instant::now();
+                self.begin_step()?;
+                info_print!("Creating messages (GPU)");
+                let outgoing_msgs = self.create_messages_gpu(backend)?;
+                return self.finish_step(outgoing_msgs, None, false, start);
+            }
+        }
+        self.propagate_step()
+    }
+
+    pub fn propagate_gpu(&mut self, steps: usize) -> BPResult<()> {
+        if !self.is_initialized() {
+            return Err(BPError::new(
+                "BPGraph::propagate_gpu".to_owned(),
+                "Graph is not initialized".to_owned(),
+            ));
+        }
+        for _ in 0..steps {
+            self.propagate_step_gpu()?;
+        }
+        Ok(())
+    }
+
+    //Like create_messages (without batch), the nodes accepted by gpu_node_size are computed by the backend
+    #[cfg(feature = "gpu")]
+    #[allow(clippy::type_complexity)]
+    fn create_messages_gpu(
+        &mut self,
+        backend: &gpu::GpuBackend,
+    ) -> BPResult<Vec<(NodeIndex, Vec<(NodeIndex, DenseMsg)>)>> {
+        let mut res = Vec::new();
+        let mut batch = gpu::GpuBatch::new();
+        //(position in res, whether the node is a variable, receivers with the length of their message)
+        let mut pending: Vec<(usize, bool, Vec<(NodeIndex, usize)>)> = Vec::new();
+        let (strict, step) = (self.strict, self.step);
+        for (i, node) in self.nodes.iter_mut().enumerate() {
+            if !node.is_ready(step)? {
+                if node.discard_mode() {
+                    let post = node.read_post();
+                    node.recycle_post(post, &mut self.msg_pool);
+                }
+                continue;
+            }
+            if strict {
+                node.check_inbox()
+                    .map_err(|e| e.attach_debug_object("step", step))?;
+            }
+            let mut size = gpu_node_size(node);
+            if let Some((inputs, outputs)) = size {
+                if !batch.fits(backend, inputs, outputs) {
+                    flush_gpu_batch(
+                        &mut batch,
+                        backend,
+                        &mut pending,
+                        &mut res,
+                        &mut self.msg_pool,
+                    )?;
+                    if !batch.fits(backend, inputs, outputs) {
+                        size = None;
+                    }
+                }
+            }
+            if size.is_none() {
+                debug_print!("Creating messages at node <{}>", node.get_name());
+                res.push((
+                    i,
+                    node.create_messages_recycling(&mut self.msg_pool)
+                        .map_err(|e| {
+                            e.attach_debug_object("i", i)
+                                .attach_debug_object("node.get_name()", node.get_name())
+                        })?,
+                ));
+                continue;
+            }
+            let post = node.read_post();
+            let is_factor = node.is_factor();
+            let receivers = if is_factor {
+                let connections = node.get_connections();
+                let factor = node
+                    .node_function_as_any()
+                    .downcast_ref::<TableFactor<DenseMsg>>()
+                    .expect("Checked by gpu_node_size");
+                let msgs: Vec<&[Probability]> = connections
+                    .iter()
+                    .map(|con| {
+                        post.iter()
+                            .find(|(from, _)| from == con)
+                            .expect("Checked by gpu_node_size")
+                            .1
+                            .as_slice()
+                    })
+                    .collect();
+                batch.add_factor(factor.table(), factor.cardinalities(), &msgs);
+                connections
+                    .iter()
+                    .copied()
+                    .zip(factor.cardinalities().iter().copied())
+                    .collect()
+            } else {
+                let variable = node
+                    .node_function_as_any_mut()
+                    .downcast_mut::<VariableNode<usize, DenseMsg>>()
+                    .expect("Checked by gpu_node_size");
+                let msgs: Vec<&[Probability]> =
+                    post.iter().map(|(_, msg)| msg.as_slice()).collect();
+                batch.add_variable(variable.prior().map(|p| p.as_slice()), &msgs);
+                variable.mark_propagated();
+                post.iter().map(|(from, msg)| (*from, msg.len())).collect()
+            };
+            node.recycle_post(post, &mut self.msg_pool);
+            pending.push((res.len(), !is_factor, receivers));
+            res.push((i, Vec::new()));
+        }
+        flush_gpu_batch(
+            &mut batch,
+            backend,
+            &mut pending,
+            &mut res,
+            &mut self.msg_pool,
+        )?;
+        Ok(res)
+    }
+}
+
+//Input and output entries of the messages of node if they can be computed by the GPU (see propagate_step_gpu)
+#[cfg(feature = "gpu")]
+fn gpu_node_size<CtrlMsgT: 'static, CtrlMsgAT: Default + 'static>(
+    node: &Node<usize, DenseMsg, CtrlMsgT, CtrlMsgAT>,
+) -> Option<(usize, usize)> {
+    let degree = node.get_connections().len();
+    if node.post_len() != degree || !node.has_post_from_all_connections() {
+        return None;
+    }
+    let function = node.node_function_as_any();
+    if let Some(factor) = function.downcast_ref::<TableFactor<DenseMsg>>() {
+        let outputs = factor.cardinalities().iter().sum();
+        return Some((factor.table().len() + outputs, outputs));
+    }
+    let variable = function.downcast_ref::<VariableNode<usize, DenseMsg>>()?;
+    let post = node.get_post();
+    let len = post.first()?.1.len();
+    if degree < 2
+        || len == 0
+        || post.iter().any(|(_, msg)| msg.len() != len)
+        || variable.prior().is_some_and(|prior| prior.len() != len)
+    {
+        return None;
+    }
+    Some(((degree + 1) * len, degree * len))
+}
+
+//Runs the batch and moves the messages to the nodes in res they belong to
+#[cfg(feature = "gpu")]
+#[allow(clippy::type_complexity)]
+fn flush_gpu_batch(
+    batch: &mut gpu::GpuBatch,
+    backend: &gpu::GpuBackend,
+    pending: &mut Vec<(usize, bool, Vec<(NodeIndex, usize)>)>,
+    res: &mut [(NodeIndex, Vec<(NodeIndex, DenseMsg)>)],
+    pool: &mut MsgPool<DenseMsg>,
+) -> BPResult<()> {
+    if batch.is_empty() {
+        return Ok(());
+    }
+    let values = batch.run(backend).map_err(|e| {
+        e.attach_info_str("BPGraph::propagate_step_gpu", "GPU step failed".to_owned())
+    })?;
+    let mut values = values.into_iter();
+    for (pos, is_variable, receivers) in pending.drain(..) {
+        let mut msgs = pool.take_buffer(receivers.len());
+        for (to, len) in receivers {
+            let mut probabilities: Vec<Probability> =
+                values.by_ref().take(len).map(Probability::from).collect();
+            //Like the products of VariableNode (see DenseMsg::mult_msg)
+            let max = probabilities
+                .iter()
+                .fold(0.0, |m: Probability, p| m.max(*p));
+            if is_variable && max > 0.0 {
+                probabilities.iter_mut().for_each(|p| *p /= max);
             }
+            msgs.push((to, DenseMsg::from_vec(probabilities)));
         }
-        true
+        res[pos].1 = msgs;
     }
+    Ok(())
 }
 
 impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default> std::fmt::Display
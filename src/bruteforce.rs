@@ -0,0 +1,122 @@
+use crate::junction_tree::variable_priors;
+use crate::{BPError, BPGraph, BPResult, Msg, NodeIndex, Probability};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+
+impl<T, MsgT: Msg<T> + Clone, CtrlMsgT, CtrlMsgAT: Default> BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>
+where
+    T: Copy + Eq + Hash + Debug,
+{
+    //Exact marginals of all variable nodes by enumerating all joint assignments.
+    //Meant as ground truth for testing node functions on small graphs, fails if there are more than max_assignments assignments.
+    //Domains are given by the priors of the variable nodes, factors have to implement NodeFunction::potential.
+    //Fails if every assignment has probability 0 (e.g., contradictory evidence).
+    pub fn exact_marginals_bruteforce(
+        &self,
+        max_assignments: usize,
+    ) -> BPResult<HashMap<NodeIndex, HashMap<T, Probability>>> {
+        let (variables, priors) = variable_priors(self, "BPGraph::exact_marginals_bruteforce")?;
+        let var_id: HashMap<NodeIndex, usize> = variables
+            .iter()
+            .enumerate()
+            .map(|(i, idx)| (*idx, i))
+            .collect();
+        let number_assignments = priors
+            .iter()
+            .try_fold(1usize, |acc, prior| acc.checked_mul(prior.len()))
+            .filter(|n| *n <= max_assignments)
+            .ok_or_else(|| {
+                BPError::new(
+                    "BPGraph::exact_marginals_bruteforce".to_owned(),
+                    format!("Too many joint assignments (maximum: {})", max_assignments),
+                )
+            })?;
+
+        //Factors with the positions of their neighbours in the assignment
+        let mut factors = Vec::new();
+        for idx in 0..self.len() {
+            let node = self.get_node(idx)?;
+            if !node.is_factor() {
+                continue;
+            }
+            let scope = node
+                .get_connections()
+                .iter()
+                .map(|con| {
+                    var_id.get(con).copied().ok_or_else(|| {
+                        BPError::new(
+                            "BPGraph::exact_marginals_bruteforce".to_owned(),
+                            format!("Factor {} is connected to unknown variable {}", idx, con),
+                        )
+                    })
+                })
+                .collect::<BPResult<Vec<usize>>>()?;
+            factors.push((node, scope));
+        }
+
+        let mut marginals: Vec<Vec<Probability>> =
+            priors.iter().map(|prior| vec![0.0; prior.len()]).collect();
+        let mut assignment = vec![0; variables.len()];
+        let mut values = Vec::new();
+        for _ in 0..number_assignments {
+            let mut p: Probability = assignment
+                .iter()
+                .zip(priors.iter())
+                .map(|(a, prior)| prior[*a].1)
+                .product();
+            for (node, scope) in &factors {
+                if p == 0.0 {
+                    break;
+                }
+                values.clear();
+                values.extend(scope.iter().map(|v| priors[*v][assignment[*v]].0));
+                p *= node.potential(&values).ok_or_else(|| {
+                    BPError::new(
+                        "BPGraph::exact_marginals_bruteforce".to_owned(),
+                        format!("Factor {} does not implement potential", node.get_name()),
+                    )
+                })?;
+            }
+            for (v, a) in assignment.iter().enumerate() {
+                marginals[v][*a] += p;
+            }
+            //Next assignment
+            for (a, prior) in assignment.iter_mut().zip(priors.iter()) {
+                *a += 1;
+                if *a < prior.len() {
+                    break;
+                }
+                *a = 0;
+            }
+        }
+
+        //Every marginal sums to the total weight of all assignments
+        let sum: Probability = marginals
+            .first()
+            .map_or(1.0, |marginal| marginal.iter().sum());
+        if sum.is_nan() || sum <= 0.0 {
+            return Err(BPError::new(
+                "BPGraph::exact_marginals_bruteforce".to_owned(),
+                format!(
+                    "Joint assignments cannot be normalized (total weight: {})",
+                    sum
+                ),
+            ));
+        }
+        Ok(variables
+            .iter()
+            .zip(marginals.into_iter().zip(priors.iter()))
+            .map(|(idx, (marginal, prior))| {
+                (
+                    *idx,
+                    prior
+                        .iter()
+                        .zip(marginal)
+                        .map(|((v, _), p)| (*v, p / sum))
+                        .collect(),
+                )
+            })
+            .collect())
+    }
+}
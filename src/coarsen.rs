@@ -0,0 +1,165 @@
+use crate::{BPGraph, BPResult, EqualityFactor, Msg, NodeIndex, VariableNode};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+
+fn find(parent: &mut [NodeIndex], mut x: NodeIndex) -> NodeIndex {
+    while parent[x] != x {
+        parent[x] = parent[parent[x]];
+        x = parent[x];
+    }
+    x
+}
+
+impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default> BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>
+where
+    T: Debug + 'static,
+    MsgT: Clone + 'static,
+    CtrlMsgT: 'static,
+    CtrlMsgAT: 'static,
+{
+    //Merges the variables that are connected through EqualityFactors into one node and returns the mapping
+    //from old to new indices (merged variables are mapped to the same node, removed equality factors are
+    //not mapped). The merged node is the variable with the smallest index,
+    //it keeps its name and settings, gets the priors of the other variables (see VariableNode::add_prior) and
+    //their edges. Equality factors that only connect merged variables are removed, the others are connected
+    //to each merged node once. Inboxes of factors connected to merged variables are emptied.
+    //Variables are not merged if this would connect another factor to the merged node twice, if they are not
+    //VariableNodes or if one of the involved edges has a transform, is directed or is disabled.
+    //Variables that only have identical neighbourhoods are not merged, as this changes the distribution.
+    //Fails if the graph is sealed, the graph is unchanged then. The result has to be initialized again.
+    pub fn coarsen(&mut self) -> BPResult<HashMap<NodeIndex, NodeIndex>> {
+        self.check_unsealed("BPGraph::coarsen")?;
+        let len = self.len();
+        let mut is_equality = vec![false; len];
+        let mut is_variable = vec![false; len];
+        for idx in 0..len {
+            let f = self.get_node(idx)?.node_function_as_any();
+            is_equality[idx] = f.is::<EqualityFactor<T, MsgT>>();
+            is_variable[idx] = f.is::<VariableNode<T, MsgT>>();
+        }
+        //Factors (except equality factors) connected to a class of merged variables, stored at its root
+        let mut factors: Vec<HashSet<NodeIndex>> = Vec::with_capacity(len);
+        for (idx, is_variable) in is_variable.iter().enumerate() {
+            factors.push(if *is_variable {
+                self.get_connections(idx)?
+                    .iter()
+                    .copied()
+                    .filter(|f| !is_equality[*f])
+                    .collect()
+            } else {
+                HashSet::new()
+            });
+        }
+        let mut parent: Vec<NodeIndex> = (0..len).collect();
+        let mut removed = vec![false; len];
+        for e in (0..len).filter(|e| is_equality[*e]) {
+            if self.has_edge_settings(e) {
+                continue;
+            }
+            let node = self.get_node(e)?;
+            let connections = node.get_connections();
+            if !connections
+                .iter()
+                .all(|v| is_variable[*v] && !self.has_edge_settings(*v) && !node.is_disabled(*v))
+            {
+                continue;
+            }
+            let mut roots: Vec<NodeIndex> =
+                connections.iter().map(|v| find(&mut parent, *v)).collect();
+            roots.sort_unstable();
+            roots.dedup();
+            let total: usize = roots.iter().map(|r| factors[*r].len()).sum();
+            let union: HashSet<NodeIndex> = roots
+                .iter()
+                .flat_map(|r| factors[*r].iter().copied())
+                .collect();
+            if union.len() != total {
+                continue;
+            }
+            for r in &roots[1..] {
+                parent[*r] = roots[0];
+                factors[*r].clear();
+            }
+            factors[roots[0]] = union;
+            removed[e] = true;
+        }
+        let roots: Vec<NodeIndex> = (0..len).map(|idx| find(&mut parent, idx)).collect();
+        //Remaining equality factors connecting a single class
+        for e in (0..len).filter(|e| is_equality[*e]) {
+            let connections = self.get_connections(e)?;
+            if !removed[e]
+                && !self.has_edge_settings(e)
+                && connections
+                    .iter()
+                    .all(|v| roots[*v] == roots[connections[0]])
+            {
+                removed[e] = true;
+            }
+        }
+
+        //The priors are merged into copies first, so that nothing is changed if this fails
+        let mut merged: HashMap<NodeIndex, VariableNode<T, MsgT>> = HashMap::new();
+        for idx in (0..len).filter(|idx| roots[*idx] != *idx) {
+            let root = roots[idx];
+            let root_fn = match merged.entry(root) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(
+                    self.get_node_function::<VariableNode<T, MsgT>>(root)?
+                        .clone(),
+                ),
+            };
+            for (prior, weight) in self
+                .get_node_function::<VariableNode<T, MsgT>>(idx)?
+                .get_priors()
+            {
+                root_fn.add_prior(prior, *weight).map_err(|e| {
+                    e.attach_info_str(
+                        "BPGraph::coarsen",
+                        format!(
+                            "Could not merge the prior of node {} into node {}",
+                            idx, root
+                        ),
+                    )
+                })?;
+            }
+        }
+        for (root, root_fn) in merged {
+            *self.get_node_function_mut::<VariableNode<T, MsgT>>(root)? = root_fn;
+        }
+        for idx in (0..len).filter(|idx| roots[*idx] != *idx) {
+            let root = roots[idx];
+            let connections: Vec<NodeIndex> = self
+                .get_connections(idx)?
+                .iter()
+                .copied()
+                .filter(|f| !removed[*f])
+                .collect();
+            let node = self.get_node_mut(root)?;
+            node.extend_connections(connections);
+            let mut seen = HashSet::new();
+            node.get_connections_mut().retain(|c| seen.insert(*c));
+        }
+        for idx in (0..len).filter(|idx| !removed[*idx]) {
+            let node = self.get_node_mut(idx)?;
+            if !node.is_factor() || node.get_connections().iter().all(|c| roots[*c] == *c) {
+                continue;
+            }
+            node.remap_indices(|c| Some(roots[c]));
+            let mut seen = HashSet::new();
+            node.get_connections_mut().retain(|c| seen.insert(*c));
+            node.read_post();
+        }
+
+        let survivors: Vec<NodeIndex> = (0..len)
+            .filter(|idx| !removed[*idx] && roots[*idx] == *idx)
+            .collect();
+        let (graph, mut mapping) = self.subgraph(&survivors)?;
+        *self = graph;
+        for idx in (0..len).filter(|idx| roots[*idx] != *idx) {
+            let new = mapping[&roots[idx]];
+            mapping.insert(idx, new);
+        }
+        Ok(mapping)
+    }
+}
@@ -0,0 +1,200 @@
+use crate::variable_node::{FromVariableNodeCtrlAnswer, IntoVariableNodeCtrl};
+use crate::{
+    BPError, BPGraph, BPResult, MarginalChange, Msg, NodeIndex, Probability, VariableNodeCtrl,
+    ZeroMessagePolicy,
+};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+
+#[derive(Debug, Clone)]
+pub struct DecimationConfig {
+    //Maximal number of steps between two decimations
+    pub max_steps: usize,
+    //Propagation stops early once no marginal of a free variable changes by more than tolerance
+    pub tolerance: f64,
+    //Number of variables clamped at once
+    pub fix_per_round: usize,
+}
+
+impl Default for DecimationConfig {
+    fn default() -> Self {
+        DecimationConfig {
+            max_steps: 100,
+            tolerance: 1e-6,
+            fix_per_round: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecimationOutcome<T> {
+    //A value for every variable node
+    Solved(HashMap<NodeIndex, T>),
+    //Clamping last_fixed led to a marginal without mass (or messages that cannot be normalized).
+    //assignment contains the variables fixed so far, including last_fixed.
+    Contradiction {
+        assignment: HashMap<NodeIndex, T>,
+        last_fixed: NodeIndex,
+    },
+}
+
+impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT> BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>
+where
+    T: Copy + Ord + Hash + Debug,
+    MsgT: Clone,
+    CtrlMsgT: From<VariableNodeCtrl<MsgT>> + IntoVariableNodeCtrl<MsgT>,
+    CtrlMsgAT: FromVariableNodeCtrlAnswer<MsgT>,
+{
+    //Repeatedly propagates until the marginals converge, clamps the most polarized free variables
+    //(largest maximal probability) to their argmax by replacing their priors, and continues until every
+    //variable node is fixed or a contradiction arises. Ties are broken by the smallest index and value.
+    //The graph has to be initialized; propagation runs in pairs of steps so that the variable nodes hold the
+    //messages of the factors when the marginals are computed. The priors of the clamped nodes are not restored.
+    //Zero messages are recorded as contradictions (ZeroMessagePolicy::MarkContradiction) while decimating, other
+    //failures of the propagation are returned as errors.
+    pub fn solve_by_decimation(
+        &mut self,
+        config: &DecimationConfig,
+    ) -> BPResult<DecimationOutcome<T>> {
+        if !self.is_initialized() {
+            return Err(BPError::new(
+                "BPGraph::solve_by_decimation".to_owned(),
+                "Graph is not initialized".to_owned(),
+            ));
+        }
+        let variables: Vec<NodeIndex> = self
+            .nodes()
+            .filter(|(_, _, is_factor)| !is_factor)
+            .map(|(i, _, _)| i)
+            .collect();
+        let mut free = variables.clone();
+        let mut assignment = HashMap::new();
+        let mut last_fixed = None;
+        while !free.is_empty() {
+            let marginals = match (self.decimation_round(&free, config), last_fixed) {
+                (Ok(Some(marginals)), _) => marginals,
+                (Ok(None), Some(last_fixed)) => {
+                    return Ok(DecimationOutcome::Contradiction {
+                        assignment,
+                        last_fixed,
+                    })
+                }
+                (Ok(None), None) => {
+                    return Err(BPError::new(
+                        "BPGraph::solve_by_decimation".to_owned(),
+                        "The model has no mass before any variable has been fixed".to_owned(),
+                    ))
+                }
+                (Err(e), _) => {
+                    return Err(e.attach_info_str(
+                        "BPGraph::solve_by_decimation",
+                        "Propagation failed".to_owned(),
+                    ))
+                }
+            };
+            let mut candidates: Vec<(NodeIndex, T, Probability)> = free
+                .iter()
+                .zip(marginals.iter())
+                .map(|(node, marginal)| {
+                    let (v, p) = marginal
+                        .iter()
+                        .max_by(|(v0, p0), (v1, p1)| {
+                            p0.partial_cmp(p1)
+                                .unwrap_or(std::cmp::Ordering::Equal)
+                                .then_with(|| v1.cmp(v0))
+                        })
+                        .expect("Marginals are not empty");
+                    (*node, *v, *p)
+                })
+                .collect();
+            candidates.sort_by(|(n0, _, p0), (n1, _, p1)| {
+                p1.partial_cmp(p0)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+                    .then_with(|| n0.cmp(n1))
+            });
+            for (node, value, _) in candidates.into_iter().take(config.fix_per_round.max(1)) {
+                let domain =
+                    &marginals[free.iter().position(|n| *n == node).expect("Node is free")];
+                let mut prior = MsgT::new();
+                for v in domain.keys() {
+                    prior.insert(*v, if *v == value { 1.0 } else { 0.0 });
+                }
+                self.send_control_message(node, VariableNodeCtrl::SetPrior(Some(prior)).into())?;
+                assignment.insert(node, value);
+                last_fixed = Some(node);
+            }
+            free.retain(|n| !assignment.contains_key(n));
+        }
+        //Check that the last clamping did not lead to a contradiction
+        let round = self.decimation_round(&variables, config).map_err(|e| {
+            e.attach_info_str(
+                "BPGraph::solve_by_decimation",
+                "Propagation failed".to_owned(),
+            )
+        })?;
+        match (round, last_fixed) {
+            (Some(_), _) | (None, None) => Ok(DecimationOutcome::Solved(assignment)),
+            (None, Some(last_fixed)) => Ok(DecimationOutcome::Contradiction {
+                assignment,
+                last_fixed,
+            }),
+        }
+    }
+
+    //Propagates until the marginals of check converge and returns them (normalized to sum to one).
+    //Returns None if a message or a marginal has no mass, other failures are errors.
+    fn decimation_round(
+        &mut self,
+        check: &[NodeIndex],
+        config: &DecimationConfig,
+    ) -> BPResult<Option<Vec<HashMap<T, Probability>>>> {
+        let policy = self.get_zero_message_policy();
+        self.set_zero_message_policy(ZeroMessagePolicy::MarkContradiction);
+        let res = self.decimation_propagate(check, config);
+        self.set_zero_message_policy(policy);
+        res
+    }
+
+    fn decimation_propagate(
+        &mut self,
+        check: &[NodeIndex],
+        config: &DecimationConfig,
+    ) -> BPResult<Option<Vec<HashMap<T, Probability>>>> {
+        let contradictions = self.get_contradictions().len();
+        let mut previous: Option<Vec<HashMap<T, Probability>>> = None;
+        let mut steps = 0;
+        loop {
+            self.propagate(2)?;
+            steps += 2;
+            if self.get_contradictions().len() > contradictions {
+                return Ok(None);
+            }
+            let mut current = Vec::with_capacity(check.len());
+            for node in check {
+                //Node::get_result only fails if the product of the messages cannot be normalized
+                let marginal = match self.get_node(*node)?.get_result() {
+                    Ok(Some(marginal)) => marginal,
+                    Ok(None) | Err(_) => return Ok(None),
+                };
+                let sum: Probability = marginal.values().sum();
+                if !(sum > 0.0 && sum.is_finite()) {
+                    return Ok(None);
+                }
+                current.push(marginal.into_iter().map(|(v, p)| (v, p / sum)).collect());
+            }
+            let converged = previous.as_ref().is_some_and(|previous| {
+                check
+                    .iter()
+                    .zip(current.iter().zip(previous.iter()))
+                    .all(|(node, (cur, prev))| {
+                        MarginalChange::new(*node, cur, prev).max_abs_diff <= config.tolerance
+                    })
+            });
+            if converged || steps >= config.max_steps {
+                return Ok(Some(current));
+            }
+            previous = Some(current);
+        }
+    }
+}
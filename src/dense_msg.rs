@@ -0,0 +1,156 @@
+use crate::simd;
+use crate::{BPError, BPResult, Msg, MsgValidityError, NormalizationMode, Probability, Semiring};
+
+//Message over the values 0..len() stored as a plain vector.
+//Much faster than a HashMap for small, contiguous domains (e.g., Z_q).
+//See BPGraph::propagate_step_gpu for steps on a GPU.
+#[derive(Debug, PartialEq)]
+pub struct DenseMsg {
+    probabilities: Vec<Probability>,
+}
+
+//clone_from reuses the allocation (see MsgPool::take_clone)
+impl Clone for DenseMsg {
+    fn clone(&self) -> Self {
+        DenseMsg {
+            probabilities: self.probabilities.clone(),
+        }
+    }
+    fn clone_from(&mut self, source: &Self) {
+        self.probabilities.clone_from(&source.probabilities);
+    }
+}
+
+impl DenseMsg {
+    pub fn from_vec(probabilities: Vec<Probability>) -> Self {
+        DenseMsg { probabilities }
+    }
+    pub fn uniform(len: usize) -> Self {
+        DenseMsg {
+            probabilities: vec![1.0 / len as Probability; len],
+        }
+    }
+    pub fn zeros(len: usize) -> Self {
+        DenseMsg {
+            probabilities: vec![0.0; len],
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.probabilities.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.probabilities.is_empty()
+    }
+    pub fn as_slice(&self) -> &[Probability] {
+        &self.probabilities
+    }
+    pub fn as_mut_slice(&mut self) -> &mut [Probability] {
+        &mut self.probabilities
+    }
+    pub fn into_vec(self) -> Vec<Probability> {
+        self.probabilities
+    }
+    fn norm_max(&mut self) {
+        let max = simd::max_abs(&self.probabilities);
+        if max > 0.0 {
+            simd::divide(&mut self.probabilities, max);
+        }
+    }
+}
+
+impl IntoIterator for DenseMsg {
+    type Item = (usize, Probability);
+    type IntoIter = std::iter::Enumerate<std::vec::IntoIter<Probability>>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.probabilities.into_iter().enumerate()
+    }
+}
+
+impl Msg<usize> for DenseMsg {
+    fn new() -> Self {
+        DenseMsg {
+            probabilities: Vec::new(),
+        }
+    }
+    fn get(&self, value: usize) -> Option<Probability> {
+        self.probabilities.get(value).copied()
+    }
+    fn get_mut(&mut self, value: usize) -> Option<&mut Probability> {
+        self.probabilities.get_mut(value)
+    }
+    fn insert(&mut self, value: usize, p: Probability) {
+        if value >= self.probabilities.len() {
+            self.probabilities.resize(value + 1, 0.0);
+        }
+        self.probabilities[value] = p;
+    }
+    fn normalize(&mut self) -> BPResult<()> {
+        self.normalize_with(NormalizationMode::SumToOne)
+    }
+    fn normalize_with(&mut self, mode: NormalizationMode) -> BPResult<()> {
+        if mode == NormalizationMode::SumToOne {
+            let sum = simd::sum(&self.probabilities);
+            if sum > 0.0 && sum.is_finite() {
+                simd::scale(&mut self.probabilities, 1.0 / sum);
+                return Ok(());
+            }
+        }
+        let (shift, scale) = mode.affine(
+            self.probabilities.iter().copied(),
+            "DenseMsg::normalize_with",
+        )?;
+        self.probabilities
+            .iter_mut()
+            .for_each(|p| *p = (*p - shift) * scale);
+        Ok(())
+    }
+    fn normalization(&self, mode: NormalizationMode) -> BPResult<(Probability, Probability)> {
+        mode.affine(
+            self.probabilities.iter().copied(),
+            "DenseMsg::normalization",
+        )
+    }
+    fn is_valid(&self) -> bool {
+        self.probabilities
+            .iter()
+            .all(|p| !p.is_nan() && *p >= 0 as Probability && *p <= 1.0 as Probability)
+    }
+    fn validate(&self) -> Result<(), MsgValidityError> {
+        MsgValidityError::from_entries(self.probabilities.iter().copied().enumerate()).into_result()
+    }
+    fn mult_msg(&mut self, other: &Self) {
+        simd::mul_assign(&mut self.probabilities, &other.probabilities);
+        self.norm_max();
+    }
+    fn clear(&mut self) {
+        self.probabilities.clear();
+    }
+    fn mult_msg_weighted(&mut self, other: &Self, alpha: f64) {
+        self.probabilities
+            .iter_mut()
+            .zip(other.probabilities.iter())
+            .for_each(|(p0, p1)| *p0 *= p1.powf(alpha));
+        self.norm_max();
+    }
+    //Entries beyond the length of other are left unchanged
+    fn add_msg_weighted(&mut self, other: &Self, alpha_self: f64, alpha_other: f64) {
+        let n = self.len().min(other.len());
+        if alpha_self != 1.0 {
+            simd::scale(&mut self.probabilities[..n], alpha_self);
+        }
+        simd::axpy(
+            &mut self.probabilities[..n],
+            &other.probabilities,
+            alpha_other,
+        );
+    }
+    fn times_msg(&mut self, other: &Self, semiring: &dyn Semiring) {
+        self.probabilities
+            .iter_mut()
+            .zip(other.probabilities.iter())
+            .for_each(|(p0, p1)| *p0 = semiring.times(*p0, *p1));
+    }
+    fn for_each(&mut self, mut f: impl FnMut(Probability) -> Probability) {
+        self.probabilities.iter_mut().for_each(|p| *p = f(*p));
+    }
+}
@@ -0,0 +1,275 @@
+use crate::semiring::{self, SemiringKind};
+use crate::{BPError, BPResult, Msg, NodeFunction, NodeIndex, Probability, Semiring};
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::sync::Arc;
+
+//Shape of the cost of a DistanceFactor in the distance d between the labels
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DistanceCost {
+    //weight * |d|
+    Linear,
+    //weight * d^2
+    Quadratic,
+}
+
+//Pairwise factor over integer labels with truncated distance costs min(weight * |x0 - x1|^k, truncation)
+//(k = 1 for DistanceCost::Linear, 2 for DistanceCost::Quadratic) and potentials exp(-cost), lifted into the
+//semiring (see Semiring::lift). E.g., smoothness of disparities in stereo matching.
+//For MinSum, MaxSum and MaxProduct messages are computed in O(n log n) instead of O(n^2) with the distance
+//transform of Felzenszwalb and Huttenlocher, other semirings (and sum-product) use the direct O(n^2) sum.
+//The labels of a message to x are those of the message from x.
+#[derive(Clone)]
+pub struct DistanceFactor<MsgT> {
+    cost: DistanceCost,
+    weight: f64,
+    truncation: f64,
+    connections: Option<(NodeIndex, NodeIndex)>,
+    semiring: Option<Arc<dyn Semiring>>,
+    phantom: std::marker::PhantomData<MsgT>,
+}
+
+impl<MsgT> DistanceFactor<MsgT> {
+    //Use f64::INFINITY as truncation for untruncated costs
+    pub fn new(cost: DistanceCost, weight: f64, truncation: f64) -> Self {
+        DistanceFactor {
+            cost,
+            weight,
+            truncation,
+            connections: None,
+            semiring: None,
+            phantom: std::marker::PhantomData,
+        }
+    }
+
+    pub fn cost(&self, x0: i64, x1: i64) -> f64 {
+        let d = (x0 - x1).abs() as f64;
+        let c = match self.cost {
+            DistanceCost::Linear => self.weight * d,
+            DistanceCost::Quadratic => self.weight * d * d,
+        };
+        c.min(self.truncation)
+    }
+
+    fn lifted_potential(&self, x0: i64, x1: i64) -> Probability {
+        let p = (-self.cost(x0, x1)).exp();
+        self.semiring.as_ref().map_or(p, |s| s.lift(p))
+    }
+
+    //Message to the variable with the labels of target, given the message of the other variable
+    #[allow(clippy::type_complexity)]
+    fn message<T>(&self, source: Vec<(T, Probability)>, target: &MsgT) -> MsgT
+    where
+        T: Copy + Into<i64>,
+        MsgT: Msg<T> + Clone,
+    {
+        let targets: Vec<T> = target.clone().into_iter().map(|(v, _)| v).collect();
+        let s = self.semiring.as_deref();
+        //Entries to costs and back for the semirings with a distance transform
+        let (to_cost, from_cost): (fn(Probability) -> f64, fn(f64) -> Probability) =
+            match semiring::kind(s) {
+                SemiringKind::MinSum => (|p| p, |c| c),
+                SemiringKind::MaxSum => (|p| -p, |c| -c),
+                SemiringKind::MaxProduct => (|p| -p.ln(), |c| (-c).exp()),
+                _ => {
+                    let mut out = MsgT::new();
+                    for b in targets {
+                        let p = source.iter().fold(semiring::zero(s), |acc, (a, p)| {
+                            let psi = self.lifted_potential((*a).into(), b.into());
+                            semiring::plus(s, acc, semiring::times(s, *p, psi))
+                        });
+                        out.insert(b, p);
+                    }
+                    return out;
+                }
+            };
+        let source: Vec<(i64, f64)> = source
+            .into_iter()
+            .map(|(a, p)| (a.into(), to_cost(p)))
+            .collect();
+        let positions: Vec<i64> = targets.iter().map(|b| (*b).into()).collect();
+        let costs = self.distance_transform(source, &positions);
+        let mut out = MsgT::new();
+        for (b, c) in targets.into_iter().zip(costs) {
+            out.insert(b, from_cost(c));
+        }
+        out
+    }
+
+    //min_a (f(a) + cost(a, b)) for every b of targets
+    fn distance_transform(&self, mut source: Vec<(i64, f64)>, targets: &[i64]) -> Vec<f64> {
+        source.retain(|(_, f)| f.is_finite());
+        let min_source = source.iter().map(|(_, f)| *f).fold(f64::INFINITY, f64::min);
+        //The envelopes need increasing costs
+        if self.weight.is_nan() || self.weight <= 0.0 {
+            return targets
+                .iter()
+                .map(|b| {
+                    source
+                        .iter()
+                        .map(|(a, f)| f + self.cost(*a, *b))
+                        .fold(f64::INFINITY, f64::min)
+                })
+                .collect();
+        }
+        if source.is_empty() {
+            return vec![f64::INFINITY; targets.len()];
+        }
+        source.sort_by_key(|(a, _)| *a);
+        let mut order: Vec<usize> = (0..targets.len()).collect();
+        order.sort_by_key(|i| targets[*i]);
+        let mut res = vec![f64::INFINITY; targets.len()];
+        match self.cost {
+            DistanceCost::Linear => {
+                //Forward and backward pass over the merged positions
+                let mut forward = vec![f64::INFINITY; targets.len()];
+                let (mut j, mut best, mut pos) = (0, f64::INFINITY, i64::MIN);
+                for &i in &order {
+                    while j < source.len() && source[j].0 <= targets[i] {
+                        best = self.step(best, pos, source[j].0).min(source[j].1);
+                        pos = source[j].0;
+                        j += 1;
+                    }
+                    forward[i] = self.step(best, pos, targets[i]);
+                }
+                let (mut j, mut best, mut pos) = (source.len(), f64::INFINITY, i64::MAX);
+                for &i in order.iter().rev() {
+                    while j > 0 && source[j - 1].0 >= targets[i] {
+                        best = self.step(best, pos, source[j - 1].0).min(source[j - 1].1);
+                        pos = source[j - 1].0;
+                        j -= 1;
+                    }
+                    res[i] = forward[i].min(self.step(best, pos, targets[i]));
+                }
+            }
+            DistanceCost::Quadratic => {
+                //Lower envelope of the parabolas f(a) + weight * (x - a)^2
+                let w = self.weight;
+                let intersection = |(q, fq): (i64, f64), (v, fv): (i64, f64)| {
+                    let (q, v) = (q as f64, v as f64);
+                    ((fq + w * q * q) - (fv + w * v * v)) / (2.0 * w * (q - v))
+                };
+                let mut envelope: Vec<(i64, f64)> = Vec::with_capacity(source.len());
+                let mut starts: Vec<f64> = Vec::with_capacity(source.len());
+                for (a, f) in source {
+                    if let Some(last) = envelope.last_mut() {
+                        if last.0 == a {
+                            last.1 = last.1.min(f);
+                            continue;
+                        }
+                    }
+                    loop {
+                        match envelope.last() {
+                            Some(last) => {
+                                let s = intersection((a, f), *last);
+                                if s <= *starts.last().expect("One start per parabola") {
+                                    envelope.pop();
+                                    starts.pop();
+                                } else {
+                                    envelope.push((a, f));
+                                    starts.push(s);
+                                    break;
+                                }
+                            }
+                            None => {
+                                envelope.push((a, f));
+                                starts.push(f64::NEG_INFINITY);
+                                break;
+                            }
+                        }
+                    }
+                }
+                let mut k = 0;
+                for &i in &order {
+                    let x = targets[i] as f64;
+                    while k + 1 < envelope.len() && starts[k + 1] < x {
+                        k += 1;
+                    }
+                    let d = x - envelope[k].0 as f64;
+                    res[i] = envelope[k].1 + w * d * d;
+                }
+            }
+        }
+        res.into_iter()
+            .map(|c| c.min(min_source + self.truncation))
+            .collect()
+    }
+
+    //Linear cost of moving best from position from to position to
+    fn step(&self, best: f64, from: i64, to: i64) -> f64 {
+        if best.is_finite() {
+            best + self.weight * (to - from).abs() as f64
+        } else {
+            best
+        }
+    }
+}
+
+impl<T, MsgT: Msg<T> + Clone, CtrlMsgT, CtrlMsgAT: Default>
+    NodeFunction<T, MsgT, CtrlMsgT, CtrlMsgAT> for DistanceFactor<MsgT>
+where
+    T: Copy + Eq + Hash + Debug + Into<i64>,
+{
+    fn node_function(&mut self, inbox: Vec<(NodeIndex, MsgT)>) -> BPResult<Vec<(NodeIndex, MsgT)>> {
+        let (con0, con1) = self.connections.ok_or_else(|| {
+            BPError::new(
+                "DistanceFactor::node_function".to_owned(),
+                "DistanceFactor not initialized".to_owned(),
+            )
+        })?;
+        if inbox.len() != 2 {
+            return Err(BPError::new(
+                "DistanceFactor::node_function".to_owned(),
+                format!("Wrong number of messages ({}, needed: 2)", inbox.len()),
+            ));
+        }
+        let mut inbox = inbox;
+        if inbox[0].0 == con1 && inbox[1].0 == con0 {
+            inbox.swap(0, 1);
+        } else if inbox[0].0 != con0 || inbox[1].0 != con1 {
+            return Err(BPError::new(
+                "DistanceFactor::node_function".to_owned(),
+                "Received wrong messages".to_owned(),
+            ));
+        }
+        let msg1 = inbox.pop().expect("Two messages").1;
+        let msg0 = inbox.pop().expect("Two messages").1;
+        let out0 = self.message(msg1.clone().into_iter().collect(), &msg0);
+        let out1 = self.message(msg0.into_iter().collect(), &msg1);
+        Ok(vec![(con0, out0), (con1, out1)])
+    }
+    impl_clone_box!(T, MsgT, CtrlMsgT, CtrlMsgAT);
+
+    fn is_factor(&self) -> bool {
+        true
+    }
+    fn number_inputs(&self) -> Option<usize> {
+        Some(2)
+    }
+    fn initialize(&mut self, connections: Vec<NodeIndex>) -> BPResult<()> {
+        if connections.len() != 2 {
+            return Err(BPError::new(
+                "DistanceFactor::initialize".to_owned(),
+                "DistanceFactor needs exactly two connections".to_owned(),
+            ));
+        }
+        self.connections = Some((connections[0], connections[1]));
+        Ok(())
+    }
+    fn is_ready(&self, recv_from: &Vec<(NodeIndex, MsgT)>, _current_step: usize) -> BPResult<bool> {
+        Ok(recv_from.len() == 2)
+    }
+    fn reset(&mut self) -> BPResult<()> {
+        self.connections = None;
+        Ok(())
+    }
+    fn get_prior(&self) -> Option<MsgT> {
+        None
+    }
+    fn set_semiring(&mut self, semiring: Arc<dyn Semiring>) {
+        self.semiring = Some(semiring);
+    }
+    fn potential(&self, values: &[T]) -> Option<Probability> {
+        Some(self.lifted_potential(values[0].into(), values[1].into()))
+    }
+}
@@ -0,0 +1,484 @@
+use crate::wire::{read_batch, write_batch, WireValue};
+use crate::{BPError, BPGraph, BPResult, Msg, NodeFunction, NodeIndex, Probability};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt::Debug;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+
+/*
+Propagation of a graph split into shards processed by different workers (processes or machines), which
+exchange the messages crossing the shards over TCP after every step.
+
+Every worker builds only its shard from a GraphLayout (the structure of the whole graph, without node
+functions): the nodes it owns and placeholders (GhostNode) for their neighbours owned by other workers.
+Messages sent to a placeholder are forwarded to the owner of the node after every step (using the wire
+format), so a step of all workers is equivalent to a step of the whole graph.
+*/
+
+//Structure of a graph, in the order of the node indices
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GraphLayout {
+    pub connections: Vec<Vec<NodeIndex>>,
+    pub is_factor: Vec<bool>,
+}
+
+impl GraphLayout {
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+
+    //Assigns the nodes to parts (returns the part of every node) by splitting a breadth-first order of the
+    //nodes into parts of (almost) equal size, so that neighbours tend to be in the same part.
+    pub fn partition_bfs(&self, parts: usize) -> Vec<usize> {
+        let len = self.len();
+        let parts = parts.max(1);
+        let mut visited = vec![false; len];
+        let mut order = Vec::with_capacity(len);
+        let mut queue = VecDeque::new();
+        for root in 0..len {
+            if visited[root] {
+                continue;
+            }
+            visited[root] = true;
+            queue.push_back(root);
+            while let Some(n) = queue.pop_front() {
+                order.push(n);
+                for con in &self.connections[n] {
+                    if !visited[*con] {
+                        visited[*con] = true;
+                        queue.push_back(*con);
+                    }
+                }
+            }
+        }
+        let mut part = vec![0; len];
+        for (k, n) in order.into_iter().enumerate() {
+            part[n] = k * parts / len;
+        }
+        part
+    }
+}
+
+impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default> BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>
+where
+    T: Debug,
+{
+    pub fn layout(&self) -> GraphLayout {
+        GraphLayout {
+            connections: (0..self.len())
+                .map(|i| self.get_connections(i).expect("Index is valid").clone())
+                .collect(),
+            is_factor: self.nodes().map(|(_, _, is_factor)| is_factor).collect(),
+        }
+    }
+}
+
+//Placeholder for a node owned by another worker. It never sends messages, the messages it receives are
+//forwarded to the owner.
+#[derive(Clone)]
+pub struct GhostNode {
+    is_factor: bool,
+}
+
+impl GhostNode {
+    pub fn new(is_factor: bool) -> Self {
+        GhostNode { is_factor }
+    }
+}
+
+impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default> NodeFunction<T, MsgT, CtrlMsgT, CtrlMsgAT>
+    for GhostNode
+{
+    fn node_function(
+        &mut self,
+        _inbox: Vec<(NodeIndex, MsgT)>,
+    ) -> BPResult<Vec<(NodeIndex, MsgT)>> {
+        Err(BPError::new(
+            "GhostNode::node_function".to_owned(),
+            "Placeholders of remote nodes do not send messages".to_owned(),
+        ))
+    }
+    impl_clone_box!(T, MsgT, CtrlMsgT, CtrlMsgAT);
+
+    fn is_factor(&self) -> bool {
+        self.is_factor
+    }
+    fn number_inputs(&self) -> Option<usize> {
+        None
+    }
+    fn initialize(&mut self, _connections: Vec<NodeIndex>) -> BPResult<()> {
+        Ok(())
+    }
+    fn is_ready(
+        &self,
+        _recv_from: &Vec<(NodeIndex, MsgT)>,
+        _current_step: usize,
+    ) -> BPResult<bool> {
+        Ok(false)
+    }
+    fn reset(&mut self) -> BPResult<()> {
+        Ok(())
+    }
+    fn get_prior(&self) -> Option<MsgT> {
+        None
+    }
+}
+
+//One shard of a distributed graph
+pub struct DistributedWorker<T, MsgT: Msg<T>, CtrlMsgT = (), CtrlMsgAT: Default = ()>
+where
+    T: Debug,
+{
+    id: usize,
+    graph: BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>,
+    local_to_global: Vec<NodeIndex>,
+    global_to_local: HashMap<NodeIndex, NodeIndex>,
+    //Nodes 0..owned are owned by the worker, the others are placeholders
+    owned: usize,
+    //Local placeholder -> owner
+    ghosts: Vec<(NodeIndex, usize)>,
+    peers: BTreeMap<usize, TcpStream>,
+    max_frame_size: usize,
+    read_timeout: Option<Duration>,
+}
+
+//Defaults of DistributedWorker::set_max_frame_size and DistributedWorker::set_read_timeout
+const DEFAULT_MAX_FRAME_SIZE: usize = 1 << 30;
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default> DistributedWorker<T, MsgT, CtrlMsgT, CtrlMsgAT>
+where
+    T: WireValue + Clone + Debug + Send + Sync,
+    MsgT: Clone + Send + Sync,
+{
+    //Builds the shard of worker id: make_node is called for every node owned by the worker (part[node] == id),
+    //the neighbours owned by other workers become GhostNodes. The connections of the factors keep their
+    //order in the layout. The graph is initialized.
+    pub fn build(
+        id: usize,
+        layout: &GraphLayout,
+        part: &[usize],
+        mut make_node: impl FnMut(
+            NodeIndex,
+        ) -> (
+            String,
+            Box<dyn NodeFunction<T, MsgT, CtrlMsgT, CtrlMsgAT> + Send + Sync>,
+        ),
+    ) -> BPResult<Self> {
+        if part.len() != layout.len() {
+            return Err(BPError::new(
+                "DistributedWorker::build".to_owned(),
+                format!(
+                    "Partition has {} nodes, layout has {}",
+                    part.len(),
+                    layout.len()
+                ),
+            ));
+        }
+        let mut local_to_global: Vec<NodeIndex> =
+            (0..layout.len()).filter(|n| part[*n] == id).collect();
+        let owned = local_to_global.len();
+        let mut is_ghost = HashSet::new();
+        for n in 0..owned {
+            for con in &layout.connections[local_to_global[n]] {
+                if part[*con] != id && is_ghost.insert(*con) {
+                    local_to_global.push(*con);
+                }
+            }
+        }
+        let global_to_local: HashMap<NodeIndex, NodeIndex> = local_to_global
+            .iter()
+            .enumerate()
+            .map(|(local, global)| (*global, local))
+            .collect();
+        let mut graph = BPGraph::new();
+        graph.reserve(local_to_global.len());
+        for global in &local_to_global[..owned] {
+            let (name, node_function) = make_node(*global);
+            graph.add_node(name, node_function)?;
+        }
+        for global in &local_to_global[owned..] {
+            graph.add_node(
+                format!("ghost{}", global),
+                Box::new(GhostNode::new(layout.is_factor[*global])),
+            )?;
+        }
+        //Every edge with an owned end has both ends in the shard. Adding the edges from the side of the
+        //factors keeps the order of their connections.
+        for (local, global) in local_to_global.iter().enumerate() {
+            if !layout.is_factor[*global] {
+                continue;
+            }
+            for con in &layout.connections[*global] {
+                match global_to_local.get(con) {
+                    Some(con_local) if local < owned || *con_local < owned => {
+                        graph.add_edge(local, *con_local)?;
+                    }
+                    _ => (),
+                }
+            }
+        }
+        graph.initialize()?;
+        let ghosts = (owned..local_to_global.len())
+            .map(|local| (local, part[local_to_global[local]]))
+            .collect();
+        Ok(DistributedWorker {
+            id,
+            graph,
+            local_to_global,
+            global_to_local,
+            owned,
+            ghosts,
+            peers: BTreeMap::new(),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            read_timeout: Some(DEFAULT_READ_TIMEOUT),
+        })
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn graph(&self) -> &BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT> {
+        &self.graph
+    }
+
+    pub fn graph_mut(&mut self) -> &mut BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT> {
+        &mut self.graph
+    }
+
+    pub fn local_index(&self, global: NodeIndex) -> Option<NodeIndex> {
+        self.global_to_local.get(&global).copied()
+    }
+
+    pub fn global_index(&self, local: NodeIndex) -> Option<NodeIndex> {
+        self.local_to_global.get(local).copied()
+    }
+
+    //Workers owning neighbours of the nodes of this worker
+    pub fn peer_ids(&self) -> Vec<usize> {
+        let mut ids: Vec<usize> = self.ghosts.iter().map(|(_, owner)| *owner).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+
+    //Maximal size in bytes of a batch received from a peer (default: 1 GiB), larger ones fail the exchange
+    //before anything is allocated
+    pub fn set_max_frame_size(&mut self, max_frame_size: usize) {
+        self.max_frame_size = max_frame_size;
+    }
+
+    //Time to wait for a peer when reading from it (default: 60 s, None waits forever), also applied to the
+    //connected peers
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> BPResult<()> {
+        for stream in self.peers.values() {
+            stream
+                .set_read_timeout(timeout)
+                .map_err(|e| io_error("DistributedWorker::set_read_timeout", e))?;
+        }
+        self.read_timeout = timeout;
+        Ok(())
+    }
+
+    //Uses stream to exchange messages with worker peer
+    pub fn add_peer(&mut self, peer: usize, stream: TcpStream) -> BPResult<()> {
+        stream
+            .set_nodelay(true)
+            .and_then(|_| stream.set_read_timeout(self.read_timeout))
+            .map_err(|e| io_error("DistributedWorker::add_peer", e))?;
+        self.peers.insert(peer, stream);
+        Ok(())
+    }
+
+    //Connects to all peers: workers with a smaller id are connected to at addresses[peer] (retrying until
+    //timeout), connections from workers with a larger id are accepted on listener (bound to addresses[id]).
+    pub fn connect(
+        &mut self,
+        listener: &TcpListener,
+        addresses: &[SocketAddr],
+        timeout: Duration,
+    ) -> BPResult<()> {
+        let peers = self.peer_ids();
+        let id = self.id;
+        let deadline = Instant::now() + timeout;
+        for peer in peers.iter().filter(|p| **p < id) {
+            let address = addresses.get(*peer).ok_or_else(|| {
+                BPError::new(
+                    "DistributedWorker::connect".to_owned(),
+                    format!("No address for worker {}", peer),
+                )
+            })?;
+            let mut stream = loop {
+                match TcpStream::connect(address) {
+                    Ok(stream) => break stream,
+                    Err(e) if Instant::now() >= deadline => {
+                        return Err(io_error("DistributedWorker::connect", e).attach_info_str(
+                            "DistributedWorker::connect",
+                            format!("Could not connect to worker {}", peer),
+                        ))
+                    }
+                    Err(_) => std::thread::sleep(Duration::from_millis(10)),
+                }
+            };
+            stream
+                .write_all(&(self.id as u64).to_le_bytes())
+                .map_err(|e| io_error("DistributedWorker::connect", e))?;
+            self.add_peer(*peer, stream)?;
+        }
+        let mut missing = peers.iter().filter(|p| **p > self.id).count();
+        while missing > 0 {
+            let (mut stream, _) = listener
+                .accept()
+                .map_err(|e| io_error("DistributedWorker::connect", e))?;
+            let mut id = [0u8; 8];
+            stream
+                .set_read_timeout(self.read_timeout)
+                .and_then(|_| stream.read_exact(&mut id))
+                .map_err(|e| io_error("DistributedWorker::connect", e))?;
+            let id = u64::from_le_bytes(id) as usize;
+            if !peers.contains(&id) || id < self.id || self.peers.contains_key(&id) {
+                return Err(BPError::new(
+                    "DistributedWorker::connect".to_owned(),
+                    format!("Unexpected connection from worker {}", id),
+                ));
+            }
+            self.add_peer(id, stream)?;
+            missing -= 1;
+        }
+        Ok(())
+    }
+
+    //Runs steps steps, exchanging the messages to remote nodes after each one. All workers have to call
+    //this with the same number of steps.
+    pub fn propagate(&mut self, steps: usize) -> BPResult<()> {
+        for _ in 0..steps {
+            let step = self.graph.get_step();
+            self.graph.propagate(1)?;
+            self.exchange().map_err(|e| {
+                e.attach_info_str(
+                    "DistributedWorker::propagate",
+                    format!("Exchanging the messages of step {} failed", step),
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    //Result of a node owned by this worker (global index)
+    pub fn get_result(&self, global: NodeIndex) -> BPResult<Option<HashMap<T, Probability>>>
+    where
+        T: Copy + Eq + std::hash::Hash,
+    {
+        match self.local_index(global) {
+            Some(local) if local < self.owned => self.graph.get_result(local),
+            _ => Err(BPError::new(
+                "DistributedWorker::get_result".to_owned(),
+                format!("Node {} is not owned by worker {}", global, self.id),
+            )),
+        }
+    }
+
+    //Sends the inboxes of the placeholders to their owners and delivers the received messages
+    fn exchange(&mut self) -> BPResult<()> {
+        let mut outgoing: BTreeMap<usize, Vec<(NodeIndex, NodeIndex, MsgT)>> =
+            self.peers.keys().map(|peer| (*peer, Vec::new())).collect();
+        for (ghost, owner) in &self.ghosts {
+            let post = self.graph.get_node_mut(*ghost)?.read_post();
+            let entries = outgoing.get_mut(owner).ok_or_else(|| {
+                BPError::new(
+                    "DistributedWorker::exchange".to_owned(),
+                    format!("Not connected to worker {}", owner),
+                )
+            })?;
+            let to = self.local_to_global[*ghost];
+            entries.extend(
+                post.into_iter()
+                    .map(|(from, msg)| (self.local_to_global[from], to, msg)),
+            );
+        }
+        let batches: Vec<(usize, Vec<u8>)> = outgoing
+            .into_iter()
+            .map(|(peer, entries)| (peer, write_batch(entries)))
+            .collect();
+        let peers = &self.peers;
+        let max_frame_size = self.max_frame_size;
+        //Writing in another thread, so that large batches cannot block both ends
+        let received = crossbeam::scope(|scope| {
+            let writer = scope.spawn(move |_| -> BPResult<()> {
+                for (peer, batch) in &batches {
+                    let mut stream = &peers[peer];
+                    stream
+                        .write_all(&(batch.len() as u64).to_le_bytes())
+                        .and_then(|_| stream.write_all(batch))
+                        .map_err(|e| io_error("DistributedWorker::exchange", e))?;
+                }
+                Ok(())
+            });
+            let received: BPResult<Vec<Vec<u8>>> = peers
+                .values()
+                .map(|mut stream| {
+                    let mut len = [0u8; 8];
+                    stream
+                        .read_exact(&mut len)
+                        .map_err(|e| io_error("DistributedWorker::exchange", e))?;
+                    let len = u64::from_le_bytes(len);
+                    if len > max_frame_size as u64 {
+                        return Err(BPError::new(
+                            "DistributedWorker::exchange".to_owned(),
+                            format!(
+                                "Batch of {} bytes exceeds the maximal frame size ({} bytes, see set_max_frame_size)",
+                                len, max_frame_size
+                            ),
+                        ));
+                    }
+                    let mut batch = vec![0u8; len as usize];
+                    stream
+                        .read_exact(&mut batch)
+                        .map_err(|e| io_error("DistributedWorker::exchange", e))?;
+                    Ok(batch)
+                })
+                .collect();
+            writer.join().expect("Joining threads failed")?;
+            received
+        })
+        .expect("Scoped threading failed")?;
+        for batch in received {
+            for (from, to, msg) in read_batch::<T, MsgT>(&batch)? {
+                let (from, to) = match (self.local_index(from), self.local_index(to)) {
+                    (Some(from), Some(to)) if to < self.owned => (from, to),
+                    _ => {
+                        return Err(BPError::new(
+                            "DistributedWorker::exchange".to_owned(),
+                            format!(
+                                "Received a message for an edge not in the shard ({} -> {})",
+                                from, to
+                            ),
+                        ))
+                    }
+                };
+                let node = self.graph.get_node_mut(to)?;
+                if !node.is_connected(from) {
+                    return Err(BPError::new(
+                        "DistributedWorker::exchange".to_owned(),
+                        format!(
+                            "Received a message along a non-existent edge ({} -> {})",
+                            from, to
+                        ),
+                    ));
+                }
+                node.send_post(from, msg);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn io_error(fn_name: &str, e: std::io::Error) -> BPError {
+    BPError::new(fn_name.to_owned(), format!("I/O error: {}", e))
+}
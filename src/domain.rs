@@ -0,0 +1,249 @@
+use crate::{BPError, BPResult, DenseMsg, GraphInfo, Msg, NodeFunction, NodeIndex, Probability};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::sync::Arc;
+
+//Assigns the indices 0..len() to the values of a domain (in the given order),
+//so that values of any type can be used with DenseMsg.
+#[derive(Debug, Clone)]
+pub struct DomainMap<T> {
+    values: Vec<T>,
+    indices: HashMap<T, usize>,
+}
+
+impl<T> DomainMap<T>
+where
+    T: Clone + Eq + Hash + Debug,
+{
+    //Fails if a value occurs more than once
+    pub fn new(values: Vec<T>) -> BPResult<Self> {
+        let mut indices = HashMap::with_capacity(values.len());
+        for (i, v) in values.iter().enumerate() {
+            if indices.insert(v.clone(), i).is_some() {
+                return Err(BPError::new(
+                    "DomainMap::new".to_owned(),
+                    format!("Value {:?} occurs more than once", v),
+                ));
+            }
+        }
+        Ok(DomainMap { values, indices })
+    }
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+    pub fn index_of(&self, value: &T) -> Option<usize> {
+        self.indices.get(value).copied()
+    }
+    pub fn value(&self, index: usize) -> Option<&T> {
+        self.values.get(index)
+    }
+    pub fn values(&self) -> &[T] {
+        &self.values
+    }
+    //Values missing in msg get probability 0, fails for values that are not in the domain
+    pub fn to_dense(&self, msg: &HashMap<T, Probability>) -> BPResult<DenseMsg> {
+        let mut dense = DenseMsg::zeros(self.len());
+        for (v, p) in msg {
+            let i = self.index_of(v).ok_or_else(|| {
+                BPError::new(
+                    "DomainMap::to_dense".to_owned(),
+                    format!("Value {:?} is not in the domain", v),
+                )
+            })?;
+            dense.as_mut_slice()[i] = *p;
+        }
+        Ok(dense)
+    }
+    //Fails if msg is longer than the domain
+    pub fn from_dense(&self, msg: &DenseMsg) -> BPResult<HashMap<T, Probability>> {
+        if msg.len() > self.len() {
+            return Err(BPError::new(
+                "DomainMap::from_dense".to_owned(),
+                format!(
+                    "Message is longer ({}) than the domain ({})",
+                    msg.len(),
+                    self.len()
+                ),
+            ));
+        }
+        Ok(self
+            .values
+            .iter()
+            .cloned()
+            .zip(msg.as_slice().iter().copied())
+            .collect())
+    }
+}
+
+//Lets a node function working on DenseMsg (e.g., ModAddFactor) be used in a graph with HashMap messages
+//over arbitrary values. Messages are converted at the boundary of the node using the domain of each connection.
+pub struct DenseAdapter<T, CtrlMsgT = (), CtrlMsgAT = ()> {
+    inner: Box<dyn NodeFunction<usize, DenseMsg, CtrlMsgT, CtrlMsgAT> + Send + Sync>,
+    //One domain per connection (in the order of the connections), or a single domain for all connections
+    domains: Vec<Arc<DomainMap<T>>>,
+    connections: Option<Vec<NodeIndex>>,
+}
+
+impl<T, CtrlMsgT, CtrlMsgAT> DenseAdapter<T, CtrlMsgT, CtrlMsgAT> {
+    pub fn new(
+        inner: Box<dyn NodeFunction<usize, DenseMsg, CtrlMsgT, CtrlMsgAT> + Send + Sync>,
+        domain: Arc<DomainMap<T>>,
+    ) -> Self {
+        Self::with_domains(inner, vec![domain])
+    }
+    pub fn with_domains(
+        inner: Box<dyn NodeFunction<usize, DenseMsg, CtrlMsgT, CtrlMsgAT> + Send + Sync>,
+        domains: Vec<Arc<DomainMap<T>>>,
+    ) -> Self {
+        DenseAdapter {
+            inner,
+            domains,
+            connections: None,
+        }
+    }
+}
+
+impl<T, CtrlMsgT, CtrlMsgAT> DenseAdapter<T, CtrlMsgT, CtrlMsgAT>
+where
+    T: Clone + Eq + Hash + Debug,
+{
+    fn domain_of(&self, node: NodeIndex) -> BPResult<&DomainMap<T>> {
+        if self.domains.len() == 1 {
+            return Ok(&self.domains[0]);
+        }
+        let pos = self
+            .connections
+            .as_ref()
+            .and_then(|cons| cons.iter().position(|con| *con == node))
+            .ok_or_else(|| {
+                BPError::new(
+                    "DenseAdapter::domain_of".to_owned(),
+                    format!("Node {} is not a connection", node),
+                )
+            })?;
+        Ok(&self.domains[pos])
+    }
+    fn to_dense(
+        &self,
+        msgs: &[(NodeIndex, HashMap<T, Probability>)],
+    ) -> BPResult<Vec<(NodeIndex, DenseMsg)>> {
+        msgs.iter()
+            .map(|(idx, msg)| Ok((*idx, self.domain_of(*idx)?.to_dense(msg)?)))
+            .collect()
+    }
+}
+
+impl<T, CtrlMsgT, CtrlMsgAT: Default> NodeFunction<T, HashMap<T, Probability>, CtrlMsgT, CtrlMsgAT>
+    for DenseAdapter<T, CtrlMsgT, CtrlMsgAT>
+where
+    T: Clone + Eq + Hash + Debug,
+{
+    fn node_function(
+        &mut self,
+        inbox: Vec<(NodeIndex, HashMap<T, Probability>)>,
+    ) -> BPResult<Vec<(NodeIndex, HashMap<T, Probability>)>> {
+        let dense = self.to_dense(&inbox)?;
+        self.inner
+            .node_function(dense)?
+            .into_iter()
+            .map(|(idx, msg)| Ok((idx, self.domain_of(idx)?.from_dense(&msg)?)))
+            .collect()
+    }
+    //None if the inner node function cannot be cloned
+    fn clone_box(
+        &self,
+    ) -> Option<Box<dyn NodeFunction<T, HashMap<T, Probability>, CtrlMsgT, CtrlMsgAT> + Send + Sync>>
+    where
+        Self: Send + Sync + 'static,
+    {
+        Some(Box::new(DenseAdapter {
+            inner: self.inner.clone_box()?,
+            domains: self.domains.clone(),
+            connections: self.connections.clone(),
+        }))
+    }
+
+    fn is_factor(&self) -> bool {
+        self.inner.is_factor()
+    }
+    fn number_inputs(&self) -> Option<usize> {
+        self.inner.number_inputs()
+    }
+    fn initialize(&mut self, connections: Vec<NodeIndex>) -> BPResult<()> {
+        if self.domains.len() != 1 && self.domains.len() != connections.len() {
+            return Err(BPError::new(
+                "DenseAdapter::initialize".to_owned(),
+                format!(
+                    "Wrong number of domains ({}, needed: 1 or {})",
+                    self.domains.len(),
+                    connections.len()
+                ),
+            ));
+        }
+        self.connections = Some(connections.clone());
+        self.inner.initialize(connections)
+    }
+    fn is_ready(
+        &self,
+        recv_from: &Vec<(NodeIndex, HashMap<T, Probability>)>,
+        current_step: usize,
+    ) -> BPResult<bool> {
+        //Readiness only depends on the senders, the inner node function gets empty messages instead of conversions
+        let senders: Vec<(NodeIndex, DenseMsg)> = recv_from
+            .iter()
+            .map(|(idx, _)| (*idx, DenseMsg::zeros(0)))
+            .collect();
+        self.inner.is_ready(&senders, current_step)
+    }
+    fn reset(&mut self) -> BPResult<()> {
+        self.connections = None;
+        self.inner.reset()
+    }
+    fn get_prior(&self) -> Option<HashMap<T, Probability>> {
+        let prior = self.inner.get_prior()?;
+        //Priors are only defined for nodes with a single domain
+        self.domains.first()?.from_dense(&prior).ok()
+    }
+    fn accepts_control_message(&self, ctrl_msg: &CtrlMsgT) -> bool {
+        self.inner.accepts_control_message(ctrl_msg)
+    }
+    fn control_message_changes_node(&self, ctrl_msg: &CtrlMsgT) -> bool {
+        self.inner.control_message_changes_node(ctrl_msg)
+    }
+    fn send_control_message(&mut self, ctrl_msg: CtrlMsgT) -> BPResult<CtrlMsgAT> {
+        self.inner.send_control_message(ctrl_msg)
+    }
+    fn on_step_end(&mut self, step: usize) -> BPResult<()> {
+        self.inner.on_step_end(step)
+    }
+    fn on_graph_initialized(
+        &mut self,
+        connections: &[NodeIndex],
+        graph_info: &GraphInfo,
+    ) -> BPResult<()> {
+        self.inner.on_graph_initialized(connections, graph_info)
+    }
+    fn discard_mode(&self) -> bool {
+        self.inner.discard_mode()
+    }
+    fn potential(&self, values: &[T]) -> Option<Probability> {
+        //values are in the order of the connections
+        let indices = values
+            .iter()
+            .enumerate()
+            .map(|(k, v)| {
+                let domain = if self.domains.len() == 1 {
+                    &self.domains[0]
+                } else {
+                    self.domains.get(k)?
+                };
+                domain.index_of(v)
+            })
+            .collect::<Option<Vec<usize>>>()?;
+        self.inner.potential(&indices)
+    }
+}
@@ -0,0 +1,73 @@
+use crate::{BPGraph, BPResult, Msg, NodeIndex, Probability};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+
+impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default> BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>
+where
+    T: Copy + Eq + Hash + Debug,
+    MsgT: Clone,
+{
+    //E-step for one evidence case: propagates steps steps (one more if needed, see bethe_log_partition) and
+    //passes the belief of every factor that learns parameters (see NodeFunction::learns_parameters) to
+    //NodeFunction::accumulate_statistics. Returns the number of these factors.
+    //The beliefs are computed from the potentials, so the factors have to implement NodeFunction::potential.
+    pub fn accumulate_statistics(
+        &mut self,
+        steps: usize,
+        max_assignments: usize,
+    ) -> BPResult<usize> {
+        self.propagate_until_variables_have_post(steps)?;
+        let factors = self.learning_factors();
+        for factor in &factors {
+            let belief: HashMap<Vec<T>, Probability> = self
+                .cavity_factor_belief(*factor, max_assignments)?
+                .into_iter()
+                .map(|(values, b, _)| (values, b))
+                .collect();
+            self.get_node_mut(*factor)?
+                .accumulate_statistics(&belief)
+                .map_err(|e| {
+                    e.attach_info_str(
+                        "BPGraph::accumulate_statistics",
+                        format!("Factor {} could not accumulate statistics", factor),
+                    )
+                })?;
+        }
+        Ok(factors.len())
+    }
+
+    //M-step: every factor that learns parameters updates them from its accumulated statistics.
+    //The factors are marked as dirty (see propagate_incremental). Returns the number of updated factors.
+    pub fn update_parameters(&mut self) -> BPResult<usize> {
+        let factors = self.learning_factors();
+        for factor in &factors {
+            self.get_node_mut(*factor)?
+                .update_parameters()
+                .map_err(|e| {
+                    e.attach_info_str(
+                        "BPGraph::update_parameters",
+                        format!("Factor {} could not update its parameters", factor),
+                    )
+                })?;
+            self.mark_dirty(*factor)?;
+        }
+        Ok(factors.len())
+    }
+
+    //One EM iteration for the evidence currently in the graph: accumulate_statistics and update_parameters.
+    //For several evidence cases, call accumulate_statistics once per case and update_parameters at the end.
+    pub fn em_step(&mut self, steps: usize, max_assignments: usize) -> BPResult<usize> {
+        self.accumulate_statistics(steps, max_assignments)?;
+        self.update_parameters()
+    }
+
+    fn learning_factors(&self) -> Vec<NodeIndex> {
+        self.nodes()
+            .filter(|(i, _, is_factor)| {
+                *is_factor && self.get_node(*i).is_ok_and(|n| n.learns_parameters())
+            })
+            .map(|(i, _, _)| i)
+            .collect()
+    }
+}
@@ -0,0 +1,144 @@
+use crate::semiring;
+use crate::{BPError, BPResult, Msg, NodeFunction, NodeIndex, Probability, Semiring};
+use std::fmt::Debug;
+use std::sync::Arc;
+
+//Factor enforcing that all connected variables take the same value.
+//Every neighbour receives the product of the messages of all other neighbours,
+//so variables of different subgraphs can be linked (aliased) through this node.
+pub struct EqualityFactor<T, MsgT: Msg<T>> {
+    connections: Option<Vec<NodeIndex>>,
+    semiring: Option<Arc<dyn Semiring>>,
+    phantom: std::marker::PhantomData<(T, MsgT)>,
+}
+
+//Not derived, which would require T: Clone
+impl<T, MsgT: Msg<T>> Clone for EqualityFactor<T, MsgT> {
+    fn clone(&self) -> Self {
+        EqualityFactor {
+            connections: self.connections.clone(),
+            semiring: self.semiring.clone(),
+            phantom: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<T, MsgT: Msg<T>> EqualityFactor<T, MsgT> {
+    pub fn new() -> Self {
+        EqualityFactor {
+            connections: None,
+            semiring: None,
+            phantom: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default> NodeFunction<T, MsgT, CtrlMsgT, CtrlMsgAT>
+    for EqualityFactor<T, MsgT>
+where
+    T: PartialEq,
+    MsgT: Clone,
+{
+    fn node_function(&mut self, inbox: Vec<(NodeIndex, MsgT)>) -> BPResult<Vec<(NodeIndex, MsgT)>> {
+        NodeFunction::<T, MsgT, CtrlMsgT, CtrlMsgAT>::node_function_borrowed(
+            self,
+            inbox.iter().map(|(idx, msg)| (*idx, msg)).collect(),
+        )
+    }
+
+    fn node_function_borrowed(
+        &mut self,
+        inbox: Vec<(NodeIndex, &MsgT)>,
+    ) -> BPResult<Vec<(NodeIndex, MsgT)>> {
+        let connections = self.connections.as_ref().ok_or_else(|| {
+            BPError::new(
+                "EqualityFactor::node_function".to_owned(),
+                "EqualityFactor not initialized".to_owned(),
+            )
+        })?;
+        if inbox.len() != connections.len() {
+            return Err(BPError::new(
+                "EqualityFactor::node_function".to_owned(),
+                format!(
+                    "Wrong number of messages ({}, needed: {})",
+                    inbox.len(),
+                    connections.len()
+                ),
+            ));
+        }
+        let n = inbox.len();
+        if n < 2 {
+            return Ok(Vec::new());
+        }
+        //result[i-1] holds the message for inbox[i], starting with the product of all messages before i
+        let mut result: Vec<(NodeIndex, MsgT)> = Vec::with_capacity(n);
+        let semiring = self.semiring.as_deref();
+        let mut acc = inbox[0].1.clone();
+        for (idx, msg) in &inbox[1..] {
+            result.push((*idx, acc.clone()));
+            semiring::times_msg(semiring, &mut acc, msg);
+        }
+        acc = inbox[n - 1].1.clone();
+        for i in (1..n - 1).rev() {
+            semiring::times_msg(semiring, &mut result[i - 1].1, &acc);
+            semiring::times_msg(semiring, &mut acc, inbox[i].1);
+        }
+        result.push((inbox[0].0, acc));
+        Ok(result)
+    }
+
+    fn borrows_inbox(&self) -> bool {
+        true
+    }
+
+    impl_clone_box!(T, MsgT, CtrlMsgT, CtrlMsgAT);
+
+    fn is_factor(&self) -> bool {
+        true
+    }
+
+    fn number_inputs(&self) -> Option<usize> {
+        None
+    }
+
+    fn initialize(&mut self, connections: Vec<NodeIndex>) -> BPResult<()> {
+        self.connections = Some(connections);
+        Ok(())
+    }
+
+    fn is_ready(&self, recv_from: &Vec<(NodeIndex, MsgT)>, _current_step: usize) -> BPResult<bool> {
+        Ok(recv_from.len()
+            == self
+                .connections
+                .as_ref()
+                .expect("EqualityFactor not initialized.")
+                .len())
+    }
+
+    fn reset(&mut self) -> BPResult<()> {
+        self.connections = None;
+        Ok(())
+    }
+
+    fn get_prior(&self) -> Option<MsgT> {
+        None
+    }
+
+    fn set_semiring(&mut self, semiring: Arc<dyn Semiring>) {
+        self.semiring = Some(semiring);
+    }
+
+    fn potential(&self, values: &[T]) -> Option<Probability> {
+        Some(if values.windows(2).all(|w| w[0] == w[1]) {
+            1.0
+        } else {
+            0.0
+        })
+    }
+}
+
+impl<T, MsgT: Msg<T>> Default for EqualityFactor<T, MsgT> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
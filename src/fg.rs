@@ -0,0 +1,227 @@
+use crate::junction_tree::variable_priors;
+use crate::{BPError, BPGraph, BPResult, Msg, NodeIndex, Probability, TableFactor};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{BufRead, Write};
+
+//Reader and writer for the .fg factor graph format of libDAI (also used by pgmpy and the UAI benchmarks):
+//
+//  <number of factors>
+//  per factor, separated by blank lines:
+//  <number of variables>
+//  <labels of the variables>
+//  <cardinalities of the variables>
+//  <number of nonzero entries>
+//  <index> <value>      (one line per nonzero entry)
+//
+//Lines starting with # are comments. The index of an entry is its position in the table of TableFactor,
+//i.e., the value of the first variable changes fastest.
+
+//Reads a .fg file into a graph over usize with one TableFactor per factor. Every label becomes a variable
+//named after the label with a uniform prior over 0..cardinality, added in the order of the labels.
+//Factors without variables only scale the distribution and are skipped.
+pub fn read_fg<MsgT, R: BufRead>(reader: R) -> BPResult<BPGraph<usize, MsgT>>
+where
+    MsgT: Msg<usize> + Clone + Send + Sync + 'static,
+{
+    let error = |msg: String| BPError::new("read_fg".to_owned(), msg);
+    let mut tokens = Vec::new();
+    for line in reader.lines() {
+        let line = line.map_err(|e| error(format!("Reading failed: {}", e)))?;
+        if line.trim_start().starts_with('#') {
+            continue;
+        }
+        tokens.extend(line.split_whitespace().map(|t| t.to_owned()));
+    }
+    let mut tokens = tokens.into_iter();
+    let mut next = |what: &str| {
+        tokens
+            .next()
+            .ok_or_else(|| error(format!("Unexpected end of file, expected {}", what)))
+    };
+    let parse_usize = |token: String, what: &str| {
+        token
+            .parse::<usize>()
+            .map_err(|_| error(format!("Invalid {} '{}'", what, token)))
+    };
+
+    let number_factors = parse_usize(next("number of factors")?, "number of factors")?;
+    //Labels, cardinalities and (nonzero) tables of the factors
+    let mut factors = Vec::with_capacity(number_factors);
+    let mut cardinality_of: BTreeMap<usize, usize> = BTreeMap::new();
+    for f in 0..number_factors {
+        let number_vars = parse_usize(next("number of variables")?, "number of variables")?;
+        let mut labels = Vec::with_capacity(number_vars);
+        for _ in 0..number_vars {
+            labels.push(parse_usize(next("label")?, "label")?);
+        }
+        if labels.iter().collect::<HashSet<_>>().len() != labels.len() {
+            return Err(error(format!("Factor {} contains a variable twice", f)));
+        }
+        let mut cardinalities = Vec::with_capacity(number_vars);
+        for label in &labels {
+            let cardinality = parse_usize(next("cardinality")?, "cardinality")?;
+            if cardinality == 0 {
+                return Err(error(format!("Variable {} has cardinality 0", label)));
+            }
+            if *cardinality_of.entry(*label).or_insert(cardinality) != cardinality {
+                return Err(error(format!(
+                    "Variable {} has cardinality {} in factor {}, but {} before",
+                    label, cardinality, f, cardinality_of[label]
+                )));
+            }
+            cardinalities.push(cardinality);
+        }
+        let size: usize = cardinalities.iter().product();
+        let mut table = vec![0.0; size];
+        let nonzeros = parse_usize(next("number of entries")?, "number of entries")?;
+        for _ in 0..nonzeros {
+            let index = parse_usize(next("index")?, "index")?;
+            let token = next("value")?;
+            let value = token
+                .parse::<Probability>()
+                .map_err(|_| error(format!("Invalid value '{}'", token)))?;
+            if index >= size {
+                return Err(error(format!(
+                    "Index {} out of range in factor {} ({} entries)",
+                    index, f, size
+                )));
+            }
+            table[index] = value;
+        }
+        factors.push((labels, cardinalities, table));
+    }
+    if next("end of file").is_ok() {
+        return Err(error("Trailing tokens after the last factor".to_owned()));
+    }
+
+    let mut graph = BPGraph::new();
+    let mut node_of = HashMap::new();
+    for (label, cardinality) in cardinality_of {
+        let mut prior = MsgT::new();
+        for v in 0..cardinality {
+            prior.insert(v, 1.0 / cardinality as Probability);
+        }
+        node_of.insert(label, graph.add_variable(label.to_string(), prior)?);
+    }
+    for (f, (labels, cardinalities, table)) in factors.into_iter().enumerate() {
+        if labels.is_empty() {
+            continue;
+        }
+        let factor =
+            graph.add_factor(format!("f{}", f), TableFactor::new(cardinalities, table)?)?;
+        for label in labels {
+            graph.add_edge(node_of[&label], factor)?;
+        }
+    }
+    Ok(graph)
+}
+
+//Writes the factors of a graph over usize in the .fg format, such that read_fg restores the model.
+//The domain of a variable is 0..cardinality, cardinality - 1 being the largest value of its prior, and the
+//tables are taken from the potentials of the factors (see NodeFunction::potential). Priors that are not
+//uniform are written as additional factors with a single variable. The variables are labeled with their
+//names if these are distinct numbers and with their position among the variables otherwise.
+pub fn write_fg<MsgT, CtrlMsgT, CtrlMsgAT: Default, W: Write>(
+    graph: &BPGraph<usize, MsgT, CtrlMsgT, CtrlMsgAT>,
+    writer: &mut W,
+) -> BPResult<()>
+where
+    MsgT: Msg<usize> + Clone,
+{
+    let error = |msg: String| BPError::new("write_fg".to_owned(), msg);
+    let (variables, priors) = variable_priors(graph, "write_fg")?;
+    let names: Vec<Option<usize>> = variables
+        .iter()
+        .map(|v| graph.get_node(*v).map(|node| node.get_name().parse().ok()))
+        .collect::<BPResult<_>>()?;
+    let numbered = names.iter().all(|name| name.is_some())
+        && names.iter().collect::<HashSet<_>>().len() == names.len();
+    let mut label_of = HashMap::new();
+    let mut cardinality_of = HashMap::new();
+    //Factors as (variables, table)
+    let mut factors: Vec<(Vec<NodeIndex>, Vec<Probability>)> = Vec::new();
+    for (i, (v, prior)) in variables.iter().zip(priors).enumerate() {
+        label_of.insert(*v, if numbered { names[i].unwrap() } else { i });
+        let cardinality = prior.iter().map(|(value, _)| value + 1).max().unwrap_or(0);
+        cardinality_of.insert(*v, cardinality);
+        let mut table = vec![0.0; cardinality];
+        for (value, p) in prior {
+            table[value] = p;
+        }
+        if table.windows(2).any(|w| w[0] != w[1]) {
+            factors.push((vec![*v], table));
+        }
+    }
+    let mut values = Vec::new();
+    for (idx, name, is_factor) in graph.nodes() {
+        if !is_factor {
+            continue;
+        }
+        let node = graph.get_node(idx)?;
+        let vars = node.get_connections().clone();
+        let cardinalities = vars
+            .iter()
+            .map(|v| {
+                cardinality_of.get(v).copied().ok_or_else(|| {
+                    error(format!(
+                        "Factor {} ({}) is connected to node {}, which is not a variable",
+                        idx, name, v
+                    ))
+                })
+            })
+            .collect::<BPResult<Vec<usize>>>()?;
+        let size: usize = cardinalities.iter().product();
+        let mut table = Vec::with_capacity(size);
+        values.clear();
+        values.resize(vars.len(), 0);
+        for _ in 0..size {
+            table.push(node.potential(&values).ok_or_else(|| {
+                error(format!(
+                    "Factor {} ({}) does not provide its potential for {:?}",
+                    idx, name, values
+                ))
+            })?);
+            for k in 0..values.len() {
+                values[k] += 1;
+                if values[k] < cardinalities[k] {
+                    break;
+                }
+                values[k] = 0;
+            }
+        }
+        factors.push((vars, table));
+    }
+
+    let io_error = |e: std::io::Error| error(format!("Writing failed: {}", e));
+    writeln!(writer, "{}", factors.len()).map_err(io_error)?;
+    for (vars, table) in factors {
+        let join = |items: Vec<usize>| {
+            items
+                .iter()
+                .map(|item| item.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        writeln!(writer).map_err(io_error)?;
+        writeln!(writer, "{}", vars.len()).map_err(io_error)?;
+        writeln!(
+            writer,
+            "{}",
+            join(vars.iter().map(|v| label_of[v]).collect())
+        )
+        .map_err(io_error)?;
+        writeln!(
+            writer,
+            "{}",
+            join(vars.iter().map(|v| cardinality_of[v]).collect())
+        )
+        .map_err(io_error)?;
+        writeln!(writer, "{}", table.iter().filter(|p| **p != 0.0).count()).map_err(io_error)?;
+        for (index, p) in table.iter().enumerate() {
+            if *p != 0.0 {
+                writeln!(writer, "{} {}", index, p).map_err(io_error)?;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,134 @@
+use crate::pairwise_factor::add_to;
+use crate::semiring;
+use crate::{BPError, BPResult, Msg, NodeFunction, NodeIndex, Probability, Semiring};
+use std::convert::{TryFrom, TryInto};
+use std::fmt::Debug;
+use std::sync::Arc;
+
+//Factor with exactly N connections given by a potential psi([x0, ..., xN-1]) (in the order in which the edges
+//were added). Connections, assignments and outgoing messages are kept in arrays of length N, so that the
+//marginalization over all combinations of the incoming values does not allocate per assignment, and the
+//incoming messages are collected into buffers that are reused by every call.
+//Meant for small arities (e.g., 2 or 3), the cost is the product of the domain sizes times N^2.
+#[derive(Clone)]
+pub struct FixedArityFactor<const N: usize, T, MsgT> {
+    potential: fn([T; N]) -> Probability,
+    connections: Option<[NodeIndex; N]>,
+    semiring: Option<Arc<dyn Semiring>>,
+    //Entries of the incoming messages in the order of the connections
+    msgs: [Vec<(T, Probability)>; N],
+    phantom: std::marker::PhantomData<MsgT>,
+}
+
+impl<const N: usize, T, MsgT> FixedArityFactor<N, T, MsgT> {
+    pub fn new(potential: fn([T; N]) -> Probability) -> Self {
+        FixedArityFactor {
+            potential,
+            connections: None,
+            semiring: None,
+            msgs: std::array::from_fn(|_| Vec::new()),
+            phantom: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<const N: usize, T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default>
+    NodeFunction<T, MsgT, CtrlMsgT, CtrlMsgAT> for FixedArityFactor<N, T, MsgT>
+where
+    T: Copy + Debug,
+    MsgT: Clone,
+{
+    fn node_function(&mut self, inbox: Vec<(NodeIndex, MsgT)>) -> BPResult<Vec<(NodeIndex, MsgT)>> {
+        let connections = self.connections.ok_or_else(|| {
+            BPError::new(
+                "FixedArityFactor::node_function".to_owned(),
+                "FixedArityFactor not initialized".to_owned(),
+            )
+        })?;
+        if inbox.len() != N {
+            return Err(BPError::new(
+                "FixedArityFactor::node_function".to_owned(),
+                format!("Wrong number of messages ({}, needed: {})", inbox.len(), N),
+            ));
+        }
+        let msgs = &mut self.msgs;
+        msgs.iter_mut().for_each(|msg| msg.clear());
+        for (from, msg) in inbox {
+            let pos = connections
+                .iter()
+                .position(|con| *con == from)
+                .ok_or_else(|| {
+                    BPError::new(
+                        "FixedArityFactor::node_function".to_owned(),
+                        format!("Received message from unknown node {}", from),
+                    )
+                })?;
+            msgs[pos].clear();
+            msgs[pos].extend(msg);
+        }
+        let mut out: [MsgT; N] = std::array::from_fn(|_| MsgT::new());
+        if msgs.iter().all(|msg| !msg.is_empty()) {
+            let s = self.semiring.as_deref();
+            let zero = semiring::zero(s);
+            let mut idx = [0usize; N];
+            'assignments: loop {
+                let values: [T; N] = std::array::from_fn(|i| msgs[i][idx[i]].0);
+                let psi = (self.potential)(values);
+                if psi != zero {
+                    for (i, out_i) in out.iter_mut().enumerate() {
+                        let p = (0..N)
+                            .filter(|j| *j != i)
+                            .fold(psi, |p, j| semiring::times(s, p, msgs[j][idx[j]].1));
+                        add_to(out_i, values[i], p, s);
+                    }
+                }
+                for k in 0..N {
+                    idx[k] += 1;
+                    if idx[k] < msgs[k].len() {
+                        continue 'assignments;
+                    }
+                    idx[k] = 0;
+                }
+                break;
+            }
+        }
+        Ok(connections.iter().copied().zip(out).collect())
+    }
+    impl_clone_box!(T, MsgT, CtrlMsgT, CtrlMsgAT);
+
+    fn is_factor(&self) -> bool {
+        true
+    }
+    fn number_inputs(&self) -> Option<usize> {
+        Some(N)
+    }
+    fn initialize(&mut self, connections: Vec<NodeIndex>) -> BPResult<()> {
+        let len = connections.len();
+        self.connections = Some(connections.try_into().map_err(|_| {
+            BPError::new(
+                "FixedArityFactor::initialize".to_owned(),
+                format!(
+                    "FixedArityFactor needs exactly {} connections, got {}",
+                    N, len
+                ),
+            )
+        })?);
+        Ok(())
+    }
+    fn is_ready(&self, recv_from: &Vec<(NodeIndex, MsgT)>, _current_step: usize) -> BPResult<bool> {
+        Ok(recv_from.len() == N)
+    }
+    fn reset(&mut self) -> BPResult<()> {
+        self.connections = None;
+        Ok(())
+    }
+    fn get_prior(&self) -> Option<MsgT> {
+        None
+    }
+    fn set_semiring(&mut self, semiring: Arc<dyn Semiring>) {
+        self.semiring = Some(semiring);
+    }
+    fn potential(&self, values: &[T]) -> Option<Probability> {
+        <[T; N]>::try_from(values).ok().map(self.potential)
+    }
+}
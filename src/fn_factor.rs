@@ -0,0 +1,143 @@
+use crate::pairwise_factor::add_to;
+use crate::semiring;
+use crate::{BPError, BPResult, Msg, NodeFunction, NodeIndex, Probability, Semiring};
+use std::fmt::Debug;
+use std::sync::Arc;
+
+//Factor with arity connections given by a closure psi(&[x0, ..., x_arity-1]) (values in the order in which the
+//edges were added), e.g., g.add_factor(name, FnFactor::new(2, |x: &[i32]| if x[0] < x[1] { 1.0 } else { 0.5 })).
+//The messages are computed by enumerating all combinations of the values in the incoming messages, so the cost
+//is the product of their sizes times arity^2. Like FixedArityFactor, but the arity is chosen at runtime and the
+//potential may capture its environment.
+#[allow(clippy::type_complexity)]
+#[derive(Clone)]
+pub struct FnFactor<T, MsgT> {
+    arity: usize,
+    potential: Arc<dyn Fn(&[T]) -> Probability + Send + Sync>,
+    connections: Option<Vec<NodeIndex>>,
+    semiring: Option<Arc<dyn Semiring>>,
+    phantom: std::marker::PhantomData<MsgT>,
+}
+
+impl<T, MsgT> FnFactor<T, MsgT> {
+    pub fn new<F>(arity: usize, potential: F) -> Self
+    where
+        F: Fn(&[T]) -> Probability + Send + Sync + 'static,
+    {
+        FnFactor {
+            arity,
+            potential: Arc::new(potential),
+            connections: None,
+            semiring: None,
+            phantom: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default> NodeFunction<T, MsgT, CtrlMsgT, CtrlMsgAT>
+    for FnFactor<T, MsgT>
+where
+    T: Copy + Debug,
+    MsgT: Clone,
+{
+    fn node_function(&mut self, inbox: Vec<(NodeIndex, MsgT)>) -> BPResult<Vec<(NodeIndex, MsgT)>> {
+        let connections = self.connections.as_ref().ok_or_else(|| {
+            BPError::new(
+                "FnFactor::node_function".to_owned(),
+                "FnFactor not initialized".to_owned(),
+            )
+        })?;
+        let n = self.arity;
+        if inbox.len() != n {
+            return Err(BPError::new(
+                "FnFactor::node_function".to_owned(),
+                format!("Wrong number of messages ({}, needed: {})", inbox.len(), n),
+            ));
+        }
+        //Incoming messages in the order of the connections
+        let mut msgs: Vec<Vec<(T, Probability)>> = vec![Vec::new(); n];
+        for (from, msg) in inbox {
+            let pos = connections
+                .iter()
+                .position(|con| *con == from)
+                .ok_or_else(|| {
+                    BPError::new(
+                        "FnFactor::node_function".to_owned(),
+                        format!("Received message from unknown node {}", from),
+                    )
+                })?;
+            msgs[pos] = msg.into_iter().collect();
+        }
+        let mut out: Vec<MsgT> = (0..n).map(|_| MsgT::new()).collect();
+        if n > 0 && msgs.iter().all(|msg| !msg.is_empty()) {
+            let s = self.semiring.as_deref();
+            let zero = semiring::zero(s);
+            let mut idx = vec![0usize; n];
+            let mut values: Vec<T> = msgs.iter().map(|msg| msg[0].0).collect();
+            'assignments: loop {
+                let psi = (self.potential)(&values);
+                if psi != zero {
+                    for (i, out_i) in out.iter_mut().enumerate() {
+                        let p = (0..n)
+                            .filter(|j| *j != i)
+                            .fold(psi, |p, j| semiring::times(s, p, msgs[j][idx[j]].1));
+                        add_to(out_i, values[i], p, s);
+                    }
+                }
+                for k in 0..n {
+                    idx[k] += 1;
+                    if idx[k] < msgs[k].len() {
+                        values[k] = msgs[k][idx[k]].0;
+                        continue 'assignments;
+                    }
+                    idx[k] = 0;
+                    values[k] = msgs[k][0].0;
+                }
+                break;
+            }
+        }
+        Ok(connections.iter().copied().zip(out).collect())
+    }
+    impl_clone_box!(T, MsgT, CtrlMsgT, CtrlMsgAT);
+
+    fn is_factor(&self) -> bool {
+        true
+    }
+    fn number_inputs(&self) -> Option<usize> {
+        Some(self.arity)
+    }
+    fn initialize(&mut self, connections: Vec<NodeIndex>) -> BPResult<()> {
+        if connections.len() != self.arity {
+            return Err(BPError::new(
+                "FnFactor::initialize".to_owned(),
+                format!(
+                    "FnFactor needs exactly {} connections, got {}",
+                    self.arity,
+                    connections.len()
+                ),
+            ));
+        }
+        self.connections = Some(connections);
+        Ok(())
+    }
+    fn is_ready(&self, recv_from: &Vec<(NodeIndex, MsgT)>, _current_step: usize) -> BPResult<bool> {
+        Ok(recv_from.len() == self.arity)
+    }
+    fn reset(&mut self) -> BPResult<()> {
+        self.connections = None;
+        Ok(())
+    }
+    fn get_prior(&self) -> Option<MsgT> {
+        None
+    }
+    fn set_semiring(&mut self, semiring: Arc<dyn Semiring>) {
+        self.semiring = Some(semiring);
+    }
+    fn potential(&self, values: &[T]) -> Option<Probability> {
+        if values.len() == self.arity {
+            Some((self.potential)(values))
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use crate::pairwise_factor::add_to;
+use crate::semiring;
+use crate::{BPError, BPResult, Msg, NodeFunction, NodeIndex, Probability, Semiring};
+use std::fmt::Debug;
+use std::sync::Arc;
+
+//Splits the inbox of a factor connecting x (first edge) and y (second edge) into the messages of x and y
+fn split_inbox<MsgT>(
+    connections: &Option<[NodeIndex; 2]>,
+    inbox: Vec<(NodeIndex, MsgT)>,
+    fn_name: &str,
+) -> BPResult<([NodeIndex; 2], MsgT, MsgT)> {
+    let connections = connections
+        .ok_or_else(|| BPError::new(fn_name.to_owned(), "Factor not initialized".to_owned()))?;
+    if inbox.len() != 2 {
+        return Err(BPError::new(
+            fn_name.to_owned(),
+            format!("Wrong number of messages ({}, needed: 2)", inbox.len()),
+        ));
+    }
+    let mut msg_x = None;
+    let mut msg_y = None;
+    for (from, msg) in inbox {
+        if from == connections[0] {
+            msg_x = Some(msg);
+        } else if from == connections[1] {
+            msg_y = Some(msg);
+        } else {
+            return Err(BPError::new(
+                fn_name.to_owned(),
+                format!("Received message from unknown node {}", from),
+            ));
+        }
+    }
+    match (msg_x, msg_y) {
+        (Some(msg_x), Some(msg_y)) => Ok((connections, msg_x, msg_y)),
+        _ => Err(BPError::new(
+            fn_name.to_owned(),
+            "Received two messages from the same node".to_owned(),
+        )),
+    }
+}
+
+fn check_connections(connections: Vec<NodeIndex>, fn_name: &str) -> BPResult<[NodeIndex; 2]> {
+    if connections.len() != 2 {
+        return Err(BPError::new(
+            fn_name.to_owned(),
+            format!(
+                "Factor needs exactly 2 connections (x, y), got {}",
+                connections.len()
+            ),
+        ));
+    }
+    Ok([connections[0], connections[1]])
+}
+
+//Factor enforcing y = f(x) for a bijection f (e.g., an S-box or a bit permutation), x being connected first.
+//Messages are relabeled instead of enumerating pairs: x -> y sends m(f(x)) = m_x(x) and y -> x sends
+//m(f_inverse(y)) = m_y(y), so a step costs O(n). f_inverse has to be the inverse of f on the domains of the
+//variables; use FunctionFactor for functions that are not bijective.
+#[derive(Clone)]
+pub struct BijectionFactor<T, MsgT> {
+    f: Arc<dyn Fn(T) -> T + Send + Sync>,
+    f_inverse: Arc<dyn Fn(T) -> T + Send + Sync>,
+    connections: Option<[NodeIndex; 2]>,
+    phantom: std::marker::PhantomData<MsgT>,
+}
+
+impl<T, MsgT> BijectionFactor<T, MsgT> {
+    pub fn new<F, G>(f: F, f_inverse: G) -> Self
+    where
+        F: Fn(T) -> T + Send + Sync + 'static,
+        G: Fn(T) -> T + Send + Sync + 'static,
+    {
+        BijectionFactor {
+            f: Arc::new(f),
+            f_inverse: Arc::new(f_inverse),
+            connections: None,
+            phantom: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default> NodeFunction<T, MsgT, CtrlMsgT, CtrlMsgAT>
+    for BijectionFactor<T, MsgT>
+where
+    T: Copy + PartialEq + Debug,
+    MsgT: Clone,
+{
+    fn node_function(&mut self, inbox: Vec<(NodeIndex, MsgT)>) -> BPResult<Vec<(NodeIndex, MsgT)>> {
+        let ([x, y], msg_x, msg_y) =
+            split_inbox(&self.connections, inbox, "BijectionFactor::node_function")?;
+        let mut to_y = MsgT::new();
+        for (v, p) in msg_x {
+            to_y.insert((self.f)(v), p);
+        }
+        let mut to_x = MsgT::new();
+        for (v, p) in msg_y {
+            to_x.insert((self.f_inverse)(v), p);
+        }
+        Ok(vec![(x, to_x), (y, to_y)])
+    }
+    impl_clone_box!(T, MsgT, CtrlMsgT, CtrlMsgAT);
+
+    fn is_factor(&self) -> bool {
+        true
+    }
+    fn number_inputs(&self) -> Option<usize> {
+        Some(2)
+    }
+    fn initialize(&mut self, connections: Vec<NodeIndex>) -> BPResult<()> {
+        self.connections = Some(check_connections(
+            connections,
+            "BijectionFactor::initialize",
+        )?);
+        Ok(())
+    }
+    fn is_ready(&self, recv_from: &Vec<(NodeIndex, MsgT)>, _current_step: usize) -> BPResult<bool> {
+        Ok(recv_from.len() == 2)
+    }
+    fn reset(&mut self) -> BPResult<()> {
+        self.connections = None;
+        Ok(())
+    }
+    fn get_prior(&self) -> Option<MsgT> {
+        None
+    }
+    fn potential(&self, values: &[T]) -> Option<Probability> {
+        match values {
+            [x, y] => Some(if (self.f)(*x) == *y { 1.0 } else { 0.0 }),
+            _ => None,
+        }
+    }
+}
+
+//Factor enforcing y = f(x) for an arbitrary function f, x being connected first.
+//x -> y sums (in the semiring) the messages of all preimages, m(y) = sum_{f(x) = y} m_x(x), and y -> x
+//sends m(x) = m_y(f(x)) for every value x in the message of x. Both directions cost O(n).
+#[derive(Clone)]
+pub struct FunctionFactor<T, MsgT> {
+    f: Arc<dyn Fn(T) -> T + Send + Sync>,
+    connections: Option<[NodeIndex; 2]>,
+    semiring: Option<Arc<dyn Semiring>>,
+    phantom: std::marker::PhantomData<MsgT>,
+}
+
+impl<T, MsgT> FunctionFactor<T, MsgT> {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(T) -> T + Send + Sync + 'static,
+    {
+        FunctionFactor {
+            f: Arc::new(f),
+            connections: None,
+            semiring: None,
+            phantom: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default> NodeFunction<T, MsgT, CtrlMsgT, CtrlMsgAT>
+    for FunctionFactor<T, MsgT>
+where
+    T: Copy + PartialEq + Debug,
+    MsgT: Clone,
+{
+    fn node_function(&mut self, inbox: Vec<(NodeIndex, MsgT)>) -> BPResult<Vec<(NodeIndex, MsgT)>> {
+        let ([x, y], msg_x, msg_y) =
+            split_inbox(&self.connections, inbox, "FunctionFactor::node_function")?;
+        let s = self.semiring.as_deref();
+        let zero = semiring::zero(s);
+        let mut to_y = MsgT::new();
+        let mut to_x = MsgT::new();
+        for (v, p) in msg_x {
+            let image = (self.f)(v);
+            add_to(&mut to_y, image, p, s);
+            to_x.insert(v, msg_y.get(image).unwrap_or(zero));
+        }
+        Ok(vec![(x, to_x), (y, to_y)])
+    }
+    impl_clone_box!(T, MsgT, CtrlMsgT, CtrlMsgAT);
+
+    fn is_factor(&self) -> bool {
+        true
+    }
+    fn number_inputs(&self) -> Option<usize> {
+        Some(2)
+    }
+    fn initialize(&mut self, connections: Vec<NodeIndex>) -> BPResult<()> {
+        self.connections = Some(check_connections(
+            connections,
+            "FunctionFactor::initialize",
+        )?);
+        Ok(())
+    }
+    fn is_ready(&self, recv_from: &Vec<(NodeIndex, MsgT)>, _current_step: usize) -> BPResult<bool> {
+        Ok(recv_from.len() == 2)
+    }
+    fn reset(&mut self) -> BPResult<()> {
+        self.connections = None;
+        Ok(())
+    }
+    fn get_prior(&self) -> Option<MsgT> {
+        None
+    }
+    fn set_semiring(&mut self, semiring: Arc<dyn Semiring>) {
+        self.semiring = Some(semiring);
+    }
+    fn potential(&self, values: &[T]) -> Option<Probability> {
+        match values {
+            [x, y] => Some(if (self.f)(*x) == *y { 1.0 } else { 0.0 }),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use crate::rng::SplitMix64;
+use crate::{BPError, BPGraph, BPResult, Grid, Msg, Probability, TableFactor};
+
+//Generators of synthetic graphs over usize for benchmarks and tests (see benches/propagate.rs).
+//All graphs only depend on their parameters and the seed. Variables have random priors over 0..cardinality,
+//factors are TableFactors with random potentials in [0.1, 1.1), i.e., every assignment is possible.
+//The graphs are not initialized.
+
+fn random_prior<MsgT: Msg<usize>>(cardinality: usize, rng: &mut SplitMix64) -> MsgT {
+    let weights: Vec<Probability> = (0..cardinality).map(|_| 0.1 + rng.next_f64()).collect();
+    let sum: Probability = weights.iter().sum();
+    let mut prior = MsgT::new();
+    for (v, w) in weights.into_iter().enumerate() {
+        prior.insert(v, w / sum);
+    }
+    prior
+}
+
+fn random_table<MsgT>(
+    cardinality: usize,
+    arity: usize,
+    rng: &mut SplitMix64,
+) -> BPResult<TableFactor<MsgT>> {
+    let size = cardinality.pow(arity as u32);
+    TableFactor::new(
+        vec![cardinality; arity],
+        (0..size).map(|_| 0.1 + rng.next_f64()).collect(),
+    )
+}
+
+fn below(n: usize, rng: &mut SplitMix64) -> usize {
+    (rng.next_u64() % n as u64) as usize
+}
+
+fn shuffle<T>(values: &mut [T], rng: &mut SplitMix64) {
+    for i in (1..values.len()).rev() {
+        values.swap(i, below(i + 1, rng));
+    }
+}
+
+//Splits the shuffled sockets into groups of size arity such that no group contains a variable twice.
+//Repeated variables are swapped with random sockets of other groups, fails if this does not succeed.
+fn group_sockets(
+    sockets: &mut [usize],
+    arity: usize,
+    rng: &mut SplitMix64,
+) -> Option<Vec<Vec<usize>>> {
+    let groups = sockets.len() / arity;
+    let repeated = |sockets: &[usize], g: usize| {
+        let group = &sockets[g * arity..(g + 1) * arity];
+        (0..arity).find(|i| group[..*i].contains(&group[*i]))
+    };
+    for _ in 0..100 * sockets.len() {
+        let conflict = (0..groups).find_map(|g| repeated(sockets, g).map(|i| (g, g * arity + i)));
+        let (g, i) = match conflict {
+            None => return Some(sockets.chunks(arity).map(|c| c.to_vec()).collect()),
+            Some(conflict) => conflict,
+        };
+        let j = below(sockets.len(), rng);
+        if j / arity == g {
+            continue;
+        }
+        sockets.swap(i, j);
+        if repeated(sockets, j / arity).is_some() {
+            sockets.swap(i, j);
+        }
+    }
+    None
+}
+
+//Random bipartite graph in which every variable is connected to variable_degree factors and every factor to
+//factor_arity distinct variables (configuration model). num_variables * variable_degree has to be a multiple
+//of factor_arity.
+pub fn random_regular_bipartite<MsgT>(
+    num_variables: usize,
+    variable_degree: usize,
+    factor_arity: usize,
+    cardinality: usize,
+    seed: u64,
+) -> BPResult<BPGraph<usize, MsgT>>
+where
+    MsgT: Msg<usize> + Clone + Send + Sync + 'static,
+{
+    let error = |msg: String| BPError::new("random_regular_bipartite".to_owned(), msg);
+    if factor_arity == 0 || factor_arity > num_variables || cardinality == 0 {
+        return Err(error(format!(
+            "Invalid parameters ({} variables, arity {}, cardinality {})",
+            num_variables, factor_arity, cardinality
+        )));
+    }
+    if (num_variables * variable_degree) % factor_arity != 0 {
+        return Err(error(format!(
+            "{} variables of degree {} cannot be split into factors of arity {}",
+            num_variables, variable_degree, factor_arity
+        )));
+    }
+    let mut rng = SplitMix64::new(seed);
+    let mut sockets: Vec<usize> = (0..num_variables)
+        .flat_map(|v| std::iter::repeat_n(v, variable_degree))
+        .collect();
+    shuffle(&mut sockets, &mut rng);
+    let factors = group_sockets(&mut sockets, factor_arity, &mut rng)
+        .ok_or_else(|| error("Could not find factors without repeated variables".to_owned()))?;
+    let mut graph = BPGraph::new();
+    for v in 0..num_variables {
+        graph.add_variable(format!("x{}", v), random_prior(cardinality, &mut rng))?;
+    }
+    for (f, variables) in factors.into_iter().enumerate() {
+        let factor = graph.add_factor(
+            format!("f{}", f),
+            random_table(cardinality, factor_arity, &mut rng)?,
+        )?;
+        for v in variables {
+            graph.add_edge(v, factor)?;
+        }
+    }
+    Ok(graph)
+}
+
+//Tree of variables of the given depth in which every inner variable has branching children, each connected
+//to its parent by a pairwise factor. The variables are added in breadth-first order, the root is variable 0.
+pub fn random_tree<MsgT>(
+    depth: usize,
+    branching: usize,
+    cardinality: usize,
+    seed: u64,
+) -> BPResult<BPGraph<usize, MsgT>>
+where
+    MsgT: Msg<usize> + Clone + Send + Sync + 'static,
+{
+    if cardinality == 0 {
+        return Err(BPError::new(
+            "random_tree".to_owned(),
+            "Cardinality has to be positive".to_owned(),
+        ));
+    }
+    let mut rng = SplitMix64::new(seed);
+    let mut graph = BPGraph::new();
+    let mut level = vec![graph.add_variable("x0".to_owned(), random_prior(cardinality, &mut rng))?];
+    let mut variables = 1;
+    for _ in 0..depth {
+        let mut next_level = Vec::with_capacity(level.len() * branching);
+        for parent in level {
+            for _ in 0..branching {
+                let child = graph.add_variable(
+                    format!("x{}", variables),
+                    random_prior(cardinality, &mut rng),
+                )?;
+                variables += 1;
+                let factor = graph.add_factor(
+                    format!("f{}", variables - 2),
+                    random_table(cardinality, 2, &mut rng)?,
+                )?;
+                graph.add_edge(parent, factor)?;
+                graph.add_edge(child, factor)?;
+                next_level.push(child);
+            }
+        }
+        level = next_level;
+    }
+    Ok(graph)
+}
+
+//Grid MRF (see BPGraph::add_grid) with random data costs in [0, 1) and a Potts smoothness cost of coupling
+//for different neighbouring labels.
+pub fn random_grid<MsgT>(
+    width: usize,
+    height: usize,
+    cardinality: usize,
+    coupling: Probability,
+    seed: u64,
+) -> BPResult<(BPGraph<usize, MsgT>, Grid)>
+where
+    MsgT: Msg<usize> + Clone + Send + Sync + 'static,
+{
+    let mut rng = SplitMix64::new(seed);
+    let labels: Vec<usize> = (0..cardinality).collect();
+    let costs: Vec<Probability> = (0..width * height * cardinality)
+        .map(|_| rng.next_f64())
+        .collect();
+    let mut graph = BPGraph::new();
+    let grid = graph
+        .add_grid(width, height, &labels, &costs, |a, b| {
+            if a == b {
+                0.0
+            } else {
+                coupling
+            }
+        })
+        .map_err(|e| e.attach_info_str("random_grid", "Could not build the grid".to_owned()))?;
+    Ok((graph, grid))
+}
+
+//Parity-check matrix of a random (column_weight, row_weight)-regular LDPC code in the format of
+//LdpcDecoder::new, i.e., the columns of every row. Every bit is in column_weight checks and every check
+//contains row_weight distinct bits. num_bits * column_weight has to be a multiple of row_weight.
+pub fn ldpc_ensemble(
+    num_bits: usize,
+    column_weight: usize,
+    row_weight: usize,
+    seed: u64,
+) -> BPResult<Vec<Vec<usize>>> {
+    let error = |msg: String| BPError::new("ldpc_ensemble".to_owned(), msg);
+    if row_weight == 0 || row_weight > num_bits || (num_bits * column_weight) % row_weight != 0 {
+        return Err(error(format!(
+            "No ({}, {})-regular code with {} bits",
+            column_weight, row_weight, num_bits
+        )));
+    }
+    let mut rng = SplitMix64::new(seed);
+    let mut sockets: Vec<usize> = (0..num_bits)
+        .flat_map(|b| std::iter::repeat_n(b, column_weight))
+        .collect();
+    shuffle(&mut sockets, &mut rng);
+    group_sockets(&mut sockets, row_weight, &mut rng)
+        .ok_or_else(|| error("Could not find checks without repeated bits".to_owned()))
+}
+
+//Channel LLRs of the all-zero codeword sent over a BPSK AWGN channel with noise standard deviation sigma
+//(0 is sent as +1), i.e., 2 * y / sigma^2 for y = 1 + sigma * n.
+pub fn awgn_llrs(num_bits: usize, sigma: f64, seed: u64) -> Vec<f64> {
+    let mut rng = SplitMix64::new(seed);
+    (0..num_bits)
+        .map(|_| {
+            //Box-Muller
+            let u = 1.0 - rng.next_f64();
+            let n = (-2.0 * u.ln()).sqrt() * (2.0 * std::f64::consts::PI * rng.next_f64()).cos();
+            2.0 * (1.0 + sigma * n) / (sigma * sigma)
+        })
+        .collect()
+}
@@ -0,0 +1,411 @@
+#[cfg(feature = "gpu")]
+use crate::{BPError, BPResult, Probability};
+#[cfg(feature = "gpu")]
+use std::sync::OnceLock;
+
+/*
+Compute shader backend of BPGraph::propagate_step_gpu (feature gpu, based on wgpu).
+
+The messages of a step are packed into one flat buffer of f32: for a VariableNode over DenseMsg the prior and
+the incoming messages (scaled to a maximum of one), for a TableFactor its table and the incoming messages in
+the order of its connections. One invocation computes one entry of an outgoing message, i.e., the product of
+the prior and the other messages for variables and the sum over the table entries with this value for factors.
+The results are converted back to f64, so they agree with the CPU up to the precision of f32.
+
+Everything else (other node functions, normalization, edge settings, ...) stays on the CPU. Without the
+feature or a GPU adapter, propagate_step_gpu is propagate_step.
+*/
+
+//Words of a descriptor of an outgoing message:
+//[kind, input base, degree/arity, length/shape base, skipped/computed connection, output base, count, 0]
+#[cfg(feature = "gpu")]
+const DESCRIPTOR_LEN: usize = 8;
+//Words before the descriptors: [invocations per row of workgroups, number of invocations, shapes base]
+#[cfg(feature = "gpu")]
+const HEADER_LEN: usize = 3;
+#[cfg(feature = "gpu")]
+const WORKGROUP_SIZE: u32 = 64;
+#[cfg(feature = "gpu")]
+const MAX_WORKGROUPS: u32 = 65535;
+
+#[cfg(feature = "gpu")]
+const SHADER: &str = r#"
+@group(0) @binding(0) var<storage, read> inputs: array<f32>;
+@group(0) @binding(1) var<storage, read> words: array<u32>;
+@group(0) @binding(2) var<storage, read> elements: array<u32>;
+@group(0) @binding(3) var<storage, read_write> outputs: array<f32>;
+
+@compute @workgroup_size(64)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let e = id.x + id.y * words[0];
+    if (e >= words[1]) {
+        return;
+    }
+    let d = 3u + 8u * elements[e];
+    let value = e - words[d + 5u];
+    if (words[d] == 0u) {
+        //Variable: prior and degree messages of the same length, the message to connection skip
+        let base = words[d + 1u];
+        let degree = words[d + 2u];
+        let len = words[d + 3u];
+        let skip = words[d + 4u];
+        var p = inputs[base + value];
+        for (var k = 0u; k < degree; k++) {
+            if (k != skip) {
+                p = p * inputs[base + (k + 1u) * len + value];
+            }
+        }
+        outputs[e] = p;
+    } else {
+        //Factor: shape has (cardinality, stride, message base) of every connection
+        let table = words[d + 1u];
+        let arity = words[d + 2u];
+        let shape = words[2] + words[d + 3u];
+        let i = words[d + 4u];
+        let count = words[d + 6u];
+        var sum = 0.0;
+        for (var r = 0u; r < count; r++) {
+            var rest = r;
+            var index = value * words[shape + 3u * i + 1u];
+            var p = 1.0;
+            for (var j = 0u; j < arity; j++) {
+                if (j != i) {
+                    let cardinality = words[shape + 3u * j];
+                    let v = rest % cardinality;
+                    rest = rest / cardinality;
+                    index += v * words[shape + 3u * j + 1u];
+                    p = p * inputs[words[shape + 3u * j + 2u] + v];
+                }
+            }
+            sum += inputs[table + index] * p;
+        }
+        outputs[e] = sum;
+    }
+}
+"#;
+
+//Whether propagate_step_gpu runs on a GPU, i.e., the feature gpu is enabled and an adapter was found
+pub fn gpu_available() -> bool {
+    #[cfg(feature = "gpu")]
+    {
+        backend().is_some()
+    }
+    #[cfg(not(feature = "gpu"))]
+    {
+        false
+    }
+}
+
+#[cfg(feature = "gpu")]
+pub(crate) struct GpuBackend {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    pipeline: wgpu::ComputePipeline,
+    //Entries of the largest buffer
+    max_len: usize,
+}
+
+//Shared by all graphs, created on first use
+#[cfg(feature = "gpu")]
+pub(crate) fn backend() -> Option<&'static GpuBackend> {
+    static BACKEND: OnceLock<Option<GpuBackend>> = OnceLock::new();
+    BACKEND.get_or_init(GpuBackend::new).as_ref()
+}
+
+#[cfg(feature = "gpu")]
+impl GpuBackend {
+    fn new() -> Option<Self> {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+        let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            force_fallback_adapter: false,
+            compatible_surface: None,
+        }))?;
+        if !adapter
+            .get_downlevel_capabilities()
+            .flags
+            .contains(wgpu::DownlevelFlags::COMPUTE_SHADERS)
+        {
+            return None;
+        }
+        let limits = adapter.limits();
+        let (device, queue) = pollster::block_on(adapter.request_device(
+            &wgpu::DeviceDescriptor {
+                label: Some("belief_propagation"),
+                required_features: wgpu::Features::empty(),
+                required_limits: limits.clone(),
+            },
+            None,
+        ))
+        .ok()?;
+        device.push_error_scope(wgpu::ErrorFilter::Validation);
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("belief_propagation"),
+            source: wgpu::ShaderSource::Wgsl(SHADER.into()),
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("belief_propagation"),
+            layout: None,
+            module: &module,
+            entry_point: "main",
+        });
+        if pollster::block_on(device.pop_error_scope()).is_some() {
+            return None;
+        }
+        let max_bytes = (limits.max_storage_buffer_binding_size as u64).min(limits.max_buffer_size);
+        Some(GpuBackend {
+            device,
+            queue,
+            pipeline,
+            max_len: (max_bytes / 4) as usize,
+        })
+    }
+
+    fn storage_buffer(&self, bytes: Vec<u8>, usage: wgpu::BufferUsages) -> wgpu::Buffer {
+        use wgpu::util::DeviceExt;
+        self.device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: None,
+                contents: &bytes,
+                usage: wgpu::BufferUsages::STORAGE | usage,
+            })
+    }
+}
+
+//Outgoing messages of one step, see add_variable and add_factor
+#[cfg(feature = "gpu")]
+pub(crate) struct GpuBatch {
+    inputs: Vec<f32>,
+    descriptors: Vec<u32>,
+    shapes: Vec<u32>,
+    //Descriptor of every output entry
+    elements: Vec<u32>,
+}
+
+#[cfg(feature = "gpu")]
+impl GpuBatch {
+    pub(crate) fn new() -> Self {
+        GpuBatch {
+            inputs: Vec::new(),
+            descriptors: Vec::new(),
+            shapes: Vec::new(),
+            elements: Vec::new(),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    //Whether a node with inputs more input and outputs more output entries fits into the buffers of the backend
+    pub(crate) fn fits(&self, backend: &GpuBackend, inputs: usize, outputs: usize) -> bool {
+        self.inputs.len() + inputs <= backend.max_len
+            && self.elements.len() + outputs <= backend.max_len
+            && HEADER_LEN
+                + self.descriptors.len()
+                + self.shapes.len()
+                + (DESCRIPTOR_LEN + 3) * outputs
+                <= backend.max_len
+    }
+
+    fn push_descriptor(&mut self, words: [u32; DESCRIPTOR_LEN], len: usize) {
+        let index = (self.descriptors.len() / DESCRIPTOR_LEN) as u32;
+        self.descriptors.extend_from_slice(&words);
+        self.elements.extend(std::iter::repeat_n(index, len));
+    }
+
+    //Appends the messages of a variable to each of its connections in the order of msgs, i.e., the product of
+    //the prior and all messages but the one of the receiver. All messages and the prior have the same length.
+    pub(crate) fn add_variable(&mut self, prior: Option<&[Probability]>, msgs: &[&[Probability]]) {
+        let len = msgs[0].len();
+        let base = self.inputs.len() as u32;
+        match prior {
+            Some(prior) => push_scaled(&mut self.inputs, prior),
+            None => self.inputs.extend(std::iter::repeat_n(1.0, len)),
+        }
+        for msg in msgs {
+            push_scaled(&mut self.inputs, msg);
+        }
+        for skip in 0..msgs.len() {
+            let out = self.elements.len() as u32;
+            self.push_descriptor(
+                [
+                    0,
+                    base,
+                    msgs.len() as u32,
+                    len as u32,
+                    skip as u32,
+                    out,
+                    0,
+                    0,
+                ],
+                len,
+            );
+        }
+    }
+
+    //Appends the messages of a TableFactor (see TableFactor for the order of the table) to each of its
+    //connections in the order of the connections. msgs are in the order of the connections, missing values
+    //count as zero.
+    pub(crate) fn add_factor(
+        &mut self,
+        table: &[Probability],
+        cardinalities: &[usize],
+        msgs: &[&[Probability]],
+    ) {
+        let table_base = self.inputs.len() as u32;
+        self.inputs.extend(table.iter().map(|p| *p as f32));
+        let shapes_base = self.shapes.len() as u32;
+        let mut stride = 1;
+        for (msg, cardinality) in msgs.iter().zip(cardinalities) {
+            self.shapes.extend_from_slice(&[
+                *cardinality as u32,
+                stride as u32,
+                self.inputs.len() as u32,
+            ]);
+            self.inputs
+                .extend((0..*cardinality).map(|v| msg.get(v).copied().unwrap_or(0.0) as f32));
+            stride *= cardinality;
+        }
+        for (i, cardinality) in cardinalities.iter().enumerate() {
+            let out = self.elements.len() as u32;
+            let count = (table.len() / cardinality) as u32;
+            self.push_descriptor(
+                [
+                    1,
+                    table_base,
+                    msgs.len() as u32,
+                    shapes_base,
+                    i as u32,
+                    out,
+                    count,
+                    0,
+                ],
+                *cardinality,
+            );
+        }
+    }
+
+    //Computes all messages added so far and empties the batch. The entries of the messages are returned one
+    //after the other, in the order they were added.
+    pub(crate) fn run(&mut self, backend: &GpuBackend) -> BPResult<Vec<f32>> {
+        let len = self.elements.len();
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        let groups = (len as u32).div_ceil(WORKGROUP_SIZE);
+        let (groups_x, groups_y) = if groups <= MAX_WORKGROUPS {
+            (groups, 1)
+        } else {
+            (MAX_WORKGROUPS, groups.div_ceil(MAX_WORKGROUPS))
+        };
+        let header = [
+            groups_x * WORKGROUP_SIZE,
+            len as u32,
+            (HEADER_LEN + self.descriptors.len()) as u32,
+        ];
+        let words = header
+            .iter()
+            .chain(self.descriptors.iter())
+            .chain(self.shapes.iter());
+        //Bindings must not be empty
+        let inputs = if self.inputs.is_empty() {
+            vec![0; 4]
+        } else {
+            self.inputs.iter().flat_map(|p| p.to_le_bytes()).collect()
+        };
+        let device = &backend.device;
+        device.push_error_scope(wgpu::ErrorFilter::Validation);
+        device.push_error_scope(wgpu::ErrorFilter::OutOfMemory);
+        let buffers = [
+            backend.storage_buffer(inputs, wgpu::BufferUsages::empty()),
+            backend.storage_buffer(
+                words.flat_map(|w| w.to_le_bytes()).collect(),
+                wgpu::BufferUsages::empty(),
+            ),
+            backend.storage_buffer(
+                self.elements.iter().flat_map(|w| w.to_le_bytes()).collect(),
+                wgpu::BufferUsages::empty(),
+            ),
+            backend.storage_buffer(vec![0; 4 * len], wgpu::BufferUsages::COPY_SRC),
+        ];
+        let staging = device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: 4 * len as u64,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let entries: Vec<wgpu::BindGroupEntry> = buffers
+            .iter()
+            .enumerate()
+            .map(|(i, buffer)| wgpu::BindGroupEntry {
+                binding: i as u32,
+                resource: buffer.as_entire_binding(),
+            })
+            .collect();
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &backend.pipeline.get_bind_group_layout(0),
+            entries: &entries,
+        });
+        let mut encoder =
+            device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: None,
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(&backend.pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.dispatch_workgroups(groups_x, groups_y, 1);
+        }
+        encoder.copy_buffer_to_buffer(&buffers[3], 0, &staging, 0, 4 * len as u64);
+        backend.queue.submit(Some(encoder.finish()));
+        let errors = [
+            pollster::block_on(device.pop_error_scope()),
+            pollster::block_on(device.pop_error_scope()),
+        ];
+        if let Some(e) = errors.iter().flatten().next() {
+            return Err(BPError::new(
+                "GpuBatch::run".to_owned(),
+                format!("Dispatching the step failed: {}", e),
+            ));
+        }
+        let slice = staging.slice(..);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |res| {
+            let _ = sender.send(res);
+        });
+        device.poll(wgpu::Maintain::Wait);
+        receiver
+            .recv()
+            .map_err(|e| e.to_string())
+            .and_then(|res| res.map_err(|e| e.to_string()))
+            .map_err(|e| {
+                BPError::new(
+                    "GpuBatch::run".to_owned(),
+                    format!("Reading the messages failed: {}", e),
+                )
+            })?;
+        let res = slice
+            .get_mapped_range()
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        staging.unmap();
+        self.inputs.clear();
+        self.descriptors.clear();
+        self.shapes.clear();
+        self.elements.clear();
+        Ok(res)
+    }
+}
+
+//Scales the message to a maximum of one (variables renormalize every product the same way), so that products
+//of many messages do not underflow in f32
+#[cfg(feature = "gpu")]
+fn push_scaled(inputs: &mut Vec<f32>, msg: &[Probability]) {
+    let max = msg.iter().fold(0.0, |m: Probability, p| m.max(p.abs()));
+    let scale = if max > 0.0 { 1.0 / max } else { 1.0 };
+    inputs.extend(msg.iter().map(|p| (p * scale) as f32));
+}
@@ -0,0 +1,183 @@
+use crate::variable_node::{FromVariableNodeCtrlAnswer, IntoVariableNodeCtrl};
+use crate::{BPError, BPGraph, BPResult, Msg, NodeIndex, PairwiseFactor, Probability};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::ops::Range;
+
+//Node indices of a 2D grid MRF built by BPGraph::add_grid. The variables are added row by row, followed by
+//the horizontal smoothness factors (row by row) and the vertical ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Grid {
+    width: usize,
+    height: usize,
+    first_variable: NodeIndex,
+}
+
+impl Grid {
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn variables(&self) -> Range<NodeIndex> {
+        self.first_variable..self.first_variable + self.width * self.height
+    }
+
+    pub fn variable(&self, x: usize, y: usize) -> Option<NodeIndex> {
+        if x < self.width && y < self.height {
+            Some(self.first_variable + y * self.width + x)
+        } else {
+            None
+        }
+    }
+
+    //(x, y) of a variable of the grid
+    pub fn position(&self, node: NodeIndex) -> Option<(usize, usize)> {
+        if self.variables().contains(&node) {
+            let i = node - self.first_variable;
+            Some((i % self.width, i / self.width))
+        } else {
+            None
+        }
+    }
+
+    //Variables of the 2x2 squares of the grid, row by row, e.g., as outer regions of a RegionGraph
+    pub fn squares(&self) -> Vec<Vec<NodeIndex>> {
+        let mut squares = Vec::new();
+        for y in 0..self.height.saturating_sub(1) {
+            for x in 0..self.width.saturating_sub(1) {
+                squares.push(
+                    [(x, y), (x + 1, y), (x, y + 1), (x + 1, y + 1)]
+                        .iter()
+                        .filter_map(|(x, y)| self.variable(*x, *y))
+                        .collect(),
+                );
+            }
+        }
+        squares
+    }
+
+    //Factor between (x, y) and (x + 1, y)
+    pub fn horizontal_factor(&self, x: usize, y: usize) -> Option<NodeIndex> {
+        if x + 1 < self.width && y < self.height {
+            Some(self.variables().end + y * (self.width - 1) + x)
+        } else {
+            None
+        }
+    }
+
+    //Factor between (x, y) and (x, y + 1)
+    pub fn vertical_factor(&self, x: usize, y: usize) -> Option<NodeIndex> {
+        if x < self.width && y + 1 < self.height {
+            Some(self.variables().end + self.height * (self.width - 1) + y * self.width + x)
+        } else {
+            None
+        }
+    }
+}
+
+impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT> BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>
+where
+    T: Copy + Eq + Hash + Debug + Send + Sync + 'static,
+    MsgT: Clone + Send + Sync + 'static,
+    CtrlMsgT: IntoVariableNodeCtrl<MsgT> + 'static,
+    CtrlMsgAT: FromVariableNodeCtrlAnswer<MsgT> + 'static,
+{
+    //Builds a grid MRF (e.g., for stereo matching) of width x height variables over labels:
+    //costs is the cost volume, costs[(y * width + x) * labels.len() + l] is the data cost of label l at (x, y),
+    //and smoothness(a, b) the cost of neighbouring labels a and b. Costs c become potentials exp(-c), lifted
+    //into the semiring of the graph if one is set (see Semiring::lift), i.e., MinSum works on the costs.
+    //The data costs are the priors of the variables, every pair of horizontal and vertical neighbours is
+    //connected by a PairwiseFactor. The edges are added with add_edges_unchecked_parallel.
+    pub fn add_grid(
+        &mut self,
+        width: usize,
+        height: usize,
+        labels: &[T],
+        costs: &[Probability],
+        smoothness: impl Fn(T, T) -> Probability,
+    ) -> BPResult<Grid> {
+        self.check_unsealed("BPGraph::add_grid")?;
+        if width == 0 || height == 0 || labels.is_empty() {
+            return Err(BPError::new(
+                "BPGraph::add_grid".to_owned(),
+                format!(
+                    "Grid has no variables or no labels ({} x {}, {} labels)",
+                    width,
+                    height,
+                    labels.len()
+                ),
+            ));
+        }
+        if costs.len() != width * height * labels.len() {
+            return Err(BPError::new(
+                "BPGraph::add_grid".to_owned(),
+                format!(
+                    "Wrong size of the cost volume ({}, needed: {} x {} x {})",
+                    costs.len(),
+                    width,
+                    height,
+                    labels.len()
+                ),
+            ));
+        }
+        let potential = |cost: Probability| {
+            let p = (-cost).exp();
+            self.get_semiring().map_or(p, |s| s.lift(p))
+        };
+        let table: HashMap<(T, T), Probability> = labels
+            .iter()
+            .flat_map(|a| labels.iter().map(move |b| (*a, *b)))
+            .map(|(a, b)| ((a, b), potential(smoothness(a, b))))
+            .collect();
+        let priors: Vec<MsgT> = costs
+            .chunks(labels.len())
+            .map(|c| {
+                let mut prior = MsgT::new();
+                for (l, cost) in labels.iter().zip(c) {
+                    prior.insert(*l, potential(*cost));
+                }
+                prior
+            })
+            .collect();
+        let first_variable = self.len();
+        for (i, prior) in priors.into_iter().enumerate() {
+            self.add_variable(format!("pixel({}, {})", i % width, i / width), prior)?;
+        }
+        let grid = Grid {
+            width,
+            height,
+            first_variable,
+        };
+        let var = |x, y| grid.variable(x, y).expect("Position is in the grid");
+        let mut edges = Vec::with_capacity(4 * width * height);
+        for y in 0..height {
+            for x in 0..width - 1 {
+                let factor = self.add_factor(
+                    format!("smoothness(({}, {}), ({}, {}))", x, y, x + 1, y),
+                    PairwiseFactor::new(table.clone()),
+                )?;
+                edges.push((factor, var(x, y)));
+                edges.push((factor, var(x + 1, y)));
+            }
+        }
+        for y in 0..height - 1 {
+            for x in 0..width {
+                let factor = self.add_factor(
+                    format!("smoothness(({}, {}), ({}, {}))", x, y, x, y + 1),
+                    PairwiseFactor::new(table.clone()),
+                )?;
+                edges.push((factor, var(x, y)));
+                edges.push((factor, var(x, y + 1)));
+            }
+        }
+        self.add_edges_unchecked_parallel(&edges).map_err(|e| {
+            e.attach_info_str("BPGraph::add_grid", "Could not connect the grid".to_owned())
+        })?;
+        Ok(grid)
+    }
+}
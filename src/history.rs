@@ -0,0 +1,159 @@
+use crate::{BPError, BPResult, Msg, NodeIndex, Probability};
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::io::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryFormat {
+    //One row per entry of a message: step,from,to,value,probability
+    Csv,
+    //An array of {"step", "from", "to", "msg": [[value, probability], ..]}
+    Json,
+}
+
+#[derive(Debug, Clone)]
+pub struct HistoryEntry<MsgT> {
+    pub step: usize,
+    pub from: NodeIndex,
+    pub to: NodeIndex,
+    pub msg: MsgT,
+}
+
+//Messages sent during propagation, as created by the nodes (i.e., before normalization).
+//Only every every-th step is recorded, and only the last capacity messages are kept.
+#[derive(Clone)]
+pub struct MsgHistory<MsgT> {
+    every: usize,
+    capacity: usize,
+    entries: VecDeque<HistoryEntry<MsgT>>,
+    clone_msg: fn(&MsgT) -> MsgT,
+}
+
+impl<MsgT> MsgHistory<MsgT> {
+    pub(crate) fn new(every: usize, capacity: usize, clone_msg: fn(&MsgT) -> MsgT) -> Self {
+        MsgHistory {
+            every: every.max(1),
+            capacity,
+            entries: VecDeque::with_capacity(capacity.min(1 << 16)),
+            clone_msg,
+        }
+    }
+
+    pub(crate) fn record(&mut self, step: usize, msgs: &[(NodeIndex, Vec<(NodeIndex, MsgT)>)]) {
+        if step % self.every != 0 || self.capacity == 0 {
+            return;
+        }
+        for (from, out) in msgs {
+            for (to, msg) in out {
+                if self.entries.len() == self.capacity {
+                    self.entries.pop_front();
+                }
+                self.entries.push_back(HistoryEntry {
+                    step,
+                    from: *from,
+                    to: *to,
+                    msg: (self.clone_msg)(msg),
+                });
+            }
+        }
+    }
+
+    pub fn entries(&self) -> &VecDeque<HistoryEntry<MsgT>> {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    //All recorded messages sent along the edge from -> to, oldest first
+    pub fn edge(
+        &self,
+        from: NodeIndex,
+        to: NodeIndex,
+    ) -> impl Iterator<Item = &HistoryEntry<MsgT>> {
+        self.entries
+            .iter()
+            .filter(move |e| e.from == from && e.to == to)
+    }
+
+    pub fn dump<T, W: Write>(&self, writer: &mut W, format: HistoryFormat) -> BPResult<()>
+    where
+        T: Debug,
+        MsgT: Msg<T>,
+    {
+        let io_error = |e: std::io::Error| {
+            BPError::new(
+                "MsgHistory::dump".to_owned(),
+                format!("Writing failed: {}", e),
+            )
+        };
+        match format {
+            HistoryFormat::Csv => {
+                writeln!(writer, "step,from,to,value,probability").map_err(io_error)?;
+                for e in &self.entries {
+                    for (v, p) in (self.clone_msg)(&e.msg) {
+                        let value = format!("{:?}", v).replace('"', "\"\"");
+                        writeln!(writer, "{},{},{},\"{}\",{}", e.step, e.from, e.to, value, p)
+                            .map_err(io_error)?;
+                    }
+                }
+            }
+            HistoryFormat::Json => {
+                write!(writer, "[").map_err(io_error)?;
+                for (i, e) in self.entries.iter().enumerate() {
+                    let msg: Vec<String> = (self.clone_msg)(&e.msg)
+                        .into_iter()
+                        .map(|(v, p)| {
+                            format!("[{},{}]", json_string(&format!("{:?}", v)), json_number(p))
+                        })
+                        .collect();
+                    write!(
+                        writer,
+                        "{}{{\"step\":{},\"from\":{},\"to\":{},\"msg\":[{}]}}",
+                        if i == 0 { "" } else { "," },
+                        e.step,
+                        e.from,
+                        e.to,
+                        msg.join(",")
+                    )
+                    .map_err(io_error)?;
+                }
+                writeln!(writer, "]").map_err(io_error)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut res = String::with_capacity(s.len() + 2);
+    res.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+//JSON has no NaN or infinity
+fn json_number(p: Probability) -> String {
+    if p.is_finite() {
+        format!("{}", p)
+    } else {
+        "null".to_owned()
+    }
+}
@@ -0,0 +1,476 @@
+use crate::{BPError, BPGraph, BPResult, Msg, NodeIndex, Probability};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::hash::Hash;
+
+/*
+Exact inference via a junction tree (clique tree).
+
+The variables of the factor graph are moralized (all neighbours of a factor are connected),
+triangulated by eliminating variables with the min-fill heuristic, and the maximal elimination cliques
+are connected by a maximum weight spanning tree (weight: size of the separator).
+Factor potentials are taken from NodeFunction::potential, priors of variable nodes are used as unary potentials
+and define the domain of a variable. Every clique holds a dense table over the product of the domains of its variables,
+so this is only feasible for models with small treewidth.
+*/
+
+//Dense table over the product of the domains of some variables (first variable varies slowest)
+#[derive(Debug, Clone)]
+pub(crate) struct Table {
+    pub(crate) vars: Vec<usize>,
+    pub(crate) dims: Vec<usize>,
+    pub(crate) values: Vec<Probability>,
+}
+
+impl Table {
+    pub(crate) fn ones(vars: Vec<usize>, domain_sizes: &[usize]) -> Self {
+        let dims: Vec<usize> = vars.iter().map(|v| domain_sizes[*v]).collect();
+        let size = dims.iter().product();
+        Table {
+            vars,
+            dims,
+            values: vec![1.0; size],
+        }
+    }
+    pub(crate) fn assignment(&self, mut index: usize) -> Vec<usize> {
+        let mut res = vec![0; self.vars.len()];
+        for k in (0..self.vars.len()).rev() {
+            res[k] = index % self.dims[k];
+            index /= self.dims[k];
+        }
+        res
+    }
+    //Index of the entry of self matching an assignment of vars (self.vars has to be a subset of vars)
+    pub(crate) fn index_of(&self, vars: &[usize], assignment: &[usize]) -> usize {
+        self.vars
+            .iter()
+            .zip(self.dims.iter())
+            .fold(0, |acc, (v, d)| {
+                let pos = vars
+                    .iter()
+                    .position(|w| w == v)
+                    .expect("Variable not in scope");
+                acc * d + assignment[pos]
+            })
+    }
+    //other.vars has to be a subset of self.vars
+    pub(crate) fn multiply(&mut self, other: &Table) {
+        for i in 0..self.values.len() {
+            let assignment = self.assignment(i);
+            let j = other.index_of(&self.vars, &assignment);
+            self.values[i] *= other.values[j];
+        }
+    }
+    //other.vars has to be a subset of self.vars, entries divided by 0 become 0
+    pub(crate) fn divide(&mut self, other: &Table) {
+        for i in 0..self.values.len() {
+            let assignment = self.assignment(i);
+            let j = other.index_of(&self.vars, &assignment);
+            self.values[i] = if other.values[j] == 0.0 {
+                0.0
+            } else {
+                self.values[i] / other.values[j]
+            };
+        }
+    }
+    pub(crate) fn marginalize(&self, onto: &[usize], domain_sizes: &[usize]) -> Table {
+        let mut res = Table::ones(onto.to_vec(), domain_sizes);
+        res.values.iter_mut().for_each(|p| *p = 0.0);
+        for (i, p) in self.values.iter().enumerate() {
+            let assignment = self.assignment(i);
+            let j = res.index_of(&self.vars, &assignment);
+            res.values[j] += p;
+        }
+        res
+    }
+    pub(crate) fn normalize(&mut self) {
+        let sum: Probability = self.values.iter().sum();
+        if sum > 0.0 {
+            self.values.iter_mut().for_each(|p| *p /= sum);
+        }
+    }
+}
+
+//Variable nodes of the graph and their priors, which define their domains
+#[allow(clippy::type_complexity)]
+pub(crate) fn variable_priors<T, MsgT: Msg<T> + Clone, CtrlMsgT, CtrlMsgAT: Default>(
+    graph: &BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>,
+    function_name: &str,
+) -> BPResult<(Vec<NodeIndex>, Vec<Vec<(T, Probability)>>)>
+where
+    T: Debug,
+{
+    let mut variables = Vec::new();
+    let mut priors = Vec::new();
+    for idx in 0..graph.len() {
+        let node = graph.get_node(idx)?;
+        if node.is_factor() {
+            continue;
+        }
+        let prior = node.get_prior().ok_or_else(|| {
+            BPError::new(
+                function_name.to_owned(),
+                format!(
+                    "Variable node {} ({}) needs a prior to determine its domain",
+                    idx,
+                    node.get_name()
+                ),
+            )
+        })?;
+        variables.push(idx);
+        priors.push(prior.into_iter().collect());
+    }
+    Ok((variables, priors))
+}
+
+pub struct JunctionTree<T> {
+    //Graph node index of each variable
+    variables: Vec<NodeIndex>,
+    domains: Vec<Vec<T>>,
+    cliques: Vec<Table>,
+    //Adjacency of the cliques in the tree
+    tree: Vec<Vec<usize>>,
+    beliefs: Vec<Table>,
+}
+
+impl<T> JunctionTree<T>
+where
+    T: Copy + Eq + Hash + Debug,
+{
+    pub fn new<MsgT: Msg<T> + Clone, CtrlMsgT, CtrlMsgAT: Default>(
+        graph: &BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>,
+        max_table_size: usize,
+    ) -> BPResult<Self> {
+        let (variables, priors) = variable_priors(graph, "JunctionTree::new")?;
+        let var_id: HashMap<NodeIndex, usize> = variables
+            .iter()
+            .enumerate()
+            .map(|(i, idx)| (*idx, i))
+            .collect();
+        let domains: Vec<Vec<T>> = priors
+            .iter()
+            .map(|prior| prior.iter().map(|(v, _)| *v).collect())
+            .collect();
+        let domain_sizes: Vec<usize> = domains.iter().map(|d| d.len()).collect();
+        let nv = variables.len();
+
+        //Scopes of the factors
+        let mut factors = Vec::new();
+        for idx in 0..graph.len() {
+            let node = graph.get_node(idx)?;
+            if !node.is_factor() {
+                continue;
+            }
+            let scope = node
+                .get_connections()
+                .iter()
+                .map(|con| {
+                    var_id.get(con).copied().ok_or_else(|| {
+                        BPError::new(
+                            "JunctionTree::new".to_owned(),
+                            format!("Factor {} is connected to unknown variable {}", idx, con),
+                        )
+                    })
+                })
+                .collect::<BPResult<Vec<usize>>>()?;
+            factors.push((idx, scope));
+        }
+
+        //Moral graph
+        let mut adjacency: Vec<HashSet<usize>> = vec![HashSet::new(); nv];
+        for (_, scope) in &factors {
+            for v in scope {
+                for w in scope {
+                    if v != w {
+                        adjacency[*v].insert(*w);
+                    }
+                }
+            }
+        }
+
+        //Triangulation by min-fill elimination
+        let mut eliminated = vec![false; nv];
+        let mut elimination_cliques: Vec<Vec<usize>> = Vec::with_capacity(nv);
+        for _ in 0..nv {
+            let (_, _, v) = (0..nv)
+                .filter(|v| !eliminated[*v])
+                .map(|v| {
+                    let nbrs: Vec<&usize> = adjacency[v].iter().collect();
+                    let mut fill = 0;
+                    for (i, a) in nbrs.iter().enumerate() {
+                        for b in &nbrs[i + 1..] {
+                            if !adjacency[**a].contains(b) {
+                                fill += 1;
+                            }
+                        }
+                    }
+                    (fill, nbrs.len(), v)
+                })
+                .min()
+                .expect("No variable left to eliminate");
+            let nbrs: Vec<usize> = adjacency[v].iter().copied().collect();
+            for a in &nbrs {
+                for b in &nbrs {
+                    if a != b {
+                        adjacency[*a].insert(*b);
+                    }
+                }
+                adjacency[*a].remove(&v);
+            }
+            eliminated[v] = true;
+            let mut clique = nbrs;
+            clique.push(v);
+            clique.sort_unstable();
+            elimination_cliques.push(clique);
+        }
+
+        //Maximal cliques
+        let mut clique_vars: Vec<Vec<usize>> = Vec::new();
+        for (i, c) in elimination_cliques.iter().enumerate() {
+            let is_subset = elimination_cliques.iter().enumerate().any(|(j, d)| {
+                i != j && c.iter().all(|v| d.contains(v)) && (c.len() < d.len() || j < i)
+            });
+            if !is_subset {
+                clique_vars.push(c.clone());
+            }
+        }
+        for c in &clique_vars {
+            let size: usize = c.iter().map(|v| domain_sizes[*v]).product();
+            if size > max_table_size {
+                return Err(BPError::new(
+                    "JunctionTree::new".to_owned(),
+                    format!(
+                        "Clique table too large ({} entries, maximum: {})",
+                        size, max_table_size
+                    ),
+                )
+                .attach_debug_object(
+                    "clique (variable node indices)",
+                    c.iter().map(|v| variables[*v]).collect::<Vec<_>>(),
+                ));
+            }
+        }
+
+        //Maximum weight spanning forest (Kruskal)
+        let nc = clique_vars.len();
+        let mut edges = Vec::new();
+        for i in 0..nc {
+            for j in i + 1..nc {
+                let weight = clique_vars[i]
+                    .iter()
+                    .filter(|v| clique_vars[j].contains(v))
+                    .count();
+                if weight > 0 {
+                    edges.push((weight, i, j));
+                }
+            }
+        }
+        edges.sort_by(|a, b| b.0.cmp(&a.0).then((a.1, a.2).cmp(&(b.1, b.2))));
+        let mut component: Vec<usize> = (0..nc).collect();
+        fn find(component: &mut [usize], i: usize) -> usize {
+            let mut root = i;
+            while component[root] != root {
+                root = component[root];
+            }
+            component[i] = root;
+            root
+        }
+        let mut tree = vec![Vec::new(); nc];
+        for (_, i, j) in edges {
+            let (ri, rj) = (find(&mut component, i), find(&mut component, j));
+            if ri != rj {
+                component[ri] = rj;
+                tree[i].push(j);
+                tree[j].push(i);
+            }
+        }
+
+        //Clique potentials
+        let mut cliques: Vec<Table> = clique_vars
+            .into_iter()
+            .map(|vars| Table::ones(vars, &domain_sizes))
+            .collect();
+        for (v, prior) in priors.iter().enumerate() {
+            let c = cliques
+                .iter()
+                .position(|c| c.vars.contains(&v))
+                .expect("Variable not in any clique");
+            let mut unary = Table::ones(vec![v], &domain_sizes);
+            unary.values = prior.iter().map(|(_, p)| *p).collect();
+            cliques[c].multiply(&unary);
+        }
+        for (idx, scope) in &factors {
+            let node = graph.get_node(*idx)?;
+            let c = cliques
+                .iter()
+                .position(|c| scope.iter().all(|v| c.vars.contains(v)))
+                .expect("Factor scope not in any clique");
+            let clique = &mut cliques[c];
+            for i in 0..clique.values.len() {
+                let assignment = clique.assignment(i);
+                let values: Vec<T> = scope
+                    .iter()
+                    .map(|v| {
+                        let pos = clique.vars.iter().position(|w| w == v).unwrap();
+                        domains[*v][assignment[pos]]
+                    })
+                    .collect();
+                let p = node.potential(&values).ok_or_else(|| {
+                    BPError::new(
+                        "JunctionTree::new".to_owned(),
+                        format!(
+                            "Factor {} ({}) does not implement potential",
+                            idx,
+                            node.get_name()
+                        ),
+                    )
+                })?;
+                clique.values[i] *= p;
+            }
+        }
+
+        let mut jt = JunctionTree {
+            variables,
+            domains,
+            beliefs: cliques.clone(),
+            cliques,
+            tree,
+        };
+        jt.calibrate(&domain_sizes);
+        Ok(jt)
+    }
+
+    //Two pass sum-product message passing on every tree of the forest
+    fn calibrate(&mut self, domain_sizes: &[usize]) {
+        let nc = self.cliques.len();
+        let mut visited = vec![false; nc];
+        let mut msgs: HashMap<(usize, usize), Table> = HashMap::new();
+        for root in 0..nc {
+            if visited[root] {
+                continue;
+            }
+            //Order of the cliques such that parents come before their children
+            let mut order = vec![(root, None)];
+            visited[root] = true;
+            let mut i = 0;
+            while i < order.len() {
+                let (c, _) = order[i];
+                for n in &self.tree[c] {
+                    if !visited[*n] {
+                        visited[*n] = true;
+                        order.push((*n, Some(c)));
+                    }
+                }
+                i += 1;
+            }
+            for (c, parent) in order.iter().rev() {
+                if let Some(parent) = parent {
+                    let msg = self.message(*c, *parent, &msgs, domain_sizes);
+                    msgs.insert((*c, *parent), msg);
+                }
+            }
+            for (c, parent) in order.iter() {
+                for n in &self.tree[*c] {
+                    if Some(*n) != *parent {
+                        let msg = self.message(*c, *n, &msgs, domain_sizes);
+                        msgs.insert((*c, *n), msg);
+                    }
+                }
+            }
+        }
+        for c in 0..nc {
+            let mut belief = self.cliques[c].clone();
+            for n in &self.tree[c] {
+                belief.multiply(&msgs[&(*n, c)]);
+            }
+            belief.normalize();
+            self.beliefs[c] = belief;
+        }
+    }
+
+    fn message(
+        &self,
+        from: usize,
+        to: usize,
+        msgs: &HashMap<(usize, usize), Table>,
+        domain_sizes: &[usize],
+    ) -> Table {
+        let mut table = self.cliques[from].clone();
+        for n in &self.tree[from] {
+            if *n != to {
+                table.multiply(&msgs[&(*n, from)]);
+            }
+        }
+        let separator: Vec<usize> = self.cliques[from]
+            .vars
+            .iter()
+            .copied()
+            .filter(|v| self.cliques[to].vars.contains(v))
+            .collect();
+        let mut msg = table.marginalize(&separator, domain_sizes);
+        msg.normalize();
+        msg
+    }
+
+    //Exact marginal (summing to one) of a variable node
+    pub fn marginal(&self, node_index: NodeIndex) -> Option<HashMap<T, Probability>> {
+        let v = self.variables.iter().position(|idx| *idx == node_index)?;
+        let belief = self.beliefs.iter().find(|b| b.vars.contains(&v))?;
+        let domain_sizes: Vec<usize> = self.domains.iter().map(|d| d.len()).collect();
+        let mut marginal = belief.marginalize(&[v], &domain_sizes);
+        marginal.normalize();
+        Some(
+            self.domains[v]
+                .iter()
+                .copied()
+                .zip(marginal.values)
+                .collect(),
+        )
+    }
+
+    pub fn marginals(&self) -> HashMap<NodeIndex, HashMap<T, Probability>> {
+        self.variables
+            .iter()
+            .filter_map(|idx| self.marginal(*idx).map(|m| (*idx, m)))
+            .collect()
+    }
+
+    //Cliques as lists of variable node indices
+    pub fn cliques(&self) -> Vec<Vec<NodeIndex>> {
+        self.cliques
+            .iter()
+            .map(|c| c.vars.iter().map(|v| self.variables[*v]).collect())
+            .collect()
+    }
+
+    //Size of the largest clique minus one
+    pub fn treewidth(&self) -> usize {
+        self.cliques
+            .iter()
+            .map(|c| c.vars.len())
+            .max()
+            .unwrap_or(1)
+            .saturating_sub(1)
+    }
+}
+
+impl<T, MsgT: Msg<T> + Clone, CtrlMsgT, CtrlMsgAT: Default> BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>
+where
+    T: Copy + Eq + Hash + Debug,
+{
+    //Exact marginals of all variable nodes using a junction tree.
+    //Fails if a clique table would have more than max_table_size entries.
+    pub fn exact_marginals_junction_tree(
+        &self,
+        max_table_size: usize,
+    ) -> BPResult<HashMap<NodeIndex, HashMap<T, Probability>>> {
+        Ok(JunctionTree::new(self, max_table_size)
+            .map_err(|e| {
+                e.attach_info_str(
+                    "BPGraph::exact_marginals_junction_tree",
+                    "Failed to build junction tree".to_owned(),
+                )
+            })?
+            .marginals())
+    }
+}
@@ -0,0 +1,261 @@
+use crate::{BPError, BPGraph, BPResult, Msg, NodeIndex, Probability};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fmt::Debug;
+use std::hash::Hash;
+
+//Rank of a key among all keys ordered by decreasing probability, 1 being the most likely key.
+//See estimate_key_rank.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyRank {
+    pub estimate: f64,
+    //The true rank is within [lower, upper]
+    pub lower: f64,
+    pub upper: f64,
+}
+
+impl KeyRank {
+    pub fn log2_estimate(&self) -> f64 {
+        self.estimate.log2()
+    }
+}
+
+//Normalized probabilities of the possible values of a subkey, the most likely first
+//(ties are broken by the smallest value)
+fn sorted_probabilities<T>(
+    marginal: &HashMap<T, Probability>,
+    i: usize,
+    fn_name: &str,
+) -> BPResult<Vec<(T, Probability)>>
+where
+    T: Copy + Ord,
+{
+    let sum: Probability = marginal.values().sum();
+    if sum <= 0.0 || !sum.is_finite() || marginal.values().any(|p| *p < 0.0) {
+        return Err(BPError::new(
+            fn_name.to_owned(),
+            format!(
+                "Marginal of subkey {} is not a distribution (sum {})",
+                i, sum
+            ),
+        ));
+    }
+    let mut probs: Vec<(T, Probability)> = marginal
+        .iter()
+        .filter(|(_, p)| **p > 0.0)
+        .map(|(v, p)| (*v, p / sum))
+        .collect();
+    probs.sort_by(|(v0, p0), (v1, p1)| {
+        p1.partial_cmp(p0)
+            .unwrap_or(Ordering::Equal)
+            .then(v0.cmp(v1))
+    });
+    Ok(probs)
+}
+
+//Estimates the rank of key given the marginals of its subkeys (e.g., the key bytes of a side-channel attack),
+//assuming that the subkeys are independent. Uses the histogram convolution method: the costs -log2(p) of the
+//values of every subkey are binned into a histogram with bins bins over the common range of all costs, and the
+//histograms are convolved into a histogram of the costs of all keys. Finer bins give tighter bounds, the
+//bounds are off by at most one bin per subkey.
+//Values missing from a marginal or with probability 0 are impossible and ranked after all possible keys.
+pub fn estimate_key_rank<T>(
+    marginals: &[HashMap<T, Probability>],
+    key: &[T],
+    bins: usize,
+) -> BPResult<KeyRank>
+where
+    T: Copy + Ord + Hash,
+{
+    if marginals.is_empty() || marginals.len() != key.len() {
+        return Err(BPError::new(
+            "estimate_key_rank".to_owned(),
+            format!(
+                "Needs a marginal for every subkey ({} marginals, {} subkeys)",
+                marginals.len(),
+                key.len()
+            ),
+        ));
+    }
+    if bins == 0 {
+        return Err(BPError::new(
+            "estimate_key_rank".to_owned(),
+            "Needs at least one bin".to_owned(),
+        ));
+    }
+    let probs = marginals
+        .iter()
+        .enumerate()
+        .map(|(i, m)| sorted_probabilities(m, i, "estimate_key_rank"))
+        .collect::<BPResult<Vec<_>>>()?;
+    let key_probs: Vec<Option<Probability>> = probs
+        .iter()
+        .zip(key)
+        .map(|(p, k)| p.iter().find(|(v, _)| v == k).map(|(_, p)| *p))
+        .collect();
+    let possible_keys: f64 = probs.iter().map(|p| p.len() as f64).product();
+    if key_probs.iter().any(|p| p.is_none()) {
+        let all_keys: f64 = marginals.iter().map(|m| m.len().max(1) as f64).product();
+        return Ok(KeyRank {
+            estimate: (possible_keys + 1.0 + all_keys) / 2.0,
+            lower: possible_keys + 1.0,
+            upper: all_keys.max(possible_keys + 1.0),
+        });
+    }
+    let costs: Vec<Vec<f64>> = probs
+        .iter()
+        .map(|p| p.iter().map(|(_, p)| -p.log2()).collect())
+        .collect();
+    let min = costs
+        .iter()
+        .flatten()
+        .copied()
+        .fold(f64::INFINITY, f64::min);
+    let max = costs
+        .iter()
+        .flatten()
+        .copied()
+        .fold(f64::NEG_INFINITY, f64::max);
+    let width = if max > min {
+        (max - min) / bins as f64
+    } else {
+        1.0
+    };
+    let bin = |c: f64| (((c - min) / width) as usize).min(bins - 1);
+    //Histogram of the bin sums of all possible keys
+    let mut hist = vec![1.0];
+    for subkey in &costs {
+        let mut h: HashMap<usize, f64> = HashMap::new();
+        for c in subkey {
+            *h.entry(bin(*c)).or_insert(0.0) += 1.0;
+        }
+        let mut next = vec![0.0; hist.len() + bins - 1];
+        for (b, count) in h {
+            for (j, n) in hist.iter().enumerate() {
+                next[j + b] += n * count;
+            }
+        }
+        hist = next;
+    }
+    let key_bin: usize = key_probs
+        .iter()
+        .map(|p| bin(-p.expect("Key is possible").log2()))
+        .sum();
+    let n = key.len();
+    //A key whose bin sum is at least n below (above) the one of key is more (less) likely
+    let lower = 1.0 + hist[..(key_bin + 1).saturating_sub(n)].iter().sum::<f64>();
+    let upper = hist[..(key_bin + n).min(hist.len())].iter().sum::<f64>();
+    let estimate = hist[..key_bin].iter().sum::<f64>() + (hist[key_bin] + 1.0) / 2.0;
+    Ok(KeyRank {
+        estimate: estimate.clamp(lower, upper),
+        lower,
+        upper,
+    })
+}
+
+struct Candidate {
+    log_prob: f64,
+    indices: Vec<usize>,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    //The most likely candidate is the largest, ties are broken by the smaller indices
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.log_prob
+            .partial_cmp(&other.log_prob)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| other.indices.cmp(&self.indices))
+    }
+}
+
+//The count most likely keys with their probabilities, the most likely first, assuming independent subkeys.
+//Enumerates best first, so the cost grows with count, not with the number of keys.
+pub fn enumerate_keys<T>(
+    marginals: &[HashMap<T, Probability>],
+    count: usize,
+) -> BPResult<Vec<(Vec<T>, Probability)>>
+where
+    T: Copy + Ord + Hash,
+{
+    let probs = marginals
+        .iter()
+        .enumerate()
+        .map(|(i, m)| sorted_probabilities(m, i, "enumerate_keys"))
+        .collect::<BPResult<Vec<_>>>()?;
+    let mut keys = Vec::new();
+    if probs.is_empty() || probs.iter().any(|p| p.is_empty()) {
+        return Ok(keys);
+    }
+    let log_prob =
+        |indices: &[usize]| -> f64 { indices.iter().zip(&probs).map(|(i, p)| p[*i].1.ln()).sum() };
+    let first = vec![0; probs.len()];
+    let mut heap = BinaryHeap::new();
+    let mut seen: HashSet<Vec<usize>> = HashSet::new();
+    heap.push(Candidate {
+        log_prob: log_prob(&first),
+        indices: first.clone(),
+    });
+    seen.insert(first);
+    while keys.len() < count {
+        let candidate = match heap.pop() {
+            Some(candidate) => candidate,
+            None => break,
+        };
+        for s in 0..probs.len() {
+            if candidate.indices[s] + 1 < probs[s].len() {
+                let mut next = candidate.indices.clone();
+                next[s] += 1;
+                if seen.insert(next.clone()) {
+                    heap.push(Candidate {
+                        log_prob: log_prob(&next),
+                        indices: next,
+                    });
+                }
+            }
+        }
+        keys.push((
+            candidate
+                .indices
+                .iter()
+                .zip(&probs)
+                .map(|(i, p)| p[*i].0)
+                .collect(),
+            candidate.log_prob.exp(),
+        ));
+    }
+    Ok(keys)
+}
+
+impl<T, MsgT: Msg<T> + Clone, CtrlMsgT, CtrlMsgAT: Default> BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>
+where
+    T: Copy + Eq + Hash + Debug,
+{
+    //Results of the subkey nodes, in the order of subkeys, for estimate_key_rank and enumerate_keys
+    pub fn key_marginals(&self, subkeys: &[NodeIndex]) -> BPResult<Vec<HashMap<T, Probability>>> {
+        subkeys
+            .iter()
+            .map(|node| {
+                self.get_result(*node)?.ok_or_else(|| {
+                    BPError::new(
+                        "BPGraph::key_marginals".to_owned(),
+                        format!("Node {} has no result", node),
+                    )
+                })
+            })
+            .collect()
+    }
+}
@@ -1,32 +1,153 @@
 #![allow(unused)]
 #[macro_use]
 pub mod macros;
+pub mod algorithm;
+pub mod batched;
+pub mod bayesnet;
+pub mod bethe;
 pub mod bperror;
 pub mod bpgraph;
+pub mod bruteforce;
+pub mod coarsen;
+pub mod decimation;
+pub mod dense_msg;
+pub mod distance_factor;
+pub mod distributed_bp;
+pub mod domain;
+pub mod em;
+pub mod equality_factor;
+pub mod fg;
+pub mod fixed_arity_factor;
+pub mod fn_factor;
+pub mod function_factor;
+pub mod generators;
+pub mod gpu;
+pub mod grid;
+pub mod history;
+pub mod junction_tree;
+pub mod key_rank;
+pub mod memoize;
+pub mod metrics;
+pub mod modular_factor;
 pub mod msg;
+pub mod msg_pool;
+pub mod msg_transform;
 pub mod node;
 pub mod node_function;
+pub mod ntt;
+pub mod pairwise_factor;
+pub mod parity_factor;
+pub mod particle_msg;
+pub mod potts_factor;
+pub mod prune;
+pub mod quantized_msg;
+pub mod region_graph;
+pub mod restarts;
+pub mod rng;
+pub mod scaled_msg;
+pub mod scheduler;
+pub mod semiring;
+pub mod shared_msg;
+pub mod simd;
+pub mod stats;
+pub mod survey;
+pub mod table_factor;
+pub mod template;
+pub mod thread_pool;
+pub mod trw;
 pub mod types;
+pub mod validation;
 pub mod variable_node;
+pub mod window;
+pub mod wire;
 
+pub use algorithm::ldpc::{LdpcAlgorithm, LdpcDecoder, LdpcGraph, LdpcResult};
+pub use batched::BatchedBPGraph;
+pub use bayesnet::{BayesNet, BayesNetGraph, CompiledBayesNet};
 pub use bperror::{BPError, BPResult};
-pub use bpgraph::{BPGraph, NodeIndex};
-pub use msg::Msg;
+pub use bpgraph::{
+    BPGraph, Contradiction, GraphState, NodeFilter, NodeIndex, NodeReadiness, PropagationState,
+    StepReport,
+};
+pub use decimation::{DecimationConfig, DecimationOutcome};
+pub use dense_msg::DenseMsg;
+pub use distance_factor::{DistanceCost, DistanceFactor};
+pub use distributed_bp::{DistributedWorker, GhostNode, GraphLayout};
+pub use domain::{DenseAdapter, DomainMap};
+pub use equality_factor::EqualityFactor;
+pub use fg::{read_fg, write_fg};
+pub use fixed_arity_factor::FixedArityFactor;
+pub use fn_factor::FnFactor;
+pub use function_factor::{BijectionFactor, FunctionFactor};
+pub use gpu::gpu_available;
+pub use grid::Grid;
+pub use history::{HistoryEntry, HistoryFormat, MsgHistory};
+pub use junction_tree::JunctionTree;
+pub use key_rank::{enumerate_keys, estimate_key_rank, KeyRank};
+pub use memoize::MemoizedFactor;
+pub use metrics::{entropy, polarization, MarginalChange};
+pub use modular_factor::{ModAddFactor, ModMulFactor};
+pub use msg::{Msg, MsgValidityError, NormalizationMode, ZeroMessagePolicy};
+pub use msg_pool::MsgPool;
+pub use msg_transform::MsgTransform;
 pub use node::hashmap_to_distribution;
-pub use node::Node;
-pub use node_function::NodeFunction;
+pub use node::{InboxPolicy, Node, ResultOptions, ResultOrder};
+pub use node_function::{AsAny, GraphInfo, NodeFunction};
+pub use ntt::{ConvolutionBackend, NttConvolutionFactor, NttPlan};
+pub use pairwise_factor::PairwiseFactor;
+pub use parity_factor::ParityFactor;
+pub use particle_msg::ParticleMsg;
+pub use potts_factor::PottsFactor;
+pub use quantized_msg::{QuantizedMsg, Quantum};
+pub use region_graph::RegionGraph;
+pub use restarts::{average_marginals, disagreement_report, Disagreement, MarginalSet};
+pub use rng::SplitMix64;
+pub use scaled_msg::ScaledMsg;
+pub use scheduler::{FloodingScheduler, LayerScheduler, Scheduler};
+pub use semiring::{BooleanOrAnd, MaxProduct, MaxSum, MinSum, Semiring, SumProduct};
+pub use shared_msg::SharedMsg;
+pub use simd::{set_simd_enabled, simd_available};
+pub use stats::GraphStats;
+pub use survey::{
+    SpBias, SpConfig, SpCtrl, SpCtrlAnswer, SpFactor, SpGraph, SpOutcome, SpValue, SpVariable,
+    SurveyPropagation,
+};
+pub use table_factor::TableFactor;
+pub use template::{Plate, Template};
+pub use thread_pool::{BatchStrategy, ThreadPool, ThreadingConfig};
+pub use trw::TreeReweighted;
 pub use types::Probability;
-pub use variable_node::VariableNode;
+pub use validation::ValidationIssue;
+pub use variable_node::{
+    InputNeed, PriorCombination, ReadyPolicy, VariableNode, VariableNodeBuilder, VariableNodeCtrl,
+    VariableNodeCtrlAnswer,
+};
+pub use window::TimeWindow;
+pub use wire::WireValue;
 
 //TODO: Add tests
 #[cfg(test)]
 mod tests {
     use crate::{
-        node_function, BPError, BPGraph, BPResult, Msg, NodeFunction, NodeIndex, Probability,
-        VariableNode,
+        average_marginals, disagreement_report, enumerate_keys, estimate_key_rank, generators,
+        gpu_available, modular_factor, node_function, read_fg, write_fg, BPError, BPGraph,
+        BPResult, BatchStrategy, BatchedBPGraph, BayesNet, BijectionFactor, BooleanOrAnd,
+        Contradiction, ConvolutionBackend, DecimationConfig, DecimationOutcome, DenseAdapter,
+        DenseMsg, DistanceCost, DistanceFactor, DistributedWorker, DomainMap, EqualityFactor,
+        FixedArityFactor, FnFactor, FunctionFactor, GraphInfo, HistoryFormat, InboxPolicy,
+        InputNeed, LayerScheduler, LdpcAlgorithm, LdpcDecoder, MarginalChange, MaxProduct, MaxSum,
+        MemoizedFactor, MinSum, ModAddFactor, ModMulFactor, Msg, NodeFilter, NodeFunction,
+        NodeIndex, NormalizationMode, NttConvolutionFactor, NttPlan, PairwiseFactor, ParticleMsg,
+        PottsFactor, PriorCombination, Probability, PropagationState, QuantizedMsg, RegionGraph,
+        ResultOptions, ResultOrder, ScaledMsg, Semiring, SharedMsg, SpConfig, SpOutcome,
+        SurveyPropagation, TableFactor, Template, ThreadingConfig, TreeReweighted, ValidationIssue,
+        VariableNode, VariableNodeCtrl, VariableNodeCtrlAnswer, ZeroMessagePolicy,
     };
     use std::collections::HashMap;
     use std::fmt::Debug;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
 
     fn mul(x: i32, y: i32) -> Probability {
         if 2 * x == y {
@@ -36,57 +157,5041 @@ mod tests {
         }
     }
 
+    fn near(x: i32, y: i32) -> Probability {
+        1.0 / (1.0 + (x - y).abs() as Probability)
+    }
+
+    #[test]
+    #[allow(clippy::iter_kv_map)]
+    fn test() -> BPResult<()> {
+        let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+        let mut v0 = VariableNode::new();
+        let mut v1 = VariableNode::new();
+        let mut v2 = VariableNode::new();
+        let mut dist0 = HashMap::new();
+        let mut dist1 = HashMap::new();
+        dist0.insert(1, 1.0);
+        dist1.insert(1, 0.25);
+        dist1.insert(2, 0.25);
+        dist1.insert(3, 0.25);
+        dist1.insert(4, 0.25);
+        v0.set_prior(&dist0);
+        v1.set_prior(&dist1);
+        v2.set_prior(&dist1);
+
+        let t3 = TwoNode::new(mul);
+        let t4 = TwoNode::new(mul);
+        g.add_node("0".to_string(), Box::new(v0))?;
+        g.add_node("1".to_string(), Box::new(v1))?;
+        g.add_node("2".to_string(), Box::new(v2))?;
+        g.add_node("m3".to_string(), Box::new(t3))?;
+        g.add_node("m4".to_string(), Box::new(t4))?;
+
+        g.add_edge(0, 3)?;
+        g.add_edge(3, 1)?;
+        g.add_edge(1, 4)?;
+        g.add_edge(4, 2)?;
+
+        assert!(g.is_valid());
+
+        g.initialize()?;
+
+        g.propagate_threaded(2, 1)?;
+        g.propagate(10)?;
+        let mut res = g.get_result(2)?.unwrap();
+        let mut sum: f64 = res.iter().map(|(_, p)| p).sum();
+        let res_normed: HashMap<i32, Probability> =
+            res.iter().map(|(v, p)| (*v, p / sum)).collect();
+
+        println!("{:?}", res_normed);
+        for (v, p) in res_normed {
+            if v == 4 {
+                //TODO: Allow a small error
+                assert_eq!(p, 1.0);
+            } else {
+                assert_eq!(p, 0.0);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_link_variables() -> BPResult<()> {
+        let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+        let mut v0 = VariableNode::new();
+        let mut v1 = VariableNode::new();
+        let mut dist0 = HashMap::new();
+        let mut dist1 = HashMap::new();
+        dist0.insert(1, 0.2);
+        dist0.insert(2, 0.8);
+        dist1.insert(1, 0.5);
+        dist1.insert(2, 0.5);
+        v0.set_prior(&dist0)?;
+        v1.set_prior(&dist1)?;
+        g.add_node("v0".to_string(), Box::new(v0))?;
+        g.add_node("v1".to_string(), Box::new(v1))?;
+        g.link_variables("eq".to_string(), &[0, 1])?;
+
+        assert!(g.is_valid());
+        g.initialize()?;
+        g.propagate(2)?;
+
+        let res = g.get_result(1)?.unwrap();
+        assert!((res[&1] / res[&2] - 0.25).abs() < 1e-9);
+        Ok(())
+    }
+
+    #[test]
+    fn test_modular_factors() -> BPResult<()> {
+        let q = 7;
+        let mut g = BPGraph::<usize, DenseMsg>::new();
+        let mut x = VariableNode::new();
+        let mut y = VariableNode::new();
+        let mut z = VariableNode::new();
+        let mut w = VariableNode::new();
+        let mut dist_x = DenseMsg::zeros(q);
+        let mut dist_y = DenseMsg::zeros(q);
+        dist_x.insert(2, 1.0);
+        dist_y.insert(3, 1.0);
+        x.set_prior(&dist_x)?;
+        y.set_prior(&dist_y)?;
+        z.set_prior(&DenseMsg::uniform(q))?;
+        w.set_prior(&DenseMsg::uniform(q))?;
+        let x = g.add_node("x".to_string(), Box::new(x))?;
+        let y = g.add_node("y".to_string(), Box::new(y))?;
+        let z = g.add_node("z".to_string(), Box::new(z))?;
+        let w = g.add_node("w".to_string(), Box::new(w))?;
+        let add = g.add_node("add".to_string(), Box::new(ModAddFactor::new(q)))?;
+        let mul = g.add_node("mul".to_string(), Box::new(ModMulFactor::new(3, q)))?;
+        g.add_edge(add, x)?;
+        g.add_edge(add, y)?;
+        g.add_edge(add, z)?;
+        g.add_edge(mul, x)?;
+        g.add_edge(mul, w)?;
+
+        assert!(g.is_valid());
+        g.initialize()?;
+        g.propagate(2)?;
+
+        let res_z = g.get_result(z)?.unwrap();
+        let res_w = g.get_result(w)?.unwrap();
+        for v in 0..q {
+            assert_eq!(res_z[&v], if v == 5 { 1.0 } else { 0.0 });
+            assert_eq!(res_w[&v], if v == 6 { 1.0 } else { 0.0 });
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_dense_adapter() -> BPResult<()> {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        enum Day {
+            Mon,
+            Tue,
+            Wed,
+        }
+        let domain = Arc::new(DomainMap::new(vec![Day::Mon, Day::Tue, Day::Wed])?);
+        assert!(DomainMap::new(vec![Day::Mon, Day::Mon]).is_err());
+
+        //Tomorrow: x + 1 = y
+        let mut g = BPGraph::<Day, HashMap<Day, Probability>>::new();
+        let mut today = HashMap::new();
+        today.insert(Day::Tue, 1.0);
+        let mut x = VariableNode::new();
+        x.set_prior(&today)?;
+        let mut one = VariableNode::new();
+        one.set_prior(&[(Day::Tue, 1.0)].iter().copied().collect())?;
+        let mut y = VariableNode::new();
+        y.set_prior(&domain.values().iter().map(|d| (*d, 1.0)).collect())?;
+        let x = g.add_node("x".to_string(), Box::new(x))?;
+        let one = g.add_node("one".to_string(), Box::new(one))?;
+        let y = g.add_node("y".to_string(), Box::new(y))?;
+        let add = g.add_node(
+            "add".to_string(),
+            Box::new(DenseAdapter::new(Box::new(ModAddFactor::new(3)), domain)),
+        )?;
+        g.add_edge(add, x)?;
+        g.add_edge(add, one)?;
+        g.add_edge(add, y)?;
+        g.initialize()?;
+        g.propagate(2)?;
+        let res = g.get_result(y)?.unwrap();
+        assert_eq!(res[&Day::Wed], 1.0);
+        assert_eq!(res[&Day::Mon], 0.0);
+        assert_eq!(g.exact_marginals_bruteforce(100)?[&y][&Day::Wed], 1.0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dense_adapter_is_ready() -> BPResult<()> {
+        let domain = Arc::new(DomainMap::new(vec![10, 20, 30])?);
+        let mut adapter: DenseAdapter<i32> =
+            DenseAdapter::new(Box::new(ModAddFactor::new(3)), domain);
+        NodeFunction::<i32, HashMap<i32, Probability>, (), ()>::initialize(
+            &mut adapter,
+            vec![0, 1, 2],
+        )?;
+        //Readiness does not convert the messages, so values outside the domain are only noticed later
+        let msg: HashMap<i32, Probability> = vec![(10, 0.5), (99, 0.5)].into_iter().collect();
+        let recv_from = vec![(0, msg.clone()), (1, msg.clone()), (2, msg)];
+        assert!(adapter.is_ready(&recv_from, 0)?);
+        assert!(adapter.node_function(recv_from).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate() -> BPResult<()> {
+        let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+        g.add_node("v0".to_string(), Box::new(VariableNode::new()))?;
+        g.add_node("v1".to_string(), Box::new(VariableNode::new()))?;
+        g.add_node("t".to_string(), Box::new(TwoNode::new(mul)))?;
+        g.add_edge(0, 2)?;
+
+        let issues = g.validate().unwrap_err();
+        assert!(!g.is_valid());
+        assert_eq!(
+            issues,
+            vec![
+                ValidationIssue::IsolatedNode {
+                    node: 1,
+                    name: "v1".to_string()
+                },
+                ValidationIssue::WrongArity {
+                    node: 2,
+                    name: "t".to_string(),
+                    expected: 2,
+                    got: 1
+                },
+            ]
+        );
+        g.add_edge(1, 2)?;
+        assert!(g.validate().is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_msg_validate() {
+        let msg = DenseMsg::from_vec(vec![0.5, Probability::NAN, -0.1, 2.0, 1.5]);
+        let err = msg.validate().unwrap_err();
+        assert_eq!((err.nan, err.negative, err.greater_one), (1, 1, 2));
+        assert_eq!(err.examples[0].0, "1");
+        assert!(DenseMsg::uniform(4).validate().is_ok());
+    }
+
+    #[test]
+    fn test_cancel() -> BPResult<()> {
+        let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+        let mut v0 = VariableNode::new();
+        let mut dist0 = HashMap::new();
+        dist0.insert(1, 1.0);
+        v0.set_prior(&dist0)?;
+        g.add_node("v0".to_string(), Box::new(v0))?;
+        g.add_node("v1".to_string(), Box::new(VariableNode::new()))?;
+        g.link_variables("eq".to_string(), &[0, 1])?;
+        g.initialize()?;
+
+        let cancel = AtomicBool::new(true);
+        assert_eq!(
+            g.propagate_threaded_cancellable(10, 2, &cancel)?,
+            PropagationState::Cancelled {
+                completed_steps: 0,
+                partial_step: false
+            }
+        );
+        cancel.store(false, Ordering::Relaxed);
+        assert_eq!(
+            g.propagate_threaded_cancellable(1, 2, &cancel)?,
+            PropagationState::Finished
+        );
+        assert!(g.get_result(0)?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_propagate_for() -> BPResult<()> {
+        let mut g = chain_graph()?;
+        assert_eq!(g.propagate_for(Duration::from_millis(0))?, 0);
+        let steps = g.propagate_for(Duration::from_millis(20))?;
+        assert!(steps > 0);
+        assert_eq!(g.get_step(), steps);
+        let steps_threaded = g.propagate_threaded_for(Duration::from_millis(20), 2)?;
+        assert!(g.get_step() >= steps + steps_threaded);
+        assert!(g.get_result(0)?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_initialize_random() -> BPResult<()> {
+        let mut graphs = Vec::new();
+        for seed in [7, 7, 8] {
+            let mut g = chain_graph()?;
+            g.initialize_random(seed, 0.1)?;
+            g.propagate(1)?;
+            graphs.push(g.get_result(1)?.unwrap());
+        }
+        //Equal up to the order of summation within the messages
+        let max_diff = |a: &HashMap<i32, Probability>, b: &HashMap<i32, Probability>| {
+            a.iter().map(|(v, p)| (p - b[v]).abs()).fold(0.0, f64::max)
+        };
+        assert!(max_diff(&graphs[0], &graphs[1]) < 1e-12);
+        assert!(max_diff(&graphs[0], &graphs[2]) > 1e-6);
+        assert!(chain_graph()?.initialize_random(7, 1.5).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_reweighted() -> BPResult<()> {
+        //On a tree with all weights 1, TRW is exact
+        let g = chain_graph()?;
+        let mut trw = TreeReweighted::new(&g, 100)?;
+        assert!(trw.propagate(50, 1e-12) < 50);
+        let exact = g.exact_marginals_bruteforce(1000)?;
+        let mut log_z: f64 = 0.0;
+        for a in 0..256 {
+            let x: Vec<i32> = (0..4).map(|i| (a >> (2 * i)) % 4 + 1).collect();
+            let p: Probability = x.iter().map(|v| 0.1 * *v as Probability).product();
+            log_z += p
+                * (0..3)
+                    .map(|i| near(x[i], x[i + 1]))
+                    .product::<Probability>();
+        }
+        log_z = log_z.ln();
+        assert!((trw.log_partition_upper_bound() - log_z).abs() < 1e-9);
+        for i in 0..4 {
+            for (v, p) in &exact[&i] {
+                assert!((trw.marginal(i).unwrap()[v] - p).abs() < 1e-9);
+            }
+        }
+
+        //A loop v0 - v1 - v2 - v0, every edge is in two of the three spanning trees
+        let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+        let mut dist = HashMap::new();
+        for v in 0..3 {
+            dist.insert(v, 1.0 + v as Probability);
+        }
+        for i in 0..3 {
+            //Unnormalized potentials, the partition function depends on their scale
+            let mut v = VariableNode::new();
+            v.set_normalize_prior(false);
+            v.set_prior(&dist)?;
+            g.add_node(format!("v{}", i), Box::new(v))?;
+        }
+        let mut weights = HashMap::new();
+        for i in 0..3 {
+            let t = g.add_node(format!("t{}", i), Box::new(TwoNode::new(near)))?;
+            g.add_edge(i, t)?;
+            g.add_edge(t, (i + 1) % 3)?;
+            weights.insert(t, 2.0 / 3.0);
+        }
+        assert!(g
+            .set_edge_weights(std::iter::once((0, 0.5)).collect())
+            .is_err());
+        g.set_edge_weights(weights)?;
+        let mut trw = TreeReweighted::new(&g, 100)?;
+        assert!(trw.propagate(200, 1e-12) < 200);
+        let mut log_z: f64 = 0.0;
+        for a in 0..27 {
+            let x: Vec<i32> = (0..3).map(|i| (a / 3i32.pow(i)) % 3).collect();
+            let p: Probability = x.iter().map(|v| 1.0 + *v as Probability).product();
+            log_z += p
+                * (0..3)
+                    .map(|i| near(x[i], x[(i + 1) % 3]))
+                    .product::<Probability>();
+        }
+        assert!(trw.log_partition_upper_bound() >= log_z.ln());
+        Ok(())
+    }
+
+    #[test]
+    fn test_history() -> BPResult<()> {
+        let mut g = chain_graph()?;
+        g.set_history_recording(2, 10);
+        g.propagate(6)?;
+        let history = g.get_history().unwrap();
+        assert_eq!(history.len(), 10);
+        assert!(history.entries().iter().all(|e| e.step % 2 == 0));
+        assert_eq!(history.entries().back().unwrap().step, 4);
+
+        let mut csv = Vec::new();
+        g.dump_history(&mut csv, HistoryFormat::Csv)?;
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv.lines().count(), 1 + 10 * 4);
+        let mut json = Vec::new();
+        g.dump_history(&mut json, HistoryFormat::Json)?;
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.starts_with("[{\"step\":"));
+        assert_eq!(json.matches("\"msg\"").count(), 10);
+        Ok(())
+    }
+
+    fn chain_graph() -> BPResult<BPGraph<i32, HashMap<i32, Probability>>> {
+        let mut g = BPGraph::new();
+        let mut dist = HashMap::new();
+        for v in 1..5 {
+            dist.insert(v, 0.1 * v as Probability);
+        }
+        for i in 0..4 {
+            let mut v = VariableNode::new();
+            v.set_prior(&dist)?;
+            g.add_node(format!("v{}", i), Box::new(v))?;
+        }
+        for i in 0..3 {
+            let t = g.add_node(format!("t{}", i), Box::new(TwoNode::new(near)))?;
+            g.add_edge(i, t)?;
+            g.add_edge(t, i + 1)?;
+        }
+        g.initialize()?;
+        Ok(g)
+    }
+
+    #[test]
+    fn test_step_report() -> BPResult<()> {
+        let mut g = chain_graph()?;
+        let report = g.propagate_step()?;
+        assert_eq!(
+            (
+                report.step,
+                report.nodes_fired,
+                report.messages_sent,
+                report.skipped_not_ready
+            ),
+            (0, 4, 6, 3)
+        );
+        let report = g.propagate_step_threaded(2)?;
+        assert_eq!(
+            (
+                report.step,
+                report.nodes_fired,
+                report.messages_sent,
+                report.skipped_not_ready
+            ),
+            (1, 3, 6, 4)
+        );
+        assert!(!report.is_stalled());
+        //Only the factors are scheduled, they have already sent
+        let report =
+            g.propagate_step_with_scheduler(&mut LayerScheduler::new(vec![vec![4, 5, 6]]))?;
+        assert!(report.is_stalled());
+        assert_eq!(report.skipped_not_ready, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bayes_net() -> BPResult<()> {
+        let mut bn = BayesNet::new();
+        bn.add_node("rain", 2, &[], vec![0.8, 0.2])?;
+        bn.add_node("sprinkler", 2, &[], vec![0.6, 0.4])?;
+        bn.add_node(
+            "wet",
+            2,
+            &["rain", "sprinkler"],
+            vec![0.9, 0.1, 0.2, 0.8, 0.1, 0.9, 0.01, 0.99],
+        )?;
+        assert!(bn.add_node("wet", 2, &[], vec![0.5, 0.5]).is_err());
+        assert!(bn
+            .add_node("x", 2, &["cloudy"], vec![0.5, 0.5, 0.5, 0.5])
+            .is_err());
+        assert!(bn
+            .add_node("x", 2, &["rain"], vec![0.5, 0.5, 0.5, 0.6])
+            .is_err());
+        assert_eq!(bn.parents("wet"), Some(vec!["rain", "sprinkler"]));
+
+        let mut compiled = bn.compile()?;
+        compiled.propagate(10)?;
+        assert!((compiled.marginal("wet")?[1] - 0.5112).abs() < 1e-9);
+        compiled.observe("wet", 1)?;
+        compiled.propagate(10)?;
+        assert!((compiled.marginal("rain")?[1] - 0.1752 / 0.5112).abs() < 1e-9);
+        //Explaining away
+        compiled.observe("sprinkler", 1)?;
+        compiled.propagate(10)?;
+        let marginals = compiled.marginals()?;
+        assert!((marginals["rain"][1] - 0.198 / 0.918).abs() < 1e-9);
+        compiled.clear_evidence("wet")?;
+        compiled.clear_evidence("sprinkler")?;
+        compiled.propagate(10)?;
+        assert!((compiled.marginal("rain")?[1] - 0.2).abs() < 1e-9);
+        assert!(compiled.observe("rain", 2).is_err());
+        assert!(compiled.marginal("cloudy").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sender_normalization() -> BPResult<()> {
+        let inbox_sum =
+            |g: &BPGraph<i32, HashMap<i32, Probability>>, node: NodeIndex, from: NodeIndex| {
+                let inbox = g.get_node(node).unwrap().clone_inbox();
+                inbox
+                    .iter()
+                    .find(|(idx, _)| *idx == from)
+                    .unwrap()
+                    .1
+                    .values()
+                    .sum::<Probability>()
+            };
+        let mut normalized = chain_graph()?;
+        let mut raw = chain_graph()?;
+        raw.set_sender_normalization(NodeFilter::Factors, Some(NormalizationMode::None));
+        assert_eq!(raw.get_edge_normalization(4, 0)?, NormalizationMode::None);
+        assert_eq!(
+            raw.get_edge_normalization(0, 4)?,
+            NormalizationMode::SumToOne
+        );
+        raw.set_edge_normalization(5, 1, NormalizationMode::SumToOne)?;
+        assert!(raw
+            .set_edge_normalization(4, 2, NormalizationMode::SumToOne)
+            .is_err());
+        normalized.propagate(2)?;
+        raw.propagate_threaded(2, 2)?;
+        assert!((inbox_sum(&normalized, 0, 4) - 1.0).abs() < 1e-12);
+        assert!((inbox_sum(&raw, 0, 4) - 1.0).abs() > 1e-3);
+        assert!((inbox_sum(&raw, 1, 4) - 1.0).abs() > 1e-3);
+        assert!((inbox_sum(&raw, 1, 5) - 1.0).abs() < 1e-12);
+        normalized.propagate(4)?;
+        raw.propagate(4)?;
+        //Scaling the messages does not change the results
+        for i in 0..4 {
+            let (a, b) = (
+                normalized.get_result(i)?.unwrap(),
+                raw.get_result(i)?.unwrap(),
+            );
+            for (v, p) in &a {
+                assert!((p - b[v]).abs() < 1e-9);
+            }
+        }
+        raw.remove_edge_normalization(5, 1);
+        raw.set_sender_normalization(NodeFilter::All, None);
+        assert_eq!(
+            raw.get_edge_normalization(4, 0)?,
+            NormalizationMode::SumToOne
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_deterministic() -> BPResult<()> {
+        let mut g0 = chain_graph()?;
+        let mut g1 = chain_graph()?;
+        g0.propagate(5)?;
+        g1.set_deterministic(true);
+        g1.propagate_threaded(5, 3)?;
+        for i in 0..4 {
+            assert_eq!(g0.get_result(i)?, g1.get_result(i)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_thread_pool() -> BPResult<()> {
+        let mut g0 = chain_graph()?;
+        let mut g1 = chain_graph()?;
+        g0.set_deterministic(true);
+        g0.propagate_threaded(5, 3)?;
+        g1.set_deterministic(true);
+        g1.set_thread_pool(Some(2));
+        g1.propagate_threaded(5, 3)?;
+        assert_eq!(g1.get_thread_pool().unwrap().thread_count(), 2);
+        for i in 0..4 {
+            assert_eq!(g0.get_result(i)?, g1.get_result(i)?);
+        }
+        g1.set_thread_pool(None);
+        assert!(g1.get_thread_pool().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_variable_builder() -> BPResult<()> {
+        let mut g0 = chain_graph()?;
+        let mut g1 = BPGraph::<i32, HashMap<i32, Probability>>::new();
+        let dist: HashMap<i32, Probability> = (1..5).map(|v| (v, 0.1 * v as Probability)).collect();
+        for i in 0..4 {
+            g1.add_variable(format!("v{}", i), dist.clone())?;
+        }
+        for i in 0..3 {
+            let t = g1.add_factor(format!("t{}", i), TwoNode::new(near))?;
+            g1.add_edge(i, t)?;
+            g1.add_edge(t, i + 1)?;
+        }
+        g1.initialize()?;
+        g0.propagate(5)?;
+        g1.propagate(5)?;
+        for i in 0..4 {
+            assert_eq!(g0.get_result(i)?, g1.get_result(i)?);
+        }
+        let v: VariableNode<i32, HashMap<i32, Probability>> = VariableNode::builder()
+            .prior(dist)
+            .input_need(InputNeed::Always)
+            .send_to_all(true)
+            .build();
+        assert!(NodeFunction::<i32, _, (), ()>::get_prior(&v).is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalization_mode() -> BPResult<()> {
+        let mut msg: HashMap<i32, Probability> =
+            (0..4).map(|v| (v, 0.5 * v as Probability)).collect();
+        msg.normalize()?;
+        assert!((msg.values().sum::<Probability>() - 1.0).abs() < 1e-12);
+        msg.normalize_with(NormalizationMode::MaxToOne)?;
+        assert!((msg[&3] - 1.0).abs() < 1e-12 && (msg[&1] - 1.0 / 3.0).abs() < 1e-12);
+        let mut log_msg = DenseMsg::from_vec(vec![-3.0, -1.0, -2.0]);
+        log_msg.normalize_with(NormalizationMode::LogShift)?;
+        assert_eq!(log_msg.as_slice(), &[-2.0, 0.0, -1.0]);
+        assert!(HashMap::<i32, Probability>::new()
+            .normalize_with(NormalizationMode::SumToOne)
+            .is_err());
+
+        let mut g0 = chain_graph()?;
+        let mut g1 = chain_graph()?;
+        g1.set_normalization(NormalizationMode::MaxToOne);
+        g0.propagate(5)?;
+        g1.propagate(5)?;
+        for i in 0..4 {
+            let r0 = g0.get_result(i)?.unwrap();
+            let r1 = g1.get_result(i)?.unwrap();
+            assert!((r0.values().sum::<Probability>() - 1.0).abs() < 1e-12);
+            assert!((r1.values().cloned().fold(0.0, Probability::max) - 1.0).abs() < 1e-12);
+            let sum1: Probability = r1.values().sum();
+            for (v, p) in r0 {
+                assert!((p - r1[&v] / sum1).abs() < 1e-12);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_nodes_edges() -> BPResult<()> {
+        let g = chain_graph()?;
+        let nodes: Vec<_> = g.nodes().collect();
+        assert_eq!(nodes.len(), 7);
+        assert_eq!(nodes[1], (1, "v1", false));
+        assert_eq!(nodes[5], (5, "t1", true));
+        let edges: Vec<_> = g.edges().collect();
+        assert_eq!(edges, vec![(0, 4), (1, 4), (1, 5), (2, 5), (2, 6), (3, 6)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_template() -> BPResult<()> {
+        let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+        let key = g.add_variable("key".to_owned(), (1..5).map(|v| (v, 0.25)).collect())?;
+        let mut template = Template::new();
+        let trace = template.add_node("trace".to_owned(), |i| {
+            let prior = (1..5)
+                .map(|v| (v, if v == 2 + i as i32 % 2 { 0.7 } else { 0.1 }))
+                .collect();
+            Box::new(VariableNode::builder().prior(prior).build())
+        });
+        let leakage = template.add_node("leakage".to_owned(), |_| {
+            let table = (1..5)
+                .flat_map(|x| (1..5).map(move |y| ((x, y), near(x, y))))
+                .collect();
+            Box::new(PairwiseFactor::new(table))
+        });
+        template.add_edge(trace, leakage)?;
+        assert!(template.add_edge(leakage, trace).is_err());
+        assert!(g.instantiate(&template, 2, &[(trace, key)]).is_err());
+        assert_eq!(g.len(), 1);
+
+        let plate = g.instantiate(&template, 3, &[(leakage, key)])?;
+        assert_eq!(g.len(), 7);
+        assert_eq!(plate.instance(1), 3..5);
+        assert_eq!(plate.get(2, leakage), Some(6));
+        assert_eq!(plate.node(leakage).collect::<Vec<_>>(), vec![2, 4, 6]);
+        assert_eq!(g.nodes().nth(5).unwrap().1, "trace_2");
+        assert_eq!(g.edges().filter(|(n0, _)| *n0 == key).count(), 3);
+        g.initialize()?;
+        g.propagate(3)?;
+        assert!(g.get_result(key)?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_result_options() -> BPResult<()> {
+        let mut g = chain_graph()?;
+        g.propagate(6)?;
+        let full = g.get_result(1)?.unwrap();
+        let default = g
+            .get_result_with_options(1, &ResultOptions::default())?
+            .unwrap();
+        assert_eq!(full, default);
+        let cavity = g
+            .get_result_with_options(
+                1,
+                &ResultOptions {
+                    include_prior: true,
+                    exclude_neighbors: &[5],
+                },
+            )?
+            .unwrap();
+        let no_prior = g
+            .get_result_with_options(
+                1,
+                &ResultOptions {
+                    include_prior: false,
+                    exclude_neighbors: &[],
+                },
+            )?
+            .unwrap();
+        //full = cavity * msg from 5 = prior * msgs, so full / cavity is proportional to the message from 5
+        //and full / no_prior is proportional to the prior (0.1 * v)
+        let ratio = |v: i32| full[&v] / no_prior[&v];
+        for v in 2..5 {
+            assert!((ratio(v) / ratio(1) - v as Probability).abs() < 1e-9);
+        }
+        assert_ne!(cavity, full);
+        assert!(g
+            .get_result_with_options(
+                1,
+                &ResultOptions {
+                    include_prior: false,
+                    exclude_neighbors: &[4, 5]
+                }
+            )?
+            .is_none());
+        assert!(g
+            .get_result_with_options(
+                1,
+                &ResultOptions {
+                    include_prior: true,
+                    exclude_neighbors: &[6]
+                }
+            )
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_decimation() -> BPResult<()> {
+        type M = HashMap<i32, Probability>;
+        let not_equal: HashMap<(i32, i32), Probability> =
+            [((0, 1), 1.0), ((1, 0), 1.0)].iter().copied().collect();
+        let build = |edges: &[(usize, usize)]| -> BPResult<
+            BPGraph<i32, M, VariableNodeCtrl<M>, VariableNodeCtrlAnswer<M>>,
+        > {
+            let mut g = BPGraph::new();
+            for i in 0..3 {
+                let p0 = if i == 0 { 0.4 } else { 0.5 };
+                g.add_variable(
+                    format!("v{}", i),
+                    [(0, p0), (1, 1.0 - p0)].iter().copied().collect(),
+                )?;
+            }
+            for (v0, v1) in edges {
+                g.add_pairwise_potential(*v0, *v1, not_equal.clone())?;
+            }
+            g.initialize()?;
+            Ok(g)
+        };
+        let mut g = build(&[(0, 1), (1, 2)])?;
+        let expected: HashMap<NodeIndex, i32> = [(0, 1), (1, 0), (2, 1)].iter().copied().collect();
+        assert_eq!(
+            g.solve_by_decimation(&DecimationConfig::default())?,
+            DecimationOutcome::Solved(expected)
+        );
+        //An odd cycle of inequalities cannot be 2-colored
+        let mut g = build(&[(0, 1), (1, 2), (2, 0)])?;
+        assert!(matches!(
+            g.solve_by_decimation(&DecimationConfig::default())?,
+            DecimationOutcome::Contradiction { .. }
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_decimation_error() -> BPResult<()> {
+        type M = HashMap<i32, Probability>;
+        //Swaps the messages of its two neighbors and fails once a value has probability zero
+        struct RejectsEvidence;
+        impl NodeFunction<i32, M, VariableNodeCtrl<M>, VariableNodeCtrlAnswer<M>> for RejectsEvidence {
+            fn node_function(
+                &mut self,
+                inbox: Vec<(NodeIndex, M)>,
+            ) -> BPResult<Vec<(NodeIndex, M)>> {
+                if inbox.iter().any(|(_, msg)| msg.values().any(|p| *p == 0.0)) {
+                    return Err(BPError::new(
+                        "RejectsEvidence::node_function".to_owned(),
+                        "Evidence is not supported".to_owned(),
+                    ));
+                }
+                Ok(vec![
+                    (inbox[0].0, inbox[1].1.clone()),
+                    (inbox[1].0, inbox[0].1.clone()),
+                ])
+            }
+            fn is_factor(&self) -> bool {
+                true
+            }
+            fn number_inputs(&self) -> Option<usize> {
+                Some(2)
+            }
+            fn initialize(&mut self, _connections: Vec<NodeIndex>) -> BPResult<()> {
+                Ok(())
+            }
+            fn is_ready(
+                &self,
+                recv_from: &Vec<(NodeIndex, M)>,
+                _current_step: usize,
+            ) -> BPResult<bool> {
+                Ok(recv_from.len() == 2)
+            }
+            fn reset(&mut self) -> BPResult<()> {
+                Ok(())
+            }
+            fn get_prior(&self) -> Option<M> {
+                None
+            }
+        }
+        let mut g: BPGraph<i32, M, VariableNodeCtrl<M>, VariableNodeCtrlAnswer<M>> = BPGraph::new();
+        g.add_variable(
+            "v0".to_owned(),
+            [(0, 0.3), (1, 0.7)].iter().copied().collect(),
+        )?;
+        g.add_variable(
+            "v1".to_owned(),
+            [(0, 0.5), (1, 0.5)].iter().copied().collect(),
+        )?;
+        let f = g.add_factor("f".to_owned(), RejectsEvidence)?;
+        g.add_edge(0, f)?;
+        g.add_edge(1, f)?;
+        g.initialize()?;
+        //Failures that are not contradictions are not reported as one after the first variable is fixed
+        assert!(g.solve_by_decimation(&DecimationConfig::default()).is_err());
+        assert_eq!(g.get_zero_message_policy(), ZeroMessagePolicy::Error);
+        Ok(())
+    }
+
+    #[test]
+    fn test_survey_propagation() -> BPResult<()> {
+        //x1, x1 -> x2, x2 -> x3, !x3 v !x4, x2 v x4 v x5
+        let clauses = vec![
+            vec![1],
+            vec![-1, 2],
+            vec![-2, 3],
+            vec![-3, -4],
+            vec![2, 4, 5],
+        ];
+        let mut sp = SurveyPropagation::<HashMap<_, _>>::new(5, clauses.clone(), 7)?;
+        match sp.sp_decimation(&SpConfig::default())? {
+            SpOutcome::Solved(assignment) => {
+                for clause in &clauses {
+                    assert!(clause
+                        .iter()
+                        .any(|lit| assignment[lit.unsigned_abs() as usize - 1] == (*lit > 0)));
+                }
+                assert_eq!(&assignment[..4], &[true, true, true, false]);
+            }
+            outcome => panic!("Unexpected outcome {:?}", outcome),
+        }
+        let mut sp =
+            SurveyPropagation::<HashMap<_, _>>::new(2, vec![vec![1], vec![-1, 2], vec![-2]], 7)?;
+        assert!(matches!(
+            sp.sp_decimation(&SpConfig::default())?,
+            SpOutcome::Contradiction(_)
+        ));
+        assert!(SurveyPropagation::<HashMap<_, _>>::new(2, vec![vec![1, 3]], 7).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_edges_parallel() -> BPResult<()> {
+        let build = || -> BPResult<BPGraph<i32, HashMap<i32, Probability>>> {
+            let mut g = BPGraph::new();
+            let dist: HashMap<i32, Probability> =
+                (1..5).map(|v| (v, 0.1 * v as Probability)).collect();
+            for i in 0..4 {
+                g.add_variable(format!("v{}", i), dist.clone())?;
+            }
+            for i in 0..3 {
+                g.add_factor(format!("t{}", i), TwoNode::new(near))?;
+            }
+            Ok(g)
+        };
+        let mut g = build()?;
+        g.add_edge(0, 4)?;
+        //Duplicates are skipped
+        g.add_edges_unchecked_parallel(&[(0, 4), (4, 1), (1, 5), (5, 2), (2, 6), (6, 3), (5, 1)])?;
+        assert_eq!(
+            g.edges().collect::<Vec<_>>(),
+            chain_graph()?.edges().collect::<Vec<_>>()
+        );
+        g.initialize()?;
+        let mut g0 = chain_graph()?;
+        g.propagate(6)?;
+        g0.propagate(6)?;
+        for i in 0..4 {
+            let (r, r0) = (g.get_result(i)?.unwrap(), g0.get_result(i)?.unwrap());
+            for (v, p) in r0 {
+                assert!((p - r[&v]).abs() < 1e-12);
+            }
+        }
+
+        let mut g = build()?;
+        assert!(g.add_edges_unchecked_parallel(&[(0, 4), (0, 1)]).is_err());
+        assert!(g
+            .add_edges_unchecked_parallel(&[(0, 4), (1, 4), (2, 4)])
+            .is_err());
+        assert!(g.add_edges_unchecked_parallel(&[(0, 7)]).is_err());
+        assert_eq!(g.edges().count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_edges_parallel_shards() -> BPResult<()> {
+        //Every variable is connected to the factors i + 1 and i + 3 (mod 7), with each edge given twice
+        let edges: Vec<(NodeIndex, NodeIndex)> = (0..13)
+            .flat_map(|i| {
+                [
+                    (i, 13 + (i + 1) % 7),
+                    (13 + (i + 3) % 7, i),
+                    (i, 13 + (i + 1) % 7),
+                ]
+            })
+            .collect();
+        for threads in [1, 2, 3, 5, 20, 40] {
+            let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+            for i in 0..13 {
+                g.add_variable(
+                    format!("v{}", i),
+                    vec![(0, 0.5), (1, 0.5)].into_iter().collect(),
+                )?;
+            }
+            for i in 0..7 {
+                g.add_factor(format!("f{}", i), EqualityFactor::new())?;
+            }
+            g.set_thread_pool(Some(threads));
+            g.add_edges_unchecked_parallel(&edges)?;
+            for i in 0..13 {
+                assert_eq!(
+                    g.get_connections(i)?,
+                    &vec![13 + (i + 1) % 7, 13 + (i + 3) % 7]
+                );
+            }
+            assert_eq!(g.edges().count(), 26);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_high_degree_node() -> BPResult<()> {
+        let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+        let dist: HashMap<i32, Probability> = (1..5).map(|v| (v, 0.1 * v as Probability)).collect();
+        let hub = g.add_variable("hub".to_owned(), dist.clone())?;
+        for i in 0..100 {
+            let t = g.add_factor(format!("t{}", i), TwoNode::new(near))?;
+            let v = g.add_variable(format!("v{}", i), dist.clone())?;
+            g.add_edge(hub, t)?;
+            g.add_edge(t, v)?;
+        }
+        assert!(g.add_edge(hub, 99).is_err());
+        assert!(g.get_node(hub)?.is_connected(199) && !g.get_node(hub)?.is_connected(200));
+        g.initialize()?;
+        g.propagate(4)?;
+        assert!(g.get_result(hub)?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_strict() -> BPResult<()> {
+        let mut g = chain_graph()?;
+        g.set_strict(true);
+        g.propagate(4)?;
+        g.propagate_threaded(4, 2)?;
+
+        let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+        let dist: HashMap<i32, Probability> = (1..5).map(|v| (v, 0.1 * v as Probability)).collect();
+        g.add_variable("v0".to_owned(), dist.clone())?;
+        g.add_node("v1".to_owned(), Box::new(VariableNode::new()))?;
+        g.add_factor("t".to_owned(), TwoNode::new(near))?;
+        g.add_edge(0, 2)?;
+        g.add_edge(2, 1)?;
+        g.set_strict(true);
+        //Duplicates are only kept with InboxPolicy::Accumulate
+        g.set_inbox_policy(2, InboxPolicy::Accumulate)?;
+        g.initialize_node(0, Some(vec![(2, dist)]))?;
+        g.initialize()?;
+        let err = g.propagate(2).unwrap_err();
+        let msg = format!("{:?}", err);
+        assert!(msg.contains("missing senders: [1], duplicate senders: [0]"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_reset_schedule_state() -> BPResult<()> {
+        let mut g = chain_graph()?;
+        g.propagate(4)?;
+        assert_eq!(g.get_step(), 4);
+        let res = g.get_result(3)?.unwrap();
+        g.reset_schedule_state()?;
+        assert_eq!(g.get_step(), 0);
+        assert!(g.is_initialized());
+        g.propagate(4)?;
+        let res_again = g.get_result(3)?.unwrap();
+        for (v, p) in res {
+            assert!((res_again[&v] - p).abs() < 1e-12);
+        }
+        g.set_step(10);
+        g.propagate(1)?;
+        assert_eq!(g.get_step(), 11);
+        assert_eq!(g.current_step(), 11);
+        Ok(())
+    }
+
+    #[test]
+    fn test_result_cache() -> BPResult<()> {
+        //HashMaps are summed in arbitrary order when normalizing
+        fn close(a: &HashMap<i32, Probability>, b: &HashMap<i32, Probability>) -> bool {
+            a.len() == b.len() && a.iter().all(|(v, p)| (b[v] - p).abs() < 1e-12)
+        }
+        let mut g = chain_graph()?;
+        g.propagate(2)?;
+        for _ in 0..2 {
+            assert!(close(
+                g.get_result_cached(1)?.unwrap(),
+                &g.get_result(1)?.unwrap()
+            ));
+        }
+        //The normalized result is cached, not recomputed
+        assert!(std::ptr::eq(
+            g.get_result_cached(1)?.unwrap(),
+            g.get_result_cached(1)?.unwrap()
+        ));
+        let before = g.get_result_cached(1)?.unwrap().clone();
+        g.propagate(2)?;
+        let after = g.get_result_cached(1)?.unwrap().clone();
+        assert!(close(&after, &g.get_result(1)?.unwrap()));
+        assert!(!close(&before, &after));
+
+        g.set_normalization(NormalizationMode::MaxToOne);
+        assert!(close(
+            g.get_result_cached(1)?.unwrap(),
+            &g.get_result(1)?.unwrap()
+        ));
+        let node = g.get_node(1)?;
+        assert!(node.get_result_cached(NormalizationMode::SumToOne).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_prior_combination() -> BPResult<()> {
+        let p0: HashMap<i32, Probability> = vec![(0, 0.2), (1, 0.8)].into_iter().collect();
+        let p1: HashMap<i32, Probability> = vec![(0, 0.6), (1, 0.4)].into_iter().collect();
+        let mut v = VariableNode::new();
+        v.set_prior(&p0)?;
+        v.add_prior(&p1, 1.0)?;
+        assert!(v.add_prior(&p1, 0.0).is_err());
+        let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+        g.add_node("v".to_owned(), Box::new(v.clone()))?;
+        v.set_prior_combination(PriorCombination::Mixture)?;
+        g.add_node("v_mixture".to_owned(), Box::new(v))?;
+        g.initialize()?;
+        let product = g.get_result(0)?.unwrap();
+        assert!((product[&0] - 0.12 / 0.44).abs() < 1e-12);
+        let mixture = g.get_result(1)?.unwrap();
+        assert!((mixture[&0] - 0.4).abs() < 1e-12);
+        Ok(())
+    }
+
+    #[test]
+    fn test_prior_mixture_support() -> BPResult<()> {
+        let p0: HashMap<i32, Probability> = vec![(0, 1.0)].into_iter().collect();
+        let p1: HashMap<i32, Probability> = vec![(1, 1.0)].into_iter().collect();
+        let mut v: VariableNode<i32, HashMap<i32, Probability>> = VariableNode::new();
+        v.set_prior_combination(PriorCombination::Mixture)?;
+        v.set_prior(&p0)?;
+        v.add_prior(&p1, 1.0)?;
+        let mixture = NodeFunction::<i32, _, (), ()>::get_prior(&v).unwrap();
+        assert_eq!(mixture.len(), 2);
+        assert!((mixture[&0] - 0.5).abs() < 1e-12 && (mixture[&1] - 0.5).abs() < 1e-12);
+
+        let mut v: VariableNode<usize, DenseMsg> = VariableNode::new();
+        v.set_prior_combination(PriorCombination::Mixture)?;
+        v.set_prior(&DenseMsg::from_vec(vec![1.0]))?;
+        v.add_prior(&DenseMsg::from_vec(vec![0.0, 0.0, 1.0]), 3.0)?;
+        let mixture = NodeFunction::<usize, _, (), ()>::get_prior(&v).unwrap();
+        assert_eq!(mixture.as_slice(), &[0.25, 0.0, 0.75]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_times_msg() {
+        //ParticleMsg uses the default implementation: the weights of particles at the same positions are combined
+        let mut msg = ParticleMsg::from_particles(vec![(0.0, 1.0), (1.0, 2.0)]);
+        msg.times_msg(
+            &ParticleMsg::from_particles(vec![(1.0, 3.0), (2.0, 4.0)]),
+            &MinSum,
+        );
+        assert_eq!(msg.particles(), &[(0.0, 1.0), (1.0, 5.0)]);
+        msg.times_msg(&ParticleMsg::from_particles(vec![(0.0, 0.5)]), &MaxProduct);
+        assert_eq!(msg.particles(), &[(0.0, 0.5), (1.0, 5.0)]);
+    }
+
+    #[test]
+    fn test_default_weighted_msg_ops() -> BPResult<()> {
+        //ParticleMsg uses the default implementations of mult_msg_weighted and add_msg_weighted
+        let mut msg = ParticleMsg::from_particles(vec![(0.0, 1.0), (1.0, 1.0), (2.0, 2.0)]);
+        msg.mult_msg_weighted(
+            &ParticleMsg::from_particles(vec![(0.0, 4.0), (1.0, 16.0)]),
+            0.5,
+        );
+        assert_eq!(msg.particles(), &[(0.0, 0.25), (1.0, 0.5), (2.0, 0.25)]);
+        msg.add_msg_weighted(
+            &ParticleMsg::from_particles(vec![(1.0, 1.0), (3.0, 1.0)]),
+            2.0,
+            0.5,
+        );
+        assert_eq!(msg.particles(), &[(0.0, 0.5), (1.0, 1.5), (2.0, 0.5)]);
+
+        let mut v = VariableNode::<f64, ParticleMsg>::new();
+        v.set_prior(&ParticleMsg::from_particles(vec![(0.0, 1.0), (1.0, 3.0)]))?;
+        assert!(v
+            .add_prior(
+                &ParticleMsg::from_particles(vec![(0.0, 1.0), (1.0, Probability::NAN)]),
+                1.0
+            )
+            .is_err());
+        assert!(v
+            .add_prior(
+                &ParticleMsg::from_particles(vec![(0.0, -1.0), (1.0, 1.0)]),
+                1.0
+            )
+            .is_err());
+        assert_eq!(v.get_priors().len(), 1);
+        v.add_prior(
+            &ParticleMsg::from_particles(vec![(0.0, 2.0), (1.0, 2.0)]),
+            1.0,
+        )?;
+        assert_eq!(v.get_priors()[1].0.particles(), &[(0.0, 0.5), (1.0, 0.5)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_stats() -> BPResult<()> {
+        let mut g = chain_graph()?;
+        let stats = g.stats();
+        assert_eq!((stats.variables, stats.factors, stats.edges), (4, 3, 6));
+        assert_eq!(
+            stats.variable_degrees.into_iter().collect::<Vec<_>>(),
+            vec![(1, 2), (2, 2)]
+        );
+        assert_eq!(
+            stats.factor_degrees.into_iter().collect::<Vec<_>>(),
+            vec![(2, 3)]
+        );
+        assert_eq!(stats.connected_components, 1);
+        assert_eq!(stats.shortest_cycle, None);
+        assert_eq!(stats.average_domain_size, Some(4.0));
+
+        g.unseal();
+        let t = g.add_node("t3".to_owned(), Box::new(TwoNode::new(near)))?;
+        g.add_edge(0, t)?;
+        g.add_edge(t, 3)?;
+        g.add_node("v4".to_owned(), Box::new(VariableNode::new()))?;
+        let stats = g.stats();
+        assert_eq!(stats.connected_components, 2);
+        assert!(!stats.is_forest());
+        assert_eq!(stats.shortest_cycle, Some(8));
+        assert!(stats.shortest_cycle_exact);
+        Ok(())
+    }
+
+    #[test]
+    fn test_semiring() -> BPResult<()> {
+        fn two_variables(
+            prior0: [Probability; 2],
+            prior1: [Probability; 2],
+            table: &[((i32, i32), Probability)],
+            semiring: Arc<dyn Semiring>,
+        ) -> BPResult<HashMap<i32, Probability>> {
+            let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+            g.set_semiring(semiring);
+            let v0 = g.add_variable(
+                "v0".to_owned(),
+                vec![(0, prior0[0]), (1, prior0[1])].into_iter().collect(),
+            )?;
+            let v1 = g.add_variable(
+                "v1".to_owned(),
+                vec![(0, prior1[0]), (1, prior1[1])].into_iter().collect(),
+            )?;
+            g.add_pairwise_potential(v0, v1, table.iter().copied().collect())?;
+            g.initialize()?;
+            g.propagate(2)?;
+            Ok(g.get_result(v1)?.unwrap())
+        }
+        let table = [((0, 0), 0.1), ((1, 1), 1.0), ((0, 1), 0.0), ((1, 0), 0.0)];
+        let max_product = two_variables([0.6, 0.4], [0.5, 0.5], &table, Arc::new(MaxProduct))?;
+        assert!((max_product[&0] - 0.15).abs() < 1e-12);
+        assert!((max_product[&1] - 1.0).abs() < 1e-12);
+
+        let costs: Vec<((i32, i32), Probability)> =
+            table.iter().map(|(x, p)| (*x, -p.ln())).collect();
+        let min_sum = two_variables(
+            [-0.6f64.ln(), -0.4f64.ln()],
+            [-0.5f64.ln(); 2],
+            &costs,
+            Arc::new(MinSum),
+        )?;
+        assert!((min_sum[&0] + 0.15f64.ln()).abs() < 1e-12);
+        assert_eq!(min_sum[&1], 0.0);
+
+        let reachable = two_variables(
+            [1.0, 0.0],
+            [1.0, 1.0],
+            &[((0, 1), 1.0), ((1, 0), 1.0)],
+            Arc::new(BooleanOrAnd),
+        )?;
+        assert_eq!((reachable[&0], reachable[&1]), (0.0, 1.0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_sum() -> BPResult<()> {
+        let table = [((0, 0), 0.1), ((1, 1), 1.0), ((0, 1), 0.0), ((1, 0), 0.0)];
+        let log: HashMap<(i32, i32), Probability> =
+            table.iter().map(|(x, p)| (*x, MaxSum.lift(*p))).collect();
+        let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+        g.set_semiring(Arc::new(MaxSum));
+        let v0 = g.add_variable(
+            "v0".to_owned(),
+            vec![(0, 0.6f64.ln()), (1, 0.4f64.ln())]
+                .into_iter()
+                .collect(),
+        )?;
+        let v1 = g.add_variable(
+            "v1".to_owned(),
+            vec![(0, 0.5f64.ln()), (1, 0.5f64.ln())]
+                .into_iter()
+                .collect(),
+        )?;
+        g.add_pairwise_potential(v0, v1, log)?;
+        g.initialize()?;
+        g.propagate(2)?;
+        let max_sum = g.get_result(v1)?.unwrap();
+        assert!((max_sum[&0] - 0.15f64.ln()).abs() < 1e-12);
+        assert_eq!(max_sum[&1], 0.0);
+
+        //Tables given as probabilities are lifted into the domain of the semiring
+        let costs = PairwiseFactor::<i32, HashMap<i32, Probability>>::from_probabilities(
+            table.iter().copied().collect(),
+            &MinSum,
+        );
+        assert_eq!(costs.get_table()[&(1, 1)], 0.0);
+        assert_eq!(costs.get_table()[&(0, 1)], Probability::INFINITY);
+        let log = PairwiseFactor::<i32, HashMap<i32, Probability>>::from_probabilities(
+            table.iter().copied().collect(),
+            &MaxSum,
+        );
+        assert!((log.get_table()[&(0, 0)] - 0.1f64.ln()).abs() < 1e-12);
+        let reachable = PairwiseFactor::<i32, HashMap<i32, Probability>>::from_probabilities(
+            table.iter().copied().collect(),
+            &BooleanOrAnd,
+        );
+        assert_eq!(reachable.get_table()[&(0, 0)], 1.0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_prune() -> BPResult<()> {
+        type M = HashMap<i32, Probability>;
+        let mut g = BPGraph::<i32, M, VariableNodeCtrl<M>, VariableNodeCtrlAnswer<M>>::new();
+        let v0 = g.add_variable(
+            "v0".to_owned(),
+            vec![(0, 0.98), (1, 0.01), (2, 0.01)].into_iter().collect(),
+        )?;
+        let v1 = g.add_variable("v1".to_owned(), (0..3).map(|v| (v, 1.0 / 3.0)).collect())?;
+        let table: HashMap<(i32, i32), Probability> = (0..3)
+            .flat_map(|x0| (0..3).map(move |x1| ((x0, x1), if x0 == x1 { 0.9 } else { 0.05 })))
+            .collect();
+        let f = g.add_factor("f".to_owned(), PairwiseFactor::new(table))?;
+        g.add_edge(v0, f)?;
+        g.add_edge(v1, f)?;
+        g.initialize()?;
+        g.propagate(2)?;
+        //Only the unlikely values of v0 are removed
+        assert_eq!(g.prune(0.05)?, 2);
+        assert_eq!(
+            g.get_node(v0)?
+                .get_prior()
+                .unwrap()
+                .keys()
+                .collect::<Vec<_>>(),
+            vec![&0]
+        );
+        g.propagate(2)?;
+        assert_eq!(g.get_result(v0)?.unwrap().len(), 1);
+        let res = g.get_result(v1)?.unwrap();
+        assert!((res[&0] - 0.9).abs() < 1e-12 && (res[&1] - 0.05).abs() < 1e-12);
+        assert_eq!(g.prune(0.04)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot() -> BPResult<()> {
+        type M = HashMap<i32, Probability>;
+        let mut g = BPGraph::<i32, M, VariableNodeCtrl<M>, VariableNodeCtrlAnswer<M>>::new();
+        let v0 = g.add_variable(
+            "v0".to_owned(),
+            vec![(0, 0.6), (1, 0.4)].into_iter().collect(),
+        )?;
+        let v1 = g.add_variable(
+            "v1".to_owned(),
+            vec![(0, 0.5), (1, 0.5)].into_iter().collect(),
+        )?;
+        let table = vec![((0, 0), 0.9), ((0, 1), 0.1), ((1, 0), 0.2), ((1, 1), 0.8)]
+            .into_iter()
+            .collect();
+        let f = g.add_factor("f".to_owned(), PairwiseFactor::new(table))?;
+        g.add_edge(v0, f)?;
+        g.add_edge(v1, f)?;
+        g.initialize()?;
+        g.propagate(2)?;
+        let before = g.get_result(v1)?.unwrap();
+        let state = g.snapshot();
+        assert_eq!(state.step(), 2);
+
+        //Clamp v0 to 1 and roll back
+        g.send_control_message(
+            v0,
+            VariableNodeCtrl::SetPrior(Some(vec![(0, 0.0), (1, 1.0)].into_iter().collect())),
+        )?;
+        g.propagate(2)?;
+        assert!((g.get_result(v1)?.unwrap()[&1] - 0.8).abs() < 1e-12);
+        g.restore(state)?;
+        assert_eq!(g.get_step(), 2);
+        assert_eq!(g.get_node(v0)?.get_prior().unwrap()[&0], 0.6);
+        let restored = g.get_result(v1)?.unwrap();
+        assert!((restored[&0] - before[&0]).abs() < 1e-12);
+        g.propagate(2)?;
+        assert!((g.get_result(v1)?.unwrap()[&0] - before[&0]).abs() < 1e-12);
+
+        let state = g.snapshot();
+        g.unseal();
+        g.add_variable("v2".to_owned(), vec![(0, 1.0)].into_iter().collect())?;
+        assert!(g.restore(state).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_fixed_arity_factor() -> BPResult<()> {
+        let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+        let priors = [[0.3, 0.7], [0.6, 0.4], [0.5, 0.5]];
+        for (i, prior) in priors.iter().enumerate() {
+            g.add_variable(
+                format!("v{}", i),
+                vec![(0, prior[0]), (1, prior[1])].into_iter().collect(),
+            )?;
+        }
+        let f = g.add_factor(
+            "f".to_owned(),
+            FixedArityFactor::<3, i32, _>::new(
+                |[x0, x1, x2]| if (x0 + x1) % 2 == x2 { 0.9 } else { 0.1 },
+            ),
+        )?;
+        for v in 0..3 {
+            g.add_edge(v, f)?;
+        }
+        g.initialize()?;
+        g.propagate(2)?;
+        let exact = g.exact_marginals_bruteforce(100)?;
+        for v in 0..3 {
+            let res = g.get_result(v)?.unwrap();
+            for x in 0..2 {
+                assert!((res[&x] - exact[&v][&x]).abs() < 1e-12);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_fixed_arity_factor_repeated_calls() -> BPResult<()> {
+        type M = HashMap<i32, Probability>;
+        let factor =
+            || FixedArityFactor::<2, i32, M>::new(|[x0, x1]| if x0 == x1 { 0.9 } else { 0.1 });
+        let msg = |entries: &[(i32, Probability)]| -> M { entries.iter().copied().collect() };
+        let inboxes = [
+            vec![
+                (3, msg(&[(0, 0.2), (1, 0.3), (2, 0.5)])),
+                (5, msg(&[(0, 0.6), (1, 0.4)])),
+            ],
+            vec![(5, msg(&[(1, 1.0)])), (3, msg(&[(0, 0.5), (2, 0.5)]))],
+        ];
+        //The buffers of the previous call do not leak into the next one
+        let mut reused = factor();
+        NodeFunction::<i32, M>::initialize(&mut reused, vec![3, 5])?;
+        for inbox in inboxes {
+            let mut fresh = factor();
+            NodeFunction::<i32, M>::initialize(&mut fresh, vec![3, 5])?;
+            assert_eq!(
+                NodeFunction::<i32, M>::node_function(&mut reused, inbox.clone())?,
+                NodeFunction::<i32, M>::node_function(&mut fresh, inbox)?
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_fn_factor() -> BPResult<()> {
+        let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+        let priors = [[0.3, 0.7], [0.6, 0.4], [0.5, 0.5], [0.2, 0.8]];
+        for (i, prior) in priors.iter().enumerate() {
+            g.add_variable(
+                format!("v{}", i),
+                vec![(0, prior[0]), (1, prior[1])].into_iter().collect(),
+            )?;
+        }
+        let weights = [0.9, 0.2, 0.4, 0.7];
+        let f = g.add_factor(
+            "f".to_owned(),
+            FnFactor::new(4, move |x: &[i32]| {
+                weights[x.iter().sum::<i32>() as usize % 4] * if x[0] == x[3] { 1.0 } else { 0.5 }
+            }),
+        )?;
+        for v in 0..4 {
+            g.add_edge(v, f)?;
+        }
+        assert!(g.get_node(f)?.potential(&[0, 0, 0]).is_none());
+        g.initialize()?;
+        g.propagate(2)?;
+        let exact = g.exact_marginals_bruteforce(100)?;
+        for v in 0..4 {
+            let res = g.get_result(v)?.unwrap();
+            for x in 0..2 {
+                assert!((res[&x] - exact[&v][&x]).abs() < 1e-12);
+            }
+        }
+
+        //Wrong number of connections
+        let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+        let v0 = g.add_variable(
+            "v0".to_owned(),
+            vec![(0, 0.5), (1, 0.5)].into_iter().collect(),
+        )?;
+        let f = g.add_factor("f".to_owned(), FnFactor::new(2, |_: &[i32]| 1.0))?;
+        g.add_edge(v0, f)?;
+        assert!(g.initialize().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_function_factors() -> BPResult<()> {
+        let sbox = [2, 0, 3, 1];
+        let mut inverse = [0; 4];
+        for (x, y) in sbox.iter().enumerate() {
+            inverse[*y] = x;
+        }
+        let mut g = BPGraph::<usize, HashMap<usize, Probability>>::new();
+        let x = g.add_variable(
+            "x".to_owned(),
+            vec![(0, 0.1), (1, 0.2), (2, 0.3), (3, 0.4)]
+                .into_iter()
+                .collect(),
+        )?;
+        let y = g.add_variable(
+            "y".to_owned(),
+            vec![(0, 0.4), (1, 0.1), (2, 0.1), (3, 0.4)]
+                .into_iter()
+                .collect(),
+        )?;
+        let z = g.add_variable(
+            "z".to_owned(),
+            vec![(0, 0.3), (1, 0.7)].into_iter().collect(),
+        )?;
+        let f = g.add_factor(
+            "f".to_owned(),
+            BijectionFactor::new(move |x| sbox[x], move |y| inverse[y]),
+        )?;
+        g.add_edge(x, f)?;
+        g.add_edge(y, f)?;
+        let h = g.add_factor("h".to_owned(), FunctionFactor::new(|y| y % 2))?;
+        g.add_edge(y, h)?;
+        g.add_edge(z, h)?;
+        g.initialize()?;
+        g.propagate(6)?;
+        let exact = g.exact_marginals_bruteforce(100)?;
+        for (v, size) in [(x, 4), (y, 4), (z, 2)].iter().copied() {
+            let res = g.get_result(v)?.unwrap();
+            for value in 0..size {
+                assert!((res[&value] - exact[&v][&value]).abs() < 1e-12);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_marginals_threaded() -> BPResult<()> {
+        let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+        let mut prev = g.add_variable(
+            "v0".to_owned(),
+            vec![(0, 0.3), (1, 0.7)].into_iter().collect(),
+        )?;
+        for i in 1..50 {
+            let p = (i % 7) as Probability / 10.0 + 0.1;
+            let v = g.add_variable(
+                format!("v{}", i),
+                vec![(0, p), (1, 1.0 - p)].into_iter().collect(),
+            )?;
+            g.add_pairwise_potential(
+                prev,
+                v,
+                vec![((0, 0), 0.8), ((0, 1), 0.2), ((1, 0), 0.3), ((1, 1), 0.7)]
+                    .into_iter()
+                    .collect(),
+            )?;
+            prev = v;
+        }
+        g.initialize()?;
+        g.propagate(100)?;
+        for pool in [None, Some(3)].iter().copied() {
+            g.set_thread_pool(pool);
+            for threads in [1, 4, 1000].iter().copied() {
+                let marginals = g.get_marginals_threaded(threads)?;
+                assert_eq!(marginals.len(), 50);
+                for (v, res) in &marginals {
+                    assert!(!g.is_factor(*v)?);
+                    assert_eq!(*res, g.get_result(*v)?.unwrap());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_inbox_policy() -> BPResult<()> {
+        let send_twice = |policy: Option<InboxPolicy>| -> BPResult<HashMap<i32, Probability>> {
+            let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+            let v = g.add_variable(
+                "v".to_owned(),
+                vec![(0, 0.5), (1, 0.5)].into_iter().collect(),
+            )?;
+            let f = g.add_factor("f".to_owned(), EqualityFactor::new())?;
+            g.add_edge(v, f)?;
+            if let Some(policy) = policy {
+                g.set_inbox_policy(v, policy)?;
+            }
+            g.initialize()?;
+            g.get_node_mut(v)?
+                .send_post(f, vec![(0, 0.8), (1, 0.2)].into_iter().collect());
+            g.get_node_mut(v)?
+                .send_post(f, vec![(0, 0.6), (1, 0.4)].into_iter().collect());
+            Ok(g.get_result(v)?.unwrap())
+        };
+        assert!((send_twice(None)?[&0] - 0.6).abs() < 1e-12);
+        assert!((send_twice(Some(InboxPolicy::KeepLatest))?[&0] - 0.6).abs() < 1e-12);
+        assert!((send_twice(Some(InboxPolicy::KeepFirst))?[&0] - 0.8).abs() < 1e-12);
+        assert!((send_twice(Some(InboxPolicy::Accumulate))?[&0] - 0.48 / 0.56).abs() < 1e-12);
+        Ok(())
+    }
+
+    #[test]
+    fn test_inbox_policy_high_degree() -> BPResult<()> {
+        type M = HashMap<i32, Probability>;
+        let msg = |p: Probability| -> M { vec![(0, p), (1, 1.0 - p)].into_iter().collect() };
+        for policy in [
+            InboxPolicy::KeepLatest,
+            InboxPolicy::KeepFirst,
+            InboxPolicy::Accumulate,
+        ] {
+            //More connections than the threshold of the connection index
+            let mut g = BPGraph::<i32, M>::new();
+            let v = g.add_variable("v".to_owned(), msg(0.5))?;
+            let factors: Vec<NodeIndex> = (0..40)
+                .map(|i| g.add_factor(format!("f{}", i), EqualityFactor::new()))
+                .collect::<BPResult<_>>()?;
+            for f in &factors {
+                g.add_edge(v, *f)?;
+            }
+            g.set_inbox_policy(v, policy)?;
+            g.initialize()?;
+            for round in 0..2 {
+                let node = g.get_node_mut(v)?;
+                for p in [0.8, 0.6] {
+                    for f in &factors {
+                        node.send_post(*f, msg(p));
+                    }
+                }
+                let expected = match policy {
+                    InboxPolicy::KeepLatest => vec![(factors[7], msg(0.6))],
+                    InboxPolicy::KeepFirst => vec![(factors[7], msg(0.8))],
+                    InboxPolicy::Accumulate => vec![(factors[7], msg(0.8)), (factors[7], msg(0.6))],
+                };
+                let post: Vec<(NodeIndex, M)> = node
+                    .get_post()
+                    .iter()
+                    .filter(|(from, _)| *from == factors[7])
+                    .cloned()
+                    .collect();
+                assert_eq!(post, expected);
+                assert_eq!(node.post_len(), 40 * expected.len());
+                //The positions are rebuilt after the inbox has been read
+                if round == 0 {
+                    node.read_post();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_disable_edge() -> BPResult<()> {
+        type G = BPGraph<i32, HashMap<i32, Probability>>;
+        //v0 - f - v1 - leak
+        let build = |with_leak: bool| -> BPResult<(G, usize)> {
+            let mut g = G::new();
+            let v0 = g.add_variable(
+                "v0".to_owned(),
+                vec![(0, 0.3), (1, 0.7)].into_iter().collect(),
+            )?;
+            let v1 = g.add_variable(
+                "v1".to_owned(),
+                vec![(0, 0.5), (1, 0.5)].into_iter().collect(),
+            )?;
+            g.add_pairwise_potential(
+                v0,
+                v1,
+                vec![((0, 0), 0.9), ((0, 1), 0.1), ((1, 0), 0.2), ((1, 1), 0.8)]
+                    .into_iter()
+                    .collect(),
+            )?;
+            let mut leak = 0;
+            if with_leak {
+                leak = g.add_factor(
+                    "leak".to_owned(),
+                    FnFactor::new(1, |x: &[i32]| if x[0] == 0 { 0.9 } else { 0.1 }),
+                )?;
+                g.add_edge(v1, leak)?;
+            }
+            g.initialize()?;
+            Ok((g, leak))
+        };
+        let results = |g: &G| -> BPResult<Vec<Probability>> {
+            Ok(vec![
+                g.get_result(0)?.unwrap()[&0],
+                g.get_result(1)?.unwrap()[&0],
+            ])
+        };
+        let close = |a: &[Probability], b: &[Probability]| {
+            a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-12)
+        };
+
+        let (mut g, leak) = build(true)?;
+        g.disable_edge(leak, 1)?;
+        assert!(g.is_edge_disabled(1, leak)?);
+        g.propagate(10)?;
+        let (mut reference, _) = build(false)?;
+        reference.propagate(10)?;
+        assert!(close(&results(&g)?, &results(&reference)?));
+
+        g.enable_edge(1, leak)?;
+        assert!(!g.is_edge_disabled(1, leak)?);
+        assert!(g.enable_edge(1, leak).is_err());
+        g.propagate(10)?;
+        let (mut full, _) = build(true)?;
+        full.propagate(10)?;
+        assert!(close(&results(&g)?, &results(&full)?));
+
+        //The factor sums over the disabled variable
+        let (mut g, _) = build(true)?;
+        g.disable_edge(0, 2)?;
+        g.propagate(10)?;
+        assert!(close(&results(&g)?, &[0.3, 0.99 / 1.08]));
+        assert!(g.disable_edge(0, 1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_quantized_msg() -> BPResult<()> {
+        let probabilities = vec![0.1, 0.2, 0.0, 0.7, 1e-9];
+        let msg = QuantizedMsg::<u16>::from_probabilities(&probabilities);
+        assert_eq!(msg.size_in_bytes(), 10);
+        for (p, q) in probabilities.iter().zip(msg.probabilities()) {
+            assert!((p - q).abs() <= msg.resolution());
+        }
+        //Positive entries stay positive
+        assert!(msg.get(4).unwrap() > 0.0);
+        assert_eq!(msg.get(2), Some(0.0));
+        let coarse = QuantizedMsg::<u8>::from(&DenseMsg::from_vec(probabilities.clone()));
+        assert!(coarse.resolution() > msg.resolution());
+        assert!((DenseMsg::from(&coarse).as_slice()[3] - 0.7).abs() <= coarse.resolution() / 2.0);
+
+        //get_mut accumulates and entries above the largest one requantize the message
+        let mut msg = msg;
+        *msg.get_mut(1).unwrap() += 0.3;
+        *msg.get_mut(0).unwrap() += 0.9;
+        assert!((msg.get(1).unwrap() - 0.5).abs() < 1e-4);
+        assert!((msg.get(0).unwrap() - 1.0).abs() < 1e-4);
+        msg.normalize()?;
+        assert!((msg.probabilities().iter().sum::<Probability>() - 1.0).abs() < 1e-4);
+        assert!(msg.is_valid());
+
+        //Same results as DenseMsg up to the quantization error
+        let q = 17;
+        let data = |i: usize| -> Vec<Probability> {
+            (0..q)
+                .map(|v| ((v * (i + 3)) % q + 1) as Probability)
+                .collect()
+        };
+        fn run<MsgT: Msg<usize> + Clone + Send + Sync + 'static>(
+            priors: Vec<MsgT>,
+            q: usize,
+        ) -> BPResult<Vec<HashMap<usize, Probability>>> {
+            let mut g = BPGraph::<usize, MsgT>::new();
+            let vars: Vec<NodeIndex> = priors
+                .into_iter()
+                .enumerate()
+                .map(|(i, prior)| g.add_variable(format!("v{}", i), prior))
+                .collect::<BPResult<_>>()?;
+            let table: HashMap<(usize, usize), Probability> = (0..q)
+                .flat_map(|x| {
+                    (0..q).map(move |y| {
+                        (
+                            (x, y),
+                            if (x + 1) % q == y {
+                                0.9
+                            } else {
+                                0.1 / q as Probability
+                            },
+                        )
+                    })
+                })
+                .collect();
+            for w in vars.windows(2) {
+                g.add_pairwise_potential(w[0], w[1], table.clone())?;
+            }
+            g.initialize()?;
+            g.propagate(6)?;
+            vars.iter()
+                .map(|v| g.get_result(*v).map(|r| r.unwrap()))
+                .collect()
+        }
+        let dense = run((0..3).map(|i| DenseMsg::from_vec(data(i))).collect(), q)?;
+        let quantized = run(
+            (0..3)
+                .map(|i| QuantizedMsg::<u16>::from_probabilities(&data(i)))
+                .collect(),
+            q,
+        )?;
+        for (r0, r1) in dense.iter().zip(&quantized) {
+            for v in 0..q {
+                assert!((r0[&v] - r1[&v]).abs() < 1e-4);
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "indexmap")]
+    #[test]
+    fn test_indexmap_msg() -> BPResult<()> {
+        use indexmap::IndexMap;
+        let mut msg: IndexMap<i32, Probability> = Msg::new();
+        for v in [3, 1, 2].iter() {
+            Msg::insert(&mut msg, *v, 0.5);
+        }
+        msg.normalize()?;
+        assert_eq!(msg.keys().copied().collect::<Vec<i32>>(), vec![3, 1, 2]);
+
+        fn graph<MsgT: Msg<i32> + Clone + Send + Sync + 'static>(
+            prior: impl Fn(&[(i32, Probability)]) -> MsgT,
+        ) -> BPResult<BPGraph<i32, MsgT>> {
+            let mut g = BPGraph::<i32, MsgT>::new();
+            let vars: Vec<NodeIndex> = (0..4)
+                .map(|i| {
+                    g.add_variable(
+                        format!("v{}", i),
+                        prior(&[(0, 0.1 * (i + 1) as Probability), (1, 0.5), (2, 0.3)]),
+                    )
+                })
+                .collect::<BPResult<_>>()?;
+            for (i, w) in vars.windows(2).enumerate() {
+                let f = g.add_factor(
+                    format!("f{}", i),
+                    FnFactor::new(2, |x: &[i32]| if x[0] == x[1] { 0.8 } else { 0.1 }),
+                )?;
+                g.add_edge(w[0], f)?;
+                g.add_edge(w[1], f)?;
+            }
+            g.initialize()?;
+            g.propagate(8)?;
+            Ok(g)
+        }
+        let ordered = || -> BPResult<Vec<IndexMap<i32, Probability>>> {
+            let g = graph(|entries| {
+                entries
+                    .iter()
+                    .copied()
+                    .collect::<IndexMap<i32, Probability>>()
+            })?;
+            (0..4)
+                .map(|v| g.get_result_msg(v).map(|r| r.unwrap()))
+                .collect()
+        };
+        let results = ordered()?;
+        assert_eq!(results, ordered()?);
+        let unordered = graph(|entries| {
+            entries
+                .iter()
+                .copied()
+                .collect::<HashMap<i32, Probability>>()
+        })?;
+        for (v, r) in results.iter().enumerate() {
+            let expected = unordered.get_result(v)?.unwrap();
+            for x in 0..3 {
+                assert!((r[&x] - expected[&x]).abs() < 1e-12);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_fg_format() -> BPResult<()> {
+        let fg = "3\n\n2\n3 7\n2 3\n5\n0 0.5\n1 1.5\n2 2\n3 1\n5 0.25\n\n2\n7 10\n3 2\n6\n0 1\n1 2\n2 3\n3 0.5\n4 0.5\n5 1\n\n1\n10\n2\n2\n0 0.3\n1 0.7\n";
+        let commented = format!("# tree with three variables\n{}", fg);
+        let mut g = read_fg::<HashMap<usize, Probability>, _>(commented.as_bytes())?;
+        assert_eq!(g.len(), 6);
+        assert_eq!(g.get_node(0)?.get_name(), "3");
+        let f0 = g.get_node_function::<TableFactor<HashMap<usize, Probability>>>(3)?;
+        assert_eq!(f0.table(), &[0.5, 1.5, 2.0, 1.0, 0.0, 0.25]);
+        assert_eq!(f0.index(&[1, 2]), Some(5));
+        let mut out = Vec::new();
+        write_fg(&g, &mut out)?;
+        assert_eq!(String::from_utf8(out).unwrap(), fg);
+        g.initialize()?;
+        g.propagate(10)?;
+        let exact = g.exact_marginals_bruteforce(100)?;
+        for (v, size) in [(0, 2), (1, 3), (2, 2)].iter().copied() {
+            let res = g.get_result(v)?.unwrap();
+            for value in 0..size {
+                assert!((res[&value] - exact[&v][&value]).abs() < 1e-12);
+            }
+        }
+
+        //Export of other factors, the non-uniform prior becomes a factor
+        let mut g = BPGraph::<usize, HashMap<usize, Probability>>::new();
+        let x = g.add_variable(
+            "x".to_owned(),
+            vec![(0, 0.2), (1, 0.8)].into_iter().collect(),
+        )?;
+        let y = g.add_variable(
+            "y".to_owned(),
+            vec![(0, 0.5), (1, 0.5)].into_iter().collect(),
+        )?;
+        let f = g.add_factor(
+            "f".to_owned(),
+            FnFactor::new(2, |v: &[usize]| if v[0] == v[1] { 0.9 } else { 0.1 }),
+        )?;
+        g.add_edge(x, f)?;
+        g.add_edge(y, f)?;
+        let exact = g.exact_marginals_bruteforce(100)?;
+        let mut out = Vec::new();
+        write_fg(&g, &mut out)?;
+        let mut h = read_fg::<HashMap<usize, Probability>, _>(&out[..])?;
+        assert_eq!(h.len(), 4);
+        h.initialize()?;
+        h.propagate(4)?;
+        for v in [x, y].iter().copied() {
+            let res = h.get_result(v)?.unwrap();
+            for value in 0..2 {
+                assert!((res[&value] - exact[&v][&value]).abs() < 1e-12);
+            }
+        }
+
+        //Malformed files
+        for bad in [
+            "2\n1\n0\n2\n0\n",
+            "1\n1\n0\n2\n1\n2 1\n",
+            "2\n1\n0\n2\n0\n1\n0\n3\n0\n",
+            "1\n2\n0 0\n2 2\n0\n",
+            "1\n1\n0\n2\n0\n5\n",
+        ]
+        .iter()
+        {
+            assert!(read_fg::<HashMap<usize, Probability>, _>(bad.as_bytes()).is_err());
+        }
+        assert!(TableFactor::<HashMap<usize, Probability>>::new(vec![2, 2], vec![1.0; 3]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_metrics_output() -> BPResult<()> {
+        #[derive(Clone, Default)]
+        struct SharedBuffer(Arc<std::sync::Mutex<Vec<u8>>>);
+        impl std::io::Write for SharedBuffer {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+        let x = g.add_variable(
+            "x".to_owned(),
+            vec![(0, 0.3), (1, 0.7)].into_iter().collect(),
+        )?;
+        let y = g.add_variable(
+            "y".to_owned(),
+            vec![(0, 0.5), (1, 0.5)].into_iter().collect(),
+        )?;
+        let f = g.add_factor(
+            "f".to_owned(),
+            FnFactor::new(2, |v: &[i32]| near(v[0], v[1])),
+        )?;
+        g.add_edge(x, f)?;
+        g.add_edge(y, f)?;
+        let buffer = SharedBuffer::default();
+        g.set_metrics_output(buffer.clone());
+        g.initialize()?;
+        g.propagate(3)?;
+        g.propagate_threaded(1, 2)?;
+        g.disable_metrics_output();
+        g.propagate(1)?;
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 4);
+        for (step, line) in lines.iter().enumerate() {
+            assert!(line.starts_with(&format!("{{\"step\":{},\"residual\":", step)));
+            assert!(line.contains("\"wall_time\":") && line.ends_with('}'));
+        }
+        //Only the factor sends in step 1, the variables are updated for the first time
+        assert!(lines[1].contains("\"residual\":null") && lines[1].ends_with("\"messages\":2}"));
+        assert!(
+            !lines[3].contains("\"residual\":null") && !lines[3].contains("\"mean_entropy\":null")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_coarsen() -> BPResult<()> {
+        let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+        let priors = [
+            [0.2, 0.5, 0.3],
+            [0.6, 0.3, 0.1],
+            [0.4, 0.4, 0.2],
+            [0.1, 0.1, 0.8],
+            [0.3, 0.3, 0.4],
+            [0.5, 0.2, 0.3],
+        ];
+        for (i, prior) in priors.iter().enumerate() {
+            g.add_variable(
+                format!("v{}", i),
+                (0..3).map(|x| (x, prior[x as usize])).collect(),
+            )?;
+        }
+        let (a, b, c, d, x, y) = (0, 1, 2, 3, 4, 5);
+        let mut add_factor = |g: &mut BPGraph<i32, HashMap<i32, Probability>>,
+                              name: &str,
+                              vars: &[NodeIndex],
+                              equality: bool|
+         -> BPResult<NodeIndex> {
+            let f = if equality {
+                g.add_factor(name.to_owned(), EqualityFactor::new())?
+            } else {
+                g.add_factor(
+                    name.to_owned(),
+                    FnFactor::new(2, |v: &[i32]| near(v[0], v[1])),
+                )?
+            };
+            for v in vars {
+                g.add_edge(*v, f)?;
+            }
+            Ok(f)
+        };
+        let eq0 = add_factor(&mut g, "eq0", &[a, b], true)?;
+        let eq1 = add_factor(&mut g, "eq1", &[b, c], true)?;
+        let f = add_factor(&mut g, "f", &[a, d], false)?;
+        add_factor(&mut g, "g", &[c, d], false)?;
+        let eq2 = add_factor(&mut g, "eq2", &[a, c], true)?;
+        //x and y share h, merging them would connect h twice
+        add_factor(&mut g, "h", &[x, y], false)?;
+        let eq3 = add_factor(&mut g, "eq3", &[x, y], true)?;
+        let exact = g.exact_marginals_bruteforce(1000)?;
+
+        let mut coarse = g;
+        let mapping = coarse.coarsen()?;
+        assert_eq!(coarse.len(), 8);
+        assert_eq!(mapping[&a], 0);
+        assert_eq!((mapping[&b], mapping[&c]), (0, 0));
+        assert!([eq0, eq1, eq2].iter().all(|e| !mapping.contains_key(e)));
+        assert!(mapping.contains_key(&eq3) && mapping[&x] != mapping[&y]);
+        assert_eq!(coarse.get_connections(mapping[&a])?.len(), 2);
+        assert_eq!(
+            coarse.get_connections(mapping[&f])?,
+            &vec![mapping[&a], mapping[&d]]
+        );
+        let coarse_exact = coarse.exact_marginals_bruteforce(1000)?;
+        for v in [a, b, c, d, x, y].iter() {
+            for value in 0..3 {
+                assert!((exact[v][&value] - coarse_exact[&mapping[v]][&value]).abs() < 1e-12);
+            }
+        }
+        coarse.initialize()?;
+        coarse.propagate(4)?;
+        assert!(coarse.get_result(mapping[&a])?.is_some());
+        assert!(coarse.coarsen().is_err());
+        assert_eq!(coarse.len(), 8);
+        Ok(())
+    }
+
+    #[test]
+    fn test_send_control_message_at() -> BPResult<()> {
+        type M = HashMap<i32, Probability>;
+        let mut g = BPGraph::<i32, M, VariableNodeCtrl<M>, VariableNodeCtrlAnswer<M>>::new();
+        let x = g.add_variable(
+            "x".to_owned(),
+            vec![(0, 0.5), (1, 0.5)].into_iter().collect(),
+        )?;
+        let y = g.add_variable(
+            "y".to_owned(),
+            vec![(0, 0.5), (1, 0.5)].into_iter().collect(),
+        )?;
+        let f = g.add_factor(
+            "f".to_owned(),
+            FnFactor::new(2, |v: &[i32]| near(v[0], v[1])),
+        )?;
+        g.add_edge(x, f)?;
+        g.add_edge(y, f)?;
+        g.initialize()?;
+        let prior: M = vec![(0, 0.9), (1, 0.1)].into_iter().collect();
+        g.send_control_message_at(x, VariableNodeCtrl::SetPrior(Some(prior.clone())), 2)?;
+        g.send_control_message_at(x, VariableNodeCtrl::GetPrior, 2)?;
+        g.send_control_message_at(y, VariableNodeCtrl::HasPropagated, 5)?;
+        assert!(g
+            .send_control_message_at(7, VariableNodeCtrl::GetPrior, 2)
+            .is_err());
+        assert_eq!(g.scheduled_control_messages(), 3);
+        g.propagate(4)?;
+        assert!(g
+            .send_control_message_at(x, VariableNodeCtrl::GetPrior, 3)
+            .is_err());
+        let answers = g.take_control_answers();
+        assert_eq!(answers.len(), 2);
+        assert!(matches!(&answers[0], (2, 0, VariableNodeCtrlAnswer::Done)));
+        assert!(
+            matches!(&answers[1], (2, 0, VariableNodeCtrlAnswer::Prior(Some(p))) if *p == prior)
+        );
+        assert!(g.get_result(y)?.unwrap()[&0] > 0.5);
+        assert_eq!(g.scheduled_control_messages(), 1);
+        g.propagate_threaded(2, 2)?;
+        assert!(matches!(
+            &g.take_control_answers()[..],
+            [(5, 1, VariableNodeCtrlAnswer::HasPropagated(true))]
+        ));
+        assert_eq!(g.scheduled_control_messages(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_send_control_message_at_incremental() -> BPResult<()> {
+        type M = HashMap<i32, Probability>;
+        let mut g = BPGraph::<i32, M, VariableNodeCtrl<M>, VariableNodeCtrlAnswer<M>>::new();
+        let x = g.add_variable(
+            "x".to_owned(),
+            vec![(0, 0.5), (1, 0.5)].into_iter().collect(),
+        )?;
+        let y = g.add_variable(
+            "y".to_owned(),
+            vec![(0, 0.5), (1, 0.5)].into_iter().collect(),
+        )?;
+        let f = g.add_factor(
+            "f".to_owned(),
+            FnFactor::new(2, |v: &[i32]| near(v[0], v[1])),
+        )?;
+        g.add_edge(x, f)?;
+        g.add_edge(y, f)?;
+        g.set_edge_directed(x, f)?;
+        g.set_edge_directed(f, y)?;
+        g.initialize()?;
+        g.set_incremental(true);
+        g.propagate(4)?;
+        let prior: M = vec![(0, 0.9), (1, 0.1)].into_iter().collect();
+        g.send_control_message_at(x, VariableNodeCtrl::SetPrior(Some(prior)), 4)?;
+        g.send_control_message_at(x, VariableNodeCtrl::GetPrior, 5)?;
+        g.send_control_message_at(x, VariableNodeCtrl::HasPropagated, 100)?;
+        //The changed prior is propagated, the messages of later steps stay scheduled
+        let steps = g.propagate_incremental(10, 1e-12)?;
+        assert!((2..10).contains(&steps));
+        let answers = g.take_control_answers();
+        assert_eq!(answers.len(), 2);
+        assert!(matches!(&answers[0], (4, 0, VariableNodeCtrlAnswer::Done)));
+        assert!(matches!(
+            &answers[1],
+            (5, 0, VariableNodeCtrlAnswer::Prior(Some(_)))
+        ));
+        assert!(g.get_result(y)?.unwrap()[&0] > 0.5);
+        assert_eq!(g.scheduled_control_messages(), 1);
+        g.clear_scheduled_control_messages();
+        g.send_control_message_at(x, VariableNodeCtrl::GetPrior, g.get_step())?;
+        g.propagate_forward()?;
+        assert_eq!(g.take_control_answers().len(), 1);
+        assert_eq!(g.scheduled_control_messages(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_control_message_dirty() -> BPResult<()> {
+        type M = HashMap<i32, Probability>;
+        let mut g = BPGraph::<i32, M, VariableNodeCtrl<M>, VariableNodeCtrlAnswer<M>>::new();
+        let x = g.add_variable(
+            "x".to_owned(),
+            vec![(0, 0.5), (1, 0.5)].into_iter().collect(),
+        )?;
+        let y = g.add_variable(
+            "y".to_owned(),
+            vec![(0, 0.5), (1, 0.5)].into_iter().collect(),
+        )?;
+        let f = g.add_factor(
+            "f".to_owned(),
+            FnFactor::new(2, |v: &[i32]| near(v[0], v[1])),
+        )?;
+        g.add_edge(x, f)?;
+        g.add_edge(y, f)?;
+        g.initialize()?;
+        g.set_incremental(true);
+        g.propagate(4)?;
+        //Queries do not change the nodes
+        g.send_control_message(x, VariableNodeCtrl::GetPrior)?;
+        g.broadcast_control_message(VariableNodeCtrl::HasPropagated)?;
+        assert!(g.get_dirty().is_empty());
+        //A change below the tolerance is not sent on
+        let prior: M = vec![(0, 0.5 + 1e-9), (1, 0.5)].into_iter().collect();
+        g.send_control_message(x, VariableNodeCtrl::SetPrior(Some(prior)))?;
+        assert_eq!(g.get_dirty().iter().copied().collect::<Vec<_>>(), vec![x]);
+        assert_eq!(g.propagate_incremental(10, 1e-6)?, 1);
+        assert!(g.get_dirty().is_empty());
+        let prior: M = vec![(0, 0.9), (1, 0.1)].into_iter().collect();
+        g.send_control_message(x, VariableNodeCtrl::SetPrior(Some(prior)))?;
+        assert!(g.propagate_incremental(10, 1e-6)? > 1);
+        assert!(g.get_result(y)?.unwrap()[&0] > 0.5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_temperature() -> BPResult<()> {
+        let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+        let x = g.add_variable(
+            "x".to_owned(),
+            vec![(0, 0.8), (1, 0.2)].into_iter().collect(),
+        )?;
+        let y = g.add_variable(
+            "y".to_owned(),
+            vec![(0, 0.5), (1, 0.5)].into_iter().collect(),
+        )?;
+        let f = g.add_factor(
+            "f".to_owned(),
+            FnFactor::new(2, |v: &[i32]| if v[0] == v[1] { 1.0 } else { 0.0 }),
+        )?;
+        g.add_edge(x, f)?;
+        g.add_edge(y, f)?;
+        assert!(g.set_temperature(0.0).is_err());
+        assert!(g.set_annealing(2.0, 1.5).is_err());
+        g.set_temperature(2.0)?;
+        g.initialize()?;
+        g.propagate(2)?;
+        //x -> f and f -> y are both raised to the power 1/2
+        let (p0, p1) = (0.8f64.powf(0.25), 0.2f64.powf(0.25));
+        let res = g.get_result(y)?.unwrap();
+        assert!((res[&0] - p0 / (p0 + p1)).abs() < 1e-12);
+        assert_eq!(g.get_temperature(), 2.0);
+
+        g.set_annealing(3.0, 0.5)?;
+        g.propagate(1)?;
+        assert_eq!(g.get_temperature(), 2.0);
+        g.propagate_threaded(1, 2)?;
+        assert_eq!(g.get_temperature(), 1.5);
+        g.disable_annealing();
+        g.propagate(1)?;
+        assert_eq!(g.get_temperature(), 1.5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_propagate_subset() -> BPResult<()> {
+        type M = HashMap<i32, Probability>;
+        let mut g = BPGraph::<i32, M, VariableNodeCtrl<M>, VariableNodeCtrlAnswer<M>>::new();
+        let priors = [[0.2, 0.8], [0.5, 0.5], [0.6, 0.4], [0.3, 0.7]];
+        for (i, prior) in priors.iter().enumerate() {
+            g.add_variable(
+                format!("v{}", i),
+                vec![(0, prior[0]), (1, prior[1])].into_iter().collect(),
+            )?;
+        }
+        let mut factors = Vec::new();
+        for i in 0..3 {
+            let f = g.add_factor(
+                format!("f{}", i),
+                FnFactor::new(2, |v: &[i32]| near(v[0], v[1])),
+            )?;
+            g.add_edge(i, f)?;
+            g.add_edge(i + 1, f)?;
+            factors.push(f);
+        }
+        assert!(g.propagate_subset(&[0], 1).is_err());
+        g.set_incremental(true);
+        g.initialize()?;
+        g.propagate(10)?;
+        let v3 = g.get_result(3)?.unwrap();
+        g.send_control_message(
+            1,
+            VariableNodeCtrl::SetPrior(Some(vec![(0, 0.9), (1, 0.1)].into_iter().collect())),
+        )?;
+        //v3 and f2 are outside, the message of f2 to v2 stays fixed
+        g.propagate_subset(&[0, factors[0], 1, factors[1], 2], 10)?;
+        assert_eq!(g.get_step(), 20);
+        let exact = g.exact_marginals_bruteforce(100)?;
+        for v in 0..3 {
+            let res = g.get_result(v)?.unwrap();
+            assert!((res[&0] - exact[&v][&0]).abs() < 1e-12);
+        }
+        assert_eq!(g.get_result(3)?.unwrap(), v3);
+        assert!((v3[&0] - exact[&3][&0]).abs() > 1e-3);
+        assert!(g.propagate_subset(&[0, 42], 1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_node_function() -> BPResult<()> {
+        type M = HashMap<i32, Probability>;
+        let mut g = BPGraph::<i32, M>::new();
+        let v0 = g.add_variable(
+            "v0".to_owned(),
+            vec![(0, 0.5), (1, 0.5)].into_iter().collect(),
+        )?;
+        let v1 = g.add_variable(
+            "v1".to_owned(),
+            vec![(0, 0.5), (1, 0.5)].into_iter().collect(),
+        )?;
+        let f = g.add_pairwise_potential(
+            v0,
+            v1,
+            vec![((0, 0), 1.0), ((1, 1), 1.0)].into_iter().collect(),
+        )?;
+        assert_eq!(
+            g.get_node_function::<PairwiseFactor<i32, M>>(f)?
+                .get_table()
+                .len(),
+            2
+        );
+        assert!(g.get_node_function::<VariableNode<i32, M>>(f).is_err());
+        assert!(g.get_node_function::<TwoNode<i32, M>>(f).is_err());
+        g.get_node_function_mut::<VariableNode<i32, M>>(v0)?
+            .add_prior(&vec![(0, 0.9), (1, 0.1)].into_iter().collect(), 1.0)?;
+        assert_eq!(
+            g.get_node_function::<VariableNode<i32, M>>(v0)?
+                .get_priors()
+                .len(),
+            2
+        );
+        g.initialize()?;
+        g.propagate(2)?;
+        assert!((g.get_result(v1)?.unwrap()[&0] - 0.9).abs() < 1e-12);
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_node_function_mut_out_of_range() -> BPResult<()> {
+        //A failed lookup must not leave a stale index behind that breaks the next validation
+        type M = HashMap<i32, Probability>;
+        let mut g = chain_graph()?;
+        g.set_check_validity(true);
+        g.propagate(1)?;
+        assert!(g
+            .get_node_function_mut::<VariableNode<i32, M>>(999)
+            .is_err());
+        g.propagate(1)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_node_function_custom() -> BPResult<()> {
+        //Every node function supports downcasting (see AsAny), also ones defined outside of the crate
+        type M = HashMap<i32, Probability>;
+        let mut g = BPGraph::<i32, M>::new();
+        let t = g.add_factor(
+            "t".to_owned(),
+            TwoNode::<i32, M>::new(|a, b| if a == b { 1.0 } else { 0.0 }),
+        )?;
+        assert!(g
+            .get_node_function::<TwoNode<i32, M>>(t)?
+            .connection0
+            .is_none());
+        g.get_node_function_mut::<TwoNode<i32, M>>(t)?.connection0 = Some(1);
+        assert_eq!(
+            g.get_node(t)?
+                .node_function_as_any()
+                .downcast_ref::<TwoNode<i32, M>>()
+                .unwrap()
+                .connection0,
+            Some(1)
+        );
+        assert!(g.get_node_function::<PairwiseFactor<i32, M>>(t).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_boundary_messages() -> BPResult<()> {
+        let build = || -> BPResult<BPGraph<i32, HashMap<i32, Probability>>> {
+            let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+            let v0 = g.add_variable(
+                "v0".to_owned(),
+                vec![(0, 0.8), (1, 0.2)].into_iter().collect(),
+            )?;
+            let v1 = g.add_variable(
+                "v1".to_owned(),
+                vec![(0, 0.5), (1, 0.5)].into_iter().collect(),
+            )?;
+            g.add_pairwise_potential(
+                v0,
+                v1,
+                vec![((0, 0), 0.7), ((1, 1), 0.7), ((0, 1), 0.3), ((1, 0), 0.3)]
+                    .into_iter()
+                    .collect(),
+            )?;
+            g.initialize()?;
+            Ok(g)
+        };
+        let mut a = build()?;
+        let mut b = build()?;
+        a.propagate(2)?;
+        let bytes = a.export_boundary_messages(&[1])?;
+        assert_eq!(b.import_boundary_messages(&bytes, Some)?, 1);
+        let (res_a, res_b) = (a.get_result(1)?.unwrap(), b.get_result(1)?.unwrap());
+        assert!((res_a[&0] - res_b[&0]).abs() < 1e-12 && (res_a[&0] - 0.62).abs() < 1e-12);
+
+        assert!(b
+            .import_boundary_messages(&bytes[..bytes.len() - 1], Some)
+            .is_err());
+        assert!(b
+            .import_boundary_messages(&bytes, |n| if n == 2 { None } else { Some(n) })
+            .is_err());
+        assert!(b
+            .import_boundary_messages(&bytes, |n| Some(1 - n.min(1)))
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_boundary_messages_invalid_batch() -> BPResult<()> {
+        let build = || -> BPResult<BPGraph<i32, HashMap<i32, Probability>>> {
+            let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+            let v0 = g.add_variable(
+                "v0".to_owned(),
+                vec![(0, 0.8), (1, 0.2)].into_iter().collect(),
+            )?;
+            let v1 = g.add_variable(
+                "v1".to_owned(),
+                vec![(0, 0.5), (1, 0.5)].into_iter().collect(),
+            )?;
+            g.add_pairwise_potential(
+                v0,
+                v1,
+                vec![((0, 0), 0.7), ((1, 1), 0.7), ((0, 1), 0.3), ((1, 0), 0.3)]
+                    .into_iter()
+                    .collect(),
+            )?;
+            g.initialize()?;
+            Ok(g)
+        };
+        let mut a = build()?;
+        let mut b = build()?;
+        a.propagate(2)?;
+        let bytes = a.export_boundary_messages(&[0, 1])?;
+        //The first entry (2 -> 0) is valid, the second one (2 -> 1) is not
+        assert!(b
+            .import_boundary_messages(&bytes, |n| if n == 1 { None } else { Some(n) })
+            .is_err());
+        assert!(b
+            .import_boundary_messages(&bytes, |n| if n == 1 { Some(3) } else { Some(n) })
+            .is_err());
+        assert_eq!(b.get_node(0)?.post_len(), 0);
+        assert_eq!(b.get_node(1)?.post_len(), 0);
+        assert_eq!(b.import_boundary_messages(&bytes, Some)?, 2);
+        assert_eq!(b.get_node(0)?.post_len(), 1);
+        Ok(())
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_boundary_messages_compressed() -> BPResult<()> {
+        let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+        let v0 = g.add_variable("v0".to_owned(), (0..64).map(|v| (v, 1.0)).collect())?;
+        let v1 = g.add_variable("v1".to_owned(), (0..64).map(|v| (v, 1.0)).collect())?;
+        g.add_pairwise_potential(
+            v0,
+            v1,
+            (0..64)
+                .flat_map(|a| (0..64).map(move |b| ((a, b), if a == b { 0.9 } else { 0.1 })))
+                .collect(),
+        )?;
+        g.initialize()?;
+        g.propagate(2)?;
+        let plain = g.export_boundary_messages(&[v0, v1])?;
+        let compressed = g.export_boundary_messages_compressed(&[v0, v1], 0)?;
+        assert!(compressed.len() < plain.len());
+        assert_eq!(crate::wire::decompress(&compressed)?, plain);
+        assert_eq!(
+            crate::wire::decompress_with_limit(&compressed, plain.len())?,
+            plain
+        );
+        assert!(crate::wire::decompress_with_limit(&compressed, plain.len() - 1).is_err());
+        let mut copy = g.try_clone()?;
+        copy.reset_schedule_state()?;
+        assert_eq!(
+            copy.import_boundary_messages_compressed(&compressed, Some)?,
+            2
+        );
+        assert_eq!(copy.get_result(v1)?, g.get_result(v1)?);
+        assert!(copy
+            .import_boundary_messages_compressed(&plain, Some)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_distributed() -> BPResult<()> {
+        type M = HashMap<i32, Probability>;
+        //Loop v0 - v1 - v2 - v3 - v0, nodes 0..4 are variables, 4..8 factors
+        fn make_node(n: NodeIndex) -> (String, Box<dyn NodeFunction<i32, M> + Send + Sync>) {
+            if n < 4 {
+                let p = 0.2 + 0.2 * n as Probability;
+                (
+                    format!("v{}", n),
+                    Box::new(
+                        VariableNode::builder()
+                            .prior(vec![(0, p), (1, 1.0 - p)].into_iter().collect())
+                            .build(),
+                    ),
+                )
+            } else {
+                let same = 0.6 + 0.1 * (n - 4) as Probability;
+                let table = vec![
+                    ((0, 0), same),
+                    ((1, 1), same),
+                    ((0, 1), 1.0 - same),
+                    ((1, 0), 1.0 - same),
+                ];
+                (
+                    format!("f{}", n),
+                    Box::new(PairwiseFactor::new(table.into_iter().collect())),
+                )
+            }
+        }
+        let mut g = BPGraph::<i32, M>::new();
+        for n in 0..8 {
+            let (name, node_function) = make_node(n);
+            g.add_node(name, node_function)?;
+        }
+        for f in 0..4 {
+            g.add_edge(4 + f, f)?;
+            g.add_edge(4 + f, (f + 1) % 4)?;
+        }
+        let layout = g.layout();
+        let part = layout.partition_bfs(2);
+        assert_eq!(part.iter().filter(|p| **p == 0).count(), 4);
+        g.initialize()?;
+        g.propagate(8)?;
+
+        let listeners: Vec<std::net::TcpListener> = (0..2)
+            .map(|_| std::net::TcpListener::bind("127.0.0.1:0").unwrap())
+            .collect();
+        let addresses: Vec<std::net::SocketAddr> =
+            listeners.iter().map(|l| l.local_addr().unwrap()).collect();
+        let results: Vec<BPResult<Vec<(NodeIndex, M)>>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = listeners
+                .iter()
+                .enumerate()
+                .map(|(id, listener)| {
+                    let (layout, part, addresses) = (&layout, &part, &addresses);
+                    scope.spawn(move || {
+                        let mut worker = DistributedWorker::build(id, layout, part, make_node)?;
+                        assert_eq!(worker.peer_ids(), vec![1 - id]);
+                        worker.connect(listener, addresses, Duration::from_secs(10))?;
+                        worker.propagate(8)?;
+                        let owned: Vec<NodeIndex> = (0..4).filter(|v| part[*v] == id).collect();
+                        owned
+                            .into_iter()
+                            .map(|v| Ok((v, worker.get_result(v)?.unwrap())))
+                            .collect()
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        let mut checked = 0;
+        for res in results {
+            for (v, marginal) in res? {
+                let expected = g.get_result(v)?.unwrap();
+                assert!((marginal[&0] - expected[&0]).abs() < 1e-12);
+                checked += 1;
+            }
+        }
+        assert_eq!(checked, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_distributed_frame_limits() -> BPResult<()> {
+        type M = HashMap<i32, Probability>;
+        //v0 - f - v1, v1 is owned by a peer that misbehaves
+        fn make_node(n: NodeIndex) -> (String, Box<dyn NodeFunction<i32, M> + Send + Sync>) {
+            if n < 2 {
+                (
+                    format!("v{}", n),
+                    Box::new(
+                        VariableNode::builder()
+                            .prior(vec![(0, 0.5), (1, 0.5)].into_iter().collect())
+                            .build(),
+                    ),
+                )
+            } else {
+                (format!("f{}", n), Box::new(TwoNode::new(near)))
+            }
+        }
+        let mut g = BPGraph::<i32, M>::new();
+        for n in 0..3 {
+            let (name, node_function) = make_node(n);
+            g.add_node(name, node_function)?;
+        }
+        g.add_edge(2, 0)?;
+        g.add_edge(2, 1)?;
+        let layout = g.layout();
+        let part = [0, 1, 0];
+        let connect = |worker: &mut DistributedWorker<i32, M>| -> BPResult<std::net::TcpStream> {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let peer = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            worker.add_peer(1, listener.accept().unwrap().0)?;
+            Ok(peer)
+        };
+        let mut worker = DistributedWorker::build(0, &layout, &part, make_node)?;
+        worker.set_max_frame_size(1024);
+        let mut peer = connect(&mut worker)?;
+        std::io::Write::write_all(&mut peer, &(1u64 << 40).to_le_bytes()).unwrap();
+        assert!(worker.propagate(1).is_err());
+        //A silent peer makes the exchange fail instead of blocking forever
+        let mut worker = DistributedWorker::build(0, &layout, &part, make_node)?;
+        let _peer = connect(&mut worker)?;
+        worker.set_read_timeout(Some(Duration::from_millis(50)))?;
+        assert!(worker.propagate(1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_certainty() -> BPResult<()> {
+        let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+        for p in [0.5, 0.9, 0.7] {
+            g.add_variable(
+                format!("v{}", p),
+                vec![(0, p), (1, 1.0 - p)].into_iter().collect(),
+            )?;
+        }
+        let uniform: HashMap<(i32, i32), Probability> =
+            vec![((0, 0), 1.0), ((0, 1), 1.0), ((1, 0), 1.0), ((1, 1), 1.0)]
+                .into_iter()
+                .collect();
+        g.add_pairwise_potential(0, 1, uniform.clone())?;
+        g.add_pairwise_potential(1, 2, uniform)?;
+        g.initialize()?;
+        g.propagate(2)?;
+        assert!((g.get_entropy(0)?.unwrap() - 2.0f64.ln()).abs() < 1e-12);
+        assert!((g.get_polarization(1)?.unwrap() - 0.8).abs() < 1e-12);
+        assert!(g.get_polarization(0)?.unwrap().abs() < 1e-12);
+        let most: Vec<NodeIndex> = g
+            .most_certain_nodes(2)?
+            .into_iter()
+            .map(|(n, _)| n)
+            .collect();
+        assert_eq!(most, vec![1, 2]);
+        let least: Vec<NodeIndex> = g
+            .least_certain_nodes(1)?
+            .into_iter()
+            .map(|(n, _)| n)
+            .collect();
+        assert_eq!(least, vec![0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_validity_cache() -> BPResult<()> {
+        let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+        g.set_check_validity(true);
+        let prior: HashMap<i32, Probability> = vec![(0, 0.6), (1, 0.4)].into_iter().collect();
+        let v0 = g.add_variable("v0".to_owned(), prior.clone())?;
+        let v1 = g.add_variable("v1".to_owned(), prior.clone())?;
+        let table: HashMap<(i32, i32), Probability> =
+            vec![((0, 0), 1.0), ((1, 1), 1.0)].into_iter().collect();
+        g.add_pairwise_potential(v0, v1, table.clone())?;
+        g.initialize()?;
+        g.propagate(2)?;
+        //Nodes added after the first validation are checked as well
+        g.unseal();
+        let v2 = g.add_variable("v2".to_owned(), prior)?;
+        assert!(g.initialize().is_err());
+        g.add_pairwise_potential(v1, v2, table)?;
+        g.initialize()?;
+        g.propagate(2)?;
+        g.invalidate_validation();
+        g.propagate(1)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_edge_ports() -> BPResult<()> {
+        //minuend - subtrahend = difference, independent of the order of the connections
+        struct Difference(Option<[NodeIndex; 3]>);
+        impl NodeFunction<i32, HashMap<i32, Probability>> for Difference {
+            fn node_function(
+                &mut self,
+                inbox: Vec<(NodeIndex, HashMap<i32, Probability>)>,
+            ) -> BPResult<Vec<(NodeIndex, HashMap<i32, Probability>)>> {
+                let ports = self.0.unwrap();
+                let msgs: Vec<&HashMap<i32, Probability>> = ports
+                    .iter()
+                    .map(|p| &inbox.iter().find(|(from, _)| from == p).unwrap().1)
+                    .collect();
+                let mut out: Vec<HashMap<i32, Probability>> = msgs
+                    .iter()
+                    .map(|m| m.keys().map(|v| (*v, 0.0)).collect())
+                    .collect();
+                for (x, px) in msgs[0] {
+                    for (y, py) in msgs[1] {
+                        for (z, pz) in msgs[2] {
+                            if x - y == *z {
+                                *out[0].get_mut(x).unwrap() += py * pz;
+                                *out[1].get_mut(y).unwrap() += px * pz;
+                                *out[2].get_mut(z).unwrap() += px * py;
+                            }
+                        }
+                    }
+                }
+                Ok(ports.iter().copied().zip(out).collect())
+            }
+            fn is_factor(&self) -> bool {
+                true
+            }
+            fn number_inputs(&self) -> Option<usize> {
+                Some(3)
+            }
+            fn initialize(&mut self, _connections: Vec<NodeIndex>) -> BPResult<()> {
+                Ok(())
+            }
+            fn ports(&self) -> Vec<String> {
+                vec![
+                    "minuend".to_owned(),
+                    "subtrahend".to_owned(),
+                    "difference".to_owned(),
+                ]
+            }
+            fn initialize_ports(&mut self, ports: Vec<(String, NodeIndex)>) -> BPResult<()> {
+                let get = |name: &str| {
+                    ports
+                        .iter()
+                        .find(|(p, _)| p == name)
+                        .map(|(_, n)| *n)
+                        .unwrap()
+                };
+                self.0 = Some([get("minuend"), get("subtrahend"), get("difference")]);
+                Ok(())
+            }
+            fn is_ready(
+                &self,
+                recv_from: &Vec<(NodeIndex, HashMap<i32, Probability>)>,
+                _current_step: usize,
+            ) -> BPResult<bool> {
+                Ok(recv_from.len() == 3)
+            }
+            fn reset(&mut self) -> BPResult<()> {
+                Ok(())
+            }
+            fn get_prior(&self) -> Option<HashMap<i32, Probability>> {
+                None
+            }
+        }
+        let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+        let z = g.add_variable("z".to_owned(), (-2..3).map(|v| (v, 1.0)).collect())?;
+        let x = g.add_variable(
+            "x".to_owned(),
+            vec![(1, 0.5), (2, 0.5)].into_iter().collect(),
+        )?;
+        let y = g.add_variable("y".to_owned(), vec![(1, 1.0)].into_iter().collect())?;
+        let f = g.add_factor("difference".to_owned(), Difference(None))?;
+        g.add_edge_port(f, "difference", z)?;
+        g.add_edge_port(f, "minuend", x)?;
+        assert!(g.add_edge_port(f, "minuend", y).is_err());
+        g.add_edge(f, y)?;
+        assert!(matches!(
+            g.validate().unwrap_err().as_slice(),
+            [ValidationIssue::UnboundPort { port, .. }] if port == "subtrahend"
+        ));
+        let mut g2 = BPGraph::<i32, HashMap<i32, Probability>>::new();
+        let z = g2.add_variable("z".to_owned(), (-2..3).map(|v| (v, 1.0)).collect())?;
+        let y = g2.add_variable("y".to_owned(), vec![(1, 1.0)].into_iter().collect())?;
+        let x = g2.add_variable(
+            "x".to_owned(),
+            vec![(1, 0.5), (2, 0.5)].into_iter().collect(),
+        )?;
+        let f = g2.add_factor("difference".to_owned(), Difference(None))?;
+        g2.add_edge_port(f, "difference", z)?;
+        g2.add_edge_port(f, "subtrahend", y)?;
+        g2.add_edge_port(f, "minuend", x)?;
+        assert!(g2.validate().is_ok());
+        g2.initialize()?;
+        g2.propagate(2)?;
+        let res = g2.get_result(z)?.unwrap();
+        assert!((res[&0] - 0.5).abs() < 1e-12);
+        assert!((res[&1] - 0.5).abs() < 1e-12);
+        assert!(res[&-1].abs() < 1e-12);
+        Ok(())
+    }
+
+    #[test]
+    fn test_scaled_msg() -> BPResult<()> {
+        type M = ScaledMsg<HashMap<i32, Probability>>;
+        //100 unary evidence factors, the likelihood (about 1e-470) underflows without scaling
+        let mut g = BPGraph::<i32, M>::new();
+        g.set_normalization(NormalizationMode::None);
+        //The likelihood includes the scale of the prior
+        let v = g.add_node(
+            "v".to_owned(),
+            Box::new(
+                VariableNode::builder()
+                    .prior(ScaledMsg::new(
+                        vec![(0, 1.0), (1, 1.0)].into_iter().collect(),
+                    ))
+                    .normalize_prior(false)
+                    .build(),
+            ),
+        )?;
+        for i in 0..100 {
+            let f = g.add_factor(
+                format!("evidence{}", i),
+                FixedArityFactor::<1, i32, M>::new(|[x]| if x == 0 { 1e-5 } else { 2e-5 }),
+            )?;
+            g.add_edge(f, v)?;
+        }
+        g.initialize()?;
+        g.propagate(2)?;
+        let (res, log_scale) = g.get_result_scaled(v)?.unwrap();
+        assert!((res[&1] - 1.0).abs() < 1e-12);
+        assert!((res[&0] - 0.5f64.powi(100)).abs() < 1e-40);
+        let log_likelihood = ScaledMsg::with_log_scale(res, log_scale).log_sum();
+        let expected = -500.0 * 10f64.ln() + (1.0 + 2f64.powi(100)).ln();
+        assert!((log_likelihood - expected).abs() < 1e-9 * expected.abs());
+        let marginal = g.get_result(v)?.unwrap();
+        assert!((marginal[&1] - 1.0 / (1.0 + 0.5f64.powi(100))).abs() < 1e-12);
+        Ok(())
+    }
+
+    #[test]
+    fn test_scaled_msg_factor_drops_scale() -> BPResult<()> {
+        type M = ScaledMsg<HashMap<i32, Probability>>;
+        let mut g = BPGraph::<i32, M>::new();
+        g.set_normalization(NormalizationMode::None);
+        let uniform =
+            || -> HashMap<i32, Probability> { vec![(0, 1.0), (1, 1.0)].into_iter().collect() };
+        let variable = |prior: M| {
+            VariableNode::builder()
+                .prior(prior)
+                .normalize_prior(false)
+                .build()
+        };
+        let x = g.add_node(
+            "x".to_owned(),
+            Box::new(variable(ScaledMsg::with_log_scale(uniform(), 50.0))),
+        )?;
+        let y = g.add_node(
+            "y".to_owned(),
+            Box::new(variable(ScaledMsg::new(uniform()))),
+        )?;
+        let f = g.add_factor(
+            "f".to_owned(),
+            FixedArityFactor::<2, i32, M>::new(|[a, b]| if a == b { 1.0 } else { 0.5 }),
+        )?;
+        g.add_edge(x, f)?;
+        g.add_edge(f, y)?;
+        g.initialize()?;
+        g.propagate(2)?;
+        //The scale of the prior is kept at x, but the factor builds its message to y from the entries only
+        let (_, log_scale_x) = g.get_result_scaled(x)?.unwrap();
+        assert!((log_scale_x - 50.0 - 1.5f64.ln()).abs() < 1e-12);
+        let (res_y, log_scale_y) = g.get_result_scaled(y)?.unwrap();
+        assert!((log_scale_y - 1.5f64.ln()).abs() < 1e-12);
+        assert!((res_y[&0] - 1.0).abs() < 1e-12);
+
+        //mult_msg, add_msg_weighted and NormalizationMode::None keep the scale, for_each and other modes drop it
+        let mut msg = ScaledMsg::with_log_scale(uniform(), 2.0);
+        msg.mult_msg(&ScaledMsg::with_log_scale(uniform(), 3.0));
+        assert_eq!(msg.log_scale(), 5.0);
+        msg.add_msg_weighted(&ScaledMsg::with_log_scale(uniform(), 5.0), 0.5, 0.5);
+        assert_eq!(msg.log_scale(), 5.0);
+        assert!((msg.log_sum() - (5.0 + 2f64.ln())).abs() < 1e-12);
+        msg.normalize_with(NormalizationMode::None)?;
+        assert_eq!(msg.log_scale(), 5.0);
+        msg.for_each(|p| p);
+        assert_eq!(msg.log_scale(), 0.0);
+        let mut msg = ScaledMsg::with_log_scale(uniform(), 2.0);
+        msg.normalize_with(NormalizationMode::SumToOne)?;
+        assert_eq!(msg.log_scale(), 0.0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bp_graph_macro() -> BPResult<()> {
+        let prior: HashMap<i32, Probability> = vec![(0, 0.3), (1, 0.7)].into_iter().collect();
+        let table: HashMap<(i32, i32), Probability> =
+            vec![((0, 0), 1.0), ((1, 1), 1.0)].into_iter().collect();
+        let (mut g, nodes) = bp_graph! {
+            BPGraph<i32, HashMap<i32, Probability>>;
+            variables {
+                x = prior.clone(),
+                y = vec![(0, 1.0), (1, 1.0)].into_iter().collect(),
+                z = prior,
+            }
+            factors {
+                f = table(table) => [x, y],
+                g = potential(|[a, b]: [i32; 2]| if a != b { 1.0 } else { 0.0 }) => [y, z],
+            }
+        }?;
+        assert_eq!(g.len(), 5);
+        assert_eq!(nodes["x"], 0);
+        assert_eq!(nodes["g"], 4);
+        assert_eq!(
+            g.get_node(nodes["f"])?.get_connections(),
+            &vec![nodes["x"], nodes["y"]]
+        );
+        g.initialize()?;
+        g.propagate(4)?;
+        let res = g.get_result(nodes["y"])?.unwrap();
+        //x = y = 1 - z
+        assert!((res[&0] - 0.5).abs() < 1e-12);
+        Ok(())
+    }
+
+    #[test]
+    fn test_log_likelihood_of_evidence() -> BPResult<()> {
+        //Chain x0 - x1 - x2 with unnormalized priors, exact on a tree
+        let priors = [[1.0, 3.0], [2.0, 2.0], [0.5, 1.5]];
+        let table: HashMap<(i32, i32), Probability> =
+            vec![((0, 0), 2.0), ((0, 1), 1.0), ((1, 0), 0.5), ((1, 1), 3.0)]
+                .into_iter()
+                .collect();
+        type M = HashMap<i32, Probability>;
+        let mut g = BPGraph::<i32, M, VariableNodeCtrl<M>, VariableNodeCtrlAnswer<M>>::new();
+        for (i, p) in priors.iter().enumerate() {
+            let prior: M = vec![(0, p[0]), (1, p[1])].into_iter().collect();
+            g.add_node(
+                format!("x{}", i),
+                Box::new(
+                    VariableNode::builder()
+                        .prior(prior)
+                        .normalize_prior(false)
+                        .build(),
+                ),
+            )?;
+        }
+        g.add_pairwise_potential(0, 1, table.clone())?;
+        g.add_pairwise_potential(1, 2, table.clone())?;
+        g.initialize()?;
+        let weight = |x: [i32; 3]| {
+            (0..3)
+                .map(|i| priors[i][x[i] as usize])
+                .product::<Probability>()
+                * table[&(x[0], x[1])]
+                * table[&(x[1], x[2])]
+        };
+        let assignments: Vec<[i32; 3]> = (0..8).map(|a| [a & 1, (a >> 1) & 1, a >> 2]).collect();
+        let z: Probability = assignments.iter().map(|x| weight(*x)).sum();
+        g.propagate(4)?;
+        assert!((g.bethe_log_partition(100)? - z.ln()).abs() < 1e-9);
+        let z_evidence: Probability = assignments
+            .iter()
+            .filter(|x| x[0] == 1 && x[2] == 0)
+            .map(|x| weight(*x))
+            .sum();
+        let before = g.get_result(1)?.unwrap();
+        let ll = g.log_likelihood_of_evidence(&[(0, 1), (2, 0)], 4, 100)?;
+        assert!((ll - (z_evidence / z).ln()).abs() < 1e-9);
+        //The graph is restored
+        assert!((g.get_result(1)?.unwrap()[&0] - before[&0]).abs() < 1e-12);
+        assert!(g.log_likelihood_of_evidence(&[(0, 5)], 4, 100).is_err());
+        assert!(g.log_likelihood_of_evidence(&[(3, 0)], 4, 100).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_em_step() -> BPResult<()> {
+        let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+        let x0 = g.add_variable(
+            "x0".to_owned(),
+            vec![(0, 0.9), (1, 0.1)].into_iter().collect(),
+        )?;
+        let x1 = g.add_variable(
+            "x1".to_owned(),
+            vec![(0, 0.2), (1, 0.8)].into_iter().collect(),
+        )?;
+        let uniform: HashMap<(i32, i32), Probability> =
+            vec![((0, 0), 1.0), ((0, 1), 1.0), ((1, 0), 1.0), ((1, 1), 1.0)]
+                .into_iter()
+                .collect();
+        let learned = g.add_factor(
+            "learned".to_owned(),
+            PairwiseFactor::new(uniform.clone()).learnable(),
+        )?;
+        g.add_edge(learned, x0)?;
+        g.add_edge(learned, x1)?;
+        let x2 = g.add_variable(
+            "x2".to_owned(),
+            vec![(0, 0.5), (1, 0.5)].into_iter().collect(),
+        )?;
+        let fixed = g.add_pairwise_potential(x1, x2, uniform.clone())?;
+        g.initialize()?;
+        assert_eq!(g.em_step(2, 16)?, 1);
+        //With a uniform table, the expected counts are the product of the priors
+        let table = g
+            .get_node_function::<PairwiseFactor<i32, HashMap<i32, Probability>>>(learned)?
+            .get_table()
+            .clone();
+        assert!((table[&(0, 1)] - 0.72).abs() < 1e-12);
+        assert!((table[&(1, 0)] - 0.02).abs() < 1e-12);
+        assert_eq!(
+            g.get_node_function::<PairwiseFactor<i32, HashMap<i32, Probability>>>(fixed)?
+                .get_table(),
+            &uniform
+        );
+        //Two evidence cases before the update
+        for _ in 0..2 {
+            assert_eq!(g.accumulate_statistics(2, 16)?, 1);
+        }
+        let stats_sum: Probability = g
+            .get_node_function::<PairwiseFactor<i32, HashMap<i32, Probability>>>(learned)?
+            .get_statistics()
+            .unwrap()
+            .values()
+            .sum();
+        assert!((stats_sum - 2.0).abs() < 1e-12);
+        g.update_parameters()?;
+        let table = g
+            .get_node_function::<PairwiseFactor<i32, HashMap<i32, Probability>>>(learned)?
+            .get_table()
+            .clone();
+        let weights = [
+            ((0, 0), 0.18 * 0.18),
+            ((0, 1), 0.72 * 0.72),
+            ((1, 0), 0.02 * 0.02),
+            ((1, 1), 0.08 * 0.08),
+        ];
+        let sum: Probability = weights.iter().map(|(_, w)| w).sum();
+        for (x, w) in weights {
+            assert!((table[&x] - w / sum).abs() < 1e-12);
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[allow(clippy::type_complexity)]
+    fn test_zero_message_policy() -> BPResult<()> {
+        let build = |policy| -> BPResult<(BPGraph<i32, HashMap<i32, Probability>>, NodeIndex)> {
+            let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+            g.set_zero_message_policy(policy);
+            let x0 = g.add_variable(
+                "x0".to_owned(),
+                vec![(0, 1.0), (1, 0.0)].into_iter().collect(),
+            )?;
+            let x1 = g.add_variable(
+                "x1".to_owned(),
+                vec![(0, 0.0), (1, 1.0)].into_iter().collect(),
+            )?;
+            let x2 = g.add_variable(
+                "x2".to_owned(),
+                vec![(0, 0.5), (1, 0.5)].into_iter().collect(),
+            )?;
+            //x0 = x1 = x2 contradicts the priors of x0 and x1
+            let eq = g.link_variables("eq".to_owned(), &[x0, x1, x2])?;
+            g.initialize()?;
+            Ok((g, eq))
+        };
+        assert!(build(ZeroMessagePolicy::Error)?.0.propagate(2).is_err());
+        let (mut g, _) = build(ZeroMessagePolicy::DropMessage)?;
+        g.propagate(2)?;
+        assert!(g.get_contradictions().is_empty());
+        let (mut g, eq) = build(ZeroMessagePolicy::MarkContradiction)?;
+        g.propagate(2)?;
+        assert_eq!(
+            g.get_contradictions(),
+            &[Contradiction {
+                from: eq,
+                to: 2,
+                step: 1
+            }]
+        );
+        g.clear_contradictions();
+        assert!(g.get_contradictions().is_empty());
+        let (mut g, eq) = build(ZeroMessagePolicy::MarkContradiction)?;
+        g.propagate_threaded(2, 2)?;
+        assert_eq!(
+            g.contradicting_nodes().into_iter().collect::<Vec<_>>(),
+            vec![eq]
+        );
+        let (mut g, _) = build(ZeroMessagePolicy::ReplaceWithUniform)?;
+        g.propagate(2)?;
+        let res = g.get_result(2)?.unwrap();
+        assert!((res[&0] - 0.5).abs() < 1e-12);
+        Ok(())
+    }
+
+    #[test]
+    fn test_grid() -> BPResult<()> {
+        let (width, height) = (3, 2);
+        let labels = [0, 1, 2];
+        //Every pixel prefers label 1 except (2, 1), which strongly prefers label 2
+        let mut costs: Vec<Probability> = (0..width * height)
+            .flat_map(|_| vec![1.0, 0.0, 1.0])
+            .collect();
+        costs[(width + 2) * 3..(width + 3) * 3].copy_from_slice(&[5.0, 5.0, 0.0]);
+        let potts = |a: i32, b: i32| if a == b { 0.0 } else { 0.5 };
+        let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+        let grid = g.add_grid(width, height, &labels, &costs, potts)?;
+        assert_eq!(g.len(), 6 + 4 + 3);
+        assert!(g.is_valid());
+        assert_eq!(grid.variable(2, 1), Some(5));
+        assert_eq!(grid.position(4), Some((1, 1)));
+        assert_eq!(grid.variable(3, 0), None);
+        let h = grid.horizontal_factor(1, 1).unwrap();
+        assert_eq!(g.get_connections(h)?, &vec![4, 5]);
+        let v = grid.vertical_factor(2, 0).unwrap();
+        assert_eq!(v, 12);
+        assert_eq!(g.get_connections(v)?, &vec![2, 5]);
+        assert_eq!(grid.horizontal_factor(2, 0), None);
+        g.initialize()?;
+        g.propagate(20)?;
+        let argmax = |m: HashMap<i32, Probability>| {
+            *m.iter()
+                .max_by(|a, b| a.1.partial_cmp(b.1).unwrap())
+                .unwrap()
+                .0
+        };
+        assert_eq!(
+            argmax(g.get_result(grid.variable(0, 0).unwrap())?.unwrap()),
+            1
+        );
+        assert_eq!(
+            argmax(g.get_result(grid.variable(2, 1).unwrap())?.unwrap()),
+            2
+        );
+
+        let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+        g.set_semiring(Arc::new(MinSum));
+        g.add_grid(width, height, &labels, &costs, potts)?;
+        assert!((g.get_node(0)?.get_prior().unwrap()[&0] - 1.0).abs() < 1e-12);
+        assert!(g
+            .add_grid(width, height, &labels, &costs[1..], potts)
+            .is_err());
+        assert!(g.add_grid(0, height, &labels, &[], potts).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_potts_and_distance_factor() -> BPResult<()> {
+        type M = HashMap<i32, Probability>;
+        fn messages<F: NodeFunction<i32, M, (), ()>>(
+            f: &mut F,
+            semiring: Option<Arc<dyn Semiring>>,
+            m0: &M,
+            m1: &M,
+        ) -> BPResult<Vec<M>> {
+            if let Some(s) = semiring {
+                f.set_semiring(s);
+            }
+            f.initialize(vec![0, 1])?;
+            let mut out = f.node_function(vec![(1, m1.clone()), (0, m0.clone())])?;
+            out.sort_by_key(|(to, _)| *to);
+            Ok(out.into_iter().map(|(_, m)| m).collect())
+        }
+        //The labels of the two variables overlap partially, 9 is never sent
+        let m0: M = (0..6)
+            .map(|i| (i, 0.1 + ((i * 7) % 5) as Probability * 0.2))
+            .collect();
+        let m1: M = (2..10)
+            .map(|i| (i, 0.3 + ((i * 3) % 4) as Probability * 0.25))
+            .collect();
+        let semirings: Vec<Option<Arc<dyn Semiring>>> = vec![
+            None,
+            Some(Arc::new(MinSum)),
+            Some(Arc::new(MaxProduct)),
+            Some(Arc::new(MaxSum)),
+            Some(Arc::new(BooleanOrAnd)),
+        ];
+        for semiring in semirings {
+            let lift = |p: Probability| semiring.as_ref().map_or(p, |s| s.lift(p));
+            let table = |cost: &dyn Fn(i32, i32) -> f64| -> HashMap<(i32, i32), Probability> {
+                (0..10)
+                    .flat_map(|a| (0..10).map(move |b| (a, b)))
+                    .map(|(a, b)| ((a, b), lift((-cost(a, b)).exp())))
+                    .collect()
+            };
+            let same = |expected: &[M], res: &[M]| {
+                for (e, r) in expected.iter().zip(res) {
+                    for (v, p) in r {
+                        assert!(
+                            (e[v] - p).abs() < 1e-9 || e[v] == *p,
+                            "{:?}: {} vs. {}",
+                            semiring,
+                            e[v],
+                            p
+                        );
+                    }
+                }
+            };
+            let (m0, m1) = (
+                m0.iter().map(|(v, p)| (*v, lift(*p))).collect(),
+                m1.iter().map(|(v, p)| (*v, lift(*p))).collect(),
+            );
+            let potts = |a: i32, b: i32| if a == b { 0.0 } else { 0.8 };
+            let expected = messages(
+                &mut PairwiseFactor::new(table(&potts)),
+                semiring.clone(),
+                &m0,
+                &m1,
+            )?;
+            let res = messages(
+                &mut PottsFactor::new(lift(1.0), lift((-0.8f64).exp())),
+                semiring.clone(),
+                &m0,
+                &m1,
+            )?;
+            assert_eq!(res[0].len(), 6);
+            assert_eq!(res[1].len(), 8);
+            same(&expected, &res);
+            for cost in [DistanceCost::Linear, DistanceCost::Quadratic] {
+                let factor = DistanceFactor::new(cost, 0.7, 2.5);
+                let expected = messages(
+                    &mut PairwiseFactor::new(table(&|a, b| factor.cost(a.into(), b.into()))),
+                    semiring.clone(),
+                    &m0,
+                    &m1,
+                )?;
+                same(
+                    &expected,
+                    &messages(&mut factor.clone(), semiring.clone(), &m0, &m1)?,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_sliding_window() -> BPResult<()> {
+        type M = HashMap<i32, Probability>;
+        let evidence = [0.8, 0.3, 0.6, 0.1, 0.9, 0.4, 0.7];
+        let transition: HashMap<(i32, i32), Probability> =
+            vec![((0, 0), 0.9), ((0, 1), 0.1), ((1, 0), 0.2), ((1, 1), 0.8)]
+                .into_iter()
+                .collect();
+        //x_t has the evidence as prior and is connected to x_{t-1} by a transition factor
+        fn add_slice(
+            g: &mut BPGraph<i32, M, VariableNodeCtrl<M>, VariableNodeCtrlAnswer<M>>,
+            t: usize,
+            e: Probability,
+            transition: &HashMap<(i32, i32), Probability>,
+        ) -> BPResult<Vec<NodeIndex>> {
+            let prior: M = vec![(0, e), (1, 1.0 - e)].into_iter().collect();
+            if t == 0 {
+                return Ok(vec![g.add_variable("x0".to_owned(), prior)?]);
+            }
+            let f = g.add_factor(format!("f{}", t), PairwiseFactor::new(transition.clone()))?;
+            let x = g.add_variable(format!("x{}", t), prior)?;
+            g.add_edge(f, x - 2)?;
+            g.add_edge(f, x)?;
+            Ok(vec![x, f])
+        }
+        let mut full = BPGraph::<i32, M, VariableNodeCtrl<M>, VariableNodeCtrlAnswer<M>>::new();
+        for (t, e) in evidence.iter().enumerate() {
+            add_slice(&mut full, t, *e, &transition)?;
+        }
+        full.initialize()?;
+        full.propagate(30)?;
+
+        let mut g = BPGraph::<i32, M, VariableNodeCtrl<M>, VariableNodeCtrlAnswer<M>>::new();
+        assert!(g.append_slice(vec![]).is_err());
+        g.set_window(3)?;
+        for (t, e) in evidence.iter().enumerate() {
+            let slice = add_slice(&mut g, t, *e, &transition)?;
+            g.initialize()?;
+            assert_eq!(g.append_slice(slice)?, t);
+            g.propagate_window(10)?;
+        }
+        let window = g.get_window().unwrap();
+        assert_eq!((window.active_slices(), window.frozen_slices()), (3, 4));
+        //x3 (6) and the factor between x3 and x4 (7) are frozen
+        assert!(window.is_frozen(6) && window.is_frozen(7) && !window.is_frozen(8));
+        assert_eq!(g.get_connections(8)?, &vec![9]);
+        assert!(g.is_valid());
+        //The past is collapsed exactly into the priors, the beliefs in the window are the smoothed marginals
+        for x in [8, 10, 12] {
+            let (res, expected) = (g.get_result(x)?.unwrap(), full.get_result(x)?.unwrap());
+            assert!(
+                (res[&0] - expected[&0]).abs() < 1e-9,
+                "{}: {:?} vs. {:?}",
+                x,
+                res,
+                expected
+            );
+        }
+        //Frozen variables keep their last beliefs
+        assert!(g.get_result(6)?.is_some());
+        assert!(g.advance_window(4).is_err());
+        assert_eq!(g.advance_window(1)?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_clone_graph() -> BPResult<()> {
+        type M = HashMap<i32, Probability>;
+        let mut g = BPGraph::<i32, M>::new();
+        let table: HashMap<(i32, i32), Probability> =
+            vec![((0, 0), 0.8), ((0, 1), 0.2), ((1, 0), 0.3), ((1, 1), 0.7)]
+                .into_iter()
+                .collect();
+        let a = g.add_variable(
+            "a".to_owned(),
+            vec![(0, 0.6), (1, 0.4)].into_iter().collect(),
+        )?;
+        let b = g.add_variable(
+            "b".to_owned(),
+            vec![(0, 0.5), (1, 0.5)].into_iter().collect(),
+        )?;
+        let c = g.add_variable(
+            "c".to_owned(),
+            vec![(0, 0.1), (1, 0.9)].into_iter().collect(),
+        )?;
+        for (x, y) in [(a, b), (b, c)] {
+            let f = g.add_factor(format!("f{}{}", x, y), PairwiseFactor::new(table.clone()))?;
+            g.add_edge(f, x)?;
+            g.add_edge(f, y)?;
+        }
+        //The same (tree) graph from several random initializations in parallel reaches the same fixed point
+        let copies = (0..3)
+            .map(|_| g.try_clone())
+            .collect::<BPResult<Vec<_>>>()?;
+        let results: Vec<M> = std::thread::scope(|s| {
+            let handles: Vec<_> = copies
+                .into_iter()
+                .zip(0..3u64)
+                .map(|(mut g, seed)| {
+                    s.spawn(move || -> BPResult<M> {
+                        g.initialize_random(seed, 0.5)?;
+                        g.propagate(10)?;
+                        Ok(g.get_result(b)?.unwrap())
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .collect::<BPResult<Vec<M>>>()
+        })?;
+        for res in &results[1..] {
+            assert!((res[&0] - results[0][&0]).abs() < 1e-9);
+        }
+        //Clones are independent
+        g.initialize()?;
+        g.propagate(2)?;
+        let mut copy = g.try_clone()?;
+        assert_eq!(copy.get_step(), 2);
+        assert_eq!(copy.get_result(b)?, g.get_result(b)?);
+        copy.propagate(2)?;
+        assert_eq!(g.get_step(), 2);
+
+        //Node functions without clone_box
+        struct Opaque;
+        impl NodeFunction<i32, M> for Opaque {
+            fn node_function(
+                &mut self,
+                _inbox: Vec<(NodeIndex, M)>,
+            ) -> BPResult<Vec<(NodeIndex, M)>> {
+                Ok(Vec::new())
+            }
+            fn is_factor(&self) -> bool {
+                true
+            }
+            fn number_inputs(&self) -> Option<usize> {
+                None
+            }
+            fn initialize(&mut self, _connections: Vec<NodeIndex>) -> BPResult<()> {
+                Ok(())
+            }
+            fn is_ready(
+                &self,
+                _recv_from: &Vec<(NodeIndex, M)>,
+                _current_step: usize,
+            ) -> BPResult<bool> {
+                Ok(false)
+            }
+            fn reset(&mut self) -> BPResult<()> {
+                Ok(())
+            }
+            fn get_prior(&self) -> Option<M> {
+                None
+            }
+        }
+        g.unseal();
+        let f = g.add_factor("opaque".to_owned(), Opaque)?;
+        g.add_edge(f, c)?;
+        assert!(g.try_clone().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_lifecycle_callbacks() -> BPResult<()> {
+        type M = HashMap<i32, Probability>;
+        #[derive(Default)]
+        struct Log {
+            steps: Vec<usize>,
+            neighbors: Vec<String>,
+        }
+        struct Observer(Arc<std::sync::Mutex<Log>>);
+        impl NodeFunction<i32, M> for Observer {
+            fn node_function(
+                &mut self,
+                _inbox: Vec<(NodeIndex, M)>,
+            ) -> BPResult<Vec<(NodeIndex, M)>> {
+                Ok(Vec::new())
+            }
+            fn is_factor(&self) -> bool {
+                true
+            }
+            fn number_inputs(&self) -> Option<usize> {
+                None
+            }
+            fn initialize(&mut self, _connections: Vec<NodeIndex>) -> BPResult<()> {
+                Ok(())
+            }
+            fn is_ready(
+                &self,
+                _recv_from: &Vec<(NodeIndex, M)>,
+                _current_step: usize,
+            ) -> BPResult<bool> {
+                Ok(false)
+            }
+            fn reset(&mut self) -> BPResult<()> {
+                Ok(())
+            }
+            fn get_prior(&self) -> Option<M> {
+                None
+            }
+            fn on_step_end(&mut self, step: usize) -> BPResult<()> {
+                self.0.lock().unwrap().steps.push(step);
+                Ok(())
+            }
+            fn on_graph_initialized(
+                &mut self,
+                connections: &[NodeIndex],
+                graph_info: &GraphInfo,
+            ) -> BPResult<()> {
+                assert_eq!(connections, &[0, 1]);
+                assert_eq!(graph_info.node_index, 2);
+                self.0.lock().unwrap().neighbors = graph_info.neighbor_names.clone();
+                Ok(())
+            }
+        }
+        let log = Arc::new(std::sync::Mutex::new(Log::default()));
+        let mut g = BPGraph::<i32, M>::new();
+        let x = g.add_variable(
+            "x".to_owned(),
+            vec![(0, 0.5), (1, 0.5)].into_iter().collect(),
+        )?;
+        let y = g.add_variable(
+            "y".to_owned(),
+            vec![(0, 0.5), (1, 0.5)].into_iter().collect(),
+        )?;
+        let f = g.add_factor("observer".to_owned(), Observer(log.clone()))?;
+        g.add_edge(x, f)?;
+        g.add_edge(y, f)?;
+        g.initialize()?;
+        assert_eq!(
+            log.lock().unwrap().neighbors,
+            vec!["x".to_owned(), "y".to_owned()]
+        );
+        g.propagate(2)?;
+        g.propagate_threaded(1, 2)?;
+        assert_eq!(log.lock().unwrap().steps, vec![0, 1, 2]);
+        //Already initialized nodes are not told again
+        log.lock().unwrap().neighbors.clear();
+        g.initialize()?;
+        assert!(log.lock().unwrap().neighbors.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_graph_initialized_after_failure() -> BPResult<()> {
+        type M = HashMap<i32, Probability>;
+        struct Counter(Arc<std::sync::atomic::AtomicUsize>);
+        impl NodeFunction<i32, M> for Counter {
+            fn node_function(
+                &mut self,
+                _inbox: Vec<(NodeIndex, M)>,
+            ) -> BPResult<Vec<(NodeIndex, M)>> {
+                Ok(Vec::new())
+            }
+            fn is_factor(&self) -> bool {
+                true
+            }
+            fn number_inputs(&self) -> Option<usize> {
+                None
+            }
+            fn initialize(&mut self, _connections: Vec<NodeIndex>) -> BPResult<()> {
+                Ok(())
+            }
+            fn is_ready(
+                &self,
+                _recv_from: &Vec<(NodeIndex, M)>,
+                _current_step: usize,
+            ) -> BPResult<bool> {
+                Ok(false)
+            }
+            fn reset(&mut self) -> BPResult<()> {
+                Ok(())
+            }
+            fn get_prior(&self) -> Option<M> {
+                None
+            }
+            fn on_graph_initialized(
+                &mut self,
+                _connections: &[NodeIndex],
+                _graph_info: &GraphInfo,
+            ) -> BPResult<()> {
+                self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                Ok(())
+            }
+        }
+        for threaded in [false, true] {
+            let count = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+            let mut g = BPGraph::<i32, M>::new();
+            let x = g.add_variable(
+                "x".to_owned(),
+                vec![(0, 0.5), (1, 0.5)].into_iter().collect(),
+            )?;
+            let y = g.add_variable(
+                "y".to_owned(),
+                vec![(0, 0.5), (1, 0.5)].into_iter().collect(),
+            )?;
+            let c = g.add_factor("counter".to_owned(), Counter(count.clone()))?;
+            let t = g.add_factor("t".to_owned(), TwoNode::new(near))?;
+            g.add_edge(x, c)?;
+            g.add_edge(x, t)?;
+            let initialize = |g: &mut BPGraph<i32, M>| {
+                if threaded {
+                    g.initialize_threaded(2)
+                } else {
+                    g.initialize()
+                }
+            };
+            //t misses a connection, the counter is initialized but the graph is not complete
+            assert!(initialize(&mut g).is_err());
+            assert!(g.get_node(c)?.is_initialized());
+            assert_eq!(count.load(std::sync::atomic::Ordering::SeqCst), 0);
+            g.add_edge(y, t)?;
+            initialize(&mut g)?;
+            assert_eq!(count.load(std::sync::atomic::Ordering::SeqCst), 1);
+            initialize(&mut g)?;
+            assert_eq!(count.load(std::sync::atomic::Ordering::SeqCst), 1);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_threading_config() -> BPResult<()> {
+        let (width, height) = (8, 6);
+        let labels = [0, 1, 2];
+        let costs: Vec<Probability> = (0..width * height)
+            .flat_map(|i| vec![(i % 3) as Probability, 0.5, 1.0])
+            .collect();
+        let potts = |a: i32, b: i32| if a == b { 0.0 } else { 0.5 };
+        let grid_graph = || -> BPResult<BPGraph<i32, HashMap<i32, Probability>>> {
+            let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+            g.add_grid(width, height, &labels, &costs, potts)?;
+            g.initialize()?;
+            Ok(g)
+        };
+        let mut g0 = grid_graph()?;
+        g0.propagate(10)?;
+        assert_eq!(g0.get_threading_config(), ThreadingConfig::default());
+        for pool in [None, Some(2)] {
+            let mut g1 = grid_graph()?;
+            g1.set_deterministic(true);
+            g1.set_thread_pool(pool);
+            g1.set_threading_config(ThreadingConfig {
+                min_batch: 1,
+                max_batch: 2,
+                strategy: BatchStrategy::WorkStealing,
+            })?;
+            g1.propagate_threaded(10, 3)?;
+            for i in 0..width * height {
+                let (r0, r1) = (g0.get_result(i)?.unwrap(), g1.get_result(i)?.unwrap());
+                assert!(labels.iter().all(|l| (r0[l] - r1[l]).abs() < 1e-12));
+            }
+        }
+        let mut g = grid_graph()?;
+        let config = ThreadingConfig {
+            min_batch: 4,
+            max_batch: 3,
+            strategy: BatchStrategy::Guided,
+        };
+        assert!(g.set_threading_config(config).is_err());
+        assert!(g
+            .set_threading_config(ThreadingConfig {
+                min_batch: 0,
+                ..config
+            })
+            .is_err());
+        assert_eq!(g.get_threading_config(), ThreadingConfig::default());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ready_report() -> BPResult<()> {
+        let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+        let mut v0 = VariableNode::new();
+        v0.set_prior(&HashMap::from([(1, 1.0)]))?;
+        g.add_node("v0".to_string(), Box::new(v0))?;
+        g.add_node("v1".to_string(), Box::new(VariableNode::new()))?;
+        g.link_variables("eq".to_string(), &[0, 1])?;
+        assert!(g.ready_report(0).is_err());
+        g.initialize()?;
+        let report = g.ready_report(0)?;
+        assert_eq!(report.len(), 3);
+        assert!(report[0].ready);
+        assert_eq!(report[0].needed, Some(0));
+        assert_eq!(report[0].input_need, Some(InputNeed::AlwaysExceptFirst));
+        assert!(!report[1].ready);
+        assert_eq!((report[1].received, report[1].needed), (0, Some(1)));
+        //v1 never sends, so the factor waits forever
+        g.propagate(1)?;
+        let report = g.ready_report(1)?;
+        assert_eq!(report[2].name, "eq");
+        assert!(!report[2].ready);
+        assert_eq!(
+            (report[2].received, report[2].needed, report[2].connections),
+            (1, Some(2), 2)
+        );
+        assert_eq!(report[2].input_need, None);
+        assert_eq!(report[0].needed, Some(1));
+        assert!(report.iter().all(|r| !r.ready));
+        Ok(())
+    }
+
+    #[test]
+    fn test_restarts() -> BPResult<()> {
+        type M = HashMap<i32, Probability>;
+        let m = |p0: Probability, p1: Probability| -> M { HashMap::from([(0, p0), (1, p1)]) };
+        let sets = vec![
+            HashMap::from([(0, m(0.8, 0.2)), (1, m(1.0, 1.0))]),
+            HashMap::from([(0, m(0.2, 0.8))]),
+        ];
+        let avg = average_marginals(&sets, Some(&[3.0, 1.0]))?;
+        assert!((avg[&0][&0] - 0.65).abs() < 1e-12);
+        assert!((avg[&1][&1] - 0.5).abs() < 1e-12);
+        assert!(average_marginals::<i32>(&[], None).is_err());
+        assert!(average_marginals(&sets, Some(&[1.0])).is_err());
+        assert!(average_marginals(&sets, Some(&[1.0, -1.0])).is_err());
+        let report = disagreement_report(&sets)?;
+        assert_eq!(report.len(), 1);
+        assert_eq!(
+            (report[0].node, report[0].runs, report[0].majority),
+            (0, 2, 0)
+        );
+        assert!((report[0].max_total_variation - 0.6).abs() < 1e-12);
+        assert!((report[0].mean_total_variation - 0.3).abs() < 1e-12);
+        assert!((report[0].agreement - 0.5).abs() < 1e-12);
+
+        //A tree has a single fixed point, all restarts agree
+        let mut g = BPGraph::<i32, M>::new();
+        let table: HashMap<(i32, i32), Probability> =
+            HashMap::from([((0, 0), 0.8), ((0, 1), 0.2), ((1, 0), 0.3), ((1, 1), 0.7)]);
+        let a = g.add_variable("a".to_owned(), m(0.6, 0.4))?;
+        let b = g.add_variable("b".to_owned(), m(0.5, 0.5))?;
+        let f = g.add_factor("f".to_owned(), PairwiseFactor::new(table))?;
+        g.add_edge(f, a)?;
+        g.add_edge(f, b)?;
+        let runs = g.run_restarts(&[1, 2, 3], 0.5, 10)?;
+        assert_eq!(runs.len(), 3);
+        assert!(!g.is_initialized());
+        for d in disagreement_report(&runs)? {
+            assert!(d.max_total_variation < 1e-9);
+            assert_eq!(d.agreement, 1.0);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_borrowed_inbox() -> BPResult<()> {
+        type M = HashMap<i32, Probability>;
+        //Swaps the messages of its two neighbours, node_function must not be called
+        struct Swap(Vec<NodeIndex>);
+        impl NodeFunction<i32, M> for Swap {
+            fn node_function(
+                &mut self,
+                _inbox: Vec<(NodeIndex, M)>,
+            ) -> BPResult<Vec<(NodeIndex, M)>> {
+                Err(BPError::new(
+                    "Swap::node_function".to_owned(),
+                    "Messages were moved".to_owned(),
+                ))
+            }
+            fn node_function_borrowed(
+                &mut self,
+                inbox: Vec<(NodeIndex, &M)>,
+            ) -> BPResult<Vec<(NodeIndex, M)>> {
+                Ok(inbox
+                    .iter()
+                    .map(|(from, msg)| {
+                        (*self.0.iter().find(|c| *c != from).unwrap(), (*msg).clone())
+                    })
+                    .collect())
+            }
+            fn borrows_inbox(&self) -> bool {
+                true
+            }
+            fn is_factor(&self) -> bool {
+                true
+            }
+            fn number_inputs(&self) -> Option<usize> {
+                Some(2)
+            }
+            fn initialize(&mut self, connections: Vec<NodeIndex>) -> BPResult<()> {
+                self.0 = connections;
+                Ok(())
+            }
+            fn is_ready(
+                &self,
+                recv_from: &Vec<(NodeIndex, M)>,
+                _current_step: usize,
+            ) -> BPResult<bool> {
+                Ok(recv_from.len() == 2)
+            }
+            fn reset(&mut self) -> BPResult<()> {
+                Ok(())
+            }
+            fn get_prior(&self) -> Option<M> {
+                None
+            }
+        }
+        let mut g = BPGraph::<i32, M>::new();
+        let a = g.add_variable("a".to_owned(), HashMap::from([(0, 0.25), (1, 0.75)]))?;
+        let b = g.add_variable("b".to_owned(), HashMap::from([(0, 0.5), (1, 0.5)]))?;
+        let f = g.add_factor("swap".to_owned(), Swap(Vec::new()))?;
+        g.add_edge(f, a)?;
+        g.add_edge(f, b)?;
+        g.initialize()?;
+        g.propagate(2)?;
+        let res = g.get_result(b)?.unwrap();
+        assert!((res[&1] / (res[&0] + res[&1]) - 0.75).abs() < 1e-12);
+
+        //Built-in factors give the same results with both variants
+        let mut eq = EqualityFactor::<i32, M>::new();
+        NodeFunction::<i32, M>::initialize(&mut eq, vec![0, 1, 2])?;
+        let inbox: Vec<(NodeIndex, M)> = (0..3)
+            .map(|i| (i, HashMap::from([(0, 1.0 + i as f64), (1, 1.0)])))
+            .collect();
+        let borrowed = NodeFunction::<i32, M>::node_function_borrowed(
+            &mut eq,
+            inbox.iter().map(|(i, m)| (*i, m)).collect(),
+        )?;
+        assert_eq!(
+            NodeFunction::<i32, M>::node_function(&mut eq, inbox)?,
+            borrowed
+        );
+        assert!(NodeFunction::<i32, M>::borrows_inbox(&eq));
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_rank() -> BPResult<()> {
+        type M = HashMap<i32, Probability>;
+        let marginals: Vec<M> = vec![
+            HashMap::from([(0, 0.5), (1, 0.3), (2, 0.15), (3, 0.05)]),
+            HashMap::from([(0, 0.1), (1, 0.6), (2, 0.2), (3, 0.1)]),
+            HashMap::from([(0, 0.25), (1, 0.25), (2, 0.4), (3, 0.1)]),
+        ];
+        let prob = |key: &[i32]| -> Probability {
+            key.iter().zip(&marginals).map(|(k, m)| m[k]).product()
+        };
+        let keys = enumerate_keys(&marginals, 64)?;
+        assert_eq!(keys.len(), 64);
+        assert_eq!(keys[0].0, vec![0, 1, 2]);
+        assert!((keys[0].1 - 0.12).abs() < 1e-12);
+        assert!(keys.windows(2).all(|w| w[0].1 >= w[1].1));
+        for (key, p) in &keys {
+            assert!((prob(key) - p).abs() < 1e-12);
+        }
+        for key in [vec![0, 1, 2], vec![1, 2, 0], vec![3, 0, 3]] {
+            let true_rank =
+                1.0 + keys.iter().filter(|(_, p)| *p > prob(&key) + 1e-12).count() as f64;
+            for bins in [1, 8, 1000] {
+                let rank = estimate_key_rank(&marginals, &key, bins)?;
+                assert!(rank.lower <= true_rank && true_rank <= rank.upper);
+                assert!(rank.lower <= rank.estimate && rank.estimate <= rank.upper);
+            }
+            assert!(
+                estimate_key_rank(&marginals, &key, 100000)?.upper
+                    - estimate_key_rank(&marginals, &key, 100000)?.lower
+                    <= 8.0
+            );
+        }
+        assert_eq!(estimate_key_rank(&marginals, &[0, 1, 2], 1000)?.lower, 1.0);
+        assert!(estimate_key_rank(&marginals, &[0, 1], 10).is_err());
+        //Impossible keys rank after all possible ones
+        let mut impossible = marginals.clone();
+        impossible[0].insert(4, 0.0);
+        assert_eq!(estimate_key_rank(&impossible, &[4, 0, 0], 10)?.lower, 65.0);
+
+        let mut g = BPGraph::<i32, M>::new();
+        let k0 = g.add_variable("k0".to_owned(), marginals[0].clone())?;
+        g.initialize()?;
+        g.propagate(1)?;
+        assert_eq!(enumerate_keys(&g.key_marginals(&[k0])?, 1)?[0].0, vec![0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_ntt_convolution() -> BPResult<()> {
+        //Deterministic positive test data
+        let data = |q: usize, seed: usize| -> Vec<Probability> {
+            (0..q)
+                .map(|i| ((i * 7919 + seed * 104729) % 1000) as Probability / 1000.0 + 0.001)
+                .collect()
+        };
+        for q in [1, 5, 64, 3329] {
+            let (x, y) = (data(q, 1), data(q, 2));
+            let direct = modular_factor::cyclic_convolution(&x, &y);
+            let correlation = modular_factor::cyclic_correlation(&x, &y);
+            for plan in [NttPlan::new(q)?, NttPlan::fft(q)?] {
+                let max = direct.iter().copied().fold(0.0, f64::max);
+                let tolerance = match plan.backend() {
+                    ConvolutionBackend::Ntt { bits, .. } => max * 4.0 / (1u64 << bits) as f64,
+                    ConvolutionBackend::Fft => max * 1e-10,
+                };
+                for (a, b) in plan.convolve(&x, &y)?.iter().zip(&direct) {
+                    assert!((a - b).abs() <= tolerance);
+                }
+                for (a, b) in plan.correlate(&x, &y)?.iter().zip(&correlation) {
+                    assert!((a - b).abs() <= tolerance);
+                }
+            }
+        }
+        assert!(matches!(
+            NttPlan::new(3329)?.backend(),
+            ConvolutionBackend::Ntt { bits: 24, .. }
+        ));
+        //Entries too small to be quantized are not lost
+        let plan = NttPlan::new(8)?;
+        assert!(matches!(plan.backend(), ConvolutionBackend::Ntt { .. }));
+        let small = [1.0, 1e-12, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+        let delta = [1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+        assert!((plan.convolve(&small, &delta)?[1] / 1e-12 - 1.0).abs() < 1e-3);
+        assert!(NttPlan::new(0).is_err());
+        assert!(NttPlan::new(5)?.convolve(&[1.0; 4], &[1.0; 5]).is_err());
+        assert!(Arc::ptr_eq(
+            &NttPlan::shared(17)?,
+            NttConvolutionFactor::with_modulus(17)?.plan()
+        ));
+
+        //Same messages as ModAddFactor
+        let q = 17;
+        let mut results = Vec::new();
+        for ntt in [false, true] {
+            let mut g = BPGraph::<usize, DenseMsg>::new();
+            let vars: Vec<NodeIndex> = (0..3)
+                .map(|i| g.add_variable(format!("v{}", i), DenseMsg::from_vec(data(q, i))))
+                .collect::<BPResult<_>>()?;
+            let add = if ntt {
+                g.add_factor("add".to_owned(), NttConvolutionFactor::with_modulus(q)?)?
+            } else {
+                g.add_factor("add".to_owned(), ModAddFactor::new(q))?
+            };
+            for v in &vars {
+                g.add_edge(add, *v)?;
+            }
+            g.initialize()?;
+            g.propagate(2)?;
+            results.push(
+                vars.iter()
+                    .map(|v| g.get_result(*v).map(|r| r.unwrap()))
+                    .collect::<BPResult<Vec<_>>>()?,
+            );
+        }
+        for (r0, r1) in results[0].iter().zip(&results[1]) {
+            for v in 0..q {
+                assert!((r0[&v] - r1[&v]).abs() < 1e-6);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_modular_factor_zero_modulus() -> BPResult<()> {
+        let mut g = BPGraph::<usize, DenseMsg>::new();
+        let x = g.add_variable("x".to_owned(), DenseMsg::uniform(2))?;
+        let y = g.add_variable("y".to_owned(), DenseMsg::uniform(2))?;
+        let mul = g.add_factor("mul".to_owned(), ModMulFactor::new(3, 0))?;
+        g.add_edge(mul, x)?;
+        g.add_edge(mul, y)?;
+        assert!(g.initialize().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_mod_add_factor_ntt() -> BPResult<()> {
+        let data = |q: usize, seed: usize| -> Vec<Probability> {
+            (0..q)
+                .map(|i| ((i * 7919 + seed * 104729) % 1000) as Probability / 1000.0 + 0.001)
+                .collect()
+        };
+        //(q, NTT requested, NTT used)
+        for (q, use_ntt, ntt) in [
+            (7, true, false),
+            (48, true, false),
+            (64, false, false),
+            (64, true, true),
+            (256, true, true),
+        ] {
+            let mut g = BPGraph::<usize, DenseMsg>::new();
+            let vars: Vec<NodeIndex> = (0..3)
+                .map(|i| g.add_variable(format!("v{}", i), DenseMsg::from_vec(data(q, i))))
+                .collect::<BPResult<_>>()?;
+            let mut factor = ModAddFactor::new(q);
+            factor.set_use_ntt(use_ntt);
+            let add = g.add_factor("add".to_owned(), factor)?;
+            for v in &vars {
+                g.add_edge(add, *v)?;
+            }
+            g.initialize()?;
+            assert_eq!(g.get_node_function::<ModAddFactor>(add)?.uses_ntt(), ntt);
+            g.propagate(2)?;
+            //Messages of the direct computation
+            let (x, y, z) = (data(q, 0), data(q, 1), data(q, 2));
+            let expected = [
+                modular_factor::cyclic_correlation(&y, &z),
+                modular_factor::cyclic_correlation(&x, &z),
+                modular_factor::cyclic_convolution(&x, &y),
+            ];
+            for ((v, prior), msg) in vars.iter().zip([x, y, z]).zip(expected) {
+                let mut belief: Vec<Probability> =
+                    prior.iter().zip(&msg).map(|(a, b)| a * b).collect();
+                let sum: Probability = belief.iter().sum();
+                belief.iter_mut().for_each(|p| *p /= sum);
+                let res = g.get_result(*v)?.unwrap();
+                for (value, p) in belief.iter().enumerate() {
+                    //Relative error, the NTT is approximate
+                    assert!((res[&value] - p).abs() < 1e-6 * p);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_region_graph() -> BPResult<()> {
+        let (width, height) = (3, 3);
+        let labels = [0, 1];
+        let costs: Vec<Probability> = (0..width * height)
+            .flat_map(|i| vec![0.0, [0.4, -0.3, 0.2, 0.6, -0.5][i % 5]])
+            .collect();
+        let potts = |a: i32, b: i32| if a == b { 0.0 } else { 0.8 };
+        let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+        let grid = g.add_grid(width, height, &labels, &costs, potts)?;
+        let exact = g.exact_marginals_junction_tree(1 << 12)?;
+        g.initialize()?;
+        g.propagate(100)?;
+        let error = |marginal: &dyn Fn(NodeIndex) -> HashMap<i32, Probability>| {
+            grid.variables()
+                .map(|v| {
+                    let m = marginal(v);
+                    (m[&1] / (m[&0] + m[&1]) - exact[&v][&1]).abs()
+                })
+                .fold(0.0, f64::max)
+        };
+        let bp_error = error(&|v| g.get_result(v).unwrap().unwrap());
+
+        //The neighbourhoods of the factors as outer regions give loopy BP
+        let pairs: Vec<Vec<NodeIndex>> = g
+            .nodes()
+            .filter(|(_, _, is_factor)| *is_factor)
+            .map(|(f, _, _)| g.get_connections(f).unwrap().clone())
+            .collect();
+        let mut bethe = RegionGraph::new(&g, &pairs, 1 << 12)?;
+        assert!(bethe.propagate(200, 1e-12) < 200);
+        for v in grid.variables() {
+            let (m, r) = (bethe.get_marginal(v).unwrap(), g.get_result(v)?.unwrap());
+            assert!((m[&1] - r[&1] / (r[&0] + r[&1])).abs() < 1e-6);
+        }
+        assert!(bethe
+            .regions()
+            .iter()
+            .all(|(r, c)| if r.len() == 1 { *c < 0 } else { *c == 1 }));
+
+        //Squares are more accurate
+        let mut kikuchi = RegionGraph::new(&g, &grid.squares(), 1 << 12)?;
+        kikuchi.set_damping(0.3)?;
+        assert!(kikuchi.propagate(500, 1e-12) < 500);
+        let gbp_error = error(&|v| kikuchi.get_marginal(v).unwrap());
+        assert!(gbp_error < bp_error / 2.0);
+        //4 squares, 4 pairs, 1 center
+        let regions = kikuchi.regions();
+        assert_eq!(regions.len(), 9);
+        assert!(regions.contains(&(vec![grid.variable(1, 1).unwrap()], 1)));
+        assert_eq!(regions.iter().map(|(_, c)| c).sum::<i64>(), 1);
+        assert!(kikuchi.marginals().len() == width * height);
+
+        assert!(RegionGraph::new(&g, &grid.squares()[..3], 1 << 12).is_err());
+        assert!(RegionGraph::new(&g, &grid.squares(), 8).is_err());
+        assert!(kikuchi.set_damping(1.0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sorted_results() -> BPResult<()> {
+        type M = HashMap<i32, Probability>;
+        let mut g = BPGraph::<i32, M>::new();
+        let a = g.add_variable(
+            "a".to_owned(),
+            HashMap::from([(2, 0.5), (0, 0.25), (1, 0.25)]),
+        )?;
+        let b = g.add_variable("b \"x\"".to_owned(), HashMap::from([(0, 0.4), (1, 0.6)]))?;
+        let table: HashMap<(i32, i32), Probability> = (0..3)
+            .flat_map(|x| (0..2).map(move |y| ((x, y), 1.0)))
+            .collect();
+        let f = g.add_factor("f".to_owned(), PairwiseFactor::new(table))?;
+        g.add_edge(f, a)?;
+        g.add_edge(f, b)?;
+        g.initialize()?;
+        g.propagate(2)?;
+        let by_value = g.get_result_sorted(a, ResultOrder::ByValue)?.unwrap();
+        assert_eq!(
+            by_value.iter().map(|(v, _)| *v).collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+        let by_prob = g.get_result_sorted(a, ResultOrder::ByProbability)?.unwrap();
+        assert_eq!(
+            by_prob.iter().map(|(v, _)| *v).collect::<Vec<_>>(),
+            vec![2, 0, 1]
+        );
+        assert!((by_prob[0].1 - 0.5).abs() < 1e-12);
+
+        let mut csv = Vec::new();
+        g.export_marginals_csv(&mut csv)?;
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[0], "node,name,value,probability");
+        assert_eq!(lines[1], "0,\"a\",\"0\",0.25");
+        assert!(lines[4].starts_with("1,\"b \"\"x\"\"\",\"0\","));
+        let mut again = Vec::new();
+        g.export_marginals_csv(&mut again)?;
+        assert_eq!(csv.as_bytes(), &again[..]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_prior_validation() -> BPResult<()> {
+        type M = HashMap<i32, Probability>;
+        let mut v = VariableNode::<i32, M>::new();
+        assert!(v.set_prior(&M::new()).is_err());
+        assert!(v
+            .set_prior(&HashMap::from([(0, 1.0), (1, Probability::NAN)]))
+            .is_err());
+        assert!(v.set_prior(&HashMap::from([(0, 1.0), (1, -0.5)])).is_err());
+        assert!(v.set_prior(&HashMap::from([(0, 0.0), (1, 0.0)])).is_err());
+        assert!(v.get_priors().is_empty());
+        v.set_prior(&HashMap::from([(0, 3.0), (1, 1.0)]))?;
+        assert_eq!(v.get_priors()[0].0, HashMap::from([(0, 0.75), (1, 0.25)]));
+        assert!(v.set_prior(&HashMap::from([(0, 1.0)])).is_err());
+
+        let mut v = VariableNode::<i32, M>::new();
+        v.set_normalize_prior(false);
+        v.set_prior(&HashMap::from([(0, 3.0), (1, 1.0)]))?;
+        assert_eq!(v.get_priors()[0].0, HashMap::from([(0, 3.0), (1, 1.0)]));
+
+        //Costs are not probabilities
+        let mut v = VariableNode::<i32, M>::new();
+        NodeFunction::<i32, M, (), ()>::set_semiring(&mut v, Arc::new(MinSum));
+        v.set_prior(&HashMap::from([(0, -1.0), (1, 2.0)]))?;
+        assert_eq!(v.get_priors()[0].0, HashMap::from([(0, -1.0), (1, 2.0)]));
+        let mut v = VariableNode::<i32, M>::new();
+        v.set_prior_unchecked(&HashMap::from([(0, -1.0), (1, Probability::NAN)]))?;
+        assert_eq!(v.get_priors().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_prior_validation_graph() -> BPResult<()> {
+        type M = HashMap<i32, Probability>;
+        //Priors of add_variable are checked in initialize
+        let mut g = BPGraph::<i32, M, VariableNodeCtrl<M>, VariableNodeCtrlAnswer<M>>::new();
+        let v = g.add_variable("v".to_owned(), HashMap::from([(0, 3.0), (1, 1.0)]))?;
+        g.initialize()?;
+        let prior = match g.send_control_message(v, VariableNodeCtrl::GetPrior)? {
+            VariableNodeCtrlAnswer::Prior(prior) => prior.unwrap(),
+            _ => panic!("Expected a prior"),
+        };
+        assert_eq!(prior, HashMap::from([(0, 0.75), (1, 0.25)]));
+        assert!(g
+            .send_control_message(
+                v,
+                VariableNodeCtrl::SetPrior(Some(HashMap::from([(0, -1.0), (1, 1.0)])))
+            )
+            .is_err());
+        assert!(g
+            .send_control_message(
+                v,
+                VariableNodeCtrl::SetPrior(Some(HashMap::from([(0, Probability::NAN)])))
+            )
+            .is_err());
+        g.send_control_message(
+            v,
+            VariableNodeCtrl::SetPrior(Some(HashMap::from([(0, 1.0), (1, 1.0)]))),
+        )?;
+        g.send_control_message(v, VariableNodeCtrl::SetPrior(None))?;
+
+        let mut g = BPGraph::<i32, M>::new();
+        g.add_variable("v".to_owned(), HashMap::from([(0, 0.0), (1, 0.0)]))?;
+        assert!(g.initialize().is_err());
+        let mut g = BPGraph::<i32, M>::new();
+        g.add_node(
+            "v".to_owned(),
+            Box::new(
+                VariableNode::builder()
+                    .prior_unchecked(HashMap::from([(0, -1.0)]))
+                    .build(),
+            ),
+        )?;
+        g.initialize()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_batched() -> BPResult<()> {
+        type M = HashMap<i32, Probability>;
+        let table: HashMap<(i32, i32), Probability> =
+            HashMap::from([((0, 0), 0.9), ((0, 1), 0.1), ((1, 0), 0.2), ((1, 1), 0.8)]);
+        let chain = |priors: Vec<M>| -> BPResult<BPGraph<i32, M>> {
+            let mut g = BPGraph::<i32, M>::new();
+            for (i, prior) in priors.into_iter().enumerate() {
+                g.add_variable(format!("v{}", i), prior)?;
+            }
+            for i in 0..2 {
+                let f = g.add_factor(format!("f{}", i), PairwiseFactor::new(table.clone()))?;
+                g.add_edge(f, i)?;
+                g.add_edge(f, i + 1)?;
+            }
+            Ok(g)
+        };
+        let m = |p0: Probability| -> M { HashMap::from([(0, p0), (1, 1.0 - p0)]) };
+        let observations = [
+            vec![m(0.7), m(0.5), m(0.5)],
+            vec![m(0.1), m(0.5), m(0.9)],
+            vec![m(0.5), m(0.3), m(0.4)],
+        ];
+
+        let mut batched = BatchedBPGraph::new(&chain(vec![m(0.5); 3])?, 3, 1 << 10)?;
+        assert_eq!(batched.batch_size(), 3);
+        for v in 0..3 {
+            let priors: Vec<M> = observations.iter().map(|o| o[v].clone()).collect();
+            batched.set_priors(v, &priors)?;
+        }
+        assert!(batched.propagate(20, 1e-12) < 20);
+        for (b, o) in observations.iter().enumerate() {
+            let mut g = chain(o.clone())?;
+            g.initialize()?;
+            g.propagate(10)?;
+            let marginals = batched.marginals(b);
+            assert_eq!(marginals.len(), 3);
+            for v in 0..3 {
+                let expected = g.get_result(v)?.unwrap();
+                for x in 0..2 {
+                    assert!((marginals[&v][&x] - expected[&x]).abs() < 1e-9);
+                }
+            }
+        }
+        assert!(batched.marginal(0, 3).is_none());
+        assert!(batched.set_prior(0, 3, &m(0.5)).is_err());
+        assert!(batched.set_prior(0, 0, &HashMap::from([(2, 1.0)])).is_err());
+        assert!(batched.set_prior(3, 0, &m(0.5)).is_err());
+        assert!(batched.set_priors(0, &[m(0.5)]).is_err());
+        assert!(BatchedBPGraph::new(&chain(vec![m(0.5); 3])?, 0, 1 << 10).is_err());
+        Ok(())
+    }
+
     #[test]
-    fn test() -> BPResult<()> {
+    fn test_simd_dense_msg() -> BPResult<()> {
+        //Odd length, so that the tail after the last full vector is covered
+        let n = 1027;
+        let mut rng = crate::SplitMix64::new(7);
+        let a: Vec<Probability> = (0..n).map(|_| rng.next_f64()).collect();
+        let b: Vec<Probability> = (0..n).map(|_| rng.next_f64()).collect();
+
+        let mut prod = DenseMsg::from_vec(a.clone());
+        prod.mult_msg(&DenseMsg::from_vec(b.clone()));
+        let expected: Vec<Probability> = a.iter().zip(&b).map(|(x, y)| x * y).collect();
+        let max = expected.iter().fold(0.0, |acc: Probability, p| acc.max(*p));
+        for (p, e) in prod.as_slice().iter().zip(&expected) {
+            assert!((p - e / max).abs() < 1e-12);
+        }
+        assert!(prod.as_slice().contains(&1.0));
+
+        let mut normalized = DenseMsg::from_vec(a.clone());
+        normalized.normalize()?;
+        let sum: Probability = a.iter().sum();
+        for (p, x) in normalized.as_slice().iter().zip(&a) {
+            assert!((p - x / sum).abs() < 1e-12);
+        }
+        assert!(DenseMsg::zeros(n).normalize().is_err());
+        let mut nan = DenseMsg::from_vec(a.clone());
+        nan.insert(5, Probability::NAN);
+        assert!(nan.normalize().is_err());
+
+        let mut mixed = DenseMsg::from_vec(a.clone());
+        mixed.add_msg_weighted(&DenseMsg::from_vec(b.clone()), 0.25, 0.75);
+        for ((p, x), y) in mixed.as_slice().iter().zip(&a).zip(&b) {
+            assert!((p - (0.25 * x + 0.75 * y)).abs() < 1e-12);
+        }
+
+        //Entries beyond the length of the other message are not scaled
+        let mut longer = DenseMsg::from_vec(a[..5].to_vec());
+        longer.add_msg_weighted(&DenseMsg::from_vec(b[..3].to_vec()), 0.25, 0.75);
+        for i in 0..5 {
+            let e = if i < 3 {
+                0.25 * a[i] + 0.75 * b[i]
+            } else {
+                a[i]
+            };
+            assert!((longer.as_slice()[i] - e).abs() < 1e-12);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_result_msg() -> BPResult<()> {
+        let q = 5;
+        let mut g = BPGraph::<usize, DenseMsg>::new();
+        let x = g.add_variable(
+            "x".to_owned(),
+            DenseMsg::from_vec(vec![0.1, 0.2, 0.3, 0.2, 0.2]),
+        )?;
+        let y = g.add_variable(
+            "y".to_owned(),
+            DenseMsg::from_vec(vec![2.0, 1.0, 1.0, 0.0, 1.0]),
+        )?;
+        let z = g.add_variable("z".to_owned(), DenseMsg::uniform(q))?;
+        let add = g.add_factor("add".to_owned(), ModAddFactor::new(q))?;
+        g.add_edge(add, x)?;
+        g.add_edge(add, y)?;
+        g.add_edge(add, z)?;
+        //Before propagating, the result is the normalized prior
+        let prior = g.get_result_msg(y)?.unwrap();
+        assert_eq!(prior.as_slice(), &[0.4, 0.2, 0.2, 0.0, 0.2]);
+        assert!(g.get_result_msg(add)?.is_none());
+        g.initialize()?;
+        g.propagate(2)?;
+        for v in [x, y, z].iter() {
+            let msg = g.get_result_msg(*v)?.unwrap();
+            let map = g.get_result(*v)?.unwrap();
+            assert_eq!(msg.len(), q);
+            for (value, p) in msg.into_iter() {
+                assert!((p - map[&value]).abs() < 1e-12);
+            }
+        }
+        assert!(g.get_result_msg(add)?.is_none());
+        assert!(g.get_result_msg(10).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_memoized_factor() -> BPResult<()> {
+        type M = HashMap<i32, Probability>;
+        let table: HashMap<(i32, i32), Probability> =
+            HashMap::from([((0, 0), 0.9), ((0, 1), 0.1), ((1, 0), 0.2), ((1, 1), 0.8)]);
+        let chain = |memoize: bool| -> BPResult<BPGraph<i32, M>> {
+            let mut g = BPGraph::<i32, M>::new();
+            let a = g.add_variable("a".to_owned(), HashMap::from([(0, 0.3), (1, 0.7)]))?;
+            let b = g.add_variable("b".to_owned(), HashMap::from([(0, 0.6), (1, 0.4)]))?;
+            let c = g.add_variable("c".to_owned(), HashMap::from([(0, 0.5), (1, 0.5)]))?;
+            for (i, (x, y)) in [(a, b), (b, c)].iter().enumerate() {
+                let pairwise = PairwiseFactor::new(table.clone());
+                let f = if memoize {
+                    g.add_factor(
+                        format!("f{}", i),
+                        MemoizedFactor::new(Box::new(pairwise), 1e-12),
+                    )?
+                } else {
+                    g.add_factor(format!("f{}", i), pairwise)?
+                };
+                g.add_edge(f, *x)?;
+                g.add_edge(f, *y)?;
+            }
+            g.initialize()?;
+            g.propagate(10)?;
+            Ok(g)
+        };
+        let plain = chain(false)?;
+        let mut memoized = chain(true)?;
+        for v in 0..3 {
+            let (p, m) = (
+                plain.get_result(v)?.unwrap(),
+                memoized.get_result(v)?.unwrap(),
+            );
+            for x in 0..2 {
+                assert!((p[&x] - m[&x]).abs() < 1e-12);
+            }
+        }
+        //The inputs of the factors do not change once the chain has converged
+        let f0 = memoized.get_node_function::<MemoizedFactor<i32, M>>(3)?;
+        assert!(f0.hits() > 0);
+        assert!(f0.misses() < 10);
+        let (hits, misses) = (f0.hits(), f0.misses());
+        memoized
+            .get_node_function_mut::<MemoizedFactor<i32, M>>(3)?
+            .clear_cache();
+        //Factors compute their messages every other step
+        memoized.propagate(2)?;
+        let f0 = memoized.get_node_function::<MemoizedFactor<i32, M>>(3)?;
+        assert_eq!((f0.hits(), f0.misses()), (hits, misses + 1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_ldpc() -> BPResult<()> {
+        //Hamming(7, 4)
+        let checks = vec![vec![0, 1, 3, 4], vec![0, 2, 3, 5], vec![1, 2, 3, 6]];
+        //All-zero codeword, bit 2 received (weakly) wrong
+        let mut llrs = vec![2.0; 7];
+        llrs[2] = -0.5;
+        for algorithm in [LdpcAlgorithm::SumProduct, LdpcAlgorithm::MinSum] {
+            let mut decoder = LdpcDecoder::new(7, checks.clone(), algorithm)?;
+            let res = decoder.decode(&llrs, 10)?;
+            assert!(res.is_codeword());
+            assert_eq!(res.codeword, vec![false; 7]);
+            assert!(res.iterations > 0);
+            //Decoding again starts from scratch
+            assert_eq!(decoder.decode(&llrs, 10)?, res);
+            assert_eq!(
+                decoder.syndrome(&[false, false, true, false, false, false, false]),
+                vec![1, 2]
+            );
+        }
+        assert!(LdpcDecoder::new(7, vec![vec![0, 7]], LdpcAlgorithm::MinSum).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_propagate_forward() -> BPResult<()> {
+        let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+        let uniform: HashMap<i32, Probability> = vec![(0, 0.5), (1, 0.5)].into_iter().collect();
+        let a = g.add_variable(
+            "a".to_owned(),
+            vec![(0, 0.3), (1, 0.7)].into_iter().collect(),
+        )?;
+        let b = g.add_variable("b".to_owned(), uniform.clone())?;
+        let c = g.add_variable("c".to_owned(), uniform)?;
+        let cpt = |p0: Probability, p1: Probability| -> HashMap<(i32, i32), Probability> {
+            vec![
+                ((0, 0), 1.0 - p0),
+                ((0, 1), p0),
+                ((1, 0), 1.0 - p1),
+                ((1, 1), p1),
+            ]
+            .into_iter()
+            .collect()
+        };
+        let f_ab = g.add_pairwise_potential(a, b, cpt(0.2, 0.9))?;
+        let f_bc = g.add_pairwise_potential(b, c, cpt(0.5, 0.1))?;
+        for (parent, child) in [(a, f_ab), (f_ab, b), (b, f_bc), (f_bc, c)] {
+            g.set_edge_directed(parent, child)?;
+        }
+        assert!(g.set_edge_directed(b, f_ab).is_err());
+        g.initialize()?;
+        g.propagate_forward()?;
+        assert!((g.get_result(b)?.unwrap()[&1] - 0.69).abs() < 1e-12);
+        assert!((g.get_result(c)?.unwrap()[&1] - 0.224).abs() < 1e-12);
+        Ok(())
+    }
+
+    #[test]
+    fn test_ready_policy() -> BPResult<()> {
+        type M = HashMap<i32, Probability>;
+        let prior: M = vec![(0, 0.5), (1, 0.5)].into_iter().collect();
+        let mut v = VariableNode::builder()
+            .prior(prior.clone())
+            .ready_policy(|received: usize, total: usize, _step: usize| 5 * received >= 4 * total)
+            .build();
+        NodeFunction::<i32, M>::initialize(&mut v, (0..5).collect())?;
+        let inbox: Vec<(NodeIndex, M)> = (0..4).map(|i| (i, prior.clone())).collect();
+        assert!(!NodeFunction::<i32, M>::is_ready(
+            &v,
+            &inbox[..3].to_vec(),
+            0
+        )?);
+        assert!(NodeFunction::<i32, M>::is_ready(&v, &inbox, 0)?);
+
+        v.set_ready_policy(|_: usize, _: usize, step: usize| step % 2 == 0);
+        assert!(NodeFunction::<i32, M>::is_ready(&v, &Vec::new(), 2)?);
+        assert!(!NodeFunction::<i32, M>::is_ready(&v, &inbox, 3)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_shared_msg() -> BPResult<()> {
+        type M = SharedMsg<HashMap<i32, Probability>>;
+        let mut g = BPGraph::<i32, M>::new();
+        let mut g_plain = BPGraph::<i32, HashMap<i32, Probability>>::new();
+        let prior: HashMap<i32, Probability> =
+            (0..3).map(|v| (v, (v + 1) as Probability / 6.0)).collect();
+        g.add_variable("v".to_owned(), SharedMsg::new(prior.clone()))?;
+        g_plain.add_variable("v".to_owned(), prior)?;
+        for i in 0..3 {
+            let other: HashMap<i32, Probability> = (0..3)
+                .map(|v| (v, if v == i { 0.5 } else { 0.25 }))
+                .collect();
+            g.add_variable(format!("w{}", i), SharedMsg::new(other.clone()))?;
+            g_plain.add_variable(format!("w{}", i), other)?;
+            g.add_factor(format!("f{}", i), TwoNode::new(near))?;
+            g_plain.add_factor(format!("f{}", i), TwoNode::new(near))?;
+            g.add_edge(0, 2 * i as usize + 2)?;
+            g.add_edge(2 * i as usize + 1, 2 * i as usize + 2)?;
+            g_plain.add_edge(0, 2 * i as usize + 2)?;
+            g_plain.add_edge(2 * i as usize + 1, 2 * i as usize + 2)?;
+        }
+        g.initialize()?;
+        g_plain.initialize()?;
+        g.propagate(1)?;
+        //The prior of v is broadcast without copies
+        let received: Vec<M> = [2, 4, 6]
+            .iter()
+            .map(|f| {
+                g.get_node(*f)
+                    .unwrap()
+                    .clone_inbox()
+                    .into_iter()
+                    .find(|(from, _)| *from == 0)
+                    .unwrap()
+                    .1
+            })
+            .collect();
+        assert!(received[0].ptr_eq(&received[1]) && received[0].ptr_eq(&received[2]));
+        g.propagate(3)?;
+        g_plain.propagate(4)?;
+        for n in 0..7 {
+            if let Some(res) = g.get_result(n)? {
+                let plain = g_plain.get_result(n)?.unwrap();
+                for (v, p) in res {
+                    assert!((p - plain[&v]).abs() < 1e-12);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_edge_transform() -> BPResult<()> {
         let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+        let dist: HashMap<i32, Probability> = (1..5).map(|v| (v, 0.1 * v as Probability)).collect();
+        g.add_variable("v0".to_owned(), dist)?;
+        g.add_variable("v1".to_owned(), (1..5).map(|v| (v, 0.25)).collect())?;
+        g.add_factor("t".to_owned(), TwoNode::new(near))?;
+        g.add_edge(0, 2)?;
+        g.add_edge(2, 1)?;
+        assert!(g.set_edge_transform(0, 1, Box::new(Ok)).is_err());
+        //Reverses the values
+        g.set_edge_transform(
+            2,
+            1,
+            Box::new(|msg: HashMap<i32, Probability>| {
+                Ok(msg.into_iter().map(|(v, p)| (5 - v, p)).collect())
+            }),
+        )?;
+        g.initialize()?;
+        g.propagate(2)?;
+        let res = g.get_result(1)?.unwrap();
+        g.remove_edge_transform(2, 1);
+        g.propagate(2)?;
+        let plain = g.get_result(1)?.unwrap();
+        for v in 1..5 {
+            assert!((res[&v] - plain[&(5 - v)]).abs() < 1e-12);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_particle_msg() -> BPResult<()> {
+        struct Evidence(ParticleMsg, Option<NodeIndex>);
+        impl NodeFunction<f64, ParticleMsg> for Evidence {
+            fn node_function(
+                &mut self,
+                _inbox: Vec<(NodeIndex, ParticleMsg)>,
+            ) -> BPResult<Vec<(NodeIndex, ParticleMsg)>> {
+                Ok(vec![(self.1.unwrap(), self.0.clone())])
+            }
+            fn is_factor(&self) -> bool {
+                true
+            }
+            fn number_inputs(&self) -> Option<usize> {
+                Some(1)
+            }
+            fn initialize(&mut self, connections: Vec<NodeIndex>) -> BPResult<()> {
+                self.1 = Some(connections[0]);
+                Ok(())
+            }
+            fn is_ready(
+                &self,
+                _recv_from: &Vec<(NodeIndex, ParticleMsg)>,
+                _current_step: usize,
+            ) -> BPResult<bool> {
+                Ok(true)
+            }
+            fn reset(&mut self) -> BPResult<()> {
+                Ok(())
+            }
+            fn get_prior(&self) -> Option<ParticleMsg> {
+                None
+            }
+        }
+        let grid: Vec<f64> = (0..=200).map(|i| -5.0 + 0.05 * i as f64).collect();
+        let evidence = ParticleMsg::from_particles(
+            grid.iter()
+                .map(|x| (*x, (-2.0 * (x - 1.0) * (x - 1.0)).exp()))
+                .collect(),
+        );
+        let mut g = BPGraph::<f64, ParticleMsg>::new();
+        g.add_variable("x".to_owned(), ParticleMsg::from_samples(&grid))?;
+        g.add_factor("evidence".to_owned(), Evidence(evidence, None))?;
+        g.add_edge(0, 1)?;
+        g.initialize()?;
+        g.propagate(1)?;
+        let res = g.get_particle_result(0)?.unwrap();
+        assert!((res.mean() - 1.0).abs() < 1e-2);
+        let density = g.get_result_density(0, &[-1.0, 1.0, 3.0])?.unwrap();
+        assert!(density[1] > density[0] && density[1] > density[2]);
+
+        let mut msg = res.with_resampling(0.5, 3);
+        msg.resample(50);
+        assert_eq!(msg.len(), 50);
+        assert!((msg.particles().iter().map(|(_, w)| w).sum::<f64>() - 1.0).abs() < 1e-9);
+        assert!((msg.mean() - 1.0).abs() < 0.2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_junction_tree() -> BPResult<()> {
+        let mut g = chain_graph()?;
+        let exact = g.exact_marginals_junction_tree(1000)?;
+        g.propagate(10)?;
+        for i in 0..4 {
+            let res = g.get_result(i)?.unwrap();
+            let sum: Probability = res.values().sum();
+            for (v, p) in res {
+                assert!((p / sum - exact[&i][&v]).abs() < 1e-9);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_bruteforce() -> BPResult<()> {
+        let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+        for i in 0..3 {
+            let mut v = VariableNode::new();
+            let mut dist = HashMap::new();
+            for val in 0..3 {
+                dist.insert(val, (1 + i + val) as Probability);
+            }
+            v.set_prior(&dist)?;
+            g.add_node(format!("v{}", i), Box::new(v))?;
+        }
+        //A loop v0 - v1 - v2 - v0
+        for i in 0..3 {
+            let t = g.add_node(format!("t{}", i), Box::new(TwoNode::new(near)))?;
+            g.add_edge(i, t)?;
+            g.add_edge(t, (i + 1) % 3)?;
+        }
+        let bruteforce = g.exact_marginals_bruteforce(1000)?;
+        let junction_tree = g.exact_marginals_junction_tree(1000)?;
+        assert!(g.exact_marginals_bruteforce(26).is_err());
+        for i in 0..3 {
+            for (v, p) in &bruteforce[&i] {
+                assert!((p - junction_tree[&i][v]).abs() < 1e-9);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_bruteforce_contradiction() -> BPResult<()> {
+        let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+        let x = g.add_variable(
+            "x".to_owned(),
+            vec![(0, 1.0), (1, 0.0)].into_iter().collect(),
+        )?;
+        let y = g.add_variable(
+            "y".to_owned(),
+            vec![(0, 0.0), (1, 1.0)].into_iter().collect(),
+        )?;
+        let f = g.add_factor(
+            "f".to_owned(),
+            FnFactor::new(2, |v: &[i32]| if v[0] == v[1] { 1.0 } else { 0.0 }),
+        )?;
+        g.add_edge(x, f)?;
+        g.add_edge(y, f)?;
+        //Contradictory evidence leaves no assignment to normalize by
+        assert!(g.exact_marginals_bruteforce(100).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_marginal_change_metrics() -> BPResult<()> {
+        let mut g = chain_graph()?;
+        assert!(g.marginal_change_metrics().is_err());
+        g.set_track_marginals(true);
+        g.propagate(2)?;
+        assert!(g.marginal_change_metrics()?.is_empty());
+        g.propagate(2)?;
+        let metrics = g.marginal_change_metrics()?;
+        assert_eq!(metrics.len(), 4);
+        assert!(metrics.iter().any(|m| m.total_variation > 0.0));
+        g.propagate(10)?;
+        for m in g.marginal_change_metrics()? {
+            assert!(m.kl_divergence.abs() < 1e-12);
+            assert!(m.total_variation < 1e-12);
+            assert!(m.max_abs_diff <= m.total_variation);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_marginal_change_rounding() {
+        //Differences at the level of rounding errors
+        let current = HashMap::from([(0, 0.1 + 0.2), (1, 0.7), (2, 1e-17)]);
+        let previous = HashMap::from([(0, 0.3), (1, 0.7 + 1e-16)]);
+        let m = MarginalChange::new(0, &current, &previous);
+        assert!(m.max_abs_diff <= m.total_variation);
+        assert!(m.total_variation < 1e-15);
+    }
+
+    #[test]
+    fn test_layer_scheduler() -> BPResult<()> {
+        let mut g = chain_graph()?;
+        let exact = g.exact_marginals_junction_tree(1000)?;
+        let mut scheduler = LayerScheduler::new(vec![vec![0, 1, 2, 3], vec![4, 5, 6]]);
+        g.propagate_with_scheduler(10, &mut scheduler)?;
+        for i in 0..4 {
+            let res = g.get_result(i)?.unwrap();
+            let sum: Probability = res.values().sum();
+            for (v, p) in res {
+                assert!((p / sum - exact[&i][&v]).abs() < 1e-9);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_broadcast_control_message() -> BPResult<()> {
+        let mut g = BPGraph::<
+            i32,
+            HashMap<i32, Probability>,
+            VariableNodeCtrl<HashMap<i32, Probability>>,
+            VariableNodeCtrlAnswer<HashMap<i32, Probability>>,
+        >::new();
+        let mut dist = HashMap::new();
+        dist.insert(1, 1.0);
         let mut v0 = VariableNode::new();
-        let mut v1 = VariableNode::new();
-        let mut v2 = VariableNode::new();
-        let mut dist0 = HashMap::new();
-        let mut dist1 = HashMap::new();
-        dist0.insert(1, 1.0);
-        dist1.insert(1, 0.25);
-        dist1.insert(2, 0.25);
-        dist1.insert(3, 0.25);
-        dist1.insert(4, 0.25);
-        v0.set_prior(&dist0);
-        v1.set_prior(&dist1);
-        v2.set_prior(&dist1);
+        v0.set_prior(&dist)?;
+        g.add_node("v0".to_string(), Box::new(v0))?;
+        g.add_node("v1".to_string(), Box::new(VariableNode::new()))?;
+        g.link_variables("eq".to_string(), &[0, 1])?;
 
-        let t3 = TwoNode::new(mul);
-        let t4 = TwoNode::new(mul);
-        g.add_node("0".to_string(), Box::new(v0));
-        g.add_node("1".to_string(), Box::new(v1));
-        g.add_node("2".to_string(), Box::new(v2));
-        g.add_node("m3".to_string(), Box::new(t3));
-        g.add_node("m4".to_string(), Box::new(t4));
+        let answers = g.broadcast_control_message_filtered(
+            VariableNodeCtrl::GetPrior,
+            NodeFilter::Variables,
+        )?;
+        assert_eq!(answers.len(), 2);
+        assert!(matches!(&answers[0], (0, VariableNodeCtrlAnswer::Prior(Some(p))) if *p == dist));
+        assert!(matches!(
+            &answers[1],
+            (1, VariableNodeCtrlAnswer::Prior(None))
+        ));
+        g.broadcast_control_message(VariableNodeCtrl::SetPrior(Some(dist.clone())))?;
+        assert!(matches!(
+            g.send_control_message(1, VariableNodeCtrl::GetPrior)?,
+            VariableNodeCtrlAnswer::Prior(Some(_))
+        ));
+        //The equality factor does not understand VariableNodeCtrl
+        assert_eq!(
+            g.broadcast_control_message(VariableNodeCtrl::GetPrior)?
+                .len(),
+            2
+        );
+        assert!(g
+            .send_control_message(2, VariableNodeCtrl::GetPrior)
+            .is_err());
+        //Scheduled messages are rejected when they are delivered
+        g.initialize()?;
+        g.send_control_message_at(2, VariableNodeCtrl::GetPrior, 0)?;
+        assert!(g.propagate(1).is_err());
+        Ok(())
+    }
 
-        g.add_edge(0, 3)?;
-        g.add_edge(3, 1)?;
-        g.add_edge(1, 4)?;
-        g.add_edge(4, 2)?;
+    #[test]
+    fn test_control_message_without_accepts() -> BPResult<()> {
+        //Node functions that only override send_control_message still receive control messages
+        type M = HashMap<i32, Probability>;
+        struct Counter(usize);
+        impl NodeFunction<i32, M, VariableNodeCtrl<M>, VariableNodeCtrlAnswer<M>> for Counter {
+            fn node_function(
+                &mut self,
+                inbox: Vec<(NodeIndex, M)>,
+            ) -> BPResult<Vec<(NodeIndex, M)>> {
+                Ok(inbox)
+            }
+            fn is_factor(&self) -> bool {
+                true
+            }
+            fn number_inputs(&self) -> Option<usize> {
+                None
+            }
+            fn initialize(&mut self, _connections: Vec<NodeIndex>) -> BPResult<()> {
+                Ok(())
+            }
+            fn is_ready(
+                &self,
+                _recv_from: &Vec<(NodeIndex, M)>,
+                _current_step: usize,
+            ) -> BPResult<bool> {
+                Ok(true)
+            }
+            fn reset(&mut self) -> BPResult<()> {
+                Ok(())
+            }
+            fn get_prior(&self) -> Option<M> {
+                None
+            }
+            fn send_control_message(
+                &mut self,
+                _ctrl_msg: VariableNodeCtrl<M>,
+            ) -> BPResult<VariableNodeCtrlAnswer<M>> {
+                self.0 += 1;
+                Ok(VariableNodeCtrlAnswer::Done)
+            }
+        }
+        let mut g = BPGraph::<i32, M, VariableNodeCtrl<M>, VariableNodeCtrlAnswer<M>>::new();
+        let v = g.add_variable(
+            "v".to_owned(),
+            vec![(0, 0.5), (1, 0.5)].into_iter().collect(),
+        )?;
+        let c = g.add_factor("c".to_owned(), Counter(0))?;
+        let eq = g.link_variables("eq".to_owned(), &[v])?;
+        assert!(matches!(
+            g.send_control_message(c, VariableNodeCtrl::GetPrior)?,
+            VariableNodeCtrlAnswer::Done
+        ));
+        let answers = g.broadcast_control_message(VariableNodeCtrl::HasPropagated)?;
+        assert_eq!(
+            answers.iter().map(|(i, _)| *i).collect::<Vec<_>>(),
+            vec![v, c]
+        );
+        assert!(!answers.iter().any(|(i, _)| *i == eq));
+        assert_eq!(g.get_node_function::<Counter>(c)?.0, 2);
+        Ok(())
+    }
 
-        assert!(g.is_valid());
+    #[test]
+    fn test_generators() -> BPResult<()> {
+        let g = generators::random_regular_bipartite::<DenseMsg>(30, 2, 3, 2, 1)?;
+        assert_eq!(g.len(), 50);
+        assert_eq!(g.edges().count(), 60);
+        for i in 30..50 {
+            let mut connections = g.get_connections(i)?.clone();
+            connections.dedup();
+            assert_eq!(connections.len(), 3);
+        }
+        let edges: Vec<_> = g.edges().collect();
+        let again = generators::random_regular_bipartite::<DenseMsg>(30, 2, 3, 2, 1)?;
+        assert_eq!(again.edges().collect::<Vec<_>>(), edges);
+        assert!(generators::random_regular_bipartite::<DenseMsg>(10, 2, 3, 2, 1).is_err());
+
+        //BP is exact on trees
+        let mut tree = generators::random_tree::<DenseMsg>(2, 3, 2, 5)?;
+        assert_eq!(tree.len(), 13 + 12);
+        let exact = tree.exact_marginals_bruteforce(1 << 13)?;
+        tree.initialize()?;
+        tree.propagate(10)?;
+        for (i, marginal) in &exact {
+            let res = tree.get_result(*i)?.unwrap();
+            assert!((res[&0] - marginal[&0]).abs() < 1e-9);
+        }
+
+        let (grid, layout) = generators::random_grid::<DenseMsg>(4, 3, 3, 0.5, 2)?;
+        assert_eq!(grid.len(), 12 + 3 * 3 + 2 * 4);
+        assert_eq!(layout.variable(3, 2), Some(11));
 
+        let checks = generators::ldpc_ensemble(24, 3, 6, 3)?;
+        assert_eq!(checks.len(), 12);
+        let mut decoder = LdpcDecoder::new(24, checks, LdpcAlgorithm::SumProduct)?;
+        let result = decoder.decode(&generators::awgn_llrs(24, 0.5, 4), 20)?;
+        assert!(result.is_codeword());
+        assert!(result.codeword.iter().all(|b| !b));
+        Ok(())
+    }
+
+    #[test]
+    fn test_propagate_gpu() -> BPResult<()> {
+        //Without the feature gpu or a GPU, the steps run on the CPU
+        let tolerance = if gpu_available() { 1e-5 } else { 1e-12 };
+        let compare = |cpu: &BPGraph<usize, DenseMsg>,
+                       gpu: &BPGraph<usize, DenseMsg>|
+         -> BPResult<()> {
+            for (i, _, is_factor) in cpu.nodes() {
+                if is_factor {
+                    continue;
+                }
+                let (expected, res) = (cpu.get_result(i)?.unwrap(), gpu.get_result(i)?.unwrap());
+                for (v, p) in expected {
+                    assert!((res[&v] - p).abs() < tolerance);
+                }
+            }
+            Ok(())
+        };
+        let build = || generators::random_regular_bipartite::<DenseMsg>(60, 3, 3, 4, 7);
+        let (mut cpu, mut gpu) = (build()?, build()?);
+        cpu.initialize()?;
+        gpu.initialize()?;
+        cpu.propagate(6)?;
+        let report = gpu.propagate_step_gpu()?;
+        assert_eq!((report.step, report.nodes_fired), (0, 60));
+        gpu.propagate_gpu(5)?;
+        assert_eq!(gpu.get_step(), 6);
+        compare(&cpu, &gpu)?;
+
+        //PairwiseFactors are computed on the CPU
+        let build = || generators::random_grid::<DenseMsg>(5, 4, 3, 0.5, 3).map(|(g, _)| g);
+        let (mut cpu, mut gpu) = (build()?, build()?);
+        cpu.initialize()?;
+        gpu.initialize()?;
+        cpu.propagate(8)?;
+        gpu.propagate_gpu(8)?;
+        compare(&cpu, &gpu)?;
+        let mut uninitialized = BPGraph::<usize, DenseMsg>::new();
+        uninitialized.add_variable("x".to_owned(), DenseMsg::uniform(2))?;
+        assert!(uninitialized.propagate_gpu(1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_msg_pool_bounded() -> BPResult<()> {
+        let build = |pool_size| -> BPResult<BPGraph<usize, DenseMsg>> {
+            let mut g = generators::random_regular_bipartite(60, 3, 3, 3, 7)?;
+            g.set_msg_pool_size(pool_size);
+            g.initialize()?;
+            Ok(g)
+        };
+        let mut g = build(4)?;
+        let mut unpooled = build(0)?;
+        for _ in 0..3 {
+            g.propagate(2)?;
+            unpooled.propagate(2)?;
+            //Buffers of outgoing messages are pooled as well, neither grows beyond the pool size
+            assert!(g.get_msg_pool().len() <= 4);
+            assert!(g.get_msg_pool().buffers() > 0 && g.get_msg_pool().buffers() <= 4);
+        }
+        assert!(unpooled.get_msg_pool().is_empty() && unpooled.get_msg_pool().buffers() == 0);
+        for i in 0..60 {
+            let (a, b) = (unpooled.get_result(i)?.unwrap(), g.get_result(i)?.unwrap());
+            for v in 0..3 {
+                assert!((a[&v] - b[&v]).abs() < 1e-12);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_msg_pool_reuse() -> BPResult<()> {
+        let mut g: BPGraph<i32, HashMap<i32, Probability>> = BPGraph::new();
+        let prior: HashMap<i32, Probability> = vec![(0, 0.3), (1, 0.7)].into_iter().collect();
+        let table: HashMap<(i32, i32), Probability> =
+            vec![((0, 0), 0.9), ((1, 1), 0.9)].into_iter().collect();
+        let v0 = g.add_variable("v0".to_owned(), prior.clone())?;
+        let v1 = g.add_variable("v1".to_owned(), prior)?;
+        g.add_pairwise_potential(v0, v1, table)?;
         g.initialize()?;
+        g.propagate(3)?;
+        //The messages consumed by the variables are recycled with their allocations. Most of them are
+        //taken again for the copies the variables send.
+        let pooled = g.get_msg_pool().len();
+        assert_eq!(pooled, 2);
+        for _ in 0..pooled {
+            assert!(g.take_msg().capacity() > 0);
+        }
+        assert!(g.get_msg_pool().is_empty());
+        Ok(())
+    }
 
-        g.propagate_threaded(2, 1)?;
+    #[test]
+    fn test_variable_node_takes_pooled_msgs() -> BPResult<()> {
+        //The copies sent by a variable node reuse the pooled messages instead of allocating
+        let mut v = VariableNode::<usize, DenseMsg>::new();
+        v.set_prior(&DenseMsg::from_vec(vec![0.4, 0.6]))?;
+        NodeFunction::<usize, DenseMsg>::initialize(&mut v, vec![1, 2, 3])?;
+        let mut pooled = v.clone();
+        let mut pool = crate::MsgPool::new(8);
+        for _ in 0..3 {
+            pool.recycle(DenseMsg::zeros(2));
+        }
+        let inbox: Vec<(NodeIndex, DenseMsg)> = (1..4)
+            .map(|i| (i, DenseMsg::from_vec(vec![0.1 * i as Probability, 0.5])))
+            .collect();
+        let (mut out, mut pooled_out) = (Vec::new(), Vec::new());
+        NodeFunction::<usize, DenseMsg>::node_function_inplace(
+            &mut v,
+            &mut inbox.clone(),
+            &mut out,
+        )?;
+        NodeFunction::<usize, DenseMsg>::node_function_pooled(
+            &mut pooled,
+            &mut inbox.clone(),
+            &mut pooled_out,
+            &mut pool,
+        )?;
+        assert!(pool.is_empty());
+        assert_eq!(out, pooled_out);
+        Ok(())
+    }
+
+    #[test]
+    fn test_initialize_threaded() -> BPResult<()> {
+        let mut g = generators::random_regular_bipartite::<DenseMsg>(90, 2, 3, 3, 11)?;
+        let mut threaded = generators::random_regular_bipartite::<DenseMsg>(90, 2, 3, 3, 11)?;
+        g.initialize()?;
+        threaded.initialize_threaded(4)?;
+        assert!(threaded.is_initialized() && threaded.is_sealed());
+        g.propagate(6)?;
+        threaded.propagate(6)?;
+        for i in 0..90 {
+            let (a, b) = (g.get_result(i)?.unwrap(), threaded.get_result(i)?.unwrap());
+            for v in 0..3 {
+                assert!((a[&v] - b[&v]).abs() < 1e-12);
+            }
+        }
+
+        let mut g = BPGraph::<i32, HashMap<i32, Probability>>::new();
+        let v = g.add_variable("v".to_owned(), vec![(0, 1.0)].into_iter().collect())?;
+        let t = g.add_node("t".to_owned(), Box::new(TwoNode::new(near)))?;
+        g.add_edge(v, t)?;
+        assert!(g.initialize_threaded(2).is_err());
+        assert!(!g.is_initialized());
+        Ok(())
+    }
+
+    #[test]
+    fn test_subgraph_merge() -> BPResult<()> {
+        let mut g = chain_graph()?;
+        //The graph is kept if the nodes are invalid
+        assert!(g.subgraph(&[0, 4, 0]).is_err());
+        assert_eq!(g.len(), 7);
+        let (mut sub, mapping) = g.subgraph(&[0, 4, 1])?;
+        assert!(g.is_empty());
+        assert_eq!(mapping[&4], 1);
+        assert_eq!(sub.len(), 3);
+        assert!(sub.is_valid());
+        assert!(!sub.is_initialized());
+
+        let (other, _) = chain_graph()?.subgraph(&[0, 4, 1])?;
+        let offset = sub.merge(other)?;
+        assert_eq!(offset, 3);
+        assert_eq!(sub.get_connections(4)?, &vec![3, 5]);
+        sub.link_variables("eq".to_string(), &[2, offset])?;
+        assert!(sub.is_valid());
+        sub.initialize()?;
+        sub.propagate(4)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_sealed() -> BPResult<()> {
+        let mut g = chain_graph()?;
+        assert!(g.is_sealed());
+        assert!(g.merge(chain_graph()?).is_err());
+        assert_eq!(g.len(), 7);
+        g.unseal();
+        assert_eq!(g.merge(chain_graph()?)?, 7);
+        assert_eq!(g.len(), 14);
+        Ok(())
+    }
+
+    #[test]
+    fn test_builders_sealed() -> BPResult<()> {
+        //Nothing is added to a sealed graph, not even the first nodes of composite builders
+        type M = HashMap<i32, Probability>;
+        let mut g = chain_graph()?;
+        let prior: M = vec![(1, 0.5), (2, 0.5)].into_iter().collect();
+        assert!(g.add_variable("v".to_owned(), prior.clone()).is_err());
+        assert!(g
+            .add_factor("t".to_owned(), TwoNode::<i32, M>::new(near))
+            .is_err());
+        assert!(g.link_variables("eq".to_owned(), &[0, 1]).is_err());
+        assert!(g.add_edge_with_connector(0, 1).is_err());
+        assert!(g
+            .add_pairwise_potential(0, 3, vec![((1, 1), 1.0)].into_iter().collect())
+            .is_err());
+        let mut template = Template::new();
+        template.add_node("v".to_owned(), move |_| {
+            Box::new(VariableNode::builder().prior(prior.clone()).build())
+        });
+        assert!(g.instantiate(&template, 2, &[]).is_err());
+        assert_eq!(g.len(), 7);
+        g.unseal();
+        g.link_variables("eq".to_owned(), &[0, 1])?;
+        assert_eq!(g.len(), 8);
+        Ok(())
+    }
+
+    #[test]
+    fn test_clone_subgraph() -> BPResult<()> {
+        let mut g: BPGraph<i32, HashMap<i32, Probability>> = BPGraph::new();
+        let prior: HashMap<i32, Probability> = vec![(0, 0.3), (1, 0.7)].into_iter().collect();
+        for i in 0..3 {
+            g.add_variable(format!("v{}", i), prior.clone())?;
+        }
+        let table: HashMap<(i32, i32), Probability> =
+            vec![((0, 0), 0.9), ((1, 1), 0.9), ((0, 1), 0.1), ((1, 0), 0.1)]
+                .into_iter()
+                .collect();
+        let f01 = g.add_pairwise_potential(0, 1, table.clone())?;
+        g.add_pairwise_potential(1, 2, table)?;
+        g.initialize()?;
+        g.propagate(2)?;
+        let (mut sub, mapping) = g.clone_subgraph(&[1, f01, 0])?;
+        assert_eq!(mapping[&f01], 1);
+        assert_eq!(sub.len(), 3);
+        assert!(sub.is_valid());
+        let (mut moved, _) = g.try_clone()?.subgraph(&[1, f01, 0])?;
+        //The graph is kept
+        assert_eq!(g.len(), 5);
+        g.propagate(2)?;
+        for graph in [&mut sub, &mut moved] {
+            graph.initialize()?;
+            graph.propagate(4)?;
+        }
+        assert_eq!(sub.get_result(0)?, moved.get_result(0)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_factor_belief() -> BPResult<()> {
+        let mut g = chain_graph()?;
+        //After an odd number of steps, the factors hold the messages of the variables
+        g.propagate(9)?;
+        let exact = g.exact_marginals_bruteforce(1000)?;
+        let belief = g.get_factor_belief(5, 16)?;
+        assert_eq!(belief.len(), 16);
+        assert!((belief.values().sum::<Probability>() - 1.0).abs() < 1e-9);
+        assert!(g.get_factor_belief(5, 15).is_err());
+        assert!(g.get_factor_belief(0, 16).is_err());
+        for (var, marginal) in g.get_factor_belief_marginals(5, 16)? {
+            for (v, p) in &exact[&var] {
+                assert!((marginal[v] - p).abs() < 1e-9);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_propagate_incremental() -> BPResult<()> {
+        let mut g = chain_graph()?;
+        g.set_incremental(true);
         g.propagate(10)?;
-        let mut res = g.get_result(2)?.unwrap();
-        let mut sum: f64 = res.iter().map(|(_, p)| p).sum();
-        let res_normed: HashMap<i32, Probability> =
-            res.iter().map(|(v, p)| (*v, p / sum)).collect();
+        assert!(g.get_dirty().is_empty());
 
-        println!("{:?}", res_normed);
-        for (v, p) in res_normed {
-            if v == 4 {
-                //TODO: Allow a small error
-                assert_eq!(p, 1.0);
-            } else {
-                assert_eq!(p, 0.0);
+        //New observation at the end of the chain
+        let mut dist = HashMap::new();
+        dist.insert(1, 0.9);
+        dist.insert(4, 0.1);
+        let mut v = VariableNode::new();
+        v.set_prior(&dist)?;
+        assert!(g
+            .add_node("t3".to_string(), Box::new(TwoNode::new(near)))
+            .is_err());
+        assert!(g.add_edge(3, 4).is_err());
+        g.unseal();
+        let v = g.add_node("v4".to_string(), Box::new(v))?;
+        let t = g.add_node("t3".to_string(), Box::new(TwoNode::new(near)))?;
+        g.add_edge(3, t)?;
+        g.add_edge(t, v)?;
+        assert_eq!(g.get_dirty().len(), 3);
+        g.initialize()?;
+        assert!(g.is_sealed());
+        let steps = g.propagate_incremental(20, 1e-12)?;
+        assert!(steps < 20);
+        assert!(g.get_dirty().is_empty());
+
+        let exact = g.exact_marginals_bruteforce(10000)?;
+        for i in [0, 1, 2, 3, v] {
+            let res = g.get_result(i)?.unwrap();
+            let sum: Probability = res.values().sum();
+            for (v, p) in &exact[&i] {
+                assert!((res[v] / sum - p).abs() < 1e-9);
             }
         }
+        Ok(())
+    }
+
+    #[test]
+    fn test_pairwise_potential_invalid() -> BPResult<()> {
+        let mut g: BPGraph<i32, HashMap<i32, Probability>> = BPGraph::new();
+        let prior: HashMap<i32, Probability> = vec![(0, 0.5), (1, 0.5)].into_iter().collect();
+        let table: HashMap<(i32, i32), Probability> =
+            vec![((0, 0), 0.9), ((1, 1), 0.9)].into_iter().collect();
+        let v0 = g.add_variable("v0".to_owned(), prior.clone())?;
+        let v1 = g.add_variable("v1".to_owned(), prior)?;
+        let f = g.add_pairwise_potential(v0, v1, table.clone())?;
+        //Failures leave no factor without edges behind
+        for (a, b) in [(v0, v0), (v0, f), (v0, 7)] {
+            assert!(g.add_pairwise_potential(a, b, table.clone()).is_err());
+            assert_eq!(g.len(), 3);
+        }
+        g.initialize()?;
+        assert!(g.add_pairwise_potential(v0, v1, table).is_err());
+        assert_eq!(g.len(), 3);
+        assert!(g.is_valid());
+        Ok(())
+    }
 
+    #[test]
+    fn test_pairwise_potential() -> BPResult<()> {
+        let mut g: BPGraph<i32, HashMap<i32, Probability>> = BPGraph::new();
+        let mut dist = HashMap::new();
+        let mut table = HashMap::new();
+        for v in 1..5 {
+            dist.insert(v, 0.1 * v as Probability);
+            for w in 1..5 {
+                table.insert((v, w), near(v, w));
+            }
+        }
+        for i in 0..3 {
+            let mut v = VariableNode::new();
+            v.set_prior(&dist)?;
+            g.add_node(format!("v{}", i), Box::new(v))?;
+        }
+        g.add_pairwise_potential(0, 1, table)?;
+        assert_eq!(g.add_edge_with_connector(1, 2)?, Some(4));
+        assert!(g.add_pairwise_potential(0, 3, HashMap::new()).is_err());
+        assert!(g.is_valid());
+        g.initialize()?;
+        let exact = g.exact_marginals_bruteforce(1000)?;
+        g.propagate(6)?;
+        for i in 0..3 {
+            let res = g.get_result(i)?.unwrap();
+            let sum: Probability = res.values().sum();
+            for (v, p) in &exact[&i] {
+                assert!((res[v] / sum - p).abs() < 1e-9);
+            }
+        }
         Ok(())
     }
 
@@ -98,6 +5203,7 @@ mod tests {
     }
 
     impl<T: Debug + Copy, MsgT: Msg<T> + Clone> TwoNode<T, MsgT> {
+        #[allow(clippy::redundant_field_names)]
         pub fn new(f_node_function: fn(T, T) -> Probability) -> Self {
             Self {
                 connection0: None,
@@ -193,5 +5299,8 @@ mod tests {
         fn get_prior(&self) -> Option<MsgT> {
             None
         }
+        fn potential(&self, values: &[T]) -> Option<Probability> {
+            Some((self.f_node_function)(values[0], values[1]))
+        }
     }
 }
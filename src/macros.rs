@@ -32,3 +32,85 @@ macro_rules! thread_print
 macro_rules! thread_print {
     ($( $args:expr ),*) => {};
 }
+
+//Implements NodeFunction::clone_box by Clone. Used inside an impl of NodeFunction with its type arguments, e.g.,
+//    impl_clone_box!(T, MsgT, CtrlMsgT, CtrlMsgAT);
+#[macro_export]
+macro_rules! impl_clone_box {
+    ($($arg:ty),* $(,)?) => {
+        fn clone_box(&self) -> Option<Box<dyn $crate::NodeFunction<$($arg),*> + Send + Sync>>
+        where
+            Self: Send + Sync + 'static,
+        {
+            Some(Box::new(self.clone()))
+        }
+    };
+}
+
+//Builds a graph from a declaration of its nodes and edges and evaluates to
+//BPResult<(graph, HashMap<&'static str, NodeIndex>)> with the indices of the nodes by name.
+//Variables are added first (with their priors), then the factors, each connected to the listed nodes in order:
+//    table(expr)     PairwiseFactor::new(expr)
+//    potential(expr) FixedArityFactor::new(expr) with one input per listed node
+//    node(expr)      any node function
+//Names have to be unique, duplicates are a compile error. For example:
+//    let (g, nodes) = bp_graph! {
+//        BPGraph<i32, HashMap<i32, Probability>>;
+//        variables {
+//            x = prior_x,
+//            y = prior_y,
+//        }
+//        factors {
+//            f = table(table_xy) => [x, y],
+//            same = potential(|[a, b]| if a == b { 1.0 } else { 0.0 }) => [x, y],
+//        }
+//    }?;
+#[macro_export]
+macro_rules! bp_graph {
+    (@factor table $arg:expr; $($con:ident),*) => {
+        $crate::PairwiseFactor::new($arg)
+    };
+    (@factor potential $arg:expr; $($con:ident),*) => {
+        $crate::FixedArityFactor::<{ [$(stringify!($con)),*].len() }, _, _>::new($arg)
+    };
+    (@factor node $arg:expr; $($con:ident),*) => {
+        $arg
+    };
+    (
+        $graph:ty;
+        variables {
+            $($var:ident = $prior:expr),* $(,)?
+        }
+        factors {
+            $($fac:ident = $kind:ident($arg:expr) => [$($con:ident),* $(,)?]),* $(,)?
+        }
+    ) => {{
+        //Duplicate names are duplicate variants
+        #[allow(non_camel_case_types, dead_code)]
+        enum BpGraphNodeNames {
+            $($var,)*
+            $($fac,)*
+        }
+        #[allow(clippy::redundant_closure_call)]
+        let res = (|| -> $crate::BPResult<($graph, ::std::collections::HashMap<&'static str, $crate::NodeIndex>)> {
+            let mut graph = <$graph>::new();
+            let mut nodes = ::std::collections::HashMap::new();
+            $(
+                let $var = graph.add_variable(stringify!($var).to_owned(), $prior)?;
+                nodes.insert(stringify!($var), $var);
+            )*
+            $(
+                let $fac = graph.add_factor(
+                    stringify!($fac).to_owned(),
+                    $crate::bp_graph!(@factor $kind $arg; $($con),*),
+                )?;
+                $(
+                    graph.add_edge($fac, $con)?;
+                )*
+                nodes.insert(stringify!($fac), $fac);
+            )*
+            Ok((graph, nodes))
+        })();
+        res
+    }};
+}
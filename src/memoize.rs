@@ -0,0 +1,200 @@
+use crate::{BPResult, GraphInfo, InputNeed, Msg, NodeFunction, NodeIndex, Probability, Semiring};
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::sync::Arc;
+
+//Inbox of a call of node_function, sorted by sender
+type Inputs<T> = Vec<(NodeIndex, HashMap<T, Probability>)>;
+
+//Wraps a stateless factor (its messages only depend on its inbox, e.g., a closure-based factor) and returns
+//the messages of the last computation as long as no message of the inbox differs by more than tolerance
+//from the inbox of that computation, e.g., for frozen parts of a graph or expensive factors whose inputs
+//have converged. The inbox is compared to the one of the last computation (not of the last call), so slowly
+//drifting inputs cannot accumulate. Control messages, semirings, restricted domains and parameter updates
+//are forwarded and clear the cache.
+#[allow(clippy::type_complexity)]
+pub struct MemoizedFactor<T, MsgT: Msg<T>, CtrlMsgT = (), CtrlMsgAT: Default = ()> {
+    inner: Box<dyn NodeFunction<T, MsgT, CtrlMsgT, CtrlMsgAT> + Send + Sync>,
+    tolerance: Probability,
+    cache: Option<(Inputs<T>, Vec<(NodeIndex, MsgT)>)>,
+    hits: usize,
+    misses: usize,
+}
+
+impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default> MemoizedFactor<T, MsgT, CtrlMsgT, CtrlMsgAT>
+where
+    T: Copy + Eq + Hash,
+    MsgT: Clone,
+{
+    //A tolerance of 0 only reuses the messages for identical inboxes
+    pub fn new(
+        inner: Box<dyn NodeFunction<T, MsgT, CtrlMsgT, CtrlMsgAT> + Send + Sync>,
+        tolerance: Probability,
+    ) -> Self {
+        MemoizedFactor {
+            inner,
+            tolerance,
+            cache: None,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    //Number of calls answered from the cache
+    pub fn hits(&self) -> usize {
+        self.hits
+    }
+
+    //Number of calls computed by the wrapped factor
+    pub fn misses(&self) -> usize {
+        self.misses
+    }
+
+    pub fn clear_cache(&mut self) {
+        self.cache = None;
+    }
+
+    fn inputs(inbox: &[(NodeIndex, MsgT)]) -> Inputs<T> {
+        let mut inputs: Inputs<T> = inbox
+            .iter()
+            .map(|(from, msg)| (*from, msg.clone().into_iter().collect()))
+            .collect();
+        inputs.sort_unstable_by_key(|(from, _)| *from);
+        inputs
+    }
+
+    fn matches(&self, inputs: &Inputs<T>) -> bool {
+        let cached = match &self.cache {
+            Some((cached, _)) => cached,
+            None => return false,
+        };
+        cached.len() == inputs.len()
+            && cached.iter().zip(inputs).all(|((c_from, c), (from, m))| {
+                c_from == from
+                    && c.len() == m.len()
+                    && m.iter()
+                        .all(|(v, p)| c.get(v).is_some_and(|q| (p - q).abs() <= self.tolerance))
+            })
+    }
+}
+
+impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default> NodeFunction<T, MsgT, CtrlMsgT, CtrlMsgAT>
+    for MemoizedFactor<T, MsgT, CtrlMsgT, CtrlMsgAT>
+where
+    T: Copy + Eq + Hash + Debug,
+    MsgT: Clone,
+{
+    fn node_function(&mut self, inbox: Vec<(NodeIndex, MsgT)>) -> BPResult<Vec<(NodeIndex, MsgT)>> {
+        let inputs = Self::inputs(&inbox);
+        if self.matches(&inputs) {
+            self.hits += 1;
+            return Ok(self.cache.as_ref().expect("Cache exists").1.clone());
+        }
+        self.misses += 1;
+        let out = self.inner.node_function(inbox)?;
+        self.cache = Some((inputs, out.clone()));
+        Ok(out)
+    }
+    fn is_factor(&self) -> bool {
+        self.inner.is_factor()
+    }
+    fn number_inputs(&self) -> Option<usize> {
+        self.inner.number_inputs()
+    }
+    fn initialize(&mut self, connections: Vec<NodeIndex>) -> BPResult<()> {
+        self.cache = None;
+        self.inner.initialize(connections)
+    }
+    fn is_ready(&self, recv_from: &Vec<(NodeIndex, MsgT)>, current_step: usize) -> BPResult<bool> {
+        self.inner.is_ready(recv_from, current_step)
+    }
+    fn reset(&mut self) -> BPResult<()> {
+        self.cache = None;
+        self.inner.reset()
+    }
+    fn reset_schedule_state(&mut self) -> BPResult<()> {
+        self.inner.reset_schedule_state()
+    }
+    fn get_prior(&self) -> Option<MsgT> {
+        self.inner.get_prior()
+    }
+    fn ports(&self) -> Vec<String> {
+        self.inner.ports()
+    }
+    fn initialize_ports(&mut self, ports: Vec<(String, NodeIndex)>) -> BPResult<()> {
+        self.inner.initialize_ports(ports)
+    }
+    fn save_state(&self) -> Option<Box<dyn Any + Send + Sync>> {
+        self.inner.save_state()
+    }
+    fn load_state(&mut self, state: Box<dyn Any + Send + Sync>) -> BPResult<()> {
+        self.cache = None;
+        self.inner.load_state(state)
+    }
+    fn accepts_control_message(&self, ctrl_msg: &CtrlMsgT) -> bool {
+        self.inner.accepts_control_message(ctrl_msg)
+    }
+    fn control_message_changes_node(&self, ctrl_msg: &CtrlMsgT) -> bool {
+        self.inner.control_message_changes_node(ctrl_msg)
+    }
+    fn send_control_message(&mut self, ctrl_msg: CtrlMsgT) -> BPResult<CtrlMsgAT> {
+        self.cache = None;
+        self.inner.send_control_message(ctrl_msg)
+    }
+    fn on_step_end(&mut self, step: usize) -> BPResult<()> {
+        self.inner.on_step_end(step)
+    }
+    fn on_graph_initialized(
+        &mut self,
+        connections: &[NodeIndex],
+        graph_info: &GraphInfo,
+    ) -> BPResult<()> {
+        self.inner.on_graph_initialized(connections, graph_info)
+    }
+    fn set_semiring(&mut self, semiring: Arc<dyn Semiring>) {
+        self.cache = None;
+        self.inner.set_semiring(semiring)
+    }
+    fn restrict_domain(&mut self, variable: NodeIndex, values: &[T]) -> BPResult<()> {
+        self.cache = None;
+        self.inner.restrict_domain(variable, values)
+    }
+    fn learns_parameters(&self) -> bool {
+        self.inner.learns_parameters()
+    }
+    fn accumulate_statistics(&mut self, belief: &HashMap<Vec<T>, Probability>) -> BPResult<()> {
+        self.inner.accumulate_statistics(belief)
+    }
+    fn update_parameters(&mut self) -> BPResult<()> {
+        self.cache = None;
+        self.inner.update_parameters()
+    }
+    //None if the inner node function cannot be cloned
+    fn clone_box(&self) -> Option<Box<dyn NodeFunction<T, MsgT, CtrlMsgT, CtrlMsgAT> + Send + Sync>>
+    where
+        Self: Send + Sync + 'static,
+    {
+        Some(Box::new(MemoizedFactor {
+            inner: self.inner.clone_box()?,
+            tolerance: self.tolerance,
+            cache: self.cache.clone(),
+            hits: self.hits,
+            misses: self.misses,
+        }))
+    }
+
+    fn discard_mode(&self) -> bool {
+        self.inner.discard_mode()
+    }
+    fn potential(&self, values: &[T]) -> Option<Probability> {
+        self.inner.potential(values)
+    }
+    fn input_need(&self) -> Option<InputNeed> {
+        self.inner.input_need()
+    }
+    fn inputs_needed(&self, current_step: usize) -> Option<usize> {
+        self.inner.inputs_needed(current_step)
+    }
+}
@@ -0,0 +1,249 @@
+use crate::{BPError, BPGraph, BPResult, Msg, NodeIndex, Probability};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::Instant;
+
+//Change of the marginal of a variable node between two consecutive steps.
+//Both marginals are normalized to sum to one before comparing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MarginalChange {
+    pub node: crate::NodeIndex,
+    //KL(current || previous), infinite if the current marginal puts mass on a value the previous one excluded
+    pub kl_divergence: f64,
+    pub total_variation: f64,
+    pub max_abs_diff: f64,
+}
+
+impl MarginalChange {
+    pub fn new<T>(
+        node: crate::NodeIndex,
+        current: &HashMap<T, Probability>,
+        previous: &HashMap<T, Probability>,
+    ) -> Self
+    where
+        T: Eq + Hash,
+    {
+        let sum_cur: Probability = current.values().sum();
+        let sum_prev: Probability = previous.values().sum();
+        let mut kl_divergence = 0.0;
+        //Mass gained and lost, equal up to rounding. Their maximum is the total variation, so that a
+        //single difference never exceeds it.
+        let (mut gained, mut lost) = (0.0, 0.0);
+        let mut max_abs_diff: f64 = 0.0;
+        for (v, p) in current {
+            let p = p / sum_cur;
+            let q = previous.get(v).map_or(0.0, |q| q / sum_prev);
+            if p > 0.0 {
+                kl_divergence += p * (p / q).ln();
+            }
+            if p > q {
+                gained += p - q;
+            } else {
+                lost += q - p;
+            }
+            max_abs_diff = max_abs_diff.max((p - q).abs());
+        }
+        //Values missing in the current marginal
+        for (v, q) in previous {
+            if !current.contains_key(v) {
+                let q = q / sum_prev;
+                lost += q;
+                max_abs_diff = max_abs_diff.max(q);
+            }
+        }
+        MarginalChange {
+            node,
+            kl_divergence,
+            total_variation: f64::max(gained, lost),
+            max_abs_diff,
+        }
+    }
+}
+
+//Shannon entropy (in nats) of a marginal, normalized to sum to one first
+pub fn entropy<T>(marginal: &HashMap<T, Probability>) -> f64 {
+    let sum: Probability = marginal.values().sum();
+    -marginal
+        .values()
+        .map(|p| p / sum)
+        .filter(|p| *p > 0.0)
+        .map(|p| p * p.ln())
+        .sum::<f64>()
+}
+
+//Difference between the largest and the second largest probability of a marginal (normalized to sum to one
+//first), 1 for a marginal with a single value
+pub fn polarization<T>(marginal: &HashMap<T, Probability>) -> f64 {
+    let sum: Probability = marginal.values().sum();
+    let (first, second) = marginal.values().fold((0.0, 0.0), |(first, second), p| {
+        if *p > first {
+            (*p, first)
+        } else {
+            (first, second.max(*p))
+        }
+    });
+    (first - second) / sum
+}
+
+type Marginals<T> = [Option<HashMap<T, Probability>>];
+
+//Writes one JSON line per propagation step, see BPGraph::set_metrics_output
+#[allow(clippy::type_complexity)]
+pub(crate) struct MetricsEmitter<T> {
+    writer: Mutex<Box<dyn Write + Send>>,
+    start: Instant,
+    summarize: fn(&Marginals<T>, &Marginals<T>) -> (Option<f64>, Option<f64>),
+}
+
+impl<T> MetricsEmitter<T> {
+    pub(crate) fn new<W: Write + Send + 'static>(writer: W) -> Self
+    where
+        T: Eq + Hash,
+    {
+        MetricsEmitter {
+            writer: Mutex::new(Box::new(writer)),
+            start: Instant::now(),
+            summarize: summarize::<T>,
+        }
+    }
+
+    pub(crate) fn emit(
+        &mut self,
+        step: usize,
+        messages: usize,
+        current: &Marginals<T>,
+        previous: &Marginals<T>,
+    ) -> BPResult<()> {
+        let (residual, mean_entropy) = (self.summarize)(current, previous);
+        //JSON has no infinite numbers
+        let number = |x: Option<f64>| match x {
+            Some(x) if x.is_finite() => x.to_string(),
+            _ => "null".to_owned(),
+        };
+        let writer = self.writer.get_mut().expect("Locking mutex failed.");
+        writeln!(
+            writer,
+            "{{\"step\":{},\"residual\":{},\"mean_entropy\":{},\"wall_time\":{},\"messages\":{}}}",
+            step,
+            number(residual),
+            number(mean_entropy),
+            self.start.elapsed().as_secs_f64(),
+            messages
+        )
+        .and_then(|_| writer.flush())
+        .map_err(|e| {
+            BPError::new(
+                "MetricsEmitter::emit".to_owned(),
+                format!("Writing metrics of step {} failed: {}", step, e),
+            )
+        })
+    }
+}
+
+//Largest maximal absolute difference between the current and previous marginal of a node and the mean entropy
+//of the current marginals, None if there are no such marginals
+fn summarize<T: Eq + Hash>(
+    current: &Marginals<T>,
+    previous: &Marginals<T>,
+) -> (Option<f64>, Option<f64>) {
+    let residual = current
+        .iter()
+        .zip(previous.iter())
+        .enumerate()
+        .filter_map(|(i, (cur, prev))| match (cur, prev) {
+            (Some(cur), Some(prev)) => Some(MarginalChange::new(i, cur, prev).max_abs_diff),
+            _ => None,
+        })
+        .fold(None, |max: Option<f64>, d| {
+            Some(max.map_or(d, |max| max.max(d)))
+        });
+    let entropies: Vec<f64> = current.iter().flatten().map(entropy).collect();
+    let mean_entropy = if entropies.is_empty() {
+        None
+    } else {
+        Some(entropies.iter().sum::<f64>() / entropies.len() as f64)
+    };
+    (residual, mean_entropy)
+}
+
+impl<T, MsgT: Msg<T> + Clone, CtrlMsgT, CtrlMsgAT: Default> BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>
+where
+    T: Copy + Eq + Hash + Debug,
+{
+    //Entropy of the current belief of a node, None if it has no result
+    pub fn get_entropy(&self, node_index: NodeIndex) -> BPResult<Option<f64>> {
+        Ok(self
+            .certainty_result(node_index, "BPGraph::get_entropy")?
+            .map(|m| entropy(&m)))
+    }
+
+    //Polarization (largest minus second largest probability) of the current belief of a node, None if it has no result
+    pub fn get_polarization(&self, node_index: NodeIndex) -> BPResult<Option<f64>> {
+        Ok(self
+            .certainty_result(node_index, "BPGraph::get_polarization")?
+            .map(|m| polarization(&m)))
+    }
+
+    //The k variable nodes with the smallest entropy as (node, entropy), ties are broken by the smallest index.
+    //Variables without a result are skipped.
+    pub fn most_certain_nodes(&self, k: usize) -> BPResult<Vec<(NodeIndex, f64)>> {
+        let mut entropies = self.variable_entropies()?;
+        entropies.sort_by(|(n0, e0), (n1, e1)| {
+            e0.partial_cmp(e1)
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then(n0.cmp(n1))
+        });
+        entropies.truncate(k);
+        Ok(entropies)
+    }
+
+    //The k variable nodes with the largest entropy as (node, entropy), ties are broken by the smallest index.
+    //Variables without a result are skipped.
+    pub fn least_certain_nodes(&self, k: usize) -> BPResult<Vec<(NodeIndex, f64)>> {
+        let mut entropies = self.variable_entropies()?;
+        entropies.sort_by(|(n0, e0), (n1, e1)| {
+            e1.partial_cmp(e0)
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then(n0.cmp(n1))
+        });
+        entropies.truncate(k);
+        Ok(entropies)
+    }
+
+    fn variable_entropies(&self) -> BPResult<Vec<(NodeIndex, f64)>> {
+        let variables: Vec<NodeIndex> = self
+            .nodes()
+            .filter(|(_, _, is_factor)| !is_factor)
+            .map(|(i, _, _)| i)
+            .collect();
+        let mut entropies = Vec::with_capacity(variables.len());
+        for node in variables {
+            if let Some(e) = self.get_entropy(node)? {
+                entropies.push((node, e));
+            }
+        }
+        Ok(entropies)
+    }
+
+    fn certainty_result(
+        &self,
+        node_index: NodeIndex,
+        fn_name: &'static str,
+    ) -> BPResult<Option<HashMap<T, Probability>>> {
+        if !self.get_normalization().is_probability() {
+            return Err(BPError::new(
+                fn_name.to_owned(),
+                "Messages are not probabilities".to_owned(),
+            ));
+        }
+        self.get_result(node_index).map_err(|e| {
+            e.attach_info_str(
+                fn_name,
+                format!("Could not get the result of node {}", node_index),
+            )
+        })
+    }
+}
@@ -0,0 +1,344 @@
+use crate::semiring;
+use crate::{
+    BPError, BPResult, DenseMsg, Msg, NodeFunction, NodeIndex, NttPlan, Probability, Semiring,
+};
+use std::sync::Arc;
+
+//Cyclic convolution over Z_q: res[c] = sum_{a + b = c mod q} x[a] * y[b]
+pub fn cyclic_convolution(x: &[Probability], y: &[Probability]) -> Vec<Probability> {
+    cyclic_convolution_in(x, y, None)
+}
+
+//Cyclic correlation over Z_q: res[a] = sum_b y[b] * z[a + b mod q]
+pub fn cyclic_correlation(y: &[Probability], z: &[Probability]) -> Vec<Probability> {
+    cyclic_correlation_in(y, z, None)
+}
+
+//cyclic_convolution with plus and times of semiring (sum-product if None)
+pub fn cyclic_convolution_in(
+    x: &[Probability],
+    y: &[Probability],
+    semiring: Option<&dyn Semiring>,
+) -> Vec<Probability> {
+    let q = x.len();
+    let zero = semiring::zero(semiring);
+    let mut res = vec![zero; q];
+    for (a, pa) in x.iter().enumerate() {
+        if *pa == zero {
+            continue;
+        }
+        for (b, pb) in y.iter().enumerate() {
+            let c = (a + b) % q;
+            res[c] = semiring::plus(semiring, res[c], semiring::times(semiring, *pa, *pb));
+        }
+    }
+    res
+}
+
+//cyclic_correlation with plus and times of semiring (sum-product if None)
+pub fn cyclic_correlation_in(
+    y: &[Probability],
+    z: &[Probability],
+    semiring: Option<&dyn Semiring>,
+) -> Vec<Probability> {
+    let q = z.len();
+    let zero = semiring::zero(semiring);
+    let mut res = vec![zero; q];
+    for (b, pb) in y.iter().enumerate() {
+        if *pb == zero {
+            continue;
+        }
+        for (a, r) in res.iter_mut().enumerate() {
+            *r = semiring::plus(semiring, *r, semiring::times(semiring, *pb, z[(a + b) % q]));
+        }
+    }
+    res
+}
+
+pub(crate) fn check_inbox(
+    function_name: &str,
+    connections: &Option<Vec<NodeIndex>>,
+    inbox: &[(NodeIndex, DenseMsg)],
+    modulus: usize,
+) -> BPResult<Vec<usize>> {
+    let connections = connections
+        .as_ref()
+        .ok_or_else(|| BPError::new(function_name.to_owned(), "Node not initialized".to_owned()))?;
+    if inbox.len() != connections.len() {
+        return Err(BPError::new(
+            function_name.to_owned(),
+            format!(
+                "Wrong number of messages ({}, needed: {})",
+                inbox.len(),
+                connections.len()
+            ),
+        ));
+    }
+    //Position of each connection in the inbox
+    let mut positions = Vec::with_capacity(connections.len());
+    for con in connections {
+        let pos = inbox
+            .iter()
+            .position(|(from, _)| from == con)
+            .ok_or_else(|| {
+                BPError::new(
+                    function_name.to_owned(),
+                    format!("Missing message from {}", con),
+                )
+            })?;
+        if inbox[pos].1.len() != modulus {
+            return Err(BPError::new(
+                function_name.to_owned(),
+                format!(
+                    "Message from {} has wrong length ({}, needed: {})",
+                    con,
+                    inbox[pos].1.len(),
+                    modulus
+                ),
+            ));
+        }
+        positions.push(pos);
+    }
+    Ok(positions)
+}
+
+//Smallest modulus for which ModAddFactor uses the NTT, the direct computation is faster below
+const MIN_NTT_MODULUS: usize = 64;
+
+//Factor enforcing x + y = z (mod q).
+//The connections are interpreted in the order in which the edges were added: x, y, z.
+//The messages are computed directly in O(q^2). With set_use_ntt, for the sum-product semiring and q a power
+//of two (at least MIN_NTT_MODULUS), they are computed with the transforms of NttPlan::shared instead
+//(see NttConvolutionFactor), which is faster but only approximate.
+#[derive(Clone)]
+pub struct ModAddFactor {
+    modulus: usize,
+    connections: Option<Vec<NodeIndex>>,
+    semiring: Option<Arc<dyn Semiring>>,
+    use_ntt: bool,
+    //Set by initialize if the transforms are used
+    plan: Option<Arc<NttPlan>>,
+}
+
+impl ModAddFactor {
+    pub fn new(modulus: usize) -> Self {
+        ModAddFactor {
+            modulus,
+            connections: None,
+            semiring: None,
+            use_ntt: false,
+            plan: None,
+        }
+    }
+    //Takes effect at the next initialize
+    pub fn set_use_ntt(&mut self, use_ntt: bool) {
+        self.use_ntt = use_ntt;
+    }
+    //Whether the messages are computed with the transforms of an NttPlan (after initialize)
+    pub fn uses_ntt(&self) -> bool {
+        self.plan.is_some()
+    }
+    pub fn modulus(&self) -> usize {
+        self.modulus
+    }
+}
+
+impl<CtrlMsgT, CtrlMsgAT: Default> NodeFunction<usize, DenseMsg, CtrlMsgT, CtrlMsgAT>
+    for ModAddFactor
+{
+    fn node_function(
+        &mut self,
+        inbox: Vec<(NodeIndex, DenseMsg)>,
+    ) -> BPResult<Vec<(NodeIndex, DenseMsg)>> {
+        let pos = check_inbox(
+            "ModAddFactor::node_function",
+            &self.connections,
+            &inbox,
+            self.modulus,
+        )?;
+        let (x, y, z) = (&inbox[pos[0]], &inbox[pos[1]], &inbox[pos[2]]);
+        let (x_msg, y_msg, z_msg) = (x.1.as_slice(), y.1.as_slice(), z.1.as_slice());
+        let semiring = self.semiring.as_deref();
+        let (to_x, to_y, to_z) = match &self.plan {
+            Some(plan) if semiring::kind(semiring) == semiring::SemiringKind::SumProduct => (
+                plan.correlate(y_msg, z_msg)?,
+                plan.correlate(x_msg, z_msg)?,
+                plan.convolve(x_msg, y_msg)?,
+            ),
+            _ => (
+                cyclic_correlation_in(y_msg, z_msg, semiring),
+                cyclic_correlation_in(x_msg, z_msg, semiring),
+                cyclic_convolution_in(x_msg, y_msg, semiring),
+            ),
+        };
+        Ok(vec![
+            (x.0, DenseMsg::from_vec(to_x)),
+            (y.0, DenseMsg::from_vec(to_y)),
+            (z.0, DenseMsg::from_vec(to_z)),
+        ])
+    }
+    impl_clone_box!(usize, DenseMsg, CtrlMsgT, CtrlMsgAT);
+
+    fn is_factor(&self) -> bool {
+        true
+    }
+    fn number_inputs(&self) -> Option<usize> {
+        Some(3)
+    }
+    fn initialize(&mut self, connections: Vec<NodeIndex>) -> BPResult<()> {
+        if connections.len() != 3 {
+            return Err(BPError::new(
+                "ModAddFactor::initialize".to_owned(),
+                "ModAddFactor needs exactly three connections".to_owned(),
+            ));
+        }
+        if self.modulus == 0 {
+            return Err(BPError::new(
+                "ModAddFactor::initialize".to_owned(),
+                "Modulus must not be 0".to_owned(),
+            ));
+        }
+        //Falls back to the direct computation if there is no plan
+        self.plan =
+            if self.use_ntt && self.modulus >= MIN_NTT_MODULUS && self.modulus.is_power_of_two() {
+                NttPlan::shared(self.modulus).ok()
+            } else {
+                None
+            };
+        self.connections = Some(connections);
+        Ok(())
+    }
+    fn is_ready(
+        &self,
+        recv_from: &Vec<(NodeIndex, DenseMsg)>,
+        _current_step: usize,
+    ) -> BPResult<bool> {
+        Ok(recv_from.len() == 3)
+    }
+    fn reset(&mut self) -> BPResult<()> {
+        self.connections = None;
+        Ok(())
+    }
+    fn get_prior(&self) -> Option<DenseMsg> {
+        None
+    }
+    fn set_semiring(&mut self, semiring: Arc<dyn Semiring>) {
+        self.semiring = Some(semiring);
+    }
+    fn potential(&self, values: &[usize]) -> Option<Probability> {
+        let q = self.modulus;
+        Some(if (values[0] + values[1]) % q == values[2] % q {
+            1.0
+        } else {
+            0.0
+        })
+    }
+}
+
+//Factor enforcing c * x = y (mod q) for a constant c.
+//The connections are interpreted in the order in which the edges were added: x, y.
+//A modulus of 0 is rejected by initialize.
+#[derive(Clone)]
+pub struct ModMulFactor {
+    modulus: usize,
+    factor: usize,
+    connections: Option<Vec<NodeIndex>>,
+    semiring: Option<Arc<dyn Semiring>>,
+}
+
+impl ModMulFactor {
+    pub fn new(factor: usize, modulus: usize) -> Self {
+        ModMulFactor {
+            modulus,
+            factor: factor.checked_rem(modulus).unwrap_or(factor),
+            connections: None,
+            semiring: None,
+        }
+    }
+    pub fn modulus(&self) -> usize {
+        self.modulus
+    }
+    pub fn factor(&self) -> usize {
+        self.factor
+    }
+}
+
+impl<CtrlMsgT, CtrlMsgAT: Default> NodeFunction<usize, DenseMsg, CtrlMsgT, CtrlMsgAT>
+    for ModMulFactor
+{
+    fn node_function(
+        &mut self,
+        inbox: Vec<(NodeIndex, DenseMsg)>,
+    ) -> BPResult<Vec<(NodeIndex, DenseMsg)>> {
+        let pos = check_inbox(
+            "ModMulFactor::node_function",
+            &self.connections,
+            &inbox,
+            self.modulus,
+        )?;
+        let (x, y) = (&inbox[pos[0]], &inbox[pos[1]]);
+        let q = self.modulus;
+        let semiring = self.semiring.as_deref();
+        let zero = semiring::zero(semiring);
+        let mut to_x = vec![zero; q];
+        let mut to_y = vec![zero; q];
+        for (a, (px, pto_x)) in x.1.as_slice().iter().zip(to_x.iter_mut()).enumerate() {
+            let b = (self.factor * a) % q;
+            *pto_x = y.1.as_slice()[b];
+            to_y[b] = semiring::plus(semiring, to_y[b], *px);
+        }
+        Ok(vec![
+            (x.0, DenseMsg::from_vec(to_x)),
+            (y.0, DenseMsg::from_vec(to_y)),
+        ])
+    }
+    impl_clone_box!(usize, DenseMsg, CtrlMsgT, CtrlMsgAT);
+
+    fn is_factor(&self) -> bool {
+        true
+    }
+    fn number_inputs(&self) -> Option<usize> {
+        Some(2)
+    }
+    fn initialize(&mut self, connections: Vec<NodeIndex>) -> BPResult<()> {
+        if connections.len() != 2 {
+            return Err(BPError::new(
+                "ModMulFactor::initialize".to_owned(),
+                "ModMulFactor needs exactly two connections".to_owned(),
+            ));
+        }
+        if self.modulus == 0 {
+            return Err(BPError::new(
+                "ModMulFactor::initialize".to_owned(),
+                "Modulus must not be 0".to_owned(),
+            ));
+        }
+        self.connections = Some(connections);
+        Ok(())
+    }
+    fn is_ready(
+        &self,
+        recv_from: &Vec<(NodeIndex, DenseMsg)>,
+        _current_step: usize,
+    ) -> BPResult<bool> {
+        Ok(recv_from.len() == 2)
+    }
+    fn reset(&mut self) -> BPResult<()> {
+        self.connections = None;
+        Ok(())
+    }
+    fn get_prior(&self) -> Option<DenseMsg> {
+        None
+    }
+    fn set_semiring(&mut self, semiring: Arc<dyn Semiring>) {
+        self.semiring = Some(semiring);
+    }
+    fn potential(&self, values: &[usize]) -> Option<Probability> {
+        let q = self.modulus;
+        Some(if (self.factor * values[0]) % q == values[1] % q {
+            1.0
+        } else {
+            0.0
+        })
+    }
+}
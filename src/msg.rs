@@ -1,4 +1,4 @@
-use crate::{BPError, BPResult, Probability};
+use crate::{BPError, BPResult, Probability, Semiring};
 use std::collections::HashMap;
 use std::fmt::Debug;
 
@@ -14,19 +14,329 @@ where
     fn get_mut(&mut self, value: T) -> Option<&mut Probability>;
     fn insert(&mut self, value: T, p: Probability);
     fn normalize(&mut self) -> BPResult<()>;
+    //The default implementation only supports NormalizationMode::SumToOne and NormalizationMode::None
+    fn normalize_with(&mut self, mode: NormalizationMode) -> BPResult<()> {
+        match mode {
+            NormalizationMode::SumToOne => self.normalize(),
+            NormalizationMode::None => Ok(()),
+            _ => Err(BPError::new(
+                "Msg::normalize_with".to_owned(),
+                format!(
+                    "Normalization mode {:?} is not supported by this message type",
+                    mode
+                ),
+            )),
+        }
+    }
+    //(shift, scale) such that normalize_with(mode) maps every entry p to (p - shift) * scale
+    //(see NormalizationMode::affine). The default implementation iterates a copy of the message.
+    fn normalization(&self, mode: NormalizationMode) -> BPResult<(Probability, Probability)>
+    where
+        Self: Clone,
+    {
+        mode.affine(
+            self.clone().into_iter().map(|(_, p)| p),
+            "Msg::normalization",
+        )
+    }
     fn is_valid(&self) -> bool;
+    //Like is_valid, but reports the offending entries.
+    //The default implementation cannot name them and should be overridden.
+    fn validate(&self) -> Result<(), MsgValidityError> {
+        if self.is_valid() {
+            Ok(())
+        } else {
+            Err(MsgValidityError::default())
+        }
+    }
     fn mult_msg(&mut self, other: &Self);
-    fn mult_msg_weighted(&mut self, other: &Self, alpha: f64) {
-        todo!("Not implemented.");
+    //Empties the message but may keep its allocation for reuse
+    fn clear(&mut self)
+    where
+        Self: Sized,
+    {
+        *self = Self::new();
     }
-    fn add_msg_weighted(&mut self, other: &Self, alpha_self: f64, alpha_other: f64) {
-        todo!("Not implemented.");
+    //Values missing in other are kept (as in mult_msg). The default implementation iterates a copy of other.
+    fn mult_msg_weighted(&mut self, other: &Self, alpha: f64)
+    where
+        Self: Clone,
+    {
+        for (v, p0) in other.clone() {
+            if let Some(p) = self.get_mut(v) {
+                *p *= p0.powf(alpha);
+            }
+        }
+        //Like mult_msg, an unnormalizable message is left as is
+        let _ = self.normalize();
+    }
+    //Values missing in other count as 0, values missing in self are ignored.
+    //The default implementation iterates a copy of other.
+    fn add_msg_weighted(&mut self, other: &Self, alpha_self: f64, alpha_other: f64)
+    where
+        Self: Clone,
+    {
+        self.for_each(|p| alpha_self * p);
+        for (v, p0) in other.clone() {
+            if let Some(p) = self.get_mut(v) {
+                *p += alpha_other * p0;
+            }
+        }
+    }
+    //Like mult_msg, but with the times of semiring and without normalizing.
+    //Values missing in other are kept (as in mult_msg). The default implementation iterates a copy of other.
+    fn times_msg(&mut self, other: &Self, semiring: &dyn Semiring)
+    where
+        Self: Clone,
+    {
+        for (v, p0) in other.clone() {
+            if let Some(p) = self.get_mut(v) {
+                *p = semiring.times(*p, p0);
+            }
+        }
     }
     //.iter_mut would be preferable but makes things complicated as impl returns are not complete
-    fn for_each(&mut self, f: impl FnMut(Probability) -> Probability) {
-        todo!("Not implemented.");
+    //The default implementation rebuilds the message entry by entry
+    fn for_each(&mut self, mut f: impl FnMut(Probability) -> Probability)
+    where
+        Self: Sized,
+    {
+        for (v, p) in std::mem::replace(self, Self::new()) {
+            self.insert(v, f(p));
+        }
+    }
+}
+//How messages are normalized before being sent (see BPGraph::set_normalization) and how results are normalized
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NormalizationMode {
+    //Divide by the sum
+    #[default]
+    SumToOne,
+    //Divide by the maximum
+    MaxToOne,
+    //For messages in the log domain: subtract the maximum, i.e., the maximal entry becomes 0.
+    //Validity checks are skipped in this mode.
+    LogShift,
+    //For costs (e.g., min-sum): subtract the minimum, i.e., the minimal entry becomes 0.
+    //Validity checks are skipped in this mode.
+    MinShift,
+    None,
+}
+
+//What happens to a message that cannot be normalized because all of its entries are 0, e.g., because of
+//contradictory evidence (see BPGraph::set_zero_message_policy). Only applies to normalization modes for
+//probabilities; detecting the zeros needs Msg::for_each.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ZeroMessagePolicy {
+    //Stop the propagation with an error
+    #[default]
+    Error,
+    //Send a uniform message over the same values instead
+    ReplaceWithUniform,
+    //Do not deliver the message
+    DropMessage,
+    //Do not deliver the message and record the edge, see BPGraph::get_contradictions
+    MarkContradiction,
+}
+
+impl NormalizationMode {
+    //false if normalized messages are not probabilities (and cannot be validated)
+    pub fn is_probability(self) -> bool {
+        !matches!(
+            self,
+            NormalizationMode::LogShift | NormalizationMode::MinShift
+        )
+    }
+
+    //Returns (shift, scale) such that the normalized entries are (p - shift) * scale
+    pub(crate) fn affine(
+        self,
+        values: impl Iterator<Item = Probability>,
+        fn_name: &str,
+    ) -> BPResult<(Probability, Probability)> {
+        let (mut count, mut sum, mut max, mut min) = (
+            0usize,
+            ExactSum::default(),
+            Probability::NEG_INFINITY,
+            Probability::INFINITY,
+        );
+        for p in values {
+            count += 1;
+            sum.add(p);
+            //NaN propagates through the sum but not through max and min
+            max = if p.is_nan() { p } else { max.max(p) };
+            min = if p.is_nan() { p } else { min.min(p) };
+        }
+        let err = |what: &str, value: Probability| {
+            Err(BPError::new(
+                fn_name.to_owned(),
+                format!("Could not normalize message ({}: {})", what, value),
+            ))
+        };
+        if count == 0 && self != NormalizationMode::None {
+            return Err(BPError::new(
+                fn_name.to_owned(),
+                "Message is empty".to_owned(),
+            ));
+        }
+        let sum = sum.value();
+        match self {
+            NormalizationMode::SumToOne if sum.is_nan() || sum <= 0.0 || sum.is_infinite() => {
+                err("sum", sum)
+            }
+            NormalizationMode::SumToOne => Ok((0.0, 1.0 / sum)),
+            NormalizationMode::MaxToOne if max.is_nan() || max <= 0.0 || max.is_infinite() => {
+                err("max", max)
+            }
+            NormalizationMode::MaxToOne => Ok((0.0, 1.0 / max)),
+            NormalizationMode::LogShift if !max.is_finite() => err("max", max),
+            NormalizationMode::LogShift => Ok((max, 1.0)),
+            NormalizationMode::MinShift if !min.is_finite() => err("min", min),
+            NormalizationMode::MinShift => Ok((min, 1.0)),
+            NormalizationMode::None => Ok((0.0, 1.0)),
+        }
     }
 }
+
+//Correctly rounded sum (as Python's math.fsum), which does not depend on the order of the values. Messages
+//stored in HashMaps are iterated in a different order every time, so a naive sum would make their
+//normalization (and equal results computed twice) differ in the last bits.
+#[derive(Default)]
+struct ExactSum {
+    //Non-overlapping partial sums, increasing in magnitude
+    partials: Vec<Probability>,
+    //Naive sum, used if a value is not finite
+    naive: Probability,
+}
+
+impl ExactSum {
+    fn add(&mut self, mut x: Probability) {
+        self.naive += x;
+        if !x.is_finite() {
+            return;
+        }
+        let mut i = 0;
+        for j in 0..self.partials.len() {
+            let mut y = self.partials[j];
+            if x.abs() < y.abs() {
+                std::mem::swap(&mut x, &mut y);
+            }
+            let hi = x + y;
+            let lo = y - (hi - x);
+            if lo != 0.0 {
+                self.partials[i] = lo;
+                i += 1;
+            }
+            x = hi;
+        }
+        self.partials.truncate(i);
+        self.partials.push(x);
+    }
+
+    fn value(&self) -> Probability {
+        if !self.naive.is_finite() {
+            return self.naive;
+        }
+        let mut n = self.partials.len();
+        let mut hi = 0.0;
+        let mut lo = 0.0;
+        if n > 0 {
+            n -= 1;
+            hi = self.partials[n];
+            while n > 0 {
+                let x = hi;
+                n -= 1;
+                let y = self.partials[n];
+                hi = x + y;
+                lo = y - (hi - x);
+                if lo != 0.0 {
+                    break;
+                }
+            }
+            //Round half to even with respect to the remaining partials
+            if n > 0
+                && ((lo < 0.0 && self.partials[n - 1] < 0.0)
+                    || (lo > 0.0 && self.partials[n - 1] > 0.0))
+            {
+                let y = lo * 2.0;
+                let x = hi + y;
+                if y == x - hi {
+                    hi = x;
+                }
+            }
+        }
+        hi
+    }
+}
+
+//Entries of a message that are NaN, negative or greater than one.
+//Only the first MsgValidityError::MAX_EXAMPLES offending entries are kept, the counts are complete.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MsgValidityError {
+    pub nan: usize,
+    pub negative: usize,
+    pub greater_one: usize,
+    pub examples: Vec<(String, Probability)>,
+}
+
+impl MsgValidityError {
+    pub const MAX_EXAMPLES: usize = 8;
+
+    pub fn from_entries<V: Debug>(entries: impl IntoIterator<Item = (V, Probability)>) -> Self {
+        let mut err = MsgValidityError::default();
+        for (v, p) in entries {
+            if p.is_nan() {
+                err.nan += 1;
+            } else if p < 0.0 {
+                err.negative += 1;
+            } else if p > 1.0 {
+                err.greater_one += 1;
+            } else {
+                continue;
+            }
+            if err.examples.len() < Self::MAX_EXAMPLES {
+                err.examples.push((format!("{:?}", v), p));
+            }
+        }
+        err
+    }
+    pub fn count(&self) -> usize {
+        self.nan + self.negative + self.greater_one
+    }
+    pub fn into_result(self) -> Result<(), Self> {
+        if self.count() == 0 {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl std::fmt::Display for MsgValidityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.count() == 0 {
+            return write!(f, "invalid message");
+        }
+        write!(
+            f,
+            "{} invalid entries ({} NaN, {} negative, {} greater than one)",
+            self.count(),
+            self.nan,
+            self.negative,
+            self.greater_one
+        )?;
+        if !self.examples.is_empty() {
+            let examples: Vec<String> = self
+                .examples
+                .iter()
+                .map(|(v, p)| format!("{}: {}", v, p))
+                .collect();
+            write!(f, ", e.g., {}", examples.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
 /*
 impl<MsgT: Msg<T>, T: Clone> MultMsg<T> for MsgT
     where for<'a> &'a MsgT: IntoIterator<Item = (T, Probability)>
@@ -72,25 +382,144 @@ where
         self.insert(value, p);
     }
     fn normalize(&mut self) -> BPResult<()> {
-        if self.is_empty() {
-            return Err(BPError::new(
-                "HashMap as Msg::normalize".to_owned(),
-                "Message is empty".to_owned(),
-            ));
+        self.normalize_with(NormalizationMode::SumToOne)
+    }
+    fn normalize_with(&mut self, mode: NormalizationMode) -> BPResult<()> {
+        let (shift, scale) =
+            mode.affine(self.values().copied(), "HashMap as Msg::normalize_with")?;
+        self.values_mut().for_each(|p| *p = (*p - shift) * scale);
+        Ok(())
+    }
+    fn normalization(&self, mode: NormalizationMode) -> BPResult<(Probability, Probability)> {
+        mode.affine(self.values().copied(), "HashMap as Msg::normalization")
+    }
+    fn is_valid(&self) -> bool {
+        self.iter()
+            .all(|(_, p)| !p.is_nan() && *p >= 0 as Probability && *p <= 1.0 as Probability)
+    }
+    fn validate(&self) -> Result<(), MsgValidityError> {
+        MsgValidityError::from_entries(self.iter().map(|(v, p)| (v, *p))).into_result()
+    }
+    fn mult_msg(&mut self, other: &Self) {
+        mult_hashmaps(self, other);
+    }
+    //Values missing in other are kept (as in mult_msg)
+    fn mult_msg_weighted(&mut self, other: &Self, alpha: f64) {
+        for (v, p0) in other {
+            if let Some(p) = HashMap::get_mut(self, v) {
+                *p *= p0.powf(alpha);
+            }
         }
-        let len = self.len() as Probability;
-        for (_, p) in self.iter_mut() {
-            *p *= len;
+        crate::node::norm_hashmap(self);
+    }
+    //Values missing in other are kept (as in mult_msg)
+    fn times_msg(&mut self, other: &Self, semiring: &dyn Semiring) {
+        for (v, p0) in other {
+            if let Some(p) = HashMap::get_mut(self, v) {
+                *p = semiring.times(*p, *p0);
+            }
         }
+    }
+    //Values missing in other count as 0, values missing in self are ignored
+    fn add_msg_weighted(&mut self, other: &Self, alpha_self: f64, alpha_other: f64) {
+        for (v, p) in self.iter_mut() {
+            *p = alpha_self * *p + alpha_other * other.get(v).copied().unwrap_or(0.0);
+        }
+    }
+    fn clear(&mut self) {
+        HashMap::clear(self);
+    }
+    fn for_each(&mut self, mut f: impl FnMut(Probability) -> Probability) {
+        self.values_mut().for_each(|p| *p = f(*p));
+    }
+}
+
+//Like HashMap, but entries are iterated in insertion order, so normalization and products accumulate in the
+//same order in every run. Results are reproducible bit by bit if the node functions insert the entries of
+//their messages in a deterministic order (e.g., FnFactor, which enumerates the incoming messages, but not
+//PairwiseFactor, which iterates a HashMap) and are read with BPGraph::get_result_msg (get_result converts
+//to HashMap).
+#[cfg(feature = "indexmap")]
+impl<T> Msg<T> for indexmap::IndexMap<T, Probability>
+where
+    T: std::hash::Hash + Eq + Debug,
+{
+    fn new() -> Self {
+        indexmap::IndexMap::new()
+    }
+    fn get(&self, value: T) -> Option<Probability> {
+        indexmap::IndexMap::get(self, &value).copied()
+    }
+    fn get_mut(&mut self, value: T) -> Option<&mut Probability> {
+        indexmap::IndexMap::get_mut(self, &value)
+    }
+    //New values are appended, existing ones keep their position
+    fn insert(&mut self, value: T, p: Probability) {
+        indexmap::IndexMap::insert(self, value, p);
+    }
+    fn normalize(&mut self) -> BPResult<()> {
+        self.normalize_with(NormalizationMode::SumToOne)
+    }
+    fn normalize_with(&mut self, mode: NormalizationMode) -> BPResult<()> {
+        let (shift, scale) =
+            mode.affine(self.values().copied(), "IndexMap as Msg::normalize_with")?;
+        self.values_mut().for_each(|p| *p = (*p - shift) * scale);
         Ok(())
     }
+    fn normalization(&self, mode: NormalizationMode) -> BPResult<(Probability, Probability)> {
+        mode.affine(self.values().copied(), "IndexMap as Msg::normalization")
+    }
     fn is_valid(&self) -> bool {
         self.iter()
             .all(|(_, p)| !p.is_nan() && *p >= 0 as Probability && *p <= 1.0 as Probability)
     }
+    fn validate(&self) -> Result<(), MsgValidityError> {
+        MsgValidityError::from_entries(self.iter().map(|(v, p)| (v, *p))).into_result()
+    }
+    //Values missing in other are kept, the product is divided by its largest absolute entry (as for HashMap)
     fn mult_msg(&mut self, other: &Self) {
-        mult_hashmaps(self, other);
+        for (v, p0) in other {
+            if let Some(p) = indexmap::IndexMap::get_mut(self, v) {
+                *p *= p0;
+            }
+        }
+        norm_max_abs(self);
+    }
+    fn mult_msg_weighted(&mut self, other: &Self, alpha: f64) {
+        for (v, p0) in other {
+            if let Some(p) = indexmap::IndexMap::get_mut(self, v) {
+                *p *= p0.powf(alpha);
+            }
+        }
+        norm_max_abs(self);
+    }
+    fn times_msg(&mut self, other: &Self, semiring: &dyn Semiring) {
+        for (v, p0) in other {
+            if let Some(p) = indexmap::IndexMap::get_mut(self, v) {
+                *p = semiring.times(*p, *p0);
+            }
+        }
+    }
+    fn add_msg_weighted(&mut self, other: &Self, alpha_self: f64, alpha_other: f64) {
+        for (v, p) in self.iter_mut() {
+            *p = alpha_self * *p + alpha_other * other.get(v).copied().unwrap_or(0.0);
+        }
+    }
+    fn clear(&mut self) {
+        indexmap::IndexMap::clear(self);
+    }
+    fn for_each(&mut self, mut f: impl FnMut(Probability) -> Probability) {
+        self.values_mut().for_each(|p| *p = f(*p));
     }
 }
 
-//TODO: indexmap
+//Divides by the largest absolute entry, entries are left as they are if it is 0 or NaN (see norm_hashmap)
+#[cfg(feature = "indexmap")]
+fn norm_max_abs<T>(map: &mut indexmap::IndexMap<T, Probability>) {
+    let max = map
+        .values()
+        .fold(0.0, |max: Probability, p| max.max(p.abs()));
+    if max > 0.0 {
+        map.values_mut().for_each(|p| *p /= max);
+    }
+}
@@ -0,0 +1,96 @@
+use crate::{Msg, NodeIndex};
+
+//Free list of cleared messages.
+//Messages that are discarded by the graph are recycled here, so their allocations can be reused. The buffers
+//of outgoing messages are pooled as well. When propagating sequentially, node functions take their new
+//messages from the pool (see NodeFunction::node_function_pooled), e.g., the copies sent by variable nodes.
+//At most max_size messages and max_size buffers are kept.
+pub struct MsgPool<MsgT> {
+    free: Vec<MsgT>,
+    buffers: Vec<Vec<(NodeIndex, MsgT)>>,
+    max_size: usize,
+}
+
+impl<MsgT> MsgPool<MsgT> {
+    pub fn new(max_size: usize) -> Self {
+        MsgPool {
+            free: Vec::new(),
+            buffers: Vec::new(),
+            max_size,
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.free.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.free.is_empty()
+    }
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+        self.free.truncate(max_size);
+        self.buffers.truncate(max_size);
+    }
+    //Drops all pooled messages and buffers
+    pub fn clear(&mut self) {
+        self.free = Vec::new();
+        self.buffers = Vec::new();
+    }
+    //Number of pooled buffers of outgoing messages
+    pub fn buffers(&self) -> usize {
+        self.buffers.len()
+    }
+    //Returns an empty buffer with at least capacity, reusing a pooled one if available
+    pub fn take_buffer(&mut self, capacity: usize) -> Vec<(NodeIndex, MsgT)> {
+        match self.buffers.pop() {
+            Some(mut buffer) => {
+                buffer.reserve(capacity);
+                buffer
+            }
+            None => Vec::with_capacity(capacity),
+        }
+    }
+    //Remaining messages in the buffer are dropped, not recycled
+    pub fn recycle_buffer(&mut self, mut buffer: Vec<(NodeIndex, MsgT)>) {
+        if buffer.capacity() > 0 && self.buffers.len() < self.max_size {
+            buffer.clear();
+            self.buffers.push(buffer);
+        }
+    }
+}
+
+impl<MsgT> MsgPool<MsgT> {
+    //Returns a cleared message, reusing a pooled one if available
+    pub fn take<T>(&mut self) -> MsgT
+    where
+        MsgT: Msg<T>,
+    {
+        self.free.pop().unwrap_or_else(MsgT::new)
+    }
+    //Copy of msg in a pooled message. Only saves an allocation if MsgT::clone_from reuses it.
+    pub fn take_clone<T>(&mut self, msg: &MsgT) -> MsgT
+    where
+        MsgT: Msg<T> + Clone,
+    {
+        let mut copy = self.take();
+        copy.clone_from(msg);
+        copy
+    }
+    pub fn recycle<T>(&mut self, mut msg: MsgT)
+    where
+        MsgT: Msg<T>,
+    {
+        if self.free.len() < self.max_size {
+            msg.clear();
+            self.free.push(msg);
+        }
+    }
+}
+
+impl<MsgT> Default for MsgPool<MsgT> {
+    fn default() -> Self {
+        Self::new(1024)
+    }
+}
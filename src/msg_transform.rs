@@ -0,0 +1,16 @@
+use crate::{BPResult, Msg};
+
+//Applied to every message sent along a directed edge (see BPGraph::set_edge_transform), e.g., to model
+//channel noise, leakage or quantization. The transform runs before the message is normalized.
+pub trait MsgTransform<T, MsgT: Msg<T>>: Send + Sync {
+    fn transform(&self, msg: MsgT) -> BPResult<MsgT>;
+}
+
+impl<T, MsgT: Msg<T>, F> MsgTransform<T, MsgT> for F
+where
+    F: Fn(MsgT) -> BPResult<MsgT> + Send + Sync,
+{
+    fn transform(&self, msg: MsgT) -> BPResult<MsgT> {
+        self(msg)
+    }
+}
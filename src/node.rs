@@ -1,17 +1,62 @@
-use crate::{BPError, BPResult, Msg, NodeFunction, NodeIndex, Probability};
-use std::collections::HashMap;
+use crate::semiring;
+use crate::{
+    BPError, BPResult, GraphInfo, InputNeed, Msg, MsgPool, NodeFunction, NodeIndex,
+    NormalizationMode, Probability, Semiring,
+};
+use itertools::Itertools;
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
 use std::default::Default;
 use std::fmt::Debug;
+use std::sync::{Arc, OnceLock};
+
+//Nodes with more connections keep a hash set of them for is_connected
+const CONNECTION_INDEX_THRESHOLD: usize = 32;
+
+//Copy of the inbox and the node function state of a node, see BPGraph::snapshot
+pub(crate) struct NodeState<MsgT> {
+    inbox: Vec<(NodeIndex, MsgT)>,
+    last_received: Vec<(NodeIndex, MsgT)>,
+    function_state: Option<Box<dyn Any + Send + Sync>>,
+}
 
 pub struct Node<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default>
 where
     T: Debug,
 {
     name: String,
+    //In insertion order, as passed to NodeFunction::initialize
     connections: Vec<NodeIndex>,
+    //Set of the connections if there are more than CONNECTION_INDEX_THRESHOLD
+    connection_index: Option<HashSet<NodeIndex>>,
+    //Named connections as (port, connection), see BPGraph::add_edge_port
+    ports: Vec<(String, NodeIndex)>,
     inbox: Vec<(NodeIndex, MsgT)>,
+    //Position of the first message of every sender in the inbox, built by send_post for nodes with a
+    //connection_index and dropped whenever the inbox changes otherwise
+    inbox_index: Option<HashMap<NodeIndex, usize>>,
+    //Allocation of a previous inbox, reused by read_post
+    spare_inbox: Vec<(NodeIndex, MsgT)>,
+    //Copies of the last message received from every connection, only kept if clone_msg is set
+    //(see BPGraph::set_incremental)
+    last_received: Vec<(NodeIndex, MsgT)>,
+    clone_msg: Option<fn(&MsgT) -> MsgT>,
     node_function: Box<dyn NodeFunction<T, MsgT, CtrlMsgT, CtrlMsgAT> + Send + Sync>,
     is_initialized: bool,
+    //Set once NodeFunction::on_graph_initialized succeeded after the last initialize
+    graph_notified: bool,
+    //Result computed by get_result_cached and the mode it is normalized with, cleared whenever the inbox or the
+    //node function changes
+    result_cache: OnceLock<(NormalizationMode, Option<HashMap<T, Probability>>)>,
+    //Used to combine the messages in get_result, see BPGraph::set_semiring
+    semiring: Option<Arc<dyn Semiring>>,
+    //How send_post handles a message from a sender that already has a message in the inbox
+    inbox_policy: InboxPolicy,
+    //Connections whose edge is disabled (see BPGraph::disable_edge) with the neutral message that is kept in
+    //the inbox in place of their messages, so that the node function still sees all connections
+    disabled: Vec<(NodeIndex, MsgT)>,
+    //Set by disable_connection, clones the neutral messages into every new inbox
+    clone_neutral: Option<fn(&MsgT) -> MsgT>,
 }
 
 impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default> Node<T, MsgT, CtrlMsgT, CtrlMsgAT>
@@ -30,19 +75,85 @@ where
         Node {
             name,
             is_initialized: false,
+            graph_notified: false,
             connections: Vec::new(),
+            connection_index: None,
+            ports: Vec::new(),
             inbox,
+            inbox_index: None,
+            spare_inbox: Vec::new(),
+            last_received: Vec::new(),
+            clone_msg: None,
             node_function,
+            result_cache: OnceLock::new(),
+            semiring: None,
+            inbox_policy: InboxPolicy::default(),
+            disabled: Vec::new(),
+            clone_neutral: None,
         }
     }
+    pub fn accepts_control_message(&self, ctrl_msg: &CtrlMsgT) -> bool {
+        self.node_function.accepts_control_message(ctrl_msg)
+    }
+    pub fn control_message_changes_node(&self, ctrl_msg: &CtrlMsgT) -> bool {
+        self.node_function.control_message_changes_node(ctrl_msg)
+    }
+    //Control messages may change the prior
     pub fn send_control_message(&mut self, ctrl_msg: CtrlMsgT) -> BPResult<CtrlMsgAT> {
+        if self.control_message_changes_node(&ctrl_msg) {
+            self.invalidate_result();
+        }
         self.node_function.send_control_message(ctrl_msg)
     }
+    pub fn invalidate_result(&mut self) {
+        self.result_cache.take();
+    }
+    pub fn set_semiring(&mut self, semiring: Arc<dyn Semiring>) {
+        self.invalidate_result();
+        self.node_function.set_semiring(semiring.clone());
+        self.semiring = Some(semiring);
+    }
+    pub fn restrict_domain(&mut self, variable: NodeIndex, values: &[T]) -> BPResult<()> {
+        self.invalidate_result();
+        self.node_function.restrict_domain(variable, values)
+    }
+    pub fn learns_parameters(&self) -> bool {
+        self.node_function.learns_parameters()
+    }
+    pub fn accumulate_statistics(&mut self, belief: &HashMap<Vec<T>, Probability>) -> BPResult<()> {
+        self.node_function.accumulate_statistics(belief)
+    }
+    //The potential changes, so the cached result is dropped
+    pub fn update_parameters(&mut self) -> BPResult<()> {
+        self.invalidate_result();
+        self.node_function.update_parameters()
+    }
+    pub fn node_function_as_any(&self) -> &dyn Any
+    where
+        T: 'static,
+        MsgT: 'static,
+        CtrlMsgT: 'static,
+        CtrlMsgAT: 'static,
+    {
+        (*self.node_function).as_any()
+    }
+    //The node function may be changed, so the cached result is dropped
+    pub fn node_function_as_any_mut(&mut self) -> &mut dyn Any
+    where
+        T: 'static,
+        MsgT: 'static,
+        CtrlMsgT: 'static,
+        CtrlMsgAT: 'static,
+    {
+        self.invalidate_result();
+        (*self.node_function).as_any_mut()
+    }
     pub fn get_name(&self) -> &String {
         &self.name
     }
+    //The node has to be initialized again after adding an edge.
     pub fn add_edge(&mut self, to: NodeIndex) -> BPResult<()> {
-        if self.connections.contains(&to) {
+        if self.is_connected(to) {
             return Err(BPError::new(
                 "Node::add_edge".to_owned(),
                 format!("Connection -> {} already exists", to),
@@ -54,21 +165,90 @@ where
             }
         }
         self.connections.push(to);
+        match self.connection_index.as_mut() {
+            Some(index) => {
+                index.insert(to);
+            }
+            None => self.update_connection_index(),
+        }
+        self.is_initialized = false;
         Ok(())
     }
+    //Adds connections without checking for duplicates or the number of inputs
+    pub(crate) fn extend_connections(&mut self, to: impl IntoIterator<Item = NodeIndex>) {
+        self.connections.extend(to);
+        self.update_connection_index();
+        self.is_initialized = false;
+    }
+    //O(1) for nodes with many connections
+    pub fn is_connected(&self, to: NodeIndex) -> bool {
+        match &self.connection_index {
+            Some(index) => index.contains(&to),
+            None => self.connections.contains(&to),
+        }
+    }
+    fn update_connection_index(&mut self) {
+        self.connection_index = if self.connections.len() > CONNECTION_INDEX_THRESHOLD {
+            Some(self.connections.iter().copied().collect())
+        } else {
+            None
+        };
+    }
     pub fn is_initialized(&self) -> bool {
         self.is_initialized
     }
     pub fn reset(&mut self) -> BPResult<()> {
         self.node_function.reset()?;
+        self.invalidate_result();
         self.inbox = Vec::new();
+        self.inbox_index = None;
         let num_input = self.node_function.number_inputs();
         if let Some(num_input) = num_input {
             self.inbox.reserve(num_input);
         }
+        self.add_neutral_post();
+        self.last_received.clear();
         self.is_initialized = false;
         Ok(())
     }
+    //Drops the inbox and the last received messages and resets the schedule state of the node function.
+    //The node stays initialized.
+    pub fn reset_schedule_state(&mut self, pool: &mut MsgPool<MsgT>) -> BPResult<()> {
+        self.node_function.reset_schedule_state()?;
+        let post = self.read_post();
+        self.recycle_post(post, pool);
+        self.last_received.clear();
+        Ok(())
+    }
+    pub(crate) fn save_state(&self) -> NodeState<MsgT>
+    where
+        MsgT: Clone,
+    {
+        NodeState {
+            inbox: self.inbox.clone(),
+            last_received: self.last_received.clone(),
+            function_state: self.node_function.save_state(),
+        }
+    }
+    pub(crate) fn load_state(
+        &mut self,
+        state: NodeState<MsgT>,
+        pool: &mut MsgPool<MsgT>,
+    ) -> BPResult<()> {
+        if let Some(function_state) = state.function_state {
+            self.node_function.load_state(function_state)?;
+        }
+        let post = std::mem::replace(&mut self.inbox, state.inbox);
+        self.inbox_index = None;
+        self.recycle_post(post, pool);
+        //The edges may have been disabled or enabled since the state was saved
+        let disabled: Vec<NodeIndex> = self.disabled.iter().map(|(con, _)| *con).collect();
+        self.inbox.retain(|(from, _)| !disabled.contains(from));
+        self.add_neutral_post();
+        self.last_received = state.last_received;
+        self.invalidate_result();
+        Ok(())
+    }
     pub fn number_inputs(&self) -> Option<usize> {
         self.node_function.number_inputs()
     }
@@ -93,30 +273,346 @@ where
             }
         }
         self.is_initialized = true;
-        self.node_function.initialize(self.connections.clone())
+        self.graph_notified = false;
+        self.update_connection_index();
+        self.node_function.initialize(self.connections.clone())?;
+        if !self.ports.is_empty() {
+            self.node_function.initialize_ports(self.ports.clone())?;
+        }
+        Ok(())
+    }
+    pub fn on_step_end(&mut self, step: usize) -> BPResult<()> {
+        self.node_function.on_step_end(step)
+    }
+    pub fn on_graph_initialized(&mut self, graph_info: &GraphInfo) -> BPResult<()> {
+        self.node_function
+            .on_graph_initialized(&self.connections, graph_info)?;
+        self.graph_notified = true;
+        Ok(())
+    }
+    //Initialized, but on_graph_initialized did not succeed yet
+    pub(crate) fn needs_graph_info(&self) -> bool {
+        self.is_initialized && !self.graph_notified
+    }
+    //Names the connection to. The node has to be initialized again afterwards.
+    pub fn add_port(&mut self, port: String, to: NodeIndex) -> BPResult<()> {
+        if self.get_port(&port).is_some() {
+            return Err(BPError::new(
+                "Node::add_port".to_owned(),
+                format!("Port {} of node {} is already bound", port, self.name),
+            ));
+        }
+        self.ports.push((port, to));
+        self.is_initialized = false;
+        Ok(())
+    }
+    pub fn get_port(&self, port: &str) -> Option<NodeIndex> {
+        self.ports
+            .iter()
+            .find(|(p, _)| p == port)
+            .map(|(_, to)| *to)
+    }
+    pub fn get_ports(&self) -> &Vec<(String, NodeIndex)> {
+        &self.ports
+    }
+    pub fn declared_ports(&self) -> Vec<String> {
+        self.node_function.ports()
     }
     pub fn get_connections(&self) -> &Vec<NodeIndex> {
         &self.connections
     }
 
+    //The set of connections is rebuilt by initialize
     pub fn get_connections_mut(&mut self) -> &mut Vec<NodeIndex> {
+        self.connection_index = None;
         &mut self.connections
     }
+
+    //Changes the indices of the connections and of the senders in the inbox, indices mapped to None are removed.
+    //The node has to be initialized again afterwards.
+    pub fn remap_indices(&mut self, f: impl Fn(NodeIndex) -> Option<NodeIndex>) {
+        self.connections = self.connections.iter().filter_map(|c| f(*c)).collect();
+        self.ports = std::mem::take(&mut self.ports)
+            .into_iter()
+            .filter_map(|(port, to)| f(to).map(|to| (port, to)))
+            .collect();
+        self.update_connection_index();
+        self.invalidate_result();
+        self.inbox = std::mem::take(&mut self.inbox)
+            .into_iter()
+            .filter_map(|(from, msg)| f(from).map(|from| (from, msg)))
+            .collect();
+        self.inbox_index = None;
+        self.last_received = std::mem::take(&mut self.last_received)
+            .into_iter()
+            .filter_map(|(from, msg)| f(from).map(|from| (from, msg)))
+            .collect();
+        self.disabled = std::mem::take(&mut self.disabled)
+            .into_iter()
+            .filter_map(|(con, msg)| f(con).map(|con| (con, msg)))
+            .collect();
+        self.is_initialized = false;
+    }
     pub fn is_factor(&self) -> bool {
         self.node_function.is_factor()
     }
+    pub fn get_prior(&self) -> Option<MsgT> {
+        self.node_function.get_prior()
+    }
+    pub fn potential(&self, values: &[T]) -> Option<Probability> {
+        self.node_function.potential(values)
+    }
+    //The neutral messages of disabled connections do not count
     pub fn has_post(&self) -> bool {
-        !self.inbox.is_empty()
+        self.inbox.len() > self.disabled.len()
+    }
+    pub(crate) fn has_post_from(&self, from: NodeIndex) -> bool {
+        self.inbox.iter().any(|(idx, _)| *idx == from)
+    }
+    pub fn post_len(&self) -> usize {
+        self.inbox.len()
+    }
+    pub(crate) fn get_post(&self) -> &[(NodeIndex, MsgT)] {
+        &self.inbox
+    }
+    pub fn input_need(&self) -> Option<InputNeed> {
+        self.node_function.input_need()
+    }
+    pub fn inputs_needed(&self, step: usize) -> Option<usize> {
+        self.node_function.inputs_needed(step)
     }
 
     pub fn read_post(&mut self) -> Vec<(NodeIndex, MsgT)> {
-        std::mem::replace(&mut self.inbox, Vec::with_capacity(self.connections.len()))
+        self.invalidate_result();
+        let mut spare = std::mem::take(&mut self.spare_inbox);
+        spare.reserve(self.connections.len());
+        let post = std::mem::replace(&mut self.inbox, spare);
+        self.inbox_index = None;
+        self.add_neutral_post();
+        post
+    }
+
+    fn add_neutral_post(&mut self) {
+        if let Some(clone_neutral) = self.clone_neutral {
+            self.inbox.extend(
+                self.disabled
+                    .iter()
+                    .map(|(con, neutral)| (*con, clone_neutral(neutral))),
+            );
+        }
+    }
+
+    pub fn is_disabled(&self, con: NodeIndex) -> bool {
+        self.disabled.iter().any(|(idx, _)| *idx == con)
     }
 
+    //Drops the messages from con (see send_post) and keeps neutral in the inbox in their place, see
+    //BPGraph::disable_edge. Replaces the neutral message if con is already disabled.
+    pub fn disable_connection(&mut self, con: NodeIndex, neutral: MsgT) -> BPResult<()>
+    where
+        MsgT: Clone,
+    {
+        if !self.is_connected(con) {
+            return Err(BPError::new(
+                "Node::disable_connection".to_owned(),
+                format!("Node {} is not connected to {}", self.name, con),
+            ));
+        }
+        self.disabled.retain(|(idx, _)| *idx != con);
+        self.inbox.retain(|(from, _)| *from != con);
+        self.inbox_index = None;
+        self.last_received.retain(|(from, _)| *from != con);
+        self.inbox.push((con, neutral.clone()));
+        self.disabled.push((con, neutral));
+        self.clone_neutral = Some(MsgT::clone);
+        self.invalidate_result();
+        Ok(())
+    }
+    //Accepts messages from con again. Returns false if the connection was not disabled.
+    //The neutral message stays in the inbox until it is read (or replaced, see InboxPolicy), so that the
+    //nodes at both ends of the edge do not wait for each other.
+    pub fn enable_connection(&mut self, con: NodeIndex) -> bool {
+        match self.disabled.iter().position(|(idx, _)| *idx == con) {
+            Some(pos) => {
+                self.disabled.remove(pos);
+                true
+            }
+            None => false,
+        }
+    }
+
+    //Returns an inbox obtained by read_post that is no longer needed.
+    //The messages are recycled into the pool and the allocation of the inbox is kept for the next read_post.
+    pub fn recycle_post(&mut self, mut post: Vec<(NodeIndex, MsgT)>, pool: &mut MsgPool<MsgT>) {
+        for (_, msg) in post.drain(..) {
+            pool.recycle(msg);
+        }
+        if post.capacity() > self.spare_inbox.capacity() {
+            self.spare_inbox = post;
+        }
+    }
+
+    //Messages from disabled connections are dropped
     pub fn send_post(&mut self, from: NodeIndex, msg: MsgT) {
+        self.post(from, msg);
+    }
+
+    //Like send_post, but messages that are dropped or replaced are recycled into pool
+    pub fn send_post_recycling(&mut self, from: NodeIndex, msg: MsgT, pool: &mut MsgPool<MsgT>) {
+        if let Some(msg) = self.post(from, msg) {
+            pool.recycle(msg);
+        }
+    }
+
+    //Returns the message that is not kept, if any
+    fn post(&mut self, from: NodeIndex, msg: MsgT) -> Option<MsgT> {
+        if self.is_disabled(from) {
+            return Some(msg);
+        }
+        self.invalidate_result();
+        if let Some(clone_msg) = self.clone_msg {
+            let copy = clone_msg(&msg);
+            match self.last_received.iter_mut().find(|(idx, _)| *idx == from) {
+                Some(entry) => entry.1 = copy,
+                None => self.last_received.push((from, copy)),
+            }
+        }
+        if self.inbox_policy != InboxPolicy::Accumulate {
+            if let Some(pos) = self.inbox_position(from) {
+                if self.inbox_policy == InboxPolicy::KeepLatest {
+                    return Some(std::mem::replace(&mut self.inbox[pos].1, msg));
+                }
+                return Some(msg);
+            }
+        }
+        if let Some(index) = self.inbox_index.as_mut() {
+            index.entry(from).or_insert(self.inbox.len());
+        }
         self.inbox.push((from, msg));
+        None
+    }
+
+    //Position of the first message from from in the inbox, O(1) for nodes with many connections
+    fn inbox_position(&mut self, from: NodeIndex) -> Option<usize> {
+        if self.connection_index.is_none() {
+            return self.inbox.iter().position(|(idx, _)| *idx == from);
+        }
+        let inbox = &self.inbox;
+        self.inbox_index
+            .get_or_insert_with(|| {
+                let mut index = HashMap::with_capacity(inbox.len());
+                for (pos, (idx, _)) in inbox.iter().enumerate() {
+                    index.entry(*idx).or_insert(pos);
+                }
+                index
+            })
+            .get(&from)
+            .copied()
+    }
+
+    pub fn set_inbox_policy(&mut self, policy: InboxPolicy) {
+        self.inbox_policy = policy;
+    }
+    pub fn get_inbox_policy(&self) -> InboxPolicy {
+        self.inbox_policy
+    }
+
+    //Keeps a copy (made by clone_msg) of the last message received from every connection, None disables this.
+    pub fn set_keep_last_received(&mut self, clone_msg: Option<fn(&MsgT) -> MsgT>) {
+        if clone_msg.is_none() {
+            self.last_received = Vec::new();
+        }
+        self.clone_msg = clone_msg;
+    }
+    pub fn get_last_received(&self) -> &[(NodeIndex, MsgT)] {
+        &self.last_received
+    }
+
+    //Replaces the inbox by copies of the last received messages (in the order of the connections).
+    //Variable nodes get a message with all probabilities set to one for connections that have not sent yet
+    //(e.g., new factors), based on another received message or the prior.
+    pub fn restore_post(&mut self) -> BPResult<()> {
+        let clone_msg = self.clone_msg.ok_or_else(|| {
+            BPError::new(
+                "Node::restore_post".to_owned(),
+                format!(
+                    "Node {} does not keep the last received messages",
+                    self.name
+                ),
+            )
+        })?;
+        let neutral = if self.is_factor() {
+            None
+        } else {
+            self.last_received
+                .first()
+                .map(|(_, msg)| clone_msg(msg))
+                .or_else(|| self.node_function.get_prior())
+                .map(|mut msg| {
+                    msg.for_each(|_| 1.0);
+                    msg
+                })
+        };
+        self.invalidate_result();
+        self.inbox.clear();
+        self.inbox_index = None;
+        for con in &self.connections {
+            if self.disabled.iter().any(|(idx, _)| idx == con) {
+                continue;
+            }
+            match self.last_received.iter().find(|(idx, _)| idx == con) {
+                Some((_, msg)) => self.inbox.push((*con, clone_msg(msg))),
+                None => {
+                    if let Some(neutral) = &neutral {
+                        self.inbox.push((*con, clone_msg(neutral)));
+                    }
+                }
+            }
+        }
+        self.add_neutral_post();
+        Ok(())
     }
 
+    //Fails unless the inbox contains exactly one message from every connection (an empty inbox is allowed,
+    //e.g., for variable nodes sending their prior), naming the missing, duplicate and unknown senders.
+    //The neutral messages of disabled connections do not count.
+    pub fn check_inbox(&self) -> BPResult<()> {
+        if !self.has_post() {
+            return Ok(());
+        }
+        let mut counts: HashMap<NodeIndex, usize> = HashMap::with_capacity(self.inbox.len());
+        for (from, _) in &self.inbox {
+            *counts.entry(*from).or_insert(0) += 1;
+        }
+        let missing: Vec<NodeIndex> = self
+            .connections
+            .iter()
+            .filter(|con| !counts.contains_key(con))
+            .copied()
+            .collect();
+        let mut duplicate: Vec<NodeIndex> = counts
+            .iter()
+            .filter(|(_, count)| **count > 1)
+            .map(|(from, _)| *from)
+            .collect();
+        let mut unknown: Vec<NodeIndex> = counts
+            .keys()
+            .filter(|from| !self.is_connected(**from))
+            .copied()
+            .collect();
+        if missing.is_empty() && duplicate.is_empty() && unknown.is_empty() {
+            return Ok(());
+        }
+        duplicate.sort_unstable();
+        unknown.sort_unstable();
+        Err(BPError::new(
+            "Node::check_inbox".to_owned(),
+            format!(
+                "Inbox of node {} is inconsistent (missing senders: {:?}, duplicate senders: {:?}, unknown senders: {:?})",
+                self.name, missing, duplicate, unknown
+            ),
+        ))
+    }
     pub fn is_ready(&self, step: usize) -> BPResult<bool> {
         self.node_function.is_ready(&self.inbox, step)
     }
@@ -124,14 +620,50 @@ where
         self.node_function.discard_mode()
     }
     pub fn create_messages(&mut self) -> BPResult<Vec<(NodeIndex, MsgT)>> {
-        let incoming_msgs = self.read_post();
+        self.create_messages_impl(None)
+    }
+    //Like create_messages, but the messages left in the inbox are recycled into pool
+    pub fn create_messages_recycling(
+        &mut self,
+        pool: &mut MsgPool<MsgT>,
+    ) -> BPResult<Vec<(NodeIndex, MsgT)>> {
+        self.create_messages_impl(Some(pool))
+    }
+    fn create_messages_impl(
+        &mut self,
+        mut pool: Option<&mut MsgPool<MsgT>>,
+    ) -> BPResult<Vec<(NodeIndex, MsgT)>> {
+        let mut incoming_msgs = self.read_post();
         debug_print!(
             "<{}> starting to create messages: Collected {} incoming messages",
             self.name,
             incoming_msgs.len()
         );
-        //TODO: Check in debug mode if all messages arrived?
-        self.node_function.node_function(incoming_msgs)
+        //The inbox is only checked in strict mode (see BPGraph::set_strict)
+        let mut out = match pool.as_deref_mut() {
+            Some(pool) => pool.take_buffer(self.connections.len()),
+            None => Vec::with_capacity(self.connections.len()),
+        };
+        if self.node_function.borrows_inbox() {
+            let borrowed = incoming_msgs.iter().map(|(idx, msg)| (*idx, msg)).collect();
+            out.extend(self.node_function.node_function_borrowed(borrowed)?);
+        } else if let Some(pool) = pool.as_deref_mut() {
+            self.node_function
+                .node_function_pooled(&mut incoming_msgs, &mut out, pool)?;
+        } else {
+            self.node_function
+                .node_function_inplace(&mut incoming_msgs, &mut out)?;
+        }
+        match pool {
+            Some(pool) => self.recycle_post(incoming_msgs, pool),
+            None => {
+                incoming_msgs.clear();
+                if incoming_msgs.capacity() > self.spare_inbox.capacity() {
+                    self.spare_inbox = incoming_msgs;
+                }
+            }
+        }
+        Ok(out)
     }
 }
 
@@ -143,6 +675,86 @@ where
     pub fn clone_inbox(&self) -> Vec<(NodeIndex, MsgT)> {
         self.inbox.clone()
     }
+    //Copy of the node including its inbox, see NodeFunction::clone_box. The cached result is not copied.
+    pub fn try_clone(&self) -> BPResult<Self>
+    where
+        T: 'static,
+        MsgT: 'static,
+        CtrlMsgT: 'static,
+        CtrlMsgAT: 'static,
+    {
+        let node_function = self.node_function.clone_box().ok_or_else(|| {
+            BPError::new(
+                "Node::try_clone".to_owned(),
+                format!("Node function of node {} cannot be cloned", self.name),
+            )
+        })?;
+        Ok(Node {
+            name: self.name.clone(),
+            is_initialized: self.is_initialized,
+            graph_notified: self.graph_notified,
+            connections: self.connections.clone(),
+            connection_index: self.connection_index.clone(),
+            ports: self.ports.clone(),
+            inbox: self.inbox.clone(),
+            inbox_index: None,
+            spare_inbox: Vec::new(),
+            last_received: self.last_received.clone(),
+            clone_msg: self.clone_msg,
+            node_function,
+            result_cache: OnceLock::new(),
+            semiring: self.semiring.clone(),
+            inbox_policy: self.inbox_policy,
+            disabled: self.disabled.clone(),
+            clone_neutral: self.clone_neutral,
+        })
+    }
+    //Like get_result_with_options, but the messages are multiplied in their native representation
+    //(Msg::mult_msg, or Msg::times_msg with a semiring) instead of being converted to HashMaps.
+    //Values missing in some of the messages are handled by the message type.
+    pub fn get_result_msg(&self, options: &ResultOptions) -> BPResult<Option<MsgT>> {
+        let prior = if options.include_prior {
+            self.node_function.get_prior()
+        } else {
+            None
+        };
+        if self.is_factor() && !self.inbox.is_empty() {
+            info_print!("Results at factor nodes are given by get_factor_belief");
+            return Ok(None);
+        }
+        let mut inbox = self
+            .inbox
+            .iter()
+            .filter(|(from, _)| !options.exclude_neighbors.contains(from));
+        let mut res = match prior {
+            Some(mut prior) => {
+                if self.semiring.is_none() {
+                    prior.normalize().map_err(|e| {
+                        e.attach_info_str(
+                            "Node::get_result_msg",
+                            format!("Failed to normalize the prior of node {}", self.name),
+                        )
+                    })?;
+                }
+                prior
+            }
+            None => match inbox.next() {
+                Some((_, msg)) => msg.clone(),
+                None => {
+                    info_print!(
+                        "Get result: No messages and no prior at node - propagate one step?"
+                    );
+                    return Ok(None);
+                }
+            },
+        };
+        match self.semiring.as_deref() {
+            //Combined in the semiring without normalizing, BPGraph normalizes the result
+            Some(semiring) => inbox.for_each(|(_, msg)| res.times_msg(msg, semiring)),
+            None => inbox.for_each(|(_, msg)| res.mult_msg(msg)),
+        }
+        Ok(Some(res))
+    }
 }
 
 impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default> Node<T, MsgT, CtrlMsgT, CtrlMsgAT>
@@ -151,12 +763,55 @@ where
     MsgT: Clone,
 {
     pub fn get_result(&self) -> BPResult<Option<std::collections::HashMap<T, Probability>>> {
-        let prior = self.node_function.get_prior();
+        self.get_result_with_options(&ResultOptions::default())
+    }
+
+    //Like get_result normalized with mode, but the result is kept until the inbox changes (e.g., new post arrives).
+    //A result cached with another mode has to be invalidated first (see invalidate_result).
+    pub fn get_result_cached(
+        &self,
+        mode: NormalizationMode,
+    ) -> BPResult<Option<&HashMap<T, Probability>>> {
+        if let Some((cached_mode, res)) = self.result_cache.get() {
+            if *cached_mode != mode {
+                return Err(BPError::new(
+                    "Node::get_result_cached".to_owned(),
+                    format!(
+                        "Result is cached with normalization {:?}, not {:?}",
+                        cached_mode, mode
+                    ),
+                ));
+            }
+            return Ok(res.as_ref());
+        }
+        let mut res = self.get_result()?;
+        if let Some(res) = res.as_mut() {
+            res.normalize_with(mode).map_err(|e| {
+                e.attach_info_str(
+                    "Node::get_result_cached",
+                    "Failed to normalize result".to_owned(),
+                )
+            })?;
+        }
+        Ok(self.result_cache.get_or_init(|| (mode, res)).1.as_ref())
+    }
+
+    pub fn get_result_with_options(
+        &self,
+        options: &ResultOptions,
+    ) -> BPResult<Option<std::collections::HashMap<T, Probability>>> {
+        let prior = if options.include_prior {
+            self.node_function.get_prior()
+        } else {
+            None
+        };
         if self.inbox.is_empty() {
             // TODO: use everything
             return if let Some(prior) = prior {
                 let mut prior_hm = msg_to_hashmap(prior);
-                norm_hashmap(&mut prior_hm);
+                if self.semiring.is_none() {
+                    norm_hashmap(&mut prior_hm);
+                }
                 Ok(Some(prior_hm))
             } else {
                 info_print!("Get result: No messages and no prior at node - propagate one step?");
@@ -164,17 +819,35 @@ where
             };
         }
         if self.is_factor() {
-            info_print!("Results at factor nodes are not implemented yet");
+            info_print!("Results at factor nodes are given by get_factor_belief");
             Ok(None)
         } else {
-            let (mut res, start) = if let Some(prior) = self.node_function.get_prior() {
+            let mut inbox = self
+                .inbox
+                .iter()
+                .filter(|(from, _)| !options.exclude_neighbors.contains(from));
+            if let Some(semiring) = self.semiring.as_deref() {
+                //Combined in the semiring without normalizing, BPGraph normalizes the result
+                let mut res = match prior.or_else(|| inbox.next().map(|(_, msg)| msg.clone())) {
+                    Some(res) => res,
+                    None => return Ok(None),
+                };
+                for (_, msg) in inbox {
+                    res.times_msg(msg, semiring);
+                }
+                return Ok(Some(msg_to_hashmap(res)));
+            }
+            let mut res = if let Some(prior) = prior {
                 let mut prior = msg_to_hashmap(prior);
                 norm_hashmap(&mut prior);
-                (prior, 0)
+                prior
+            } else if let Some(inb) = inbox.next() {
+                msg_to_hashmap(inb.1.clone())
             } else {
-                (msg_to_hashmap(self.inbox[0].1.clone()), 1)
+                info_print!("Get result: All messages are excluded and the prior is not used");
+                return Ok(None);
             };
-            for inb in &self.inbox[start..] {
+            for inb in inbox {
                 mult_hashmaps(&mut res, msg_to_hashmap(inb.1.clone())).map_err(|e| {
                     e.attach_info_str(
                         "node::get_result",
@@ -191,14 +864,149 @@ where
             Ok(Some(res))
         }
     }
+
+    //Belief of a factor over the joint assignments of its connections (values in the order of the connections),
+    //i.e., the potential times the incoming messages, normalized to sum to one.
+    //Uses the inbox if it holds a message from every connection, otherwise the last received messages (if kept).
+    //Fails if there are more than max_assignments assignments or the factor does not implement potential.
+    pub fn get_factor_belief(
+        &self,
+        max_assignments: usize,
+    ) -> BPResult<HashMap<Vec<T>, Probability>> {
+        if !self.is_factor() {
+            return Err(BPError::new(
+                "Node::get_factor_belief".to_owned(),
+                format!("Node {} is not a factor", self.name),
+            ));
+        }
+        let msgs = self
+            .messages_from_all_connections(&self.inbox)
+            .or_else(|| self.messages_from_all_connections(&self.last_received))
+            .ok_or_else(|| {
+                BPError::new(
+                    "Node::get_factor_belief".to_owned(),
+                    format!(
+                        "Node {} has not received messages from all connections",
+                        self.name
+                    ),
+                )
+            })?;
+        msgs.iter()
+            .try_fold(1usize, |acc, msg| acc.checked_mul(msg.len()))
+            .filter(|n| *n <= max_assignments)
+            .ok_or_else(|| {
+                BPError::new(
+                    "Node::get_factor_belief".to_owned(),
+                    format!("Too many joint assignments (maximum: {})", max_assignments),
+                )
+            })?;
+        let mut belief = HashMap::new();
+        let mut sum = 0.0;
+        for assignment in msgs.iter().map(|msg| msg.iter()).multi_cartesian_product() {
+            let values: Vec<T> = assignment.iter().map(|(v, _)| *v).collect();
+            let potential = self.potential(&values).ok_or_else(|| {
+                BPError::new(
+                    "Node::get_factor_belief".to_owned(),
+                    format!("Factor {} does not implement potential", self.name),
+                )
+            })?;
+            let p = assignment.iter().fold(potential, |acc, (_, p)| acc * p);
+            sum += p;
+            belief.insert(values, p);
+        }
+        if sum <= 0.0 || sum.is_nan() {
+            return Err(BPError::new(
+                "Node::get_factor_belief".to_owned(),
+                format!("Belief of factor {} cannot be normalized", self.name),
+            ));
+        }
+        belief.values_mut().for_each(|p| *p /= sum);
+        Ok(belief)
+    }
+
+    //Whether the inbox holds a message from every connection
+    pub(crate) fn has_post_from_all_connections(&self) -> bool {
+        self.connections
+            .iter()
+            .all(|con| self.inbox.iter().any(|(from, _)| from == con))
+    }
+    fn messages_from_all_connections(
+        &self,
+        msgs: &[(NodeIndex, MsgT)],
+    ) -> Option<Vec<Vec<(T, Probability)>>> {
+        self.connections
+            .iter()
+            .map(|con| {
+                msgs.iter()
+                    .find(|(from, _)| from == con)
+                    .map(|(_, msg)| msg.clone().into_iter().collect())
+            })
+            .collect()
+    }
+}
+
+//Which factors of the belief of a variable node are used by Node::get_result_with_options.
+//Excluding neighbors yields cavity distributions, excluding the prior the product of the incoming messages.
+#[derive(Debug, Clone, Copy)]
+pub struct ResultOptions<'a> {
+    pub include_prior: bool,
+    pub exclude_neighbors: &'a [NodeIndex],
+}
+
+impl Default for ResultOptions<'_> {
+    fn default() -> Self {
+        ResultOptions {
+            include_prior: true,
+            exclude_neighbors: &[],
+        }
+    }
+}
+
+//Handling of a message from a sender that already has an unread message in the inbox of a node
+//(e.g., a neighbour with InputNeed::Never sending in consecutive steps)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InboxPolicy {
+    //The new message replaces the unread one
+    #[default]
+    KeepLatest,
+    //The new message is dropped
+    KeepFirst,
+    //Both messages are kept, so that both are multiplied into the result (the behaviour of older versions)
+    Accumulate,
+}
+
+//Order of the entries of BPGraph::get_result_sorted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResultOrder {
+    //Ascending values
+    ByValue,
+    //Descending probabilities, ties are broken by ascending values
+    ByProbability,
+}
+
+impl ResultOrder {
+    pub fn sort<T: Ord>(&self, result: HashMap<T, Probability>) -> Vec<(T, Probability)> {
+        let mut sorted: Vec<(T, Probability)> = result.into_iter().collect();
+        match self {
+            ResultOrder::ByValue => sorted.sort_by(|(v0, _), (v1, _)| v0.cmp(v1)),
+            ResultOrder::ByProbability => sorted.sort_by(|(v0, p0), (v1, p1)| {
+                p1.partial_cmp(p0)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+                    .then_with(|| v0.cmp(v1))
+            }),
+        }
+        sorted
+    }
 }
 
+#[allow(clippy::iter_kv_map)]
 pub fn hashmap_to_distribution<T>(map: &mut HashMap<T, Probability>) -> BPResult<()> {
     let sum = map.iter().map(|(_, p)| p).sum::<f64>();
     map.iter_mut().for_each(|(_, p)| *p /= sum);
     Ok(())
 }
 
+#[allow(clippy::iter_kv_map)]
 pub fn norm_hashmap<T>(map: &mut HashMap<T, Probability>) -> BPResult<()>
 where
     T: Eq + std::hash::Hash + Debug,
@@ -224,7 +1032,7 @@ where
     Ok(())
 }
 
-fn msg_to_hashmap<T, MsgT: Msg<T>>(msg: MsgT) -> HashMap<T, Probability>
+pub(crate) fn msg_to_hashmap<T, MsgT: Msg<T>>(msg: MsgT) -> HashMap<T, Probability>
 where
     T: Eq + std::hash::Hash + Debug,
 {
@@ -1,19 +1,198 @@
-use crate::{BPResult, Msg, NodeIndex, Probability};
+use crate::{BPError, BPResult, InputNeed, Msg, MsgPool, NodeIndex, Probability, Semiring};
+use std::any::Any;
+use std::collections::HashMap;
 use std::default::Default;
 use std::fmt::Debug;
+use std::sync::Arc;
 
-pub trait NodeFunction<T, MsgT: Msg<T>, CtrlMsgT = (), CtrlMsgAT: Default = ()> {
+pub trait NodeFunction<T, MsgT: Msg<T>, CtrlMsgT = (), CtrlMsgAT: Default = ()>: AsAny {
     fn node_function(&mut self, inbox: Vec<(NodeIndex, MsgT)>) -> BPResult<Vec<(NodeIndex, MsgT)>>;
+    //Used by BPGraph instead of node_function. out is empty when called.
+    //Messages may be moved out of the inbox; the graph reuses the inbox afterwards.
+    //The default moves all messages out of the inbox and forwards to node_function.
+    fn node_function_inplace(
+        &mut self,
+        inbox: &mut [(NodeIndex, MsgT)],
+        out: &mut Vec<(NodeIndex, MsgT)>,
+    ) -> BPResult<()> {
+        let msgs = inbox
+            .iter_mut()
+            .map(|(idx, msg)| (*idx, std::mem::replace(msg, MsgT::new())))
+            .collect();
+        out.extend(self.node_function(msgs)?);
+        Ok(())
+    }
+    //Used by BPGraph instead of node_function_inplace when propagating sequentially.
+    //New messages may be taken from pool (see MsgPool::take) instead of being allocated.
+    //The default ignores the pool.
+    fn node_function_pooled(
+        &mut self,
+        inbox: &mut [(NodeIndex, MsgT)],
+        out: &mut Vec<(NodeIndex, MsgT)>,
+        pool: &mut MsgPool<MsgT>,
+    ) -> BPResult<()> {
+        self.node_function_inplace(inbox, out)
+    }
+    //Read-only variant of node_function, used by BPGraph instead of node_function_inplace if borrows_inbox
+    //returns true. The messages stay in the inbox and are recycled by the graph afterwards, so nodes that
+    //only read their messages neither move nor copy them.
+    fn node_function_borrowed(
+        &mut self,
+        inbox: Vec<(NodeIndex, &MsgT)>,
+    ) -> BPResult<Vec<(NodeIndex, MsgT)>> {
+        Err(BPError::new(
+            "NodeFunction::node_function_borrowed".to_owned(),
+            "Not implemented (see NodeFunction::borrows_inbox)".to_owned(),
+        ))
+    }
+    fn borrows_inbox(&self) -> bool {
+        false
+    }
     fn is_factor(&self) -> bool;
     fn number_inputs(&self) -> Option<usize>;
     fn initialize(&mut self, connections: Vec<NodeIndex>) -> BPResult<()>;
+    #[allow(clippy::ptr_arg)]
     fn is_ready(&self, recv_from: &Vec<(NodeIndex, MsgT)>, current_step: usize) -> BPResult<bool>;
     fn reset(&mut self) -> BPResult<()>;
+    //Called by BPGraph::reset_schedule_state. Has to forget the progress of the propagation (e.g., whether
+    //the node has sent messages before) but keep priors and connections.
+    fn reset_schedule_state(&mut self) -> BPResult<()> {
+        Ok(())
+    }
     fn get_prior(&self) -> Option<MsgT>;
+    //Names of the ports that have to be bound by BPGraph::add_edge_port, checked by BPGraph::validate.
+    //Node functions that need to know which neighbour is which use ports instead of the order of the connections.
+    fn ports(&self) -> Vec<String> {
+        Vec::new()
+    }
+    //Called after initialize with the bound ports as (port, connection)
+    fn initialize_ports(&mut self, ports: Vec<(String, NodeIndex)>) -> BPResult<()> {
+        Ok(())
+    }
+    //Used by BPGraph::snapshot and BPGraph::restore. Node functions whose state changes during propagation
+    //or through control messages (e.g., priors) return a copy of it; None means there is nothing to restore.
+    fn save_state(&self) -> Option<Box<dyn Any + Send + Sync>> {
+        None
+    }
+    //Gets the value returned by save_state of the same node function
+    fn load_state(&mut self, state: Box<dyn Any + Send + Sync>) -> BPResult<()> {
+        Ok(())
+    }
+    //Node functions handling control messages override both functions. send_control_message has to fail for
+    //messages the node function does not understand instead of answering with a default.
+    //BPGraph delivers messages that are not accepted here as well, but treats a failure of send_control_message
+    //as a rejection instead of an error (e.g., broadcasts skip the node). Node functions that only override
+    //send_control_message thus keep receiving every message.
+    fn accepts_control_message(&self, ctrl_msg: &CtrlMsgT) -> bool {
+        false
+    }
     fn send_control_message(&mut self, ctrl_msg: CtrlMsgT) -> BPResult<CtrlMsgAT> {
-        Ok(CtrlMsgAT::default())
+        Err(BPError::new(
+            "NodeFunction::send_control_message".to_owned(),
+            "Node function does not understand control messages".to_owned(),
+        ))
+    }
+    //Whether ctrl_msg changes the messages of the node (e.g., a new prior). BPGraph only marks the node dirty
+    //(see BPGraph::propagate_incremental) for such messages, so queries return false.
+    fn control_message_changes_node(&self, ctrl_msg: &CtrlMsgT) -> bool {
+        true
+    }
+    //Called by BPGraph after every propagation step for every node (also for nodes that did not send in the
+    //step), step being the index of the step that ended. Node functions can update internal state here (e.g.,
+    //decay their damping).
+    fn on_step_end(&mut self, step: usize) -> BPResult<()> {
+        Ok(())
+    }
+    //Called by BPGraph::initialize after all nodes were initialized, for the nodes initialized by it.
+    //connections are the same as in initialize.
+    fn on_graph_initialized(
+        &mut self,
+        connections: &[NodeIndex],
+        graph_info: &GraphInfo,
+    ) -> BPResult<()> {
+        Ok(())
+    }
+    //Called by BPGraph::set_semiring (and for nodes added afterwards). Node functions that do not support
+    //semirings keep computing sum-product messages.
+    fn set_semiring(&mut self, semiring: Arc<dyn Semiring>) {}
+    //Called by BPGraph::prune after values of the connected variable were removed from its domain.
+    //Node functions enumerating the domains of their neighbours (e.g., tables) can drop the other values.
+    fn restrict_domain(&mut self, variable: NodeIndex, values: &[T]) -> BPResult<()> {
+        Ok(())
+    }
+    //Parameter learning (see BPGraph::em_step): factors returning true get their belief over the joint
+    //assignments of their connections (values in the order of the connections) by accumulate_statistics
+    //for every evidence case and update their parameters (e.g., a table) from the accumulated statistics
+    //in update_parameters, which also forgets the statistics.
+    fn learns_parameters(&self) -> bool {
+        false
+    }
+    fn accumulate_statistics(&mut self, belief: &HashMap<Vec<T>, Probability>) -> BPResult<()> {
+        Ok(())
+    }
+    fn update_parameters(&mut self) -> BPResult<()> {
+        Ok(())
+    }
+    //Copy of the node function, used by BPGraph::try_clone. None if the node function cannot be cloned.
+    //Node functions implementing Clone get it from impl_clone_box!, the built-in node functions do.
+    fn clone_box(&self) -> Option<Box<dyn NodeFunction<T, MsgT, CtrlMsgT, CtrlMsgAT> + Send + Sync>>
+    where
+        Self: Send + Sync + 'static,
+    {
+        None
     }
     fn discard_mode(&self) -> bool {
         false
     }
+    //Value of the factor for an assignment of its neighbours (in the order of the connections).
+    //Only needed for exact inference (BPGraph::exact_marginals_junction_tree and
+    //BPGraph::exact_marginals_bruteforce), None if not supported.
+    fn potential(&self, values: &[T]) -> Option<Probability> {
+        None
+    }
+    //Used by BPGraph::ready_report. The InputNeed of the node, None if it has none.
+    fn input_need(&self) -> Option<InputNeed> {
+        None
+    }
+    //Used by BPGraph::ready_report. Number of messages the node needs to be ready in current_step,
+    //None if unknown (e.g., the readiness depends on the senders).
+    fn inputs_needed(&self, current_step: usize) -> Option<usize> {
+        None
+    }
+}
+
+//Passed to NodeFunction::on_graph_initialized
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GraphInfo {
+    pub node_index: NodeIndex,
+    pub name: String,
+    //Names of the neighbours in the order of the connections
+    pub neighbor_names: Vec<String>,
+    pub number_nodes: usize,
+}
+
+//Typed access after the node function was added to a graph (see BPGraph::get_node_function), implemented for
+//every node function. Call it on the node function itself: a Box or a reference is an Any as well.
+pub trait AsAny {
+    fn as_any(&self) -> &dyn Any
+    where
+        Self: 'static;
+    fn as_any_mut(&mut self) -> &mut dyn Any
+    where
+        Self: 'static;
+}
+
+impl<F> AsAny for F {
+    fn as_any(&self) -> &dyn Any
+    where
+        Self: 'static,
+    {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any
+    where
+        Self: 'static,
+    {
+        self
+    }
 }
@@ -0,0 +1,455 @@
+use crate::modular_factor::{check_inbox, cyclic_convolution_in, cyclic_correlation_in};
+use crate::semiring;
+use crate::{BPError, BPResult, DenseMsg, NodeFunction, NodeIndex, Probability, Semiring};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+
+//Minimal number of bits the entries of a message are quantized to by the NTT backend
+const MIN_QUANTIZATION_BITS: u32 = 16;
+
+//How NttPlan computes convolutions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConvolutionBackend {
+    //Number-theoretic transform modulo prime. The entries of both inputs are scaled to their maximum and
+    //quantized to bits bits, i.e., with an absolute error of up to 2^-bits of the maximum. Inputs with positive
+    //entries below 2^-bits of their maximum, which would be lost, are convolved with the FFT instead.
+    Ntt { prime: u64, bits: u32 },
+    //Floating point FFT, used if no suitable prime is available or the quantization would be too coarse
+    Fft,
+}
+
+//Precomputed transform for cyclic convolutions over Z_q. Cyclic convolutions are computed as linear
+//convolutions of length size >= 2q - 1 (a power of two) that are folded modulo q, so any q is supported.
+//Plans are immutable and meant to be shared, see NttPlan::shared.
+#[derive(Debug)]
+pub struct NttPlan {
+    modulus: usize,
+    size: usize,
+    backend: ConvolutionBackend,
+    //Powers w^0, ..., w^(size/2 - 1) of the root of unity of order size (and of its inverse)
+    roots: Vec<u64>,
+    inverse_roots: Vec<u64>,
+    inverse_size: u64,
+    //(cos, sin) of 2 pi k / size for the FFT backend and the inputs the NTT backend cannot quantize
+    twiddles: Vec<(f64, f64)>,
+}
+
+fn mul_mod(a: u64, b: u64, p: u64) -> u64 {
+    ((a as u128 * b as u128) % p as u128) as u64
+}
+
+fn pow_mod(mut a: u64, mut e: u64, p: u64) -> u64 {
+    let mut res = 1;
+    a %= p;
+    while e > 0 {
+        if e & 1 == 1 {
+            res = mul_mod(res, a, p);
+        }
+        a = mul_mod(a, a, p);
+        e >>= 1;
+    }
+    res
+}
+
+//Deterministic Miller-Rabin for 64 bit integers
+fn is_prime(n: u64) -> bool {
+    if n < 2 {
+        return false;
+    }
+    const BASES: [u64; 12] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37];
+    for b in BASES {
+        if n % b == 0 {
+            return n == b;
+        }
+    }
+    let (mut d, mut s) = (n - 1, 0);
+    while d % 2 == 0 {
+        d /= 2;
+        s += 1;
+    }
+    'bases: for a in BASES {
+        let mut x = pow_mod(a, d, n);
+        if x == 1 || x == n - 1 {
+            continue;
+        }
+        for _ in 1..s {
+            x = mul_mod(x, x, n);
+            if x == n - 1 {
+                continue 'bases;
+            }
+        }
+        return false;
+    }
+    true
+}
+
+//Prime p = k * size + 1 < 2^62 with a root of unity of order size (a power of two), and the root
+fn find_prime(size: usize) -> Option<(u64, u64)> {
+    let size = size as u64;
+    let limit = 1u64 << 62;
+    let first = ((1u64 << 61) / size).max(1);
+    for k in first..first + 1_000_000 {
+        let p = k.checked_mul(size)?.checked_add(1)?;
+        if p >= limit {
+            return None;
+        }
+        if is_prime(p) {
+            //a^((p - 1) / size) has an order dividing size, which is exactly size iff its size/2-th power is not 1
+            for a in 2..1000 {
+                let w = pow_mod(a, (p - 1) / size, p);
+                if size == 1 || pow_mod(w, size / 2, p) != 1 {
+                    return Some((p, w));
+                }
+            }
+        }
+    }
+    None
+}
+
+//Whether no positive entry of v is lost when v is quantized to bits bits (see NttPlan::convolve_ntt)
+fn quantizable(v: &[Probability], bits: u32) -> bool {
+    let max = v.iter().copied().fold(0.0, f64::max);
+    let min = max / ((1u64 << bits) - 1) as f64;
+    v.iter().all(|p| *p <= 0.0 || *p >= min)
+}
+
+fn bit_reverse<V>(a: &mut [V]) {
+    let n = a.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j ^= bit;
+        if i < j {
+            a.swap(i, j);
+        }
+    }
+}
+
+impl NttPlan {
+    //Uses the NTT backend if possible, the FFT backend otherwise
+    pub fn new(modulus: usize) -> BPResult<Self> {
+        let mut plan = Self::fft(modulus)?;
+        let log_q = usize::BITS - (modulus - 1).leading_zeros();
+        let bits = (61u32.saturating_sub(log_q)) / 2;
+        if bits < MIN_QUANTIZATION_BITS {
+            return Ok(plan);
+        }
+        if let Some((prime, root)) = find_prime(plan.size) {
+            let half = plan.size / 2;
+            let inverse_root = pow_mod(root, prime - 2, prime);
+            let powers = |w: u64| {
+                let mut powers = Vec::with_capacity(half);
+                let mut acc = 1;
+                for _ in 0..half {
+                    powers.push(acc);
+                    acc = mul_mod(acc, w, prime);
+                }
+                powers
+            };
+            plan.roots = powers(root);
+            plan.inverse_roots = powers(inverse_root);
+            plan.inverse_size = pow_mod(plan.size as u64, prime - 2, prime);
+            plan.backend = ConvolutionBackend::Ntt { prime, bits };
+        }
+        Ok(plan)
+    }
+
+    //Always uses the FFT backend
+    pub fn fft(modulus: usize) -> BPResult<Self> {
+        if modulus == 0 {
+            return Err(BPError::new(
+                "NttPlan::new".to_owned(),
+                "Modulus has to be positive".to_owned(),
+            ));
+        }
+        let size = (2 * modulus - 1).next_power_of_two();
+        let twiddles = (0..size / 2)
+            .map(|k| {
+                let angle = 2.0 * std::f64::consts::PI * k as f64 / size as f64;
+                (angle.cos(), angle.sin())
+            })
+            .collect();
+        Ok(NttPlan {
+            modulus,
+            size,
+            backend: ConvolutionBackend::Fft,
+            roots: Vec::new(),
+            inverse_roots: Vec::new(),
+            inverse_size: 0,
+            twiddles,
+        })
+    }
+
+    //Plan for modulus shared by all callers in the process, created by NttPlan::new on first use
+    pub fn shared(modulus: usize) -> BPResult<Arc<Self>> {
+        static PLANS: OnceLock<Mutex<HashMap<usize, Arc<NttPlan>>>> = OnceLock::new();
+        let mut plans = PLANS
+            .get_or_init(|| Mutex::new(HashMap::new()))
+            .lock()
+            .expect("Locking mutex failed.");
+        if let Some(plan) = plans.get(&modulus) {
+            return Ok(plan.clone());
+        }
+        let plan = Arc::new(Self::new(modulus)?);
+        plans.insert(modulus, plan.clone());
+        Ok(plan)
+    }
+
+    pub fn modulus(&self) -> usize {
+        self.modulus
+    }
+
+    pub fn backend(&self) -> ConvolutionBackend {
+        self.backend
+    }
+
+    //Cyclic convolution over Z_q like cyclic_convolution, the entries have to be non-negative
+    pub fn convolve(&self, x: &[Probability], y: &[Probability]) -> BPResult<Vec<Probability>> {
+        for v in [x, y] {
+            if v.len() != self.modulus {
+                return Err(BPError::new(
+                    "NttPlan::convolve".to_owned(),
+                    format!("Wrong length ({}, needed: {})", v.len(), self.modulus),
+                ));
+            }
+        }
+        let linear = match self.backend {
+            ConvolutionBackend::Ntt { prime, bits }
+                if quantizable(x, bits) && quantizable(y, bits) =>
+            {
+                self.convolve_ntt(x, y, prime, bits)
+            }
+            _ => self.convolve_fft(x, y),
+        };
+        let mut res = vec![0.0; self.modulus];
+        for (i, v) in linear.into_iter().enumerate().take(2 * self.modulus - 1) {
+            res[i % self.modulus] += v;
+        }
+        Ok(res)
+    }
+
+    //Cyclic correlation over Z_q like cyclic_correlation
+    pub fn correlate(&self, y: &[Probability], z: &[Probability]) -> BPResult<Vec<Probability>> {
+        let q = self.modulus;
+        //res[a] = sum_b y[b] z[a + b] = sum_b y'[-b] z[a + b] with y'[b] = y[-b]
+        let reversed: Vec<Probability> = (0..y.len()).map(|b| y[(q - b) % q]).collect();
+        self.convolve(z, &reversed)
+    }
+
+    fn number_transform(&self, a: &mut [u64], roots: &[u64], prime: u64) {
+        bit_reverse(a);
+        let n = a.len();
+        let mut len = 2;
+        while len <= n {
+            let step = n / len;
+            for start in (0..n).step_by(len) {
+                for k in 0..len / 2 {
+                    let u = a[start + k];
+                    let v = mul_mod(a[start + k + len / 2], roots[k * step], prime);
+                    a[start + k] = if u + v >= prime { u + v - prime } else { u + v };
+                    a[start + k + len / 2] = if u >= v { u - v } else { u + prime - v };
+                }
+            }
+            len <<= 1;
+        }
+    }
+
+    fn convolve_ntt(
+        &self,
+        x: &[Probability],
+        y: &[Probability],
+        prime: u64,
+        bits: u32,
+    ) -> Vec<Probability> {
+        let scale = ((1u64 << bits) - 1) as f64;
+        let quantize = |v: &[Probability]| -> (Vec<u64>, f64) {
+            let max = v.iter().copied().fold(0.0, f64::max);
+            let mut a = vec![0; self.size];
+            if max > 0.0 {
+                for (ai, vi) in a.iter_mut().zip(v) {
+                    *ai = (vi / max * scale).round().max(0.0) as u64;
+                }
+            }
+            (a, max)
+        };
+        let (mut a, max_x) = quantize(x);
+        let (mut b, max_y) = quantize(y);
+        self.number_transform(&mut a, &self.roots, prime);
+        self.number_transform(&mut b, &self.roots, prime);
+        for (ai, bi) in a.iter_mut().zip(&b) {
+            *ai = mul_mod(*ai, *bi, prime);
+        }
+        self.number_transform(&mut a, &self.inverse_roots, prime);
+        let factor = max_x * max_y / (scale * scale);
+        a.into_iter()
+            .map(|v| mul_mod(v, self.inverse_size, prime) as f64 * factor)
+            .collect()
+    }
+
+    fn transform(&self, a: &mut [(f64, f64)], inverse: bool) {
+        bit_reverse(a);
+        let n = a.len();
+        let mut len = 2;
+        while len <= n {
+            let step = n / len;
+            for start in (0..n).step_by(len) {
+                for k in 0..len / 2 {
+                    let (c, s) = self.twiddles[k * step];
+                    let s = if inverse { s } else { -s };
+                    let (ur, ui) = a[start + k];
+                    let (vr, vi) = a[start + k + len / 2];
+                    let (tr, ti) = (vr * c - vi * s, vr * s + vi * c);
+                    a[start + k] = (ur + tr, ui + ti);
+                    a[start + k + len / 2] = (ur - tr, ui - ti);
+                }
+            }
+            len <<= 1;
+        }
+    }
+
+    fn convolve_fft(&self, x: &[Probability], y: &[Probability]) -> Vec<Probability> {
+        //Both inputs are real, so they are transformed at once as x + iy
+        let mut a = vec![(0.0, 0.0); self.size];
+        for (i, ai) in a.iter_mut().enumerate().take(self.modulus) {
+            *ai = (x[i], y[i]);
+        }
+        self.transform(&mut a, false);
+        let n = self.size;
+        //X[k] Y[k] = (A[k]^2 - conj(A[-k])^2) / 4i
+        let product: Vec<(f64, f64)> = (0..n)
+            .map(|k| {
+                let (ar, ai) = a[k];
+                let (br, bi) = a[(n - k) % n];
+                let (br, bi) = (br, -bi);
+                let (sr, si) = (
+                    ar * ar - ai * ai - (br * br - bi * bi),
+                    2.0 * ar * ai - 2.0 * br * bi,
+                );
+                (si / 4.0, -sr / 4.0)
+            })
+            .collect();
+        let mut product = product;
+        self.transform(&mut product, true);
+        //Rounding errors may leave tiny negative entries
+        product
+            .into_iter()
+            .map(|(r, _)| (r / n as f64).max(0.0))
+            .collect()
+    }
+}
+
+//Factor enforcing x + y = z (mod q) like ModAddFactor, but computing the messages with the transforms of a
+//shared NttPlan in O(q log q) instead of O(q^2) for every q (ModAddFactor only does so for large powers of
+//two). Only the sum-product semiring is transformed, other semirings fall back to the direct computation.
+//The connections are interpreted in the order in which the edges were added: x, y, z.
+#[derive(Clone)]
+pub struct NttConvolutionFactor {
+    plan: Arc<NttPlan>,
+    connections: Option<Vec<NodeIndex>>,
+    semiring: Option<Arc<dyn Semiring>>,
+}
+
+impl NttConvolutionFactor {
+    pub fn new(plan: Arc<NttPlan>) -> Self {
+        NttConvolutionFactor {
+            plan,
+            connections: None,
+            semiring: None,
+        }
+    }
+    //Uses the plan of NttPlan::shared
+    pub fn with_modulus(modulus: usize) -> BPResult<Self> {
+        Ok(Self::new(NttPlan::shared(modulus)?))
+    }
+    pub fn modulus(&self) -> usize {
+        self.plan.modulus
+    }
+    pub fn plan(&self) -> &Arc<NttPlan> {
+        &self.plan
+    }
+}
+
+impl<CtrlMsgT, CtrlMsgAT: Default> NodeFunction<usize, DenseMsg, CtrlMsgT, CtrlMsgAT>
+    for NttConvolutionFactor
+{
+    fn node_function(
+        &mut self,
+        inbox: Vec<(NodeIndex, DenseMsg)>,
+    ) -> BPResult<Vec<(NodeIndex, DenseMsg)>> {
+        let pos = check_inbox(
+            "NttConvolutionFactor::node_function",
+            &self.connections,
+            &inbox,
+            self.plan.modulus,
+        )?;
+        let (x, y, z) = (&inbox[pos[0]], &inbox[pos[1]], &inbox[pos[2]]);
+        let (x_msg, y_msg, z_msg) = (x.1.as_slice(), y.1.as_slice(), z.1.as_slice());
+        let semiring = self.semiring.as_deref();
+        let (to_x, to_y, to_z) = if semiring::kind(semiring) == semiring::SemiringKind::SumProduct {
+            let plan = &self.plan;
+            (
+                plan.correlate(y_msg, z_msg)?,
+                plan.correlate(x_msg, z_msg)?,
+                plan.convolve(x_msg, y_msg)?,
+            )
+        } else {
+            (
+                cyclic_correlation_in(y_msg, z_msg, semiring),
+                cyclic_correlation_in(x_msg, z_msg, semiring),
+                cyclic_convolution_in(x_msg, y_msg, semiring),
+            )
+        };
+        Ok(vec![
+            (x.0, DenseMsg::from_vec(to_x)),
+            (y.0, DenseMsg::from_vec(to_y)),
+            (z.0, DenseMsg::from_vec(to_z)),
+        ])
+    }
+    impl_clone_box!(usize, DenseMsg, CtrlMsgT, CtrlMsgAT);
+
+    fn is_factor(&self) -> bool {
+        true
+    }
+    fn number_inputs(&self) -> Option<usize> {
+        Some(3)
+    }
+    fn initialize(&mut self, connections: Vec<NodeIndex>) -> BPResult<()> {
+        if connections.len() != 3 {
+            return Err(BPError::new(
+                "NttConvolutionFactor::initialize".to_owned(),
+                "NttConvolutionFactor needs exactly three connections".to_owned(),
+            ));
+        }
+        self.connections = Some(connections);
+        Ok(())
+    }
+    fn is_ready(
+        &self,
+        recv_from: &Vec<(NodeIndex, DenseMsg)>,
+        _current_step: usize,
+    ) -> BPResult<bool> {
+        Ok(recv_from.len() == 3)
+    }
+    fn reset(&mut self) -> BPResult<()> {
+        self.connections = None;
+        Ok(())
+    }
+    fn get_prior(&self) -> Option<DenseMsg> {
+        None
+    }
+    fn set_semiring(&mut self, semiring: Arc<dyn Semiring>) {
+        self.semiring = Some(semiring);
+    }
+    fn potential(&self, values: &[usize]) -> Option<Probability> {
+        let q = self.plan.modulus;
+        Some(if (values[0] + values[1]) % q == values[2] % q {
+            1.0
+        } else {
+            0.0
+        })
+    }
+}
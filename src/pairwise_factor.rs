@@ -0,0 +1,301 @@
+use crate::semiring;
+use crate::variable_node::{FromVariableNodeCtrlAnswer, IntoVariableNodeCtrl};
+use crate::{
+    BPError, BPGraph, BPResult, Msg, NodeFunction, NodeIndex, Probability, Semiring, VariableNode,
+};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::sync::Arc;
+
+//Factor between two variables given by a table of potentials psi(x0, x1).
+//Pairs that are not in the table have potential 0.
+//The connections are interpreted in the order in which the edges were added: x0, x1.
+//A learnable factor (see PairwiseFactor::learnable) replaces its table by the normalized expected counts
+//in BPGraph::em_step.
+#[derive(Clone)]
+pub struct PairwiseFactor<T, MsgT> {
+    table: HashMap<(T, T), Probability>,
+    //Expected counts of the pairs, None if the table is fixed
+    statistics: Option<HashMap<(T, T), Probability>>,
+    connections: Option<(NodeIndex, NodeIndex)>,
+    semiring: Option<Arc<dyn Semiring>>,
+    phantom: std::marker::PhantomData<MsgT>,
+}
+
+impl<T, MsgT> PairwiseFactor<T, MsgT> {
+    pub fn new(table: HashMap<(T, T), Probability>) -> Self {
+        PairwiseFactor {
+            table,
+            statistics: None,
+            connections: None,
+            semiring: None,
+            phantom: std::marker::PhantomData,
+        }
+    }
+    //Converts a table of probabilities into the domain of semiring (e.g., costs for MinSum)
+    pub fn from_probabilities(table: HashMap<(T, T), Probability>, semiring: &dyn Semiring) -> Self
+    where
+        T: Eq + Hash,
+    {
+        Self::new(
+            table
+                .into_iter()
+                .map(|(x, p)| (x, semiring.lift(p)))
+                .collect(),
+        )
+    }
+    pub fn get_table(&self) -> &HashMap<(T, T), Probability> {
+        &self.table
+    }
+    pub fn learnable(mut self) -> Self {
+        self.statistics = Some(HashMap::new());
+        self
+    }
+    pub fn get_statistics(&self) -> Option<&HashMap<(T, T), Probability>> {
+        self.statistics.as_ref()
+    }
+}
+
+pub(crate) fn add_to<T, MsgT: Msg<T>>(
+    msg: &mut MsgT,
+    value: T,
+    p: Probability,
+    semiring: Option<&dyn Semiring>,
+) where
+    T: Copy,
+{
+    match msg.get_mut(value) {
+        Some(pold) => *pold = semiring::plus(semiring, *pold, p),
+        None => msg.insert(value, p),
+    }
+}
+
+impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default> NodeFunction<T, MsgT, CtrlMsgT, CtrlMsgAT>
+    for PairwiseFactor<T, MsgT>
+where
+    T: Copy + Eq + Hash + Debug,
+    MsgT: Clone,
+{
+    fn node_function(&mut self, inbox: Vec<(NodeIndex, MsgT)>) -> BPResult<Vec<(NodeIndex, MsgT)>> {
+        NodeFunction::<T, MsgT, CtrlMsgT, CtrlMsgAT>::node_function_borrowed(
+            self,
+            inbox.iter().map(|(idx, msg)| (*idx, msg)).collect(),
+        )
+    }
+    fn node_function_borrowed(
+        &mut self,
+        inbox: Vec<(NodeIndex, &MsgT)>,
+    ) -> BPResult<Vec<(NodeIndex, MsgT)>> {
+        let (con0, con1) = self.connections.ok_or_else(|| {
+            BPError::new(
+                "PairwiseFactor::node_function".to_owned(),
+                "PairwiseFactor not initialized".to_owned(),
+            )
+        })?;
+        if inbox.len() != 2 {
+            return Err(BPError::new(
+                "PairwiseFactor::node_function".to_owned(),
+                format!("Wrong number of messages ({}, needed: 2)", inbox.len()),
+            ));
+        }
+        let (msg0, msg1) = if inbox[0].0 == con0 && inbox[1].0 == con1 {
+            (inbox[0].1, inbox[1].1)
+        } else if inbox[0].0 == con1 && inbox[1].0 == con0 {
+            (inbox[1].1, inbox[0].1)
+        } else {
+            return Err(BPError::new(
+                "PairwiseFactor::node_function".to_owned(),
+                "Received wrong messages".to_owned(),
+            ));
+        };
+        let semiring = self.semiring.as_deref();
+        let mut out0 = MsgT::new();
+        let mut out1 = MsgT::new();
+        for ((x0, x1), psi) in &self.table {
+            if let Some(p1) = msg1.get(*x1) {
+                add_to(
+                    &mut out0,
+                    *x0,
+                    semiring::times(semiring, p1, *psi),
+                    semiring,
+                );
+            }
+            if let Some(p0) = msg0.get(*x0) {
+                add_to(
+                    &mut out1,
+                    *x1,
+                    semiring::times(semiring, p0, *psi),
+                    semiring,
+                );
+            }
+        }
+        Ok(vec![(con0, out0), (con1, out1)])
+    }
+    fn borrows_inbox(&self) -> bool {
+        true
+    }
+    impl_clone_box!(T, MsgT, CtrlMsgT, CtrlMsgAT);
+
+    fn is_factor(&self) -> bool {
+        true
+    }
+    fn number_inputs(&self) -> Option<usize> {
+        Some(2)
+    }
+    fn initialize(&mut self, connections: Vec<NodeIndex>) -> BPResult<()> {
+        if connections.len() != 2 {
+            return Err(BPError::new(
+                "PairwiseFactor::initialize".to_owned(),
+                "PairwiseFactor needs exactly two connections".to_owned(),
+            ));
+        }
+        self.connections = Some((connections[0], connections[1]));
+        Ok(())
+    }
+    fn is_ready(&self, recv_from: &Vec<(NodeIndex, MsgT)>, _current_step: usize) -> BPResult<bool> {
+        Ok(recv_from.len() == 2)
+    }
+    fn reset(&mut self) -> BPResult<()> {
+        self.connections = None;
+        Ok(())
+    }
+    fn get_prior(&self) -> Option<MsgT> {
+        None
+    }
+    fn set_semiring(&mut self, semiring: Arc<dyn Semiring>) {
+        self.semiring = Some(semiring);
+    }
+    fn restrict_domain(&mut self, variable: NodeIndex, values: &[T]) -> BPResult<()> {
+        let (con0, con1) = self.connections.ok_or_else(|| {
+            BPError::new(
+                "PairwiseFactor::restrict_domain".to_owned(),
+                "PairwiseFactor not initialized".to_owned(),
+            )
+        })?;
+        let values: HashSet<T> = values.iter().copied().collect();
+        self.table.retain(|(x0, x1), _| {
+            (variable != con0 || values.contains(x0)) && (variable != con1 || values.contains(x1))
+        });
+        Ok(())
+    }
+    fn learns_parameters(&self) -> bool {
+        self.statistics.is_some()
+    }
+    fn accumulate_statistics(&mut self, belief: &HashMap<Vec<T>, Probability>) -> BPResult<()> {
+        let statistics = match self.statistics.as_mut() {
+            Some(statistics) => statistics,
+            None => return Ok(()),
+        };
+        for (values, p) in belief {
+            if values.len() != 2 {
+                return Err(BPError::new(
+                    "PairwiseFactor::accumulate_statistics".to_owned(),
+                    format!("Wrong number of values ({}, needed: 2)", values.len()),
+                ));
+            }
+            *statistics.entry((values[0], values[1])).or_insert(0.0) += p;
+        }
+        Ok(())
+    }
+    //Pairs without expected counts get potential 0. Nothing changes if no statistics have been accumulated.
+    fn update_parameters(&mut self) -> BPResult<()> {
+        let statistics = match self.statistics.as_mut() {
+            Some(statistics) => std::mem::take(statistics),
+            None => return Ok(()),
+        };
+        let sum: Probability = statistics.values().sum();
+        if sum > 0.0 {
+            self.table = statistics.into_iter().map(|(x, p)| (x, p / sum)).collect();
+        }
+        Ok(())
+    }
+    fn potential(&self, values: &[T]) -> Option<Probability> {
+        Some(
+            self.table
+                .get(&(values[0], values[1]))
+                .copied()
+                .unwrap_or(0.0),
+        )
+    }
+}
+
+impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default> BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>
+where
+    T: Copy + Eq + Hash + Debug + Send + Sync + 'static,
+    MsgT: Clone + Send + Sync + 'static,
+{
+    //Connects two variable nodes directly (as in a Markov network) by adding a hidden PairwiseFactor.
+    //Returns the index of the factor. The graph is not changed if the edges cannot be added.
+    pub fn add_pairwise_potential(
+        &mut self,
+        var0: NodeIndex,
+        var1: NodeIndex,
+        table: HashMap<(T, T), Probability>,
+    ) -> BPResult<NodeIndex> {
+        self.check_unsealed("BPGraph::add_pairwise_potential")?;
+        if var0 == var1 {
+            return Err(BPError::new(
+                "BPGraph::add_pairwise_potential".to_owned(),
+                format!("Cannot connect node {} to itself", var0),
+            ));
+        }
+        for var in [var0, var1] {
+            let node = self.get_node(var)?;
+            if matches!(node.number_inputs(), Some(n) if node.get_connections().len() >= n) {
+                return Err(BPError::new(
+                    "BPGraph::add_pairwise_potential".to_owned(),
+                    format!("Node {} cannot take another connection", var),
+                ));
+            }
+        }
+        if self.is_factor(var0)? || self.is_factor(var1)? {
+            return Err(BPError::new(
+                "BPGraph::add_pairwise_potential".to_owned(),
+                format!(
+                    "Pairwise potentials can only connect variable nodes ({}, {})",
+                    var0, var1
+                ),
+            ));
+        }
+        let name = format!("pairwise({}, {})", var0, var1);
+        let factor = self.add_node(name, Box::new(PairwiseFactor::new(table)))?;
+        self.add_edge(factor, var0)?;
+        self.add_edge(factor, var1)?;
+        Ok(factor)
+    }
+}
+
+impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT> BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>
+where
+    T: Debug + PartialEq + Send + Sync + 'static,
+    MsgT: Clone + Send + Sync + 'static,
+    CtrlMsgT: IntoVariableNodeCtrl<MsgT>,
+    CtrlMsgAT: FromVariableNodeCtrlAnswer<MsgT>,
+{
+    //Like add_edge, but nodes of the same type are connected through a hidden connector node:
+    //two variables through an EqualityFactor, two factors through a VariableNode without prior.
+    //Returns the index of the connector node if one has been inserted.
+    pub fn add_edge_with_connector(
+        &mut self,
+        node0: NodeIndex,
+        node1: NodeIndex,
+    ) -> BPResult<Option<NodeIndex>> {
+        self.check_unsealed("BPGraph::add_edge_with_connector")?;
+        let is_factor0 = self.is_factor(node0)?;
+        if is_factor0 != self.is_factor(node1)? {
+            self.add_edge(node0, node1)?;
+            return Ok(None);
+        }
+        let name = format!("connector({}, {})", node0, node1);
+        let connector = if is_factor0 {
+            let var = self.add_node(name, Box::new(VariableNode::<T, MsgT>::new()))?;
+            self.add_edge(var, node0)?;
+            self.add_edge(var, node1)?;
+            var
+        } else {
+            self.link_variables(name, &[node0, node1])?
+        };
+        Ok(Some(connector))
+    }
+}
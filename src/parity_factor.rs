@@ -0,0 +1,138 @@
+use crate::semiring;
+use crate::{BPError, BPResult, Msg, NodeFunction, NodeIndex, Probability, Semiring};
+use std::sync::Arc;
+
+//Factor enforcing that the connected binary variables (values 0 and 1) sum to an even number,
+//e.g., a row of the parity-check matrix of a linear code.
+//The messages are computed by forward-backward recursions over the parity of the prefixes and suffixes of
+//the inbox, so the cost is linear in the number of connections. Supports semirings (e.g., min-sum).
+#[derive(Clone)]
+pub struct ParityFactor<MsgT> {
+    connections: Option<Vec<NodeIndex>>,
+    semiring: Option<Arc<dyn Semiring>>,
+    phantom: std::marker::PhantomData<MsgT>,
+}
+
+impl<MsgT> ParityFactor<MsgT> {
+    pub fn new() -> Self {
+        ParityFactor {
+            connections: None,
+            semiring: None,
+            phantom: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<MsgT> Default for ParityFactor<MsgT> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<MsgT: Msg<usize>, CtrlMsgT, CtrlMsgAT: Default> NodeFunction<usize, MsgT, CtrlMsgT, CtrlMsgAT>
+    for ParityFactor<MsgT>
+where
+    MsgT: Clone,
+{
+    fn node_function(&mut self, inbox: Vec<(NodeIndex, MsgT)>) -> BPResult<Vec<(NodeIndex, MsgT)>> {
+        let connections = self.connections.as_ref().ok_or_else(|| {
+            BPError::new(
+                "ParityFactor::node_function".to_owned(),
+                "ParityFactor not initialized".to_owned(),
+            )
+        })?;
+        if inbox.len() != connections.len() {
+            return Err(BPError::new(
+                "ParityFactor::node_function".to_owned(),
+                format!(
+                    "Wrong number of messages ({}, needed: {})",
+                    inbox.len(),
+                    connections.len()
+                ),
+            ));
+        }
+        let s = self.semiring.as_deref();
+        let (zero, one) = (semiring::zero(s), s.map_or(1.0, |s| s.one()));
+        let msgs: Vec<(Probability, Probability)> = inbox
+            .iter()
+            .map(|(_, msg)| (msg.get(0).unwrap_or(zero), msg.get(1).unwrap_or(zero)))
+            .collect();
+        //(even, odd) after adding a variable with message m to a prefix with (even, odd)
+        let add = |(even, odd): (Probability, Probability),
+                   (m0, m1): (Probability, Probability)| {
+            (
+                semiring::plus(s, semiring::times(s, even, m0), semiring::times(s, odd, m1)),
+                semiring::plus(s, semiring::times(s, even, m1), semiring::times(s, odd, m0)),
+            )
+        };
+        let n = msgs.len();
+        //backward[i]: parity of msgs[i..]
+        let mut backward = vec![(one, zero); n + 1];
+        for i in (0..n).rev() {
+            backward[i] = add(backward[i + 1], msgs[i]);
+        }
+        let mut forward = (one, zero);
+        let mut out = Vec::with_capacity(n);
+        for (i, (from, _)) in inbox.iter().enumerate() {
+            let (f_even, f_odd) = forward;
+            let (b_even, b_odd) = backward[i + 1];
+            let mut msg = MsgT::new();
+            msg.insert(
+                0,
+                semiring::plus(
+                    s,
+                    semiring::times(s, f_even, b_even),
+                    semiring::times(s, f_odd, b_odd),
+                ),
+            );
+            msg.insert(
+                1,
+                semiring::plus(
+                    s,
+                    semiring::times(s, f_even, b_odd),
+                    semiring::times(s, f_odd, b_even),
+                ),
+            );
+            out.push((*from, msg));
+            forward = add(forward, msgs[i]);
+        }
+        Ok(out)
+    }
+    impl_clone_box!(usize, MsgT, CtrlMsgT, CtrlMsgAT);
+
+    fn is_factor(&self) -> bool {
+        true
+    }
+    fn number_inputs(&self) -> Option<usize> {
+        None
+    }
+    fn initialize(&mut self, connections: Vec<NodeIndex>) -> BPResult<()> {
+        self.connections = Some(connections);
+        Ok(())
+    }
+    fn is_ready(&self, recv_from: &Vec<(NodeIndex, MsgT)>, _current_step: usize) -> BPResult<bool> {
+        Ok(recv_from.len()
+            == self
+                .connections
+                .as_ref()
+                .expect("ParityFactor not initialized.")
+                .len())
+    }
+    fn reset(&mut self) -> BPResult<()> {
+        self.connections = None;
+        Ok(())
+    }
+    fn get_prior(&self) -> Option<MsgT> {
+        None
+    }
+    fn set_semiring(&mut self, semiring: Arc<dyn Semiring>) {
+        self.semiring = Some(semiring);
+    }
+    fn potential(&self, values: &[usize]) -> Option<Probability> {
+        Some(if values.iter().sum::<usize>() % 2 == 0 {
+            1.0
+        } else {
+            0.0
+        })
+    }
+}
@@ -0,0 +1,265 @@
+use crate::{
+    BPError, BPGraph, BPResult, Msg, MsgValidityError, NodeIndex, NormalizationMode, Probability,
+    SplitMix64,
+};
+
+//Message over a continuous (real valued) variable given by weighted samples, for nonparametric BP.
+//Multiplying by another message reweights the particles by the kernel density estimate (Gaussian kernel)
+//of the other message (importance reweighting). If resampling is enabled, the particles are resampled
+//(systematic resampling) whenever the effective sample size drops below a fraction of their number.
+//
+//Variable nodes use the particles of their prior, so priors should be sampled from a proposal covering the
+//support of the posterior. Factors have to be implemented for the model at hand (e.g., by propagating samples).
+#[derive(Debug, Clone)]
+pub struct ParticleMsg {
+    //(value, weight)
+    particles: Vec<(f64, Probability)>,
+    //Kernel bandwidth, Silverman's rule of thumb if None
+    bandwidth: Option<f64>,
+    //Fraction of the number of particles
+    resample_below: Option<f64>,
+    rng: SplitMix64,
+}
+
+impl ParticleMsg {
+    pub fn from_particles(particles: Vec<(f64, Probability)>) -> Self {
+        ParticleMsg {
+            particles,
+            bandwidth: None,
+            resample_below: None,
+            rng: SplitMix64::new(0),
+        }
+    }
+
+    //Equally weighted samples
+    pub fn from_samples(samples: &[f64]) -> Self {
+        let w = 1.0 / samples.len().max(1) as Probability;
+        Self::from_particles(samples.iter().map(|x| (*x, w)).collect())
+    }
+
+    pub fn with_bandwidth(mut self, bandwidth: f64) -> Self {
+        self.bandwidth = Some(bandwidth);
+        self
+    }
+
+    //Resample after mult_msg if the effective sample size is below fraction * len(); seed determines the resampling
+    pub fn with_resampling(mut self, fraction: f64, seed: u64) -> Self {
+        self.resample_below = Some(fraction);
+        self.rng = SplitMix64::new(seed);
+        self
+    }
+
+    pub fn particles(&self) -> &[(f64, Probability)] {
+        &self.particles
+    }
+
+    pub fn len(&self) -> usize {
+        self.particles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.particles.is_empty()
+    }
+
+    fn total_weight(&self) -> Probability {
+        self.particles.iter().map(|(_, w)| w).sum()
+    }
+
+    //(sum w)^2 / sum w^2
+    pub fn effective_sample_size(&self) -> f64 {
+        let sum_sq: Probability = self.particles.iter().map(|(_, w)| w * w).sum();
+        if sum_sq > 0.0 {
+            self.total_weight().powi(2) / sum_sq
+        } else {
+            0.0
+        }
+    }
+
+    pub fn mean(&self) -> f64 {
+        let total = self.total_weight();
+        self.particles.iter().map(|(x, w)| x * w).sum::<f64>() / total
+    }
+
+    pub fn variance(&self) -> f64 {
+        let total = self.total_weight();
+        let mean = self.mean();
+        self.particles
+            .iter()
+            .map(|(x, w)| w * (x - mean).powi(2))
+            .sum::<f64>()
+            / total
+    }
+
+    //The given bandwidth or 1.06 * std * n^(-1/5) with the effective sample size n
+    pub fn get_bandwidth(&self) -> f64 {
+        self.bandwidth.unwrap_or_else(|| {
+            let h = 1.06 * self.variance().sqrt() * self.effective_sample_size().powf(-0.2);
+            if h.is_finite() && h > 0.0 {
+                h
+            } else {
+                1.0
+            }
+        })
+    }
+
+    //Kernel density estimate at x (integrates to the total weight)
+    pub fn density(&self, x: f64) -> Probability {
+        self.density_with_bandwidth(x, self.get_bandwidth())
+    }
+
+    fn density_with_bandwidth(&self, x: f64, h: f64) -> Probability {
+        let norm = 1.0 / (h * (2.0 * std::f64::consts::PI).sqrt());
+        self.particles
+            .iter()
+            .map(|(xi, w)| w * norm * (-0.5 * ((x - xi) / h).powi(2)).exp())
+            .sum()
+    }
+
+    //Systematic resampling to n equally weighted particles (keeping the total weight)
+    pub fn resample(&mut self, n: usize) {
+        let total = self.total_weight();
+        if n == 0 || total <= 0.0 || total.is_nan() {
+            return;
+        }
+        let step = total / n as Probability;
+        let mut u = self.rng.next_f64() * step;
+        let mut resampled = Vec::with_capacity(n);
+        let mut cumulative = 0.0;
+        let mut particles = self.particles.iter().peekable();
+        while resampled.len() < n {
+            match particles.peek() {
+                Some((x, w)) if u < cumulative + w => {
+                    resampled.push((*x, step));
+                    u += step;
+                }
+                Some((_, w)) => {
+                    cumulative += w;
+                    particles.next();
+                }
+                //Rounding errors
+                None => resampled.push((self.particles.last().expect("Not empty").0, step)),
+            }
+        }
+        self.particles = resampled;
+    }
+}
+
+impl IntoIterator for ParticleMsg {
+    type Item = (f64, Probability);
+    type IntoIter = std::vec::IntoIter<(f64, Probability)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.particles.into_iter()
+    }
+}
+
+impl Msg<f64> for ParticleMsg {
+    fn new() -> Self {
+        Self::from_particles(Vec::new())
+    }
+    //Weight of the (first) particle at exactly value
+    fn get(&self, value: f64) -> Option<Probability> {
+        self.particles
+            .iter()
+            .find(|(x, _)| *x == value)
+            .map(|(_, w)| *w)
+    }
+    fn get_mut(&mut self, value: f64) -> Option<&mut Probability> {
+        self.particles
+            .iter_mut()
+            .find(|(x, _)| *x == value)
+            .map(|(_, w)| w)
+    }
+    fn insert(&mut self, value: f64, p: Probability) {
+        match self.get_mut(value) {
+            Some(w) => *w = p,
+            None => self.particles.push((value, p)),
+        }
+    }
+    fn normalize(&mut self) -> BPResult<()> {
+        self.normalize_with(NormalizationMode::SumToOne)
+    }
+    fn normalize_with(&mut self, mode: NormalizationMode) -> BPResult<()> {
+        let (shift, scale) = mode.affine(
+            self.particles.iter().map(|(_, w)| *w),
+            "ParticleMsg::normalize_with",
+        )?;
+        self.particles
+            .iter_mut()
+            .for_each(|(_, w)| *w = (*w - shift) * scale);
+        Ok(())
+    }
+    fn normalization(&self, mode: NormalizationMode) -> BPResult<(Probability, Probability)> {
+        mode.affine(
+            self.particles.iter().map(|(_, w)| *w),
+            "ParticleMsg::normalization",
+        )
+    }
+    fn is_valid(&self) -> bool {
+        self.validate().is_ok()
+    }
+    fn validate(&self) -> Result<(), MsgValidityError> {
+        MsgValidityError::from_entries(self.particles.iter().copied()).into_result()
+    }
+    //Importance reweighting by the kernel density estimate of other
+    fn mult_msg(&mut self, other: &Self) {
+        if other.is_empty() {
+            return;
+        }
+        let h = other.get_bandwidth();
+        for (x, w) in self.particles.iter_mut() {
+            *w *= other.density_with_bandwidth(*x, h);
+        }
+        if let Some(fraction) = self.resample_below {
+            if self.effective_sample_size() < fraction * self.len() as f64 {
+                self.resample(self.len());
+            }
+        }
+    }
+    fn clear(&mut self) {
+        self.particles.clear();
+    }
+    fn for_each(&mut self, mut f: impl FnMut(Probability) -> Probability) {
+        self.particles.iter_mut().for_each(|(_, w)| *w = f(*w));
+    }
+}
+
+impl<CtrlMsgT, CtrlMsgAT: Default> BPGraph<f64, ParticleMsg, CtrlMsgT, CtrlMsgAT> {
+    //Particles of the prior of a variable node (or of the first message if there is no prior)
+    //reweighted by all received messages, normalized to sum to one
+    pub fn get_particle_result(&self, node_index: NodeIndex) -> BPResult<Option<ParticleMsg>> {
+        let node = self.get_node(node_index)?;
+        if node.is_factor() {
+            return Err(BPError::new(
+                "BPGraph::get_particle_result".to_owned(),
+                format!("Node {} is a factor", node_index),
+            ));
+        }
+        let mut inbox = node.clone_inbox().into_iter().map(|(_, msg)| msg);
+        let mut res = match node.get_prior().or_else(|| inbox.next()) {
+            Some(res) => res,
+            None => return Ok(None),
+        };
+        for msg in inbox {
+            res.mult_msg(&msg);
+        }
+        res.normalize().map_err(|e| {
+            e.attach_info_str(
+                "BPGraph::get_particle_result",
+                format!("Failed to normalize result of node {}", node_index),
+            )
+        })?;
+        Ok(Some(res))
+    }
+
+    //Kernel density estimate of the result of a variable node at points
+    pub fn get_result_density(
+        &self,
+        node_index: NodeIndex,
+        points: &[f64],
+    ) -> BPResult<Option<Vec<Probability>>> {
+        Ok(self
+            .get_particle_result(node_index)?
+            .map(|res| points.iter().map(|x| res.density(*x)).collect()))
+    }
+}
@@ -0,0 +1,149 @@
+use crate::semiring;
+use crate::{BPError, BPResult, Msg, NodeFunction, NodeIndex, Probability, Semiring};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::sync::Arc;
+
+//Pairwise factor of the Potts form: psi(x0, x1) = same if x0 == x1 and different otherwise (e.g., smoothness
+//in grid MRFs, the Ising model for two labels). Unlike a PairwiseFactor with the same table, messages are
+//computed in O(n) instead of O(n^2) for n labels: the sum (plus of the semiring) over the other labels is
+//taken from prefix and suffix sums. The potentials are in the domain of the semiring.
+//The labels of a message to x are those of the message from x.
+#[derive(Clone)]
+pub struct PottsFactor<MsgT> {
+    same: Probability,
+    different: Probability,
+    connections: Option<(NodeIndex, NodeIndex)>,
+    semiring: Option<Arc<dyn Semiring>>,
+    phantom: std::marker::PhantomData<MsgT>,
+}
+
+impl<MsgT> PottsFactor<MsgT> {
+    pub fn new(same: Probability, different: Probability) -> Self {
+        PottsFactor {
+            same,
+            different,
+            connections: None,
+            semiring: None,
+            phantom: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<MsgT> PottsFactor<MsgT> {
+    //Message to the variable with the labels of target, given the message of the other variable
+    fn message<T>(&self, source: Vec<(T, Probability)>, target: &MsgT) -> MsgT
+    where
+        T: Copy + Eq + Hash,
+        MsgT: Msg<T> + Clone,
+    {
+        let s = self.semiring.as_deref();
+        let zero = semiring::zero(s);
+        //prefix[i] is the sum of source[..i], suffix[i] the sum of source[i..]
+        let mut prefix = Vec::with_capacity(source.len() + 1);
+        prefix.push(zero);
+        for (_, p) in &source {
+            prefix.push(semiring::plus(s, *prefix.last().expect("Not empty"), *p));
+        }
+        let mut suffix = vec![zero; source.len() + 1];
+        for (i, (_, p)) in source.iter().enumerate().rev() {
+            suffix[i] = semiring::plus(s, suffix[i + 1], *p);
+        }
+        let positions: HashMap<T, usize> = source
+            .iter()
+            .enumerate()
+            .map(|(i, (v, _))| (*v, i))
+            .collect();
+        let mut out = MsgT::new();
+        for (b, _) in target.clone() {
+            let p = match positions.get(&b) {
+                Some(i) => semiring::plus(
+                    s,
+                    semiring::times(
+                        s,
+                        self.different,
+                        semiring::plus(s, prefix[*i], suffix[*i + 1]),
+                    ),
+                    semiring::times(s, self.same, source[*i].1),
+                ),
+                None => semiring::times(s, self.different, prefix[source.len()]),
+            };
+            out.insert(b, p);
+        }
+        out
+    }
+}
+
+impl<T, MsgT: Msg<T> + Clone, CtrlMsgT, CtrlMsgAT: Default>
+    NodeFunction<T, MsgT, CtrlMsgT, CtrlMsgAT> for PottsFactor<MsgT>
+where
+    T: Copy + Eq + Hash + Debug,
+{
+    fn node_function(&mut self, inbox: Vec<(NodeIndex, MsgT)>) -> BPResult<Vec<(NodeIndex, MsgT)>> {
+        let (con0, con1) = self.connections.ok_or_else(|| {
+            BPError::new(
+                "PottsFactor::node_function".to_owned(),
+                "PottsFactor not initialized".to_owned(),
+            )
+        })?;
+        if inbox.len() != 2 {
+            return Err(BPError::new(
+                "PottsFactor::node_function".to_owned(),
+                format!("Wrong number of messages ({}, needed: 2)", inbox.len()),
+            ));
+        }
+        let mut inbox = inbox;
+        if inbox[0].0 == con1 && inbox[1].0 == con0 {
+            inbox.swap(0, 1);
+        } else if inbox[0].0 != con0 || inbox[1].0 != con1 {
+            return Err(BPError::new(
+                "PottsFactor::node_function".to_owned(),
+                "Received wrong messages".to_owned(),
+            ));
+        }
+        let msg1 = inbox.pop().expect("Two messages").1;
+        let msg0 = inbox.pop().expect("Two messages").1;
+        let out0 = self.message(msg1.clone().into_iter().collect(), &msg0);
+        let out1 = self.message(msg0.into_iter().collect(), &msg1);
+        Ok(vec![(con0, out0), (con1, out1)])
+    }
+    impl_clone_box!(T, MsgT, CtrlMsgT, CtrlMsgAT);
+
+    fn is_factor(&self) -> bool {
+        true
+    }
+    fn number_inputs(&self) -> Option<usize> {
+        Some(2)
+    }
+    fn initialize(&mut self, connections: Vec<NodeIndex>) -> BPResult<()> {
+        if connections.len() != 2 {
+            return Err(BPError::new(
+                "PottsFactor::initialize".to_owned(),
+                "PottsFactor needs exactly two connections".to_owned(),
+            ));
+        }
+        self.connections = Some((connections[0], connections[1]));
+        Ok(())
+    }
+    fn is_ready(&self, recv_from: &Vec<(NodeIndex, MsgT)>, _current_step: usize) -> BPResult<bool> {
+        Ok(recv_from.len() == 2)
+    }
+    fn reset(&mut self) -> BPResult<()> {
+        self.connections = None;
+        Ok(())
+    }
+    fn get_prior(&self) -> Option<MsgT> {
+        None
+    }
+    fn set_semiring(&mut self, semiring: Arc<dyn Semiring>) {
+        self.semiring = Some(semiring);
+    }
+    fn potential(&self, values: &[T]) -> Option<Probability> {
+        Some(if values[0] == values[1] {
+            self.same
+        } else {
+            self.different
+        })
+    }
+}
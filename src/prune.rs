@@ -0,0 +1,73 @@
+use crate::{BPError, BPGraph, BPResult, Msg, NodeIndex, Probability, VariableNodeCtrl};
+use std::fmt::Debug;
+use std::hash::Hash;
+
+impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default> BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>
+where
+    T: Copy + Eq + Hash + Debug,
+    MsgT: Clone,
+    CtrlMsgT: From<VariableNodeCtrl<MsgT>>,
+{
+    //Removes the values whose marginal (normalized to sum to one) is below threshold from the domains of
+    //the variable nodes: the priors are restricted to the remaining values (values without a prior get
+    //a uniform one, combined priors are replaced by their restriction) and the connected factors are
+    //informed with NodeFunction::restrict_domain, so later messages only contain the remaining values.
+    //The most likely value of a variable is never removed. Variables without a result are skipped.
+    //Returns the number of removed values. Only available for probabilities (not, e.g., MinSum costs).
+    pub fn prune(&mut self, threshold: Probability) -> BPResult<usize> {
+        if !self.get_normalization().is_probability() {
+            return Err(BPError::new(
+                "BPGraph::prune".to_owned(),
+                "Messages are not probabilities".to_owned(),
+            ));
+        }
+        let variables: Vec<NodeIndex> = self
+            .nodes()
+            .filter(|(_, _, is_factor)| !is_factor)
+            .map(|(i, _, _)| i)
+            .collect();
+        let mut removed = 0;
+        for node in variables {
+            let marginal = match self.get_result(node)? {
+                Some(marginal) => marginal,
+                None => continue,
+            };
+            let sum: Probability = marginal.values().sum();
+            let max = marginal
+                .values()
+                .fold(Probability::NEG_INFINITY, |m, p| m.max(*p));
+            let keep: Vec<T> = marginal
+                .iter()
+                .filter(|(_, p)| **p >= threshold * sum || **p == max)
+                .map(|(v, _)| *v)
+                .collect();
+            if keep.len() == marginal.len() {
+                continue;
+            }
+            removed += marginal.len() - keep.len();
+            let old_prior = self.get_node(node)?.get_prior();
+            let mut prior = MsgT::new();
+            for v in &keep {
+                prior.insert(
+                    *v,
+                    old_prior.as_ref().and_then(|p| p.get(*v)).unwrap_or(1.0),
+                );
+            }
+            self.send_control_message(node, VariableNodeCtrl::SetPrior(Some(prior)).into())
+                .map_err(|e| {
+                    e.attach_info_str("BPGraph::prune", format!("Could not prune node {}", node))
+                })?;
+            for factor in self.get_connections(node)?.clone() {
+                self.get_node_mut(factor)?
+                    .restrict_domain(node, &keep)
+                    .map_err(|e| {
+                        e.attach_info_str(
+                            "BPGraph::prune",
+                            format!("Could not restrict factor {} to node {}", factor, node),
+                        )
+                    })?;
+            }
+        }
+        Ok(removed)
+    }
+}
@@ -0,0 +1,279 @@
+use crate::{BPResult, DenseMsg, Msg, MsgValidityError, NormalizationMode, Probability, Semiring};
+use std::fmt::Debug;
+
+//Fixed-point type of the entries of a QuantizedMsg
+pub trait Quantum: Copy + Debug + Default + PartialEq + Send + Sync + 'static {
+    const MAX: u32;
+    fn from_u32(q: u32) -> Self;
+    fn to_u32(self) -> u32;
+}
+
+impl Quantum for u8 {
+    const MAX: u32 = u8::MAX as u32;
+    fn from_u32(q: u32) -> Self {
+        q as u8
+    }
+    fn to_u32(self) -> u32 {
+        self as u32
+    }
+}
+
+impl Quantum for u16 {
+    const MAX: u32 = u16::MAX as u32;
+    fn from_u32(q: u32) -> Self {
+        q as u16
+    }
+    fn to_u32(self) -> u32 {
+        self as u32
+    }
+}
+
+//Message over the values 0..len() (like DenseMsg) storing every probability as a fixed-point number
+//q with a scale shared by all entries, p = q * scale, e.g., 2 bytes per entry for QuantizedMsg<u16> instead
+//of 8 for DenseMsg. Meant for graphs whose messages do not fit into memory otherwise.
+//
+//The scale is chosen such that the largest entry is Q::MAX, so the absolute error of an entry is at most
+//scale / 2 (see resolution) and small entries lose relative precision. Positive entries are stored as at
+//least 1 and therefore never become 0 (their error is below scale). Every operation except normalization decodes the entries, computes
+//in f64 and quantizes the result again, which renormalizes the scale. Only non-negative finite entries can
+//be represented, all others are stored as 0, so the message type is not suited for log-domain semirings.
+//
+//get_mut hands out a decoded entry that is quantized when the message is changed or read next.
+#[derive(Clone)]
+pub struct QuantizedMsg<Q: Quantum = u16> {
+    values: Vec<Q>,
+    scale: Probability,
+    //Entry handed out by get_mut, not yet quantized
+    pending: Option<(usize, Probability)>,
+}
+
+impl<Q: Quantum> QuantizedMsg<Q> {
+    pub fn from_probabilities(probabilities: &[Probability]) -> Self {
+        let mut msg = QuantizedMsg {
+            values: Vec::new(),
+            scale: 0.0,
+            pending: None,
+        };
+        msg.quantize(probabilities);
+        msg
+    }
+    pub fn from_dense(msg: &DenseMsg) -> Self {
+        Self::from_probabilities(msg.as_slice())
+    }
+    //The decoded entries
+    pub fn probabilities(&self) -> Vec<Probability> {
+        let mut probabilities: Vec<Probability> =
+            self.values.iter().map(|q| self.decode(*q)).collect();
+        if let Some((value, p)) = self.pending {
+            probabilities[value] = p;
+        }
+        probabilities
+    }
+    pub fn to_dense(&self) -> DenseMsg {
+        DenseMsg::from_vec(self.probabilities())
+    }
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+    //Difference between two neighbouring representable values, i.e., twice the maximal rounding error
+    pub fn resolution(&self) -> Probability {
+        self.scale
+    }
+    //Bytes of the entries (without the allocation overhead)
+    pub fn size_in_bytes(&self) -> usize {
+        self.values.len() * std::mem::size_of::<Q>()
+    }
+
+    fn decode(&self, q: Q) -> Probability {
+        q.to_u32() as Probability * self.scale
+    }
+    fn encode(&self, p: Probability) -> Q {
+        if !(p > 0.0 && p.is_finite()) || self.scale == 0.0 {
+            return Q::default();
+        }
+        Q::from_u32(((p / self.scale).round() as u32).clamp(1, Q::MAX))
+    }
+    fn quantize(&mut self, probabilities: &[Probability]) {
+        let max = probabilities
+            .iter()
+            .filter(|p| p.is_finite())
+            .fold(0.0, |max: Probability, p| max.max(*p));
+        self.scale = max / Q::MAX as Probability;
+        self.values = probabilities.iter().map(|p| self.encode(*p)).collect();
+        self.pending = None;
+    }
+    //Stores p, quantizing all entries again if p exceeds the largest representable value
+    fn set(&mut self, value: usize, p: Probability) {
+        if p.is_finite() && p > self.scale * Q::MAX as Probability {
+            let mut probabilities = self.probabilities();
+            probabilities[value] = p;
+            self.quantize(&probabilities);
+        } else {
+            self.values[value] = self.encode(p);
+        }
+    }
+    fn flush(&mut self) {
+        if let Some((value, p)) = self.pending.take() {
+            self.set(value, p);
+        }
+    }
+    //After quantize, the largest entry is stored as Q::MAX
+    fn norm_max(&mut self) {
+        if self.scale > 0.0 {
+            self.scale = 1.0 / Q::MAX as Probability;
+        }
+    }
+    //Decodes the entries, applies f and quantizes them again
+    fn map(&mut self, f: impl FnOnce(&mut Vec<Probability>)) {
+        let mut probabilities = self.probabilities();
+        f(&mut probabilities);
+        self.quantize(&probabilities);
+    }
+}
+
+impl<Q: Quantum> Debug for QuantizedMsg<Q> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("QuantizedMsg")
+            .field("probabilities", &self.probabilities())
+            .field("scale", &self.scale)
+            .finish()
+    }
+}
+
+impl<Q: Quantum> PartialEq for QuantizedMsg<Q> {
+    fn eq(&self, other: &Self) -> bool {
+        self.probabilities() == other.probabilities()
+    }
+}
+
+impl<Q: Quantum> From<&DenseMsg> for QuantizedMsg<Q> {
+    fn from(msg: &DenseMsg) -> Self {
+        QuantizedMsg::from_dense(msg)
+    }
+}
+
+impl<Q: Quantum> From<&QuantizedMsg<Q>> for DenseMsg {
+    fn from(msg: &QuantizedMsg<Q>) -> Self {
+        msg.to_dense()
+    }
+}
+
+impl<Q: Quantum> IntoIterator for QuantizedMsg<Q> {
+    type Item = (usize, Probability);
+    type IntoIter = std::iter::Enumerate<std::vec::IntoIter<Probability>>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.probabilities().into_iter().enumerate()
+    }
+}
+
+impl<Q: Quantum> Msg<usize> for QuantizedMsg<Q> {
+    fn new() -> Self {
+        QuantizedMsg {
+            values: Vec::new(),
+            scale: 0.0,
+            pending: None,
+        }
+    }
+    fn get(&self, value: usize) -> Option<Probability> {
+        match self.pending {
+            Some((pending, p)) if pending == value => Some(p),
+            _ => self.values.get(value).map(|q| self.decode(*q)),
+        }
+    }
+    fn get_mut(&mut self, value: usize) -> Option<&mut Probability> {
+        if value >= self.values.len() {
+            return None;
+        }
+        if self.pending.is_some_and(|(pending, _)| pending != value) {
+            self.flush();
+        }
+        if self.pending.is_none() {
+            self.pending = Some((value, self.decode(self.values[value])));
+        }
+        self.pending.as_mut().map(|(_, p)| p)
+    }
+    fn insert(&mut self, value: usize, p: Probability) {
+        self.flush();
+        if value >= self.values.len() {
+            self.values.resize(value + 1, Q::default());
+        }
+        self.set(value, p);
+    }
+    fn normalize(&mut self) -> BPResult<()> {
+        self.normalize_with(NormalizationMode::SumToOne)
+    }
+    //Normalizing to a sum or maximum of one only changes the scale
+    fn normalize_with(&mut self, mode: NormalizationMode) -> BPResult<()> {
+        self.flush();
+        let probabilities = self.probabilities();
+        let (shift, scale) = mode.affine(
+            probabilities.iter().copied(),
+            "QuantizedMsg::normalize_with",
+        )?;
+        if shift == 0.0 {
+            self.scale *= scale;
+        } else {
+            self.map(|probabilities| {
+                probabilities
+                    .iter_mut()
+                    .for_each(|p| *p = (*p - shift) * scale)
+            });
+        }
+        Ok(())
+    }
+    fn is_valid(&self) -> bool {
+        self.probabilities().iter().all(|p| *p <= 1.0)
+    }
+    fn validate(&self) -> Result<(), MsgValidityError> {
+        MsgValidityError::from_entries(self.probabilities().into_iter().enumerate()).into_result()
+    }
+    fn mult_msg(&mut self, other: &Self) {
+        let other = other.probabilities();
+        self.map(|probabilities| {
+            probabilities
+                .iter_mut()
+                .zip(other.iter())
+                .for_each(|(p0, p1)| *p0 *= p1)
+        });
+        self.norm_max();
+    }
+    fn clear(&mut self) {
+        self.values.clear();
+        self.scale = 0.0;
+        self.pending = None;
+    }
+    fn mult_msg_weighted(&mut self, other: &Self, alpha: f64) {
+        let other = other.probabilities();
+        self.map(|probabilities| {
+            probabilities
+                .iter_mut()
+                .zip(other.iter())
+                .for_each(|(p0, p1)| *p0 *= p1.powf(alpha))
+        });
+        self.norm_max();
+    }
+    fn add_msg_weighted(&mut self, other: &Self, alpha_self: f64, alpha_other: f64) {
+        let other = other.probabilities();
+        self.map(|probabilities| {
+            probabilities
+                .iter_mut()
+                .zip(other.iter())
+                .for_each(|(p0, p1)| *p0 = alpha_self * *p0 + alpha_other * p1)
+        });
+    }
+    fn times_msg(&mut self, other: &Self, semiring: &dyn Semiring) {
+        let other = other.probabilities();
+        self.map(|probabilities| {
+            probabilities
+                .iter_mut()
+                .zip(other.iter())
+                .for_each(|(p0, p1)| *p0 = semiring.times(*p0, *p1))
+        });
+    }
+    fn for_each(&mut self, mut f: impl FnMut(Probability) -> Probability) {
+        self.map(|probabilities| probabilities.iter_mut().for_each(|p| *p = f(*p)));
+    }
+}
@@ -0,0 +1,403 @@
+use crate::junction_tree::{variable_priors, Table};
+use crate::{BPError, BPGraph, BPResult, Msg, NodeIndex, Probability};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::hash::Hash;
+
+/*
+Generalized belief propagation on a region graph (parent-to-child algorithm).
+
+The regions are the outer regions chosen by the user and all their (non-empty) intersections, a region being
+the parent of the largest regions it contains. Every region holds the factors whose neighbours it contains and the
+priors of its variables, and the counting number of a region is 1 minus the sum of the counting numbers of all
+regions containing it (cluster variation method). Messages are sent from parents to children only:
+m(P -> C) = sum_{x_P \ x_C} (factors of P not in C) * (messages from outside of P into P and its descendants
+that are not descendants of C) / (messages from P and its descendants not in C into C and its descendants).
+With the neighbourhoods of the factors as outer regions this is loopy BP, larger regions (e.g., the squares
+of a grid) trade cost for accuracy. Like the junction tree, the regions hold dense tables, the domains of the
+variables are given by their priors and the factors have to implement NodeFunction::potential.
+*/
+
+pub struct RegionGraph<T> {
+    //Graph node index of each variable
+    variables: Vec<NodeIndex>,
+    domains: Vec<Vec<T>>,
+    domain_sizes: Vec<usize>,
+    //Variables of the regions (sorted), the outer regions first
+    regions: Vec<Vec<usize>>,
+    counting_numbers: Vec<i64>,
+    //Factors (including priors) contained in each region
+    factors: Vec<Table>,
+    region_factors: Vec<Vec<usize>>,
+    //(parent, child)
+    edges: Vec<(usize, usize)>,
+    messages: Vec<Table>,
+    //Per edge: product of the factors of the parent that are not in the child, over the variables of the parent
+    edge_potentials: Vec<Table>,
+    //Per edge: the messages multiplied into and divided out of the message (see above)
+    numerators: Vec<Vec<usize>>,
+    denominators: Vec<Vec<usize>>,
+    //Per region: the messages from outside into the region and its descendants
+    incoming: Vec<Vec<usize>>,
+    damping: f64,
+}
+
+fn is_subset(a: &[usize], b: &[usize]) -> bool {
+    a.iter().all(|v| b.contains(v))
+}
+
+impl<T> RegionGraph<T>
+where
+    T: Copy + Eq + Hash + Debug,
+{
+    //outer_regions are sets of variable nodes of graph. Every factor has to be contained in an outer region.
+    //Fails if the table of a region would have more than max_table_size entries.
+    pub fn new<MsgT: Msg<T> + Clone, CtrlMsgT, CtrlMsgAT: Default>(
+        graph: &BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>,
+        outer_regions: &[Vec<NodeIndex>],
+        max_table_size: usize,
+    ) -> BPResult<Self> {
+        let (variables, priors) = variable_priors(graph, "RegionGraph::new")?;
+        let var_id: HashMap<NodeIndex, usize> = variables
+            .iter()
+            .enumerate()
+            .map(|(i, idx)| (*idx, i))
+            .collect();
+        let domains: Vec<Vec<T>> = priors
+            .iter()
+            .map(|prior| prior.iter().map(|(v, _)| *v).collect())
+            .collect();
+        let domain_sizes: Vec<usize> = domains.iter().map(|d| d.len()).collect();
+
+        //Factor tables, priors are unary factors
+        let mut factors: Vec<Table> = priors
+            .iter()
+            .enumerate()
+            .map(|(v, prior)| {
+                let mut unary = Table::ones(vec![v], &domain_sizes);
+                unary.values = prior.iter().map(|(_, p)| *p).collect();
+                unary
+            })
+            .collect();
+        for idx in 0..graph.len() {
+            let node = graph.get_node(idx)?;
+            if !node.is_factor() {
+                continue;
+            }
+            let scope = node
+                .get_connections()
+                .iter()
+                .map(|con| {
+                    var_id.get(con).copied().ok_or_else(|| {
+                        BPError::new(
+                            "RegionGraph::new".to_owned(),
+                            format!("Factor {} is connected to unknown variable {}", idx, con),
+                        )
+                    })
+                })
+                .collect::<BPResult<Vec<usize>>>()?;
+            let mut vars = scope.clone();
+            vars.sort_unstable();
+            vars.dedup();
+            let mut table = Table::ones(vars, &domain_sizes);
+            for i in 0..table.values.len() {
+                let assignment = table.assignment(i);
+                let values: Vec<T> = scope
+                    .iter()
+                    .map(|v| {
+                        let pos = table
+                            .vars
+                            .iter()
+                            .position(|w| w == v)
+                            .expect("Variable in scope");
+                        domains[*v][assignment[pos]]
+                    })
+                    .collect();
+                table.values[i] = node.potential(&values).ok_or_else(|| {
+                    BPError::new(
+                        "RegionGraph::new".to_owned(),
+                        format!(
+                            "Factor {} ({}) does not implement potential",
+                            idx,
+                            node.get_name()
+                        ),
+                    )
+                })?;
+            }
+            factors.push(table);
+        }
+
+        //Outer regions, without duplicates and regions contained in others
+        let mut outer: Vec<Vec<usize>> = Vec::with_capacity(outer_regions.len());
+        for region in outer_regions {
+            let mut vars = region
+                .iter()
+                .map(|node| {
+                    var_id.get(node).copied().ok_or_else(|| {
+                        BPError::new(
+                            "RegionGraph::new".to_owned(),
+                            format!("Node {} of an outer region is not a variable node", node),
+                        )
+                    })
+                })
+                .collect::<BPResult<Vec<usize>>>()?;
+            vars.sort_unstable();
+            vars.dedup();
+            if !vars.is_empty() {
+                outer.push(vars);
+            }
+        }
+        outer.sort_by_key(|r| std::cmp::Reverse(r.len()));
+        let mut regions: Vec<Vec<usize>> = Vec::new();
+        for region in outer {
+            if !regions.iter().any(|r| is_subset(&region, r)) {
+                regions.push(region);
+            }
+        }
+        for factor in &factors {
+            if !regions.iter().any(|r| is_subset(&factor.vars, r)) {
+                return Err(BPError::new(
+                    "RegionGraph::new".to_owned(),
+                    "Factor or variable is not contained in any outer region".to_owned(),
+                )
+                .attach_debug_object(
+                    "scope (variable node indices)",
+                    factor
+                        .vars
+                        .iter()
+                        .map(|v| variables[*v])
+                        .collect::<Vec<_>>(),
+                ));
+            }
+        }
+
+        //Closure under intersection
+        let mut known: HashSet<Vec<usize>> = regions.iter().cloned().collect();
+        let mut i = 0;
+        while i < regions.len() {
+            for j in 0..i {
+                let intersection: Vec<usize> = regions[i]
+                    .iter()
+                    .copied()
+                    .filter(|v| regions[j].contains(v))
+                    .collect();
+                if !intersection.is_empty() && known.insert(intersection.clone()) {
+                    regions.push(intersection);
+                }
+            }
+            i += 1;
+        }
+        for region in &regions {
+            let size: usize = region.iter().map(|v| domain_sizes[*v]).product();
+            if size > max_table_size {
+                return Err(BPError::new(
+                    "RegionGraph::new".to_owned(),
+                    format!(
+                        "Region table too large ({} entries, maximum: {})",
+                        size, max_table_size
+                    ),
+                )
+                .attach_debug_object(
+                    "region (variable node indices)",
+                    region.iter().map(|v| variables[*v]).collect::<Vec<_>>(),
+                ));
+            }
+        }
+
+        //Counting numbers, larger regions first
+        let nr = regions.len();
+        let mut order: Vec<usize> = (0..nr).collect();
+        order.sort_by_key(|r| std::cmp::Reverse(regions[*r].len()));
+        let mut counting_numbers = vec![0; nr];
+        for r in order {
+            let ancestors: i64 = (0..nr)
+                .filter(|a| {
+                    regions[*a].len() > regions[r].len() && is_subset(&regions[r], &regions[*a])
+                })
+                .map(|a| counting_numbers[a])
+                .sum();
+            counting_numbers[r] = 1 - ancestors;
+        }
+
+        //Descendants (including the region itself) and parent-child edges
+        let family: Vec<Vec<bool>> = (0..nr)
+            .map(|r| {
+                (0..nr)
+                    .map(|d| is_subset(&regions[d], &regions[r]))
+                    .collect()
+            })
+            .collect();
+        let mut edges = Vec::new();
+        for p in 0..nr {
+            for c in 0..nr {
+                if p != c
+                    && family[p][c]
+                    && !(0..nr).any(|m| m != p && m != c && family[p][m] && family[m][c])
+                {
+                    edges.push((p, c));
+                }
+            }
+        }
+        let region_factors: Vec<Vec<usize>> = regions
+            .iter()
+            .map(|r| {
+                (0..factors.len())
+                    .filter(|f| is_subset(&factors[*f].vars, r))
+                    .collect()
+            })
+            .collect();
+        let mut numerators = Vec::with_capacity(edges.len());
+        let mut denominators = Vec::with_capacity(edges.len());
+        let mut edge_potentials = Vec::with_capacity(edges.len());
+        for (edge, (p, c)) in edges.iter().enumerate() {
+            let in_p = |r: usize| family[*p][r];
+            let in_c = |r: usize| family[*c][r];
+            numerators.push(
+                (0..edges.len())
+                    .filter(|e| {
+                        let (i, j) = edges[*e];
+                        in_p(j) && !in_c(j) && !in_p(i)
+                    })
+                    .collect(),
+            );
+            denominators.push(
+                (0..edges.len())
+                    .filter(|e| {
+                        let (i, j) = edges[*e];
+                        *e != edge && in_c(j) && in_p(i) && !in_c(i)
+                    })
+                    .collect(),
+            );
+            let mut potential = Table::ones(regions[*p].clone(), &domain_sizes);
+            for f in &region_factors[*p] {
+                if !region_factors[*c].contains(f) {
+                    potential.multiply(&factors[*f]);
+                }
+            }
+            edge_potentials.push(potential);
+        }
+        let incoming = (0..nr)
+            .map(|r| {
+                (0..edges.len())
+                    .filter(|e| {
+                        let (i, j) = edges[*e];
+                        family[r][j] && !family[r][i]
+                    })
+                    .collect()
+            })
+            .collect();
+        let messages = edges
+            .iter()
+            .map(|(_, c)| Table::ones(regions[*c].clone(), &domain_sizes))
+            .collect();
+        Ok(RegionGraph {
+            variables,
+            domains,
+            domain_sizes,
+            regions,
+            counting_numbers,
+            factors,
+            region_factors,
+            edges,
+            messages,
+            edge_potentials,
+            numerators,
+            denominators,
+            incoming,
+            damping: 0.0,
+        })
+    }
+
+    //New messages are mixed with the old ones: damping * old + (1 - damping) * new
+    pub fn set_damping(&mut self, damping: f64) -> BPResult<()> {
+        if !(0.0..1.0).contains(&damping) {
+            return Err(BPError::new(
+                "RegionGraph::set_damping".to_owned(),
+                format!("Damping has to be in [0, 1) (got {})", damping),
+            ));
+        }
+        self.damping = damping;
+        Ok(())
+    }
+
+    //Updates all messages (in place, in the order of the edges) until the largest change of an entry
+    //of a (normalized) message is at most tolerance or after max_iterations iterations.
+    //Returns the number of iterations.
+    pub fn propagate(&mut self, max_iterations: usize, tolerance: f64) -> usize {
+        for iteration in 0..max_iterations {
+            let mut max_change: f64 = 0.0;
+            for e in 0..self.edges.len() {
+                let (_, c) = self.edges[e];
+                let mut table = self.edge_potentials[e].clone();
+                for n in &self.numerators[e] {
+                    table.multiply(&self.messages[*n]);
+                }
+                let mut msg = table.marginalize(&self.regions[c], &self.domain_sizes);
+                for d in &self.denominators[e] {
+                    msg.divide(&self.messages[*d]);
+                }
+                msg.normalize();
+                let old = &self.messages[e];
+                for (new, old) in msg.values.iter_mut().zip(&old.values) {
+                    *new = self.damping * old + (1.0 - self.damping) * *new;
+                    max_change = max_change.max((*new - old).abs());
+                }
+                self.messages[e] = msg;
+            }
+            if max_change <= tolerance {
+                return iteration + 1;
+            }
+        }
+        max_iterations
+    }
+
+    fn belief(&self, region: usize) -> Table {
+        let mut belief = Table::ones(self.regions[region].clone(), &self.domain_sizes);
+        for f in &self.region_factors[region] {
+            belief.multiply(&self.factors[*f]);
+        }
+        for e in &self.incoming[region] {
+            belief.multiply(&self.messages[*e]);
+        }
+        belief.normalize();
+        belief
+    }
+
+    //Approximate marginal (summing to one) of a variable node, taken from the smallest region containing it
+    pub fn get_marginal(&self, node_index: NodeIndex) -> Option<HashMap<T, Probability>> {
+        let v = self.variables.iter().position(|idx| *idx == node_index)?;
+        let region = (0..self.regions.len())
+            .filter(|r| self.regions[*r].contains(&v))
+            .min_by_key(|r| self.regions[*r].len())?;
+        let mut marginal = self.belief(region).marginalize(&[v], &self.domain_sizes);
+        marginal.normalize();
+        Some(
+            self.domains[v]
+                .iter()
+                .copied()
+                .zip(marginal.values)
+                .collect(),
+        )
+    }
+
+    pub fn marginals(&self) -> HashMap<NodeIndex, HashMap<T, Probability>> {
+        self.variables
+            .iter()
+            .filter_map(|idx| self.get_marginal(*idx).map(|m| (*idx, m)))
+            .collect()
+    }
+
+    //Regions as lists of variable node indices with their counting numbers, the outer regions first
+    pub fn regions(&self) -> Vec<(Vec<NodeIndex>, i64)> {
+        self.regions
+            .iter()
+            .zip(&self.counting_numbers)
+            .map(|(r, c)| (r.iter().map(|v| self.variables[*v]).collect(), *c))
+            .collect()
+    }
+
+    //Parent-child relations as (parent, child) indices into regions
+    pub fn edges(&self) -> &[(usize, usize)] {
+        &self.edges
+    }
+}
@@ -0,0 +1,220 @@
+use crate::{BPError, BPGraph, BPResult, MarginalChange, Msg, NodeIndex, Probability};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+
+//Marginals of the variable nodes of one run, e.g., from BPGraph::marginals
+pub type MarginalSet<T> = HashMap<NodeIndex, HashMap<T, Probability>>;
+
+//How much the runs of a multi-restart propagation disagree on a node, see disagreement_report
+#[derive(Debug, Clone, PartialEq)]
+pub struct Disagreement<T> {
+    pub node: NodeIndex,
+    //Number of runs with a marginal for the node
+    pub runs: usize,
+    //Largest total variation distance between the marginals of two runs
+    pub max_total_variation: f64,
+    //Mean total variation distance between the marginal of a run and the averaged marginal
+    pub mean_total_variation: f64,
+    //Most frequent argmax over the runs (ties are broken by the smallest value) and the fraction of runs agreeing on it
+    pub majority: T,
+    pub agreement: f64,
+}
+
+//Mixture of the marginals of several runs. Every marginal is normalized first and weighted by the weight of
+//its run (e.g., exp(-free energy) of the run), equal weights if None. A node missing in a run is averaged over
+//the other runs, with their weights renormalized.
+pub fn average_marginals<T>(
+    sets: &[MarginalSet<T>],
+    weights: Option<&[f64]>,
+) -> BPResult<MarginalSet<T>>
+where
+    T: Copy + Eq + Hash,
+{
+    if sets.is_empty() {
+        return Err(BPError::new(
+            "average_marginals".to_owned(),
+            "No marginals to average".to_owned(),
+        ));
+    }
+    let weights = match weights {
+        Some(weights) => {
+            if weights.len() != sets.len() {
+                return Err(BPError::new(
+                    "average_marginals".to_owned(),
+                    format!(
+                        "Number of weights ({}) does not match the number of runs ({})",
+                        weights.len(),
+                        sets.len()
+                    ),
+                ));
+            }
+            if let Some(w) = weights.iter().find(|w| !w.is_finite() || **w < 0.0) {
+                return Err(BPError::new(
+                    "average_marginals".to_owned(),
+                    format!("Invalid weight {}", w),
+                ));
+            }
+            weights.to_vec()
+        }
+        None => vec![1.0; sets.len()],
+    };
+    let mut acc: HashMap<NodeIndex, (HashMap<T, Probability>, f64)> = HashMap::new();
+    for (set, w) in sets.iter().zip(weights) {
+        for (node, marginal) in set {
+            let sum: Probability = marginal.values().sum();
+            if sum <= 0.0 || !sum.is_finite() {
+                return Err(BPError::new(
+                    "average_marginals".to_owned(),
+                    format!(
+                        "Marginal of node {} cannot be normalized (sum {})",
+                        node, sum
+                    ),
+                ));
+            }
+            let (mixture, total) = acc.entry(*node).or_default();
+            for (v, p) in marginal {
+                *mixture.entry(*v).or_insert(0.0) += w * p / sum;
+            }
+            *total += w;
+        }
+    }
+    acc.into_iter()
+        .map(|(node, (mut mixture, total))| {
+            if total <= 0.0 {
+                return Err(BPError::new(
+                    "average_marginals".to_owned(),
+                    format!("All runs with a marginal of node {} have weight 0", node),
+                ));
+            }
+            mixture.values_mut().for_each(|p| *p /= total);
+            Ok((node, mixture))
+        })
+        .collect()
+}
+
+//Disagreement between the runs for every node with a marginal in at least two runs, the most disagreeing
+//nodes (by max_total_variation) first, ties are broken by the smallest index.
+pub fn disagreement_report<T>(sets: &[MarginalSet<T>]) -> BPResult<Vec<Disagreement<T>>>
+where
+    T: Copy + Ord + Hash,
+{
+    let average = average_marginals(sets, None)?;
+    let mut nodes: Vec<NodeIndex> = average.keys().copied().collect();
+    nodes.sort_unstable();
+    let mut report = Vec::new();
+    for node in nodes {
+        let marginals: Vec<&HashMap<T, Probability>> =
+            sets.iter().filter_map(|set| set.get(&node)).collect();
+        if marginals.len() < 2 {
+            continue;
+        }
+        let mut max_total_variation: f64 = 0.0;
+        for (i, m0) in marginals.iter().enumerate() {
+            for m1 in &marginals[i + 1..] {
+                max_total_variation =
+                    max_total_variation.max(MarginalChange::new(node, m0, m1).total_variation);
+            }
+        }
+        let mean_total_variation = marginals
+            .iter()
+            .map(|m| MarginalChange::new(node, m, &average[&node]).total_variation)
+            .sum::<f64>()
+            / marginals.len() as f64;
+        let mut votes: HashMap<T, usize> = HashMap::new();
+        for m in &marginals {
+            //The argmax with the smallest value, so that the report does not depend on the iteration order
+            let argmax = m
+                .iter()
+                .fold(None, |best: Option<(T, Probability)>, (v, p)| match best {
+                    Some((bv, bp)) if bp > *p || (bp == *p && bv < *v) => Some((bv, bp)),
+                    _ => Some((*v, *p)),
+                })
+                .map(|(v, _)| v);
+            if let Some(v) = argmax {
+                *votes.entry(v).or_insert(0) += 1;
+            }
+        }
+        let (majority, count) = votes
+            .into_iter()
+            .max_by(|(v0, c0), (v1, c1)| c0.cmp(c1).then(v1.cmp(v0)))
+            .ok_or_else(|| {
+                BPError::new(
+                    "disagreement_report".to_owned(),
+                    format!("Node {} has only empty marginals", node),
+                )
+            })?;
+        report.push(Disagreement {
+            node,
+            runs: marginals.len(),
+            max_total_variation,
+            mean_total_variation,
+            majority,
+            agreement: count as f64 / marginals.len() as f64,
+        });
+    }
+    report.sort_by(|d0, d1| {
+        d1.max_total_variation
+            .partial_cmp(&d0.max_total_variation)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then(d0.node.cmp(&d1.node))
+    });
+    Ok(report)
+}
+
+impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default> BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>
+where
+    T: Copy + Ord + Hash + Debug,
+    MsgT: Clone,
+{
+    //Current results of all variable nodes that have one
+    pub fn marginals(&self) -> BPResult<MarginalSet<T>> {
+        let mut marginals = HashMap::new();
+        for (node, _, is_factor) in self.nodes() {
+            if is_factor {
+                continue;
+            }
+            if let Some(marginal) = self.get_result(node)? {
+                marginals.insert(node, marginal);
+            }
+        }
+        Ok(marginals)
+    }
+
+    //Runs steps steps on a copy of the graph for every seed, starting over from random messages
+    //(see initialize_random) each time, and returns the marginals of every run.
+    //The graph itself is not changed. Combine the runs with average_marginals and disagreement_report.
+    pub fn run_restarts(
+        &self,
+        seeds: &[u64],
+        noise: Probability,
+        steps: usize,
+    ) -> BPResult<Vec<MarginalSet<T>>>
+    where
+        T: 'static,
+        MsgT: 'static,
+        CtrlMsgT: 'static,
+        CtrlMsgAT: 'static,
+    {
+        seeds
+            .iter()
+            .map(|seed| {
+                let mut g = self.try_clone().map_err(|e| {
+                    e.attach_info_str(
+                        "BPGraph::run_restarts",
+                        "Could not copy the graph".to_owned(),
+                    )
+                })?;
+                g.reset_schedule_state()?;
+                g.initialize_random(*seed, noise)?;
+                g.propagate(steps).map_err(|e| {
+                    e.attach_info_str(
+                        "BPGraph::run_restarts",
+                        format!("Run with seed {} failed", seed),
+                    )
+                })?;
+                g.marginals()
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,231 @@
+use crate::{
+    BPError, BPGraph, BPResult, Msg, MsgValidityError, NodeIndex, NormalizationMode, Probability,
+    Semiring, SumProduct,
+};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+
+//Message with an accumulated scale: the represented message is msg * exp(log_scale).
+//Use it as MsgT (e.g., BPGraph<T, ScaledMsg<HashMap<T, Probability>>>) with NormalizationMode::None to compute
+//likelihoods: mult_msg does not normalize but moves the magnitude of the product into log_scale whenever the
+//largest entry leaves [RESCALE_MIN, RESCALE_MAX], so long products neither overflow nor underflow.
+//
+//get, insert and the iterator see the stored entries, i.e., without the scale. Node functions that build new
+//messages from the entries (most factors) therefore drop the scale of incoming messages; the scale is kept
+//through mult_msg, mult_msg_weighted, add_msg_weighted and NormalizationMode::None, i.e., in variable nodes and
+//in BPGraph::get_result_scaled. for_each and the other normalization modes drop it.
+//Hence, the likelihood recovered at a variable only includes the scales accumulated at that variable (its prior
+//and the products of its incoming messages), e.g., it is exact for a variable connected to unary factors but
+//misses the scales of the messages that factors receive from other variables.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScaledMsg<MsgT> {
+    msg: MsgT,
+    log_scale: f64,
+}
+
+//Bounds of the largest entry outside of which mult_msg rescales
+const RESCALE_MIN: Probability = 1e-100;
+const RESCALE_MAX: Probability = 1e100;
+
+impl<MsgT> ScaledMsg<MsgT> {
+    pub fn new(msg: MsgT) -> Self {
+        ScaledMsg {
+            msg,
+            log_scale: 0.0,
+        }
+    }
+
+    pub fn with_log_scale(msg: MsgT, log_scale: f64) -> Self {
+        ScaledMsg { msg, log_scale }
+    }
+
+    pub fn log_scale(&self) -> f64 {
+        self.log_scale
+    }
+
+    //The stored entries
+    pub fn inner(&self) -> &MsgT {
+        &self.msg
+    }
+
+    pub fn into_parts(self) -> (MsgT, f64) {
+        (self.msg, self.log_scale)
+    }
+
+    //Divides the entries by the largest one and adds its logarithm to the scale.
+    //Messages without a positive finite entry are left unchanged.
+    pub fn rescale<T>(&mut self)
+    where
+        MsgT: Msg<T>,
+    {
+        let mut max: Probability = 0.0;
+        self.msg.for_each(|p| {
+            max = max.max(p);
+            p
+        });
+        if max > 0.0 && max.is_finite() && max != 1.0 {
+            self.msg.for_each(|p| p / max);
+            self.log_scale += max.ln();
+        }
+    }
+
+    //Logarithm of the sum of the represented entries, e.g., the log-likelihood of a result
+    pub fn log_sum<T>(&self) -> f64
+    where
+        MsgT: Msg<T> + Clone,
+    {
+        let sum: Probability = self.msg.clone().into_iter().map(|(_, p)| p).sum();
+        sum.ln() + self.log_scale
+    }
+
+    fn rescale_if_needed<T>(&mut self)
+    where
+        MsgT: Msg<T>,
+    {
+        let mut max: Probability = 0.0;
+        self.msg.for_each(|p| {
+            max = max.max(p);
+            p
+        });
+        if max > 0.0 && !(RESCALE_MIN..=RESCALE_MAX).contains(&max) {
+            self.rescale();
+        }
+    }
+}
+
+impl<MsgT> From<MsgT> for ScaledMsg<MsgT> {
+    fn from(msg: MsgT) -> Self {
+        ScaledMsg::new(msg)
+    }
+}
+
+impl<MsgT: IntoIterator> IntoIterator for ScaledMsg<MsgT> {
+    type Item = MsgT::Item;
+    type IntoIter = MsgT::IntoIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.msg.into_iter()
+    }
+}
+
+impl<T, MsgT: Msg<T> + Clone> Msg<T> for ScaledMsg<MsgT> {
+    fn new() -> Self {
+        ScaledMsg::new(MsgT::new())
+    }
+    fn get(&self, value: T) -> Option<Probability> {
+        self.msg.get(value)
+    }
+    fn get_mut(&mut self, value: T) -> Option<&mut Probability> {
+        self.msg.get_mut(value)
+    }
+    fn insert(&mut self, value: T, p: Probability) {
+        self.msg.insert(value, p);
+    }
+    fn normalize(&mut self) -> BPResult<()> {
+        self.normalize_with(NormalizationMode::SumToOne)
+    }
+    //Every mode except NormalizationMode::None drops the scale
+    fn normalize_with(&mut self, mode: NormalizationMode) -> BPResult<()> {
+        if mode == NormalizationMode::None {
+            self.rescale_if_needed();
+            return Ok(());
+        }
+        self.msg.normalize_with(mode)?;
+        self.log_scale = 0.0;
+        Ok(())
+    }
+    fn is_valid(&self) -> bool {
+        self.msg.is_valid()
+    }
+    fn validate(&self) -> Result<(), MsgValidityError> {
+        self.msg.validate()
+    }
+    //Multiplies without normalizing, the scales are added
+    fn mult_msg(&mut self, other: &Self) {
+        self.msg.times_msg(&other.msg, &SumProduct);
+        self.log_scale += other.log_scale;
+        self.rescale_if_needed();
+    }
+    fn clear(&mut self) {
+        self.msg.clear();
+        self.log_scale = 0.0;
+    }
+    fn mult_msg_weighted(&mut self, other: &Self, alpha: f64) {
+        let mut other_msg = other.msg.clone();
+        other_msg.for_each(|p| p.powf(alpha));
+        self.msg.times_msg(&other_msg, &SumProduct);
+        self.log_scale += alpha * other.log_scale;
+        self.rescale_if_needed();
+    }
+    //Both messages are brought to the larger of the two scales first
+    fn add_msg_weighted(&mut self, other: &Self, alpha_self: f64, alpha_other: f64) {
+        let log_scale = self.log_scale.max(other.log_scale);
+        let (factor_self, factor_other) = (
+            (self.log_scale - log_scale).exp(),
+            (other.log_scale - log_scale).exp(),
+        );
+        self.msg.add_msg_weighted(
+            &other.msg,
+            alpha_self * factor_self,
+            alpha_other * factor_other,
+        );
+        self.log_scale = log_scale;
+        self.rescale_if_needed();
+    }
+    //The scales are added, which is only meaningful if times of semiring is the product
+    fn times_msg(&mut self, other: &Self, semiring: &dyn Semiring) {
+        self.msg.times_msg(&other.msg, semiring);
+        self.log_scale += other.log_scale;
+    }
+    //f is applied to the stored entries and the scale is dropped
+    fn for_each(&mut self, f: impl FnMut(Probability) -> Probability) {
+        self.msg.for_each(f);
+        self.log_scale = 0.0;
+    }
+}
+
+impl<T, MsgT: Msg<T> + Clone, CtrlMsgT, CtrlMsgAT: Default>
+    BPGraph<T, ScaledMsg<MsgT>, CtrlMsgT, CtrlMsgAT>
+where
+    T: Clone + Eq + Hash + Debug,
+{
+    //Product of the prior and the incoming messages of a variable node as (entries, log_scale), i.e., the
+    //belief is entries * exp(log_scale) and the largest entry is 1. Unlike get_result, the product is not
+    //normalized, so that the log-likelihood (see ScaledMsg::log_sum) can be recovered. Scales dropped by factors
+    //are missing (see ScaledMsg). None if the node is a factor or has neither a prior nor messages.
+    pub fn get_result_scaled(
+        &self,
+        node_index: NodeIndex,
+    ) -> BPResult<Option<(HashMap<T, Probability>, f64)>> {
+        let node = self.get_node(node_index).map_err(|e| {
+            e.attach_info_str(
+                "BPGraph::get_result_scaled",
+                format!("Could not get node {}", node_index),
+            )
+        })?;
+        if node.is_factor() {
+            return Ok(None);
+        }
+        let mut msgs = node
+            .get_prior()
+            .into_iter()
+            .chain(node.clone_inbox().into_iter().map(|(_, msg)| msg));
+        let mut res = match msgs.next() {
+            Some(res) => res,
+            None => return Ok(None),
+        };
+        for msg in msgs {
+            res.mult_msg(&msg);
+        }
+        res.rescale();
+        let (msg, log_scale) = res.into_parts();
+        if !log_scale.is_finite() {
+            return Err(BPError::new(
+                "BPGraph::get_result_scaled".to_owned(),
+                format!("Scale of node {} is not finite ({})", node_index, log_scale),
+            ));
+        }
+        Ok(Some((msg.into_iter().collect(), log_scale)))
+    }
+}
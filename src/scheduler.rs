@@ -0,0 +1,54 @@
+use crate::{BPGraph, Msg, NodeIndex};
+use std::default::Default;
+use std::fmt::Debug;
+
+//Decides which nodes may send in a step of BPGraph::propagate_with_scheduler.
+//Of the returned nodes, only those that are ready create messages; all other nodes keep their inbox.
+pub trait Scheduler<T, MsgT: Msg<T>, CtrlMsgT = (), CtrlMsgAT: Default = ()>
+where
+    T: Debug,
+{
+    fn next_batch(&mut self, graph: &BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>) -> Vec<NodeIndex>;
+}
+
+//All nodes in every step (the behaviour of BPGraph::propagate, except that inboxes are never discarded)
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FloodingScheduler;
+
+impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default> Scheduler<T, MsgT, CtrlMsgT, CtrlMsgAT>
+    for FloodingScheduler
+where
+    T: Debug,
+{
+    fn next_batch(&mut self, graph: &BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>) -> Vec<NodeIndex> {
+        (0..graph.len()).collect()
+    }
+}
+
+//Cycles through a fixed list of batches, e.g., the layers of a coding graph
+#[derive(Clone, Debug)]
+pub struct LayerScheduler {
+    layers: Vec<Vec<NodeIndex>>,
+    next: usize,
+}
+
+impl LayerScheduler {
+    pub fn new(layers: Vec<Vec<NodeIndex>>) -> Self {
+        LayerScheduler { layers, next: 0 }
+    }
+}
+
+impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default> Scheduler<T, MsgT, CtrlMsgT, CtrlMsgAT>
+    for LayerScheduler
+where
+    T: Debug,
+{
+    fn next_batch(&mut self, _graph: &BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>) -> Vec<NodeIndex> {
+        if self.layers.is_empty() {
+            return Vec::new();
+        }
+        let batch = self.layers[self.next].clone();
+        self.next = (self.next + 1) % self.layers.len();
+        batch
+    }
+}
@@ -0,0 +1,219 @@
+use crate::{Msg, NormalizationMode, Probability};
+use std::fmt::Debug;
+
+//The algebra used to combine messages, selected per graph with BPGraph::set_semiring.
+//VariableNode, EqualityFactor, PairwiseFactor, ModAddFactor and ModMulFactor use it; other node functions
+//(e.g., TreeReweighted) always compute sum-product messages.
+//Priors, potentials and messages are interpreted in the domain of the semiring (e.g., costs for MinSum).
+pub trait Semiring: Debug + Send + Sync {
+    fn plus(&self, a: Probability, b: Probability) -> Probability;
+    fn times(&self, a: Probability, b: Probability) -> Probability;
+    fn zero(&self) -> Probability;
+    fn one(&self) -> Probability;
+    //Normalization used by BPGraph::set_semiring
+    fn normalization(&self) -> NormalizationMode {
+        NormalizationMode::None
+    }
+    //Converts a probability (or potential) into the domain of the semiring, e.g., to build tables and priors.
+    //BPGraph::add_grid, DistanceFactor and PairwiseFactor::from_probabilities build their tables with it.
+    fn lift(&self, p: Probability) -> Probability {
+        p
+    }
+}
+
+//Marginals (the default if no semiring is set)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SumProduct;
+
+impl Semiring for SumProduct {
+    fn plus(&self, a: Probability, b: Probability) -> Probability {
+        a + b
+    }
+    fn times(&self, a: Probability, b: Probability) -> Probability {
+        a * b
+    }
+    fn zero(&self) -> Probability {
+        0.0
+    }
+    fn one(&self) -> Probability {
+        1.0
+    }
+    fn normalization(&self) -> NormalizationMode {
+        NormalizationMode::SumToOne
+    }
+}
+
+//Max-marginals, i.e., the probability of the most likely assignment for every value
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MaxProduct;
+
+impl Semiring for MaxProduct {
+    fn plus(&self, a: Probability, b: Probability) -> Probability {
+        a.max(b)
+    }
+    fn times(&self, a: Probability, b: Probability) -> Probability {
+        a * b
+    }
+    fn zero(&self) -> Probability {
+        0.0
+    }
+    fn one(&self) -> Probability {
+        1.0
+    }
+    fn normalization(&self) -> NormalizationMode {
+        NormalizationMode::MaxToOne
+    }
+}
+
+//Costs (e.g., negative log-probabilities): the cost of the cheapest assignment for every value
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MinSum;
+
+impl Semiring for MinSum {
+    fn plus(&self, a: Probability, b: Probability) -> Probability {
+        a.min(b)
+    }
+    fn times(&self, a: Probability, b: Probability) -> Probability {
+        a + b
+    }
+    fn zero(&self) -> Probability {
+        Probability::INFINITY
+    }
+    fn one(&self) -> Probability {
+        0.0
+    }
+    fn normalization(&self) -> NormalizationMode {
+        NormalizationMode::MinShift
+    }
+    fn lift(&self, p: Probability) -> Probability {
+        -p.ln()
+    }
+}
+
+//Log-probabilities: the log-probability of the most likely assignment for every value (max-product in the
+//log domain). Messages are normalized by subtracting the maximum.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MaxSum;
+
+impl Semiring for MaxSum {
+    fn plus(&self, a: Probability, b: Probability) -> Probability {
+        a.max(b)
+    }
+    fn times(&self, a: Probability, b: Probability) -> Probability {
+        a + b
+    }
+    fn zero(&self) -> Probability {
+        Probability::NEG_INFINITY
+    }
+    fn one(&self) -> Probability {
+        0.0
+    }
+    fn normalization(&self) -> NormalizationMode {
+        NormalizationMode::LogShift
+    }
+    fn lift(&self, p: Probability) -> Probability {
+        p.ln()
+    }
+}
+
+//Reachability: 1 if a value is consistent with some assignment, 0 otherwise (entries > 0 count as true)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BooleanOrAnd;
+
+impl Semiring for BooleanOrAnd {
+    fn plus(&self, a: Probability, b: Probability) -> Probability {
+        if a > 0.0 || b > 0.0 {
+            1.0
+        } else {
+            0.0
+        }
+    }
+    fn times(&self, a: Probability, b: Probability) -> Probability {
+        if a > 0.0 && b > 0.0 {
+            1.0
+        } else {
+            0.0
+        }
+    }
+    fn zero(&self) -> Probability {
+        0.0
+    }
+    fn one(&self) -> Probability {
+        1.0
+    }
+    fn lift(&self, p: Probability) -> Probability {
+        if p > 0.0 {
+            1.0
+        } else {
+            0.0
+        }
+    }
+}
+
+//Node functions keep Option<Arc<dyn Semiring>>, None is sum-product with the usual (renormalizing) Msg::mult_msg
+pub(crate) fn plus(semiring: Option<&dyn Semiring>, a: Probability, b: Probability) -> Probability {
+    match semiring {
+        Some(s) => s.plus(a, b),
+        None => a + b,
+    }
+}
+
+pub(crate) fn times(
+    semiring: Option<&dyn Semiring>,
+    a: Probability,
+    b: Probability,
+) -> Probability {
+    match semiring {
+        Some(s) => s.times(a, b),
+        None => a * b,
+    }
+}
+
+pub(crate) fn zero(semiring: Option<&dyn Semiring>) -> Probability {
+    semiring.map_or(0.0, |s| s.zero())
+}
+
+pub(crate) fn one(semiring: Option<&dyn Semiring>) -> Probability {
+    semiring.map_or(1.0, |s| s.one())
+}
+
+pub(crate) fn times_msg<T, MsgT: Msg<T> + Clone>(
+    semiring: Option<&dyn Semiring>,
+    acc: &mut MsgT,
+    msg: &MsgT,
+) {
+    match semiring {
+        Some(s) => acc.times_msg(msg, s),
+        None => acc.mult_msg(msg),
+    }
+}
+
+//Semirings for which node functions have specialized algorithms (e.g., distance transforms),
+//recognized by their operations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SemiringKind {
+    SumProduct,
+    MaxProduct,
+    MinSum,
+    MaxSum,
+    Other,
+}
+
+pub(crate) fn kind(semiring: Option<&dyn Semiring>) -> SemiringKind {
+    let s = match semiring {
+        Some(s) => s,
+        None => return SemiringKind::SumProduct,
+    };
+    let ops = (s.plus(2.0, 3.0), s.times(2.0, 3.0), s.zero(), s.one());
+    if ops == (5.0, 6.0, 0.0, 1.0) {
+        SemiringKind::SumProduct
+    } else if ops == (3.0, 6.0, 0.0, 1.0) {
+        SemiringKind::MaxProduct
+    } else if ops == (2.0, 5.0, Probability::INFINITY, 0.0) {
+        SemiringKind::MinSum
+    } else if ops == (3.0, 5.0, Probability::NEG_INFINITY, 0.0) {
+        SemiringKind::MaxSum
+    } else {
+        SemiringKind::Other
+    }
+}
@@ -0,0 +1,135 @@
+use crate::{BPResult, Msg, MsgValidityError, NormalizationMode, Probability, Semiring};
+use std::ops::Deref;
+use std::sync::Arc;
+
+//Reference counted message with copy-on-write semantics. Cloning only increments the reference count and the
+//inner message is copied (Arc::make_mut) the first time a receiver modifies a shared message. Use it as MsgT
+//(e.g., BPGraph<T, SharedMsg<HashMap<T, Probability>>>) when nodes send the same large message to many
+//neighbours, e.g., a variable broadcasting its prior to all factors in the first step.
+//
+//Normalizing a message that is already normalized (shift and scale within 1e-12 of 0 and 1) does not copy it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SharedMsg<MsgT>(Arc<MsgT>);
+
+//Normalizations closer than this to the identity are skipped to keep messages shared
+const NORMALIZED_TOLERANCE: Probability = 1e-12;
+
+impl<MsgT> SharedMsg<MsgT> {
+    pub fn new(msg: MsgT) -> Self {
+        SharedMsg(Arc::new(msg))
+    }
+
+    pub fn from_arc(msg: Arc<MsgT>) -> Self {
+        SharedMsg(msg)
+    }
+
+    pub fn as_arc(&self) -> &Arc<MsgT> {
+        &self.0
+    }
+
+    //Whether both messages share the same allocation
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+
+    pub fn strong_count(&self) -> usize {
+        Arc::strong_count(&self.0)
+    }
+}
+
+impl<MsgT: Clone> SharedMsg<MsgT> {
+    //Copies the inner message if it is shared
+    pub fn make_mut(&mut self) -> &mut MsgT {
+        Arc::make_mut(&mut self.0)
+    }
+
+    pub fn into_inner(self) -> MsgT {
+        Arc::try_unwrap(self.0).unwrap_or_else(|msg| (*msg).clone())
+    }
+}
+
+impl<MsgT> Deref for SharedMsg<MsgT> {
+    type Target = MsgT;
+
+    fn deref(&self) -> &MsgT {
+        &self.0
+    }
+}
+
+impl<MsgT> From<MsgT> for SharedMsg<MsgT> {
+    fn from(msg: MsgT) -> Self {
+        SharedMsg::new(msg)
+    }
+}
+
+impl<MsgT: IntoIterator + Clone> IntoIterator for SharedMsg<MsgT> {
+    type Item = MsgT::Item;
+    type IntoIter = MsgT::IntoIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.into_inner().into_iter()
+    }
+}
+
+impl<T, MsgT: Msg<T> + Clone> Msg<T> for SharedMsg<MsgT> {
+    fn new() -> Self {
+        SharedMsg::new(MsgT::new())
+    }
+    fn get(&self, value: T) -> Option<Probability> {
+        self.0.get(value)
+    }
+    fn get_mut(&mut self, value: T) -> Option<&mut Probability> {
+        self.make_mut().get_mut(value)
+    }
+    fn insert(&mut self, value: T, p: Probability) {
+        self.make_mut().insert(value, p);
+    }
+    fn normalize(&mut self) -> BPResult<()> {
+        self.normalize_with(NormalizationMode::SumToOne)
+    }
+    fn normalize_with(&mut self, mode: NormalizationMode) -> BPResult<()> {
+        if mode == NormalizationMode::None {
+            return Ok(());
+        }
+        if Arc::get_mut(&mut self.0).is_some() {
+            return self.make_mut().normalize_with(mode);
+        }
+        //Shared: keep sharing the original if normalizing would not change it
+        let (shift, scale) = self.0.normalization(mode)?;
+        if shift.abs() > NORMALIZED_TOLERANCE || (scale - 1.0).abs() > NORMALIZED_TOLERANCE {
+            self.make_mut().normalize_with(mode)?;
+        }
+        Ok(())
+    }
+    fn normalization(&self, mode: NormalizationMode) -> BPResult<(Probability, Probability)> {
+        self.0.normalization(mode)
+    }
+    fn is_valid(&self) -> bool {
+        self.0.is_valid()
+    }
+    fn validate(&self) -> Result<(), MsgValidityError> {
+        self.0.validate()
+    }
+    fn mult_msg(&mut self, other: &Self) {
+        self.make_mut().mult_msg(&other.0);
+    }
+    fn clear(&mut self) {
+        match Arc::get_mut(&mut self.0) {
+            Some(msg) => msg.clear(),
+            None => self.0 = Arc::new(MsgT::new()),
+        }
+    }
+    fn mult_msg_weighted(&mut self, other: &Self, alpha: f64) {
+        self.make_mut().mult_msg_weighted(&other.0, alpha);
+    }
+    fn add_msg_weighted(&mut self, other: &Self, alpha_self: f64, alpha_other: f64) {
+        self.make_mut()
+            .add_msg_weighted(&other.0, alpha_self, alpha_other);
+    }
+    fn times_msg(&mut self, other: &Self, semiring: &dyn Semiring) {
+        self.make_mut().times_msg(&other.0, semiring);
+    }
+    fn for_each(&mut self, f: impl FnMut(Probability) -> Probability) {
+        self.make_mut().for_each(f);
+    }
+}
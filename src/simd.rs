@@ -0,0 +1,196 @@
+use crate::Probability;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/*
+Kernels for the elementwise products and sum reductions of dense messages (see DenseMsg).
+
+On x86_64, AVX is detected at runtime and used for 4 lanes of f64 at once, everything else falls back to
+the scalar loops. Reductions use 4 partial sums, so their results may differ from the scalar loops by
+rounding. Short slices are not worth the dispatch and always use the scalar loops.
+*/
+
+//Below this length, the scalar loops are used
+const MIN_LEN: usize = 16;
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+//Allows to turn the SIMD kernels off, e.g., to compare results or timings
+pub fn set_simd_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+//Whether the SIMD kernels are enabled and supported by the CPU
+pub fn simd_available() -> bool {
+    ENABLED.load(Ordering::Relaxed) && detected()
+}
+
+#[cfg(target_arch = "x86_64")]
+fn detected() -> bool {
+    is_x86_feature_detected!("avx")
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn detected() -> bool {
+    false
+}
+
+fn use_simd(len: usize) -> bool {
+    len >= MIN_LEN && simd_available()
+}
+
+//a[i] *= b[i]
+pub(crate) fn mul_assign(a: &mut [Probability], b: &[Probability]) {
+    #[cfg(target_arch = "x86_64")]
+    if use_simd(a.len().min(b.len())) {
+        //Safe, as AVX is available
+        unsafe { avx::mul_assign(a, b) };
+        return;
+    }
+    a.iter_mut().zip(b).for_each(|(a, b)| *a *= b);
+}
+
+//a[i] *= s
+pub(crate) fn scale(a: &mut [Probability], s: Probability) {
+    #[cfg(target_arch = "x86_64")]
+    if use_simd(a.len()) {
+        //Safe, as AVX is available
+        unsafe { avx::scale(a, s) };
+        return;
+    }
+    a.iter_mut().for_each(|a| *a *= s);
+}
+
+//a[i] /= d
+pub(crate) fn divide(a: &mut [Probability], d: Probability) {
+    #[cfg(target_arch = "x86_64")]
+    if use_simd(a.len()) {
+        //Safe, as AVX is available
+        unsafe { avx::divide(a, d) };
+        return;
+    }
+    a.iter_mut().for_each(|a| *a /= d);
+}
+
+//acc[i] += s * a[i]
+pub(crate) fn axpy(acc: &mut [Probability], a: &[Probability], s: Probability) {
+    #[cfg(target_arch = "x86_64")]
+    if use_simd(acc.len().min(a.len())) {
+        //Safe, as AVX is available
+        unsafe { avx::axpy(acc, a, s) };
+        return;
+    }
+    acc.iter_mut().zip(a).for_each(|(acc, a)| *acc += s * a);
+}
+
+pub(crate) fn sum(a: &[Probability]) -> Probability {
+    #[cfg(target_arch = "x86_64")]
+    if use_simd(a.len()) {
+        //Safe, as AVX is available
+        return unsafe { avx::sum(a) };
+    }
+    a.iter().sum()
+}
+
+//Maximum of |a[i]|, 0 for an empty slice. NaN entries are ignored.
+pub(crate) fn max_abs(a: &[Probability]) -> Probability {
+    #[cfg(target_arch = "x86_64")]
+    if use_simd(a.len()) {
+        //Safe, as AVX is available
+        return unsafe { avx::max_abs(a) };
+    }
+    a.iter().fold(0.0, |acc: Probability, p| acc.max(p.abs()))
+}
+
+#[cfg(target_arch = "x86_64")]
+mod avx {
+    use crate::Probability;
+    use std::arch::x86_64::*;
+
+    //The kernels only load and store at i..i + LANES with i + LANES <= chunks <= n, i.e., within both slices
+    const LANES: usize = 4;
+
+    #[target_feature(enable = "avx")]
+    pub(super) unsafe fn mul_assign(a: &mut [Probability], b: &[Probability]) {
+        let n = a.len().min(b.len());
+        let chunks = n / LANES * LANES;
+        for i in (0..chunks).step_by(LANES) {
+            let x = _mm256_loadu_pd(a.as_ptr().add(i));
+            let y = _mm256_loadu_pd(b.as_ptr().add(i));
+            _mm256_storeu_pd(a.as_mut_ptr().add(i), _mm256_mul_pd(x, y));
+        }
+        for i in chunks..n {
+            a[i] *= b[i];
+        }
+    }
+
+    #[target_feature(enable = "avx")]
+    pub(super) unsafe fn scale(a: &mut [Probability], s: Probability) {
+        let chunks = a.len() / LANES * LANES;
+        let factor = _mm256_set1_pd(s);
+        for i in (0..chunks).step_by(LANES) {
+            let x = _mm256_loadu_pd(a.as_ptr().add(i));
+            _mm256_storeu_pd(a.as_mut_ptr().add(i), _mm256_mul_pd(x, factor));
+        }
+        a[chunks..].iter_mut().for_each(|a| *a *= s);
+    }
+
+    #[target_feature(enable = "avx")]
+    pub(super) unsafe fn divide(a: &mut [Probability], d: Probability) {
+        let chunks = a.len() / LANES * LANES;
+        let divisor = _mm256_set1_pd(d);
+        for i in (0..chunks).step_by(LANES) {
+            let x = _mm256_loadu_pd(a.as_ptr().add(i));
+            _mm256_storeu_pd(a.as_mut_ptr().add(i), _mm256_div_pd(x, divisor));
+        }
+        a[chunks..].iter_mut().for_each(|a| *a /= d);
+    }
+
+    #[target_feature(enable = "avx")]
+    pub(super) unsafe fn axpy(acc: &mut [Probability], a: &[Probability], s: Probability) {
+        let n = acc.len().min(a.len());
+        let chunks = n / LANES * LANES;
+        let factor = _mm256_set1_pd(s);
+        for i in (0..chunks).step_by(LANES) {
+            let x = _mm256_loadu_pd(acc.as_ptr().add(i));
+            let y = _mm256_loadu_pd(a.as_ptr().add(i));
+            _mm256_storeu_pd(
+                acc.as_mut_ptr().add(i),
+                _mm256_add_pd(x, _mm256_mul_pd(y, factor)),
+            );
+        }
+        for i in chunks..n {
+            acc[i] += s * a[i];
+        }
+    }
+
+    #[target_feature(enable = "avx")]
+    pub(super) unsafe fn sum(a: &[Probability]) -> Probability {
+        let chunks = a.len() / LANES * LANES;
+        let mut acc = _mm256_setzero_pd();
+        for i in (0..chunks).step_by(LANES) {
+            acc = _mm256_add_pd(acc, _mm256_loadu_pd(a.as_ptr().add(i)));
+        }
+        let mut lanes = [0.0; LANES];
+        _mm256_storeu_pd(lanes.as_mut_ptr(), acc);
+        lanes.iter().sum::<Probability>() + a[chunks..].iter().sum::<Probability>()
+    }
+
+    #[target_feature(enable = "avx")]
+    pub(super) unsafe fn max_abs(a: &[Probability]) -> Probability {
+        let chunks = a.len() / LANES * LANES;
+        //Clears the sign bit
+        let mask = _mm256_castsi256_pd(_mm256_set1_epi64x(i64::MAX));
+        let mut acc = _mm256_setzero_pd();
+        for i in (0..chunks).step_by(LANES) {
+            let x = _mm256_and_pd(_mm256_loadu_pd(a.as_ptr().add(i)), mask);
+            //Returns the second operand if one is NaN, so that NaN entries are ignored like in f64::max
+            acc = _mm256_max_pd(x, acc);
+        }
+        let mut lanes = [0.0; LANES];
+        _mm256_storeu_pd(lanes.as_mut_ptr(), acc);
+        lanes
+            .iter()
+            .chain(a[chunks..].iter())
+            .fold(0.0, |acc: Probability, p| acc.max(p.abs()))
+    }
+}
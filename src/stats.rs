@@ -0,0 +1,147 @@
+use crate::{BPGraph, Msg, NodeIndex};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Debug;
+
+//Number of BFS roots used to search for the shortest cycle
+const CYCLE_SAMPLES: usize = 64;
+//Number of variable nodes whose priors are used for the average domain size
+const DOMAIN_SAMPLES: usize = 1000;
+
+//Summary of the structure of a graph, see BPGraph::stats
+#[derive(Debug, Clone, PartialEq)]
+pub struct GraphStats {
+    pub variables: usize,
+    pub factors: usize,
+    pub edges: usize,
+    //degree -> number of nodes
+    pub variable_degrees: BTreeMap<usize, usize>,
+    pub factor_degrees: BTreeMap<usize, usize>,
+    pub connected_components: usize,
+    //Length of the shortest cycle found (in edges, always even), None if the graph has no cycle.
+    //Cycles are searched from up to 64 nodes only, so this is an upper bound of the girth unless shortest_cycle_exact.
+    pub shortest_cycle: Option<usize>,
+    pub shortest_cycle_exact: bool,
+    //Average number of values in the priors of (up to 1000 evenly spaced) variable nodes, None if there are no priors
+    pub average_domain_size: Option<f64>,
+}
+
+impl GraphStats {
+    pub fn is_forest(&self) -> bool {
+        self.edges + self.connected_components == self.variables + self.factors
+    }
+}
+
+impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default> BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>
+where
+    T: Debug,
+{
+    pub fn stats(&self) -> GraphStats {
+        let len = self.len();
+        let mut variable_degrees = BTreeMap::new();
+        let mut factor_degrees = BTreeMap::new();
+        let mut factors = 0;
+        let mut edges = 0;
+        for (i, _, is_factor) in self.nodes() {
+            let degree = self.get_connections(i).expect("Index is valid").len();
+            edges += degree;
+            if is_factor {
+                factors += 1;
+                *factor_degrees.entry(degree).or_insert(0) += 1;
+            } else {
+                *variable_degrees.entry(degree).or_insert(0) += 1;
+            }
+        }
+        let mut stats = GraphStats {
+            variables: len - factors,
+            factors,
+            edges: edges / 2,
+            variable_degrees,
+            factor_degrees,
+            connected_components: self.count_components(),
+            shortest_cycle: None,
+            shortest_cycle_exact: true,
+            average_domain_size: self.average_domain_size(),
+        };
+        if !stats.is_forest() {
+            let step = (len / CYCLE_SAMPLES).max(1);
+            stats.shortest_cycle = (0..len)
+                .step_by(step)
+                .filter_map(|root| self.shortest_cycle_through(root))
+                .min();
+            stats.shortest_cycle_exact = step == 1;
+        }
+        stats
+    }
+
+    fn count_components(&self) -> usize {
+        let mut visited = vec![false; self.len()];
+        let mut components = 0;
+        let mut stack = Vec::new();
+        for root in 0..self.len() {
+            if visited[root] {
+                continue;
+            }
+            components += 1;
+            visited[root] = true;
+            stack.push(root);
+            while let Some(n) = stack.pop() {
+                for con in self.get_connections(n).expect("Index is valid") {
+                    if !visited[*con] {
+                        visited[*con] = true;
+                        stack.push(*con);
+                    }
+                }
+            }
+        }
+        components
+    }
+
+    //Length of the shortest cycle found by a BFS from root (the shortest cycle through root or a shorter one)
+    fn shortest_cycle_through(&self, root: NodeIndex) -> Option<usize> {
+        let mut dist: Vec<Option<(usize, NodeIndex)>> = vec![None; self.len()];
+        dist[root] = Some((0, root));
+        let mut queue = VecDeque::new();
+        queue.push_back(root);
+        let mut shortest: Option<usize> = None;
+        while let Some(n) = queue.pop_front() {
+            let (d, parent) = dist[n].expect("Queued nodes have a distance");
+            if shortest.is_some_and(|s| 2 * d + 1 >= s) {
+                break;
+            }
+            for con in self.get_connections(n).expect("Index is valid") {
+                match dist[*con] {
+                    None => {
+                        dist[*con] = Some((d + 1, n));
+                        queue.push_back(*con);
+                    }
+                    Some((d_con, _)) if *con != parent => {
+                        let length = d + d_con + 1;
+                        shortest = Some(shortest.map_or(length, |s| s.min(length)));
+                    }
+                    _ => (),
+                }
+            }
+        }
+        shortest
+    }
+
+    fn average_domain_size(&self) -> Option<f64> {
+        let variables: Vec<NodeIndex> = self
+            .nodes()
+            .filter(|(_, _, is_factor)| !is_factor)
+            .map(|(i, _, _)| i)
+            .collect();
+        let step = (variables.len() / DOMAIN_SAMPLES).max(1);
+        let sizes: Vec<usize> = variables
+            .iter()
+            .step_by(step)
+            .filter_map(|i| self.get_node(*i).expect("Index is valid").get_prior())
+            .map(|prior| prior.into_iter().count())
+            .collect();
+        if sizes.is_empty() {
+            None
+        } else {
+            Some(sizes.iter().sum::<usize>() as f64 / sizes.len() as f64)
+        }
+    }
+}
@@ -0,0 +1,554 @@
+use crate::{BPError, BPGraph, BPResult, Msg, NodeFunction, NodeIndex, Probability, SplitMix64};
+use std::collections::HashMap;
+
+/*
+Survey propagation (Braunstein, Mezard, Zecchina) for k-SAT.
+
+All messages are over SpValue:
+    clause a -> variable i: the survey eta_ai (probability that a warns i to satisfy a) under the value the
+                            literal of i in a needs (True for x_i, False for !x_i), 1 - eta_ai under Joker.
+    variable i -> clause a: Pi(True), Pi(False), Pi(Joker) computed from the warnings of all clauses but a:
+        Pi(True)  = [1 - prod_{b warns True} (1 - eta_bi)] * prod_{b warns False} (1 - eta_bi)
+        Pi(False) = [1 - prod_{b warns False} (1 - eta_bi)] * prod_{b warns True} (1 - eta_bi)
+        Pi(Joker) = prod_b (1 - eta_bi)
+The clause computes eta_ai = prod_{j != i} Pi_j(violates a) / (Pi_j(True) + Pi_j(False) + Pi_j(Joker)).
+
+Clauses send random surveys in the first step, afterwards every node waits for all its messages.
+Variables are fixed (SpCtrl::Fix) by sending a message that forces their value to every clause.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum SpValue {
+    True,
+    False,
+    Joker,
+}
+
+impl SpValue {
+    fn from_bool(value: bool) -> Self {
+        if value {
+            SpValue::True
+        } else {
+            SpValue::False
+        }
+    }
+}
+
+//Normalized bias of a variable: probability of being forced to true, forced to false or being free
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct SpBias {
+    pub plus: Probability,
+    pub minus: Probability,
+    pub zero: Probability,
+}
+
+#[derive(Debug, Clone)]
+pub enum SpCtrl {
+    //Answered by SpVariable with SpCtrlAnswer::Bias
+    GetBias,
+    //Fixes (or frees) a variable
+    Fix(Option<bool>),
+}
+
+#[derive(Debug, Clone, Default)]
+pub enum SpCtrlAnswer {
+    #[default]
+    Done,
+    //All entries are 0 if the variable receives contradicting warnings
+    Bias(SpBias),
+}
+
+pub type SpGraph<MsgT = HashMap<SpValue, Probability>> =
+    BPGraph<SpValue, MsgT, SpCtrl, SpCtrlAnswer>;
+
+//prod (1 - eta) over the warnings for True and for False, skipping the warning of skip
+fn survey_products(
+    warnings: &[(NodeIndex, SpValue, Probability)],
+    skip: Option<NodeIndex>,
+) -> (Probability, Probability) {
+    warnings
+        .iter()
+        .filter(|(from, _, _)| Some(*from) != skip)
+        .fold((1.0, 1.0), |(t, f), (_, value, eta)| match value {
+            SpValue::True => (t * (1.0 - eta), f),
+            SpValue::False => (t, f * (1.0 - eta)),
+            SpValue::Joker => (t, f),
+        })
+}
+
+//Pi(True), Pi(False), Pi(Joker)
+fn variable_triple(
+    products: (Probability, Probability),
+) -> (Probability, Probability, Probability) {
+    let (t, f) = products;
+    ((1.0 - t) * f, (1.0 - f) * t, t * f)
+}
+
+pub struct SpVariable<MsgT> {
+    connections: Option<Vec<NodeIndex>>,
+    //(clause, value the clause warns for, eta) as last received
+    warnings: Vec<(NodeIndex, SpValue, Probability)>,
+    fixed: Option<bool>,
+    phantom: std::marker::PhantomData<MsgT>,
+}
+
+//Not derived, which would require MsgT: Clone
+impl<MsgT> Clone for SpVariable<MsgT> {
+    fn clone(&self) -> Self {
+        SpVariable {
+            connections: self.connections.clone(),
+            warnings: self.warnings.clone(),
+            fixed: self.fixed,
+            phantom: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<MsgT> SpVariable<MsgT> {
+    pub fn new() -> Self {
+        SpVariable {
+            connections: None,
+            warnings: Vec::new(),
+            fixed: None,
+            phantom: std::marker::PhantomData,
+        }
+    }
+
+    pub fn get_fixed(&self) -> Option<bool> {
+        self.fixed
+    }
+
+    //Computed from the last received surveys
+    pub fn bias(&self) -> SpBias {
+        let (plus, minus, zero) = variable_triple(survey_products(&self.warnings, None));
+        let sum = plus + minus + zero;
+        if sum > 0.0 {
+            SpBias {
+                plus: plus / sum,
+                minus: minus / sum,
+                zero: zero / sum,
+            }
+        } else {
+            SpBias::default()
+        }
+    }
+}
+
+impl<MsgT> Default for SpVariable<MsgT> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<MsgT: Msg<SpValue>> NodeFunction<SpValue, MsgT, SpCtrl, SpCtrlAnswer> for SpVariable<MsgT> {
+    fn node_function(&mut self, inbox: Vec<(NodeIndex, MsgT)>) -> BPResult<Vec<(NodeIndex, MsgT)>> {
+        let connections = self.connections.as_ref().ok_or_else(|| {
+            BPError::new(
+                "SpVariable::node_function".to_owned(),
+                "SpVariable not initialized".to_owned(),
+            )
+        })?;
+        self.warnings = inbox
+            .iter()
+            .map(
+                |(from, msg)| match (msg.get(SpValue::True), msg.get(SpValue::False)) {
+                    (Some(eta), _) => (*from, SpValue::True, eta),
+                    (None, Some(eta)) => (*from, SpValue::False, eta),
+                    (None, None) => (*from, SpValue::Joker, 0.0),
+                },
+            )
+            .collect();
+        Ok(connections
+            .iter()
+            .map(|con| {
+                let (t, f, j) = match self.fixed {
+                    Some(true) => (1.0, 0.0, 0.0),
+                    Some(false) => (0.0, 1.0, 0.0),
+                    None => variable_triple(survey_products(&self.warnings, Some(*con))),
+                };
+                let mut msg = MsgT::new();
+                msg.insert(SpValue::True, t);
+                msg.insert(SpValue::False, f);
+                msg.insert(SpValue::Joker, j);
+                (*con, msg)
+            })
+            .collect())
+    }
+    impl_clone_box!(SpValue, MsgT, SpCtrl, SpCtrlAnswer);
+
+    fn is_factor(&self) -> bool {
+        false
+    }
+    fn number_inputs(&self) -> Option<usize> {
+        None
+    }
+    fn initialize(&mut self, connections: Vec<NodeIndex>) -> BPResult<()> {
+        self.connections = Some(connections);
+        Ok(())
+    }
+    fn is_ready(&self, recv_from: &Vec<(NodeIndex, MsgT)>, _current_step: usize) -> BPResult<bool> {
+        Ok(!recv_from.is_empty()
+            && recv_from.len() == self.connections.as_ref().map_or(0, |c| c.len()))
+    }
+    fn reset(&mut self) -> BPResult<()> {
+        self.connections = None;
+        self.warnings.clear();
+        Ok(())
+    }
+    fn get_prior(&self) -> Option<MsgT> {
+        None
+    }
+    fn accepts_control_message(&self, _ctrl_msg: &SpCtrl) -> bool {
+        true
+    }
+    fn control_message_changes_node(&self, ctrl_msg: &SpCtrl) -> bool {
+        !matches!(ctrl_msg, SpCtrl::GetBias)
+    }
+    fn send_control_message(&mut self, ctrl_msg: SpCtrl) -> BPResult<SpCtrlAnswer> {
+        Ok(match ctrl_msg {
+            SpCtrl::GetBias => SpCtrlAnswer::Bias(self.bias()),
+            SpCtrl::Fix(value) => {
+                self.fixed = value;
+                SpCtrlAnswer::Done
+            }
+        })
+    }
+}
+
+//A clause; signs[k] is true if the variable of the k-th connection appears as a positive literal
+pub struct SpFactor<MsgT> {
+    signs: Vec<bool>,
+    connections: Option<Vec<NodeIndex>>,
+    has_propagated: bool,
+    rng: SplitMix64,
+    phantom: std::marker::PhantomData<MsgT>,
+}
+
+impl<MsgT> Clone for SpFactor<MsgT> {
+    fn clone(&self) -> Self {
+        SpFactor {
+            signs: self.signs.clone(),
+            connections: self.connections.clone(),
+            has_propagated: self.has_propagated,
+            rng: self.rng.clone(),
+            phantom: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<MsgT> SpFactor<MsgT> {
+    //seed determines the random surveys of the first step
+    pub fn new(signs: Vec<bool>, seed: u64) -> Self {
+        SpFactor {
+            signs,
+            connections: None,
+            has_propagated: false,
+            rng: SplitMix64::new(seed),
+            phantom: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<MsgT: Msg<SpValue>> NodeFunction<SpValue, MsgT, SpCtrl, SpCtrlAnswer> for SpFactor<MsgT> {
+    fn node_function(&mut self, inbox: Vec<(NodeIndex, MsgT)>) -> BPResult<Vec<(NodeIndex, MsgT)>> {
+        let connections = self.connections.as_ref().ok_or_else(|| {
+            BPError::new(
+                "SpFactor::node_function".to_owned(),
+                "SpFactor not initialized".to_owned(),
+            )
+        })?;
+        let surveys: Vec<Probability> = if self.has_propagated {
+            //Probability that the variable of each connection is forced to violate the clause
+            let mut violating = vec![0.0; connections.len()];
+            for (from, msg) in &inbox {
+                let k = connections
+                    .iter()
+                    .position(|con| con == from)
+                    .ok_or_else(|| {
+                        BPError::new(
+                            "SpFactor::node_function".to_owned(),
+                            format!("Received message from {}, which is not a connection", from),
+                        )
+                    })?;
+                let get = |v| msg.get(v).unwrap_or(0.0);
+                let sum = get(SpValue::True) + get(SpValue::False) + get(SpValue::Joker);
+                let violates = get(SpValue::from_bool(!self.signs[k]));
+                violating[k] = if sum > 0.0 { violates / sum } else { 0.0 };
+            }
+            (0..connections.len())
+                .map(|k| {
+                    violating
+                        .iter()
+                        .enumerate()
+                        .filter(|(j, _)| *j != k)
+                        .map(|(_, p)| p)
+                        .product()
+                })
+                .collect()
+        } else {
+            let rng = &mut self.rng;
+            connections.iter().map(|_| rng.next_f64()).collect()
+        };
+        self.has_propagated = true;
+        Ok(connections
+            .iter()
+            .zip(surveys)
+            .zip(self.signs.iter())
+            .map(|((con, eta), sign)| {
+                let mut msg = MsgT::new();
+                msg.insert(SpValue::from_bool(*sign), eta);
+                msg.insert(SpValue::Joker, 1.0 - eta);
+                (*con, msg)
+            })
+            .collect())
+    }
+    impl_clone_box!(SpValue, MsgT, SpCtrl, SpCtrlAnswer);
+
+    fn is_factor(&self) -> bool {
+        true
+    }
+    fn number_inputs(&self) -> Option<usize> {
+        Some(self.signs.len())
+    }
+    fn initialize(&mut self, connections: Vec<NodeIndex>) -> BPResult<()> {
+        self.connections = Some(connections);
+        Ok(())
+    }
+    fn is_ready(&self, recv_from: &Vec<(NodeIndex, MsgT)>, _current_step: usize) -> BPResult<bool> {
+        Ok(!self.has_propagated || recv_from.len() == self.signs.len())
+    }
+    fn reset(&mut self) -> BPResult<()> {
+        self.connections = None;
+        self.has_propagated = false;
+        Ok(())
+    }
+    fn reset_schedule_state(&mut self) -> BPResult<()> {
+        self.has_propagated = false;
+        Ok(())
+    }
+    fn get_prior(&self) -> Option<MsgT> {
+        None
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SpConfig {
+    //Maximal number of steps before each decimation
+    pub max_steps: usize,
+    //Propagation stops early once no bias changes by more than tolerance
+    pub tolerance: f64,
+    //Number of variables fixed at once
+    pub fix_per_round: usize,
+    //Decimation stops if no free variable has |bias.plus - bias.minus| above this threshold
+    pub paramagnetic_threshold: f64,
+}
+
+impl Default for SpConfig {
+    fn default() -> Self {
+        SpConfig {
+            max_steps: 1000,
+            tolerance: 1e-3,
+            fix_per_round: 1,
+            paramagnetic_threshold: 1e-2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SpOutcome {
+    //A satisfying assignment of all variables
+    Solved(Vec<bool>),
+    //The surveys became trivial before the formula was solved. The partial assignment should be
+    //completed by another solver (e.g., a local search like WalkSAT).
+    Paramagnetic(Vec<Option<bool>>),
+    //Fixing the variables led to a violated clause or contradicting warnings
+    Contradiction(Vec<Option<bool>>),
+}
+
+//A k-SAT formula as a factor graph of SpVariable (node i is variable i) and SpFactor nodes
+pub struct SurveyPropagation<MsgT: Msg<SpValue> = HashMap<SpValue, Probability>> {
+    graph: SpGraph<MsgT>,
+    clauses: Vec<Vec<i64>>,
+    assignment: Vec<Option<bool>>,
+}
+
+impl<MsgT> SurveyPropagation<MsgT>
+where
+    MsgT: Msg<SpValue> + Send + Sync + 'static,
+{
+    //Clauses are given as in DIMACS: variable i (starting at 1) is the literal i, its negation is -i.
+    //The graph is initialized.
+    pub fn new(num_vars: usize, clauses: Vec<Vec<i64>>, seed: u64) -> BPResult<Self> {
+        let mut graph = SpGraph::new();
+        graph.reserve(num_vars + clauses.len());
+        for i in 0..num_vars {
+            graph.add_node(format!("x{}", i + 1), Box::new(SpVariable::<MsgT>::new()))?;
+        }
+        for (c, clause) in clauses.iter().enumerate() {
+            let mut vars: Vec<usize> = Vec::with_capacity(clause.len());
+            for lit in clause {
+                let var = lit.unsigned_abs() as usize;
+                if *lit == 0 || var > num_vars || vars.contains(&(var - 1)) {
+                    return Err(BPError::new(
+                        "SurveyPropagation::new".to_owned(),
+                        format!("Invalid or repeated literal {} in clause {}", lit, c),
+                    ));
+                }
+                vars.push(var - 1);
+            }
+            if vars.is_empty() {
+                return Err(BPError::new(
+                    "SurveyPropagation::new".to_owned(),
+                    format!("Clause {} is empty", c),
+                ));
+            }
+            let signs = clause.iter().map(|lit| *lit > 0).collect();
+            let factor = graph.add_node(
+                format!("c{}", c),
+                Box::new(SpFactor::<MsgT>::new(signs, seed.wrapping_add(c as u64))),
+            )?;
+            for var in vars {
+                graph.add_edge(var, factor)?;
+            }
+        }
+        graph.initialize()?;
+        Ok(SurveyPropagation {
+            graph,
+            clauses,
+            assignment: vec![None; num_vars],
+        })
+    }
+
+    pub fn graph(&self) -> &SpGraph<MsgT> {
+        &self.graph
+    }
+
+    pub fn graph_mut(&mut self) -> &mut SpGraph<MsgT> {
+        &mut self.graph
+    }
+
+    pub fn assignment(&self) -> &[Option<bool>] {
+        &self.assignment
+    }
+
+    //Biases of all variables (None for fixed variables), as of the last propagation
+    pub fn biases(&mut self) -> BPResult<Vec<Option<SpBias>>> {
+        (0..self.assignment.len())
+            .map(|i| {
+                if self.assignment[i].is_some() {
+                    return Ok(None);
+                }
+                match self.graph.send_control_message(i, SpCtrl::GetBias)? {
+                    SpCtrlAnswer::Bias(bias) => Ok(Some(bias)),
+                    SpCtrlAnswer::Done => Err(BPError::new(
+                        "SurveyPropagation::biases".to_owned(),
+                        format!("Node {} is not an SpVariable", i),
+                    )),
+                }
+            })
+            .collect()
+    }
+
+    pub fn fix(&mut self, var: usize, value: bool) -> BPResult<()> {
+        self.graph
+            .send_control_message(var, SpCtrl::Fix(Some(value)))?;
+        self.assignment[var] = Some(value);
+        Ok(())
+    }
+
+    //Propagates (in pairs of steps) until no bias of a free variable changes by more than tolerance.
+    //Returns the number of steps.
+    pub fn converge(&mut self, max_steps: usize, tolerance: f64) -> BPResult<usize> {
+        let mut previous = None;
+        let mut steps = 0;
+        while steps < max_steps {
+            self.graph.propagate(2)?;
+            steps += 2;
+            let current = self.biases()?;
+            let converged = previous
+                .as_ref()
+                .is_some_and(|previous: &Vec<Option<SpBias>>| {
+                    previous
+                        .iter()
+                        .zip(current.iter())
+                        .all(|(prev, cur)| match (prev, cur) {
+                            (Some(p), Some(c)) => {
+                                (p.plus - c.plus).abs() <= tolerance
+                                    && (p.minus - c.minus).abs() <= tolerance
+                                    && (p.zero - c.zero).abs() <= tolerance
+                            }
+                            _ => true,
+                        })
+                });
+            if converged {
+                break;
+            }
+            previous = Some(current);
+        }
+        Ok(steps)
+    }
+
+    fn is_satisfied(&self, clause: &[i64]) -> bool {
+        clause
+            .iter()
+            .any(|lit| self.assignment[lit.unsigned_abs() as usize - 1] == Some(*lit > 0))
+    }
+
+    fn is_violated(&self, clause: &[i64]) -> bool {
+        clause
+            .iter()
+            .all(|lit| self.assignment[lit.unsigned_abs() as usize - 1] == Some(*lit < 0))
+    }
+
+    //Runs SP until convergence, fixes the most biased free variables (largest |plus - minus|) to the favored
+    //value and repeats until all variables are fixed, the surveys are trivial or a contradiction arises.
+    pub fn sp_decimation(&mut self, config: &SpConfig) -> BPResult<SpOutcome> {
+        let mut fixed_any = false;
+        loop {
+            if self.clauses.iter().any(|c| self.is_violated(c)) {
+                return Ok(SpOutcome::Contradiction(self.assignment.clone()));
+            }
+            if self.clauses.iter().all(|c| self.is_satisfied(c)) {
+                //Free variables do not matter
+                return Ok(SpOutcome::Solved(
+                    self.assignment.iter().map(|v| v.unwrap_or(false)).collect(),
+                ));
+            }
+            match self.converge(config.max_steps, config.tolerance) {
+                Ok(_) => {}
+                //Messages that cannot be normalized come from contradicting warnings
+                Err(_) if fixed_any => {
+                    return Ok(SpOutcome::Contradiction(self.assignment.clone()))
+                }
+                Err(e) => return Err(e),
+            }
+            let biases = self.biases()?;
+            if biases
+                .iter()
+                .flatten()
+                .any(|b| b.plus + b.minus + b.zero == 0.0)
+            {
+                return Ok(SpOutcome::Contradiction(self.assignment.clone()));
+            }
+            let mut candidates: Vec<(usize, Probability)> = biases
+                .iter()
+                .enumerate()
+                .filter_map(|(i, b)| b.map(|b| (i, b.plus - b.minus)))
+                .filter(|(_, d)| d.abs() > config.paramagnetic_threshold)
+                .collect();
+            if candidates.is_empty() {
+                return Ok(SpOutcome::Paramagnetic(self.assignment.clone()));
+            }
+            candidates.sort_by(|(i0, d0), (i1, d1)| {
+                d1.abs()
+                    .partial_cmp(&d0.abs())
+                    .unwrap_or(std::cmp::Ordering::Equal)
+                    .then_with(|| i0.cmp(i1))
+            });
+            for (var, d) in candidates.into_iter().take(config.fix_per_round.max(1)) {
+                self.fix(var, d > 0.0)?;
+            }
+            fixed_any = true;
+        }
+    }
+}
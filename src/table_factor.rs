@@ -0,0 +1,169 @@
+use crate::semiring;
+use crate::{BPError, BPResult, Msg, NodeFunction, NodeIndex, Probability, Semiring};
+use std::sync::Arc;
+
+//Factor over variables with the values 0..cardinality given by a dense table of its potential. The entries are
+//in the order of libDAI (see fg): the value of the first connection changes fastest, i.e., the entry of
+//(x0, ..., x_n-1) is table[x0 + c0 * (x1 + c1 * (x2 + ...))] for the cardinalities c0, ..., c_n-1.
+//A step costs the size of the table times arity^2. Values missing in an incoming message count as zero.
+#[derive(Clone)]
+pub struct TableFactor<MsgT> {
+    cardinalities: Vec<usize>,
+    table: Vec<Probability>,
+    connections: Option<Vec<NodeIndex>>,
+    semiring: Option<Arc<dyn Semiring>>,
+    phantom: std::marker::PhantomData<MsgT>,
+}
+
+impl<MsgT> TableFactor<MsgT> {
+    pub fn new(cardinalities: Vec<usize>, table: Vec<Probability>) -> BPResult<Self> {
+        let size: usize = cardinalities.iter().product();
+        if table.len() != size {
+            return Err(BPError::new(
+                "TableFactor::new".to_owned(),
+                format!(
+                    "Table has {} entries, the cardinalities {:?} need {}",
+                    table.len(),
+                    cardinalities,
+                    size
+                ),
+            ));
+        }
+        Ok(TableFactor {
+            cardinalities,
+            table,
+            connections: None,
+            semiring: None,
+            phantom: std::marker::PhantomData,
+        })
+    }
+    pub fn cardinalities(&self) -> &[usize] {
+        &self.cardinalities
+    }
+    pub fn table(&self) -> &[Probability] {
+        &self.table
+    }
+    //Position of an assignment in the table
+    pub fn index(&self, values: &[usize]) -> Option<usize> {
+        if values.len() != self.cardinalities.len() {
+            return None;
+        }
+        let mut index = 0;
+        for (v, c) in values.iter().zip(self.cardinalities.iter()).rev() {
+            if v >= c {
+                return None;
+            }
+            index = index * c + v;
+        }
+        Some(index)
+    }
+}
+
+impl<MsgT: Msg<usize>, CtrlMsgT, CtrlMsgAT: Default> NodeFunction<usize, MsgT, CtrlMsgT, CtrlMsgAT>
+    for TableFactor<MsgT>
+where
+    MsgT: Clone,
+{
+    fn node_function(&mut self, inbox: Vec<(NodeIndex, MsgT)>) -> BPResult<Vec<(NodeIndex, MsgT)>> {
+        let connections = self.connections.as_ref().ok_or_else(|| {
+            BPError::new(
+                "TableFactor::node_function".to_owned(),
+                "TableFactor not initialized".to_owned(),
+            )
+        })?;
+        let n = self.cardinalities.len();
+        if inbox.len() != n {
+            return Err(BPError::new(
+                "TableFactor::node_function".to_owned(),
+                format!("Wrong number of messages ({}, needed: {})", inbox.len(), n),
+            ));
+        }
+        let s = self.semiring.as_deref();
+        let zero = semiring::zero(s);
+        //Incoming messages in the order of the connections
+        let mut msgs: Vec<Vec<Probability>> = vec![Vec::new(); n];
+        for (from, msg) in inbox {
+            let pos = connections
+                .iter()
+                .position(|con| *con == from)
+                .ok_or_else(|| {
+                    BPError::new(
+                        "TableFactor::node_function".to_owned(),
+                        format!("Received message from unknown node {}", from),
+                    )
+                })?;
+            msgs[pos] = (0..self.cardinalities[pos])
+                .map(|v| msg.get(v).unwrap_or(zero))
+                .collect();
+        }
+        let mut out: Vec<Vec<Probability>> =
+            self.cardinalities.iter().map(|c| vec![zero; *c]).collect();
+        let mut values = vec![0usize; n];
+        for psi in &self.table {
+            if *psi != zero {
+                for (i, out_i) in out.iter_mut().enumerate() {
+                    let p = (0..n)
+                        .filter(|j| *j != i)
+                        .fold(*psi, |p, j| semiring::times(s, p, msgs[j][values[j]]));
+                    out_i[values[i]] = semiring::plus(s, out_i[values[i]], p);
+                }
+            }
+            for (v, c) in values.iter_mut().zip(self.cardinalities.iter()) {
+                *v += 1;
+                if *v < *c {
+                    break;
+                }
+                *v = 0;
+            }
+        }
+        Ok(connections
+            .iter()
+            .copied()
+            .zip(out.into_iter().map(|probabilities| {
+                let mut msg = MsgT::new();
+                for (v, p) in probabilities.into_iter().enumerate() {
+                    msg.insert(v, p);
+                }
+                msg
+            }))
+            .collect())
+    }
+    impl_clone_box!(usize, MsgT, CtrlMsgT, CtrlMsgAT);
+
+    fn is_factor(&self) -> bool {
+        true
+    }
+    fn number_inputs(&self) -> Option<usize> {
+        Some(self.cardinalities.len())
+    }
+    fn initialize(&mut self, connections: Vec<NodeIndex>) -> BPResult<()> {
+        if connections.len() != self.cardinalities.len() {
+            return Err(BPError::new(
+                "TableFactor::initialize".to_owned(),
+                format!(
+                    "TableFactor needs exactly {} connections, got {}",
+                    self.cardinalities.len(),
+                    connections.len()
+                ),
+            ));
+        }
+        self.connections = Some(connections);
+        Ok(())
+    }
+    fn is_ready(&self, recv_from: &Vec<(NodeIndex, MsgT)>, _current_step: usize) -> BPResult<bool> {
+        Ok(recv_from.len() == self.cardinalities.len())
+    }
+    fn reset(&mut self) -> BPResult<()> {
+        self.connections = None;
+        Ok(())
+    }
+    fn get_prior(&self) -> Option<MsgT> {
+        None
+    }
+    fn set_semiring(&mut self, semiring: Arc<dyn Semiring>) {
+        self.semiring = Some(semiring);
+    }
+    fn potential(&self, values: &[usize]) -> Option<Probability> {
+        self.index(values).map(|index| self.table[index])
+    }
+}
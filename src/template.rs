@@ -0,0 +1,201 @@
+use crate::{BPError, BPGraph, BPResult, Msg, Node, NodeFunction, NodeIndex};
+use std::fmt::Debug;
+use std::ops::Range;
+
+type NodeFactory<T, MsgT, CtrlMsgT, CtrlMsgAT> =
+    dyn Fn(usize) -> Box<dyn NodeFunction<T, MsgT, CtrlMsgT, CtrlMsgAT> + Send + Sync>;
+
+//A substructure (nodes and the edges between them) that can be replicated with BPGraph::instantiate.
+//Nodes are given by factories that are called with the number of the instance, so that, e.g., every
+//instance can get its own prior. Nodes are referred to by their local index (as returned by add_node).
+#[allow(clippy::type_complexity)]
+pub struct Template<T, MsgT: Msg<T>, CtrlMsgT = (), CtrlMsgAT = ()> {
+    nodes: Vec<(String, Box<NodeFactory<T, MsgT, CtrlMsgT, CtrlMsgAT>>)>,
+    edges: Vec<(usize, usize)>,
+}
+
+impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT> Template<T, MsgT, CtrlMsgT, CtrlMsgAT> {
+    pub fn new() -> Self {
+        Template {
+            nodes: Vec::new(),
+            edges: Vec::new(),
+        }
+    }
+
+    //The instances are named name_i
+    pub fn add_node<F>(&mut self, name: String, factory: F) -> usize
+    where
+        F: Fn(usize) -> Box<dyn NodeFunction<T, MsgT, CtrlMsgT, CtrlMsgAT> + Send + Sync> + 'static,
+    {
+        self.nodes.push((name, Box::new(factory)));
+        self.nodes.len() - 1
+    }
+
+    pub fn add_edge(&mut self, node0: usize, node1: usize) -> BPResult<()> {
+        self.check_index(node0, "Template::add_edge")?;
+        self.check_index(node1, "Template::add_edge")?;
+        if self
+            .edges
+            .iter()
+            .any(|e| *e == (node0, node1) || *e == (node1, node0))
+        {
+            return Err(BPError::new(
+                "Template::add_edge".to_owned(),
+                format!("Edge ({}, {}) already exists", node0, node1),
+            ));
+        }
+        self.edges.push((node0, node1));
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    fn check_index(&self, node: usize, fn_name: &str) -> BPResult<()> {
+        if node >= self.nodes.len() {
+            return Err(BPError::new(
+                fn_name.to_owned(),
+                format!(
+                    "Template node {} out of bounds ({})",
+                    node,
+                    self.nodes.len()
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT> Default for Template<T, MsgT, CtrlMsgT, CtrlMsgAT> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+//The nodes created by BPGraph::instantiate. Instances occupy contiguous ranges of node indices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Plate {
+    start: NodeIndex,
+    size: usize,
+    count: usize,
+}
+
+impl Plate {
+    //Graph index of the local node of instance
+    pub fn get(&self, instance: usize, node: usize) -> Option<NodeIndex> {
+        if instance < self.count && node < self.size {
+            Some(self.start + instance * self.size + node)
+        } else {
+            None
+        }
+    }
+
+    //Graph indices of all nodes of instance, in the order of the template
+    pub fn instance(&self, instance: usize) -> Range<NodeIndex> {
+        let start = self.start + instance.min(self.count) * self.size;
+        start..start + if instance < self.count { self.size } else { 0 }
+    }
+
+    //Graph indices of the local node in all instances
+    pub fn node(&self, node: usize) -> impl Iterator<Item = NodeIndex> + '_ {
+        (0..self.count).filter_map(move |i| self.get(i, node))
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    //All created nodes
+    pub fn range(&self) -> Range<NodeIndex> {
+        self.start..self.start + self.count * self.size
+    }
+}
+
+impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default> BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>
+where
+    T: Debug,
+{
+    //Adds count copies of template. The local nodes in external_bindings ((local node, graph node))
+    //are connected to the given existing nodes in every instance.
+    //The structure is checked before anything is added; only failures of NodeFunction (e.g., too many
+    //connections at an external node) can leave a part of the instances in the graph.
+    pub fn instantiate(
+        &mut self,
+        template: &Template<T, MsgT, CtrlMsgT, CtrlMsgAT>,
+        count: usize,
+        external_bindings: &[(usize, NodeIndex)],
+    ) -> BPResult<Plate> {
+        self.check_unsealed("BPGraph::instantiate")?;
+        let start = self.len();
+        let size = template.len();
+        for (local, external) in external_bindings {
+            template.check_index(*local, "BPGraph::instantiate")?;
+            self.get_node(*external)?;
+        }
+        for (k, binding) in external_bindings.iter().enumerate() {
+            if external_bindings[..k].contains(binding) {
+                return Err(BPError::new(
+                    "BPGraph::instantiate".to_owned(),
+                    format!("Binding {:?} occurs more than once", binding),
+                ));
+            }
+        }
+        if count == 0 {
+            return Ok(Plate { start, size, count });
+        }
+        //The first instance is built before it is added to check the types of all edges
+        let first: Vec<Node<T, MsgT, CtrlMsgT, CtrlMsgAT>> = template
+            .nodes
+            .iter()
+            .map(|(name, factory)| Node::new(format!("{}_0", name), factory(0)))
+            .collect();
+        for (n0, n1) in &template.edges {
+            if first[*n0].is_factor() == first[*n1].is_factor() {
+                return Err(BPError::new(
+                    "BPGraph::instantiate".to_owned(),
+                    format!(
+                        "Cannot link template nodes of same type (variable/factor) ({}, {})",
+                        n0, n1
+                    ),
+                ));
+            }
+        }
+        for (local, external) in external_bindings {
+            if first[*local].is_factor() == self.get_node(*external)?.is_factor() {
+                return Err(BPError::new(
+                    "BPGraph::instantiate".to_owned(),
+                    format!(
+                        "Cannot link template node {} and node {} of same type (variable/factor)",
+                        local, external
+                    ),
+                ));
+            }
+        }
+
+        self.reserve(count * size);
+        for node in first {
+            self.add_node_directly(node)?;
+        }
+        for i in 1..count {
+            for (name, factory) in &template.nodes {
+                self.add_node(format!("{}_{}", name, i), factory(i))?;
+            }
+        }
+        let plate = Plate { start, size, count };
+        for i in 0..count {
+            let offset = start + i * size;
+            for (n0, n1) in &template.edges {
+                self.add_edge(offset + n0, offset + n1)?;
+            }
+            for (local, external) in external_bindings {
+                self.add_edge(offset + local, *external)?;
+            }
+        }
+        Ok(plate)
+    }
+}
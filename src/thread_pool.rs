@@ -0,0 +1,173 @@
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use std::any::Any;
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+
+type Task = &'static (dyn Fn(u32) + Sync);
+
+struct Job {
+    task: Task,
+    index: u32,
+    done: Sender<Result<(), Box<dyn Any + Send>>>,
+}
+
+//How the threaded propagation distributes the ready nodes of a step over the threads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchStrategy {
+    //Threads take batches of remaining / (2 * threads) nodes from a shared queue
+    Guided,
+    //The nodes are split into batches of len / (8 * threads) nodes, every thread starts with an equal share
+    //in its own deque. Idle threads steal half of the remaining batches of another thread, so a single
+    //expensive node only delays the other nodes of its batch.
+    WorkStealing,
+}
+
+//Set by BPGraph::set_threading_config. Batch sizes are clamped to [min_batch, max_batch],
+//the cancel flag of propagate_threaded_cancellable is checked between batches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThreadingConfig {
+    pub min_batch: usize,
+    pub max_batch: usize,
+    pub strategy: BatchStrategy,
+}
+
+impl Default for ThreadingConfig {
+    fn default() -> Self {
+        ThreadingConfig {
+            min_batch: 5,
+            max_batch: usize::MAX,
+            strategy: BatchStrategy::Guided,
+        }
+    }
+}
+
+impl ThreadingConfig {
+    //Size of the next batch with remaining nodes left in a step on thread_count threads
+    pub(crate) fn batch_size(&self, remaining: usize, thread_count: u32) -> usize {
+        let divisor = match self.strategy {
+            BatchStrategy::Guided => 2,
+            BatchStrategy::WorkStealing => 8,
+        };
+        (remaining / (divisor * thread_count.max(1) as usize)).clamp(self.min_batch, self.max_batch)
+    }
+}
+
+//Persistent worker threads that can be reused by the threaded propagation of BPGraph
+//(see BPGraph::set_thread_pool) instead of spawning new threads in every step.
+//The threads are joined when the pool is dropped.
+pub struct ThreadPool {
+    jobs: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl ThreadPool {
+    pub fn new(thread_count: u32) -> Self {
+        let (jobs, receiver): (Sender<Job>, Receiver<Job>) = unbounded();
+        let workers = (0..thread_count.max(1))
+            .map(|_| {
+                let receiver = receiver.clone();
+                std::thread::spawn(move || {
+                    for job in receiver.iter() {
+                        let task = job.task;
+                        let index = job.index;
+                        let res = catch_unwind(AssertUnwindSafe(|| task(index)));
+                        //The caller waits for every job, so it cannot have gone away
+                        let _ = job.done.send(res);
+                    }
+                })
+            })
+            .collect();
+        ThreadPool {
+            jobs: Some(jobs),
+            workers,
+        }
+    }
+
+    pub fn thread_count(&self) -> usize {
+        self.workers.len()
+    }
+
+    //Runs f(i) for i in 0..job_count on the workers and blocks until all calls have returned.
+    //Panics of f are propagated after all calls have returned.
+    pub fn run<F>(&self, job_count: u32, f: &F)
+    where
+        F: Fn(u32) + Sync,
+    {
+        let task: &(dyn Fn(u32) + Sync) = f;
+        //SAFETY: The reference only has to live until every job has been run, and this function
+        //does not return (or unwind) before all jobs have reported back. Panics in f are caught by the workers.
+        let task: Task = unsafe { std::mem::transmute::<&(dyn Fn(u32) + Sync), Task>(task) };
+        let (done, finished) = unbounded();
+        let jobs = self.jobs.as_ref().expect("Thread pool has been shut down");
+        for index in 0..job_count {
+            jobs.send(Job {
+                task,
+                index,
+                done: done.clone(),
+            })
+            .expect("Thread pool workers have stopped");
+        }
+        drop(done);
+        let mut panic = None;
+        for _ in 0..job_count {
+            match finished.recv() {
+                Ok(Ok(())) => {}
+                Ok(Err(payload)) => panic = Some(payload),
+                //Only possible if a worker died, which would leave f in use; abort instead of returning
+                Err(_) => std::process::abort(),
+            }
+        }
+        if let Some(payload) = panic {
+            resume_unwind(payload);
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        //Closing the channel stops the workers
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+//Runs f(i) for i in 0..thread_count on pool if given, otherwise on new scoped threads.
+//Returns the results in the order of i.
+pub(crate) fn run_on_threads<R, F>(pool: Option<&ThreadPool>, thread_count: u32, f: F) -> Vec<R>
+where
+    R: Send,
+    F: Fn(u32) -> R + Sync,
+{
+    match pool {
+        Some(pool) => {
+            let results: Vec<Mutex<Option<R>>> =
+                (0..thread_count).map(|_| Mutex::new(None)).collect();
+            pool.run(thread_count, &|i| {
+                let res = f(i);
+                *results[i as usize].lock().expect("Locking mutex failed.") = Some(res);
+            });
+            results
+                .into_iter()
+                .map(|r| {
+                    r.into_inner()
+                        .expect("Locking mutex failed.")
+                        .expect("Job has not been run")
+                })
+                .collect()
+        }
+        None => crossbeam::scope(|scope| {
+            let f = &f;
+            let handles: Vec<_> = (0..thread_count)
+                .map(|i| scope.spawn(move |_| f(i)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("Joining threads failed"))
+                .collect()
+        })
+        .expect("Scoped threading failed"),
+    }
+}
@@ -0,0 +1,321 @@
+use crate::junction_tree::{variable_priors, Table};
+use crate::{BPError, BPGraph, BPResult, Msg, NodeIndex, Probability};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+
+/*
+Tree-reweighted belief propagation (Wainwright, Jaakkola, Willsky).
+
+Every factor f gets an appearance probability rho_f (see BPGraph::set_edge_weights), i.e., the probability
+that f is part of a spanning tree drawn from some distribution over spanning trees. The updates are
+    var -> factor:  n_vf(x) = prior_v(x) * prod_g m_gv(x)^rho_g / m_fv(x)
+    factor -> var:  m_fv(x) = sum_{x_f, x_v = x} psi_f(x_f)^(1/rho_f) * prod_{u != v} n_uf(x_u)
+and the beliefs are b_v = prior_v * prod_g m_gv^rho_g. With all weights set to 1, this is plain loopy BP.
+If the weights are valid (in the spanning tree polytope), the fixed point yields an upper bound on log Z.
+
+As for the junction tree, factor potentials are taken from NodeFunction::potential and the priors of the
+variable nodes define their domains.
+*/
+
+struct Factor {
+    node: NodeIndex,
+    rho: f64,
+    //psi_f
+    potential: Table,
+    //psi_f^(1/rho_f)
+    powered: Vec<Probability>,
+}
+
+pub struct TreeReweighted<T> {
+    //Graph node index of each variable
+    variables: Vec<NodeIndex>,
+    domains: Vec<Vec<T>>,
+    priors: Vec<Vec<Probability>>,
+    factors: Vec<Factor>,
+    //(factor, position in the scope of the factor) for every variable
+    neighbours: Vec<Vec<(usize, usize)>>,
+    //Factor to variable messages, in the order of the scope of the factor
+    msgs: Vec<Vec<Vec<Probability>>>,
+}
+
+fn normalized(mut values: Vec<Probability>) -> Vec<Probability> {
+    let sum: Probability = values.iter().sum();
+    if sum > 0.0 {
+        values.iter_mut().for_each(|p| *p /= sum);
+    }
+    values
+}
+
+fn entropy(values: &[Probability]) -> f64 {
+    -values
+        .iter()
+        .filter(|p| **p > 0.0)
+        .map(|p| p * p.ln())
+        .sum::<f64>()
+}
+
+impl<T> TreeReweighted<T>
+where
+    T: Copy + Eq + Hash + Debug,
+{
+    //Fails if the table of a factor would have more than max_table_size entries.
+    pub fn new<MsgT: Msg<T> + Clone, CtrlMsgT, CtrlMsgAT: Default>(
+        graph: &BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>,
+        max_table_size: usize,
+    ) -> BPResult<Self> {
+        let (variables, priors) = variable_priors(graph, "TreeReweighted::new")?;
+        let var_id: HashMap<NodeIndex, usize> = variables
+            .iter()
+            .enumerate()
+            .map(|(i, idx)| (*idx, i))
+            .collect();
+        let domains: Vec<Vec<T>> = priors
+            .iter()
+            .map(|prior| prior.iter().map(|(v, _)| *v).collect())
+            .collect();
+        let domain_sizes: Vec<usize> = domains.iter().map(|d| d.len()).collect();
+        let priors: Vec<Vec<Probability>> = priors
+            .into_iter()
+            .map(|prior| prior.into_iter().map(|(_, p)| p).collect())
+            .collect();
+
+        let mut factors = Vec::new();
+        let mut neighbours = vec![Vec::new(); variables.len()];
+        for idx in 0..graph.len() {
+            let node = graph.get_node(idx)?;
+            if !node.is_factor() {
+                continue;
+            }
+            let scope = node
+                .get_connections()
+                .iter()
+                .map(|con| {
+                    var_id.get(con).copied().ok_or_else(|| {
+                        BPError::new(
+                            "TreeReweighted::new".to_owned(),
+                            format!("Factor {} is connected to unknown variable {}", idx, con),
+                        )
+                    })
+                })
+                .collect::<BPResult<Vec<usize>>>()?;
+            scope
+                .iter()
+                .try_fold(1usize, |acc, v| acc.checked_mul(domain_sizes[*v]))
+                .filter(|n| *n <= max_table_size)
+                .ok_or_else(|| {
+                    BPError::new(
+                        "TreeReweighted::new".to_owned(),
+                        format!(
+                            "Table of factor {} is too large (maximum: {})",
+                            idx, max_table_size
+                        ),
+                    )
+                })?;
+            for (k, v) in scope.iter().enumerate() {
+                neighbours[*v].push((factors.len(), k));
+            }
+            let mut potential = Table::ones(scope, &domain_sizes);
+            let mut values = Vec::with_capacity(potential.vars.len());
+            for i in 0..potential.values.len() {
+                let assignment = potential.assignment(i);
+                values.clear();
+                values.extend(
+                    potential
+                        .vars
+                        .iter()
+                        .zip(assignment)
+                        .map(|(v, a)| domains[*v][a]),
+                );
+                potential.values[i] = node.potential(&values).ok_or_else(|| {
+                    BPError::new(
+                        "TreeReweighted::new".to_owned(),
+                        format!(
+                            "Factor {} ({}) does not implement potential",
+                            idx,
+                            node.get_name()
+                        ),
+                    )
+                })?;
+            }
+            let rho = graph.get_edge_weight(idx);
+            let powered = potential.values.iter().map(|p| p.powf(1.0 / rho)).collect();
+            factors.push(Factor {
+                node: idx,
+                rho,
+                potential,
+                powered,
+            });
+        }
+        let msgs = factors
+            .iter()
+            .map(|f| {
+                f.potential
+                    .dims
+                    .iter()
+                    .map(|d| vec![1.0 / *d as Probability; *d])
+                    .collect()
+            })
+            .collect();
+        Ok(TreeReweighted {
+            variables,
+            domains,
+            priors,
+            factors,
+            neighbours,
+            msgs,
+        })
+    }
+
+    //prior_v * prod_g m_gv^rho_g (unnormalized)
+    fn full_belief(&self, v: usize) -> Vec<Probability> {
+        let mut belief = self.priors[v].clone();
+        for (f, k) in &self.neighbours[v] {
+            let rho = self.factors[*f].rho;
+            for (b, m) in belief.iter_mut().zip(self.msgs[*f][*k].iter()) {
+                *b *= m.powf(rho);
+            }
+        }
+        belief
+    }
+
+    //Variable to factor messages of all factors, in the order of their scopes
+    fn variable_msgs(&self) -> Vec<Vec<Vec<Probability>>> {
+        let beliefs: Vec<Vec<Probability>> = (0..self.variables.len())
+            .map(|v| self.full_belief(v))
+            .collect();
+        self.factors
+            .iter()
+            .enumerate()
+            .map(|(f, factor)| {
+                factor
+                    .potential
+                    .vars
+                    .iter()
+                    .zip(self.msgs[f].iter())
+                    .map(|(v, m)| {
+                        normalized(
+                            beliefs[*v]
+                                .iter()
+                                .zip(m.iter())
+                                .map(|(b, m)| if *m > 0.0 { b / m } else { 0.0 })
+                                .collect(),
+                        )
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    //psi_f^(1/rho_f) * prod_v n_vf (unnormalized), over the table of the factor
+    fn factor_table(&self, f: usize, incoming: &[Vec<Probability>]) -> Vec<Probability> {
+        let factor = &self.factors[f];
+        (0..factor.powered.len())
+            .map(|i| {
+                factor
+                    .potential
+                    .assignment(i)
+                    .iter()
+                    .zip(incoming.iter())
+                    .fold(factor.powered[i], |acc, (a, n)| acc * n[*a])
+            })
+            .collect()
+    }
+
+    //One synchronous update of all messages, returns the maximal absolute change of a message
+    pub fn step(&mut self) -> f64 {
+        let incoming = self.variable_msgs();
+        let mut max_change: f64 = 0.0;
+        for (f, factor) in self.factors.iter().enumerate() {
+            let dims = &factor.potential.dims;
+            let mut new_msgs: Vec<Vec<Probability>> = dims.iter().map(|d| vec![0.0; *d]).collect();
+            for i in 0..factor.powered.len() {
+                let assignment = factor.potential.assignment(i);
+                for k in 0..dims.len() {
+                    let p = assignment
+                        .iter()
+                        .zip(incoming[f].iter())
+                        .enumerate()
+                        .filter(|(j, _)| *j != k)
+                        .fold(factor.powered[i], |acc, (_, (a, n))| acc * n[*a]);
+                    new_msgs[k][assignment[k]] += p;
+                }
+            }
+            for (old, new) in self.msgs[f].iter_mut().zip(new_msgs) {
+                let new = normalized(new);
+                for (o, n) in old.iter().zip(new.iter()) {
+                    max_change = max_change.max((o - n).abs());
+                }
+                *old = new;
+            }
+        }
+        max_change
+    }
+
+    //Runs at most max_steps steps until the messages change by at most tolerance, returns the number of steps
+    pub fn propagate(&mut self, max_steps: usize, tolerance: f64) -> usize {
+        for steps in 0..max_steps {
+            if self.step() <= tolerance {
+                return steps + 1;
+            }
+        }
+        max_steps
+    }
+
+    //Belief (summing to one) of a variable node
+    pub fn marginal(&self, node_index: NodeIndex) -> Option<HashMap<T, Probability>> {
+        let v = self.variables.iter().position(|idx| *idx == node_index)?;
+        Some(
+            self.domains[v]
+                .iter()
+                .copied()
+                .zip(normalized(self.full_belief(v)))
+                .collect(),
+        )
+    }
+
+    pub fn marginals(&self) -> HashMap<NodeIndex, HashMap<T, Probability>> {
+        self.variables
+            .iter()
+            .filter_map(|idx| self.marginal(*idx).map(|m| (*idx, m)))
+            .collect()
+    }
+
+    //The TRW upper bound on the log partition function evaluated at the current beliefs:
+    //sum_f E_bf[ln psi_f] + sum_v E_bv[ln prior_v] + sum_v H(b_v) - sum_f rho_f * (sum_{v in f} H(b_v) - H(b_f)).
+    //Only an upper bound at a fixed point and for valid weights.
+    pub fn log_partition_upper_bound(&self) -> f64 {
+        let beliefs: Vec<Vec<Probability>> = (0..self.variables.len())
+            .map(|v| normalized(self.full_belief(v)))
+            .collect();
+        let entropies: Vec<f64> = beliefs.iter().map(|b| entropy(b)).collect();
+        let mut bound = 0.0;
+        for (v, b) in beliefs.iter().enumerate() {
+            bound += entropies[v];
+            bound += b
+                .iter()
+                .zip(self.priors[v].iter())
+                .filter(|(b, _)| **b > 0.0)
+                .map(|(b, p)| b * p.ln())
+                .sum::<f64>();
+        }
+        let incoming = self.variable_msgs();
+        for (f, factor) in self.factors.iter().enumerate() {
+            let belief = normalized(self.factor_table(f, &incoming[f]));
+            bound += belief
+                .iter()
+                .zip(factor.potential.values.iter())
+                .filter(|(b, _)| **b > 0.0)
+                .map(|(b, psi)| b * psi.ln())
+                .sum::<f64>();
+            let sum_entropies: f64 = factor.potential.vars.iter().map(|v| entropies[*v]).sum();
+            bound -= factor.rho * (sum_entropies - entropy(&belief));
+        }
+        bound
+    }
+
+    //Graph node indices of the factors and their weights
+    pub fn weights(&self) -> Vec<(NodeIndex, f64)> {
+        self.factors.iter().map(|f| (f.node, f.rho)).collect()
+    }
+}
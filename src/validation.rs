@@ -0,0 +1,121 @@
+use crate::NodeIndex;
+
+//Problems found by BPGraph::validate.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ValidationIssue {
+    NodeNotFound {
+        node: NodeIndex,
+    },
+    IsolatedNode {
+        node: NodeIndex,
+        name: String,
+    },
+    WrongArity {
+        node: NodeIndex,
+        name: String,
+        expected: usize,
+        got: usize,
+    },
+    DanglingEdge {
+        node: NodeIndex,
+        name: String,
+        to: NodeIndex,
+    },
+    DuplicateEdge {
+        node: NodeIndex,
+        name: String,
+        to: NodeIndex,
+    },
+    //node has to as connection but not vice versa
+    AsymmetricEdge {
+        node: NodeIndex,
+        name: String,
+        to: NodeIndex,
+        to_name: String,
+    },
+    //A port declared by the node function is not bound to a connection (see BPGraph::add_edge_port)
+    UnboundPort {
+        node: NodeIndex,
+        name: String,
+        port: String,
+    },
+    //Both nodes are variables or both are factors; node0 < node1
+    SameTypeEdge {
+        node0: NodeIndex,
+        name0: String,
+        node1: NodeIndex,
+        name1: String,
+    },
+}
+
+impl ValidationIssue {
+    //The node the issue has been found at
+    pub fn node(&self) -> NodeIndex {
+        match self {
+            ValidationIssue::NodeNotFound { node }
+            | ValidationIssue::IsolatedNode { node, .. }
+            | ValidationIssue::WrongArity { node, .. }
+            | ValidationIssue::DanglingEdge { node, .. }
+            | ValidationIssue::DuplicateEdge { node, .. }
+            | ValidationIssue::AsymmetricEdge { node, .. }
+            | ValidationIssue::UnboundPort { node, .. } => *node,
+            ValidationIssue::SameTypeEdge { node0, .. } => *node0,
+        }
+    }
+}
+
+impl std::fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ValidationIssue::NodeNotFound { node } => write!(f, "Could not find node {}", node),
+            ValidationIssue::IsolatedNode { node, name } => {
+                write!(f, "Node {} ({}) has no edges", node, name)
+            }
+            ValidationIssue::WrongArity {
+                node,
+                name,
+                expected,
+                got,
+            } => write!(
+                f,
+                "Node {} ({}) has a wrong number ({}) of inputs (should be: {})",
+                node, name, got, expected
+            ),
+            ValidationIssue::DanglingEdge { node, name, to } => write!(
+                f,
+                "Could not find node {} in connections of {} ({})",
+                to, node, name
+            ),
+            ValidationIssue::DuplicateEdge { node, name, to } => write!(
+                f,
+                "Node {} ({}) has {} more than once as connection",
+                node, name, to
+            ),
+            ValidationIssue::AsymmetricEdge {
+                node,
+                name,
+                to,
+                to_name,
+            } => write!(
+                f,
+                "{} ({}) does not have {} ({}) as connection but {} has {} as connection",
+                to, to_name, node, name, node, to
+            ),
+            ValidationIssue::UnboundPort { node, name, port } => write!(
+                f,
+                "Port {} of node {} ({}) is not bound to a connection",
+                port, node, name
+            ),
+            ValidationIssue::SameTypeEdge {
+                node0,
+                name0,
+                node1,
+                name1,
+            } => write!(
+                f,
+                "Nodes {} ({}) and {} ({}) are of the same type (variable/factor) but connected",
+                node0, name0, node1, name1
+            ),
+        }
+    }
+}
@@ -1,9 +1,12 @@
-use crate::{BPError, BPResult, Msg, NodeFunction, NodeIndex, Probability};
+use crate::semiring;
+use crate::{BPError, BPResult, Msg, MsgPool, NodeFunction, NodeIndex, Probability, Semiring};
+use std::any::Any;
 use std::cmp::Eq;
 use std::fmt::Debug;
 use std::hash::Hash;
+use std::sync::Arc;
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum InputNeed {
     AlwaysExceptFirst,
     Always,
@@ -11,19 +14,158 @@ pub enum InputNeed {
     Never,
 }
 
-#[derive(Clone)]
+//Decides when a VariableNode is ready, replacing its InputNeed (see VariableNode::set_ready_policy).
+//received is the number of messages in the inbox, total the number of connections.
+//Nodes without prior are never ready without messages, regardless of the policy.
+pub trait ReadyPolicy: Send + Sync {
+    fn ready(&self, received: usize, total: usize, step: usize) -> bool;
+}
+
+impl<F> ReadyPolicy for F
+where
+    F: Fn(usize, usize, usize) -> bool + Send + Sync,
+{
+    fn ready(&self, received: usize, total: usize, step: usize) -> bool {
+        self(received, total, step)
+    }
+}
+
+//How the priors added by VariableNode::add_prior are combined
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum PriorCombination {
+    //Product of the priors, each raised to the power of its weight
+    #[default]
+    Product,
+    //Mixture of the (normalized) priors, weighted by their relative weights
+    Mixture,
+}
+
+//Control messages understood by VariableNode (see NodeFunction::send_control_message)
+#[derive(Clone, Debug)]
+pub enum VariableNodeCtrl<MsgT> {
+    GetPrior,
+    //Replaces all priors (None removes them), checked as by VariableNode::set_prior
+    SetPrior(Option<MsgT>),
+    SetInputNeed(InputNeed),
+    SetSendToAll(bool),
+    SetThreaded(bool),
+    HasPropagated,
+}
+
+#[derive(Clone, Debug)]
+pub enum VariableNodeCtrlAnswer<MsgT> {
+    Done,
+    Prior(Option<MsgT>),
+    HasPropagated(bool),
+}
+
+//Not derived, as that would require MsgT: Default
+#[allow(clippy::derivable_impls)]
+impl<MsgT> Default for VariableNodeCtrlAnswer<MsgT> {
+    fn default() -> Self {
+        VariableNodeCtrlAnswer::Done
+    }
+}
+
+//Control message types a VariableNode can be used with.
+//Implement this for a custom control message enum to forward the VariableNode protocol.
+//is_variable_node_ctrl decides whether a VariableNode accepts a message (e.g., for broadcasts), it has to be
+//true exactly for the messages into_variable_node_ctrl converts. Messages accepted but not converted are
+//rejected with an error.
+pub trait IntoVariableNodeCtrl<MsgT> {
+    fn into_variable_node_ctrl(self) -> Option<VariableNodeCtrl<MsgT>>;
+    fn is_variable_node_ctrl(&self) -> bool;
+    //True for the messages converted to queries (GetPrior, HasPropagated), which do not change the node
+    fn is_variable_node_query(&self) -> bool {
+        false
+    }
+}
+
+impl<MsgT> IntoVariableNodeCtrl<MsgT> for () {
+    fn into_variable_node_ctrl(self) -> Option<VariableNodeCtrl<MsgT>> {
+        None
+    }
+    fn is_variable_node_ctrl(&self) -> bool {
+        false
+    }
+}
+
+impl<MsgT> IntoVariableNodeCtrl<MsgT> for VariableNodeCtrl<MsgT> {
+    fn into_variable_node_ctrl(self) -> Option<VariableNodeCtrl<MsgT>> {
+        Some(self)
+    }
+    fn is_variable_node_ctrl(&self) -> bool {
+        true
+    }
+    fn is_variable_node_query(&self) -> bool {
+        matches!(
+            self,
+            VariableNodeCtrl::GetPrior | VariableNodeCtrl::HasPropagated
+        )
+    }
+}
+
+//Control answer types a VariableNode can be used with.
+pub trait FromVariableNodeCtrlAnswer<MsgT>: Default {
+    fn from_variable_node_ctrl_answer(answer: VariableNodeCtrlAnswer<MsgT>) -> Self;
+}
+
+impl<MsgT> FromVariableNodeCtrlAnswer<MsgT> for () {
+    fn from_variable_node_ctrl_answer(_answer: VariableNodeCtrlAnswer<MsgT>) -> Self {}
+}
+
+impl<MsgT> FromVariableNodeCtrlAnswer<MsgT> for VariableNodeCtrlAnswer<MsgT> {
+    fn from_variable_node_ctrl_answer(answer: VariableNodeCtrlAnswer<MsgT>) -> Self {
+        answer
+    }
+}
+
 pub struct VariableNode<T, MsgT: Msg<T>> {
     //TODO:
     is_log: bool,
     connections: Option<Vec<NodeIndex>>,
+    //Combination of priors
     prior: Option<MsgT>,
+    //(prior, weight), see add_prior
+    priors: Vec<(MsgT, f64)>,
+    prior_combination: PriorCombination,
+    //See set_normalize_prior
+    normalize_prior: bool,
+    //Prior set by the builder, checked as by set_prior in initialize (when the semiring is known)
+    check_prior: bool,
     is_threaded: bool,
     needs_all_inputs: InputNeed,
+    //Replaces needs_all_inputs if set
+    ready_policy: Option<Arc<dyn ReadyPolicy>>,
     has_propagated: bool,
     send_to_all: bool,
+    //See BPGraph::set_semiring, None is sum-product
+    semiring: Option<Arc<dyn Semiring>>,
     phantom: std::marker::PhantomData<T>,
 }
 
+//Not derived, which would require T: Clone
+impl<T, MsgT: Msg<T> + Clone> Clone for VariableNode<T, MsgT> {
+    fn clone(&self) -> Self {
+        VariableNode {
+            is_log: self.is_log,
+            connections: self.connections.clone(),
+            prior: self.prior.clone(),
+            priors: self.priors.clone(),
+            prior_combination: self.prior_combination,
+            normalize_prior: self.normalize_prior,
+            check_prior: self.check_prior,
+            is_threaded: self.is_threaded,
+            needs_all_inputs: self.needs_all_inputs.clone(),
+            ready_policy: self.ready_policy.clone(),
+            has_propagated: self.has_propagated,
+            send_to_all: self.send_to_all,
+            semiring: self.semiring.clone(),
+            phantom: std::marker::PhantomData,
+        }
+    }
+}
+
 impl<T, MsgT: Msg<T>> VariableNode<T, MsgT>
 where
     MsgT: Clone,
@@ -34,13 +176,27 @@ where
             is_log: false,
             connections: None,
             prior: None,
+            priors: Vec::new(),
+            prior_combination: PriorCombination::Product,
+            normalize_prior: true,
+            check_prior: false,
             is_threaded: true,
             needs_all_inputs: InputNeed::AlwaysExceptFirst,
+            ready_policy: None,
             has_propagated: false,
             send_to_all: false,
+            semiring: None,
             phantom: std::marker::PhantomData,
         }
     }
+    pub fn builder() -> VariableNodeBuilder<T, MsgT> {
+        VariableNodeBuilder { node: Self::new() }
+    }
+
+    //Fails if the prior is empty or has NaN entries, or, for probabilities (sum-product or max-product,
+    //the default until BPGraph::set_semiring), negative entries or only zeros.
+    //Priors of probability semirings are normalized unless disabled by set_normalize_prior.
+    //Costs or log-probabilities set before the semiring have to be set with set_prior_unchecked.
     pub fn set_prior(&mut self, prior: &MsgT) -> BPResult<()> {
         if self.prior.is_some() {
             return Err(BPError::new(
@@ -48,74 +204,209 @@ where
                 "Prior is already set".to_owned(),
             ));
         }
-        self.prior = Some(prior.clone());
+        let prior = self.checked_prior(prior, "VariableNode::set_prior")?;
+        self.replace_prior(Some(prior));
         Ok(())
     }
 
-    pub fn set_input_need(&mut self, input_need: InputNeed) {
-        self.needs_all_inputs = input_need;
+    //Like set_prior, but the prior is neither validated nor normalized
+    pub fn set_prior_unchecked(&mut self, prior: &MsgT) -> BPResult<()> {
+        if self.prior.is_some() {
+            return Err(BPError::new(
+                "VariableNode::set_prior_unchecked".to_owned(),
+                "Prior is already set".to_owned(),
+            ));
+        }
+        self.replace_prior(Some(prior.clone()));
+        Ok(())
     }
 
-    pub fn set_threaded(&mut self, is_threaded: bool) {
-        self.is_threaded = is_threaded;
+    //Whether set_prior normalizes priors of probability semirings (default: true)
+    pub fn set_normalize_prior(&mut self, normalize_prior: bool) {
+        self.normalize_prior = normalize_prior;
     }
 
-    pub fn set_send_to_all(&mut self, send_to_all: bool) {
-        self.send_to_all = send_to_all;
+    fn checked_prior(&self, prior: &MsgT, fn_name: &str) -> BPResult<MsgT> {
+        let is_probability = matches!(
+            semiring::kind(self.semiring.as_deref()),
+            semiring::SemiringKind::SumProduct | semiring::SemiringKind::MaxProduct
+        );
+        let (mut count, mut nan, mut negative, mut zero) = (0, 0, 0, 0);
+        for (_, p) in prior.clone() {
+            count += 1;
+            if p.is_nan() {
+                nan += 1;
+            } else if p < 0.0 {
+                negative += 1;
+            } else if p == 0.0 {
+                zero += 1;
+            }
+        }
+        let err = |msg: String| Err(BPError::new(fn_name.to_owned(), msg));
+        if count == 0 {
+            return err("Prior is empty".to_owned());
+        }
+        if nan > 0 {
+            return err(format!("Prior has {} NaN entries", nan));
+        }
+        if !is_probability {
+            return Ok(prior.clone());
+        }
+        if negative > 0 {
+            return err(format!("Prior has {} negative entries", negative));
+        }
+        if zero == count {
+            return err("Prior has only zero entries".to_owned());
+        }
+        let mut prior = prior.clone();
+        if self.normalize_prior {
+            prior.normalize().map_err(|e| {
+                e.attach_info_str(
+                    "VariableNode::checked_prior",
+                    "Failed to normalize prior".to_owned(),
+                )
+            })?;
+        }
+        Ok(prior)
     }
-}
 
-impl<T, MsgT: Msg<T>> NodeFunction<T, MsgT> for VariableNode<T, MsgT>
-where
-    MsgT: Clone,
-{
-    fn is_ready(&self, recv_from: &Vec<(NodeIndex, MsgT)>, step: usize) -> BPResult<bool> {
-        Ok(
-            if recv_from.len()
-                == self
-                    .connections
-                    .as_ref()
-                    .expect("Node not initialized.")
-                    .len()
-            {
-                true
-            } else if recv_from.is_empty() && self.prior.is_none() {
-                false
-            } else {
-                match self.needs_all_inputs {
-                    InputNeed::AlwaysExceptFirst => !self.has_propagated,
-                    InputNeed::NeverExceptFirst => self.has_propagated,
-                    InputNeed::Never => true,
-                    InputNeed::Always => false,
-                }
-            },
-        )
+    //Adds an independent source of prior evidence, the priors are combined according to the prior combination.
+    //A prior set by set_prior counts as a prior of weight 1.
+    pub fn add_prior(&mut self, prior: &MsgT, weight: f64) -> BPResult<()> {
+        if !(weight > 0.0 && weight.is_finite()) {
+            return Err(BPError::new(
+                "VariableNode::add_prior".to_owned(),
+                format!("Weight has to be positive and finite (got {})", weight),
+            ));
+        }
+        let prior = self.checked_prior(prior, "VariableNode::add_prior")?;
+        self.priors.push((prior, weight));
+        self.combine_priors().map_err(|e| {
+            self.priors.pop();
+            e.attach_info_str(
+                "VariableNode::add_prior",
+                "Failed to combine priors".to_owned(),
+            )
+        })
     }
 
-    fn get_prior(&self) -> Option<MsgT> {
-        self.prior.clone()
+    pub fn set_prior_combination(&mut self, prior_combination: PriorCombination) -> BPResult<()> {
+        let previous = std::mem::replace(&mut self.prior_combination, prior_combination);
+        self.combine_priors().map_err(|e| {
+            self.prior_combination = previous;
+            e.attach_info_str(
+                "VariableNode::set_prior_combination",
+                "Failed to combine priors".to_owned(),
+            )
+        })
     }
 
-    fn initialize(&mut self, connections: Vec<NodeIndex>) -> BPResult<()> {
-        self.connections = Some(connections);
+    pub fn get_priors(&self) -> &[(MsgT, f64)] {
+        &self.priors
+    }
+
+    pub(crate) fn prior(&self) -> Option<&MsgT> {
+        self.prior.as_ref()
+    }
+
+    //For messages computed outside of node_function (see BPGraph::propagate_step_gpu)
+    pub(crate) fn mark_propagated(&mut self) {
+        self.has_propagated = true;
+    }
+
+    fn replace_prior(&mut self, prior: Option<MsgT>) {
+        self.check_prior = false;
+        self.priors = prior.iter().map(|p| (p.clone(), 1.0)).collect();
+        self.prior = prior;
+    }
+
+    fn combine_priors(&mut self) -> BPResult<()> {
+        let mut priors = self.priors.iter();
+        let (first, w_first) = match priors.next() {
+            Some(first) => first,
+            None => {
+                self.prior = None;
+                return Ok(());
+            }
+        };
+        let mut acc = first.clone();
+        match self.prior_combination {
+            PriorCombination::Product => {
+                if *w_first != 1.0 {
+                    acc.for_each(|p| p.powf(*w_first));
+                }
+                for (prior, w) in priors {
+                    if *w == 1.0 {
+                        acc.mult_msg(prior);
+                    } else {
+                        acc.mult_msg_weighted(prior, *w);
+                    }
+                }
+            }
+            PriorCombination::Mixture => {
+                let total: f64 = self.priors.iter().map(|(_, w)| w).sum();
+                acc.normalize()?;
+                acc.for_each(|p| p * w_first / total);
+                //Over the union of the supports: the entries of prior are added to acc and then written
+                //back, entries only in acc are kept
+                for (prior, w) in priors {
+                    let mut mixed = prior.clone();
+                    mixed.normalize()?;
+                    mixed.for_each(|p| p * w / total);
+                    mixed.add_msg_weighted(&acc, 1.0, 1.0);
+                    for (v, p) in mixed {
+                        acc.insert(v, p);
+                    }
+                }
+            }
+        }
+        self.prior = Some(acc);
         Ok(())
     }
 
-    fn node_function(
+    pub fn set_input_need(&mut self, input_need: InputNeed) {
+        self.needs_all_inputs = input_need;
+    }
+
+    //E.g., ready once 80% of the neighbours have sent: |received, total, _| 5 * received >= 4 * total
+    pub fn set_ready_policy<P: ReadyPolicy + 'static>(&mut self, policy: P) {
+        self.ready_policy = Some(Arc::new(policy));
+    }
+
+    //Falls back to the InputNeed
+    pub fn remove_ready_policy(&mut self) {
+        self.ready_policy = None;
+    }
+
+    pub fn set_threaded(&mut self, is_threaded: bool) {
+        self.is_threaded = is_threaded;
+    }
+
+    pub fn set_send_to_all(&mut self, send_to_all: bool) {
+        self.send_to_all = send_to_all;
+    }
+
+    //Messages of node_function_inplace, copies are taken from pool if given
+    fn create_messages(
         &mut self,
-        mut inbox: Vec<(NodeIndex, MsgT)>,
-    ) -> BPResult<Vec<(NodeIndex, MsgT)>> {
+        inbox: &mut [(NodeIndex, MsgT)],
+        out: &mut Vec<(NodeIndex, MsgT)>,
+        mut pool: Option<&mut MsgPool<MsgT>>,
+    ) -> BPResult<()> {
         let connections = self
             .connections
             .as_ref()
             .expect("VariableNode not initialized");
+        let semiring = self.semiring.as_deref();
         self.has_propagated = true;
         if inbox.is_empty() {
             if let Some(prior) = &self.prior {
-                Ok(connections
-                    .iter()
-                    .map(|idx| (*idx, prior.clone()))
-                    .collect())
+                out.extend(
+                    connections
+                        .iter()
+                        .map(|idx| (*idx, copy_msg(&mut pool, prior))),
+                );
+                Ok(())
             } else {
                 Err(BPError::new(
                     "VariableNode::node_function".to_owned(),
@@ -123,76 +414,331 @@ where
                 ))
             }
         } else if inbox.len() == 1 {
-            let (idx_in, mut msg_in) = inbox.pop().unwrap();
-            let mut out: Vec<(NodeIndex, MsgT)> = Vec::new();
+            let idx_in = inbox[0].0;
+            let mut msg_in = std::mem::replace(&mut inbox[0].1, MsgT::new());
             if let Some(prior) = &self.prior {
-                msg_in.mult_msg(prior);
-                out.push((idx_in, prior.clone()));
+                semiring::times_msg(semiring, &mut msg_in, prior);
+                out.push((idx_in, copy_msg(&mut pool, prior)));
             }
             for con in connections {
                 if idx_in != *con {
-                    out.push((*con, msg_in.clone()));
+                    out.push((*con, copy_msg(&mut pool, &msg_in)));
                 }
             }
-            Ok(out)
+            //Returned to the inbox, so that the graph can recycle it
+            inbox[0].1 = msg_in;
+            Ok(())
         } else if inbox.len() == connections.len() || !self.send_to_all {
-            let mut result: Vec<(NodeIndex, MsgT)> = Vec::with_capacity(inbox.len());
+            let result = out;
             let n = inbox.len();
+            result.reserve(n);
             let (mut acc, start) = if let Some(prior) = &self.prior {
-                (prior.clone(), 0)
+                (copy_msg(&mut pool, prior), 0)
             } else {
-                (inbox[0].1.clone(), 1)
+                (copy_msg(&mut pool, &inbox[0].1), 1)
             };
             for msg in &inbox[start..] {
-                result.push((msg.0, acc.clone()));
-                acc.mult_msg(&msg.1);
+                result.push((msg.0, copy_msg(&mut pool, &acc)));
+                semiring::times_msg(semiring, &mut acc, &msg.1);
             }
-            acc = inbox[n - 1].1.clone();
+            //inbox[n - 1] is not needed anymore
+            acc = std::mem::replace(&mut inbox[n - 1].1, MsgT::new());
             for idx in (0..n - 1 - start).rev() {
-                result[idx].1.mult_msg(&acc);
-                acc.mult_msg(&inbox[idx + start].1);
+                semiring::times_msg(semiring, &mut result[idx].1, &acc);
+                semiring::times_msg(semiring, &mut acc, &inbox[idx + start].1);
             }
             if start == 1 {
-                result.push((inbox[0].0, acc.clone()));
+                result.push((inbox[0].0, acc));
+            } else {
+                inbox[n - 1].1 = acc;
             }
-            Ok(result)
+            Ok(())
         } else {
-            let mut result: Vec<(NodeIndex, MsgT)> = Vec::with_capacity(connections.len());
+            let result = out;
+            result.reserve(connections.len());
             let mut missing = connections.clone();
             let n = inbox.len();
             let (mut acc, start) = if let Some(prior) = &self.prior {
-                (prior.clone(), 0)
+                (copy_msg(&mut pool, prior), 0)
             } else {
                 missing.retain(|idx| *idx != inbox[0].0);
-                (inbox[0].1.clone(), 1)
+                (copy_msg(&mut pool, &inbox[0].1), 1)
             };
             for msg in &inbox[start..] {
-                result.push((msg.0, acc.clone()));
-                acc.mult_msg(&msg.1);
+                result.push((msg.0, copy_msg(&mut pool, &acc)));
+                semiring::times_msg(semiring, &mut acc, &msg.1);
                 missing.retain(|idx| *idx != msg.0);
             }
-            acc = inbox[n - 1].1.clone();
+            acc = std::mem::replace(&mut inbox[n - 1].1, MsgT::new());
             for idx in (0..n - 1 - start).rev() {
-                result[idx].1.mult_msg(&acc);
-                acc.mult_msg(&inbox[idx + start].1);
+                semiring::times_msg(semiring, &mut result[idx].1, &acc);
+                semiring::times_msg(semiring, &mut acc, &inbox[idx + start].1);
             }
             if start == 1 {
-                result.push((inbox[0].0, acc.clone()));
-                acc.mult_msg(&inbox[0].1);
+                result.push((inbox[0].0, copy_msg(&mut pool, &acc)));
+                semiring::times_msg(semiring, &mut acc, &inbox[0].1);
             }
             assert_eq!(missing.len() + result.len(), connections.len());
             for idx in missing {
-                result.push((idx, acc.clone()));
+                result.push((idx, copy_msg(&mut pool, &acc)));
             }
-            Ok(result)
+            inbox[n - 1].1 = acc;
+            Ok(())
         }
     }
+}
+
+//Copy of msg, reusing a pooled message if possible
+fn copy_msg<T, MsgT: Msg<T> + Clone>(pool: &mut Option<&mut MsgPool<MsgT>>, msg: &MsgT) -> MsgT {
+    match pool {
+        Some(pool) => pool.take_clone(msg),
+        None => msg.clone(),
+    }
+}
+
+//Fluent construction of a VariableNode, e.g., VariableNode::builder().prior(dist).send_to_all(true).build()
+pub struct VariableNodeBuilder<T, MsgT: Msg<T>> {
+    node: VariableNode<T, MsgT>,
+}
+
+impl<T, MsgT: Msg<T>> VariableNodeBuilder<T, MsgT>
+where
+    MsgT: Clone,
+{
+    //The prior is checked and normalized as by VariableNode::set_prior when the node is initialized
+    pub fn prior(mut self, prior: MsgT) -> Self {
+        self.node.replace_prior(Some(prior));
+        self.node.check_prior = true;
+        self
+    }
+    //See VariableNode::set_prior_unchecked
+    pub fn prior_unchecked(mut self, prior: MsgT) -> Self {
+        self.node.replace_prior(Some(prior));
+        self
+    }
+    //See VariableNode::set_normalize_prior
+    pub fn normalize_prior(mut self, normalize_prior: bool) -> Self {
+        self.node.normalize_prior = normalize_prior;
+        self
+    }
+    pub fn input_need(mut self, input_need: InputNeed) -> Self {
+        self.node.needs_all_inputs = input_need;
+        self
+    }
+    pub fn send_to_all(mut self, send_to_all: bool) -> Self {
+        self.node.send_to_all = send_to_all;
+        self
+    }
+    pub fn ready_policy<P: ReadyPolicy + 'static>(mut self, policy: P) -> Self {
+        self.node.set_ready_policy(policy);
+        self
+    }
+    pub fn threaded(mut self, is_threaded: bool) -> Self {
+        self.node.is_threaded = is_threaded;
+        self
+    }
+    pub fn build(self) -> VariableNode<T, MsgT> {
+        self.node
+    }
+}
+
+//Saved by VariableNode::save_state
+struct VariableNodeState<MsgT> {
+    prior: Option<MsgT>,
+    priors: Vec<(MsgT, f64)>,
+    has_propagated: bool,
+}
+
+impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT> NodeFunction<T, MsgT, CtrlMsgT, CtrlMsgAT>
+    for VariableNode<T, MsgT>
+where
+    MsgT: Clone + Send + Sync + 'static,
+    CtrlMsgT: IntoVariableNodeCtrl<MsgT>,
+    CtrlMsgAT: FromVariableNodeCtrlAnswer<MsgT>,
+{
+    fn accepts_control_message(&self, ctrl_msg: &CtrlMsgT) -> bool {
+        ctrl_msg.is_variable_node_ctrl()
+    }
+
+    fn control_message_changes_node(&self, ctrl_msg: &CtrlMsgT) -> bool {
+        !ctrl_msg.is_variable_node_query()
+    }
+
+    fn send_control_message(&mut self, ctrl_msg: CtrlMsgT) -> BPResult<CtrlMsgAT> {
+        let answer = match ctrl_msg.into_variable_node_ctrl() {
+            None => {
+                return Err(BPError::new(
+                    "VariableNode::send_control_message".to_owned(),
+                    "Control message is not a VariableNodeCtrl".to_owned(),
+                ))
+            }
+            Some(VariableNodeCtrl::GetPrior) => VariableNodeCtrlAnswer::Prior(self.prior.clone()),
+            Some(VariableNodeCtrl::SetPrior(Some(prior))) => {
+                let prior = self.checked_prior(&prior, "VariableNode::send_control_message")?;
+                self.replace_prior(Some(prior));
+                VariableNodeCtrlAnswer::Done
+            }
+            Some(VariableNodeCtrl::SetPrior(None)) => {
+                self.replace_prior(None);
+                VariableNodeCtrlAnswer::Done
+            }
+            Some(VariableNodeCtrl::SetInputNeed(input_need)) => {
+                self.needs_all_inputs = input_need;
+                VariableNodeCtrlAnswer::Done
+            }
+            Some(VariableNodeCtrl::SetSendToAll(send_to_all)) => {
+                self.send_to_all = send_to_all;
+                VariableNodeCtrlAnswer::Done
+            }
+            Some(VariableNodeCtrl::SetThreaded(is_threaded)) => {
+                self.is_threaded = is_threaded;
+                VariableNodeCtrlAnswer::Done
+            }
+            Some(VariableNodeCtrl::HasPropagated) => {
+                VariableNodeCtrlAnswer::HasPropagated(self.has_propagated)
+            }
+        };
+        Ok(CtrlMsgAT::from_variable_node_ctrl_answer(answer))
+    }
+
+    fn is_ready(&self, recv_from: &Vec<(NodeIndex, MsgT)>, step: usize) -> BPResult<bool> {
+        if let Some(policy) = &self.ready_policy {
+            let total = self
+                .connections
+                .as_ref()
+                .expect("Node not initialized.")
+                .len();
+            return Ok((!recv_from.is_empty() || self.prior.is_some())
+                && policy.ready(recv_from.len(), total, step));
+        }
+        Ok(
+            if recv_from.len()
+                == self
+                    .connections
+                    .as_ref()
+                    .expect("Node not initialized.")
+                    .len()
+            {
+                true
+            } else if recv_from.is_empty() && self.prior.is_none() {
+                false
+            } else {
+                match self.needs_all_inputs {
+                    InputNeed::AlwaysExceptFirst => !self.has_propagated,
+                    InputNeed::NeverExceptFirst => self.has_propagated,
+                    InputNeed::Never => true,
+                    InputNeed::Always => false,
+                }
+            },
+        )
+    }
+
+    fn get_prior(&self) -> Option<MsgT> {
+        self.prior.clone()
+    }
+
+    fn input_need(&self) -> Option<InputNeed> {
+        Some(self.needs_all_inputs.clone())
+    }
+
+    fn inputs_needed(&self, _current_step: usize) -> Option<usize> {
+        if self.ready_policy.is_some() {
+            return None;
+        }
+        let total = self.connections.as_ref()?.len();
+        let needs_all = match self.needs_all_inputs {
+            InputNeed::AlwaysExceptFirst => self.has_propagated,
+            InputNeed::NeverExceptFirst => !self.has_propagated,
+            InputNeed::Never => false,
+            InputNeed::Always => true,
+        };
+        //Without prior, a node that does not need all inputs still needs one
+        Some(if needs_all {
+            total
+        } else if self.prior.is_none() {
+            total.min(1)
+        } else {
+            0
+        })
+    }
+
+    fn initialize(&mut self, connections: Vec<NodeIndex>) -> BPResult<()> {
+        if self.check_prior {
+            if let Some((prior, _)) = self.priors.first() {
+                self.priors[0].0 = self.checked_prior(prior, "VariableNode::initialize")?;
+                self.combine_priors()?;
+            }
+            self.check_prior = false;
+        }
+        self.connections = Some(connections);
+        Ok(())
+    }
+
+    fn node_function(
+        &mut self,
+        mut inbox: Vec<(NodeIndex, MsgT)>,
+    ) -> BPResult<Vec<(NodeIndex, MsgT)>> {
+        let mut out = Vec::new();
+        NodeFunction::<T, MsgT, CtrlMsgT, CtrlMsgAT>::node_function_inplace(
+            self, &mut inbox, &mut out,
+        )?;
+        Ok(out)
+    }
+
+    fn node_function_inplace(
+        &mut self,
+        inbox: &mut [(NodeIndex, MsgT)],
+        out: &mut Vec<(NodeIndex, MsgT)>,
+    ) -> BPResult<()> {
+        self.create_messages(inbox, out, None)
+    }
+
+    fn node_function_pooled(
+        &mut self,
+        inbox: &mut [(NodeIndex, MsgT)],
+        out: &mut Vec<(NodeIndex, MsgT)>,
+        pool: &mut MsgPool<MsgT>,
+    ) -> BPResult<()> {
+        self.create_messages(inbox, out, Some(pool))
+    }
 
     fn reset(&mut self) -> BPResult<()> {
-        self.prior = None;
+        self.replace_prior(None);
+        Ok(())
+    }
+
+    fn reset_schedule_state(&mut self) -> BPResult<()> {
+        self.has_propagated = false;
+        Ok(())
+    }
+
+    fn save_state(&self) -> Option<Box<dyn Any + Send + Sync>> {
+        Some(Box::new(VariableNodeState {
+            prior: self.prior.clone(),
+            priors: self.priors.clone(),
+            has_propagated: self.has_propagated,
+        }))
+    }
+
+    fn load_state(&mut self, state: Box<dyn Any + Send + Sync>) -> BPResult<()> {
+        let state = state.downcast::<VariableNodeState<MsgT>>().map_err(|_| {
+            BPError::new(
+                "VariableNode::load_state".to_owned(),
+                "State was not saved by a VariableNode".to_owned(),
+            )
+        })?;
+        self.prior = state.prior;
+        self.priors = state.priors;
+        self.has_propagated = state.has_propagated;
         Ok(())
     }
 
+    fn set_semiring(&mut self, semiring: Arc<dyn Semiring>) {
+        self.semiring = Some(semiring);
+    }
+
+    impl_clone_box!(T, MsgT, CtrlMsgT, CtrlMsgAT);
+
     fn is_factor(&self) -> bool {
         false
     }
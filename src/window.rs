@@ -0,0 +1,310 @@
+use crate::{
+    BPError, BPGraph, BPResult, LayerScheduler, Msg, NodeIndex, ResultOptions, SumProduct,
+    VariableNodeCtrl,
+};
+use std::collections::{BTreeSet, VecDeque};
+use std::fmt::Debug;
+use std::hash::Hash;
+
+//Time slices of a temporal model (e.g., an HMM growing over time) in windowed mode, see BPGraph::set_window.
+//The size most recent slices are active, older slices are frozen by BPGraph::advance_window.
+#[derive(Debug, Clone, Default)]
+pub struct TimeWindow {
+    size: usize,
+    //Active slices, the oldest first
+    slices: VecDeque<Vec<NodeIndex>>,
+    frozen: BTreeSet<NodeIndex>,
+    frozen_slices: usize,
+    //Set if slices were appended or frozen since the last call of propagate_window
+    changed: bool,
+}
+
+impl TimeWindow {
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn active_slices(&self) -> usize {
+        self.slices.len()
+    }
+
+    pub fn frozen_slices(&self) -> usize {
+        self.frozen_slices
+    }
+
+    //Nodes of the i-th active slice, 0 being the oldest
+    pub fn slice(&self, i: usize) -> Option<&[NodeIndex]> {
+        self.slices.get(i).map(|s| s.as_slice())
+    }
+
+    pub fn is_frozen(&self, node: NodeIndex) -> bool {
+        self.frozen.contains(&node)
+    }
+
+    pub fn frozen_nodes(&self) -> &BTreeSet<NodeIndex> {
+        &self.frozen
+    }
+}
+
+impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default> BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>
+where
+    T: Copy + Eq + Hash + Debug,
+    MsgT: Clone,
+    CtrlMsgT: From<VariableNodeCtrl<MsgT>>,
+{
+    //Switches to windowed mode: slices of nodes are added over time with append_slice, only nodes that are not
+    //frozen are propagated by propagate_window, and the oldest slices are frozen once more than size slices are
+    //active. Changing the size of an active window takes effect with the next call of append_slice.
+    pub fn set_window(&mut self, size: usize) -> BPResult<()> {
+        if size == 0 {
+            return Err(BPError::new(
+                "BPGraph::set_window".to_owned(),
+                "Window needs at least one slice".to_owned(),
+            ));
+        }
+        match self.get_window_mut() {
+            Some(window) => window.size = size,
+            None => {
+                *self.get_window_mut() = Some(TimeWindow {
+                    size,
+                    ..TimeWindow::default()
+                })
+            }
+        }
+        Ok(())
+    }
+
+    //Adds the (already connected) nodes of the next time slice to the window and freezes the oldest slices
+    //if the window holds more than its size (see advance_window). Returns the number of the slice, counted over
+    //all slices appended so far.
+    pub fn append_slice(&mut self, nodes: Vec<NodeIndex>) -> BPResult<usize> {
+        let window = self.get_window().ok_or_else(|| {
+            BPError::new(
+                "BPGraph::append_slice".to_owned(),
+                "Graph is not in windowed mode".to_owned(),
+            )
+        })?;
+        for (i, node) in nodes.iter().enumerate() {
+            self.get_node(*node).map_err(|e| {
+                e.attach_info_str(
+                    "BPGraph::append_slice",
+                    format!("Could not get node {}", node),
+                )
+            })?;
+            if window.is_frozen(*node)
+                || nodes[..i].contains(node)
+                || window.slices.iter().any(|s| s.contains(node))
+            {
+                return Err(BPError::new(
+                    "BPGraph::append_slice".to_owned(),
+                    format!("Node {} is already part of a slice", node),
+                ));
+            }
+        }
+        let (size, number) = (window.size, window.frozen_slices + window.slices.len());
+        let active = {
+            let window = self.get_window_mut().as_mut().expect("Window exists");
+            window.slices.push_back(nodes);
+            window.changed = true;
+            window.slices.len()
+        };
+        if active > size {
+            self.advance_window(active - size).map_err(|e| {
+                e.attach_info_str(
+                    "BPGraph::append_slice",
+                    "Could not advance the window".to_owned(),
+                )
+            })?;
+        }
+        Ok(number)
+    }
+
+    //Freezes the k oldest active slices. Factors connected to a frozen variable are frozen with it.
+    //Every frozen factor connected to an active variable collapses into the prior of that variable: its
+    //message, computed from the cavity distributions of its variables (see BPGraph::get_result_with_options),
+    //is multiplied into the prior and the edge is removed. Frozen factors may be connected to at most one
+    //active variable. Frozen variables keep their last beliefs. Call it after an even number of steps
+    //(with the flooding schedule), so that the variables hold the messages of all their factors.
+    //Edge weights and transforms are not applied to the collapsed messages.
+    //Returns the number of collapsed messages.
+    pub fn advance_window(&mut self, k: usize) -> BPResult<usize> {
+        let active = self
+            .get_window()
+            .ok_or_else(|| {
+                BPError::new(
+                    "BPGraph::advance_window".to_owned(),
+                    "Graph is not in windowed mode".to_owned(),
+                )
+            })?
+            .active_slices();
+        if k > active {
+            return Err(BPError::new(
+                "BPGraph::advance_window".to_owned(),
+                format!("Cannot freeze {} slices, {} are active", k, active),
+            ));
+        }
+        let mut collapsed = 0;
+        for _ in 0..k {
+            let slice = self
+                .get_window_mut()
+                .as_mut()
+                .and_then(|w| w.slices.pop_front())
+                .expect("Slice exists");
+            collapsed += self.freeze(slice)?;
+            self.get_window_mut()
+                .as_mut()
+                .expect("Window exists")
+                .frozen_slices += 1;
+        }
+        Ok(collapsed)
+    }
+
+    //Propagates steps steps, only the nodes that are not frozen create messages. If the window has changed
+    //since the last call, the propagation restarts within the window: the inboxes of the active nodes are
+    //dropped and the nodes are ready as in the first step (see reset_schedule_state), as nodes that have
+    //propagated before would otherwise wait for messages of the new connections.
+    pub fn propagate_window(&mut self, steps: usize) -> BPResult<()> {
+        let window = self.get_window().ok_or_else(|| {
+            BPError::new(
+                "BPGraph::propagate_window".to_owned(),
+                "Graph is not in windowed mode".to_owned(),
+            )
+        })?;
+        let active: Vec<NodeIndex> = (0..self.len()).filter(|n| !window.is_frozen(*n)).collect();
+        if window.changed {
+            self.reset_schedule_state_of(&active)?;
+            self.get_window_mut()
+                .as_mut()
+                .expect("Window exists")
+                .changed = false;
+        }
+        self.propagate_with_scheduler(steps, &mut LayerScheduler::new(vec![active]))
+    }
+
+    fn freeze(&mut self, slice: Vec<NodeIndex>) -> BPResult<usize> {
+        let window = self.get_window().expect("Window exists");
+        let mut frozen: BTreeSet<NodeIndex> = slice.iter().copied().collect();
+        for node in &slice {
+            if !self.is_factor(*node)? {
+                frozen.extend(
+                    self.get_connections(*node)?
+                        .iter()
+                        .filter(|f| !window.is_frozen(**f)),
+                );
+            }
+        }
+        //(frozen factor, active variable)
+        let mut boundary = Vec::new();
+        for node in &frozen {
+            if !self.is_factor(*node)? {
+                continue;
+            }
+            let active: Vec<NodeIndex> = self
+                .get_connections(*node)?
+                .iter()
+                .copied()
+                .filter(|v| !frozen.contains(v) && !window.is_frozen(*v))
+                .collect();
+            if active.len() > 1 {
+                return Err(BPError::new(
+                    "BPGraph::advance_window".to_owned(),
+                    format!(
+                        "Factor {} is connected to {} active variables and cannot be collapsed into a prior",
+                        node,
+                        active.len()
+                    ),
+                ));
+            }
+            boundary.extend(active.into_iter().map(|v| (*node, v)));
+        }
+        let mut msgs = Vec::with_capacity(boundary.len());
+        for (factor, variable) in &boundary {
+            msgs.push(self.collapse_factor(*factor, *variable)?);
+        }
+        for ((factor, variable), msg) in boundary.iter().zip(msgs) {
+            let prior = match self.get_node(*variable)?.get_prior() {
+                Some(mut prior) => {
+                    prior.times_msg(&msg, self.get_semiring().unwrap_or(&SumProduct));
+                    prior
+                }
+                None => msg,
+            };
+            let mut prior = prior;
+            prior
+                .normalize_with(self.get_normalization())
+                .map_err(|e| {
+                    e.attach_info_str(
+                        "BPGraph::advance_window",
+                        format!("Could not normalize the prior of node {}", variable),
+                    )
+                })?;
+            self.send_control_message(*variable, VariableNodeCtrl::SetPrior(Some(prior)).into())?;
+            self.get_node_mut(*variable)?
+                .remap_indices(|c| if c == *factor { None } else { Some(c) });
+            self.get_node_mut(*factor)?
+                .get_connections_mut()
+                .retain(|c| c != variable);
+        }
+        self.get_window_mut()
+            .as_mut()
+            .expect("Window exists")
+            .frozen
+            .extend(frozen);
+        self.get_window_mut()
+            .as_mut()
+            .expect("Window exists")
+            .changed = true;
+        for (_, variable) in &boundary {
+            let node = self.get_node_mut(*variable)?;
+            if !node.is_initialized() {
+                node.initialize()?;
+            }
+        }
+        self.invalidate_validation();
+        Ok(boundary.len())
+    }
+
+    //Message of factor to variable given the cavity distributions of the variables of factor
+    fn collapse_factor(&mut self, factor: NodeIndex, variable: NodeIndex) -> BPResult<MsgT> {
+        let mut inbox = Vec::new();
+        for var in self.get_connections(factor)?.clone() {
+            let options = ResultOptions {
+                include_prior: true,
+                exclude_neighbors: &[factor],
+            };
+            let cavity = match self.get_result_with_options(var, &options)? {
+                Some(cavity) => cavity,
+                //The factor is the only source of information, the cavity is uniform
+                None => self
+                    .get_result(var)?
+                    .map(|belief| belief.into_keys().map(|v| (v, 1.0)).collect())
+                    .ok_or_else(|| {
+                        BPError::new(
+                            "BPGraph::advance_window".to_owned(),
+                            format!("Variable {} has neither a prior nor messages", var),
+                        )
+                    })?,
+            };
+            let mut msg = MsgT::new();
+            for (v, p) in cavity {
+                msg.insert(v, p);
+            }
+            inbox.push((var, msg));
+        }
+        let node = self.get_node_mut(factor)?;
+        node.read_post();
+        for (from, msg) in inbox {
+            node.send_post(from, msg);
+        }
+        node.create_messages()?
+            .into_iter()
+            .find(|(to, _)| *to == variable)
+            .map(|(_, msg)| msg)
+            .ok_or_else(|| {
+                BPError::new(
+                    "BPGraph::advance_window".to_owned(),
+                    format!("Factor {} sent no message to variable {}", factor, variable),
+                )
+            })
+    }
+}
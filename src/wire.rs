@@ -0,0 +1,294 @@
+use crate::{BPError, BPGraph, BPResult, Msg, NodeIndex, Probability};
+use std::convert::TryInto;
+use std::fmt::Debug;
+
+/*
+Binary wire format for exchanging messages between graphs, e.g., shards of one large graph in different
+processes. All numbers are little endian:
+    batch:   magic "BPW1", number of entries (u64), entries
+    entry:   from (u64), to (u64), message
+    message: number of values (u64), (value, probability (f64)) for every value
+Values are encoded with WireValue. The format is not compressed; with the compression feature, batches can be
+compressed with zstd (see compress and BPGraph::export_boundary_messages_compressed).
+*/
+
+const MAGIC: &[u8; 4] = b"BPW1";
+
+//Values that can be written to the wire format
+pub trait WireValue: Sized {
+    fn write_to(&self, out: &mut Vec<u8>);
+    //Reads a value from the front of bytes and advances bytes past it
+    fn read_from(bytes: &mut &[u8]) -> BPResult<Self>;
+}
+
+fn take<'a>(bytes: &mut &'a [u8], n: usize) -> BPResult<&'a [u8]> {
+    if bytes.len() < n {
+        return Err(BPError::new(
+            "wire::read".to_owned(),
+            format!(
+                "Unexpected end of data ({} bytes left, needed: {})",
+                bytes.len(),
+                n
+            ),
+        ));
+    }
+    let (front, rest) = bytes.split_at(n);
+    *bytes = rest;
+    Ok(front)
+}
+
+macro_rules! impl_wire_value {
+    ($($t:ty),*) => {
+        $(
+            impl WireValue for $t {
+                fn write_to(&self, out: &mut Vec<u8>) {
+                    out.extend_from_slice(&self.to_le_bytes());
+                }
+                fn read_from(bytes: &mut &[u8]) -> BPResult<Self> {
+                    let front = take(bytes, std::mem::size_of::<$t>())?;
+                    Ok(<$t>::from_le_bytes(front.try_into().expect("Length is checked")))
+                }
+            }
+        )*
+    };
+}
+
+impl_wire_value!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64);
+
+//usize and isize are always written with 64 bits, so that graphs on different platforms can communicate
+impl WireValue for usize {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        (*self as u64).write_to(out);
+    }
+    fn read_from(bytes: &mut &[u8]) -> BPResult<Self> {
+        let v = u64::read_from(bytes)?;
+        v.try_into().map_err(|_| {
+            BPError::new(
+                "wire::read".to_owned(),
+                format!("Value {} does not fit into usize", v),
+            )
+        })
+    }
+}
+
+impl WireValue for isize {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        (*self as i64).write_to(out);
+    }
+    fn read_from(bytes: &mut &[u8]) -> BPResult<Self> {
+        let v = i64::read_from(bytes)?;
+        v.try_into().map_err(|_| {
+            BPError::new(
+                "wire::read".to_owned(),
+                format!("Value {} does not fit into isize", v),
+            )
+        })
+    }
+}
+
+impl WireValue for bool {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+    fn read_from(bytes: &mut &[u8]) -> BPResult<Self> {
+        match u8::read_from(bytes)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            b => Err(BPError::new(
+                "wire::read".to_owned(),
+                format!("Invalid bool {}", b),
+            )),
+        }
+    }
+}
+
+pub fn write_msg<T: WireValue, MsgT: Msg<T>>(msg: MsgT, out: &mut Vec<u8>) {
+    let entries: Vec<(T, Probability)> = msg.into_iter().collect();
+    entries.len().write_to(out);
+    for (v, p) in entries {
+        v.write_to(out);
+        p.write_to(out);
+    }
+}
+
+pub fn read_msg<T: WireValue, MsgT: Msg<T>>(bytes: &mut &[u8]) -> BPResult<MsgT> {
+    let len = usize::read_from(bytes)?;
+    let mut msg = MsgT::new();
+    for _ in 0..len {
+        let v = T::read_from(bytes)?;
+        msg.insert(v, Probability::read_from(bytes)?);
+    }
+    Ok(msg)
+}
+
+//Encodes (from, to, msg) entries as a batch
+pub fn write_batch<T: WireValue, MsgT: Msg<T>>(
+    entries: impl IntoIterator<Item = (NodeIndex, NodeIndex, MsgT)>,
+) -> Vec<u8> {
+    let entries: Vec<(NodeIndex, NodeIndex, MsgT)> = entries.into_iter().collect();
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    entries.len().write_to(&mut out);
+    for (from, to, msg) in entries {
+        from.write_to(&mut out);
+        to.write_to(&mut out);
+        write_msg(msg, &mut out);
+    }
+    out
+}
+
+pub fn read_batch<T: WireValue, MsgT: Msg<T>>(
+    mut bytes: &[u8],
+) -> BPResult<Vec<(NodeIndex, NodeIndex, MsgT)>> {
+    if take(&mut bytes, MAGIC.len())? != MAGIC {
+        return Err(BPError::new(
+            "wire::read_batch".to_owned(),
+            "Not a message batch (wrong magic)".to_owned(),
+        ));
+    }
+    let len = usize::read_from(&mut bytes)?;
+    let mut entries = Vec::with_capacity(len.min(1 << 16));
+    for i in 0..len {
+        let entry = (|| {
+            let from = usize::read_from(&mut bytes)?;
+            let to = usize::read_from(&mut bytes)?;
+            Ok((from, to, read_msg(&mut bytes)?))
+        })()
+        .map_err(|e: BPError| {
+            e.attach_info_str("wire::read_batch", format!("Could not read entry {}", i))
+        })?;
+        entries.push(entry);
+    }
+    if !bytes.is_empty() {
+        return Err(BPError::new(
+            "wire::read_batch".to_owned(),
+            format!("{} bytes left after the last entry", bytes.len()),
+        ));
+    }
+    Ok(entries)
+}
+
+//Compresses bytes (e.g., a batch) with zstd at level (0 selects the default level of zstd)
+#[cfg(feature = "compression")]
+pub fn compress(bytes: &[u8], level: i32) -> BPResult<Vec<u8>> {
+    zstd::encode_all(bytes, level).map_err(|e| {
+        BPError::new(
+            "wire::compress".to_owned(),
+            format!("Compression failed: {}", e),
+        )
+    })
+}
+
+//Maximal size of decompressed data accepted by decompress
+#[cfg(feature = "compression")]
+pub const MAX_DECOMPRESSED_SIZE: usize = 1 << 30;
+
+//Fails if the data decompresses to more than MAX_DECOMPRESSED_SIZE bytes
+#[cfg(feature = "compression")]
+pub fn decompress(bytes: &[u8]) -> BPResult<Vec<u8>> {
+    decompress_with_limit(bytes, MAX_DECOMPRESSED_SIZE)
+}
+
+//Like decompress, but fails if the data decompresses to more than max_size bytes. Decompression stops
+//right after max_size bytes.
+#[cfg(feature = "compression")]
+pub fn decompress_with_limit(bytes: &[u8], max_size: usize) -> BPResult<Vec<u8>> {
+    use std::io::Read;
+    let decompression_failed = |e: std::io::Error| {
+        BPError::new(
+            "wire::decompress".to_owned(),
+            format!("Decompression failed: {}", e),
+        )
+    };
+    let mut out = Vec::new();
+    zstd::Decoder::new(bytes)
+        .map_err(decompression_failed)?
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut out)
+        .map_err(decompression_failed)?;
+    if out.len() > max_size {
+        return Err(BPError::new(
+            "wire::decompress".to_owned(),
+            format!("Decompressed data exceeds {} bytes", max_size),
+        ));
+    }
+    Ok(out)
+}
+
+impl<T, MsgT: Msg<T>, CtrlMsgT, CtrlMsgAT: Default> BPGraph<T, MsgT, CtrlMsgT, CtrlMsgAT>
+where
+    T: WireValue + Clone + Debug,
+    MsgT: Clone,
+{
+    //Encodes the messages in the inboxes of nodes (as (sender, node, msg)) without removing them.
+    //For a graph split into shards, nodes are typically placeholders for the neighbours in other shards,
+    //so that their inboxes contain the messages to be sent across the boundary.
+    pub fn export_boundary_messages(&self, nodes: &[NodeIndex]) -> BPResult<Vec<u8>> {
+        let mut entries = Vec::new();
+        for node in nodes {
+            for (from, msg) in self.get_node(*node)?.clone_inbox() {
+                entries.push((from, *node, msg));
+            }
+        }
+        Ok(write_batch(entries))
+    }
+
+    //Delivers the messages of a batch created by export_boundary_messages (of possibly another graph) into
+    //the inboxes of this graph. remap translates the node indices of the exporting graph, None is an error.
+    //Returns the number of delivered messages. The messages are not normalized or transformed again.
+    //All entries are checked first, so nothing is delivered if the batch is invalid.
+    pub fn import_boundary_messages(
+        &mut self,
+        bytes: &[u8],
+        remap: impl Fn(NodeIndex) -> Option<NodeIndex>,
+    ) -> BPResult<usize> {
+        let entries = read_batch::<T, MsgT>(bytes)?;
+        let mut mapped = Vec::with_capacity(entries.len());
+        for (from, to, msg) in entries {
+            let (from_new, to_new) = match (remap(from), remap(to)) {
+                (Some(from_new), Some(to_new)) => (from_new, to_new),
+                _ => {
+                    return Err(BPError::new(
+                        "BPGraph::import_boundary_messages".to_owned(),
+                        format!("Cannot map edge {} -> {}", from, to),
+                    ))
+                }
+            };
+            if !self.get_node(to_new)?.is_connected(from_new) {
+                return Err(BPError::new(
+                    "BPGraph::import_boundary_messages".to_owned(),
+                    format!(
+                        "Trying to deliver a message along a non-existent edge ({} -> {})",
+                        from_new, to_new
+                    ),
+                ));
+            }
+            mapped.push((from_new, to_new, msg));
+        }
+        let count = mapped.len();
+        for (from, to, msg) in mapped {
+            self.get_node_mut(to)?.send_post(from, msg);
+        }
+        Ok(count)
+    }
+
+    //Like export_boundary_messages, but the batch is compressed (see compress)
+    #[cfg(feature = "compression")]
+    pub fn export_boundary_messages_compressed(
+        &self,
+        nodes: &[NodeIndex],
+        level: i32,
+    ) -> BPResult<Vec<u8>> {
+        compress(&self.export_boundary_messages(nodes)?, level)
+    }
+
+    //Like import_boundary_messages for batches created by export_boundary_messages_compressed
+    #[cfg(feature = "compression")]
+    pub fn import_boundary_messages_compressed(
+        &mut self,
+        bytes: &[u8],
+        remap: impl Fn(NodeIndex) -> Option<NodeIndex>,
+    ) -> BPResult<usize> {
+        self.import_boundary_messages(&decompress(bytes)?, remap)
+    }
+}